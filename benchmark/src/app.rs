@@ -147,11 +147,19 @@ impl App {
                     }
                     Message::Warmup => {}
                 },
-                Err(_) => self.is_error = true,
+                Err(_) => {
+                    self.is_error = true;
+                    self.data.errors += 1;
+                }
             }
         }
     }
 
+    /// Every batch size was benchmarked, or the benchmark failed
+    pub(crate) fn finished(&self) -> bool {
+        self.is_error || self.completed_batch == self.data.batch_size.len()
+    }
+
     /// Render frame
     pub fn render<B: Backend>(&mut self, f: &mut Frame<'_, B>) {
         let batch_progress =
@@ -372,6 +380,10 @@ pub(crate) struct Data {
     pub(crate) decode_latencies: Vec<Vec<f64>>,
     pub(crate) decode_token_latencies: Vec<Vec<f64>>,
     pub(crate) decode_throughputs: Vec<Vec<f64>>,
+    pub(crate) end_to_end_latencies: Vec<Vec<f64>>,
+    pub(crate) errors: usize,
+    /// Prefill latency of the current run, the latencies are sorted when rendered
+    run_prefill_latency: f64,
     pub(crate) prefill_batch_latency_throughput: Vec<(f64, f64)>,
     pub(crate) decode_batch_latency_throughput: Vec<(f64, f64)>,
 }
@@ -386,6 +398,7 @@ impl Data {
         let decode_latencies: Vec<Vec<f64>> = prefill_latencies.clone();
         let decode_token_latencies: Vec<Vec<f64>> = decode_latencies.clone();
         let decode_throughputs: Vec<Vec<f64>> = prefill_throughputs.clone();
+        let end_to_end_latencies: Vec<Vec<f64>> = prefill_latencies.clone();
 
         let prefill_batch_latency_throughput: Vec<(f64, f64)> =
             Vec::with_capacity(batch_size.len());
@@ -399,6 +412,9 @@ impl Data {
             decode_latencies,
             decode_token_latencies,
             decode_throughputs,
+            end_to_end_latencies,
+            errors: 0,
+            run_prefill_latency: 0.0,
             prefill_batch_latency_throughput,
             decode_batch_latency_throughput,
         }
@@ -407,6 +423,7 @@ impl Data {
     fn push_prefill(&mut self, prefill: Prefill, batch_idx: usize) {
         let latency = prefill.latency.as_micros() as f64 / 1000.0;
         self.prefill_latencies[batch_idx].push(latency);
+        self.run_prefill_latency = latency;
         self.prefill_throughputs[batch_idx].push(prefill.throughput);
     }
 
@@ -414,6 +431,7 @@ impl Data {
         let latency = decode.latency.as_micros() as f64 / 1000.0;
         let token_latency = decode.token_latency.as_micros() as f64 / 1000.0;
         self.decode_latencies[batch_idx].push(latency);
+        self.end_to_end_latencies[batch_idx].push(self.run_prefill_latency + latency);
         self.decode_token_latencies[batch_idx].push(token_latency);
         self.decode_throughputs[batch_idx].push(decode.throughput);
     }
@@ -0,0 +1,182 @@
+/// Prompts replayed from a dataset file
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use serde_json::Value;
+use std::io;
+use std::path::Path;
+use tokenizers::Tokenizer;
+
+/// Request of a dataset: the prompt, and the number of tokens of the recorded answer
+#[derive(Debug, Clone)]
+pub(crate) struct DatasetRequest {
+    pub(crate) prompt: String,
+    pub(crate) max_new_tokens: u32,
+}
+
+/// Read the requests of a dataset file.
+/// The file is a JSON array, JSON lines (`.jsonl`) or a Parquet file (`.parquet`), whose entries
+/// are either ShareGPT conversations (`{"conversations": [{"from": "human", "value": ...},
+/// {"from": "gpt", "value": ...}]}`), or `{"prompt": ..., "completion": ...}` objects.
+/// `completion` can be replaced by `max_new_tokens`. Entries without prompt or answer are skipped.
+pub(crate) fn read_dataset(path: &Path, tokenizer: &Tokenizer) -> io::Result<Vec<DatasetRequest>> {
+    let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidData, err);
+
+    let entries: Vec<Value> = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("parquet") => {
+            let reader = SerializedFileReader::new(std::fs::File::open(path)?)
+                .map_err(|err| invalid(err.to_string()))?;
+            let rows = reader
+                .get_row_iter(None)
+                .map_err(|err| invalid(err.to_string()))?;
+            rows.map(|row| row.map(|row| row.to_json_value()))
+                .collect::<Result<_, _>>()
+                .map_err(|err| invalid(err.to_string()))?
+        }
+        Some("jsonl") => std::fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()
+            .map_err(|err| invalid(err.to_string()))?,
+        _ => serde_json::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|err| invalid(err.to_string()))?,
+    };
+
+    let requests: Vec<DatasetRequest> = entries
+        .iter()
+        .filter_map(|entry| dataset_request(entry, tokenizer))
+        .collect();
+    if requests.is_empty() {
+        return Err(invalid(format!(
+            "no prompt with an answer in {}",
+            path.display()
+        )));
+    }
+    Ok(requests)
+}
+
+fn dataset_request(entry: &Value, tokenizer: &Tokenizer) -> Option<DatasetRequest> {
+    let (prompt, completion) = match entry.get("conversations") {
+        Some(Value::Array(turns)) => {
+            fn text(turn: &Value) -> Option<String> {
+                turn.get("value")?.as_str().map(str::to_string)
+            }
+            fn from(turn: &Value) -> Option<&str> {
+                turn.get("from")?.as_str()
+            }
+            let prompt = turns
+                .iter()
+                .position(|turn| matches!(from(turn), Some("human" | "user")))?;
+            let answer = turns.get(prompt + 1)?;
+            if !matches!(from(answer), Some("gpt" | "assistant")) {
+                return None;
+            }
+            (text(&turns[prompt])?, text(answer))
+        }
+        _ => (
+            entry.get("prompt")?.as_str()?.to_string(),
+            entry
+                .get("completion")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        ),
+    };
+
+    let max_new_tokens = match completion {
+        Some(completion) => tokenizer.encode(completion, false).ok()?.len() as u32,
+        None => entry.get("max_new_tokens")?.as_u64()? as u32,
+    };
+    (!prompt.is_empty() && max_new_tokens > 0).then_some(DatasetRequest {
+        prompt,
+        max_new_tokens,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::WhitespaceSplit;
+
+    fn tokenizer() -> Tokenizer {
+        let vocab = [("[UNK]".to_string(), 0)].into_iter().collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(WhitespaceSplit);
+        tokenizer
+    }
+
+    #[test]
+    fn test_dataset_request() {
+        let tokenizer = tokenizer();
+        let request =
+            |entry| dataset_request(&entry, &tokenizer).map(|r| (r.prompt, r.max_new_tokens));
+
+        // ShareGPT conversations, from the first human turn
+        assert_eq!(
+            request(json!({"conversations": [
+                {"from": "system", "value": "Be brief"},
+                {"from": "human", "value": "What is Rust?"},
+                {"from": "gpt", "value": "A programming language"},
+            ]})),
+            Some(("What is Rust?".to_string(), 3))
+        );
+        assert_eq!(
+            request(json!({"conversations": [
+                {"from": "human", "value": "Hello"},
+                {"from": "human", "value": "Anyone?"},
+            ]})),
+            None
+        );
+
+        assert_eq!(
+            request(json!({"prompt": "Hello", "completion": "Hi there"})),
+            Some(("Hello".to_string(), 2))
+        );
+        assert_eq!(
+            request(json!({"prompt": "Hello", "max_new_tokens": 20})),
+            Some(("Hello".to_string(), 20))
+        );
+        // Without answer or prompt
+        assert_eq!(request(json!({"prompt": "Hello"})), None);
+        assert_eq!(request(json!({"prompt": "", "max_new_tokens": 20})), None);
+        assert_eq!(request(json!({"prompt": "Hello", "completion": ""})), None);
+    }
+
+    #[test]
+    fn test_read_dataset() {
+        let dir = std::env::temp_dir().join(format!("tgi-dataset-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let tokenizer = tokenizer();
+
+        let jsonl = dir.join("prompts.jsonl");
+        std::fs::write(
+            &jsonl,
+            "{\"prompt\": \"a\", \"max_new_tokens\": 4}\n\n{\"prompt\": \"b\"}\n{\"prompt\": \"c\", \"completion\": \"d e\"}\n",
+        )
+        .unwrap();
+        let requests = read_dataset(&jsonl, &tokenizer).unwrap();
+        let requests: Vec<_> = requests
+            .iter()
+            .map(|r| (r.prompt.as_str(), r.max_new_tokens))
+            .collect();
+        assert_eq!(requests, [("a", 4), ("c", 2)]);
+
+        let json = dir.join("prompts.json");
+        std::fs::write(&json, r#"[{"prompt": "a", "max_new_tokens": 4}]"#).unwrap();
+        assert_eq!(read_dataset(&json, &tokenizer).unwrap().len(), 1);
+
+        // Without any usable entry
+        std::fs::write(&json, r#"[{"prompt": "a"}]"#).unwrap();
+        let err = read_dataset(&json, &tokenizer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::write(&jsonl, "not json\n").unwrap();
+        assert!(read_dataset(&jsonl, &tokenizer).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
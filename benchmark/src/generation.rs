@@ -152,12 +152,15 @@ async fn prefill(
             stopping_parameters: Some(StoppingCriteriaParameters {
                 max_new_tokens: decode_length,
                 stop_sequences: vec![],
+                stop_token_ids: vec![],
                 ignore_eos_token: true, // Will not stop even if a eos token is generated
+                max_time: 0.0,
             }),
             top_n_tokens: top_n_tokens.unwrap_or(0),
             blocks: vec![],
             slots: vec![],
             adapter_id: None,
+            input_ids: vec![],
         })
         .collect();
 
@@ -221,7 +224,7 @@ async fn decode(batch: CachedBatch, client: &mut ShardedClient) -> Result<Decode
 }
 
 /// Create a dummy sequence of the correct length
-fn create_sequence(sequence_length: u32, tokenizer: Tokenizer) -> String {
+pub(crate) fn create_sequence(sequence_length: u32, tokenizer: Tokenizer) -> String {
     let lorem_ipsum_length = tokenizer.encode(LOREM_IPSUM, true).unwrap().len();
     // Repeat lorem ipsum to cover sequence length
     let string_sequence =
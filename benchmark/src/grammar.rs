@@ -0,0 +1,161 @@
+/// Grammar-constrained workload of the open-loop benchmark
+use rand::Rng;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Kind of a request of the grammar workload
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RequestKind {
+    Unconstrained,
+    /// First use of its grammar, which the server has to compile
+    ColdGrammar,
+    /// Grammar already used by a previous request, compiled by the server
+    WarmGrammar,
+}
+
+/// Read a grammar file: a JSON schema, a `{"type": "json" | "regex", "value": ...}` grammar in
+/// the format of the router, or a regular expression if the extension is not `.json`
+fn read_grammar(path: &Path) -> io::Result<Value> {
+    let content = std::fs::read_to_string(path)?;
+    if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+        return Ok(json!({ "type": "regex", "value": content.trim() }));
+    }
+    let grammar: Value = serde_json::from_str(&content)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(match grammar.get("type").and_then(Value::as_str) {
+        Some("json" | "regex") if grammar.get("value").is_some() => grammar,
+        _ => json!({ "type": "json", "value": grammar }),
+    })
+}
+
+/// Picks the grammar of each request
+pub(crate) struct GrammarWorkload {
+    grammars: Vec<Value>,
+    used: Vec<bool>,
+    /// Fraction of the requests constrained by a grammar
+    ratio: f64,
+    /// Fraction of the constrained requests whose JSON schema is made unique
+    unique_ratio: f64,
+    unique_grammars: usize,
+}
+
+impl GrammarWorkload {
+    pub(crate) fn new(paths: &[PathBuf], ratio: f64, unique_ratio: f64) -> io::Result<Self> {
+        let grammars = paths
+            .iter()
+            .map(|path| read_grammar(path))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            used: vec![false; grammars.len()],
+            grammars,
+            ratio,
+            unique_ratio,
+            unique_grammars: 0,
+        })
+    }
+
+    /// Grammar of the next request, if constrained, and the kind of the request
+    pub(crate) fn next<R: Rng>(&mut self, rng: &mut R) -> (Option<Value>, RequestKind) {
+        if self.grammars.is_empty() || rng.gen::<f64>() >= self.ratio {
+            return (None, RequestKind::Unconstrained);
+        }
+        let i = rng.gen_range(0..self.grammars.len());
+        let mut grammar = self.grammars[i].clone();
+
+        // The server caches the compiled grammars by value, a distinct schema is compiled again
+        if grammar["type"] == "json" && rng.gen::<f64>() < self.unique_ratio {
+            if let Some(schema) = grammar["value"].as_object_mut() {
+                self.unique_grammars += 1;
+                schema.insert(
+                    "$comment".to_string(),
+                    format!("benchmark {}", self.unique_grammars).into(),
+                );
+                return (Some(grammar), RequestKind::ColdGrammar);
+            }
+        }
+        let kind = match std::mem::replace(&mut self.used[i], true) {
+            true => RequestKind::WarmGrammar,
+            false => RequestKind::ColdGrammar,
+        };
+        (Some(grammar), kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_read_grammar() {
+        let dir = std::env::temp_dir().join(format!("tgi-grammar-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let schema = dir.join("schema.json");
+        std::fs::write(&schema, r#"{"type": "object"}"#).unwrap();
+        assert_eq!(
+            read_grammar(&schema).unwrap(),
+            json!({"type": "json", "value": {"type": "object"}})
+        );
+        let grammar = dir.join("grammar.json");
+        std::fs::write(&grammar, r#"{"type": "regex", "value": "[0-9]+"}"#).unwrap();
+        assert_eq!(
+            read_grammar(&grammar).unwrap(),
+            json!({"type": "regex", "value": "[0-9]+"})
+        );
+        let regex = dir.join("digits.regex");
+        std::fs::write(&regex, "[0-9]+\n").unwrap();
+        assert_eq!(
+            read_grammar(&regex).unwrap(),
+            json!({"type": "regex", "value": "[0-9]+"})
+        );
+
+        std::fs::write(&schema, "{").unwrap();
+        assert_eq!(
+            read_grammar(&schema).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn workload(ratio: f64, unique_ratio: f64) -> GrammarWorkload {
+        GrammarWorkload {
+            grammars: vec![json!({"type": "json", "value": {"type": "object"}})],
+            used: vec![false],
+            ratio,
+            unique_ratio,
+            unique_grammars: 0,
+        }
+    }
+
+    #[test]
+    fn test_grammar_workload() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let mut unconstrained = workload(0.0, 0.0);
+        assert_eq!(
+            unconstrained.next(&mut rng),
+            (None, RequestKind::Unconstrained)
+        );
+
+        // The first use of a grammar is cold
+        let mut constrained = workload(1.0, 0.0);
+        let (grammar, kind) = constrained.next(&mut rng);
+        assert_eq!(grammar.unwrap()["value"], json!({"type": "object"}));
+        assert_eq!(kind, RequestKind::ColdGrammar);
+        assert_eq!(constrained.next(&mut rng).1, RequestKind::WarmGrammar);
+
+        // Unique schemas are always cold
+        let mut unique = workload(1.0, 1.0);
+        let (first, kind) = unique.next(&mut rng);
+        assert_eq!(kind, RequestKind::ColdGrammar);
+        let (second, kind) = unique.next(&mut rng);
+        assert_eq!(kind, RequestKind::ColdGrammar);
+        assert_ne!(first, second);
+    }
+}
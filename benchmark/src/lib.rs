@@ -1,13 +1,24 @@
 mod app;
+mod dataset;
 mod event;
 mod generation;
+mod grammar;
+mod load;
+mod results;
+mod sweep;
 mod table;
 mod utils;
 
 use crate::app::App;
 use crate::event::Event;
+use crate::results::Results;
 use crossterm::ExecutableCommand;
+pub use load::{run_open_loop, Arrivals};
+use std::collections::HashMap;
 use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+pub use sweep::run_concurrency_sweep;
 use text_generation_client::v3::{GrammarType, NextTokenChooserParameters, ShardedClient};
 use tokenizers::Tokenizer;
 use tokio::sync::{broadcast, mpsc};
@@ -34,31 +45,47 @@ pub async fn run(
     watermark: bool,
     do_sample: bool,
     client: ShardedClient,
+    results_file: Option<PathBuf>,
+    headless: bool,
 ) -> Result<(), std::io::Error> {
     let parameters = NextTokenChooserParameters {
         temperature: temperature.unwrap_or(1.0),
         top_k: top_k.unwrap_or(0),
         top_p: top_p.unwrap_or(1.0),
         typical_p: typical_p.unwrap_or(1.0),
+        min_p: 0.0,
+        epsilon_cutoff: 0.0,
+        eta_cutoff: 0.0,
+        top_a: 0.0,
+        mirostat_mode: 0,
+        mirostat_tau: 0.0,
+        mirostat_eta: 0.0,
+        grammar_fsm: None,
+        logit_bias: HashMap::new(),
+        bad_words: Vec::new(),
+        guidance_scale: 1.0,
+        negative_input_ids: Vec::new(),
+        min_new_tokens: 0,
         do_sample,
         seed: 0,
         repetition_penalty: repetition_penalty.unwrap_or(1.0),
         frequency_penalty: frequency_penalty.unwrap_or(0.0),
+        presence_penalty: 0.0,
+        no_repeat_ngram_size: 0,
+        dry_multiplier: 0.0,
+        dry_base: 0.0,
+        dry_allowed_length: 0,
         watermark,
         grammar: String::new(),
         grammar_type: GrammarType::None as i32,
     };
 
     // Initialize terminal properties
-    crossterm::terminal::enable_raw_mode()?;
-    io::stdout().execute(crossterm::terminal::EnterAlternateScreen)?;
-    io::stdout().execute(crossterm::cursor::Hide)?;
-
-    // Initialize terminal
-    let mut terminal = {
-        let backend = CrosstermBackend::new(io::stdout());
-        Terminal::new(backend)?
-    };
+    if !headless {
+        crossterm::terminal::enable_raw_mode()?;
+        io::stdout().execute(crossterm::terminal::EnterAlternateScreen)?;
+        io::stdout().execute(crossterm::cursor::Hide)?;
+    }
 
     // Create message channel between generation_task and app
     let (run_sender, run_receiver) = mpsc::channel(8);
@@ -86,12 +113,14 @@ pub async fn run(
     ));
 
     // Create event task
-    tokio::spawn(event::terminal_event_task(
-        250,
-        event_sender,
-        shutdown_sender.subscribe(),
-        shutdown_guard_sender.clone(),
-    ));
+    if !headless {
+        tokio::spawn(event::terminal_event_task(
+            250,
+            event_sender,
+            shutdown_sender.subscribe(),
+            shutdown_guard_sender.clone(),
+        ));
+    }
 
     // Drop our end of shutdown sender
     drop(shutdown_guard_sender);
@@ -106,19 +135,35 @@ pub async fn run(
         batch_size,
     );
 
-    while app.running {
-        // Draw frame
-        terminal.draw(|frame| app.render(frame))?;
-
-        // Await a new event from event handling task
-        match event_receiver.recv().await {
-            None => break,
-            // Update app state
-            Some(event) => match event {
-                Event::Tick => app.tick(),
-                Event::Key(key_event) => app.handle_key_event(key_event),
-                _ => {}
-            },
+    if headless {
+        // Stop once every batch size was benchmarked
+        while !app.finished() {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(250)) => app.tick(),
+                _ = tokio::signal::ctrl_c() => break,
+            }
+        }
+    } else {
+        // Initialize terminal
+        let mut terminal = {
+            let backend = CrosstermBackend::new(io::stdout());
+            Terminal::new(backend)?
+        };
+
+        while app.running {
+            // Draw frame
+            terminal.draw(|frame| app.render(frame))?;
+
+            // Await a new event from event handling task
+            match event_receiver.recv().await {
+                None => break,
+                // Update app state
+                Some(event) => match event {
+                    Event::Tick => app.tick(),
+                    Event::Key(key_event) => app.handle_key_event(key_event),
+                    _ => {}
+                },
+            }
         }
     }
 
@@ -128,9 +173,31 @@ pub async fn run(
     let _ = shutdown_guard_receiver.recv().await;
 
     // Revert terminal to original view
-    io::stdout().execute(crossterm::terminal::LeaveAlternateScreen)?;
-    crossterm::terminal::disable_raw_mode()?;
-    io::stdout().execute(crossterm::cursor::Show)?;
+    if !headless {
+        io::stdout().execute(crossterm::terminal::LeaveAlternateScreen)?;
+        crossterm::terminal::disable_raw_mode()?;
+        io::stdout().execute(crossterm::cursor::Show)?;
+    }
+
+    if let Some(results_file) = results_file {
+        let parameters = serde_json::json!({
+            "model": tokenizer_name,
+            "sequence_length": sequence_length,
+            "decode_length": decode_length,
+            "top_n_tokens": top_n_tokens,
+            "n_runs": n_runs,
+            "warmups": warmups,
+            "temperature": temperature,
+            "top_k": top_k,
+            "top_p": top_p,
+            "typical_p": typical_p,
+            "repetition_penalty": repetition_penalty,
+            "frequency_penalty": frequency_penalty,
+            "watermark": watermark,
+            "do_sample": do_sample,
+        });
+        Results::new(parameters, &app.data).write(&results_file)?;
+    }
 
     let parameters_table = table::parameters_table(
         tokenizer_name,
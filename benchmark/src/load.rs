@@ -0,0 +1,444 @@
+/// Open-loop load generation against the router.
+/// Requests arrive at a given rate whatever the latency of the previous ones, so that the
+/// queueing of the router is measured as well.
+use crate::dataset::{read_dataset, DatasetRequest};
+use crate::grammar::{GrammarWorkload, RequestKind};
+use crate::results::{GrammarResults, OpenLoopResults};
+use futures_util::{FutureExt, StreamExt};
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tabled::{builder::Builder, settings::Style};
+use tokenizers::Tokenizer;
+
+/// Distribution of the arrival times of the requests
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Arrivals {
+    /// One request every `1 / rate` seconds
+    Constant,
+    /// Exponentially distributed inter-arrival times of mean `1 / rate` seconds
+    Poisson,
+    /// Bursts of `burst_size` simultaneous requests, arriving as a Poisson process
+    Bursty,
+}
+
+/// Timings of a request, in milliseconds
+#[derive(Debug)]
+pub(crate) struct RequestTimings {
+    pub(crate) time_to_first_token: f64,
+    pub(crate) inter_token_latency: f64,
+    pub(crate) end_to_end_latency: f64,
+    pub(crate) queue_time: f64,
+    pub(crate) generated_tokens: u64,
+    pub(crate) kind: RequestKind,
+    /// Gaps between the tokens received, only measured when streaming
+    pub(crate) token_gaps: Vec<f64>,
+}
+
+/// Arrival times of the requests sent during `duration`, from the start of the benchmark
+fn arrival_times(
+    arrivals: Arrivals,
+    rate: f64,
+    burst_size: usize,
+    duration: Duration,
+) -> Vec<Duration> {
+    let mut rng = rand::thread_rng();
+    // Exponentially distributed with mean `mean`
+    let mut exponential = |mean: f64| -(1.0 - rng.gen::<f64>()).ln() * mean;
+
+    let mut times = Vec::new();
+    let mut time = 0.0;
+    loop {
+        let (gap, requests) = match arrivals {
+            Arrivals::Constant => (1.0 / rate, 1),
+            Arrivals::Poisson => (exponential(1.0 / rate), 1),
+            Arrivals::Bursty => (exponential(burst_size as f64 / rate), burst_size),
+        };
+        time += gap;
+        if time >= duration.as_secs_f64() {
+            return times;
+        }
+        times.extend(std::iter::repeat(Duration::from_secs_f64(time)).take(requests));
+    }
+}
+
+/// Parse a timing header of the router, in milliseconds
+fn header_ms(headers: &HeaderMap, name: &str) -> f64 {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(f64::NAN)
+}
+
+async fn send_request(
+    client: reqwest::Client,
+    url: String,
+    body: serde_json::Value,
+    kind: RequestKind,
+) -> Result<RequestTimings, String> {
+    let start = Instant::now();
+    let response = client
+        .post(url)
+        .json(&body)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(response.status().to_string());
+    }
+    let headers = response.headers().clone();
+    response.bytes().await.map_err(|err| err.to_string())?;
+
+    // The router does not stream the response, the first token is sent after its prefill
+    let queue_time = header_ms(&headers, "x-queue-time");
+    Ok(RequestTimings {
+        time_to_first_token: header_ms(&headers, "x-validation-time")
+            + queue_time
+            + header_ms(&headers, "x-prefill-time"),
+        inter_token_latency: header_ms(&headers, "x-time-per-token"),
+        end_to_end_latency: start.elapsed().as_micros() as f64 / 1000.0,
+        queue_time,
+        generated_tokens: headers
+            .get("x-generated-tokens")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0),
+        kind,
+        token_gaps: Vec::new(),
+    })
+}
+
+/// Send a request to `/generate_stream` and time the tokens as they are received
+pub(crate) async fn send_streaming_request(
+    client: reqwest::Client,
+    url: String,
+    body: serde_json::Value,
+    kind: RequestKind,
+) -> Result<RequestTimings, String> {
+    let start = Instant::now();
+    let response = client
+        .post(url)
+        .json(&body)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(response.status().to_string());
+    }
+
+    let mut first_token = None;
+    let mut last_token = start;
+    let mut token_gaps = Vec::new();
+    let mut buffer = Vec::new();
+    let mut chunks = response.bytes_stream();
+    while let Some(chunk) = chunks.next().await {
+        buffer.extend_from_slice(&chunk.map_err(|err| err.to_string())?);
+        // Server-sent events are separated by an empty line
+        while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+            let event: Vec<u8> = buffer.drain(..end + 2).collect();
+            for line in String::from_utf8_lossy(&event).lines() {
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+                let data: serde_json::Value =
+                    serde_json::from_str(data.trim()).map_err(|err| err.to_string())?;
+                if let Some(error) = data.get("error") {
+                    return Err(error.to_string());
+                }
+                if data.get("token").is_none() {
+                    continue;
+                }
+                let now = Instant::now();
+                match first_token {
+                    None => first_token = Some(now),
+                    Some(_) => token_gaps.push((now - last_token).as_micros() as f64 / 1000.0),
+                }
+                last_token = now;
+            }
+        }
+    }
+
+    let first_token = first_token.ok_or("no token received")?;
+    let generated_tokens = token_gaps.len() as u64 + 1;
+    Ok(RequestTimings {
+        time_to_first_token: (first_token - start).as_micros() as f64 / 1000.0,
+        inter_token_latency: token_gaps.iter().sum::<f64>() / token_gaps.len().max(1) as f64,
+        end_to_end_latency: start.elapsed().as_micros() as f64 / 1000.0,
+        // Not reported by the router when streaming
+        queue_time: f64::NAN,
+        generated_tokens,
+        kind,
+        token_gaps,
+    })
+}
+
+/// Bodies of the requests: the prompts of the dataset, or a synthetic sequence of
+/// `sequence_length` tokens
+pub(crate) fn request_bodies(
+    tokenizer: Tokenizer,
+    sequence_length: u32,
+    decode_length: u32,
+    dataset: Option<&Path>,
+    generation_parameters: serde_json::Value,
+) -> Result<Vec<serde_json::Value>, std::io::Error> {
+    let requests = match dataset {
+        Some(dataset) => {
+            let requests = read_dataset(dataset, &tokenizer)?;
+            let new_tokens: u64 = requests.iter().map(|r| r.max_new_tokens as u64).sum();
+            tracing::info!(
+                "Replaying {} requests of {}, {:.1} new tokens on average",
+                requests.len(),
+                dataset.display(),
+                new_tokens as f64 / requests.len() as f64
+            );
+            requests
+        }
+        None => vec![DatasetRequest {
+            prompt: crate::generation::create_sequence(sequence_length, tokenizer),
+            max_new_tokens: decode_length,
+        }],
+    };
+    Ok(requests
+        .into_iter()
+        .map(|request| {
+            let mut parameters = generation_parameters.clone();
+            parameters["max_new_tokens"] = request.max_new_tokens.into();
+            json!({ "inputs": request.prompt, "parameters": parameters })
+        })
+        .collect())
+}
+
+/// Run the open-loop benchmark against the router at `router_url`
+#[allow(clippy::too_many_arguments)]
+pub async fn run_open_loop(
+    router_url: String,
+    tokenizer: Tokenizer,
+    sequence_length: u32,
+    decode_length: u32,
+    rate: f64,
+    arrivals: Arrivals,
+    burst_size: usize,
+    duration: Duration,
+    temperature: Option<f32>,
+    top_k: Option<u32>,
+    top_p: Option<f32>,
+    typical_p: Option<f32>,
+    repetition_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    watermark: bool,
+    do_sample: bool,
+    top_n_tokens: Option<u32>,
+    results_file: Option<PathBuf>,
+    dataset: Option<PathBuf>,
+    grammars: Vec<PathBuf>,
+    grammar_ratio: f64,
+    grammar_unique_ratio: f64,
+    stream: bool,
+) -> Result<(), std::io::Error> {
+    if !(rate > 0.0 && rate.is_finite()) || burst_size == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "`rate` and `burst_size` must be positive",
+        ));
+    }
+    let generation_parameters = json!({
+        "temperature": temperature,
+        "top_k": top_k,
+        "top_p": top_p,
+        "typical_p": typical_p,
+        "repetition_penalty": repetition_penalty,
+        "frequency_penalty": frequency_penalty,
+        "watermark": watermark,
+        "do_sample": do_sample,
+        "top_n_tokens": top_n_tokens,
+    });
+    let bodies = request_bodies(
+        tokenizer,
+        sequence_length,
+        decode_length,
+        dataset.as_deref(),
+        generation_parameters,
+    )?;
+    let route = if stream {
+        "generate_stream"
+    } else {
+        "generate"
+    };
+    let url = format!("{}/{route}", router_url.trim_end_matches('/'));
+    let client = reqwest::Client::new();
+
+    let times = arrival_times(arrivals, rate, burst_size, duration);
+    tracing::info!(
+        "Sending {} requests in {duration:?} ({arrivals:?} arrivals at {rate} requests/s)",
+        times.len()
+    );
+
+    // Constrain a fraction of the requests with the grammars
+    let mut workload = GrammarWorkload::new(&grammars, grammar_ratio, grammar_unique_ratio)?;
+    let mut rng = rand::thread_rng();
+    let schedule: Vec<(Duration, serde_json::Value, RequestKind)> = times
+        .into_iter()
+        .enumerate()
+        .map(|(i, time)| {
+            let mut body = bodies[i % bodies.len()].clone();
+            let (grammar, kind) = workload.next(&mut rng);
+            if let Some(grammar) = grammar {
+                body["parameters"]["grammar"] = grammar;
+            }
+            (time, body, kind)
+        })
+        .collect();
+
+    let start = tokio::time::Instant::now();
+    let mut tasks = Vec::with_capacity(schedule.len());
+    for (time, body, kind) in schedule {
+        tokio::time::sleep_until(start + time).await;
+        let request = if stream {
+            send_streaming_request(client.clone(), url.clone(), body, kind).boxed()
+        } else {
+            send_request(client.clone(), url.clone(), body, kind).boxed()
+        };
+        tasks.push(tokio::spawn(request));
+    }
+    let mut timings = Vec::with_capacity(tasks.len());
+    let mut errors = 0;
+    for task in tasks {
+        match task.await {
+            Ok(Ok(request_timings)) => timings.push(request_timings),
+            Ok(Err(err)) => {
+                tracing::debug!("Request failed: {err}");
+                errors += 1;
+            }
+            Err(_) => errors += 1,
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let parameters = json!({
+        "router_url": router_url,
+        "dataset": dataset,
+        "stream": stream,
+        "grammars": grammars,
+        "grammar_ratio": grammar_ratio,
+        "grammar_unique_ratio": grammar_unique_ratio,
+        "sequence_length": sequence_length,
+        "decode_length": decode_length,
+        "rate": rate,
+        "arrivals": format!("{arrivals:?}").to_lowercase(),
+        "burst_size": burst_size,
+        "duration": duration.as_secs_f64(),
+        "top_n_tokens": top_n_tokens,
+        "temperature": temperature,
+        "top_k": top_k,
+        "top_p": top_p,
+        "typical_p": typical_p,
+        "repetition_penalty": repetition_penalty,
+        "frequency_penalty": frequency_penalty,
+        "watermark": watermark,
+        "do_sample": do_sample,
+    });
+    let results = OpenLoopResults::new(parameters, &timings, errors, elapsed, !grammars.is_empty());
+    println!("\n{}\n", open_loop_table(&results));
+    if let Some(grammar) = &results.grammar {
+        println!("\n{}\n", grammar_table(grammar));
+    }
+    println!(
+        "{} requests, {} errors, {:.2} requests/secs, {:.2} tokens/secs\n",
+        results.requests, results.errors, results.request_throughput, results.token_throughput
+    );
+    if let Some(results_file) = results_file {
+        results.write(&results_file)?;
+    }
+    Ok(())
+}
+
+fn grammar_table(results: &GrammarResults) -> tabled::Table {
+    let mut builder = Builder::default();
+
+    builder.set_header([
+        "Requests",
+        "Count",
+        "Time to first token (p50)",
+        "Inter-token latency (p50)",
+    ]);
+    for (kind, stats) in &results.kinds {
+        builder.push_record([
+            format!("{kind:?}"),
+            stats.requests.to_string(),
+            format!("{:.2} ms", stats.time_to_first_token.p50),
+            format!("{:.2} ms", stats.inter_token_latency.p50),
+        ]);
+    }
+    builder.push_record([
+        "Compile latency".to_string(),
+        String::new(),
+        format!("{:.2} ms", results.compile_latency),
+        String::new(),
+    ]);
+    builder.push_record([
+        "Per-token overhead".to_string(),
+        String::new(),
+        String::new(),
+        format!("{:.2} ms", results.per_token_overhead),
+    ]);
+
+    let mut table = builder.build();
+    table.with(Style::markdown());
+    table
+}
+
+fn open_loop_table(results: &OpenLoopResults) -> tabled::Table {
+    let mut builder = Builder::default();
+
+    builder.set_header(["Metric", "Average", "p50", "p90", "p99"]);
+    for (name, stats) in results.latencies() {
+        builder.push_record([
+            name.to_string(),
+            format!("{:.2} ms", stats.average),
+            format!("{:.2} ms", stats.p50),
+            format!("{:.2} ms", stats.p90),
+            format!("{:.2} ms", stats.p99),
+        ]);
+    }
+
+    let mut table = builder.build();
+    table.with(Style::markdown());
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arrival_times() {
+        let duration = Duration::from_secs(10);
+
+        // One request every 100ms, before the end of the benchmark
+        let times = arrival_times(Arrivals::Constant, 10.0, 1, duration);
+        assert!((99..=100).contains(&times.len()));
+        let gap = |first: Duration, second: Duration| (second - first).as_secs_f64();
+        assert!((times[0].as_secs_f64() - 0.1).abs() < 1e-6);
+        assert!(times
+            .windows(2)
+            .all(|pair| (gap(pair[0], pair[1]) - 0.1).abs() < 1e-6));
+        assert!(times.iter().all(|time| *time <= duration));
+
+        // 1000 requests per second on average
+        let times = arrival_times(Arrivals::Poisson, 1000.0, 1, duration);
+        assert!((9000..=11000).contains(&times.len()));
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(times.iter().all(|time| *time <= duration));
+
+        // Bursts of 4 simultaneous requests
+        let times = arrival_times(Arrivals::Bursty, 1000.0, 4, duration);
+        assert!((9000..=11000).contains(&times.len()));
+        assert_eq!(times.len() % 4, 0);
+        assert!(times
+            .chunks(4)
+            .all(|burst| burst.iter().all(|time| *time == burst[0])));
+    }
+}
@@ -3,7 +3,9 @@
 /// Inspired by the great Oha app: https://github.com/hatoo/oha
 /// and: https://github.com/orhun/rust-tui-template
 use clap::Parser;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use text_generation_benchmark::Arrivals;
 use text_generation_client::v3::ShardedClient;
 use tokenizers::{FromPretrainedParameters, Tokenizer};
 use tracing_subscriber::layer::SubscriberExt;
@@ -103,6 +105,69 @@ struct Args {
     /// decoding strategies, for full doc refer to the `text-generation-server`
     #[clap(long, env)]
     top_n_tokens: Option<u32>,
+
+    /// Write the results to this JSON file: the p50/p90/p99 latencies, the throughputs and the
+    /// error count of every batch size. Useful to track performance regressions in CI.
+    #[clap(long, env)]
+    results_file: Option<PathBuf>,
+
+    /// Run without the terminal UI and exit once every batch size was benchmarked
+    #[clap(long, env)]
+    headless: bool,
+
+    /// Requests per second of the open-loop mode. When set, requests are sent to the router at
+    /// `router_url` at this rate, whatever the latency of the previous ones, instead of sending
+    /// fixed batches to the shards. This measures the queueing and the tail latency under load.
+    #[clap(long, env)]
+    rate: Option<f64>,
+
+    /// Distribution of the arrival times of the open-loop mode
+    #[clap(default_value = "poisson", long, env, value_enum)]
+    arrivals: Arrivals,
+
+    /// Number of simultaneous requests of a burst, for `bursty` arrivals
+    #[clap(default_value = "10", long, env)]
+    burst_size: usize,
+
+    /// Duration of the open-loop mode, or of each level of the sweep mode, in seconds
+    #[clap(default_value = "60", long, env)]
+    duration: u64,
+
+    /// Dataset file replayed by the open-loop and sweep modes instead of sending `sequence_length` long
+    /// prompts: ShareGPT conversations or `prompt`/`completion` objects, as JSON, JSON lines or
+    /// Parquet. The number of tokens of the recorded answers is used as `max_new_tokens`.
+    #[clap(long, env)]
+    dataset: Option<PathBuf>,
+
+    /// Grammar files constraining a fraction of the requests of the open-loop mode: JSON schemas
+    /// (`.json`), `{"type": "json" | "regex", "value": ...}` grammars, or regular expressions.
+    /// The results compare the cold, warm and unconstrained requests.
+    #[clap(long, env, requires = "rate")]
+    grammar: Vec<PathBuf>,
+
+    /// Fraction of the requests constrained by one of the grammars
+    #[clap(default_value = "0.5", long, env)]
+    grammar_ratio: f64,
+
+    /// Fraction of the JSON schema constrained requests whose schema is made unique, so that
+    /// the server compiles it instead of using its cache
+    #[clap(default_value = "0.1", long, env)]
+    grammar_unique_ratio: f64,
+
+    /// Stream the responses of the open-loop mode, to measure the time to first token and the
+    /// gaps between the tokens as received by the client
+    #[clap(long, env)]
+    stream: bool,
+
+    /// Concurrency levels of the sweep mode, e.g. `1,2,4,8,16,32`. When set, each level runs as
+    /// many clients streaming requests back to back from the router for `duration` seconds, and
+    /// the latency/throughput frontier is reported.
+    #[clap(long, env, value_delimiter = ',', conflicts_with = "rate")]
+    concurrency: Vec<usize>,
+
+    /// URL of the router benchmarked by the open-loop and sweep modes
+    #[clap(default_value = "http://localhost:3000", long, env)]
+    router_url: String,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -129,6 +194,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         do_sample,
         master_shard_uds_path,
         top_n_tokens,
+        results_file,
+        headless,
+        rate,
+        arrivals,
+        burst_size,
+        duration,
+        dataset,
+        grammar,
+        grammar_ratio,
+        grammar_unique_ratio,
+        stream,
+        concurrency,
+        router_url,
     } = args;
 
     let batch_size = batch_size.unwrap_or(vec![1, 2, 4, 8, 16, 32]);
@@ -163,33 +241,47 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing::info!("Tokenizer loaded");
 
     // Launch Tokio runtime
-    tokio::runtime::Builder::new_multi_thread()
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .unwrap()
-        .block_on(async {
-            // Instantiate sharded client from the master unix socket
-            tracing::info!("Connect to model server");
-            let mut sharded_client = ShardedClient::connect_uds(master_shard_uds_path)
-                .await
-                .expect("Could not connect to server");
-            // Clear the cache; useful if the webserver rebooted
-            sharded_client
-                .clear_cache(None)
-                .await
-                .expect("Unable to clear cache");
-            tracing::info!("Connected");
-
-            // Run app
-            text_generation_benchmark::run(
-                tokenizer_name,
+        .unwrap();
+
+    if !concurrency.is_empty() {
+        runtime
+            .block_on(text_generation_benchmark::run_concurrency_sweep(
+                router_url,
                 tokenizer,
-                batch_size,
                 sequence_length,
                 decode_length,
+                concurrency,
+                Duration::from_secs(duration),
+                temperature,
+                top_k,
+                top_p,
+                typical_p,
+                repetition_penalty,
+                frequency_penalty,
+                watermark,
+                do_sample,
                 top_n_tokens,
-                runs,
-                warmups,
+                results_file,
+                dataset,
+            ))
+            .unwrap();
+        return Ok(());
+    }
+
+    if let Some(rate) = rate {
+        runtime
+            .block_on(text_generation_benchmark::run_open_loop(
+                router_url,
+                tokenizer,
+                sequence_length,
+                decode_length,
+                rate,
+                arrivals,
+                burst_size,
+                Duration::from_secs(duration),
                 temperature,
                 top_k,
                 top_p,
@@ -198,11 +290,56 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 frequency_penalty,
                 watermark,
                 do_sample,
-                sharded_client,
-            )
-            .await
+                top_n_tokens,
+                results_file,
+                dataset,
+                grammar,
+                grammar_ratio,
+                grammar_unique_ratio,
+                stream,
+            ))
             .unwrap();
-        });
+        return Ok(());
+    }
+
+    runtime.block_on(async {
+        // Instantiate sharded client from the master unix socket
+        tracing::info!("Connect to model server");
+        let mut sharded_client = ShardedClient::connect_uds(master_shard_uds_path)
+            .await
+            .expect("Could not connect to server");
+        // Clear the cache; useful if the webserver rebooted
+        sharded_client
+            .clear_cache(None)
+            .await
+            .expect("Unable to clear cache");
+        tracing::info!("Connected");
+
+        // Run app
+        text_generation_benchmark::run(
+            tokenizer_name,
+            tokenizer,
+            batch_size,
+            sequence_length,
+            decode_length,
+            top_n_tokens,
+            runs,
+            warmups,
+            temperature,
+            top_k,
+            top_p,
+            typical_p,
+            repetition_penalty,
+            frequency_penalty,
+            watermark,
+            do_sample,
+            sharded_client,
+            results_file,
+            headless,
+        )
+        .await
+        .unwrap();
+    });
     Ok(())
 }
 
@@ -0,0 +1,284 @@
+/// Machine-readable benchmark results
+use crate::app::Data;
+use crate::grammar::RequestKind;
+use crate::load::RequestTimings;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// Latency statistics, in milliseconds
+#[derive(Debug, Serialize)]
+pub(crate) struct LatencyStats {
+    pub(crate) average: f64,
+    min: f64,
+    max: f64,
+    pub(crate) p50: f64,
+    pub(crate) p90: f64,
+    pub(crate) p99: f64,
+}
+
+impl LatencyStats {
+    pub(crate) fn new(latencies: &[f64]) -> Self {
+        let mut latencies = latencies.to_vec();
+        float_ord::sort(&mut latencies);
+        let percentiles = crate::utils::percentiles(&latencies, &[50, 90, 99]);
+        let (average, min, max) = average_min_max(&latencies);
+        Self {
+            average,
+            min,
+            max,
+            p50: percentiles["p50"],
+            p90: percentiles["p90"],
+            p99: percentiles["p99"],
+        }
+    }
+}
+
+/// Throughput statistics, in tokens per second
+#[derive(Debug, Serialize)]
+pub(crate) struct ThroughputStats {
+    average: f64,
+    min: f64,
+    max: f64,
+}
+
+impl ThroughputStats {
+    fn new(throughputs: &[f64]) -> Self {
+        let (average, min, max) = average_min_max(throughputs);
+        Self { average, min, max }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BatchResults {
+    batch_size: u32,
+    runs: usize,
+    /// Prefill latency, the time to first token of every request of the batch
+    time_to_first_token: LatencyStats,
+    /// Decode latency per token
+    inter_token_latency: LatencyStats,
+    /// Prefill and full decode latency
+    end_to_end_latency: LatencyStats,
+    prefill_throughput: ThroughputStats,
+    decode_throughput: ThroughputStats,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Results {
+    parameters: serde_json::Value,
+    batches: Vec<BatchResults>,
+    errors: usize,
+}
+
+impl Results {
+    /// Results of the batch sizes with at least one completed run
+    pub(crate) fn new(parameters: serde_json::Value, data: &Data) -> Self {
+        let batches = data
+            .batch_size
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !data.end_to_end_latencies[*i].is_empty())
+            .map(|(i, &batch_size)| BatchResults {
+                batch_size,
+                runs: data.end_to_end_latencies[i].len(),
+                time_to_first_token: LatencyStats::new(&data.prefill_latencies[i]),
+                inter_token_latency: LatencyStats::new(&data.decode_token_latencies[i]),
+                end_to_end_latency: LatencyStats::new(&data.end_to_end_latencies[i]),
+                prefill_throughput: ThroughputStats::new(&data.prefill_throughputs[i]),
+                decode_throughput: ThroughputStats::new(&data.decode_throughputs[i]),
+            })
+            .collect();
+        Self {
+            parameters,
+            batches,
+            errors: data.errors,
+        }
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+/// Results of an open-loop benchmark
+#[derive(Debug, Serialize)]
+pub(crate) struct OpenLoopResults {
+    parameters: serde_json::Value,
+    /// Successful requests
+    pub(crate) requests: usize,
+    pub(crate) errors: usize,
+    /// Successful requests per second, over the whole benchmark
+    pub(crate) request_throughput: f64,
+    /// Generated tokens per second, over the whole benchmark
+    pub(crate) token_throughput: f64,
+    time_to_first_token: LatencyStats,
+    inter_token_latency: LatencyStats,
+    end_to_end_latency: LatencyStats,
+    /// Time spent in the queue of the router
+    queue_time: LatencyStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) grammar: Option<GrammarResults>,
+}
+
+/// Latencies of a kind of request of the grammar workload
+#[derive(Debug, Serialize)]
+pub(crate) struct KindResults {
+    pub(crate) requests: usize,
+    pub(crate) time_to_first_token: LatencyStats,
+    pub(crate) inter_token_latency: LatencyStats,
+}
+
+/// Cost of the grammar-constrained requests
+#[derive(Debug, Serialize)]
+pub(crate) struct GrammarResults {
+    pub(crate) kinds: BTreeMap<RequestKind, KindResults>,
+    /// Median time to first token of the cold grammars over the one of the warm grammars:
+    /// the compilation time saved by the cache of the server
+    pub(crate) compile_latency: f64,
+    /// Median inter-token latency of the warm grammars over the one of the unconstrained
+    /// requests
+    pub(crate) per_token_overhead: f64,
+}
+
+impl GrammarResults {
+    fn new(timings: &[RequestTimings]) -> Self {
+        let mut kinds = BTreeMap::new();
+        for kind in [
+            RequestKind::Unconstrained,
+            RequestKind::ColdGrammar,
+            RequestKind::WarmGrammar,
+        ] {
+            let timings: Vec<&RequestTimings> = timings
+                .iter()
+                .filter(|timing| timing.kind == kind)
+                .collect();
+            let stats = |timing: fn(&RequestTimings) -> f64| {
+                LatencyStats::new(&timings.iter().map(|t| timing(t)).collect::<Vec<_>>())
+            };
+            kinds.insert(
+                kind,
+                KindResults {
+                    requests: timings.len(),
+                    time_to_first_token: stats(|timing| timing.time_to_first_token),
+                    inter_token_latency: stats(|timing| timing.inter_token_latency),
+                },
+            );
+        }
+        let p50 = |kind, stats: fn(&KindResults) -> &LatencyStats| stats(&kinds[&kind]).p50;
+        Self {
+            compile_latency: p50(RequestKind::ColdGrammar, |k| &k.time_to_first_token)
+                - p50(RequestKind::WarmGrammar, |k| &k.time_to_first_token),
+            per_token_overhead: p50(RequestKind::WarmGrammar, |k| &k.inter_token_latency)
+                - p50(RequestKind::Unconstrained, |k| &k.inter_token_latency),
+            kinds,
+        }
+    }
+}
+
+impl OpenLoopResults {
+    pub(crate) fn new(
+        parameters: serde_json::Value,
+        timings: &[RequestTimings],
+        errors: usize,
+        elapsed: Duration,
+        grammar: bool,
+    ) -> Self {
+        let stats = |timing: fn(&RequestTimings) -> f64| {
+            LatencyStats::new(&timings.iter().map(timing).collect::<Vec<_>>())
+        };
+        let generated_tokens: u64 = timings.iter().map(|timing| timing.generated_tokens).sum();
+        Self {
+            parameters,
+            requests: timings.len(),
+            errors,
+            request_throughput: timings.len() as f64 / elapsed.as_secs_f64(),
+            token_throughput: generated_tokens as f64 / elapsed.as_secs_f64(),
+            time_to_first_token: stats(|timing| timing.time_to_first_token),
+            inter_token_latency: stats(|timing| timing.inter_token_latency),
+            end_to_end_latency: stats(|timing| timing.end_to_end_latency),
+            queue_time: stats(|timing| timing.queue_time),
+            grammar: grammar.then(|| GrammarResults::new(timings)),
+        }
+    }
+
+    pub(crate) fn latencies(&self) -> [(&'static str, &LatencyStats); 4] {
+        [
+            ("Time to first token", &self.time_to_first_token),
+            ("Inter-token latency", &self.inter_token_latency),
+            ("End-to-end latency", &self.end_to_end_latency),
+            ("Queue time", &self.queue_time),
+        ]
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+fn average_min_max(data: &[f64]) -> (f64, f64, f64) {
+    let average = data.iter().sum::<f64>() / data.len() as f64;
+    let min = data.iter().copied().min_by(f64::total_cmp);
+    let max = data.iter().copied().max_by(f64::total_cmp);
+    (average, min.unwrap_or(f64::NAN), max.unwrap_or(f64::NAN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn timings(
+        kind: RequestKind,
+        time_to_first_token: f64,
+        inter_token_latency: f64,
+    ) -> RequestTimings {
+        RequestTimings {
+            time_to_first_token,
+            inter_token_latency,
+            end_to_end_latency: time_to_first_token + 10.0 * inter_token_latency,
+            queue_time: 1.0,
+            generated_tokens: 10,
+            kind,
+            token_gaps: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_latency_stats() {
+        let latencies: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+        let stats = LatencyStats::new(&latencies);
+        assert_eq!(stats.average, 50.5);
+        assert_eq!((stats.min, stats.max), (1.0, 100.0));
+        assert_eq!((stats.p50, stats.p90, stats.p99), (51.0, 91.0, 100.0));
+
+        let stats = LatencyStats::new(&[]);
+        assert!(stats.average.is_nan() && stats.min.is_nan() && stats.p50.is_nan());
+    }
+
+    #[test]
+    fn test_grammar_results() {
+        let timings = [
+            timings(RequestKind::Unconstrained, 20.0, 10.0),
+            timings(RequestKind::ColdGrammar, 120.0, 12.0),
+            timings(RequestKind::WarmGrammar, 25.0, 12.0),
+            timings(RequestKind::WarmGrammar, 25.0, 12.0),
+        ];
+        let results = OpenLoopResults::new(json!({}), &timings, 0, Duration::from_secs(2), true);
+        assert_eq!(results.requests, 4);
+        assert_eq!(results.request_throughput, 2.0);
+        assert_eq!(results.token_throughput, 20.0);
+
+        let grammar = results.grammar.unwrap();
+        assert_eq!(grammar.kinds[&RequestKind::WarmGrammar].requests, 2);
+        assert_eq!(grammar.compile_latency, 95.0);
+        assert_eq!(grammar.per_token_overhead, 2.0);
+
+        let results = OpenLoopResults::new(json!({}), &timings, 0, Duration::from_secs(2), false);
+        assert!(results.grammar.is_none());
+    }
+}
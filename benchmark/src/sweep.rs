@@ -0,0 +1,280 @@
+/// Closed-loop sweep over concurrency levels, streaming the responses of the router
+use crate::grammar::RequestKind;
+use crate::load::{request_bodies, send_streaming_request, RequestTimings};
+use crate::results::LatencyStats;
+use serde::Serialize;
+use serde_json::json;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tabled::{builder::Builder, settings::Style};
+use tokenizers::Tokenizer;
+
+/// Results of a concurrency level
+#[derive(Debug, Serialize)]
+struct LevelResults {
+    concurrency: usize,
+    requests: usize,
+    errors: usize,
+    /// Successful requests per second
+    request_throughput: f64,
+    /// Generated tokens per second
+    token_throughput: f64,
+    time_to_first_token: LatencyStats,
+    /// Gaps between the tokens of all the requests
+    inter_token_gap: LatencyStats,
+    end_to_end_latency: LatencyStats,
+}
+
+impl LevelResults {
+    fn new(
+        concurrency: usize,
+        timings: &[RequestTimings],
+        errors: usize,
+        elapsed: Duration,
+    ) -> Self {
+        let gaps: Vec<f64> = timings
+            .iter()
+            .flat_map(|timing| timing.token_gaps.iter().copied())
+            .collect();
+        let generated_tokens: u64 = timings.iter().map(|timing| timing.generated_tokens).sum();
+        let stats = |timing: fn(&RequestTimings) -> f64| {
+            LatencyStats::new(&timings.iter().map(timing).collect::<Vec<_>>())
+        };
+        Self {
+            concurrency,
+            requests: timings.len(),
+            errors,
+            request_throughput: timings.len() as f64 / elapsed.as_secs_f64(),
+            token_throughput: generated_tokens as f64 / elapsed.as_secs_f64(),
+            time_to_first_token: stats(|timing| timing.time_to_first_token),
+            inter_token_gap: LatencyStats::new(&gaps),
+            end_to_end_latency: stats(|timing| timing.end_to_end_latency),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SweepResults {
+    parameters: serde_json::Value,
+    levels: Vec<LevelResults>,
+}
+
+/// Run `concurrency` clients sending streaming requests back to back for `duration`
+async fn run_level(
+    client: &reqwest::Client,
+    url: &str,
+    bodies: &Arc<Vec<serde_json::Value>>,
+    concurrency: usize,
+    duration: Duration,
+) -> LevelResults {
+    let start = tokio::time::Instant::now();
+    let next_request = Arc::new(AtomicUsize::new(0));
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let (client, url) = (client.clone(), url.to_string());
+            let (bodies, next_request) = (bodies.clone(), next_request.clone());
+            tokio::spawn(async move {
+                let mut results = Vec::new();
+                while start.elapsed() < duration {
+                    let i = next_request.fetch_add(1, Ordering::Relaxed);
+                    let body = bodies[i % bodies.len()].clone();
+                    let kind = RequestKind::Unconstrained;
+                    results.push(
+                        send_streaming_request(client.clone(), url.clone(), body, kind).await,
+                    );
+                }
+                results
+            })
+        })
+        .collect();
+
+    let mut timings = Vec::new();
+    let mut errors = 0;
+    for worker in workers {
+        for result in worker.await.unwrap_or_default() {
+            match result {
+                Ok(request_timings) => timings.push(request_timings),
+                Err(err) => {
+                    tracing::debug!("Request failed: {err}");
+                    errors += 1;
+                }
+            }
+        }
+    }
+    LevelResults::new(concurrency, &timings, errors, start.elapsed())
+}
+
+/// Measure the latency/throughput frontier of the router at `router_url`: at each concurrency
+/// level, clients stream requests back to back during `duration`
+#[allow(clippy::too_many_arguments)]
+pub async fn run_concurrency_sweep(
+    router_url: String,
+    tokenizer: Tokenizer,
+    sequence_length: u32,
+    decode_length: u32,
+    concurrency: Vec<usize>,
+    duration: Duration,
+    temperature: Option<f32>,
+    top_k: Option<u32>,
+    top_p: Option<f32>,
+    typical_p: Option<f32>,
+    repetition_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    watermark: bool,
+    do_sample: bool,
+    top_n_tokens: Option<u32>,
+    results_file: Option<PathBuf>,
+    dataset: Option<PathBuf>,
+) -> Result<(), std::io::Error> {
+    if concurrency.contains(&0) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "`concurrency` levels must be positive",
+        ));
+    }
+    let generation_parameters = json!({
+        "temperature": temperature,
+        "top_k": top_k,
+        "top_p": top_p,
+        "typical_p": typical_p,
+        "repetition_penalty": repetition_penalty,
+        "frequency_penalty": frequency_penalty,
+        "watermark": watermark,
+        "do_sample": do_sample,
+        "top_n_tokens": top_n_tokens,
+    });
+    let bodies = Arc::new(request_bodies(
+        tokenizer,
+        sequence_length,
+        decode_length,
+        dataset.as_deref(),
+        generation_parameters.clone(),
+    )?);
+    let url = format!("{}/generate_stream", router_url.trim_end_matches('/'));
+    let client = reqwest::Client::new();
+
+    let mut levels = Vec::with_capacity(concurrency.len());
+    for &level in &concurrency {
+        tracing::info!("Benchmarking {level} concurrent clients for {duration:?}");
+        let results = run_level(&client, &url, &bodies, level, duration).await;
+        tracing::info!(
+            "{level} concurrent clients: {:.2} requests/secs, {:.2} tokens/secs",
+            results.request_throughput,
+            results.token_throughput
+        );
+        levels.push(results);
+    }
+
+    let results = SweepResults {
+        parameters: json!({
+            "router_url": router_url,
+            "sequence_length": sequence_length,
+            "decode_length": decode_length,
+            "dataset": dataset,
+            "concurrency": concurrency,
+            "duration": duration.as_secs_f64(),
+            "generation_parameters": generation_parameters,
+        }),
+        levels,
+    };
+    println!("\n{}\n", sweep_table(&results));
+    if let Some(results_file) = results_file {
+        let file = std::fs::File::create(results_file)?;
+        serde_json::to_writer_pretty(file, &results)?;
+    }
+    Ok(())
+}
+
+/// Latency/throughput frontier
+fn sweep_table(results: &SweepResults) -> tabled::Table {
+    let mut builder = Builder::default();
+
+    builder.set_header([
+        "Concurrency",
+        "Requests/secs",
+        "Tokens/secs",
+        "TTFT p50",
+        "TTFT p99",
+        "Inter-token p50",
+        "Inter-token p99",
+        "End-to-end p99",
+        "Errors",
+    ]);
+    for level in &results.levels {
+        builder.push_record([
+            level.concurrency.to_string(),
+            format!("{:.2}", level.request_throughput),
+            format!("{:.2}", level.token_throughput),
+            format!("{:.2} ms", level.time_to_first_token.p50),
+            format!("{:.2} ms", level.time_to_first_token.p99),
+            format!("{:.2} ms", level.inter_token_gap.p50),
+            format!("{:.2} ms", level.inter_token_gap.p99),
+            format!("{:.2} ms", level.end_to_end_latency.p99),
+            level.errors.to_string(),
+        ]);
+    }
+
+    let mut table = builder.build();
+    table.with(Style::markdown());
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings(time_to_first_token: f64, token_gaps: Vec<f64>) -> RequestTimings {
+        RequestTimings {
+            time_to_first_token,
+            inter_token_latency: f64::NAN,
+            end_to_end_latency: time_to_first_token + token_gaps.iter().sum::<f64>(),
+            queue_time: f64::NAN,
+            generated_tokens: token_gaps.len() as u64 + 1,
+            kind: RequestKind::Unconstrained,
+            token_gaps,
+        }
+    }
+
+    #[test]
+    fn test_level_results() {
+        let level = LevelResults::new(
+            2,
+            &[timings(10.0, vec![1.0, 3.0]), timings(30.0, vec![2.0])],
+            1,
+            Duration::from_secs(2),
+        );
+        assert_eq!(level.requests, 2);
+        assert_eq!(level.errors, 1);
+        assert_eq!(level.request_throughput, 1.0);
+        assert_eq!(level.token_throughput, 2.5);
+        assert_eq!(level.time_to_first_token.average, 20.0);
+        // The gaps of every request
+        assert_eq!(level.inter_token_gap.average, 2.0);
+        assert_eq!(level.inter_token_gap.p50, 2.0);
+        assert_eq!(level.end_to_end_latency.p99, 32.0);
+    }
+
+    #[test]
+    fn test_sweep_table() {
+        let results = SweepResults {
+            parameters: json!({}),
+            levels: [1, 8]
+                .into_iter()
+                .map(|concurrency| {
+                    let timings = vec![timings(10.0 * concurrency as f64, vec![1.0])];
+                    LevelResults::new(concurrency, &timings, 0, Duration::from_secs(1))
+                })
+                .collect(),
+        };
+        // One row per concurrency level, in order
+        let table = sweep_table(&results).to_string();
+        let rows: Vec<&str> = table.lines().skip(2).collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[0].starts_with("| 1 "));
+        assert!(rows[0].contains("10.00 ms"));
+        assert!(rows[1].starts_with("| 8 "));
+        assert!(rows[1].contains("80.00 ms"));
+    }
+}
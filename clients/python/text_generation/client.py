@@ -166,7 +166,7 @@ class Client:
         messages: List[Message],
         repetition_penalty: Optional[float] = None,
         frequency_penalty: Optional[float] = None,
-        logit_bias: Optional[List[float]] = None,
+        logit_bias: Optional[Dict[int, float]] = None,
         logprobs: Optional[bool] = None,
         top_logprobs: Optional[int] = None,
         max_tokens: Optional[int] = None,
@@ -194,8 +194,8 @@ class Client:
                 The parameter for frequency penalty. 0.0 means no penalty
                 Penalize new tokens based on their existing frequency in the text so far,
                 decreasing the model's likelihood to repeat the same line verbatim.
-            logit_bias (`List[float]`):
-                Adjust the likelihood of specified tokens
+            logit_bias (`Dict[int, float]`):
+                Bias added to the logits of the given token ids, between -100 and 100
             logprobs (`bool`):
                 Include log probabilities in the response
             top_logprobs (`int`):
@@ -648,7 +648,7 @@ class AsyncClient:
         messages: List[Message],
         repetition_penalty: Optional[float] = None,
         frequency_penalty: Optional[float] = None,
-        logit_bias: Optional[List[float]] = None,
+        logit_bias: Optional[Dict[int, float]] = None,
         logprobs: Optional[bool] = None,
         top_logprobs: Optional[int] = None,
         max_tokens: Optional[int] = None,
@@ -676,8 +676,8 @@ class AsyncClient:
                 The parameter for frequency penalty. 0.0 means no penalty
                 Penalize new tokens based on their existing frequency in the text so far,
                 decreasing the model's likelihood to repeat the same line verbatim.
-            logit_bias (`List[float]`):
-                Adjust the likelihood of specified tokens
+            logit_bias (`Dict[int, float]`):
+                Bias added to the logits of the given token ids, between -100 and 100
             logprobs (`bool`):
                 Include log probabilities in the response
             top_logprobs (`int`):
@@ -1,6 +1,6 @@
 from enum import Enum
 from pydantic import BaseModel, field_validator, ConfigDict
-from typing import Optional, List, Union, Any
+from typing import Optional, List, Union, Any, Dict
 
 from text_generation.errors import ValidationError
 
@@ -8,15 +8,22 @@ from text_generation.errors import ValidationError
 # enum for grammar type
 class GrammarType(str, Enum):
     Json = "json"
+    JsonObject = "json_object"
     Regex = "regex"
+    Choice = "choice"
+    Ebnf = "ebnf"
+    Lark = "lark"
+    Registry = "registry"
+    Compiled = "compiled"
+    Union = "union"
 
 
 # Grammar type and value
 class Grammar(BaseModel):
     # Grammar type
     type: GrammarType
-    # Grammar value
-    value: Union[str, dict]
+    # Grammar value, optional for `json_object`
+    value: Optional[Union[str, dict, List[str], List["Grammar"]]] = None
 
 
 class ToolCall(BaseModel):
@@ -131,7 +138,7 @@ class ChatRequest(BaseModel):
     # decreasing the model's likelihood to repeat the same line verbatim.
     frequency_penalty: Optional[float] = None
     # Bias values for token selection
-    logit_bias: Optional[List[float]] = None
+    logit_bias: Optional[Dict[int, float]] = None
     # Whether to return log probabilities
     logprobs: Optional[bool] = None
     # Number of most likely tokens to return at each position
@@ -380,6 +387,8 @@ class FinishReason(str, Enum):
     EndOfSequenceToken = "eos_token"
     # the model generated a text included in `stop_sequences`
     StopSequence = "stop_sequence"
+    # the router stopped a generation that fell out of its grammar
+    GrammarViolation = "grammar_violation"
 
 
 # Additional sequences when using the `best_of` parameter
@@ -0,0 +1,167 @@
+/// Hot reload of the LoRA adapters listed by a directory or a manifest file
+use crate::LauncherError;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use text_generation_client::v3::ShardedClient;
+
+/// Interval between two reads of the adapters source
+const ADAPTERS_WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Adapters of `source`: the subdirectories of a directory, or the lines of a manifest file.
+/// A manifest lists one hub id or path per line, `#` starts a comment.
+fn read_adapters(source: &Path) -> io::Result<BTreeSet<String>> {
+    if source.is_dir() {
+        let mut adapters = BTreeSet::new();
+        for entry in fs::read_dir(source)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                adapters.insert(path.to_string_lossy().to_string());
+            }
+        }
+        return Ok(adapters);
+    }
+    Ok(fs::read_to_string(source)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Adapters of `adapters` that are not `loaded`, and `loaded` adapters removed from `adapters`
+fn adapter_changes(
+    loaded: &BTreeSet<String>,
+    adapters: &BTreeSet<String>,
+) -> (Vec<String>, Vec<String>) {
+    (
+        adapters.difference(loaded).cloned().collect(),
+        loaded.difference(adapters).cloned().collect(),
+    )
+}
+
+/// Load the adapters added to `source` on the running shards, and unload the removed ones.
+/// `download` fetches and converts the weights of an adapter before it is loaded.
+pub(crate) fn spawn_adapter_watcher<D>(
+    source: String,
+    master_shard_uds_path: String,
+    download: D,
+    running: Arc<AtomicBool>,
+) -> thread::JoinHandle<()>
+where
+    D: Fn(&str) -> Result<(), LauncherError> + Send + 'static,
+{
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(err) => {
+                tracing::error!("Could not start the adapter watcher: {err}");
+                return;
+            }
+        };
+        let mut client =
+            match runtime.block_on(ShardedClient::connect_uds(master_shard_uds_path.clone())) {
+                Ok(client) => client,
+                Err(err) => {
+                    tracing::error!("Adapter watcher could not connect to the shards: {err}");
+                    return;
+                }
+            };
+        tracing::info!("Watching the adapters of {source}");
+
+        let mut loaded = BTreeSet::new();
+        while running.load(Ordering::SeqCst) {
+            match read_adapters(Path::new(&source)) {
+                Ok(adapters) => {
+                    let (added, removed) = adapter_changes(&loaded, &adapters);
+                    for adapter in added {
+                        if let Err(err) = download(&adapter) {
+                            tracing::warn!("Could not download adapter {adapter}: {err}");
+                            continue;
+                        }
+                        match runtime.block_on(client.load_adapter(adapter.clone())) {
+                            Ok(index) => {
+                                tracing::info!("Loaded adapter {adapter} at index {index}");
+                                loaded.insert(adapter);
+                            }
+                            Err(err) => tracing::warn!("Could not load adapter {adapter}: {err}"),
+                        }
+                    }
+                    for adapter in removed {
+                        match runtime.block_on(client.unload_adapter(adapter.clone())) {
+                            Ok(()) => {
+                                tracing::info!("Unloaded adapter {adapter}");
+                                loaded.remove(&adapter);
+                            }
+                            Err(err) => {
+                                tracing::warn!("Could not unload adapter {adapter}: {err}")
+                            }
+                        }
+                    }
+                }
+                Err(err) => tracing::warn!("Could not read the adapters of {source}: {err}"),
+            }
+
+            let read_at = Instant::now();
+            while running.load(Ordering::SeqCst) && read_at.elapsed() < ADAPTERS_WATCH_INTERVAL {
+                sleep(Duration::from_millis(100));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adapters(ids: &[&str]) -> BTreeSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_read_adapters() {
+        let dir = std::env::temp_dir().join(format!("tgi-adapters-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("local-lora")).unwrap();
+
+        let manifest = dir.join("adapters.txt");
+        fs::write(
+            &manifest,
+            "# Adapters of the deployment\nacme/lora\n\n  acme/other  # comment\n",
+        )
+        .unwrap();
+        assert_eq!(
+            read_adapters(&manifest).unwrap(),
+            adapters(&["acme/lora", "acme/other"])
+        );
+
+        // The subdirectories of a directory
+        let local = dir.join("local-lora").to_string_lossy().to_string();
+        assert_eq!(read_adapters(&dir).unwrap(), adapters(&[&local]));
+
+        assert!(read_adapters(&dir.join("missing")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_adapter_changes() {
+        let loaded = adapters(&["acme/a", "acme/b"]);
+        assert_eq!(
+            adapter_changes(&loaded, &adapters(&["acme/b", "acme/c"])),
+            (vec!["acme/c".to_string()], vec!["acme/a".to_string()])
+        );
+        assert_eq!(adapter_changes(&loaded, &loaded), (vec![], vec![]));
+        assert_eq!(
+            adapter_changes(&loaded, &BTreeSet::new()).1,
+            vec!["acme/a".to_string(), "acme/b".to_string()]
+        );
+    }
+}
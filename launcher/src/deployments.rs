@@ -0,0 +1,369 @@
+/// Supervision of the deployments of a configuration file.
+/// Each deployment is run by its own launcher process, with its own shards and router.
+use crate::LauncherError;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Interval between two status reports of the deployments
+const STATUS_INTERVAL: Duration = Duration::from_secs(60);
+/// Delay before the first restart of a deployment, doubled at each restart
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+/// Time given to a deployment to shut down its shards and router
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// When to restart a deployment whose launcher exited
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RestartPolicy {
+    Never,
+    #[default]
+    OnFailure,
+    Always,
+}
+
+/// `deployments.<name>` table of the configuration file. Its `launcher` and `router` sections
+/// are read by the launcher of the deployment.
+#[derive(Debug, Deserialize)]
+pub(crate) struct DeploymentConfig {
+    #[serde(default)]
+    restart: RestartPolicy,
+    /// Restarts before giving up on the deployment, unlimited if not set
+    max_restarts: Option<u32>,
+    /// Environment variables of the deployment, e.g. `CUDA_VISIBLE_DEVICES`
+    #[serde(default)]
+    env: HashMap<String, String>,
+}
+
+/// Deployments of the configuration file, by name
+pub(crate) fn read_deployments(
+    config: &Value,
+) -> Result<BTreeMap<String, DeploymentConfig>, LauncherError> {
+    match config.get("deployments") {
+        None => Ok(BTreeMap::new()),
+        Some(deployments) => serde_json::from_value(deployments.clone()).map_err(|err| {
+            LauncherError::ArgumentValidation(format!("invalid `deployments`: {err}"))
+        }),
+    }
+}
+
+enum DeploymentState {
+    Running {
+        process: Child,
+        started_at: Instant,
+    },
+    /// Waiting to be restarted
+    Restarting {
+        at: Instant,
+    },
+    Stopped(ExitStatus),
+}
+
+struct Deployment {
+    name: String,
+    config: DeploymentConfig,
+    state: DeploymentState,
+    restarts: u32,
+}
+
+impl Deployment {
+    /// Delay before restarting the deployment after its launcher exited, if it must be restarted
+    fn restart_delay(&self, status: ExitStatus) -> Option<Duration> {
+        let restart = match self.config.restart {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !status.success(),
+            RestartPolicy::Always => true,
+        };
+        if !restart
+            || self
+                .config
+                .max_restarts
+                .is_some_and(|max_restarts| self.restarts >= max_restarts)
+        {
+            return None;
+        }
+        Some(
+            RESTART_BACKOFF
+                .saturating_mul(2u32.saturating_pow(self.restarts))
+                .min(MAX_RESTART_BACKOFF),
+        )
+    }
+
+    fn status(&self) -> String {
+        let state = match &self.state {
+            DeploymentState::Running {
+                process,
+                started_at,
+            } => format!(
+                "running (pid {}, up {}s)",
+                process.id(),
+                started_at.elapsed().as_secs()
+            ),
+            DeploymentState::Restarting { at } => format!(
+                "restarting in {}s",
+                at.saturating_duration_since(Instant::now()).as_secs()
+            ),
+            DeploymentState::Stopped(status) => format!("stopped ({status})"),
+        };
+        format!("{}: {state}, {} restarts", self.name, self.restarts)
+    }
+}
+
+/// Start the launcher of the deployment `name`, its output is prefixed by the name
+fn spawn_deployment(
+    launcher: &PathBuf,
+    config_file: &str,
+    name: &str,
+    config: &DeploymentConfig,
+) -> Result<Child, LauncherError> {
+    tracing::info!("Starting deployment {name}");
+    let mut process = Command::new(launcher)
+        .args(["--config-file", config_file, "--config-deployment", name])
+        .envs(&config.env)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|err| {
+            tracing::error!("Failed to start deployment {name}: {err}");
+            LauncherError::DeploymentCannotStart(name.to_string())
+        })?;
+
+    fn prefix_lines<R: Read + Send + 'static>(name: String, output: R) {
+        thread::spawn(move || {
+            for line in BufReader::new(output).lines().map_while(Result::ok) {
+                println!("[{name}] {line}");
+            }
+        });
+    }
+    prefix_lines(name.to_string(), process.stdout.take().unwrap());
+    prefix_lines(name.to_string(), process.stderr.take().unwrap());
+    Ok(process)
+}
+
+/// Check the configuration of every deployment with its launcher
+pub(crate) fn validate_deployments(
+    config_file: &str,
+    deployments: &BTreeMap<String, DeploymentConfig>,
+) -> Result<(), LauncherError> {
+    let launcher = std::env::current_exe().map_err(|err| {
+        LauncherError::ArgumentValidation(format!("could not find the launcher: {err}"))
+    })?;
+    for (name, config) in deployments {
+        let status = Command::new(&launcher)
+            .args(["--config-file", config_file, "--config-deployment", name])
+            .arg("--validate-config")
+            .envs(&config.env)
+            .status()
+            .map_err(|_| LauncherError::DeploymentCannotStart(name.clone()))?;
+        if !status.success() {
+            return Err(LauncherError::ArgumentValidation(format!(
+                "invalid configuration of deployment {name}"
+            )));
+        }
+    }
+    tracing::info!("Valid configuration of {} deployments", deployments.len());
+    Ok(())
+}
+
+/// Run every deployment and restart them according to their policy, until the launcher is asked
+/// to stop or every deployment stopped
+pub(crate) fn supervise(
+    config_file: &str,
+    deployments: BTreeMap<String, DeploymentConfig>,
+    running: Arc<AtomicBool>,
+) -> Result<(), LauncherError> {
+    let launcher = std::env::current_exe()
+        .map_err(|_| LauncherError::DeploymentCannotStart("launcher".to_string()))?;
+
+    let mut deployments = deployments
+        .into_iter()
+        .map(|(name, config)| {
+            let process = spawn_deployment(&launcher, config_file, &name, &config)?;
+            Ok(Deployment {
+                name,
+                config,
+                state: DeploymentState::Running {
+                    process,
+                    started_at: Instant::now(),
+                },
+                restarts: 0,
+            })
+        })
+        .collect::<Result<Vec<_>, LauncherError>>()?;
+
+    let report = |deployments: &[Deployment]| {
+        let statuses: Vec<String> = deployments.iter().map(Deployment::status).collect();
+        tracing::info!("Deployments: {}", statuses.join(", "));
+    };
+    let mut reported_at = Instant::now();
+
+    while running.load(Ordering::SeqCst) {
+        let mut changed = false;
+        for deployment in deployments.iter_mut() {
+            match &mut deployment.state {
+                DeploymentState::Running { process, .. } => {
+                    let Some(status) = process.try_wait().unwrap() else {
+                        continue;
+                    };
+                    tracing::warn!("Deployment {} exited: {status}", deployment.name);
+                    deployment.state = match deployment.restart_delay(status) {
+                        Some(delay) => DeploymentState::Restarting {
+                            at: Instant::now() + delay,
+                        },
+                        None => DeploymentState::Stopped(status),
+                    };
+                    changed = true;
+                }
+                DeploymentState::Restarting { at } if *at <= Instant::now() => {
+                    deployment.restarts += 1;
+                    let process = spawn_deployment(
+                        &launcher,
+                        config_file,
+                        &deployment.name,
+                        &deployment.config,
+                    )?;
+                    deployment.state = DeploymentState::Running {
+                        process,
+                        started_at: Instant::now(),
+                    };
+                    changed = true;
+                }
+                _ => {}
+            }
+        }
+
+        if changed || reported_at.elapsed() >= STATUS_INTERVAL {
+            report(&deployments);
+            reported_at = Instant::now();
+        }
+        let stopped =
+            |deployment: &Deployment| matches!(deployment.state, DeploymentState::Stopped(_));
+        if deployments.iter().all(stopped) {
+            break;
+        }
+        sleep(Duration::from_millis(100));
+    }
+
+    // Graceful termination, the launchers stop their shards and routers
+    for deployment in deployments.iter_mut() {
+        if let DeploymentState::Running { process, .. } = &deployment.state {
+            tracing::info!("Terminating deployment {}", deployment.name);
+            let _ = signal::kill(Pid::from_raw(process.id() as i32), Signal::SIGTERM);
+        }
+    }
+    let terminate_time = Instant::now();
+    for deployment in deployments.iter_mut() {
+        if let DeploymentState::Running { process, .. } = &mut deployment.state {
+            while terminate_time.elapsed() < SHUTDOWN_TIMEOUT {
+                if let Ok(Some(status)) = process.try_wait() {
+                    deployment.state = DeploymentState::Stopped(status);
+                    break;
+                }
+                sleep(Duration::from_millis(100));
+            }
+            if let DeploymentState::Running { process, .. } = &mut deployment.state {
+                tracing::info!("Killing deployment {}", deployment.name);
+                let _ = process.kill();
+                if let Ok(status) = process.wait() {
+                    deployment.state = DeploymentState::Stopped(status);
+                }
+            }
+        }
+    }
+    if !running.load(Ordering::SeqCst) {
+        report(&deployments);
+    }
+
+    let failed = deployments.iter().any(|deployment| {
+        matches!(&deployment.state, DeploymentState::Stopped(status) if !status.success())
+    });
+    match failed && running.load(Ordering::SeqCst) {
+        true => Err(LauncherError::DeploymentFailed),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn test_read_deployments() {
+        assert!(read_deployments(&json!({})).unwrap().is_empty());
+
+        let deployments = read_deployments(&json!({"deployments": {
+            "chat": {"restart": "always", "max_restarts": 3, "env": {"CUDA_VISIBLE_DEVICES": "0"}},
+            "code": {},
+        }}))
+        .unwrap();
+        assert_eq!(deployments.keys().collect::<Vec<_>>(), ["chat", "code"]);
+        let chat = &deployments["chat"];
+        assert_eq!(chat.restart, RestartPolicy::Always);
+        assert_eq!(chat.max_restarts, Some(3));
+        assert_eq!(chat.env["CUDA_VISIBLE_DEVICES"], "0");
+        let code = &deployments["code"];
+        assert_eq!(code.restart, RestartPolicy::OnFailure);
+        assert_eq!(code.max_restarts, None);
+
+        assert!(matches!(
+            read_deployments(&json!({"deployments": {"chat": {"restart": "sometimes"}}})),
+            Err(LauncherError::ArgumentValidation(_))
+        ));
+    }
+
+    #[test]
+    fn test_restart_delay() {
+        let deployment = |restart, max_restarts, restarts| Deployment {
+            name: "chat".to_string(),
+            config: DeploymentConfig {
+                restart,
+                max_restarts,
+                env: HashMap::new(),
+            },
+            state: DeploymentState::Stopped(ExitStatus::from_raw(0)),
+            restarts,
+        };
+        let (success, failure) = (ExitStatus::from_raw(0), ExitStatus::from_raw(1 << 8));
+
+        let on_failure = deployment(RestartPolicy::OnFailure, None, 0);
+        assert_eq!(on_failure.restart_delay(success), None);
+        assert_eq!(on_failure.restart_delay(failure), Some(RESTART_BACKOFF));
+        assert_eq!(
+            deployment(RestartPolicy::Always, None, 0).restart_delay(success),
+            Some(RESTART_BACKOFF)
+        );
+        assert_eq!(
+            deployment(RestartPolicy::Never, None, 0).restart_delay(failure),
+            None
+        );
+
+        // Exponential backoff, capped
+        assert_eq!(
+            deployment(RestartPolicy::OnFailure, None, 3).restart_delay(failure),
+            Some(RESTART_BACKOFF * 8)
+        );
+        assert_eq!(
+            deployment(RestartPolicy::OnFailure, None, 40).restart_delay(failure),
+            Some(MAX_RESTART_BACKOFF)
+        );
+        assert_eq!(
+            deployment(RestartPolicy::OnFailure, Some(2), 2).restart_delay(failure),
+            None
+        );
+    }
+}
@@ -16,10 +16,14 @@ use std::thread;
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 use std::{fs, io};
+use text_generation_config_file::ConfigFileError;
 use thiserror::Error;
 use tracing_subscriber::{filter::LevelFilter, EnvFilter};
 
+mod adapters;
+mod deployments;
 mod env_runtime;
+mod watchdog;
 
 #[derive(Deserialize)]
 struct RawConfig {
@@ -165,7 +169,7 @@ impl std::fmt::Display for RopeScaling {
 }
 
 /// App Configuration
-#[derive(Parser, Debug)]
+#[derive(Clone, Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     /// The name of the model to load.
@@ -312,6 +316,13 @@ struct Args {
     #[clap(long, env)]
     max_batch_total_tokens: Option<u32>,
 
+    /// Find `max_batch_total_tokens` at startup when the model cannot infer it and it is not set,
+    /// with a binary search over warmup batches of increasing size.
+    /// The search sends up to 8 extra warmup batches, which can fail with out of memory errors
+    /// in the shard logs.
+    #[clap(long, env)]
+    probe_max_batch_total_tokens: bool,
+
     /// This setting defines how many tokens can be passed before forcing the waiting
     /// queries to be put on the batch (if the size of the batch allows for it).
     /// New queries require 1 `prefill` forward, which is different from `decode`
@@ -457,12 +468,47 @@ struct Args {
     /// startup that will be available to callers via the `adapter_id` field in a request.
     #[clap(long, env)]
     lora_adapters: Option<String>,
+
+    /// Directory or manifest file of LoRA adapters loaded and unloaded while the model is served.
+    /// Each subdirectory of the directory is an adapter, the manifest lists one adapter id or path
+    /// per line. Changes are applied within 10 seconds and requests select an adapter with its
+    /// path or id in the `adapter_id` field.
+    #[clap(long, env)]
+    watch_adapters: Option<String>,
+
+    /// TOML or YAML configuration file. Its `launcher` section sets launcher arguments and its
+    /// `router` section router arguments, e.g. the authentication or the rate limits.
+    /// Flags and environment variables take precedence over the file. The router arguments set by
+    /// the launcher must be in the `launcher` section.
+    #[clap(long, env)]
+    config_file: Option<String>,
+
+    /// Deployment of the configuration file to run, its `deployments.<name>` sections override the
+    /// top-level ones. If not set and the file has deployments, the launcher runs and supervises
+    /// all of them.
+    #[clap(long, env)]
+    config_deployment: Option<String>,
+
+    /// Check the arguments and the configuration file, then exit
+    #[clap(long, env)]
+    validate_config: bool,
+
+    /// Restart the shards and the webserver when a shard crashes, stops answering health checks
+    /// or logs a NCCL, CUDA or Xid error, instead of exiting
+    #[clap(long, env)]
+    shard_watchdog: bool,
+
+    /// Restarts done by the shard watchdog before the launcher exits
+    #[clap(default_value = "5", long, env)]
+    max_shard_restarts: usize,
 }
 
 #[derive(Debug)]
 enum ShardStatus {
     Ready,
     Failed(usize),
+    /// The shard is alive but wedged, with the reason
+    Unhealthy(usize, String),
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -704,14 +750,32 @@ fn shard_manager(
     let shard_stdout_reader = BufReader::new(p.stdout.take().unwrap());
     let shard_stderr_reader = BufReader::new(p.stderr.take().unwrap());
 
+    // Report the GPU errors logged by the shard to the watchdog
+    let report_gpu_error = {
+        let status_sender = status_sender.clone();
+        move |line: &str| {
+            if let Some(error) = watchdog::gpu_error(line) {
+                let reason = format!("`{error}` in the shard output");
+                status_sender
+                    .send(ShardStatus::Unhealthy(rank, reason))
+                    .unwrap_or(());
+            }
+        }
+    };
+
     //stdout tracing thread
+    let report_stdout_gpu_error = report_gpu_error.clone();
     thread::spawn(move || {
-        log_lines(shard_stdout_reader.lines());
+        for line in shard_stdout_reader.lines().map_while(Result::ok) {
+            report_stdout_gpu_error(&line);
+            log_line(&line);
+        }
     });
     // We read stderr in another thread as it seems that lines() can block in some cases
     let (err_sender, err_receiver) = mpsc::channel();
     thread::spawn(move || {
         for line in shard_stderr_reader.lines().map_while(Result::ok) {
+            report_gpu_error(&line);
             err_sender.send(line).unwrap_or(());
         }
     });
@@ -829,12 +893,16 @@ impl TryFrom<&String> for PythonLogMessage {
     }
 }
 
+fn log_line(line: &String) {
+    match PythonLogMessage::try_from(line) {
+        Ok(log) => log.trace(),
+        Err(_) => tracing::debug!("{line}"),
+    }
+}
+
 fn log_lines<S: Sized + BufRead>(lines: Lines<S>) {
     for line in lines.map_while(Result::ok) {
-        match PythonLogMessage::try_from(&line) {
-            Ok(log) => log.trace(),
-            Err(_) => tracing::debug!("{line}"),
-        }
+        log_line(&line);
     }
 }
 
@@ -896,6 +964,12 @@ enum LauncherError {
     WebserverFailed,
     #[error("Webserver cannot start")]
     WebserverCannotStart,
+    #[error("{0}")]
+    ConfigFile(#[from] ConfigFileError),
+    #[error("Deployment {0} cannot start")]
+    DeploymentCannotStart(String),
+    #[error("Deployment failed")]
+    DeploymentFailed,
 }
 
 fn download_convert_model(
@@ -1129,6 +1203,9 @@ fn spawn_shards(
             Err(TryRecvError::Empty) => {
                 sleep(Duration::from_millis(100));
             }
+            Ok(ShardStatus::Unhealthy(rank, reason)) => {
+                tracing::warn!("Shard {rank} may be unhealthy: {reason}");
+            }
             Ok(ShardStatus::Failed(rank)) => {
                 tracing::error!("Shard {rank} failed to start");
                 shutdown_shards(shutdown, shutdown_receiver);
@@ -1206,6 +1283,10 @@ fn spawn_webserver(
         router_args.push("--disable-grammar-support".to_string());
     }
 
+    if args.probe_max_batch_total_tokens {
+        router_args.push("--probe-max-batch-total-tokens".to_string());
+    }
+
     // Tokenizer config path
     if let Some(ref tokenizer_config_path) = args.tokenizer_config_path {
         router_args.push("--tokenizer-config-path".to_string());
@@ -1260,6 +1341,16 @@ fn spawn_webserver(
         router_args.push(args.ngrok_edge.unwrap());
     }
 
+    // Router arguments not set by the launcher
+    if let Some(config_file) = args.config_file {
+        router_args.push("--config-file".to_string());
+        router_args.push(config_file);
+    }
+    if let Some(config_deployment) = args.config_deployment {
+        router_args.push("--config-deployment".to_string());
+        router_args.push(config_deployment);
+    }
+
     // Copy current process env
     let mut envs: Vec<(OsString, OsString)> = env::vars_os().collect();
 
@@ -1338,9 +1429,35 @@ fn terminate(process_name: &str, mut process: Child, timeout: Duration) -> io::R
     Ok(exit_status)
 }
 
+/// Check the `router` section of the configuration file with the router
+fn validate_router_config(
+    config_file: Option<&str>,
+    config_deployment: Option<&str>,
+) -> Result<(), LauncherError> {
+    if let Some(config_file) = config_file {
+        let mut router = Command::new("text-generation-router");
+        router.args(["--config-file", config_file, "--validate-config"]);
+        if let Some(config_deployment) = config_deployment {
+            router.args(["--config-deployment", config_deployment]);
+        }
+        let status = router.status().map_err(|err| {
+            LauncherError::ArgumentValidation(format!(
+                "could not run text-generation-router to validate the configuration: {err}"
+            ))
+        })?;
+        if !status.success() {
+            return Err(LauncherError::ArgumentValidation(format!(
+                "invalid router configuration in {config_file}"
+            )));
+        }
+    }
+    tracing::info!("Valid configuration");
+    Ok(())
+}
+
 fn main() -> Result<(), LauncherError> {
     // Pattern match configuration
-    let args: Args = Args::parse();
+    let args: Args = text_generation_config_file::parse("launcher")?;
 
     // Filter events with LOG_LEVEL
     let varname = "LOG_LEVEL";
@@ -1379,6 +1496,24 @@ fn main() -> Result<(), LauncherError> {
 
     tracing::info!("{:#?}", args);
 
+    // Supervise every deployment of the configuration file
+    if let (Some(config_file), None) = (&args.config_file, &args.config_deployment) {
+        let config = text_generation_config_file::read_config(config_file)?;
+        let deployments = deployments::read_deployments(&config)?;
+        if !deployments.is_empty() {
+            if args.validate_config {
+                return deployments::validate_deployments(config_file, &deployments);
+            }
+            let running = Arc::new(AtomicBool::new(true));
+            let r = running.clone();
+            ctrlc::set_handler(move || {
+                r.store(false, Ordering::SeqCst);
+            })
+            .expect("Error setting Ctrl-C handler");
+            return deployments::supervise(config_file, deployments, running);
+        }
+    }
+
     let get_max_position_embeddings = || -> Result<usize, Box<dyn std::error::Error>> {
         let model_id = args.model_id.clone();
         let mut path = std::path::Path::new(&args.model_id).to_path_buf();
@@ -1555,6 +1690,14 @@ fn main() -> Result<(), LauncherError> {
         }
     }
 
+    // Only check the arguments
+    if args.validate_config {
+        return validate_router_config(
+            args.config_file.as_deref(),
+            args.config_deployment.as_deref(),
+        );
+    }
+
     // Signal handler
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -1593,18 +1736,19 @@ fn main() -> Result<(), LauncherError> {
     }
 
     // Shared shutdown bool
-    let shutdown = Arc::new(AtomicBool::new(false));
+    let mut shutdown = Arc::new(AtomicBool::new(false));
     // Shared shutdown channel
     // When shutting down, the main thread will wait for all senders to be dropped
-    let (shutdown_sender, shutdown_receiver) = mpsc::channel();
+    let (shutdown_sender, mut shutdown_receiver) = mpsc::channel();
 
     // Shared channel to track shard status
-    let (status_sender, status_receiver) = mpsc::channel();
+    let (status_sender, mut status_receiver) = mpsc::channel();
+    let watchdog_status_sender = status_sender.clone();
 
     spawn_shards(
         num_shard,
         &args,
-        cuda_graphs,
+        cuda_graphs.clone(),
         max_total_tokens,
         max_input_tokens,
         max_log_level,
@@ -1622,9 +1766,15 @@ fn main() -> Result<(), LauncherError> {
         return Ok(());
     }
 
+    let master_shard_uds_path = format!("{}-0", args.shard_uds_path);
+    let watch_adapters = args.watch_adapters.clone();
+    let trust_remote_code = args.trust_remote_code;
+    let huggingface_hub_cache = args.huggingface_hub_cache.clone();
+    let weights_cache_override = args.weights_cache_override.clone();
+
     let mut webserver = spawn_webserver(
         num_shard,
-        args,
+        args.clone(),
         max_input_tokens,
         max_total_tokens,
         max_batch_prefill_tokens,
@@ -1636,15 +1786,121 @@ fn main() -> Result<(), LauncherError> {
         err
     })?;
 
+    if let Some(watch_adapters) = watch_adapters {
+        let download_running = running.clone();
+        adapters::spawn_adapter_watcher(
+            watch_adapters,
+            master_shard_uds_path,
+            move |adapter| {
+                download_convert_model(
+                    adapter,
+                    None,
+                    trust_remote_code,
+                    huggingface_hub_cache.as_deref(),
+                    weights_cache_override.as_deref(),
+                    download_running.clone(),
+                )
+            },
+            running.clone(),
+        );
+    }
+
+    let mut health_checker_stop = Arc::new(AtomicBool::new(false));
+    if args.shard_watchdog {
+        watchdog::spawn_health_checker(
+            args.shard_uds_path.clone(),
+            num_shard,
+            watchdog_status_sender,
+            health_checker_stop.clone(),
+        );
+    } else {
+        drop(watchdog_status_sender);
+    }
+    let mut shard_restarts = 0;
+
     // Default exit code
     let mut exit_code = Ok(());
 
     while running.load(Ordering::SeqCst) {
-        if let Ok(ShardStatus::Failed(rank)) = status_receiver.try_recv() {
-            tracing::error!("Shard {rank} crashed");
-            exit_code = Err(LauncherError::ShardFailed);
-            break;
+        let failure = match status_receiver.try_recv() {
+            Ok(ShardStatus::Failed(rank)) => Some(format!("Shard {rank} crashed")),
+            Ok(ShardStatus::Unhealthy(rank, reason)) if args.shard_watchdog => {
+                Some(format!("Shard {rank} is unhealthy: {reason}"))
+            }
+            // Without the watchdog, a GPU error is only reported
+            Ok(ShardStatus::Unhealthy(rank, reason)) => {
+                tracing::error!("Shard {rank} may be unhealthy: {reason}");
+                None
+            }
+            _ => None,
         };
+        if let Some(failure) = failure {
+            if !args.shard_watchdog || shard_restarts >= args.max_shard_restarts {
+                tracing::error!("{failure}");
+                exit_code = Err(LauncherError::ShardFailed);
+                break;
+            }
+
+            // The shards of a model share NCCL communicators, they restart together. The
+            // webserver stops accepting requests and is restarted to warm up the new shards.
+            // The port stays closed until the new webserver is warm and clients get connection
+            // errors meanwhile: holding the listener and the queued requests across the restart
+            // is not supported yet, it needs the router to reconnect to and warm up new shards.
+            shard_restarts += 1;
+            tracing::warn!(
+                "{failure}, restarting the shards ({shard_restarts}/{})",
+                args.max_shard_restarts
+            );
+            health_checker_stop.store(true, Ordering::SeqCst);
+            terminate("webserver", webserver, Duration::from_secs(30)).unwrap();
+            shutdown_shards(shutdown, &shutdown_receiver);
+
+            shutdown = Arc::new(AtomicBool::new(false));
+            let (shutdown_sender, new_shutdown_receiver) = mpsc::channel();
+            shutdown_receiver = new_shutdown_receiver;
+            let (status_sender, new_status_receiver) = mpsc::channel();
+            status_receiver = new_status_receiver;
+            let watchdog_status_sender = status_sender.clone();
+            spawn_shards(
+                num_shard,
+                &args,
+                cuda_graphs.clone(),
+                max_total_tokens,
+                max_input_tokens,
+                max_log_level,
+                shutdown.clone(),
+                &shutdown_receiver,
+                shutdown_sender,
+                &status_receiver,
+                status_sender,
+                running.clone(),
+            )?;
+            if !running.load(Ordering::SeqCst) {
+                shutdown_shards(shutdown, &shutdown_receiver);
+                return Ok(());
+            }
+            webserver = spawn_webserver(
+                num_shard,
+                args.clone(),
+                max_input_tokens,
+                max_total_tokens,
+                max_batch_prefill_tokens,
+                shutdown.clone(),
+                &shutdown_receiver,
+            )
+            .map_err(|err| {
+                shutdown_shards(shutdown.clone(), &shutdown_receiver);
+                err
+            })?;
+            health_checker_stop = Arc::new(AtomicBool::new(false));
+            watchdog::spawn_health_checker(
+                args.shard_uds_path.clone(),
+                num_shard,
+                watchdog_status_sender,
+                health_checker_stop.clone(),
+            );
+            continue;
+        }
 
         match webserver.try_wait().unwrap() {
             Some(_) => {
@@ -1659,6 +1915,7 @@ fn main() -> Result<(), LauncherError> {
     }
 
     // Graceful termination
+    health_checker_stop.store(true, Ordering::SeqCst);
     terminate("webserver", webserver, Duration::from_secs(90)).unwrap();
     shutdown_shards(shutdown, &shutdown_receiver);
 
@@ -0,0 +1,132 @@
+/// Detection of the shards wedged by a GPU or NCCL failure
+use crate::ShardStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use text_generation_client::v3::Client;
+
+/// Interval between two health checks of a shard
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Time given to a shard to answer a health check, a long prefill can delay the answer
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(60);
+/// Consecutive failed health checks before a shard is considered wedged
+const MAX_FAILED_HEALTH_CHECKS: u32 = 3;
+
+/// Messages of the CUDA runtime, NCCL and the NVIDIA driver after which the GPU state of a shard
+/// cannot be trusted anymore
+const GPU_ERROR_PATTERNS: [&str; 7] = [
+    "NCCL error",
+    "Watchdog caught collective operation timeout",
+    "CUDA error: an illegal memory access",
+    "CUDA error: unspecified launch failure",
+    "CUDA error: uncorrectable ECC error",
+    "CUDA error: device-side assert",
+    "NVRM: Xid",
+];
+
+/// The GPU error pattern in a log line of a shard, if any
+pub(crate) fn gpu_error(line: &str) -> Option<&'static str> {
+    GPU_ERROR_PATTERNS
+        .into_iter()
+        .find(|pattern| line.contains(pattern))
+}
+
+/// Check the health of every shard until `stop` is set.
+/// Reports `ShardStatus::Unhealthy` when a shard fails `MAX_FAILED_HEALTH_CHECKS` checks in a
+/// row, then stops. Failures are only counted once the shard answered a first check: the warmup
+/// and the CUDA graphs capture delay the answers for minutes.
+pub(crate) fn spawn_health_checker(
+    uds_path: String,
+    num_shard: usize,
+    status_sender: mpsc::Sender<ShardStatus>,
+    stop: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let _span = tracing::span!(tracing::Level::INFO, "watchdog").entered();
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(err) => {
+                tracing::error!("Could not start the shard watchdog: {err}");
+                return;
+            }
+        };
+        let mut clients = Vec::with_capacity(num_shard);
+        for rank in 0..num_shard {
+            match runtime.block_on(Client::connect_uds(format!("{uds_path}-{rank}"))) {
+                Ok(client) => clients.push(client),
+                Err(err) => {
+                    let _ = status_sender.send(ShardStatus::Unhealthy(rank, err.to_string()));
+                    return;
+                }
+            }
+        }
+
+        let mut answered = vec![false; num_shard];
+        let mut failures = vec![0; num_shard];
+        while !stop.load(Ordering::SeqCst) {
+            for (rank, client) in clients.iter_mut().enumerate() {
+                let health = runtime.block_on(async {
+                    tokio::time::timeout(HEALTH_CHECK_TIMEOUT, client.health()).await
+                });
+                let err = match health {
+                    Ok(Ok(_)) => {
+                        answered[rank] = true;
+                        failures[rank] = 0;
+                        continue;
+                    }
+                    Ok(Err(err)) => err.to_string(),
+                    Err(_) => "health check timed out".to_string(),
+                };
+                if !answered[rank] {
+                    continue;
+                }
+                failures[rank] += 1;
+                tracing::warn!(
+                    "Shard {rank} failed its health check ({}/{MAX_FAILED_HEALTH_CHECKS}): {err}",
+                    failures[rank]
+                );
+                if failures[rank] >= MAX_FAILED_HEALTH_CHECKS {
+                    let _ = status_sender.send(ShardStatus::Unhealthy(rank, err));
+                    return;
+                }
+            }
+
+            let checked_at = Instant::now();
+            while !stop.load(Ordering::SeqCst) && checked_at.elapsed() < HEALTH_CHECK_INTERVAL {
+                sleep(Duration::from_millis(100));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpu_error() {
+        assert_eq!(
+            gpu_error(
+                "RuntimeError: NCCL error in: ProcessGroupNCCL.cpp:1275, unhandled system error"
+            ),
+            Some("NCCL error")
+        );
+        assert_eq!(
+            gpu_error("RuntimeError: CUDA error: an illegal memory access was encountered"),
+            Some("CUDA error: an illegal memory access")
+        );
+        assert_eq!(
+            gpu_error("NVRM: Xid (PCI:0000:3b:00): 79, pid=1234, GPU has fallen off the bus."),
+            Some("NVRM: Xid")
+        );
+        // Unrelated lines mentioning the patterns loosely
+        assert_eq!(gpu_error("Loading adapter Xiddle/lora"), None);
+        assert_eq!(gpu_error("CUDA error: out of memory"), None);
+        assert_eq!(gpu_error("Shard ready in 12.3s"), None);
+    }
+}
@@ -9,7 +9,7 @@ use thiserror::Error;
 use tonic::transport;
 use tonic::Status;
 
-pub use v3::{Chunk, Image, Input, InputChunk};
+pub use v3::{video, Audio, Chunk, Image, Input, InputChunk, Video};
 
 #[async_trait]
 pub trait Health {
@@ -28,6 +28,10 @@ pub struct ShardInfo {
     pub device_type: String,
     pub window_size: Option<u32>,
     pub speculate: u32,
+    /// Loaded LoRA adapters, `None` if the shards do not report them
+    pub adapter_ids: Option<Vec<String>>,
+    /// The model applies the classifier-free guidance
+    pub supports_guidance: bool,
 }
 
 #[derive(Error, Debug, Clone)]
@@ -40,6 +44,17 @@ pub enum ClientError {
     EmptyResults,
 }
 
+impl ClientError {
+    /// Stable error code, used to label error metrics
+    pub fn code(&self) -> &'static str {
+        match self {
+            ClientError::Connection(_) => "shard_connection",
+            ClientError::Generation(_) => "shard_generation",
+            ClientError::EmptyResults => "shard_empty_results",
+        }
+    }
+}
+
 impl From<Status> for ClientError {
     fn from(err: Status) -> Self {
         let err = Self::Generation(err.message().to_string());
@@ -79,6 +94,24 @@ impl ChunksToString for Vec<InputChunk> {
                 let encoded = STANDARD.encode(data);
                 output.push_str(&format!("![](data:{};base64,{})", mimetype, encoded))
             }
+            Some(Chunk::Video(video)) => {
+                let source = match &video.source {
+                    Some(video::Source::Data(data)) => {
+                        format!("data:{};base64,{}", video.mimetype, STANDARD.encode(data))
+                    }
+                    Some(video::Source::Uri(uri)) => uri.clone(),
+                    None => unreachable!("Videos should always have a source"),
+                };
+                let mut hints = format!("frames={}", video.num_frames);
+                if let Some(fps) = video.fps {
+                    hints.push_str(&format!("&fps={fps}"));
+                }
+                output.push_str(&format!("![video]({source}#{hints})"))
+            }
+            Some(Chunk::Audio(Audio { data, mimetype, .. })) => {
+                let encoded = STANDARD.encode(data);
+                output.push_str(&format!("![audio](data:{};base64,{})", mimetype, encoded))
+            }
             // We don't create empty chunks, so this should be unreachable.
             None => unreachable!("Chunks should never be empty"),
         });
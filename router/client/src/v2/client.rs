@@ -7,6 +7,7 @@ use grpc_metadata::InjectTelemetryContext;
 use pb::generate::v2::text_generation_service_client::TextGenerationServiceClient;
 use pb::generate::v2::*;
 use std::cmp::min;
+use std::collections::HashMap;
 use std::time::Duration;
 use tonic::transport::{Channel, Uri};
 use tracing::instrument;
@@ -139,10 +140,28 @@ impl Client {
                     top_k: 10,
                     top_p: 0.9,
                     typical_p: 0.9,
+                    min_p: 0.0,
+                    epsilon_cutoff: 0.0,
+                    eta_cutoff: 0.0,
+                    top_a: 0.0,
+                    mirostat_mode: 0,
+                    mirostat_tau: 0.0,
+                    mirostat_eta: 0.0,
+                    grammar_fsm: None,
+                    logit_bias: HashMap::new(),
+                    bad_words: Vec::new(),
+                    guidance_scale: 1.0,
+                    negative_input_ids: Vec::new(),
+                    min_new_tokens: 0,
                     do_sample: false,
                     seed: 0,
                     repetition_penalty: 1.2,
                     frequency_penalty: 0.1,
+                    presence_penalty: 0.1,
+                    no_repeat_ngram_size: 0,
+                    dry_multiplier: 0.0,
+                    dry_base: 0.0,
+                    dry_allowed_length: 0,
                     watermark: true,
                     grammar: String::new(),
                     grammar_type: GrammarType::None as i32,
@@ -150,7 +169,9 @@ impl Client {
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens: max_total_tokens - truncate,
                     stop_sequences: vec![],
+                    stop_token_ids: vec![],
                     ignore_eos_token: true,
+                    max_time: 0.0,
                 }),
                 prefill_logprobs: true,
                 top_n_tokens: 20,
@@ -8,6 +8,7 @@ pub use client::Client;
 pub use pb::generate::v2::HealthResponse;
 pub use pb::generate::v2::{
     Batch, CachedBatch, FinishReason, GeneratedText, Generation, GrammarType, InfoResponse,
-    NextTokenChooserParameters, Request, StoppingCriteriaParameters, Tokens,
+    NextTokenChooserParameters, Request, StoppingCriteriaParameters, TokenFsm, TokenSequence,
+    Tokens,
 };
 pub use sharded_client::ShardedClient;
@@ -5,6 +5,7 @@ use crate::{ClientError, Result};
 use crate::v2::InfoResponse;
 use async_trait::async_trait;
 use futures::future::join_all;
+use std::collections::HashMap;
 use tonic::transport::Uri;
 use tracing::instrument;
 use v2::client::{DecodeTimings, PrefillTimings};
@@ -201,6 +202,8 @@ impl From<InfoResponse> for ShardInfo {
             device_type: value.device_type,
             window_size: value.window_size,
             speculate: value.speculate,
+            adapter_ids: None,
+            supports_guidance: value.supports_guidance,
         }
     }
 }
@@ -224,10 +227,28 @@ impl Health for ShardedClient {
                 top_k: 0,
                 top_p: 1.0,
                 typical_p: 1.0,
+                min_p: 0.0,
+                epsilon_cutoff: 0.0,
+                eta_cutoff: 0.0,
+                top_a: 0.0,
+                mirostat_mode: 0,
+                mirostat_tau: 0.0,
+                mirostat_eta: 0.0,
+                grammar_fsm: None,
+                logit_bias: HashMap::new(),
+                bad_words: Vec::new(),
+                guidance_scale: 1.0,
+                negative_input_ids: Vec::new(),
+                min_new_tokens: 0,
                 do_sample: false,
                 seed: 0,
                 repetition_penalty: 1.0,
                 frequency_penalty: 0.0,
+                presence_penalty: 0.0,
+                no_repeat_ngram_size: 0,
+                dry_multiplier: 0.0,
+                dry_base: 0.0,
+                dry_allowed_length: 0,
                 watermark: false,
                 grammar: String::new(),
                 grammar_type: GrammarType::None as i32,
@@ -235,7 +256,9 @@ impl Health for ShardedClient {
             stopping_parameters: Some(StoppingCriteriaParameters {
                 max_new_tokens: 1,
                 stop_sequences: vec![],
+                stop_token_ids: vec![],
                 ignore_eos_token: false,
+                max_time: 0.0,
             }),
             top_n_tokens: 0,
         };
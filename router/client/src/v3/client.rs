@@ -7,6 +7,7 @@ use grpc_metadata::InjectTelemetryContext;
 use pb::generate::v3::text_generation_service_client::TextGenerationServiceClient;
 use pb::generate::v3::*;
 use std::cmp::min;
+use std::collections::HashMap;
 use std::time::Duration;
 use tonic::transport::{Channel, Uri};
 use tracing::instrument;
@@ -77,6 +78,30 @@ impl Client {
         Ok(response)
     }
 
+    /// Get shard statistics
+    #[instrument(skip(self))]
+    pub async fn stats(&mut self) -> Result<StatsResponse> {
+        let request = tonic::Request::new(StatsRequest {}).inject_context();
+        let response = self.stub.stats(request).await?.into_inner();
+        Ok(response)
+    }
+
+    /// Load a LoRA adapter, returns its index in the model
+    #[instrument(skip(self))]
+    pub async fn load_adapter(&mut self, adapter_id: String) -> Result<u32> {
+        let request = tonic::Request::new(LoadAdapterRequest { adapter_id }).inject_context();
+        let response = self.stub.load_adapter(request).await?.into_inner();
+        Ok(response.adapter_index)
+    }
+
+    /// Remove a loaded LoRA adapter
+    #[instrument(skip(self))]
+    pub async fn unload_adapter(&mut self, adapter_id: String) -> Result<()> {
+        let request = tonic::Request::new(UnloadAdapterRequest { adapter_id }).inject_context();
+        self.stub.unload_adapter(request).await?;
+        Ok(())
+    }
+
     /// Clear the past generations cache
     #[instrument(skip(self))]
     pub async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
@@ -162,10 +187,28 @@ impl Client {
                     top_k: 10,
                     top_p: 0.9,
                     typical_p: 0.9,
+                    min_p: 0.0,
+                    epsilon_cutoff: 0.0,
+                    eta_cutoff: 0.0,
+                    top_a: 0.0,
+                    mirostat_mode: 0,
+                    mirostat_tau: 0.0,
+                    mirostat_eta: 0.0,
+                    grammar_fsm: None,
+                    logit_bias: HashMap::new(),
+                    bad_words: Vec::new(),
+                    guidance_scale: 1.0,
+                    negative_input_ids: Vec::new(),
+                    min_new_tokens: 0,
                     do_sample: false,
                     seed: 0,
                     repetition_penalty: 1.2,
                     frequency_penalty: 0.1,
+                    presence_penalty: 0.1,
+                    no_repeat_ngram_size: 0,
+                    dry_multiplier: 0.0,
+                    dry_base: 0.0,
+                    dry_allowed_length: 0,
                     watermark: true,
                     grammar: String::new(),
                     grammar_type: GrammarType::None as i32,
@@ -173,11 +216,14 @@ impl Client {
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens: max_total_tokens - truncate,
                     stop_sequences: vec![],
+                    stop_token_ids: vec![],
                     ignore_eos_token: true,
+                    max_time: 0.0,
                 }),
                 prefill_logprobs: true,
                 top_n_tokens: 20,
                 adapter_id: None,
+                input_ids: vec![],
             });
             n_tokens += max_input_length;
 
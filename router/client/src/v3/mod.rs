@@ -6,8 +6,9 @@ mod sharded_client;
 
 pub use client::Client;
 pub use pb::generate::v3::{
-    input_chunk::Chunk, Batch, CachedBatch, FinishReason, GeneratedText, Generation, GrammarType,
-    HealthResponse, Image, InfoResponse, Input, InputChunk, NextTokenChooserParameters, Request,
-    StoppingCriteriaParameters, Tokens,
+    input_chunk::Chunk, video, Audio, Batch, CachedBatch, FinishReason, GeneratedText, Generation,
+    GrammarType, HealthResponse, Image, InfoResponse, Input, InputChunk,
+    NextTokenChooserParameters, Request, StatsResponse, StoppingCriteriaParameters, TokenFsm,
+    TokenSequence, Tokens, Video,
 };
 pub use sharded_client::ShardedClient;
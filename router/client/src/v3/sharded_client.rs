@@ -5,12 +5,13 @@ use crate::{ClientError, Result};
 use crate::v3::{Chunk, InfoResponse, Input};
 use async_trait::async_trait;
 use futures::future::join_all;
+use std::collections::HashMap;
 use tonic::transport::Uri;
 use tracing::instrument;
 use v3::client::{DecodeTimings, PrefillTimings};
 use v3::{
     Batch, CachedBatch, Client, Generation, GrammarType, HealthResponse,
-    NextTokenChooserParameters, Request, StoppingCriteriaParameters,
+    NextTokenChooserParameters, Request, StatsResponse, StoppingCriteriaParameters,
 };
 
 #[derive(Debug, Clone)]
@@ -68,6 +69,46 @@ impl ShardedClient {
         join_all(futures).await.pop().unwrap()
     }
 
+    /// Get the statistics of every shard
+    #[instrument(skip(self))]
+    pub async fn stats(&mut self) -> Result<Vec<StatsResponse>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.stats())
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
+
+    /// Load a LoRA adapter on every shard, returns its index in the model
+    #[instrument(skip(self))]
+    pub async fn load_adapter(&mut self, adapter_id: String) -> Result<u32> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.load_adapter(adapter_id.clone()))
+            .collect();
+        let indices: Vec<u32> = join_all(futures).await.into_iter().collect::<Result<_>>()?;
+        // The shards assign the indices in the same order
+        if indices.windows(2).any(|pair| pair[0] != pair[1]) {
+            return Err(ClientError::Generation(format!(
+                "adapter {adapter_id} has different indices on the shards: {indices:?}"
+            )));
+        }
+        indices.first().copied().ok_or(ClientError::EmptyResults)
+    }
+
+    /// Remove a loaded LoRA adapter from every shard
+    #[instrument(skip(self))]
+    pub async fn unload_adapter(&mut self, adapter_id: String) -> Result<()> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.unload_adapter(adapter_id.clone()))
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
+
     /// Clear the past generations cache
     #[instrument(skip(self))]
     pub async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
@@ -201,6 +242,8 @@ impl From<InfoResponse> for ShardInfo {
             device_type: value.device_type,
             window_size: value.window_size,
             speculate: value.speculate,
+            adapter_ids: Some(value.adapter_ids),
+            supports_guidance: value.supports_guidance,
         }
     }
 }
@@ -227,10 +270,28 @@ impl Health for ShardedClient {
                 top_k: 0,
                 top_p: 1.0,
                 typical_p: 1.0,
+                min_p: 0.0,
+                epsilon_cutoff: 0.0,
+                eta_cutoff: 0.0,
+                top_a: 0.0,
+                mirostat_mode: 0,
+                mirostat_tau: 0.0,
+                mirostat_eta: 0.0,
+                grammar_fsm: None,
+                logit_bias: HashMap::new(),
+                bad_words: Vec::new(),
+                guidance_scale: 1.0,
+                negative_input_ids: Vec::new(),
+                min_new_tokens: 0,
                 do_sample: false,
                 seed: 0,
                 repetition_penalty: 1.0,
                 frequency_penalty: 0.0,
+                presence_penalty: 0.0,
+                no_repeat_ngram_size: 0,
+                dry_multiplier: 0.0,
+                dry_base: 0.0,
+                dry_allowed_length: 0,
                 watermark: false,
                 grammar: String::new(),
                 grammar_type: GrammarType::None as i32,
@@ -238,13 +299,16 @@ impl Health for ShardedClient {
             stopping_parameters: Some(StoppingCriteriaParameters {
                 max_new_tokens: 1,
                 stop_sequences: vec![],
+                stop_token_ids: vec![],
                 ignore_eos_token: false,
+                max_time: 0.0,
             }),
             top_n_tokens: 0,
             // Block 0 is reserved for health checks
             blocks: vec![0],
             slots: (0..16).collect(),
             adapter_id: None,
+            input_ids: vec![],
         };
         let batch = Batch {
             id: u64::MAX,
@@ -0,0 +1,350 @@
+//! A crate to complete the command line arguments of the launcher and the router with a
+//! configuration file.
+//!
+//! A single TOML or YAML file configures both binaries: its `launcher` section contains launcher
+//! arguments and its `router` section router arguments. Keys are argument names, in snake or
+//! kebab case. Nested tables only group arguments, e.g. `[router.auth]`.
+//!
+//! The file can also describe several deployments, supervised by one launcher. The sections of
+//! the `deployments.<name>` table override the top-level sections for that deployment.
+//!
+//! Command line flags take precedence over environment variables, which take precedence over the
+//! file, which takes precedence over the default values.
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches};
+use serde_json::{Map, Value};
+use std::ffi::OsString;
+use std::path::Path;
+use thiserror::Error;
+
+/// Id of the argument containing the path of the configuration file
+pub const CONFIG_FILE_ARG: &str = "config_file";
+/// Id of the argument containing the name of the deployment to configure
+pub const CONFIG_DEPLOYMENT_ARG: &str = "config_deployment";
+
+#[derive(Debug, Error)]
+pub enum ConfigFileError {
+    #[error("could not read the configuration file {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("invalid TOML configuration file: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("invalid YAML configuration file: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("the `{0}` section of the configuration file must be a table")]
+    InvalidSection(String),
+    #[error("unknown deployment `{0}` in the configuration file")]
+    UnknownDeployment(String),
+    #[error("unknown argument `{0}` in the configuration file")]
+    UnknownArgument(String),
+    #[error("invalid value of `{0}` in the configuration file: {1}")]
+    InvalidValue(String, String),
+    #[error("invalid configuration: {0}")]
+    Arguments(#[from] clap::Error),
+}
+
+/// Parse the command line arguments of the process, completed by `section` of the configuration
+/// file given by the `config_file` argument, if any, and of the deployment given by the
+/// `config_deployment` argument.
+/// Exits on invalid command line arguments, like [`clap::Parser::parse`].
+pub fn parse<P: CommandFactory + FromArgMatches>(section: &str) -> Result<P, ConfigFileError> {
+    parse_from(std::env::args_os(), section)
+}
+
+/// [`parse`] with the given command line arguments
+pub fn parse_from<P, I, T>(args: I, section: &str) -> Result<P, ConfigFileError>
+where
+    P: CommandFactory + FromArgMatches,
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    let command = P::command();
+    let matches = command
+        .clone()
+        .try_get_matches_from(&args)
+        .unwrap_or_else(|err| err.exit());
+
+    let config_file = matches
+        .try_get_one::<String>(CONFIG_FILE_ARG)
+        .ok()
+        .flatten();
+    let Some(config_file) = config_file else {
+        return Ok(P::from_arg_matches(&matches)?);
+    };
+    let deployment = matches
+        .try_get_one::<String>(CONFIG_DEPLOYMENT_ARG)
+        .ok()
+        .flatten();
+    let config = read_config(config_file)?;
+    let entries = section_entries(&config, section, deployment.map(String::as_str))?;
+    args.extend(file_arguments(&command, &matches, entries)?);
+    // The appended arguments were not set, they cannot conflict with the command line
+    let matches = command.try_get_matches_from(&args)?;
+    Ok(P::from_arg_matches(&matches)?)
+}
+
+/// Read the configuration file at `path`.
+/// The format is YAML for the `.yaml` and `.yml` extensions, TOML otherwise.
+pub fn read_config(path: &str) -> Result<Value, ConfigFileError> {
+    let content =
+        std::fs::read_to_string(path).map_err(|err| ConfigFileError::Io(path.to_string(), err))?;
+    Ok(
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => serde_yaml::from_str(&content)?,
+            _ => toml::from_str(&content)?,
+        },
+    )
+}
+
+/// Entries of `section` in `config`, nested tables flattened, overridden by the entries of the
+/// same section of `deployment`
+pub fn section_entries(
+    config: &Value,
+    section: &str,
+    deployment: Option<&str>,
+) -> Result<Vec<(String, Value)>, ConfigFileError> {
+    let mut entries = Vec::new();
+    flatten_section(config, section, &mut entries)?;
+    if let Some(name) = deployment {
+        let deployment = config
+            .get("deployments")
+            .and_then(|deployments| deployments.get(name))
+            .ok_or_else(|| ConfigFileError::UnknownDeployment(name.to_string()))?;
+        flatten_section(deployment, section, &mut entries)?;
+    }
+
+    // The last entry of an argument wins
+    let mut deduplicated: Vec<(String, Value)> = Vec::with_capacity(entries.len());
+    for (key, value) in entries {
+        let id = key.replace('-', "_");
+        deduplicated.retain(|(previous, _)| previous.replace('-', "_") != id);
+        deduplicated.push((key, value));
+    }
+    Ok(deduplicated)
+}
+
+fn flatten_section(
+    config: &Value,
+    section: &str,
+    entries: &mut Vec<(String, Value)>,
+) -> Result<(), ConfigFileError> {
+    match config.get(section) {
+        None | Some(Value::Null) => Ok(()),
+        Some(Value::Object(table)) => {
+            flatten(table, entries);
+            Ok(())
+        }
+        Some(_) => Err(ConfigFileError::InvalidSection(section.to_string())),
+    }
+}
+
+fn flatten(table: &Map<String, Value>, entries: &mut Vec<(String, Value)>) {
+    for (key, value) in table {
+        match value {
+            Value::Object(group) => flatten(group, entries),
+            value => entries.push((key.clone(), value.clone())),
+        }
+    }
+}
+
+/// Command line flags of the `entries` whose argument was not set on the command line or by an
+/// environment variable
+fn file_arguments(
+    command: &Command,
+    matches: &ArgMatches,
+    entries: Vec<(String, Value)>,
+) -> Result<Vec<OsString>, ConfigFileError> {
+    let mut args = Vec::new();
+    for (key, value) in entries {
+        let id = key.replace('-', "_");
+        let (arg, long) = command
+            .get_arguments()
+            .find(|arg| {
+                arg.get_id() == id.as_str() && id != CONFIG_FILE_ARG && id != CONFIG_DEPLOYMENT_ARG
+            })
+            .and_then(|arg| Some((arg, arg.get_long()?)))
+            .ok_or_else(|| ConfigFileError::UnknownArgument(key.clone()))?;
+        if matches!(
+            matches.value_source(&id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+
+        let invalid = |reason: &str| ConfigFileError::InvalidValue(key.clone(), reason.to_string());
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, Value::Bool(true)) => args.push(format!("--{long}").into()),
+            (ArgAction::SetTrue, Value::Bool(false)) => {}
+            (ArgAction::SetTrue, _) => return Err(invalid("expected a boolean")),
+            (_, Value::Array(values)) => {
+                for value in values {
+                    let value =
+                        scalar(&value).ok_or_else(|| invalid("expected a list of values"))?;
+                    args.push(format!("--{long}={value}").into());
+                }
+            }
+            (_, value) => {
+                let value = scalar(&value)
+                    .ok_or_else(|| invalid("expected a string, number or boolean"))?;
+                args.push(format!("--{long}={value}").into());
+            }
+        }
+    }
+    Ok(args)
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser, Debug, PartialEq)]
+    struct Args {
+        #[clap(default_value = "3000", long)]
+        port: u16,
+        #[clap(long)]
+        hostname: Option<String>,
+        #[clap(long, value_delimiter = ',')]
+        ip_allow: Vec<String>,
+        #[clap(long)]
+        json_output: bool,
+        #[clap(long)]
+        config_file: Option<String>,
+        #[clap(long)]
+        config_deployment: Option<String>,
+    }
+
+    fn write(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("tgi-config-file-test-{name}"));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_parse_config_file() {
+        let toml = write(
+            "config.toml",
+            r#"
+            [launcher]
+            port = 8080
+
+            [router]
+            port = 8081
+            json-output = true
+
+            [router.auth]
+            ip_allow = ["10.0.0.0/8", "fd00::/8"]
+            "#,
+        );
+        let args: Args = parse_from(["router", "--config-file", &toml], "router").unwrap();
+        assert_eq!(
+            args,
+            Args {
+                port: 8081,
+                hostname: None,
+                ip_allow: vec!["10.0.0.0/8".to_string(), "fd00::/8".to_string()],
+                json_output: true,
+                config_file: Some(toml.clone()),
+                config_deployment: None,
+            }
+        );
+
+        // Flags take precedence over the file
+        let args: Args = parse_from(
+            ["router", "--config-file", &toml, "--port", "9000"],
+            "router",
+        )
+        .unwrap();
+        assert_eq!(args.port, 9000);
+        let args: Args = parse_from(["launcher", "--config-file", &toml], "launcher").unwrap();
+        assert_eq!((args.port, args.json_output), (8080, false));
+
+        let yaml = write(
+            "config.yaml",
+            "router:\n  hostname: 0.0.0.0\n  ip-allow: 10.0.0.0/8,fd00::/8\n",
+        );
+        let args: Args = parse_from(["router", "--config-file", &yaml], "router").unwrap();
+        assert_eq!(args.hostname.as_deref(), Some("0.0.0.0"));
+        assert_eq!(args.ip_allow.len(), 2);
+        assert_eq!(args.port, 3000);
+    }
+
+    #[test]
+    fn test_deployment_config_file() {
+        let toml = write(
+            "deployments.toml",
+            r#"
+            [router]
+            port = 8081
+            hostname = "0.0.0.0"
+
+            [deployments.llama.router]
+            port = 8082
+            json_output = true
+            "#,
+        );
+        let args: Args = parse_from(
+            [
+                "router",
+                "--config-file",
+                &toml,
+                "--config-deployment",
+                "llama",
+            ],
+            "router",
+        )
+        .unwrap();
+        assert_eq!(
+            (args.port, args.hostname.as_deref(), args.json_output),
+            (8082, Some("0.0.0.0"), true)
+        );
+        assert!(matches!(
+            parse_from::<Args, _, _>(
+                [
+                    "router",
+                    "--config-file",
+                    &toml,
+                    "--config-deployment",
+                    "mistral"
+                ],
+                "router",
+            ),
+            Err(ConfigFileError::UnknownDeployment(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_config_file() {
+        let parse = |content: &str| {
+            let path = write("invalid.toml", content);
+            parse_from::<Args, _, _>(["router", "--config-file", &path], "router")
+        };
+        assert!(matches!(
+            parse("[router]\nmax_batch_size = 4"),
+            Err(ConfigFileError::UnknownArgument(_))
+        ));
+        assert!(matches!(
+            parse("[router]\nport = \"http\""),
+            Err(ConfigFileError::Arguments(_))
+        ));
+        assert!(matches!(
+            parse("[router]\njson_output = \"yes\""),
+            Err(ConfigFileError::InvalidValue(_, _))
+        ));
+        assert!(matches!(
+            parse("router = 1"),
+            Err(ConfigFileError::InvalidSection(_))
+        ));
+        assert!(matches!(parse("[router"), Err(ConfigFileError::Toml(_))));
+    }
+}
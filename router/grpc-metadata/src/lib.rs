@@ -3,8 +3,28 @@
 
 use opentelemetry::global;
 use opentelemetry::propagation::Injector;
+use std::future::Future;
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+/// Metadata key used to forward request ids to the shards
+pub const REQUEST_ID_KEY: &str = "x-request-id";
+
+tokio::task_local! {
+    /// Request id(s) served by the current task
+    static REQUEST_ID: String;
+}
+
+/// Run `f` with the given request id.
+/// All gRPC requests sent from `f` will carry the request id in their metadata.
+pub async fn scope_request_id<F: Future>(request_id: String, f: F) -> F::Output {
+    REQUEST_ID.scope(request_id, f).await
+}
+
+/// Get the request id of the current task if any
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|request_id| request_id.clone()).ok()
+}
+
 /// Inject context in the metadata of a gRPC request.
 struct MetadataInjector<'a>(pub &'a mut tonic::metadata::MetadataMap);
 
@@ -20,13 +40,15 @@ impl<'a> Injector for MetadataInjector<'a> {
 }
 
 /// Get a context from the global context and inject the span into a gRPC request's metadata.
+/// Also inject the request id of the current task if any.
 fn inject(metadata: &mut tonic::metadata::MetadataMap) {
+    let mut injector = MetadataInjector(metadata);
     global::get_text_map_propagator(|propagator| {
-        propagator.inject_context(
-            &tracing::Span::current().context(),
-            &mut MetadataInjector(metadata),
-        )
-    })
+        propagator.inject_context(&tracing::Span::current().context(), &mut injector)
+    });
+    if let Some(request_id) = request_id().filter(|request_id| !request_id.is_empty()) {
+        injector.set(REQUEST_ID_KEY, request_id);
+    }
 }
 
 pub trait InjectTelemetryContext {
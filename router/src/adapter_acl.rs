@@ -0,0 +1,62 @@
+/// Per-key access control of the LoRA adapters
+use serde::Deserialize;
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AdapterAclError {
+    #[error("could not read the adapter access control list: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid adapter access control list: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// Adapters each key (caller tenant or subject) may request.
+/// Patterns are adapter ids, `prefix*` or `*`. Requests without adapter are always allowed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct AdapterAcl {
+    /// Adapters of the keys missing from `keys`
+    #[serde(default)]
+    pub default: Vec<String>,
+    #[serde(default)]
+    pub keys: HashMap<String, Vec<String>>,
+}
+
+impl AdapterAcl {
+    pub(crate) fn from_file(path: &str) -> Result<Self, AdapterAclError> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// Whether `key` may request `adapter_id`
+    pub(crate) fn allows(&self, key: &str, adapter_id: &str) -> bool {
+        self.keys
+            .get(key)
+            .unwrap_or(&self.default)
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => adapter_id.starts_with(prefix),
+                None => pattern == adapter_id,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adapter_acl() {
+        let acl: AdapterAcl = serde_json::from_str(
+            r#"{"default": ["public/*"], "keys": {"acme": ["acme-lora", "public/*"], "admin": ["*"]}}"#,
+        )
+        .unwrap();
+
+        assert!(acl.allows("acme", "acme-lora"));
+        assert!(acl.allows("acme", "public/summarize"));
+        assert!(!acl.allows("acme", "acme-lora-v2"));
+        assert!(!acl.allows("other", "acme-lora"));
+        assert!(acl.allows("other", "public/summarize"));
+        assert!(acl.allows("admin", "acme-lora"));
+        assert!(!AdapterAcl::default().allows("anonymous", "public/summarize"));
+    }
+}
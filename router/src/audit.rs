@@ -0,0 +1,45 @@
+/// Audit log of the admin actions
+use crate::auth::{AuthContext, Scope};
+use axum::extract::{ConnectInfo, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::net::SocketAddr;
+
+/// Tracing target of the audit records
+pub(crate) const AUDIT_TARGET: &str = "audit";
+
+/// Record the calls to the admin routes, allowed or not, with the identity of the caller.
+/// The caller identity is read from the response extensions, set by the authentication.
+pub(crate) async fn admin_audit_middleware(request: Request, next: Next) -> Response {
+    if Scope::of_route(request.uri().path()) != Scope::Admin {
+        return next.run(request).await;
+    }
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip().to_string());
+
+    let response = next.run(request).await;
+
+    let context = response.extensions().get::<AuthContext>();
+    let actor = context
+        .and_then(|context| context.subject.as_deref())
+        .unwrap_or("anonymous");
+    let tenant = context.and_then(|context| context.tenant.as_deref());
+    let status = response.status().as_u16();
+    metrics::counter!("tgi_admin_action", "allowed" => response.status().is_success().to_string())
+        .increment(1);
+    tracing::info!(
+        target: AUDIT_TARGET,
+        actor,
+        tenant,
+        client = client.as_deref(),
+        %method,
+        path,
+        status,
+        "Admin action"
+    );
+    response
+}
@@ -0,0 +1,1181 @@
+/// Bearer token authentication
+use crate::provenance::sha256_hex;
+use crate::rate_limit::ANONYMOUS_KEY;
+use crate::secrets::{reload_task, RotatingKeys, SecretError, SecretSource};
+use crate::tls::ClientCertificate;
+use crate::ErrorResponse;
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::hmac;
+use ring::signature::{
+    RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, ECDSA_P384_SHA384_FIXED,
+    RSA_PKCS1_2048_8192_SHA256, RSA_PKCS1_2048_8192_SHA384, RSA_PKCS1_2048_8192_SHA512,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::Span;
+
+/// Interval between two refreshes of the JSON Web Key Set
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Routes reachable without a token
+const PUBLIC_ROUTES: [&str; 3] = ["/health", "/ping", "/ready"];
+
+/// Public unless the metrics are protected
+const METRICS_ROUTE: &str = "/metrics";
+
+/// Headers of the HMAC signed requests
+const HMAC_KEY_ID_HEADER: &str = "x-signature-key-id";
+const HMAC_TIMESTAMP_HEADER: &str = "x-signature-timestamp";
+const HMAC_SIGNATURE_HEADER: &str = "x-signature";
+
+/// JWT bearer token validation settings
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    /// URL of the JSON Web Key Set of the issuer
+    pub jwks_url: String,
+    /// Expected `iss` claim
+    pub issuer: Option<String>,
+    /// Expected `aud` claim
+    pub audience: Option<String>,
+    /// Tolerated clock skew when checking `exp` and `nbf`
+    pub leeway: Duration,
+    /// Claim holding the tenant of the caller
+    pub tenant_claim: String,
+    /// How long the keys removed from the key set are still accepted
+    pub rotation_window: Duration,
+}
+
+/// HMAC request signature validation settings
+#[derive(Clone, Debug)]
+pub struct HmacConfig {
+    /// JSON object mapping key ids to their secret and tenant
+    pub keys: SecretSource,
+    /// Maximum age of a signed request, and how long its signature is remembered
+    pub replay_window: Duration,
+    /// How long the replaced or removed secrets are still accepted
+    pub rotation_window: Duration,
+}
+
+/// Static API key settings
+#[derive(Clone, Debug)]
+pub struct ApiKeyConfig {
+    /// JSON object mapping key names to their key, tenant and scopes
+    pub keys: SecretSource,
+    /// How long the replaced or removed keys are still accepted
+    pub rotation_window: Duration,
+}
+
+#[derive(Debug, Error)]
+pub enum ApiKeysError {
+    #[error("could not read the API keys: {0}")]
+    Read(#[from] SecretError),
+    #[error("invalid API keys: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum HmacKeysError {
+    #[error("could not read the HMAC keys: {0}")]
+    Read(#[from] SecretError),
+    #[error("invalid HMAC keys: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// Group of routes a credential may call
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Scope {
+    /// Generation, tokenization and model info
+    Generate,
+    /// Accepted for keys shared with embedding servers, the router has no embedding route
+    Embeddings,
+    /// `/admin/*` and `/usage`, never granted to unrestricted credentials
+    Admin,
+    /// `/metrics`, when protected
+    Metrics,
+}
+
+impl Scope {
+    /// Scope required to call `path`
+    pub(crate) fn of_route(path: &str) -> Self {
+        if path.starts_with("/admin/") || path == "/usage" {
+            Scope::Admin
+        } else if path == METRICS_ROUTE {
+            Scope::Metrics
+        } else {
+            Scope::Generate
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Generate => "generate",
+            Scope::Embeddings => "embeddings",
+            Scope::Admin => "admin",
+            Scope::Metrics => "metrics",
+        }
+    }
+}
+
+/// Kind of credential identifying the caller
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Credential {
+    Jwt,
+    ApiKey,
+    Hmac,
+    Certificate,
+}
+
+impl Credential {
+    /// Prefix of the admin subjects of the credential, the subjects of the different credentials
+    /// are distinct namespaces
+    fn as_str(&self) -> &'static str {
+        match self {
+            Credential::Jwt => "jwt",
+            Credential::ApiKey => "key",
+            Credential::Hmac => "hmac",
+            Credential::Certificate => "cert",
+        }
+    }
+}
+
+/// Identity of the caller, added to the request extensions
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AuthContext {
+    /// Credential the subject comes from, `None` for anonymous callers
+    pub credential: Option<Credential>,
+    pub subject: Option<String>,
+    pub tenant: Option<String>,
+    /// Scopes of the credential, `None` if it is not restricted
+    pub scopes: Option<Vec<Scope>>,
+}
+
+impl AuthContext {
+    /// The admin scope must be granted explicitly, the other scopes are granted to
+    /// unrestricted credentials
+    fn allows(&self, scope: Scope) -> bool {
+        self.scopes
+            .as_ref()
+            .map_or(scope != Scope::Admin, |scopes| scopes.contains(&scope))
+    }
+
+    /// Key identifying the caller for rate limiting: its tenant, or its subject
+    pub(crate) fn key(&self) -> String {
+        self.tenant
+            .clone()
+            .or_else(|| self.subject.clone())
+            .unwrap_or_else(|| ANONYMOUS_KEY.to_string())
+    }
+}
+
+tokio::task_local! {
+    /// Identity of the caller of the request being served
+    static AUTH_CONTEXT: AuthContext;
+}
+
+/// Run `f` with `context` as the caller identity
+pub(crate) async fn scope_auth_context<F: Future>(context: AuthContext, f: F) -> F::Output {
+    AUTH_CONTEXT.scope(context, f).await
+}
+
+/// Identity of the caller of the current request, anonymous outside of a request scope
+pub(crate) fn auth_context() -> AuthContext {
+    AUTH_CONTEXT
+        .try_with(|context| context.clone())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum AuthError {
+    #[error("missing bearer token")]
+    MissingToken,
+    #[error("missing request signature")]
+    MissingSignature,
+    #[error("missing client certificate")]
+    MissingCertificate,
+    #[error("credential lacks the `{}` scope", .0.as_str())]
+    MissingScope(Scope),
+    #[error("malformed token: {0}")]
+    Malformed(String),
+    #[error("unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("unknown signing key")]
+    UnknownKey,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("token is expired")]
+    Expired,
+    #[error("token is not valid yet")]
+    NotYetValid,
+    #[error("invalid issuer")]
+    InvalidIssuer,
+    #[error("invalid audience")]
+    InvalidAudience,
+    #[error("request timestamp is outside of the replay window")]
+    Stale,
+    #[error("request signature was already used")]
+    Replayed,
+}
+
+impl AuthError {
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken => "missing_token",
+            AuthError::MissingSignature => "missing_signature",
+            AuthError::MissingCertificate => "missing_certificate",
+            AuthError::MissingScope(_) => "missing_scope",
+            AuthError::Malformed(_) => "malformed",
+            AuthError::UnsupportedAlgorithm(_) => "unsupported_algorithm",
+            AuthError::UnknownKey => "unknown_key",
+            AuthError::InvalidSignature => "invalid_signature",
+            AuthError::Expired => "expired",
+            AuthError::NotYetValid => "not_yet_valid",
+            AuthError::InvalidIssuer => "invalid_issuer",
+            AuthError::InvalidAudience => "invalid_audience",
+            AuthError::Stale => "stale",
+            AuthError::Replayed => "replayed",
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AuthError::MissingScope(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+        (
+            status,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            Json(ErrorResponse {
+                error: self.to_string(),
+                error_type: "authentication".to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+/// Public key of a JSON Web Key
+#[derive(Debug, Clone, PartialEq)]
+enum PublicKey {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// Uncompressed P-256 point
+    P256(Vec<u8>),
+    /// Uncompressed P-384 point
+    P384(Vec<u8>),
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+impl Jwk {
+    /// Decode the public key, `None` if the key type is not supported
+    fn public_key(&self) -> Option<PublicKey> {
+        let decode = |value: &Option<String>| URL_SAFE_NO_PAD.decode(value.as_ref()?).ok();
+        match (self.kty.as_str(), self.crv.as_deref()) {
+            ("RSA", _) => Some(PublicKey::Rsa {
+                n: decode(&self.n)?,
+                e: decode(&self.e)?,
+            }),
+            ("EC", Some(crv @ ("P-256" | "P-384"))) => {
+                let mut point = vec![0x04];
+                point.extend(decode(&self.x)?);
+                point.extend(decode(&self.y)?);
+                match crv {
+                    "P-256" => Some(PublicKey::P256(point)),
+                    _ => Some(PublicKey::P384(point)),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Validate JWT bearer tokens against the keys published by the issuer
+#[derive(Clone)]
+pub(crate) struct JwtValidator {
+    config: Arc<JwtConfig>,
+    /// Public keys by key id
+    keys: Arc<RwLock<RotatingKeys<PublicKey>>>,
+}
+
+impl JwtValidator {
+    /// Fetch the issuer keys and refresh them every `JWKS_REFRESH_INTERVAL`
+    pub(crate) async fn spawn(config: JwtConfig) -> Self {
+        let keys = RotatingKeys::new(HashMap::new(), config.rotation_window);
+        let validator = Self {
+            config: Arc::new(config),
+            keys: Arc::new(RwLock::new(keys)),
+        };
+        if let Err(err) = validator.refresh().await {
+            tracing::warn!("Could not fetch the JSON Web Key Set: {err}");
+        }
+        tokio::spawn(validator.clone().refresh_task());
+        validator
+    }
+
+    async fn refresh_task(self) {
+        let mut ticker = tokio::time::interval(JWKS_REFRESH_INTERVAL);
+        // The first tick completes immediately
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(err) = self.refresh().await {
+                tracing::warn!("Could not refresh the JSON Web Key Set: {err}");
+            }
+        }
+    }
+
+    async fn refresh(&self) -> Result<(), reqwest::Error> {
+        let body = reqwest::get(&self.config.jwks_url)
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        match serde_json::from_slice::<Jwks>(&body) {
+            Ok(jwks) => {
+                self.set_keys(jwks);
+                metrics::counter!("tgi_auth_jwks_refresh").increment(1);
+            }
+            Err(err) => tracing::warn!("Invalid JSON Web Key Set: {err}"),
+        }
+        Ok(())
+    }
+
+    fn set_keys(&self, jwks: Jwks) {
+        let keys: HashMap<String, PublicKey> = jwks
+            .keys
+            .iter()
+            .filter_map(|jwk| Some((jwk.kid.clone().unwrap_or_default(), jwk.public_key()?)))
+            .collect();
+        tracing::info!("Loaded {} JSON Web Keys", keys.len());
+        self.keys.write().unwrap().update(keys, Instant::now());
+    }
+
+    /// Check the signature and the registered claims of `token`
+    pub(crate) fn validate(&self, token: &str) -> Result<AuthContext, AuthError> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(AuthError::Malformed("expected 3 parts".to_string()));
+        };
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|err| AuthError::Malformed(err.to_string()))
+        };
+        let jwt_header: JwtHeader = serde_json::from_slice(&decode(header)?)
+            .map_err(|err| AuthError::Malformed(err.to_string()))?;
+
+        // Signature, with the current key or a key being rotated out
+        {
+            let keys = self.keys.read().unwrap();
+            let message = &token[..header.len() + 1 + payload.len()];
+            let signature = decode(signature)?;
+            let mut result = Err(AuthError::UnknownKey);
+            for key in keys.get(
+                jwt_header.kid.as_deref().unwrap_or_default(),
+                Instant::now(),
+            ) {
+                result = verify(key, &jwt_header.alg, message.as_bytes(), &signature);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result?;
+        }
+
+        let claims: HashMap<String, Value> = serde_json::from_slice(&decode(payload)?)
+            .map_err(|err| AuthError::Malformed(err.to_string()))?;
+        self.check_claims(&claims, now())?;
+
+        let claim = |name: &str| claims.get(name).and_then(Value::as_str).map(String::from);
+        Ok(AuthContext {
+            credential: Some(Credential::Jwt),
+            subject: claim("sub"),
+            tenant: claim(&self.config.tenant_claim),
+            scopes: token_scopes(&claims),
+        })
+    }
+
+    /// Check the time, issuer and audience claims
+    fn check_claims(&self, claims: &HashMap<String, Value>, now: f64) -> Result<(), AuthError> {
+        let leeway = self.config.leeway.as_secs_f64();
+        let expiration = claims
+            .get("exp")
+            .and_then(Value::as_f64)
+            .ok_or_else(|| AuthError::Malformed("missing `exp` claim".to_string()))?;
+        if now > expiration + leeway {
+            return Err(AuthError::Expired);
+        }
+        if let Some(not_before) = claims.get("nbf").and_then(Value::as_f64) {
+            if now + leeway < not_before {
+                return Err(AuthError::NotYetValid);
+            }
+        }
+        if let Some(issuer) = &self.config.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err(AuthError::InvalidIssuer);
+            }
+        }
+        if let Some(audience) = &self.config.audience {
+            let valid = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !valid {
+                return Err(AuthError::InvalidAudience);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scopes of the space separated `scope` claim, or of the `scp` list.
+/// Unknown scopes are ignored, tokens without scope claim or without any router scope (e.g.
+/// `openid profile`) are not restricted.
+fn token_scopes(claims: &HashMap<String, Value>) -> Option<Vec<Scope>> {
+    let names: Vec<&str> = match (claims.get("scope"), claims.get("scp")) {
+        (Some(Value::String(scope)), _) => scope.split_whitespace().collect(),
+        (_, Some(Value::Array(scp))) => scp.iter().filter_map(Value::as_str).collect(),
+        (_, Some(Value::String(scp))) => scp.split_whitespace().collect(),
+        _ => return None,
+    };
+    let scopes: Vec<Scope> = names
+        .into_iter()
+        .filter_map(|name| serde_json::from_value(Value::String(name.to_string())).ok())
+        .collect();
+    (!scopes.is_empty()).then_some(scopes)
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+fn verify(key: &PublicKey, alg: &str, message: &[u8], signature: &[u8]) -> Result<(), AuthError> {
+    let result = match (key, alg) {
+        (PublicKey::Rsa { n, e }, "RS256") => {
+            RsaPublicKeyComponents { n, e }.verify(&RSA_PKCS1_2048_8192_SHA256, message, signature)
+        }
+        (PublicKey::Rsa { n, e }, "RS384") => {
+            RsaPublicKeyComponents { n, e }.verify(&RSA_PKCS1_2048_8192_SHA384, message, signature)
+        }
+        (PublicKey::Rsa { n, e }, "RS512") => {
+            RsaPublicKeyComponents { n, e }.verify(&RSA_PKCS1_2048_8192_SHA512, message, signature)
+        }
+        (PublicKey::P256(point), "ES256") => {
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point).verify(message, signature)
+        }
+        (PublicKey::P384(point), "ES384") => {
+            UnparsedPublicKey::new(&ECDSA_P384_SHA384_FIXED, point).verify(message, signature)
+        }
+        _ => return Err(AuthError::UnsupportedAlgorithm(alg.to_string())),
+    };
+    result.map_err(|_| AuthError::InvalidSignature)
+}
+
+/// Secret, tenant and scopes of a HMAC key
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+struct HmacKey {
+    secret: String,
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(default)]
+    scopes: Option<Vec<Scope>>,
+}
+
+/// Validate requests signed by service callers with a shared secret.
+///
+/// The `x-signature` header holds the hex encoded HMAC-SHA256 of
+/// `"{timestamp}\n{method}\n{path and query}\n"` followed by the body, where `timestamp`
+/// is the unix time in seconds sent in `x-signature-timestamp`, with the key named in
+/// `x-signature-key-id`. Key files are re-read every `SECRET_RELOAD_INTERVAL`.
+#[derive(Clone, Debug)]
+pub(crate) struct HmacVerifier {
+    keys: Arc<RwLock<RotatingKeys<HmacKey>>>,
+    replay_window: Duration,
+    /// Signatures seen in the replay window
+    seen: Arc<Mutex<SeenSignatures>>,
+    max_body_size: usize,
+}
+
+/// Signatures of the replay window, expired in the order of their timestamps
+#[derive(Debug, Default)]
+struct SeenSignatures {
+    signatures: HashSet<Vec<u8>>,
+    by_timestamp: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl SeenSignatures {
+    /// Forget the signatures older than `oldest`, then remember `signature`.
+    /// Returns `false` if it was already seen.
+    fn insert(&mut self, signature: Vec<u8>, timestamp: u64, oldest: u64) -> bool {
+        while self
+            .by_timestamp
+            .first()
+            .is_some_and(|(seen_at, _)| *seen_at < oldest)
+        {
+            if let Some((_, expired)) = self.by_timestamp.pop_first() {
+                self.signatures.remove(&expired);
+            }
+        }
+        if !self.signatures.insert(signature.clone()) {
+            return false;
+        }
+        self.by_timestamp.insert((timestamp, signature));
+        true
+    }
+}
+
+impl HmacVerifier {
+    /// Load the keys and reload them when their file changes
+    pub(crate) fn spawn(config: HmacConfig, max_body_size: usize) -> Result<Self, HmacKeysError> {
+        let raw_keys = config.keys.read()?;
+        let keys = serde_json::from_slice(&raw_keys)?;
+        let verifier = Self {
+            keys: Arc::new(RwLock::new(RotatingKeys::new(keys, config.rotation_window))),
+            replay_window: config.replay_window,
+            seen: Arc::new(Mutex::new(SeenSignatures::default())),
+            max_body_size,
+        };
+        if config.keys.reloadable() {
+            tokio::spawn(reload_task(
+                "HMAC keys",
+                "hmac_keys",
+                config.keys,
+                raw_keys,
+                verifier.keys.clone(),
+                |data: &[u8]| serde_json::from_slice(data),
+            ));
+        }
+        Ok(verifier)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn validate(
+        &self,
+        key_id: &str,
+        timestamp: &str,
+        signature: &str,
+        method: &str,
+        path: &str,
+        body: &[u8],
+        now: u64,
+    ) -> Result<AuthContext, AuthError> {
+        let timestamp: u64 = timestamp
+            .parse()
+            .map_err(|_| AuthError::Malformed("invalid timestamp".to_string()))?;
+        let signature = decode_hex(signature)
+            .ok_or_else(|| AuthError::Malformed("invalid signature encoding".to_string()))?;
+        let keys = self.keys.read().unwrap();
+        let mut candidates = keys.get(key_id, Instant::now()).peekable();
+        if candidates.peek().is_none() {
+            return Err(AuthError::UnknownKey);
+        }
+
+        let window = self.replay_window.as_secs();
+        if timestamp.abs_diff(now) > window {
+            return Err(AuthError::Stale);
+        }
+        let mut message = format!("{timestamp}\n{method}\n{path}\n").into_bytes();
+        message.extend_from_slice(body);
+        // The current secret, or a secret being rotated out
+        let key = candidates
+            .find(|key| {
+                let key_value = hmac::Key::new(hmac::HMAC_SHA256, key.secret.as_bytes());
+                hmac::verify(&key_value, &message, &signature).is_ok()
+            })
+            .ok_or(AuthError::InvalidSignature)?;
+
+        // Only valid signatures are remembered, so they cannot be used to fill the cache
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.insert(signature, timestamp, now.saturating_sub(window)) {
+            return Err(AuthError::Replayed);
+        }
+
+        Ok(AuthContext {
+            credential: Some(Credential::Hmac),
+            subject: Some(key_id.to_string()),
+            tenant: key.tenant.clone(),
+            scopes: key.scopes.clone(),
+        })
+    }
+
+    /// Buffer the body of `request` and validate its signature
+    async fn validate_request(&self, request: Request) -> Result<Request, Response> {
+        let headers = request.headers();
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let (Some(key_id), Some(timestamp), Some(signature)) = (
+            header(HMAC_KEY_ID_HEADER),
+            header(HMAC_TIMESTAMP_HEADER),
+            header(HMAC_SIGNATURE_HEADER),
+        ) else {
+            return Err(auth_failure(AuthError::MissingSignature));
+        };
+
+        let (parts, body) = request.into_parts();
+        // Oversized bodies are reported by the body limit middleware
+        let body = axum::body::to_bytes(body, self.max_body_size)
+            .await
+            .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE.into_response())?;
+        let path = parts
+            .uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        let now = now() as u64;
+        let context = self
+            .validate(
+                &key_id,
+                &timestamp,
+                &signature,
+                parts.method.as_str(),
+                path,
+                &body,
+                now,
+            )
+            .map_err(auth_failure)?;
+
+        let mut request = Request::from_parts(parts, Body::from(body));
+        request.extensions_mut().insert(context);
+        Ok(request)
+    }
+}
+
+/// API key entry of the keys file
+#[derive(Deserialize)]
+struct ApiKey {
+    key: String,
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(default)]
+    scopes: Option<Vec<Scope>>,
+}
+
+/// Caller identity of an API key, indexed by the key hash
+#[derive(Clone, Debug, PartialEq)]
+struct ApiKeyIdentity {
+    name: String,
+    tenant: Option<String>,
+    scopes: Option<Vec<Scope>>,
+}
+
+/// Index the API keys by the SHA-256 of the key, so the lookup does not depend on the key
+fn index_api_keys(data: &[u8]) -> Result<HashMap<String, ApiKeyIdentity>, serde_json::Error> {
+    let keys: HashMap<String, ApiKey> = serde_json::from_slice(data)?;
+    Ok(keys
+        .into_iter()
+        .map(|(name, key)| {
+            let identity = ApiKeyIdentity {
+                name,
+                tenant: key.tenant,
+                scopes: key.scopes,
+            };
+            (sha256_hex(key.key.as_bytes()), identity)
+        })
+        .collect())
+}
+
+/// Validate static API keys sent as bearer tokens. Key files are re-read every
+/// `SECRET_RELOAD_INTERVAL`.
+#[derive(Clone, Debug)]
+pub(crate) struct ApiKeyVerifier {
+    keys: Arc<RwLock<RotatingKeys<ApiKeyIdentity>>>,
+}
+
+impl ApiKeyVerifier {
+    /// Load the keys and reload them when their file changes
+    pub(crate) fn spawn(config: ApiKeyConfig) -> Result<Self, ApiKeysError> {
+        let raw_keys = config.keys.read()?;
+        let keys = index_api_keys(&raw_keys)?;
+        let verifier = Self {
+            keys: Arc::new(RwLock::new(RotatingKeys::new(keys, config.rotation_window))),
+        };
+        if config.keys.reloadable() {
+            tokio::spawn(reload_task(
+                "API keys",
+                "api_keys",
+                config.keys,
+                raw_keys,
+                verifier.keys.clone(),
+                index_api_keys,
+            ));
+        }
+        Ok(verifier)
+    }
+
+    /// Identity of `token`, `None` if it is not a known API key
+    fn validate(&self, token: &str) -> Option<AuthContext> {
+        let hash = sha256_hex(token.as_bytes());
+        let keys = self.keys.read().unwrap();
+        let identity = keys.get(&hash, Instant::now()).next()?;
+        Some(AuthContext {
+            credential: Some(Credential::ApiKey),
+            subject: Some(identity.name.clone()),
+            tenant: identity.tenant.clone(),
+            scopes: identity.scopes.clone(),
+        })
+    }
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Enabled authentication methods
+#[derive(Clone)]
+pub(crate) struct Authenticator {
+    pub jwt: Option<JwtValidator>,
+    pub api_keys: Option<ApiKeyVerifier>,
+    pub hmac: Option<HmacVerifier>,
+    /// Tenants of the client certificate common names, if client certificates are required
+    pub client_tenants: Option<Arc<HashMap<String, String>>>,
+    /// Whether `/metrics` needs a credential with the `metrics` scope
+    pub protect_metrics: bool,
+    /// Subjects granted the admin role, whatever the scopes of their credential, prefixed by
+    /// their kind of credential: `jwt:<sub>`, `key:<name>`, `hmac:<key id>` or `cert:<common name>`
+    pub admin_subjects: Arc<HashSet<String>>,
+}
+
+impl Authenticator {
+    /// Whether the caller of `context` may call the routes of `scope`
+    fn authorizes(&self, context: &AuthContext, scope: Scope) -> bool {
+        let admin_subject = || match (&context.credential, &context.subject) {
+            (Some(credential), Some(subject)) => self
+                .admin_subjects
+                .contains(&format!("{}:{subject}", credential.as_str())),
+            _ => false,
+        };
+        context.allows(scope) || (scope == Scope::Admin && admin_subject())
+    }
+
+    fn accepts_bearer(&self) -> bool {
+        self.jwt.is_some() || self.api_keys.is_some()
+    }
+
+    /// Caller identity of a bearer token: an API key, or a JWT
+    fn bearer_context(&self, token: Option<&str>) -> Result<AuthContext, AuthError> {
+        let token = token.ok_or(AuthError::MissingToken)?.trim();
+        if let Some(context) = self.api_keys.as_ref().and_then(|keys| keys.validate(token)) {
+            return Ok(context);
+        }
+        match &self.jwt {
+            Some(validator) => validator.validate(token),
+            None => Err(AuthError::UnknownKey),
+        }
+    }
+
+    /// Caller identity of a client certificate: its common name, and the tenant mapped to it
+    fn certificate_context(&self, certificate: Option<&ClientCertificate>) -> Option<AuthContext> {
+        let client_tenants = self.client_tenants.as_ref()?;
+        let common_name = certificate?.common_name.clone()?;
+        Some(AuthContext {
+            credential: Some(Credential::Certificate),
+            tenant: client_tenants.get(&common_name).cloned(),
+            subject: Some(common_name),
+            scopes: None,
+        })
+    }
+}
+
+fn auth_failure(err: AuthError) -> Response {
+    metrics::counter!("tgi_auth_failure", "reason" => err.reason()).increment(1);
+    tracing::warn!("Authentication failed: {err}");
+    err.into_response()
+}
+
+/// Reject requests without a valid bearer token or signature, except on public routes.
+/// Requests carrying a signature are validated with the HMAC keys, requests without token
+/// are identified by their client certificate, the others need an API key or a JWT.
+/// The credential must have the scope of the route, admin routes also accept the admin subjects.
+/// The caller identity is added to the request and response extensions and to the request span.
+pub(crate) async fn auth_middleware(
+    State(authenticator): State<Authenticator>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    if PUBLIC_ROUTES.contains(&path) || (path == METRICS_ROUTE && !authenticator.protect_metrics) {
+        return next.run(request).await;
+    }
+    let scope = Scope::of_route(path);
+
+    let signed = request.headers().contains_key(HMAC_SIGNATURE_HEADER);
+    let bearer = request.headers().contains_key(header::AUTHORIZATION);
+    let certificate_context =
+        authenticator.certificate_context(request.extensions().get::<ClientCertificate>());
+    if let (Some(hmac), true) = (&authenticator.hmac, signed) {
+        request = match hmac.validate_request(request).await {
+            Ok(request) => request,
+            Err(response) => return response,
+        };
+    } else if let Some(context) =
+        certificate_context.filter(|_| !(bearer && authenticator.accepts_bearer()))
+    {
+        request.extensions_mut().insert(context);
+    } else if authenticator.accepts_bearer() {
+        let token = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match authenticator.bearer_context(token) {
+            Ok(context) => {
+                request.extensions_mut().insert(context);
+            }
+            Err(err) => return auth_failure(err),
+        }
+    } else if authenticator.hmac.is_some() {
+        return auth_failure(AuthError::MissingSignature);
+    } else if authenticator.client_tenants.is_some() {
+        return auth_failure(AuthError::MissingCertificate);
+    } else {
+        return next.run(request).await;
+    }
+    let context = request
+        .extensions()
+        .get::<AuthContext>()
+        .cloned()
+        .unwrap_or_default();
+    if !authenticator.authorizes(&context, scope) {
+        let mut response = auth_failure(AuthError::MissingScope(scope));
+        response.extensions_mut().insert(context);
+        return response;
+    }
+
+    metrics::counter!("tgi_auth_success", "tenant" => context.tenant.clone().unwrap_or_default())
+        .increment(1);
+    let span = Span::current();
+    if let Some(subject) = &context.subject {
+        span.record("subject", subject.as_str());
+    }
+    if let Some(tenant) = &context.tenant {
+        span.record("tenant", tenant.as_str());
+    }
+    let mut response = scope_auth_context(context.clone(), next.run(request)).await;
+    response.extensions_mut().insert(context);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+
+    fn validator(key_pair: &EcdsaKeyPair) -> JwtValidator {
+        let point = key_pair.public_key().as_ref();
+        let jwks: Jwks = serde_json::from_value(json!({"keys": [{
+            "kty": "EC",
+            "kid": "key",
+            "crv": "P-256",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        }]}))
+        .unwrap();
+        let validator = JwtValidator {
+            config: Arc::new(JwtConfig {
+                jwks_url: String::new(),
+                issuer: Some("issuer".to_string()),
+                audience: Some("tgi".to_string()),
+                leeway: Duration::from_secs(60),
+                tenant_claim: "tenant".to_string(),
+                rotation_window: Duration::from_secs(60),
+            }),
+            keys: Arc::new(RwLock::new(RotatingKeys::new(
+                HashMap::new(),
+                Duration::from_secs(60),
+            ))),
+        };
+        validator.set_keys(jwks);
+        validator
+    }
+
+    fn sign(key_pair: &EcdsaKeyPair, claims: Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({"alg": "ES256", "kid": "key"}).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let message = format!("{header}.{payload}");
+        let signature = key_pair
+            .sign(&SystemRandom::new(), message.as_bytes())
+            .unwrap();
+        format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    #[test]
+    fn test_jwt_validation() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap();
+        let validator = validator(&key_pair);
+        let exp = now() + 60.0;
+
+        let token = sign(
+            &key_pair,
+            json!({"sub": "user", "tenant": "acme", "iss": "issuer", "aud": ["tgi"], "exp": exp}),
+        );
+        let context = validator.validate(&token).unwrap();
+        assert_eq!(context.subject.as_deref(), Some("user"));
+        assert_eq!(context.tenant.as_deref(), Some("acme"));
+        assert_eq!(context.scopes, None);
+
+        let token = sign(
+            &key_pair,
+            json!({"iss": "issuer", "aud": "tgi", "exp": exp, "scope": "generate metrics unknown"}),
+        );
+        let context = validator.validate(&token).unwrap();
+        assert_eq!(context.scopes, Some(vec![Scope::Generate, Scope::Metrics]));
+
+        // OIDC scopes only, the token is not restricted
+        let oidc = sign(
+            &key_pair,
+            json!({"iss": "issuer", "aud": "tgi", "exp": exp, "scope": "openid profile"}),
+        );
+        let context = validator.validate(&oidc).unwrap();
+        assert_eq!(context.scopes, None);
+        assert!(context.allows(Scope::Generate));
+        assert!(!context.allows(Scope::Admin));
+
+        // Tampered payload
+        let other = sign(
+            &key_pair,
+            json!({"iss": "issuer", "aud": "tgi", "exp": exp}),
+        );
+        let mut parts: Vec<&str> = token.split('.').collect();
+        parts[1] = other.split('.').nth(1).unwrap();
+        assert_eq!(
+            validator.validate(&parts.join(".")).err(),
+            Some(AuthError::InvalidSignature)
+        );
+
+        let token = sign(
+            &key_pair,
+            json!({"iss": "issuer", "aud": "tgi", "exp": now() - 120.0}),
+        );
+        assert_eq!(validator.validate(&token).err(), Some(AuthError::Expired));
+
+        let token = sign(&key_pair, json!({"iss": "other", "aud": "tgi", "exp": exp}));
+        assert_eq!(
+            validator.validate(&token).err(),
+            Some(AuthError::InvalidIssuer)
+        );
+
+        let token = sign(
+            &key_pair,
+            json!({"iss": "issuer", "aud": "other", "exp": exp}),
+        );
+        assert_eq!(
+            validator.validate(&token).err(),
+            Some(AuthError::InvalidAudience)
+        );
+    }
+
+    #[test]
+    fn test_hmac_validation() {
+        let key = |secret: &str| HmacKey {
+            secret: secret.to_string(),
+            tenant: Some("acme".to_string()),
+            scopes: None,
+        };
+        let verifier = HmacVerifier {
+            keys: Arc::new(RwLock::new(RotatingKeys::new(
+                HashMap::from([("service".to_string(), key("secret"))]),
+                Duration::from_secs(60),
+            ))),
+            replay_window: Duration::from_secs(300),
+            seen: Arc::new(Mutex::new(SeenSignatures::default())),
+            max_body_size: 1024,
+        };
+        let now = 1_700_000_000;
+        let body = br#"{"inputs":"Hello"}"#;
+        let sign = |timestamp: u64| {
+            let mut message = format!("{timestamp}\nPOST\n/generate\n").into_bytes();
+            message.extend_from_slice(body);
+            let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+            hmac::sign(&key, &message)
+                .as_ref()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .concat()
+        };
+        let validate = |key_id: &str, timestamp: u64, signature: &str, body: &[u8]| {
+            verifier.validate(
+                key_id,
+                &timestamp.to_string(),
+                signature,
+                "POST",
+                "/generate",
+                body,
+                now,
+            )
+        };
+
+        let signature = sign(now - 10);
+        let context = validate("service", now - 10, &signature, body).unwrap();
+        assert_eq!(context.subject.as_deref(), Some("service"));
+        assert_eq!(context.tenant.as_deref(), Some("acme"));
+        assert_eq!(
+            validate("service", now - 10, &signature, body).err(),
+            Some(AuthError::Replayed)
+        );
+        assert_eq!(
+            validate("service", now - 5, &sign(now - 5), b"{}").err(),
+            Some(AuthError::InvalidSignature)
+        );
+        assert_eq!(
+            validate("other", now - 5, &sign(now - 5), body).err(),
+            Some(AuthError::UnknownKey)
+        );
+        assert_eq!(
+            validate("service", now - 600, &sign(now - 600), body).err(),
+            Some(AuthError::Stale)
+        );
+        assert!(matches!(
+            validate("service", now, "zz", body),
+            Err(AuthError::Malformed(_))
+        ));
+
+        // The previous secret is still accepted during the rotation window
+        verifier.keys.write().unwrap().update(
+            HashMap::from([("service".to_string(), key("rotated"))]),
+            Instant::now(),
+        );
+        assert!(validate("service", now - 1, &sign(now - 1), body).is_ok());
+        verifier.keys.write().unwrap().update(
+            HashMap::from([("service".to_string(), key("rotated"))]),
+            Instant::now() + Duration::from_secs(61),
+        );
+        assert_eq!(
+            validate("service", now - 2, &sign(now - 2), body).err(),
+            Some(AuthError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_seen_signatures_expiry() {
+        let mut seen = SeenSignatures::default();
+        assert!(seen.insert(vec![1], 100, 0));
+        assert!(seen.insert(vec![2], 200, 0));
+        assert!(!seen.insert(vec![1], 100, 0));
+
+        // Only the signatures older than the window are forgotten
+        assert!(seen.insert(vec![3], 300, 150));
+        assert_eq!(seen.by_timestamp.len(), 2);
+        assert!(seen.insert(vec![1], 100, 150));
+        assert!(!seen.insert(vec![2], 200, 150));
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let keys = index_api_keys(
+            br#"{"mobile-app": {"key": "tgi-123", "tenant": "acme", "scopes": ["generate"]}, "ops": {"key": "tgi-456"}}"#,
+        )
+        .unwrap();
+        let verifier = ApiKeyVerifier {
+            keys: Arc::new(RwLock::new(RotatingKeys::new(
+                keys,
+                Duration::from_secs(60),
+            ))),
+        };
+
+        let context = verifier.validate("tgi-123").unwrap();
+        assert_eq!(context.subject.as_deref(), Some("mobile-app"));
+        assert_eq!(context.tenant.as_deref(), Some("acme"));
+        assert!(context.allows(Scope::of_route("/generate_stream")));
+        assert!(!context.allows(Scope::of_route("/admin/drain")));
+        assert!(!context.allows(Scope::of_route("/usage")));
+        assert!(!context.allows(Scope::of_route("/metrics")));
+
+        // Keys without scopes are only restricted from the admin routes
+        let context = verifier.validate("tgi-456").unwrap();
+        assert!(context.allows(Scope::Generate));
+        assert!(!context.allows(Scope::Admin));
+        assert!(verifier.validate("tgi-789").is_none());
+    }
+
+    #[test]
+    fn test_admin_role() {
+        let authenticator = Authenticator {
+            jwt: None,
+            api_keys: None,
+            hmac: None,
+            client_tenants: None,
+            protect_metrics: false,
+            admin_subjects: Arc::new(HashSet::from(["key:ops".to_string()])),
+        };
+        let context = |subject: &str, scopes: Option<Vec<Scope>>| AuthContext {
+            credential: Some(Credential::ApiKey),
+            subject: Some(subject.to_string()),
+            tenant: None,
+            scopes,
+        };
+
+        assert!(authenticator.authorizes(&context("ops", None), Scope::Admin));
+        assert!(authenticator.authorizes(&context("ops", Some(vec![])), Scope::Admin));
+        assert!(!authenticator.authorizes(&context("ops", Some(vec![])), Scope::Generate));
+        assert!(!authenticator.authorizes(&context("user", None), Scope::Admin));
+        assert!(authenticator.authorizes(&context("user", Some(vec![Scope::Admin])), Scope::Admin));
+
+        // The same subject from another kind of credential
+        for credential in [Credential::Jwt, Credential::Hmac, Credential::Certificate] {
+            let context = AuthContext {
+                credential: Some(credential),
+                ..context("ops", None)
+            };
+            assert!(!authenticator.authorizes(&context, Scope::Admin));
+        }
+    }
+}
@@ -1,4 +1,11 @@
+use crate::ImageDetail;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Longest edge of the images after the Idefics2 preprocessing
+const IDEFICS2_LONGEST_EDGE: u32 = 980;
+/// Largest resolution of the Paligemma checkpoints
+const PALIGEMMA_MAX_RESOLUTION: u32 = 896;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "model_type")]
@@ -112,11 +119,22 @@ pub struct ClipVisionModel {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
-pub struct Idefics2 {}
+pub struct Idefics2PerceiverConfig {
+    resampler_n_latents: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Idefics2 {
+    perceiver_config: Option<Idefics2PerceiverConfig>,
+}
 
 impl Idefics2 {
     pub fn get_number_of_features(&self, _height: usize, _width: usize) -> usize {
-        64
+        // Every image is resampled to the perceiver latents, 64 by default
+        self.perceiver_config
+            .as_ref()
+            .map_or(64, |config| config.resampler_n_latents)
     }
 }
 
@@ -173,6 +191,98 @@ pub enum Config {
     T5,
 }
 
+impl Config {
+    /// Whether the model accepts images in its inputs
+    pub fn is_multimodal(&self) -> bool {
+        matches!(
+            self,
+            Config::Idefics | Config::Idefics2(_) | Config::Paligemma(_) | Config::LlavaNext(_)
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MultimodalLimitsError {
+    #[error("could not read the multimodal limits: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid multimodal limits: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// Image limits of a multimodal model. The defaults are derived from the model configuration,
+/// every limit can be overridden by the `--multimodal-limits` file.
+/// The number of tokens per image is computed by the model configuration.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct MultimodalLimits {
+    /// Maximum number of images per request
+    pub max_images: Option<usize>,
+    /// Longest edge the model processes, larger images are downscaled by the router
+    pub max_resolution: Option<u32>,
+    /// Longest edge of the images sent with `detail: low`
+    pub low_detail_resolution: Option<u32>,
+    /// Accepted image mimetypes, any format the router decodes if `None`
+    pub formats: Option<Vec<String>>,
+    /// Maximum size of an image, in bytes, 20 MiB if `None`
+    pub max_image_bytes: Option<usize>,
+    /// Resolution of the rasterized document pages
+    pub document_dpi: Option<u32>,
+    /// Maximum number of pages of a document
+    pub max_document_pages: Option<usize>,
+}
+
+impl MultimodalLimits {
+    pub fn from_config(config: &Config) -> Self {
+        let max_resolution = match config {
+            Config::LlavaNext(config) => config
+                .image_grid_pinpoints
+                .iter()
+                .map(|(height, width)| *height.max(width) as u32)
+                .max(),
+            Config::Idefics2(_) => Some(IDEFICS2_LONGEST_EDGE),
+            Config::Paligemma(_) => Some(PALIGEMMA_MAX_RESOLUTION),
+            _ => None,
+        };
+        let low_detail_resolution = match config {
+            Config::LlavaNext(config) => Some(config.vision_config.image_size as u32),
+            _ => None,
+        };
+        Self {
+            max_resolution,
+            low_detail_resolution,
+            ..Default::default()
+        }
+    }
+
+    /// Longest edge of the images processed with `detail`
+    pub(crate) fn resolution(&self, detail: ImageDetail) -> Option<u32> {
+        match (detail, self.low_detail_resolution, self.max_resolution) {
+            (ImageDetail::Low, Some(low), Some(max)) => Some(low.min(max)),
+            (ImageDetail::Low, Some(low), None) => Some(low),
+            _ => self.max_resolution,
+        }
+    }
+
+    pub(crate) fn from_file(path: &str) -> Result<Self, MultimodalLimitsError> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// Limits set in `overrides` replace the ones of `self`
+    pub(crate) fn merge(self, overrides: MultimodalLimits) -> Self {
+        Self {
+            max_images: overrides.max_images.or(self.max_images),
+            max_resolution: overrides.max_resolution.or(self.max_resolution),
+            low_detail_resolution: overrides
+                .low_detail_resolution
+                .or(self.low_detail_resolution),
+            formats: overrides.formats.or(self.formats),
+            max_image_bytes: overrides.max_image_bytes.or(self.max_image_bytes),
+            document_dpi: overrides.document_dpi.or(self.document_dpi),
+            max_document_pages: overrides.max_document_pages.or(self.max_document_pages),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct TextConfig {}
@@ -218,4 +328,51 @@ mod test {
         let slots = config.get_number_of_features(1067, 1600);
         assert_eq!(slots, 2144);
     }
+
+    #[test]
+    fn test_idefics2_features() {
+        let config: Config = serde_json::from_str(r#"{"model_type": "idefics2"}"#).unwrap();
+        let Config::Idefics2(config) = config else {
+            panic!("expected an idefics2 config");
+        };
+        assert_eq!(config.get_number_of_features(20, 20), 64);
+
+        let config: Config = serde_json::from_str(
+            r#"{"model_type": "idefics2", "perceiver_config": {"resampler_n_latents": 32}}"#,
+        )
+        .unwrap();
+        let Config::Idefics2(config) = config else {
+            panic!("expected an idefics2 config");
+        };
+        assert_eq!(config.get_number_of_features(20, 20), 32);
+    }
+
+    #[test]
+    fn test_multimodal_limits() {
+        let config: Config = serde_json::from_str(
+            r#"{"model_type": "llava_next", "text_config": {}, "vision_config": {"image_size": 336, "patch_size": 14}, "image_grid_pinpoints": [[336, 672], [1008, 336]]}"#,
+        )
+        .unwrap();
+        let limits = MultimodalLimits::from_config(&config);
+        assert_eq!(limits.max_resolution, Some(1008));
+        assert_eq!(limits.resolution(ImageDetail::Low), Some(336));
+        assert_eq!(limits.resolution(ImageDetail::Auto), Some(1008));
+        assert_eq!(limits.max_images, None);
+
+        let overrides: MultimodalLimits = serde_json::from_str(
+            r#"{"max_images": 2, "formats": ["image/png"], "max_image_bytes": 1024}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            limits.merge(overrides),
+            MultimodalLimits {
+                max_images: Some(2),
+                max_resolution: Some(1008),
+                low_detail_resolution: Some(336),
+                formats: Some(vec!["image/png".to_string()]),
+                max_image_bytes: Some(1024),
+                ..Default::default()
+            }
+        );
+    }
 }
@@ -0,0 +1,232 @@
+/// CORS policies of the public and admin route groups
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use std::time::Duration;
+use thiserror::Error;
+use tower::{Layer, ServiceExt};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+/// Routes of the admin group, every other route is public
+const ADMIN_ROUTE_PREFIXES: [&str; 2] = ["/admin/", "/metrics"];
+
+#[derive(Debug, Error)]
+pub enum CorsConfigError {
+    #[error("could not read the CORS configuration: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid CORS configuration: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("invalid CORS configuration: {0}")]
+    Invalid(String),
+}
+
+/// CORS policy of a route group, `*` allows any origin, method or header
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub(crate) struct CorsPolicy {
+    pub allow_origins: Vec<String>,
+    pub allow_methods: Vec<String>,
+    pub allow_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /// Seconds the browsers may cache the preflight responses
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            allow_origins: Vec::new(),
+            allow_methods: vec!["GET".to_string(), "POST".to_string()],
+            allow_headers: vec![header::CONTENT_TYPE.to_string()],
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl CorsPolicy {
+    fn layer(&self) -> Result<CorsLayer, CorsConfigError> {
+        let wildcard = |values: &[String]| values.iter().any(|value| value == "*");
+        let invalid =
+            |kind: &str, value: &str| CorsConfigError::Invalid(format!("{kind} `{value}`"));
+        if self.allow_credentials
+            && (wildcard(&self.allow_origins)
+                || wildcard(&self.allow_methods)
+                || wildcard(&self.allow_headers))
+        {
+            return Err(CorsConfigError::Invalid(
+                "`*` cannot be used with `allow_credentials`".to_string(),
+            ));
+        }
+
+        let allow_origin = match wildcard(&self.allow_origins) {
+            true => AllowOrigin::any(),
+            false => AllowOrigin::list(
+                self.allow_origins
+                    .iter()
+                    .map(|origin| origin.parse().map_err(|_| invalid("origin", origin)))
+                    .collect::<Result<Vec<HeaderValue>, _>>()?,
+            ),
+        };
+        let allow_methods = match wildcard(&self.allow_methods) {
+            true => AllowMethods::any(),
+            false => AllowMethods::list(
+                self.allow_methods
+                    .iter()
+                    .map(|method| method.parse().map_err(|_| invalid("method", method)))
+                    .collect::<Result<Vec<Method>, _>>()?,
+            ),
+        };
+        let allow_headers = match wildcard(&self.allow_headers) {
+            true => AllowHeaders::any(),
+            false => AllowHeaders::list(
+                self.allow_headers
+                    .iter()
+                    .map(|name| name.parse().map_err(|_| invalid("header", name)))
+                    .collect::<Result<Vec<HeaderName>, _>>()?,
+            ),
+        };
+
+        let mut layer = CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(allow_methods)
+            .allow_headers(allow_headers)
+            .allow_credentials(self.allow_credentials);
+        if let Some(max_age) = self.max_age {
+            layer = layer.max_age(Duration::from_secs(max_age));
+        }
+        Ok(layer)
+    }
+}
+
+/// CORS configuration file
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct CorsConfig {
+    /// Generation, tokenization, info and health routes
+    pub public: Option<CorsPolicy>,
+    /// `/admin/*` and `/metrics`
+    pub admin: Option<CorsPolicy>,
+}
+
+/// CORS layers of the route groups
+#[derive(Clone, Debug)]
+pub(crate) struct CorsPolicies {
+    public: CorsLayer,
+    admin: CorsLayer,
+}
+
+impl CorsPolicies {
+    /// Routes missing from `config` keep the previous behavior: the public routes allow
+    /// `allow_origin` (any origin if `None`), the admin routes allow no cross-origin request
+    pub(crate) fn new(
+        config: CorsConfig,
+        allow_origin: Option<AllowOrigin>,
+    ) -> Result<Self, CorsConfigError> {
+        let public = match config.public {
+            Some(policy) => policy.layer()?,
+            None => CorsLayer::new()
+                .allow_methods([Method::GET, Method::POST])
+                .allow_headers([header::CONTENT_TYPE])
+                .allow_origin(allow_origin.unwrap_or(AllowOrigin::any())),
+        };
+        let admin = match config.admin {
+            Some(policy) => policy.layer()?,
+            None => CorsLayer::new(),
+        };
+        Ok(Self { public, admin })
+    }
+
+    pub(crate) fn from_file(
+        path: Option<&str>,
+        allow_origin: Option<AllowOrigin>,
+    ) -> Result<Self, CorsConfigError> {
+        let config = match path {
+            Some(path) => serde_json::from_slice(&std::fs::read(path)?)?,
+            None => CorsConfig::default(),
+        };
+        Self::new(config, allow_origin)
+    }
+
+    fn layer(&self, path: &str) -> &CorsLayer {
+        match ADMIN_ROUTE_PREFIXES
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+        {
+            true => &self.admin,
+            false => &self.public,
+        }
+    }
+}
+
+/// Apply the CORS policy of the route group of the request
+pub(crate) async fn cors_middleware(
+    State(policies): State<CorsPolicies>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let cors = policies.layer(request.uri().path()).layer(next);
+    match cors.oneshot(request).await {
+        Ok(response) => response,
+        Err(err) => match err {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::post;
+    use axum::Router;
+
+    async fn preflight(app: &Router, path: &str, origin: &str) -> Option<HeaderValue> {
+        let request = Request::builder()
+            .method(Method::OPTIONS)
+            .uri(path)
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .cloned()
+    }
+
+    #[tokio::test]
+    async fn test_cors_policies() {
+        let config: CorsConfig = serde_json::from_str(
+            r#"{"public": {"allow_origins": ["https://app.example.com"], "allow_credentials": true, "max_age": 600}}"#,
+        )
+        .unwrap();
+        let policies = CorsPolicies::new(config, None).unwrap();
+        let app = Router::new()
+            .route("/generate", post(|| async {}))
+            .route("/admin/events", post(|| async {}))
+            .layer(axum::middleware::from_fn_with_state(
+                policies,
+                cors_middleware,
+            ));
+
+        assert_eq!(
+            preflight(&app, "/generate", "https://app.example.com").await,
+            Some(HeaderValue::from_static("https://app.example.com"))
+        );
+        assert_eq!(preflight(&app, "/generate", "https://evil.com").await, None);
+        assert_eq!(
+            preflight(&app, "/admin/events", "https://app.example.com").await,
+            None
+        );
+
+        let config: CorsConfig = serde_json::from_str(
+            r#"{"admin": {"allow_origins": ["*"], "allow_credentials": true}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            CorsPolicies::new(config, None),
+            Err(CorsConfigError::Invalid(_))
+        ));
+    }
+}
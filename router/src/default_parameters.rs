@@ -0,0 +1,99 @@
+/// Server-side defaults of the generation parameters
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DefaultParametersError {
+    #[error("could not read the default parameters: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid default parameters: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("invalid default parameters: {0}")]
+    Value(String),
+}
+
+/// Values of the parameters omitted by the requests, `None` keeps the built-in default.
+/// `max_new_tokens` is lowered for the inputs too long for it, instead of failing the request.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct DefaultParameters {
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub typical_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub max_new_tokens: Option<u32>,
+    /// Sample the requests that do not set `do_sample`
+    #[serde(default)]
+    pub do_sample: bool,
+}
+
+impl DefaultParameters {
+    pub(crate) fn from_file(path: &str) -> Result<Self, DefaultParametersError> {
+        let defaults: Self = serde_json::from_slice(&std::fs::read(path)?)?;
+        defaults.check().map_err(DefaultParametersError::Value)?;
+        Ok(defaults)
+    }
+
+    /// Check the ranges of the values, an invalid default would fail every request
+    fn check(&self) -> Result<(), String> {
+        let unit_interval = |value: Option<f32>| value.map_or(true, |v| v > 0.0 && v < 1.0);
+        let penalty = |value: Option<f32>| value.map_or(true, |v| (-2.0..=2.0).contains(&v));
+        if self.temperature.is_some_and(|value| value < 0.0) {
+            return Err("`temperature` must be >= 0".to_string());
+        }
+        if self.top_k.is_some_and(|value| value <= 0) {
+            return Err("`top_k` must be strictly positive".to_string());
+        }
+        if !unit_interval(self.top_p) || !unit_interval(self.typical_p) {
+            return Err("`top_p` and `typical_p` must be > 0.0 and < 1.0".to_string());
+        }
+        if !unit_interval(self.min_p) {
+            return Err("`min_p` must be > 0.0 and < 1.0".to_string());
+        }
+        if self.repetition_penalty.is_some_and(|value| value <= 0.0) {
+            return Err("`repetition_penalty` must be strictly positive".to_string());
+        }
+        if !penalty(self.frequency_penalty) || !penalty(self.presence_penalty) {
+            return Err(
+                "`frequency_penalty` and `presence_penalty` must be >= -2.0 and <= 2.0".to_string(),
+            );
+        }
+        if self.max_new_tokens == Some(0) {
+            return Err("`max_new_tokens` must be strictly positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_parameters() {
+        let defaults: DefaultParameters =
+            serde_json::from_str(r#"{"temperature": 0.7, "top_p": 0.9, "max_new_tokens": 256}"#)
+                .unwrap();
+        assert_eq!(
+            defaults,
+            DefaultParameters {
+                temperature: Some(0.7),
+                top_p: Some(0.9),
+                max_new_tokens: Some(256),
+                ..Default::default()
+            }
+        );
+        assert!(defaults.check().is_ok());
+
+        assert!(serde_json::from_str::<DefaultParameters>(r#"{"temprature": 0.7}"#).is_err());
+        let invalid = DefaultParameters {
+            top_p: Some(1.0),
+            ..Default::default()
+        };
+        assert!(invalid.check().is_err());
+    }
+}
@@ -0,0 +1,186 @@
+/// Lifecycle events broadcasting
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Number of events kept for slow subscribers
+const EVENTS_CAPACITY: usize = 128;
+
+/// State change of the router or of the shards
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum LifecycleEvent {
+    /// The model was warmed up
+    WarmupFinished { max_batch_total_tokens: u32 },
+    /// A batch failed and was discarded
+    BatchFailed { method: &'static str, error: String },
+    /// A batch succeeded after a failed batch
+    BatchRecovered,
+    /// The shards answer the health checks again after failing them
+    ShardReconnected,
+    /// The maintenance mode was turned on or off
+    MaintenanceToggled { enabled: bool },
+    /// An adapter was loaded on the shards
+    AdapterLoaded { adapter_id: String },
+    /// An adapter was unloaded from the shards
+    AdapterUnloaded { adapter_id: String },
+}
+
+impl LifecycleEvent {
+    /// Part of the state of the router updated by the event, whose latest event is replayed to
+    /// the new subscribers
+    fn state(&self) -> Option<&'static str> {
+        match self {
+            LifecycleEvent::WarmupFinished { .. } => Some("warmup"),
+            LifecycleEvent::BatchFailed { .. } | LifecycleEvent::BatchRecovered => Some("batch"),
+            LifecycleEvent::MaintenanceToggled { .. } => Some("maintenance"),
+            LifecycleEvent::ShardReconnected
+            | LifecycleEvent::AdapterLoaded { .. }
+            | LifecycleEvent::AdapterUnloaded { .. } => None,
+        }
+    }
+}
+
+/// Timestamped lifecycle event
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct EventRecord {
+    /// Unix timestamp, in milliseconds
+    pub timestamp: u128,
+    #[serde(flatten)]
+    pub event: LifecycleEvent,
+}
+
+/// Broadcast lifecycle events to all subscribers
+#[derive(Clone, Debug)]
+pub(crate) struct Events {
+    sender: broadcast::Sender<EventRecord>,
+    /// Latest event of each state, the lock orders the events sent and the subscriptions
+    latest: Arc<Mutex<HashMap<&'static str, EventRecord>>>,
+    /// Whether the last batch failed
+    batch_failed: Arc<AtomicBool>,
+    /// Whether the router is in maintenance, reported as unhealthy to drain its traffic
+    maintenance: Arc<AtomicBool>,
+}
+
+impl Events {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+        Self {
+            sender,
+            latest: Arc::new(Mutex::new(HashMap::new())),
+            batch_failed: Arc::new(AtomicBool::new(false)),
+            maintenance: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub(crate) fn send(&self, event: LifecycleEvent) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let record = EventRecord { timestamp, event };
+        let mut latest = self.latest.lock().unwrap();
+        if let Some(state) = record.event.state() {
+            latest.insert(state, record.clone());
+        }
+        // An error only means that there are no subscribers
+        let _ = self.sender.send(record);
+    }
+
+    /// Subscribe to the next events, along with the latest event of each state
+    pub(crate) fn subscribe(&self) -> (Vec<EventRecord>, broadcast::Receiver<EventRecord>) {
+        let latest = self.latest.lock().unwrap();
+        let mut replay: Vec<EventRecord> = latest.values().cloned().collect();
+        replay.sort_by_key(|record| record.timestamp);
+        (replay, self.sender.subscribe())
+    }
+
+    pub(crate) fn batch_failed(&self, method: &'static str, error: String) {
+        self.batch_failed.store(true, Ordering::SeqCst);
+        self.send(LifecycleEvent::BatchFailed { method, error });
+    }
+
+    pub(crate) fn batch_succeeded(&self) {
+        if self.batch_failed.swap(false, Ordering::SeqCst) {
+            self.send(LifecycleEvent::BatchRecovered);
+        }
+    }
+
+    pub(crate) fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_maintenance(&self, enabled: bool) {
+        if self.maintenance.swap(enabled, Ordering::SeqCst) != enabled {
+            self.send(LifecycleEvent::MaintenanceToggled { enabled });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_recovered_after_failure() {
+        let events = Events::new();
+        let (_, mut receiver) = events.subscribe();
+
+        events.batch_succeeded();
+        assert!(receiver.try_recv().is_err());
+
+        events.batch_failed("decode", "error".to_string());
+        events.batch_succeeded();
+        events.batch_succeeded();
+
+        let record = receiver.try_recv().unwrap();
+        assert!(matches!(
+            record.event,
+            LifecycleEvent::BatchFailed {
+                method: "decode",
+                ..
+            }
+        ));
+        let record = receiver.try_recv().unwrap();
+        assert!(matches!(record.event, LifecycleEvent::BatchRecovered));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_replay_latest_state() {
+        let events = Events::new();
+        events.send(LifecycleEvent::WarmupFinished {
+            max_batch_total_tokens: 1024,
+        });
+        events.set_maintenance(true);
+        events.set_maintenance(true);
+        events.send(LifecycleEvent::AdapterLoaded {
+            adapter_id: "lora".to_string(),
+        });
+
+        let (replay, mut receiver) = events.subscribe();
+        assert_eq!(replay.len(), 2);
+        assert!(replay.iter().any(|record| matches!(
+            record.event,
+            LifecycleEvent::WarmupFinished {
+                max_batch_total_tokens: 1024
+            }
+        )));
+        assert!(replay.iter().any(|record| matches!(
+            record.event,
+            LifecycleEvent::MaintenanceToggled { enabled: true }
+        )));
+        assert!(receiver.try_recv().is_err());
+
+        events.set_maintenance(false);
+        let record = receiver.try_recv().unwrap();
+        assert!(matches!(
+            record.event,
+            LifecycleEvent::MaintenanceToggled { enabled: false }
+        ));
+        assert!(!events.in_maintenance());
+    }
+}
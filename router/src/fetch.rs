@@ -0,0 +1,347 @@
+/// Fetch remote resources referenced in the inputs without exposing internal services
+use reqwest::blocking::Client;
+use reqwest::redirect::Policy;
+use reqwest::{header, Url};
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error("invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("scheme `{0}` is not allowed")]
+    Scheme(String),
+    #[error("host `{0}` is not allowed")]
+    Host(String),
+    #[error("address `{0}` is not allowed")]
+    Address(IpAddr),
+    #[error("could not resolve `{0}`")]
+    Resolve(String),
+    #[error("more than {0} redirects")]
+    TooManyRedirects(usize),
+    #[error("response larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("could not read the response: {0}")]
+    Read(std::io::Error),
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+}
+
+/// IP network in CIDR notation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, canonical(address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let address: IpAddr = address
+            .parse()
+            .map_err(|_| format!("invalid IP network: {value}"))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max_prefix,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid IP network: {value}"))?,
+        };
+        Ok(Self {
+            address: canonical(address),
+            prefix,
+        })
+    }
+}
+
+/// Host name or IP network of an allow or deny rule.
+/// Host names starting with a `.` match all the subdomains.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FetchRule {
+    Host(String),
+    Network(IpNetwork),
+}
+
+impl FetchRule {
+    fn matches_host(&self, host: &str) -> bool {
+        match self {
+            FetchRule::Host(pattern) if pattern.starts_with('.') => {
+                host.ends_with(pattern.as_str()) || host == &pattern[1..]
+            }
+            FetchRule::Host(pattern) => host == pattern,
+            FetchRule::Network(_) => false,
+        }
+    }
+
+    fn matches_address(&self, address: IpAddr) -> bool {
+        match self {
+            FetchRule::Network(network) => network.contains(address),
+            FetchRule::Host(_) => false,
+        }
+    }
+}
+
+impl FromStr for FetchRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim().to_lowercase();
+        if value.starts_with(|c: char| c.is_ascii_digit()) || value.contains(':') {
+            Ok(FetchRule::Network(value.parse()?))
+        } else {
+            Ok(FetchRule::Host(value))
+        }
+    }
+}
+
+/// Restrictions on the URLs fetched by the router
+#[derive(Clone, Debug)]
+pub struct FetchPolicy {
+    pub allowed_schemes: Vec<String>,
+    /// If not empty, only these hosts and networks can be fetched
+    pub allow: Vec<FetchRule>,
+    /// Always denied, even if allowed
+    pub deny: Vec<FetchRule>,
+    /// Allow loopback, private, link-local (cloud metadata) and other non public addresses
+    pub allow_private_networks: bool,
+    pub max_redirects: usize,
+}
+
+impl Default for FetchPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            allow: Vec::new(),
+            deny: Vec::new(),
+            allow_private_networks: false,
+            max_redirects: 3,
+        }
+    }
+}
+
+impl FetchPolicy {
+    /// Check the scheme and host of `url` and the addresses it resolves to.
+    /// Returns the host and the checked addresses the request must connect to.
+    fn check(&self, url: &Url) -> Result<(String, Vec<SocketAddr>), FetchError> {
+        if !self.allowed_schemes.iter().any(|s| s == url.scheme()) {
+            return Err(FetchError::Scheme(url.scheme().to_string()));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| FetchError::InvalidUrl(url.to_string()))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_lowercase();
+        if self.deny.iter().any(|rule| rule.matches_host(&host)) {
+            return Err(FetchError::Host(host));
+        }
+        let host_allowed = self.allow.iter().any(|rule| rule.matches_host(&host));
+
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| FetchError::InvalidUrl(url.to_string()))?;
+        let addresses: Vec<SocketAddr> = (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|_| FetchError::Resolve(host.clone()))?
+            .collect();
+        if addresses.is_empty() {
+            return Err(FetchError::Resolve(host));
+        }
+
+        for address in &addresses {
+            let ip = canonical(address.ip());
+            if self.deny.iter().any(|rule| rule.matches_address(ip)) {
+                return Err(FetchError::Address(ip));
+            }
+            let ip_allowed = self.allow.iter().any(|rule| rule.matches_address(ip));
+            if !self.allow.is_empty() && !host_allowed && !ip_allowed {
+                return Err(FetchError::Host(host));
+            }
+            if !self.allow_private_networks && !ip_allowed && !is_public(ip) {
+                return Err(FetchError::Address(ip));
+            }
+        }
+        Ok((host, addresses))
+    }
+
+    /// Check `url` without fetching it, for the URLs fetched by the model servers
+    pub fn check_url(&self, url: &str) -> Result<(), FetchError> {
+        let url = Url::parse(url).map_err(|_| FetchError::InvalidUrl(url.to_string()))?;
+        self.check(&url).map(|_| ())
+    }
+
+    /// GET `url`, checking every redirect against the policy.
+    /// Connections are pinned to the checked addresses to prevent DNS rebinding.
+    /// Responses larger than `max_bytes` are not read further.
+    pub fn fetch(&self, url: &str, max_bytes: usize) -> Result<Vec<u8>, FetchError> {
+        let mut url = Url::parse(url).map_err(|_| FetchError::InvalidUrl(url.to_string()))?;
+        for _ in 0..=self.max_redirects {
+            let (host, addresses) = self.check(&url)?;
+            let client = Client::builder()
+                .redirect(Policy::none())
+                .resolve_to_addrs(&host, &addresses)
+                .build()?;
+            let response = client.get(url.clone()).send()?;
+            if !response.status().is_redirection() {
+                let response = response.error_for_status()?;
+                if response
+                    .content_length()
+                    .is_some_and(|length| length > max_bytes as u64)
+                {
+                    return Err(FetchError::TooLarge(max_bytes));
+                }
+                let mut data = Vec::new();
+                response
+                    .take(max_bytes as u64 + 1)
+                    .read_to_end(&mut data)
+                    .map_err(FetchError::Read)?;
+                if data.len() > max_bytes {
+                    return Err(FetchError::TooLarge(max_bytes));
+                }
+                return Ok(data);
+            }
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| FetchError::InvalidUrl(url.to_string()))?;
+            url = url
+                .join(location)
+                .map_err(|_| FetchError::InvalidUrl(location.to_string()))?;
+        }
+        Err(FetchError::TooManyRedirects(self.max_redirects))
+    }
+}
+
+/// IPv4-mapped IPv6 addresses are checked as IPv4 addresses
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        v4 => v4,
+    }
+}
+
+/// Whether `address` is publicly routable
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            !(v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || v4.is_documentation()
+                // Shared address space (100.64.0.0/10)
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+                // IETF protocol assignments (192.0.0.0/24)
+                || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+                // Reserved (240.0.0.0/4) and "this network" (0.0.0.0/8)
+                || octets[0] >= 240
+                || octets[0] == 0)
+        }
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            !(v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_multicast()
+                // Unique local (fc00::/7)
+                || (segments[0] & 0xfe00) == 0xfc00
+                // Link-local (fe80::/10)
+                || (segments[0] & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_network() {
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("11.0.0.1".parse().unwrap()));
+
+        let network: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(network.contains("fd12::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_fetch_policy() {
+        let policy = FetchPolicy::default();
+        let check = |policy: &FetchPolicy, url: &str| policy.check(&Url::parse(url).unwrap());
+
+        // Cloud metadata, loopback and private addresses
+        assert!(matches!(
+            check(&policy, "http://169.254.169.254/latest/meta-data"),
+            Err(FetchError::Address(_))
+        ));
+        assert!(matches!(
+            check(&policy, "http://127.0.0.1:8080/"),
+            Err(FetchError::Address(_))
+        ));
+        assert!(matches!(
+            check(&policy, "http://[::ffff:192.168.0.1]/"),
+            Err(FetchError::Address(_))
+        ));
+        assert!(matches!(
+            check(&policy, "file:///etc/passwd"),
+            Err(FetchError::Scheme(_))
+        ));
+        assert!(check(&policy, "http://93.184.216.34/image.png").is_ok());
+
+        // Explicitly allowed private network
+        let policy = FetchPolicy {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.0.1".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(check(&policy, "http://10.1.2.3/").is_ok());
+        assert!(matches!(
+            check(&policy, "http://10.0.0.1/"),
+            Err(FetchError::Address(_))
+        ));
+        assert!(matches!(
+            check(&policy, "http://93.184.216.34/"),
+            Err(FetchError::Host(_))
+        ));
+
+        let policy = FetchPolicy {
+            deny: vec![".internal".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            check(&policy, "http://metadata.internal/"),
+            Err(FetchError::Host(_))
+        ));
+    }
+}
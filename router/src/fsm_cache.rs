@@ -0,0 +1,256 @@
+/// On-disk cache of the token FSMs of the grammars, kept across restarts
+use crate::grammar::TokenFsm;
+use crate::provenance::sha256_hex;
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FsmCacheError {
+    #[error("could not open the grammar cache directory: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Version of the file format. Each version has its own directory, the files of the previous
+/// versions are left for the operators to remove.
+const FORMAT_VERSION: u32 = 1;
+const MAGIC: &[u8; 4] = b"TFSM";
+const EXTENSION: &str = "fsm";
+
+/// Token FSMs stored by grammar and vocabulary. The least recently used files are removed when
+/// the cache grows over `max_bytes`. The files failing their integrity check are removed.
+#[derive(Clone, Debug)]
+pub(crate) struct FsmDiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl FsmDiskCache {
+    pub(crate) fn open(dir: &str, max_bytes: u64) -> Result<Self, FsmCacheError> {
+        let dir = Path::new(dir).join(format!("v{FORMAT_VERSION}"));
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, max_bytes })
+    }
+
+    fn path(&self, vocabulary_hash: &str, grammar: &str) -> PathBuf {
+        let grammar_hash = sha256_hex(grammar.as_bytes());
+        self.dir
+            .join(format!("{vocabulary_hash}-{grammar_hash}.{EXTENSION}"))
+    }
+
+    /// FSM of `grammar` built from the vocabulary with `vocabulary_hash`, if it was stored
+    pub(crate) fn load(&self, vocabulary_hash: &str, grammar: &str) -> Option<TokenFsm> {
+        let path = self.path(vocabulary_hash, grammar);
+        let data = fs::read(&path).ok()?;
+        match decode(&data, grammar) {
+            Some(fsm) => {
+                // The modification time orders the evictions
+                if let Err(err) = fs::File::options()
+                    .append(true)
+                    .open(&path)
+                    .and_then(|file| file.set_modified(SystemTime::now()))
+                {
+                    tracing::debug!("Could not touch {}: {err}", path.display());
+                }
+                Some(fsm)
+            }
+            None => {
+                tracing::warn!(
+                    "Removing the corrupted grammar cache file {}",
+                    path.display()
+                );
+                metrics::counter!("tgi_grammar_disk_cache_corrupted").increment(1);
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    /// Store the FSM of `grammar`, failures are logged
+    pub(crate) fn store(&self, vocabulary_hash: &str, grammar: &str, fsm: &TokenFsm) {
+        let path = self.path(vocabulary_hash, grammar);
+        // Written to a temporary file first, the readers never see partial files
+        let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+        if let Err(err) =
+            fs::write(&tmp_path, encode(grammar, fsm)).and_then(|_| fs::rename(&tmp_path, &path))
+        {
+            tracing::warn!("Could not write {}: {err}", path.display());
+            let _ = fs::remove_file(&tmp_path);
+            return;
+        }
+        self.evict();
+    }
+
+    /// Remove the least recently used files until the cache fits in `max_bytes`
+    fn evict(&self) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let path = entry.path();
+                if path.extension()? != EXTENSION {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), path))
+            })
+            .collect();
+        let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+        files.sort();
+        for (_, len, path) in files {
+            if size <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                metrics::counter!("tgi_grammar_disk_cache_evictions").increment(1);
+                size -= len;
+            }
+        }
+        metrics::gauge!("tgi_grammar_disk_cache_bytes").set(size as f64);
+    }
+}
+
+/// `MAGIC`, `FORMAT_VERSION`, the SHA-256 of the payload and the payload. The payload starts
+/// with the grammar, to detect the hash collisions, followed by the states and their transitions.
+fn encode(grammar: &str, fsm: &TokenFsm) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend((grammar.len() as u32).to_le_bytes());
+    payload.extend(grammar.as_bytes());
+    payload.extend((fsm.states() as u32).to_le_bytes());
+    for state in 0..fsm.states() as u32 {
+        payload.push(fsm.is_final_state(state) as u8);
+        payload.extend((fsm.transitions_of(state).count() as u32).to_le_bytes());
+        for (token, next_state) in fsm.transitions_of(state) {
+            payload.extend(token.to_le_bytes());
+            payload.extend(next_state.to_le_bytes());
+        }
+    }
+
+    let mut data = Vec::with_capacity(8 + payload.len() + SHA256_OUTPUT_LEN);
+    data.extend(MAGIC);
+    data.extend(FORMAT_VERSION.to_le_bytes());
+    data.extend(digest(&SHA256, &payload).as_ref());
+    data.extend(payload);
+    data
+}
+
+/// Inverse of `encode`, `None` if the data is corrupted or belongs to another grammar
+fn decode(data: &[u8], grammar: &str) -> Option<TokenFsm> {
+    let mut reader = Reader(data);
+    if reader.bytes(MAGIC.len())? != MAGIC || reader.u32()? != FORMAT_VERSION {
+        return None;
+    }
+    let checksum = reader.bytes(SHA256_OUTPUT_LEN)?;
+    if digest(&SHA256, reader.0).as_ref() != checksum {
+        return None;
+    }
+
+    let grammar_len = reader.u32()? as usize;
+    if reader.bytes(grammar_len)? != grammar.as_bytes() {
+        return None;
+    }
+    let states = reader.u32()?;
+    let mut states_to_token_maps = Vec::new();
+    let mut final_states = HashSet::new();
+    for state in 0..states {
+        if reader.bytes(1)? == [1] {
+            final_states.insert(state);
+        }
+        let transitions = reader.u32()?;
+        let mut token_map = HashMap::new();
+        for _ in 0..transitions {
+            let token = reader.u32()?;
+            let next_state = reader.u32()?;
+            if next_state >= states {
+                return None;
+            }
+            token_map.insert(token, next_state);
+        }
+        states_to_token_maps.push(token_map);
+    }
+    if !reader.0.is_empty() || states_to_token_maps.is_empty() {
+        return None;
+    }
+    Some(TokenFsm::new(states_to_token_maps, final_states))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fsm() -> TokenFsm {
+        TokenFsm::new(
+            vec![HashMap::from([(3, 1), (4, 0)]), HashMap::new()],
+            HashSet::from([1]),
+        )
+    }
+
+    fn cache(name: &str, max_bytes: u64) -> FsmDiskCache {
+        let dir = std::env::temp_dir().join(format!("tgi-fsm-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        FsmDiskCache::open(dir.to_str().unwrap(), max_bytes).unwrap()
+    }
+
+    #[test]
+    fn test_fsm_disk_cache() {
+        let cache = cache("roundtrip", 1 << 20);
+        assert!(cache.load("vocab", "a+").is_none());
+        cache.store("vocab", "a+", &fsm());
+        let loaded = cache.load("vocab", "a+").unwrap();
+        assert_eq!(loaded, fsm());
+        // Another vocabulary or grammar
+        assert!(cache.load("other", "a+").is_none());
+        assert!(cache.load("vocab", "b+").is_none());
+
+        // Corrupted files are removed
+        let path = cache.path("vocab", "a+");
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&path, data).unwrap();
+        assert!(cache.load("vocab", "a+").is_none());
+        assert!(!path.exists());
+
+        // The grammar is checked against the hash collisions
+        assert!(decode(&encode("a+", &fsm()), "b+").is_none());
+        let _ = fs::remove_dir_all(&cache.dir);
+    }
+
+    #[test]
+    fn test_fsm_disk_cache_eviction() {
+        let size = encode("a", &fsm()).len() as u64;
+        let cache = cache("eviction", 2 * size);
+        for grammar in ["a", "b"] {
+            cache.store("vocab", grammar, &fsm());
+        }
+        // "a" is used again, "b" is the least recently used
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(cache.load("vocab", "a").is_some());
+        cache.store("vocab", "c", &fsm());
+        assert!(cache.load("vocab", "a").is_some());
+        assert!(cache.load("vocab", "b").is_none());
+        assert!(cache.load("vocab", "c").is_some());
+        let _ = fs::remove_dir_all(&cache.dir);
+    }
+}
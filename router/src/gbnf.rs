@@ -0,0 +1,422 @@
+/// Conversion of the GBNF grammars of llama.cpp to regexes
+use crate::grammar::{escape, GrammarError};
+use std::collections::HashMap;
+
+/// Maximum length of the regex of a grammar, the rules referenced several times are repeated
+pub(crate) const MAX_REGEX_LENGTH: usize = 1 << 20;
+
+/// Regex of the text generated by the `root` rule of a GBNF `grammar`. The rules cannot be
+/// recursive: only the regular grammars can be compiled to a regex.
+pub(crate) fn gbnf_to_regex(grammar: &str) -> Result<String, GrammarError> {
+    let rules = Parser {
+        chars: grammar.chars().collect(),
+        pos: 0,
+    }
+    .parse_rules()?;
+    Expander {
+        rules: &rules,
+        expanded: HashMap::new(),
+        stack: Vec::new(),
+    }
+    .expand("root")
+}
+
+fn error(message: String) -> GrammarError {
+    GrammarError::Gbnf(message)
+}
+
+/// Alternatives of sequences of terms
+type Alternates = Vec<Vec<Term>>;
+
+#[derive(Debug)]
+enum Term {
+    Literal(String),
+    /// Ranges of chars, negated with `[^...]`
+    Class(bool, Vec<(char, char)>),
+    Any,
+    Rule(String),
+    Group(Alternates),
+    Repeat(Box<Term>, u32, Option<u32>),
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char, GrammarError> {
+        let c = self
+            .peek()
+            .ok_or_else(|| error("unexpected end of the grammar".to_string()))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), GrammarError> {
+        for c in expected.chars() {
+            if self.next()? != c {
+                return Err(error(format!(
+                    "expected `{expected}` at char {}",
+                    self.pos - 1
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Skip the spaces and comments. The newlines end the rules outside of the groups.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\r' | '\n' if newlines => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn parse_rules(&mut self) -> Result<HashMap<String, Alternates>, GrammarError> {
+        let mut rules = HashMap::new();
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                return Ok(rules);
+            }
+            let name = self.parse_name()?;
+            self.skip_space(false);
+            self.expect("::=")?;
+            self.skip_space(true);
+            let alternates = self.parse_alternates(false)?;
+            if let Some(c) = self.peek().filter(|c| !matches!(c, '\r' | '\n')) {
+                return Err(error(format!("unexpected `{c}` at char {}", self.pos)));
+            }
+            if rules.insert(name.clone(), alternates).is_some() {
+                return Err(error(format!("rule `{name}` is defined twice")));
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String, GrammarError> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(error(format!("expected a rule name at char {start}")));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn parse_alternates(&mut self, nested: bool) -> Result<Alternates, GrammarError> {
+        let mut alternates = vec![self.parse_sequence(nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alternates.push(self.parse_sequence(nested)?);
+        }
+        Ok(alternates)
+    }
+
+    fn parse_sequence(&mut self, nested: bool) -> Result<Vec<Term>, GrammarError> {
+        let mut terms = Vec::new();
+        while let Some(c) = self.peek() {
+            let term = match c {
+                '"' => {
+                    self.pos += 1;
+                    let mut literal = String::new();
+                    loop {
+                        match self.next()? {
+                            '"' => break,
+                            '\\' => literal.push(self.parse_escape()?),
+                            c => literal.push(c),
+                        }
+                    }
+                    Term::Literal(literal)
+                }
+                '[' => {
+                    self.pos += 1;
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = Vec::new();
+                    loop {
+                        let start = match self.next()? {
+                            ']' => break,
+                            '\\' => self.parse_escape()?,
+                            c => c,
+                        };
+                        let end = match (self.peek(), self.chars.get(self.pos + 1)) {
+                            (Some('-'), Some(c)) if *c != ']' => {
+                                self.pos += 1;
+                                match self.next()? {
+                                    '\\' => self.parse_escape()?,
+                                    c => c,
+                                }
+                            }
+                            _ => start,
+                        };
+                        ranges.push((start, end));
+                    }
+                    Term::Class(negated, ranges)
+                }
+                '.' => {
+                    self.pos += 1;
+                    Term::Any
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alternates = self.parse_alternates(true)?;
+                    self.skip_space(true);
+                    self.expect(")")?;
+                    Term::Group(alternates)
+                }
+                '*' | '+' | '?' | '{' => {
+                    let term = terms.pop().ok_or_else(|| {
+                        error(format!("`{c}` without a term at char {}", self.pos))
+                    })?;
+                    self.pos += 1;
+                    let (min, max) = match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        '?' => (0, Some(1)),
+                        _ => self.parse_repetition()?,
+                    };
+                    Term::Repeat(Box::new(term), min, max)
+                }
+                c if c.is_ascii_alphanumeric() || c == '-' || c == '_' => {
+                    Term::Rule(self.parse_name()?)
+                }
+                _ => break,
+            };
+            terms.push(term);
+            self.skip_space(nested);
+        }
+        Ok(terms)
+    }
+
+    /// Char following a `\` in a literal or a class
+    fn parse_escape(&mut self) -> Result<char, GrammarError> {
+        let digits = match self.next()? {
+            'n' => return Ok('\n'),
+            'r' => return Ok('\r'),
+            't' => return Ok('\t'),
+            c @ ('\\' | '"' | '[' | ']' | '-') => return Ok(c),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            c => return Err(error(format!("unknown escape `\\{c}`"))),
+        };
+        let mut value = 0;
+        for _ in 0..digits {
+            let digit = self.next()?;
+            value = value * 16
+                + digit
+                    .to_digit(16)
+                    .ok_or_else(|| error(format!("invalid hexadecimal digit `{digit}`")))?;
+        }
+        char::from_u32(value).ok_or_else(|| error(format!("invalid char {value:#x}")))
+    }
+
+    /// `{m}`, `{m,}` or `{m,n}`, after the `{`
+    fn parse_repetition(&mut self) -> Result<(u32, Option<u32>), GrammarError> {
+        let number = |parser: &mut Self| {
+            let start = parser.pos;
+            while parser.peek().is_some_and(|c| c.is_ascii_digit()) {
+                parser.pos += 1;
+            }
+            parser.chars[start..parser.pos]
+                .iter()
+                .collect::<String>()
+                .parse::<u32>()
+                .ok()
+        };
+        let min =
+            number(self).ok_or_else(|| error(format!("expected a number at char {}", self.pos)))?;
+        let max = match self.next()? {
+            '}' => return Ok((min, Some(min))),
+            ',' => number(self),
+            c => return Err(error(format!("unexpected `{c}` in a repetition"))),
+        };
+        self.expect("}")?;
+        if max.is_some_and(|max| max < min) {
+            return Err(error(format!(
+                "invalid repetition {{{min},{}}}",
+                max.unwrap()
+            )));
+        }
+        Ok((min, max))
+    }
+}
+
+struct Expander<'a> {
+    rules: &'a HashMap<String, Alternates>,
+    /// Regexes of the rules already expanded
+    expanded: HashMap<&'a str, String>,
+    /// Rules being expanded, to detect the recursions
+    stack: Vec<&'a str>,
+}
+
+impl<'a> Expander<'a> {
+    fn expand(&mut self, name: &str) -> Result<String, GrammarError> {
+        if let Some(regex) = self.expanded.get(name) {
+            return Ok(regex.clone());
+        }
+        let (name, alternates) = self
+            .rules
+            .get_key_value(name)
+            .ok_or_else(|| error(format!("undefined rule `{name}`")))?;
+        if self.stack.contains(&name.as_str()) {
+            return Err(error(format!(
+                "rule `{name}` is recursive, only regular grammars are supported"
+            )));
+        }
+        self.stack.push(name);
+        let regex = self.alternates(alternates)?;
+        self.stack.pop();
+        // Checked for each rule, before the repetitions grow exponentially
+        if regex.len() > MAX_REGEX_LENGTH {
+            return Err(error(format!(
+                "the regex of rule `{name}` is longer than {MAX_REGEX_LENGTH} bytes"
+            )));
+        }
+        self.expanded.insert(name, regex.clone());
+        Ok(regex)
+    }
+
+    fn alternates(&mut self, alternates: &'a Alternates) -> Result<String, GrammarError> {
+        let sequences = alternates
+            .iter()
+            .map(|sequence| {
+                sequence
+                    .iter()
+                    .map(|term| self.term(term))
+                    .collect::<Result<String, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match sequences.len() {
+            1 => Ok(sequences.into_iter().next().unwrap()),
+            _ => Ok(format!("({})", sequences.join("|"))),
+        }
+    }
+
+    fn term(&mut self, term: &'a Term) -> Result<String, GrammarError> {
+        let regex = match term {
+            Term::Literal(literal) => escape(literal),
+            Term::Class(negated, ranges) => {
+                let mut class = String::from("[");
+                if *negated {
+                    class.push('^');
+                }
+                for (start, end) in ranges {
+                    push_class_char(&mut class, *start);
+                    if end != start {
+                        class.push('-');
+                        push_class_char(&mut class, *end);
+                    }
+                }
+                class.push(']');
+                class
+            }
+            Term::Any => r"(.|\n)".to_string(),
+            Term::Rule(name) => format!("({})", self.expand(name)?),
+            Term::Group(alternates) => format!("({})", self.alternates(alternates)?),
+            Term::Repeat(term, min, max) => {
+                let quantifier = match (min, max) {
+                    (0, None) => "*".to_string(),
+                    (1, None) => "+".to_string(),
+                    (0, Some(1)) => "?".to_string(),
+                    (min, None) => format!("{{{min},}}"),
+                    (min, Some(max)) if min == max => format!("{{{min}}}"),
+                    (min, Some(max)) => format!("{{{min},{max}}}"),
+                };
+                format!("({}){quantifier}", self.term(term)?)
+            }
+        };
+        Ok(regex)
+    }
+}
+
+pub(crate) fn push_class_char(class: &mut String, c: char) {
+    if "\\]^-[".contains(c) {
+        class.push('\\');
+    }
+    class.push(c);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn gbnf_regex(grammar: &str) -> Regex {
+        Regex::new(&format!("^{}$", gbnf_to_regex(grammar).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_gbnf_to_regex() {
+        let regex = gbnf_regex(
+            r##"
+            # A move of chess
+            root ::= piece? file rank (capture file rank)? check{0,1}
+            piece ::= [KQRBN]
+            file ::= [a-h]
+            rank ::= [1-8]
+            capture ::= "x"
+            check ::= ("+" |
+                       "#")
+            "##,
+        );
+        for text in ["e4", "Nf3", "Qh5#", "e4xd5+"] {
+            assert!(regex.is_match(text), "{text}");
+        }
+        for text in ["", "i4", "Pe4", "e4++"] {
+            assert!(!regex.is_match(text), "{text}");
+        }
+
+        let regex = gbnf_regex(r#"root ::= "\"" [^"\\\n]* "\"" "\x21"{2,3} "é" ."#);
+        assert!(regex.is_match("\"a [quote] (1+1)\"!!é\n"));
+        assert!(regex.is_match("\"\"!!!éx"));
+        assert!(!regex.is_match("\"\"\"!!éx"));
+        assert!(!regex.is_match("\"\"!éx"));
+    }
+
+    #[test]
+    fn test_gbnf_to_regex_errors() {
+        for grammar in [
+            // Recursive rules
+            r#"root ::= "(" root ")" | """#,
+            r#"root ::= a
+               a ::= "a" b
+               b ::= a"#,
+            // Invalid grammars
+            r#"root ::= "a"#,
+            r#"root ::= value"#,
+            r#"rule ::= "a""#,
+            r#"root ::= "a" ) "b""#,
+            r#"root ::= * "a""#,
+            r#"root ::= "a"{3,2}"#,
+            r#"root ::= "a"
+               root ::= "b""#,
+        ] {
+            assert!(
+                matches!(gbnf_to_regex(grammar), Err(GrammarError::Gbnf(_))),
+                "{grammar}"
+            );
+        }
+    }
+}
@@ -0,0 +1,915 @@
+/// Grammar compilation: JSON Schemas to regexes, and regexes to the token FSM of a vocabulary
+use crate::provenance::hex;
+use once_cell::sync::Lazy;
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::{Anchored, MatchKind};
+use ring::digest;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use thiserror::Error;
+use tokenizers::Tokenizer;
+
+#[derive(Debug, Error)]
+pub(crate) enum GrammarError {
+    #[error("unsupported JSON Schema: {0}")]
+    Unsupported(String),
+    #[error("invalid regex: {0}")]
+    Regex(String),
+    #[error("no token of the vocabulary can start the grammar")]
+    Unsatisfiable,
+    #[error("invalid GBNF grammar: {0}")]
+    Gbnf(String),
+    #[error("invalid Lark grammar: {0}")]
+    Lark(String),
+    #[error("the compilation was cancelled")]
+    Cancelled,
+    #[error("invalid `$ref`: {0}")]
+    Ref(String),
+    #[error("`{0}` must be at most {MAX_REPETITIONS}")]
+    Repetitions(String),
+}
+
+/// Optional whitespace between the JSON tokens. Unbounded whitespace lets the models loop on it.
+const WHITESPACE: &str = "[ ]?";
+/// Character of a JSON string
+const STRING_CHAR: &str = r#"([^"\\\x00-\x1F\x7F-\x9F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+const STRING: &str = r#""([^"\\\x00-\x1F\x7F-\x9F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})*""#;
+const INTEGER: &str = r"(-)?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"(-)?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+const BOOLEAN: &str = "(true|false)";
+const NULL: &str = "null";
+
+/// Bound of `minLength`, `maxLength`, `minItems` and `maxItems`: the regex repeats the character
+/// or item that many times, and the DFA grows with it
+const MAX_REPETITIONS: u64 = 256;
+
+/// Regexes of the supported string formats
+const FORMATS: [(&str, &str); 4] = [
+    (
+        "uuid",
+        r#""[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}""#,
+    ),
+    (
+        "date-time",
+        r#""[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|[12][0-9]|3[01])T([01][0-9]|2[0-3]):[0-5][0-9]:[0-5][0-9](\.[0-9]+)?(Z|[+-]([01][0-9]|2[0-3]):[0-5][0-9])""#,
+    ),
+    (
+        "date",
+        r#""[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|[12][0-9]|3[01])""#,
+    ),
+    (
+        "time",
+        r#""([01][0-9]|2[0-3]):[0-5][0-9]:[0-5][0-9](\.[0-9]+)?(Z|[+-]([01][0-9]|2[0-3]):[0-5][0-9])?""#,
+    ),
+];
+
+/// Nesting depth of the values of `json_object` grammars: JSON is not a regular language,
+/// the regex can only allow a bounded nesting
+const JSON_OBJECT_MAX_DEPTH: usize = 3;
+
+/// Regex of any JSON object, prebuilt for the `json_object` grammars
+pub(crate) static JSON_OBJECT_REGEX: Lazy<String> =
+    Lazy::new(|| json_object_regex(JSON_OBJECT_MAX_DEPTH));
+
+/// Regex of any JSON value, for the schemas that do not constrain them
+static JSON_VALUE_REGEX: Lazy<String> = Lazy::new(|| json_value_regex(JSON_OBJECT_MAX_DEPTH));
+
+fn json_object(value: &str) -> String {
+    let member = format!("{STRING}{WHITESPACE}:{WHITESPACE}{value}");
+    format!(r"\{{{WHITESPACE}({member}({WHITESPACE},{WHITESPACE}{member})*)?{WHITESPACE}\}}")
+}
+
+fn json_array(value: &str) -> String {
+    format!(r"\[{WHITESPACE}({value}({WHITESPACE},{WHITESPACE}{value})*)?{WHITESPACE}\]")
+}
+
+/// Regex of a JSON value nesting at most `depth` objects or arrays
+fn json_value_regex(depth: usize) -> String {
+    let scalar = format!("{STRING}|{NUMBER}|true|false|null");
+    let mut value = format!("({scalar})");
+    for _ in 0..depth {
+        value = format!("({scalar}|{}|{})", json_object(&value), json_array(&value));
+    }
+    value
+}
+
+/// Regex of a JSON object whose values nest at most `depth` objects or arrays,
+/// in the regex syntax of the model servers
+pub(crate) fn json_object_regex(depth: usize) -> String {
+    json_object(&json_value_regex(depth))
+}
+
+/// Escape the special characters of `literal`, in the regex syntax of the model servers
+pub(crate) fn escape(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if "\\.^$*+?{}[]|()".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Maximum number of `$ref`s followed from the root, the recursive schemas are not regular
+const MAX_REF_DEPTH: usize = 8;
+
+/// Maximum number of `$ref`s inlined in a schema: each inlining can copy many more
+const MAX_INLINED_REFS: usize = 1024;
+
+/// Inline the internal `$ref`s of `schema`, which the model servers do not resolve. The keywords
+/// next to a `$ref` are added to the referenced schema. The external and recursive references
+/// are rejected.
+pub(crate) fn resolve_refs(schema: &Value) -> Result<Value, GrammarError> {
+    let mut resolver = RefResolver {
+        root: schema,
+        stack: Vec::new(),
+        inlined: 0,
+    };
+    match schema {
+        // The definitions are only walked through the references to them
+        Value::Object(object) => {
+            let mut object = object.clone();
+            object.remove("$defs");
+            object.remove("definitions");
+            resolver.resolve(&Value::Object(object))
+        }
+        schema => resolver.resolve(schema),
+    }
+}
+
+struct RefResolver<'a> {
+    root: &'a Value,
+    /// References being inlined, to detect the recursion
+    stack: Vec<String>,
+    inlined: usize,
+}
+
+impl RefResolver<'_> {
+    fn resolve(&mut self, schema: &Value) -> Result<Value, GrammarError> {
+        match schema {
+            Value::Object(object) => match object.get("$ref") {
+                // Not a reference, such as a property named `$ref`
+                Some(Value::String(reference)) => self.inline(reference, object),
+                _ => object
+                    .iter()
+                    .map(|(key, value)| Ok((key.clone(), self.resolve(value)?)))
+                    .collect::<Result<_, _>>()
+                    .map(Value::Object),
+            },
+            Value::Array(items) => items
+                .iter()
+                .map(|item| self.resolve(item))
+                .collect::<Result<_, _>>()
+                .map(Value::Array),
+            value => Ok(value.clone()),
+        }
+    }
+
+    fn inline(
+        &mut self,
+        reference: &str,
+        object: &serde_json::Map<String, Value>,
+    ) -> Result<Value, GrammarError> {
+        let Some(pointer) = reference.strip_prefix('#') else {
+            return Err(GrammarError::Ref(format!(
+                "external reference `{reference}`"
+            )));
+        };
+        if self.stack.iter().any(|parent| parent == reference) {
+            return Err(GrammarError::Ref(format!(
+                "recursive reference `{reference}`"
+            )));
+        }
+        self.inlined += 1;
+        if self.inlined > MAX_INLINED_REFS {
+            return Err(GrammarError::Ref(format!(
+                "more than {MAX_INLINED_REFS} references to inline"
+            )));
+        }
+        let target = self
+            .root
+            .pointer(pointer)
+            .ok_or_else(|| GrammarError::Ref(format!("unresolvable reference `{reference}`")))?;
+
+        self.stack.push(reference.to_string());
+        let target = self.resolve(target)?;
+        self.stack.pop();
+        let mut resolved = match target {
+            Value::Object(target) => target,
+            Value::Bool(true) => serde_json::Map::new(),
+            // Nothing is valid against `false`, whatever the other keywords
+            target => return Ok(target),
+        };
+        for (key, value) in object {
+            if key != "$ref" {
+                resolved.insert(key.clone(), self.resolve(value)?);
+            }
+        }
+        Ok(Value::Object(resolved))
+    }
+}
+
+/// Regex of the JSON documents valid against `schema`, following the conversion of outlines.
+/// The keywords that only restrict the values, such as `minimum`, are ignored.
+pub(crate) fn json_schema_to_regex(schema: &Value) -> Result<String, GrammarError> {
+    SchemaConverter { root: schema }.to_regex(schema, 0)
+}
+
+struct SchemaConverter<'a> {
+    /// Target of the `$ref`s
+    root: &'a Value,
+}
+
+impl SchemaConverter<'_> {
+    fn to_regex(&self, schema: &Value, depth: usize) -> Result<String, GrammarError> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(JSON_VALUE_REGEX.clone()),
+            Value::Object(schema) => schema,
+            _ => return Err(GrammarError::Unsupported(format!("schema `{schema}`"))),
+        };
+
+        if let Some(properties) = schema.get("properties") {
+            let Value::Object(properties) = properties else {
+                return Err(GrammarError::Unsupported("`properties`".to_string()));
+            };
+            return self.object_regex(properties, schema.get("required"), depth);
+        }
+        if let Some(all_of) = schema.get("allOf") {
+            return match all_of.as_array().map(Vec::as_slice) {
+                Some([schema]) => self.to_regex(schema, depth),
+                _ => Err(GrammarError::Unsupported("`allOf`".to_string())),
+            };
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let Some(schemas) = schemas.as_array().filter(|schemas| !schemas.is_empty()) else {
+                    return Err(GrammarError::Unsupported(format!("`{keyword}`")));
+                };
+                let regexes = schemas
+                    .iter()
+                    .map(|schema| self.to_regex(schema, depth))
+                    .collect::<Result<Vec<_>, _>>()?;
+                return Ok(format!("({})", regexes.join("|")));
+            }
+        }
+        if let Some(values) = schema.get("enum") {
+            let Some(values) = values.as_array().filter(|values| !values.is_empty()) else {
+                return Err(GrammarError::Unsupported("`enum`".to_string()));
+            };
+            let values: Vec<String> = values
+                .iter()
+                .map(|value| escape(&value.to_string()))
+                .collect();
+            return Ok(format!("({})", values.join("|")));
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(format!("({})", escape(&value.to_string())));
+        }
+        if let Some(reference) = schema.get("$ref") {
+            if depth >= MAX_REF_DEPTH {
+                return Err(GrammarError::Unsupported("recursive `$ref`".to_string()));
+            }
+            let target = reference
+                .as_str()
+                .and_then(|reference| reference.strip_prefix('#'))
+                .and_then(|pointer| self.root.pointer(pointer))
+                .ok_or_else(|| GrammarError::Unsupported(format!("`$ref` {reference}")))?;
+            return self.to_regex(target, depth + 1);
+        }
+        match schema.get("type") {
+            Some(Value::String(instance_type)) => self.type_regex(instance_type, schema, depth),
+            Some(Value::Array(types)) if !types.is_empty() => {
+                let regexes = types
+                    .iter()
+                    .map(|instance_type| match instance_type {
+                        Value::String(instance_type) => {
+                            self.type_regex(instance_type, schema, depth)
+                        }
+                        _ => Err(GrammarError::Unsupported(format!("type {instance_type}"))),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!("({})", regexes.join("|")))
+            }
+            Some(instance_type) => Err(GrammarError::Unsupported(format!("type {instance_type}"))),
+            None if schema.is_empty() => Ok(JSON_VALUE_REGEX.clone()),
+            None => {
+                let keywords: Vec<&str> = schema.keys().map(String::as_str).collect();
+                Err(GrammarError::Unsupported(format!(
+                    "keywords `{}`",
+                    keywords.join("`, `")
+                )))
+            }
+        }
+    }
+
+    /// Object with the given `properties`, in their order. The required property with the last
+    /// position is written without comma, the others are optional with a comma before or after.
+    fn object_regex(
+        &self,
+        properties: &serde_json::Map<String, Value>,
+        required: Option<&Value>,
+        depth: usize,
+    ) -> Result<String, GrammarError> {
+        let required: Vec<&str> = required
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+        let members = properties
+            .iter()
+            .map(|(name, schema)| {
+                Ok((
+                    format!(
+                        r#"{WHITESPACE}"{}"{WHITESPACE}:{WHITESPACE}{}"#,
+                        escape(name),
+                        self.to_regex(schema, depth)?
+                    ),
+                    required.contains(&name.as_str()),
+                ))
+            })
+            .collect::<Result<Vec<_>, GrammarError>>()?;
+
+        let mut regex = r"\{".to_string();
+        match members.iter().rposition(|(_, required)| *required) {
+            Some(last_required) => {
+                for (i, (member, required)) in members.iter().enumerate() {
+                    let member = match i.cmp(&last_required) {
+                        std::cmp::Ordering::Less => format!("{member}{WHITESPACE},"),
+                        std::cmp::Ordering::Equal => member.clone(),
+                        std::cmp::Ordering::Greater => format!("{WHITESPACE},{member}"),
+                    };
+                    match required {
+                        true => regex.push_str(&member),
+                        false => regex.push_str(&format!("({member})?")),
+                    }
+                }
+            }
+            // Any property can be the last one, and the object can be empty
+            None if !members.is_empty() => {
+                let patterns: Vec<String> = (0..members.len())
+                    .map(|i| {
+                        let mut pattern = String::new();
+                        for (member, _) in &members[..i] {
+                            pattern.push_str(&format!("({member}{WHITESPACE},)?"));
+                        }
+                        pattern.push_str(&members[i].0);
+                        for (member, _) in &members[i + 1..] {
+                            pattern.push_str(&format!("({WHITESPACE},{member})?"));
+                        }
+                        pattern
+                    })
+                    .collect();
+                regex.push_str(&format!("({})?", patterns.join("|")));
+            }
+            None => {}
+        }
+        regex.push_str(&format!(r"{WHITESPACE}\}}"));
+        Ok(regex)
+    }
+
+    fn type_regex(
+        &self,
+        instance_type: &str,
+        schema: &serde_json::Map<String, Value>,
+        depth: usize,
+    ) -> Result<String, GrammarError> {
+        let length = |keyword: &str| match schema.get(keyword).and_then(Value::as_u64) {
+            Some(length) if length > MAX_REPETITIONS => {
+                Err(GrammarError::Repetitions(keyword.to_string()))
+            }
+            length => Ok(length),
+        };
+        match instance_type {
+            "string" => {
+                if let Some(pattern) = schema.get("pattern") {
+                    let pattern = pattern
+                        .as_str()
+                        .ok_or_else(|| GrammarError::Unsupported("`pattern`".to_string()))?;
+                    let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+                    let pattern = pattern.strip_suffix('$').unwrap_or(pattern);
+                    return Ok(format!(r#""({pattern})""#));
+                }
+                if let Some(format) = schema.get("format") {
+                    return FORMATS
+                        .iter()
+                        .find(|(name, _)| Some(*name) == format.as_str())
+                        .map(|(_, regex)| regex.to_string())
+                        .ok_or_else(|| GrammarError::Unsupported(format!("format {format}")));
+                }
+                match (length("minLength")?, length("maxLength")?) {
+                    (None, None) => Ok(STRING.to_string()),
+                    (min, None) => Ok(format!(r#""{STRING_CHAR}{{{},}}""#, min.unwrap_or(0))),
+                    (min, Some(max)) => {
+                        Ok(format!(r#""{STRING_CHAR}{{{},{max}}}""#, min.unwrap_or(0)))
+                    }
+                }
+            }
+            "number" => Ok(NUMBER.to_string()),
+            "integer" => Ok(INTEGER.to_string()),
+            "boolean" => Ok(BOOLEAN.to_string()),
+            "null" => Ok(NULL.to_string()),
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => self.to_regex(items, depth)?,
+                    None => JSON_VALUE_REGEX.clone(),
+                };
+                let min_items = length("minItems")?.unwrap_or(0);
+                let others = match length("maxItems")? {
+                    Some(0) => return Ok(format!(r"\[{WHITESPACE}\]")),
+                    Some(max_items) => {
+                        format!("{{{},{}}}", min_items.saturating_sub(1), max_items - 1)
+                    }
+                    None => format!("{{{},}}", min_items.saturating_sub(1)),
+                };
+                let items = format!("{item}({WHITESPACE},{WHITESPACE}{item}){others}");
+                let items = match min_items {
+                    0 => format!("({items})?"),
+                    _ => items,
+                };
+                Ok(format!(r"\[{WHITESPACE}{items}{WHITESPACE}\]"))
+            }
+            "object" => match schema.get("additionalProperties") {
+                Some(value @ Value::Object(map)) if !map.is_empty() => {
+                    Ok(json_object(&self.to_regex(value, depth)?))
+                }
+                _ => Ok(JSON_OBJECT_REGEX.clone()),
+            },
+            instance_type => Err(GrammarError::Unsupported(format!("type `{instance_type}`"))),
+        }
+    }
+}
+
+/// Inverse of the byte-to-char mapping of the byte-level (GPT-2) tokenizers
+static BYTE_LEVEL_CHARS: Lazy<HashMap<char, u8>> = Lazy::new(|| {
+    let mut bytes: Vec<u8> = (b'!'..=b'~')
+        .chain(0xA1..=0xAC)
+        .chain(0xAE..=0xFF)
+        .collect();
+    let mut chars: Vec<u32> = bytes.iter().map(|b| *b as u32).collect();
+    // The other bytes are mapped to the chars following 255
+    let mut shifted = 256;
+    for b in 0..=255u8 {
+        if !bytes.contains(&b) {
+            bytes.push(b);
+            chars.push(shifted);
+            shifted += 1;
+        }
+    }
+    chars
+        .into_iter()
+        .zip(bytes)
+        .map(|(c, b)| (char::from_u32(c).unwrap(), b))
+        .collect()
+});
+
+/// Bytes generated by a `token` of the vocabulary
+fn token_bytes(token: &str, byte_level: bool) -> Vec<u8> {
+    if byte_level {
+        if let Some(bytes) = token
+            .chars()
+            .map(|c| BYTE_LEVEL_CHARS.get(&c).copied())
+            .collect::<Option<Vec<u8>>>()
+        {
+            return bytes;
+        }
+    }
+    // Byte fallback of the SentencePiece vocabularies
+    if let Some(hex) = token.strip_prefix("<0x").and_then(|t| t.strip_suffix('>')) {
+        if let Ok(byte) = u8::from_str_radix(hex, 16) {
+            return vec![byte];
+        }
+    }
+    token.replace('▁', " ").into_bytes()
+}
+
+/// Prefix tree of the token bytes
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    tokens: Vec<u32>,
+}
+
+/// Bytes of the tokens that can be generated, special tokens excluded
+#[derive(Debug)]
+pub(crate) struct TokenVocabulary {
+    nodes: Vec<TrieNode>,
+    /// SHA-256 of the token bytes, identifies the FSMs built from this vocabulary
+    hash: String,
+}
+
+impl TokenVocabulary {
+    pub(crate) fn from_tokenizer(tokenizer: &Tokenizer) -> Self {
+        let byte_level = tokenizer
+            .get_decoder()
+            .and_then(|decoder| serde_json::to_string(decoder).ok())
+            .is_some_and(|decoder| decoder.contains(r#""type":"ByteLevel""#));
+        let added_tokens = tokenizer.get_added_tokens_decoder();
+        let tokens = tokenizer
+            .get_vocab(true)
+            .into_iter()
+            .filter_map(|(token, id)| match added_tokens.get(&id) {
+                Some(added_token) if added_token.special => None,
+                // The added tokens are not encoded by the model
+                Some(_) => Some((id, token.into_bytes())),
+                None => Some((id, token_bytes(&token, byte_level))),
+            });
+        Self::from_tokens(tokens)
+    }
+
+    fn from_tokens(tokens: impl IntoIterator<Item = (u32, Vec<u8>)>) -> Self {
+        let mut tokens: Vec<(u32, Vec<u8>)> = tokens.into_iter().collect();
+        tokens.sort();
+        let mut context = digest::Context::new(&digest::SHA256);
+        for (id, bytes) in &tokens {
+            context.update(&id.to_le_bytes());
+            context.update(&(bytes.len() as u32).to_le_bytes());
+            context.update(bytes);
+        }
+        let hash = hex(context.finish().as_ref());
+
+        let mut nodes = vec![TrieNode::default()];
+        for (id, bytes) in tokens {
+            if bytes.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for byte in bytes {
+                node = match nodes[node].children.iter().find(|(b, _)| *b == byte) {
+                    Some((_, child)) => *child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((byte, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(id);
+        }
+        Self { nodes, hash }
+    }
+
+    pub(crate) fn hash(&self) -> &str {
+        &self.hash
+    }
+}
+
+/// Maximum size of the DFA of a regex grammar
+const MAX_DFA_SIZE: usize = 64 * 1024 * 1024;
+
+/// Token-level FSM of a regex grammar, sent to the model servers. The transitions of the state `s`
+/// are the range `offsets[s]..offsets[s + 1]` of `tokens` and `next_states`, sorted by token.
+#[derive(PartialEq)]
+pub(crate) struct TokenFsm {
+    pub offsets: Vec<u32>,
+    /// Tokens allowed in the states
+    pub tokens: Vec<u32>,
+    /// States reached by generating `tokens`
+    pub next_states: Vec<u32>,
+    /// States in which the generated text matches the regex, sorted
+    pub final_states: Vec<u32>,
+}
+
+// The token maps are too large to be logged with the requests
+impl fmt::Debug for TokenFsm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenFsm")
+            .field("states", &self.states())
+            .field("final_states", &self.final_states.len())
+            .finish()
+    }
+}
+
+impl TokenFsm {
+    /// FSM of the tokens allowed in each state and the states they lead to. The start state is 0.
+    pub(crate) fn new(
+        states_to_token_maps: Vec<HashMap<u32, u32>>,
+        final_states: HashSet<u32>,
+    ) -> Self {
+        let mut offsets = Vec::with_capacity(states_to_token_maps.len() + 1);
+        offsets.push(0);
+        let mut tokens = Vec::new();
+        let mut next_states = Vec::new();
+        for token_map in states_to_token_maps {
+            let mut transitions: Vec<(u32, u32)> = token_map.into_iter().collect();
+            transitions.sort_unstable();
+            for (token, next_state) in transitions {
+                tokens.push(token);
+                next_states.push(next_state);
+            }
+            offsets.push(tokens.len() as u32);
+        }
+        let mut final_states: Vec<u32> = final_states.into_iter().collect();
+        final_states.sort_unstable();
+        Self {
+            offsets,
+            tokens,
+            next_states,
+            final_states,
+        }
+    }
+
+    /// Build the FSM of `regex`. The walk of the vocabulary stops once `cancelled` is set, the
+    /// DFA itself is bounded by `MAX_DFA_SIZE`.
+    pub(crate) fn compile(
+        regex: &str,
+        vocabulary: &TokenVocabulary,
+        cancelled: &AtomicBool,
+    ) -> Result<Self, GrammarError> {
+        let dfa = dense::Builder::new()
+            .configure(
+                dense::Config::new()
+                    .match_kind(MatchKind::All)
+                    .start_kind(StartKind::Anchored)
+                    .dfa_size_limit(Some(MAX_DFA_SIZE))
+                    .determinize_size_limit(Some(MAX_DFA_SIZE)),
+            )
+            .build(&format!("(?:{regex})$"))
+            .map_err(|err| GrammarError::Regex(err.to_string()))?;
+        let start = dfa
+            .start_state(&start::Config::new().anchored(Anchored::Yes))
+            .map_err(|err| GrammarError::Regex(err.to_string()))?;
+
+        let mut ids: HashMap<StateID, u32> = HashMap::from([(start, 0)]);
+        let mut queue = VecDeque::from([start]);
+        let mut states_to_token_maps = Vec::new();
+        let mut final_states = HashSet::new();
+        while let Some(state) = queue.pop_front() {
+            if cancelled.load(Ordering::Relaxed) {
+                return Err(GrammarError::Cancelled);
+            }
+            let id = states_to_token_maps.len() as u32;
+            if dfa.is_match_state(dfa.next_eoi_state(state)) {
+                final_states.insert(id);
+            }
+            // Walk the tokens sharing a prefix together, until the DFA rejects it
+            let mut token_map = HashMap::new();
+            let mut stack = vec![(0, state)];
+            while let Some((node, state)) = stack.pop() {
+                for &(byte, child) in &vocabulary.nodes[node].children {
+                    let next = dfa.next_state(state, byte);
+                    if dfa.is_dead_state(next) || dfa.is_quit_state(next) {
+                        continue;
+                    }
+                    if !vocabulary.nodes[child].tokens.is_empty() {
+                        // The states are numbered in the order of the queue
+                        let next_id = match ids.get(&next) {
+                            Some(next_id) => *next_id,
+                            None => {
+                                let next_id = ids.len() as u32;
+                                ids.insert(next, next_id);
+                                queue.push_back(next);
+                                next_id
+                            }
+                        };
+                        for token in &vocabulary.nodes[child].tokens {
+                            token_map.insert(*token, next_id);
+                        }
+                    }
+                    stack.push((child, next));
+                }
+            }
+            states_to_token_maps.push(token_map);
+        }
+
+        if states_to_token_maps[0].is_empty() && !final_states.contains(&0) {
+            return Err(GrammarError::Unsatisfiable);
+        }
+        Ok(Self::new(states_to_token_maps, final_states))
+    }
+
+    /// State reached by generating `token` in `state`, `None` if the grammar does not allow it
+    pub(crate) fn next_state(&self, state: u32, token: u32) -> Option<u32> {
+        if state as usize >= self.states() {
+            return None;
+        }
+        let range = self.range(state);
+        let i = self.tokens[range.clone()].binary_search(&token).ok()?;
+        Some(self.next_states[range.start + i])
+    }
+
+    pub(crate) fn is_final_state(&self, state: u32) -> bool {
+        self.final_states.binary_search(&state).is_ok()
+    }
+
+    pub(crate) fn states(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Tokens allowed in `state` and the states they lead to, sorted by token
+    pub(crate) fn transitions_of(&self, state: u32) -> impl Iterator<Item = (u32, u32)> + '_ {
+        let range = self.range(state);
+        self.tokens[range.clone()]
+            .iter()
+            .copied()
+            .zip(self.next_states[range].iter().copied())
+    }
+
+    fn range(&self, state: u32) -> Range<usize> {
+        self.offsets[state as usize] as usize..self.offsets[state as usize + 1] as usize
+    }
+
+    /// Number of token transitions of all the states, the size of the FSM sent to the shards
+    pub(crate) fn transitions(&self) -> usize {
+        self.tokens.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use serde_json::json;
+
+    fn schema_regex(schema: Value) -> Regex {
+        Regex::new(&format!("^{}$", json_schema_to_regex(&schema).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_json_schema_to_regex() {
+        let regex = schema_regex(json!({
+            "$defs": {"unit": {"enum": ["celsius", "fahrenheit"]}},
+            "type": "object",
+            "properties": {
+                "location": {"type": "string", "maxLength": 8},
+                "unit": {"$ref": "#/$defs/unit"},
+                "days": {"type": "array", "items": {"type": "integer"}, "minItems": 1},
+                "alert": {"type": ["boolean", "null"]}
+            },
+            "required": ["location", "days"]
+        }));
+        // The properties are sorted by name when the schema is parsed
+        for json in [
+            r#"{"days": [1], "location": "Paris"}"#,
+            r#"{"alert":null,"days":[1, 2],"location":"Paris","unit":"celsius"}"#,
+            r#"{"alert": true, "days": [1, 2], "location": "Paris"}"#,
+        ] {
+            assert!(regex.is_match(json), "{json}");
+        }
+        for json in [
+            r#"{"location": "Paris"}"#,
+            r#"{"days": [], "location": "Paris"}"#,
+            r#"{"days": [1], "location": "Paris, France"}"#,
+            r#"{"days": [1], "location": "Paris", "unit": "kelvin"}"#,
+            r#"{"location": "Paris", "days": [1]}"#,
+        ] {
+            assert!(!regex.is_match(json), "{json}");
+        }
+
+        // Without required properties, any of them can be the last one
+        let regex = schema_regex(json!({
+            "properties": {"a": {"const": 1}, "b": {"type": "number"}}
+        }));
+        for json in [
+            "{}",
+            r#"{"a": 1}"#,
+            r#"{"b": -0.5}"#,
+            r#"{"a": 1, "b": 2e3}"#,
+        ] {
+            assert!(regex.is_match(json), "{json}");
+        }
+        assert!(!regex.is_match(r#"{"a": 2}"#));
+        assert!(!regex.is_match(r#"{"a": 1,}"#));
+
+        let regex = schema_regex(json!({"type": "string", "format": "date"}));
+        assert!(regex.is_match(r#""2024-02-29""#));
+        assert!(!regex.is_match(r#""2024-13-01""#));
+    }
+
+    #[test]
+    fn test_json_schema_to_regex_unsupported() {
+        for schema in [
+            json!({"type": "string", "format": "email"}),
+            json!({"allOf": [{"type": "string"}, {"maxLength": 3}]}),
+            json!({"not": {"type": "string"}}),
+            json!({"$ref": "https://example.com/schema.json"}),
+            json!({"$defs": {"node": {"properties": {"next": {"$ref": "#/$defs/node"}}}},
+                   "$ref": "#/$defs/node"}),
+        ] {
+            assert!(
+                matches!(
+                    json_schema_to_regex(&schema),
+                    Err(GrammarError::Unsupported(_))
+                ),
+                "{schema}"
+            );
+        }
+
+        for schema in [
+            json!({"type": "string", "maxLength": 100000}),
+            json!({"type": "string", "minLength": 257}),
+            json!({"type": "array", "items": {"type": "integer"}, "minItems": 1000000}),
+            json!({"type": "array", "maxItems": 4096}),
+        ] {
+            assert!(
+                matches!(
+                    json_schema_to_regex(&schema),
+                    Err(GrammarError::Repetitions(_))
+                ),
+                "{schema}"
+            );
+        }
+        assert!(json_schema_to_regex(&json!({"type": "string", "maxLength": 256})).is_ok());
+    }
+
+    #[test]
+    fn test_resolve_refs() {
+        let schema = json!({
+            "$defs": {
+                "unit": {"enum": ["celsius", "fahrenheit"]},
+                "temperature": {
+                    "properties": {"value": {"type": "number"}, "unit": {"$ref": "#/$defs/unit"}}
+                }
+            },
+            "properties": {
+                "min": {"$ref": "#/$defs/temperature", "description": "Minimum"},
+                "max": {"$ref": "#/$defs/temperature"}
+            }
+        });
+        let temperature = json!({
+            "properties": {
+                "value": {"type": "number"},
+                "unit": {"enum": ["celsius", "fahrenheit"]}
+            }
+        });
+        let mut min = temperature.clone();
+        min["description"] = json!("Minimum");
+        assert_eq!(
+            resolve_refs(&schema).unwrap(),
+            json!({"properties": {"min": min, "max": temperature}})
+        );
+        // Properties named `$ref` are not references
+        let schema = json!({"properties": {"$ref": {"type": "string"}}});
+        assert_eq!(resolve_refs(&schema).unwrap(), schema);
+
+        for schema in [
+            json!({"$ref": "https://example.com/schema.json"}),
+            json!({"$ref": "#/$defs/missing"}),
+            json!({"$defs": {"node": {"properties": {"next": {"$ref": "#/$defs/node"}}}},
+                   "$ref": "#/$defs/node"}),
+        ] {
+            assert!(
+                matches!(resolve_refs(&schema), Err(GrammarError::Ref(_))),
+                "{schema}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_token_bytes() {
+        assert_eq!(token_bytes("Ġhello", true), b" hello");
+        assert_eq!(token_bytes("Ċ", true), b"\n");
+        assert_eq!(token_bytes("▁hello", false), b" hello");
+        assert_eq!(token_bytes("<0x0A>", false), b"\n");
+        assert_eq!(token_bytes("<0xZZ>", false), b"<0xZZ>");
+    }
+
+    #[test]
+    fn test_token_fsm() {
+        let vocabulary = TokenVocabulary::from_tokens(
+            ["a", "b", "ab", "ba", "c"]
+                .iter()
+                .enumerate()
+                .map(|(id, token)| (id as u32, token.as_bytes().to_vec())),
+        );
+        let fsm = TokenFsm::compile("(ab)+c?", &vocabulary, &AtomicBool::new(false)).unwrap();
+        let allowed: Vec<u32> = fsm.transitions_of(0).map(|(token, _)| token).collect();
+        assert_eq!(allowed, vec![0, 2]);
+        assert!(!fsm.is_final_state(0));
+
+        // "ab" and "a" "b" lead to the same state, which can end the text
+        let state = fsm.next_state(0, 2).unwrap();
+        assert_eq!(
+            fsm.next_state(fsm.next_state(0, 0).unwrap(), 1),
+            Some(state)
+        );
+        assert!(fsm.is_final_state(state));
+        let allowed: Vec<u32> = fsm.transitions_of(state).map(|(token, _)| token).collect();
+        assert_eq!(allowed, vec![0, 2, 4]);
+        assert_eq!(fsm.states(), fsm.offsets.len() - 1);
+        assert_eq!(fsm.next_state(fsm.states() as u32, 0), None);
+        assert_eq!(fsm.next_state(0, 1), None);
+        assert_eq!(
+            fsm.next_state(state, 4).map(|end| fsm.is_final_state(end)),
+            Some(true)
+        );
+
+        assert!(matches!(
+            TokenFsm::compile("d+", &vocabulary, &AtomicBool::new(false)),
+            Err(GrammarError::Unsatisfiable)
+        ));
+        assert!(matches!(
+            TokenFsm::compile("(a", &vocabulary, &AtomicBool::new(false)),
+            Err(GrammarError::Regex(_))
+        ));
+        assert!(matches!(
+            TokenFsm::compile("(ab)+c?", &vocabulary, &AtomicBool::new(true)),
+            Err(GrammarError::Cancelled)
+        ));
+    }
+}
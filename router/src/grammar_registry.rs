@@ -0,0 +1,127 @@
+/// Named grammars loaded from a directory at startup
+use crate::validation::ValidationError;
+use crate::GrammarType;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum GrammarRegistryError {
+    #[error("could not read the grammar registry: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid JSON Schema `{0}` in the grammar registry: {1}")]
+    Parse(String, serde_json::Error),
+    #[error("grammar `{0}` is defined more than once in the grammar registry")]
+    Duplicate(String),
+    #[error("invalid grammar `{0}` in the grammar registry: {1}")]
+    Invalid(String, ValidationError),
+}
+
+/// Grammars referenced by name by the requests, so that large schemas are not sent with every
+/// request and are compiled before the first one
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GrammarRegistry {
+    grammars: Arc<HashMap<String, GrammarType>>,
+}
+
+impl GrammarRegistry {
+    /// Load the grammars of `dir`, named after their files: `invoice_v2.json` is a JSON Schema,
+    /// the `.regex`, `.gbnf` and `.lark` files hold grammars of these types. The other files are
+    /// ignored.
+    pub(crate) fn from_dir(dir: &str) -> Result<Self, GrammarRegistryError> {
+        let mut grammars = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let (Some(name), Some(extension)) = (
+                path.file_stem().and_then(OsStr::to_str),
+                path.extension().and_then(OsStr::to_str),
+            ) else {
+                continue;
+            };
+            let grammar = match extension {
+                "json" => GrammarType::Json(
+                    serde_json::from_slice(&fs::read(&path)?)
+                        .map_err(|err| GrammarRegistryError::Parse(name.to_string(), err))?,
+                ),
+                // Editors end the files with a newline
+                "regex" => GrammarType::Regex(
+                    fs::read_to_string(&path)?
+                        .trim_end_matches(['\r', '\n'])
+                        .to_string(),
+                ),
+                "gbnf" | "ebnf" => GrammarType::Ebnf(fs::read_to_string(&path)?),
+                "lark" => GrammarType::Lark(fs::read_to_string(&path)?),
+                _ => {
+                    tracing::debug!("Ignoring {} in the grammar registry", path.display());
+                    continue;
+                }
+            };
+            if grammars.insert(name.to_string(), grammar).is_some() {
+                return Err(GrammarRegistryError::Duplicate(name.to_string()));
+            }
+        }
+        Ok(Self {
+            grammars: Arc::new(grammars),
+        })
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&GrammarType> {
+        self.grammars.get(name)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&String, &GrammarType)> {
+        self.grammars.iter()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.grammars.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.grammars.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grammar_registry() {
+        let dir = std::env::temp_dir().join(format!("tgi-grammar-registry-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("invoice_v2.json"), r#"{"type": "object"}"#).unwrap();
+        fs::write(dir.join("digits.regex"), "[0-9]+\n").unwrap();
+        fs::write(dir.join("yes_no.gbnf"), "root ::= \"yes\" | \"no\"").unwrap();
+        fs::write(dir.join("README.md"), "Grammars").unwrap();
+
+        let registry = GrammarRegistry::from_dir(dir.to_str().unwrap()).unwrap();
+        assert_eq!(registry.len(), 3);
+        assert!(matches!(
+            registry.get("invoice_v2"),
+            Some(GrammarType::Json(_))
+        ));
+        assert!(matches!(
+            registry.get("digits"),
+            Some(GrammarType::Regex(regex)) if regex == "[0-9]+"
+        ));
+        assert!(matches!(registry.get("yes_no"), Some(GrammarType::Ebnf(_))));
+        assert!(registry.get("README").is_none());
+
+        fs::write(dir.join("digits.lark"), "start: \"0\"").unwrap();
+        assert!(matches!(
+            GrammarRegistry::from_dir(dir.to_str().unwrap()),
+            Err(GrammarRegistryError::Duplicate(name)) if name == "digits"
+        ));
+        fs::remove_file(dir.join("digits.lark")).unwrap();
+        fs::write(dir.join("broken.json"), "{").unwrap();
+        assert!(matches!(
+            GrammarRegistry::from_dir(dir.to_str().unwrap()),
+            Err(GrammarRegistryError::Parse(_, _))
+        ));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,695 @@
+/// Decoding, validation, resizing and re-encoding of the input images.
+/// The model servers receive PNG or JPEG images no larger than the resolution of the model.
+use crate::config::MultimodalLimits;
+use crate::provenance::sha256_hex;
+use crate::validation::ValidationError;
+use crate::ImageDetail;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::io::{Limits, Reader as ImageReader};
+use image::{DynamicImage, ImageFormat};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Cursor, Read, Write};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Maximum width and height of the input images, before resizing
+pub(crate) const MAX_IMAGE_DIMENSION: u32 = 16_384;
+/// Quality of the re-encoded JPEG images
+const JPEG_QUALITY: u8 = 95;
+
+pub(crate) fn format_from_mimetype(mimetype: &str) -> Option<ImageFormat> {
+    match mimetype {
+        "image/png" => Some(ImageFormat::Png),
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/jpg" => Some(ImageFormat::Jpeg),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::WebP),
+        "image/tiff" => Some(ImageFormat::Tiff),
+        // "image/pnm"=>Some(ImageFormat::Pnm),
+        // "image/tga"=>Some(ImageFormat::Tga),
+        // "image/dds"=>Some(ImageFormat::Dds),
+        // "image/bmp"=>Some(ImageFormat::Bmp),
+        // "image/ico"=>Some(ImageFormat::Ico),
+        // "image/x-exr"=>Some(ImageFormat::OpenExr),
+        _ => None,
+    }
+}
+
+pub(crate) fn format_to_mimetype(format: ImageFormat) -> String {
+    match format {
+        ImageFormat::Png => "image/png",
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::WebP => "image/webp",
+        ImageFormat::Tiff => "image/tiff",
+        _ => "application/octet-stream",
+    }
+    .to_string()
+}
+
+/// Image forwarded to the model servers
+#[derive(Clone, Debug)]
+pub(crate) struct ProcessedImage {
+    pub data: Vec<u8>,
+    pub mimetype: String,
+    pub height: usize,
+    pub width: usize,
+}
+
+/// Decode `data`, check that its format is accepted by the model and matches the `declared`
+/// mimetype, and that its dimensions are within `MAX_IMAGE_DIMENSION`. Then downscale it to fit
+/// in the resolution of the model for `detail`.
+/// Resized images and formats other than PNG and JPEG are re-encoded.
+pub(crate) fn process_image(
+    data: Vec<u8>,
+    declared: Option<&str>,
+    limits: &MultimodalLimits,
+    detail: ImageDetail,
+) -> Result<ProcessedImage, ValidationError> {
+    let (data, mimetype) = match check_source(&data, declared)? {
+        Some(transcoded) => {
+            let png = transcode(&data, transcoded)?;
+            (png, transcoded.mimetype().to_string())
+        }
+        None => {
+            let mimetype = format_to_mimetype(image::guess_format(&data)?);
+            (data, mimetype)
+        }
+    };
+    if let Some(formats) = &limits.formats {
+        if !formats.contains(&mimetype) {
+            return Err(ValidationError::UnsupportedImageFormat(
+                mimetype,
+                formats.join(", "),
+            ));
+        }
+    }
+    let format = image::guess_format(&data)?;
+
+    let mut reader = ImageReader::with_format(Cursor::new(&data), format);
+    let mut decoder_limits = Limits::default();
+    decoder_limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    decoder_limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    reader.limits(decoder_limits);
+    let img = reader.decode().map_err(|err| match err {
+        image::ImageError::Limits(_) => ValidationError::ImageDimensions(MAX_IMAGE_DIMENSION),
+        err => err.into(),
+    })?;
+
+    let resized = match limits.resolution(detail) {
+        Some(max) if img.width().max(img.height()) > max => {
+            Some(img.resize(max, max, FilterType::CatmullRom))
+        }
+        _ => None,
+    };
+    let (img, data, format) = match resized {
+        Some(resized) => {
+            let format = encoded_format(format);
+            let data = encode(&resized, format)?;
+            (resized, data, format)
+        }
+        None if encoded_format(format) != format => {
+            let start_time = Instant::now();
+            let encoded = encode(&img, encoded_format(format));
+            record_transcode(&mimetype, start_time, encoded.is_ok());
+            (img, encoded?, encoded_format(format))
+        }
+        None => (img, data, format),
+    };
+    Ok(ProcessedImage {
+        data,
+        mimetype: format_to_mimetype(format),
+        height: img.height().try_into()?,
+        width: img.width().try_into()?,
+    })
+}
+
+/// The `declared` mimetype must match the content.
+/// Returns the format `data` must be transcoded from, if any.
+fn check_source(
+    data: &[u8],
+    declared: Option<&str>,
+) -> Result<Option<TranscodedFormat>, ValidationError> {
+    match TranscodedFormat::sniff(data) {
+        Some(transcoded) => match declared.and_then(format_from_mimetype) {
+            Some(declared) => Err(ValidationError::InvalidImageContent(format!(
+                "declared as {} but is {}",
+                format_to_mimetype(declared),
+                transcoded.mimetype()
+            ))),
+            None => Ok(Some(transcoded)),
+        },
+        None => {
+            check_declared(image::guess_format(data)?, declared)?;
+            Ok(None)
+        }
+    }
+}
+
+fn check_declared(format: ImageFormat, declared: Option<&str>) -> Result<(), ValidationError> {
+    match declared.and_then(format_from_mimetype) {
+        Some(declared) if declared != format => Err(ValidationError::InvalidImageContent(format!(
+            "declared as {} but is {}",
+            format_to_mimetype(declared),
+            format_to_mimetype(format)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Format of the images sent to the model servers
+fn encoded_format(format: ImageFormat) -> ImageFormat {
+    match format {
+        ImageFormat::Jpeg => ImageFormat::Jpeg,
+        _ => ImageFormat::Png,
+    }
+}
+
+fn encode(img: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, ValidationError> {
+    let mut data = Vec::new();
+    match format {
+        // JPEG has no alpha channel
+        ImageFormat::Jpeg => img
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY))?,
+        format => img.write_to(&mut Cursor::new(&mut data), format)?,
+    }
+    Ok(data)
+}
+
+/// Rasterizer of the PDF documents, from poppler-utils
+const PDF_RASTERIZER: &str = "pdftoppm";
+/// Resolution of the rasterized document pages, if not set by the multimodal limits
+pub(crate) const DEFAULT_DOCUMENT_DPI: u32 = 150;
+/// Maximum number of pages of a document, if not set by the multimodal limits
+pub(crate) const DEFAULT_MAX_DOCUMENT_PAGES: usize = 8;
+
+/// Maximum duration of the rasterization of a document
+const PDF_RASTERIZER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Render the pages of a PDF document as PNG images
+pub(crate) fn rasterize_pdf(
+    data: &[u8],
+    limits: &MultimodalLimits,
+) -> Result<Vec<Vec<u8>>, ValidationError> {
+    let dpi = limits.document_dpi.unwrap_or(DEFAULT_DOCUMENT_DPI);
+    let max_pages = limits
+        .max_document_pages
+        .unwrap_or(DEFAULT_MAX_DOCUMENT_PAGES);
+    let start_time = Instant::now();
+    // The document is read from stdin and its pages written one after the other to stdout.
+    // One more page is rendered to detect documents over the limit.
+    let (dpi, last_page) = (dpi.to_string(), (max_pages + 1).to_string());
+    let args = ["-png", "-r", &dpi, "-f", "1", "-l", &last_page, "-"];
+    let output = run_filter(PDF_RASTERIZER, &args, data, PDF_RASTERIZER_TIMEOUT)
+        .map_err(|err| ValidationError::DocumentRasterizer(err.to_string()))?;
+    metrics::histogram!("tgi_document_rasterize_duration")
+        .record(start_time.elapsed().as_secs_f64());
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(ValidationError::InvalidDocumentContent(
+            "could not render the document".to_string(),
+        ));
+    }
+    let pages = split_pngs(&output.stdout)
+        .ok_or_else(|| ValidationError::DocumentRasterizer("invalid rendered pages".to_string()))?;
+    if pages.len() > max_pages {
+        return Err(ValidationError::DocumentPages(max_pages));
+    }
+    Ok(pages)
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Split consecutive PNG images, `None` if `data` is not a sequence of PNG images
+fn split_pngs(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut images = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        if !rest.starts_with(PNG_SIGNATURE) {
+            return None;
+        }
+        let mut end = PNG_SIGNATURE.len();
+        // Chunks of data length, type, data and CRC, up to the `IEND` chunk
+        loop {
+            let length = u32::from_be_bytes(rest.get(end..end + 4)?.try_into().ok()?) as usize;
+            let chunk_type = rest.get(end + 4..end + 8)?;
+            end = end.checked_add(length)?.checked_add(12)?;
+            if end > rest.len() {
+                return None;
+            }
+            if chunk_type == b"IEND" {
+                break;
+            }
+        }
+        images.push(rest[..end].to_vec());
+        rest = &rest[end..];
+    }
+    Some(images)
+}
+
+/// Interval between two checks of the completion of a filter
+const FILTER_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Run `program`, writing `input` to its stdin and collecting its stdout.
+/// The program is killed if it does not complete within `timeout`.
+fn run_filter(program: &str, args: &[&str], input: &[u8], timeout: Duration) -> io::Result<Output> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let input = input.to_vec();
+    // Written and read from other threads, the program may fill stdout before reading all of stdin
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let reader = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            // The pipes are closed with the program, which ends the threads
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{program} did not complete within {timeout:?}"),
+            ));
+        }
+        std::thread::sleep(FILTER_POLL_INTERVAL);
+    };
+    let _ = writer.join();
+    let stdout = reader
+        .join()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "could not read the output"))??;
+    Ok(Output {
+        status,
+        stdout,
+        stderr: Vec::new(),
+    })
+}
+
+/// Converter of the formats the image crate cannot decode, from ImageMagick
+const IMAGE_TRANSCODER: &str = "convert";
+/// Maximum duration of the transcoding of an image
+const IMAGE_TRANSCODER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Resource limits of the transcoder, so crafted images fail instead of exhausting the host.
+/// The width and height match `MAX_IMAGE_DIMENSION`.
+const IMAGE_TRANSCODER_LIMITS: [(&str, &str); 6] = [
+    ("memory", "256MiB"),
+    ("map", "512MiB"),
+    ("disk", "1GiB"),
+    ("time", "10"),
+    ("width", "16384"),
+    ("height", "16384"),
+];
+
+/// Formats transcoded to PNG by `IMAGE_TRANSCODER` before processing.
+/// Each one is only accepted if the router is built with the feature of the same name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TranscodedFormat {
+    Avif,
+    Heic,
+}
+
+impl TranscodedFormat {
+    /// Major brand of the ISO base media file format header
+    fn sniff(data: &[u8]) -> Option<Self> {
+        if data.get(4..8)? != b"ftyp" {
+            return None;
+        }
+        match data.get(8..12)? {
+            b"avif" | b"avis" => Some(TranscodedFormat::Avif),
+            b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" | b"mif1" | b"msf1" => {
+                Some(TranscodedFormat::Heic)
+            }
+            _ => None,
+        }
+    }
+
+    fn mimetype(self) -> &'static str {
+        match self {
+            TranscodedFormat::Avif => "image/avif",
+            TranscodedFormat::Heic => "image/heic",
+        }
+    }
+
+    fn enabled(self) -> bool {
+        match self {
+            TranscodedFormat::Avif => cfg!(feature = "avif"),
+            TranscodedFormat::Heic => cfg!(feature = "heic"),
+        }
+    }
+}
+
+fn transcode(data: &[u8], format: TranscodedFormat) -> Result<Vec<u8>, ValidationError> {
+    if !format.enabled() {
+        return Err(ValidationError::UnsupportedImageFormat(
+            format.mimetype().to_string(),
+            "image/png, image/jpeg, image/gif, image/webp, image/tiff".to_string(),
+        ));
+    }
+    let start_time = Instant::now();
+    let input = match format {
+        TranscodedFormat::Avif => "avif:-",
+        TranscodedFormat::Heic => "heic:-",
+    };
+    let mut args: Vec<&str> = IMAGE_TRANSCODER_LIMITS
+        .iter()
+        .flat_map(|(resource, limit)| ["-limit", resource, limit])
+        .collect();
+    args.extend([input, "png:-"]);
+    let png = run_filter(IMAGE_TRANSCODER, &args, data, IMAGE_TRANSCODER_TIMEOUT)
+        .ok()
+        .filter(|output| output.status.success() && !output.stdout.is_empty())
+        .map(|output| output.stdout);
+    record_transcode(format.mimetype(), start_time, png.is_some());
+    png.ok_or_else(|| {
+        ValidationError::InvalidImageContent(format!("could not decode {}", format.mimetype()))
+    })
+}
+
+fn record_transcode(mimetype: &str, start_time: Instant, success: bool) {
+    let format = mimetype.to_string();
+    metrics::histogram!("tgi_image_transcode_duration", "format" => format.clone())
+        .record(start_time.elapsed().as_secs_f64());
+    if !success {
+        metrics::counter!("tgi_image_transcode_failure", "format" => format).increment(1);
+    }
+}
+
+/// Total size of the images kept by the cache, in bytes
+pub(crate) const IMAGE_CACHE_SIZE: usize = 256 * 1024 * 1024;
+/// Time the cached images and URLs stay valid
+pub(crate) const IMAGE_CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// Processed images by hash of their original content and resolution, and the content hash of
+/// the fetched URLs, so images repeated across the turns of a conversation are fetched and
+/// processed once.
+/// Entries expire after `ttl`, the oldest entries are evicted above `max_bytes`.
+/// Disabled if `max_bytes` is 0.
+#[derive(Debug, Clone)]
+pub(crate) struct ImageCache {
+    max_bytes: usize,
+    ttl: Duration,
+    inner: Arc<Mutex<ImageCacheInner>>,
+}
+
+type ImageKey = (String, Option<u32>);
+
+#[derive(Debug, Default)]
+struct ImageCacheInner {
+    /// Content hash of the fetched URLs and when they were fetched
+    urls: HashMap<String, (String, Instant)>,
+    /// Processed images by content hash and resolution
+    images: HashMap<ImageKey, (ProcessedImage, Instant)>,
+    /// Oldest first
+    order: VecDeque<ImageKey>,
+    bytes: usize,
+}
+
+impl ImageCache {
+    pub(crate) fn new(max_bytes: usize, ttl: Duration) -> Self {
+        Self {
+            max_bytes,
+            ttl,
+            inner: Arc::new(Mutex::new(ImageCacheInner::default())),
+        }
+    }
+
+    /// Processed image previously fetched from `url`
+    pub(crate) fn get_url(
+        &self,
+        url: &str,
+        limits: &MultimodalLimits,
+        detail: ImageDetail,
+    ) -> Option<ProcessedImage> {
+        if self.max_bytes == 0 {
+            return None;
+        }
+        let inner = self.inner.lock().unwrap();
+        let (hash, fetched_at) = inner.urls.get(url)?;
+        if fetched_at.elapsed() >= self.ttl {
+            return None;
+        }
+        let key = (hash.clone(), limits.resolution(detail));
+        let (image, inserted_at) = inner.images.get(&key)?;
+        if inserted_at.elapsed() >= self.ttl {
+            return None;
+        }
+        // Misses are counted once the image is fetched
+        metrics::counter!("tgi_image_cache", "result" => "hit").increment(1);
+        Some(image.clone())
+    }
+
+    /// Process `data` with [`process_image`], unless the same content was recently processed.
+    /// `url` is the location `data` was fetched from.
+    pub(crate) fn process(
+        &self,
+        data: Vec<u8>,
+        declared: Option<&str>,
+        limits: &MultimodalLimits,
+        detail: ImageDetail,
+        url: Option<&str>,
+    ) -> Result<ProcessedImage, ValidationError> {
+        if self.max_bytes == 0 {
+            return process_image(data, declared, limits, detail);
+        }
+        let hash = sha256_hex(&data);
+        let key = (hash.clone(), limits.resolution(detail));
+        let cached = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(url) = url {
+                inner.urls.insert(url.to_string(), (hash, Instant::now()));
+            }
+            inner
+                .images
+                .get(&key)
+                .filter(|(_, inserted_at)| inserted_at.elapsed() < self.ttl)
+                .map(|(image, _)| image.clone())
+        };
+        if let Some(image) = cached {
+            metrics::counter!("tgi_image_cache", "result" => "hit").increment(1);
+            check_source(&data, declared)?;
+            return Ok(image);
+        }
+        metrics::counter!("tgi_image_cache", "result" => "miss").increment(1);
+
+        let image = process_image(data, declared, limits, detail)?;
+        self.insert(key, image.clone(), Instant::now());
+        Ok(image)
+    }
+
+    fn insert(&self, key: ImageKey, image: ProcessedImage, now: Instant) {
+        let size = image.data.len();
+        if size > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        if let Some((previous, _)) = inner.images.remove(&key) {
+            inner.bytes -= previous.data.len();
+            inner.order.retain(|cached| cached != &key);
+        }
+        // Entries are ordered by insertion time, evict the expired and then the oldest ones
+        while let Some(oldest) = inner.order.front() {
+            let expired = inner
+                .images
+                .get(oldest)
+                .map_or(true, |(_, inserted_at)| now - *inserted_at >= self.ttl);
+            if !expired && inner.bytes + size <= self.max_bytes {
+                break;
+            }
+            let oldest = inner.order.pop_front().unwrap();
+            if let Some((evicted, _)) = inner.images.remove(&oldest) {
+                inner.bytes -= evicted.data.len();
+            }
+        }
+        inner.bytes += size;
+        inner.images.insert(key.clone(), (image, now));
+        inner.order.push_back(key);
+        inner
+            .urls
+            .retain(|_, (_, fetched_at)| now - *fetched_at < self.ttl);
+        metrics::gauge!("tgi_image_cache_bytes").set(inner.bytes as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_resolution: Option<u32>) -> MultimodalLimits {
+        MultimodalLimits {
+            max_resolution,
+            ..Default::default()
+        }
+    }
+
+    fn encoded(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_process_image() {
+        // Images larger than the model resolution are downscaled
+        let png = encoded(40, 20, ImageFormat::Png);
+        let image = process_image(
+            png.clone(),
+            Some("image/png"),
+            &limits(Some(32)),
+            ImageDetail::Auto,
+        )
+        .unwrap();
+        assert_eq!((image.width, image.height), (32, 16));
+        assert_eq!(image.mimetype, "image/png");
+        // Smaller PNG images are forwarded as is
+        let image = process_image(png.clone(), None, &limits(Some(64)), ImageDetail::Auto).unwrap();
+        assert_eq!(image.data, png);
+
+        // JPEG images stay JPEG when resized
+        let jpeg = encoded(100, 300, ImageFormat::Jpeg);
+        let image = process_image(
+            jpeg,
+            Some("image/jpeg"),
+            &limits(Some(150)),
+            ImageDetail::Auto,
+        )
+        .unwrap();
+        assert_eq!((image.width, image.height), (50, 150));
+        assert_eq!(image.mimetype, "image/jpeg");
+
+        // Only the formats accepted by the model
+        let webp_only = MultimodalLimits {
+            formats: Some(vec!["image/webp".to_string()]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            process_image(png.clone(), None, &webp_only, ImageDetail::Auto),
+            Err(ValidationError::UnsupportedImageFormat(_, _))
+        ));
+
+        // Other formats are converted to PNG
+        let gif = encoded(10, 10, ImageFormat::Gif);
+        let image = process_image(gif, None, &limits(None), ImageDetail::Auto).unwrap();
+        assert_eq!(image.mimetype, "image/png");
+        assert_eq!(image::guess_format(&image.data).unwrap(), ImageFormat::Png);
+
+        // AVIF and HEIC images need their feature
+        let mut heic = vec![0, 0, 0, 24];
+        heic.extend_from_slice(b"ftypheic\0\0\0\0mif1heic");
+        assert_eq!(TranscodedFormat::sniff(&heic), Some(TranscodedFormat::Heic));
+        assert_eq!(TranscodedFormat::sniff(&png), None);
+        if !cfg!(feature = "heic") {
+            assert!(matches!(
+                process_image(heic, None, &limits(None), ImageDetail::Auto),
+                Err(ValidationError::UnsupportedImageFormat(_, _))
+            ));
+        }
+
+        assert!(matches!(
+            process_image(png, Some("image/jpeg"), &limits(None), ImageDetail::Auto),
+            Err(ValidationError::InvalidImageContent(_))
+        ));
+        let huge = encoded(MAX_IMAGE_DIMENSION + 1, 1, ImageFormat::Png);
+        assert!(matches!(
+            process_image(huge, None, &limits(None), ImageDetail::Auto),
+            Err(ValidationError::ImageDimensions(_))
+        ));
+    }
+
+    #[test]
+    fn test_split_pngs() {
+        let first = encoded(8, 8, ImageFormat::Png);
+        let second = encoded(16, 4, ImageFormat::Png);
+        let pages = split_pngs(&[first.clone(), second.clone()].concat()).unwrap();
+        assert_eq!(pages, vec![first.clone(), second]);
+
+        assert_eq!(split_pngs(&first[..first.len() - 1]), None);
+        assert_eq!(split_pngs(&encoded(8, 8, ImageFormat::Jpeg)), None);
+    }
+
+    #[test]
+    fn test_image_cache() {
+        let first = encoded(8, 8, ImageFormat::Png);
+        let second = encoded(16, 16, ImageFormat::Png);
+        let size = process_image(first.clone(), None, &limits(None), ImageDetail::Auto)
+            .unwrap()
+            .data
+            .len()
+            + process_image(second.clone(), None, &limits(None), ImageDetail::Auto)
+                .unwrap()
+                .data
+                .len();
+        let cache = ImageCache::new(size, Duration::from_secs(60));
+
+        let url = "https://example.com/cat.png";
+        assert!(cache
+            .get_url(url, &limits(None), ImageDetail::Auto)
+            .is_none());
+        cache
+            .process(
+                first.clone(),
+                None,
+                &limits(None),
+                ImageDetail::Auto,
+                Some(url),
+            )
+            .unwrap();
+        assert_eq!(
+            cache
+                .get_url(url, &limits(None), ImageDetail::Auto)
+                .unwrap()
+                .width,
+            8
+        );
+        // The declared mimetype is still checked on hits
+        assert!(cache
+            .process(
+                first.clone(),
+                Some("image/jpeg"),
+                &limits(None),
+                ImageDetail::Auto,
+                None
+            )
+            .is_err());
+
+        // The oldest image is evicted above the size bound
+        cache
+            .process(second.clone(), None, &limits(None), ImageDetail::Auto, None)
+            .unwrap();
+        assert!(cache
+            .get_url(url, &limits(None), ImageDetail::Auto)
+            .is_some());
+        let third = encoded(4, 4, ImageFormat::Png);
+        cache
+            .process(third, None, &limits(None), ImageDetail::Auto, None)
+            .unwrap();
+        assert!(cache
+            .get_url(url, &limits(None), ImageDetail::Auto)
+            .is_none());
+        assert!(cache.inner.lock().unwrap().bytes <= size);
+
+        // Expired entries are not returned
+        let cache = ImageCache::new(size, Duration::ZERO);
+        cache
+            .process(first, None, &limits(None), ImageDetail::Auto, Some(url))
+            .unwrap();
+        assert!(cache
+            .get_url(url, &limits(None), ImageDetail::Auto)
+            .is_none());
+    }
+}
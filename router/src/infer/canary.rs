@@ -0,0 +1,76 @@
+use crate::infer::Infer;
+use crate::{GenerateParameters, GenerateRequest};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info_span, Instrument};
+
+/// Inputs of the canary generation
+const CANARY_INPUTS: &str = "canary";
+
+/// Synthetic generation periodically sent through the full pipeline
+/// (validation, queue and shards) to catch a broken model behind healthy shards
+#[derive(Clone)]
+pub(crate) struct Canary {
+    healthy: Arc<AtomicBool>,
+}
+
+impl Canary {
+    /// Spawn a background task probing `infer` every `interval`
+    pub(crate) fn spawn(infer: Infer, interval: Duration) -> Self {
+        let healthy = Arc::new(AtomicBool::new(false));
+        tokio::spawn(canary_task(infer, interval, healthy.clone()));
+        Self { healthy }
+    }
+
+    /// Whether the last canary generation succeeded
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+}
+
+async fn canary_task(infer: Infer, interval: Duration, healthy: Arc<AtomicBool>) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let value = probe(&infer, interval)
+            .instrument(info_span!("canary"))
+            .await;
+        healthy.store(value, Ordering::SeqCst);
+        metrics::gauge!("tgi_canary_healthy").set(if value { 1.0 } else { 0.0 });
+    }
+}
+
+/// Run one canary generation, failing if it takes longer than `timeout`
+async fn probe(infer: &Infer, timeout: Duration) -> bool {
+    let start_time = Instant::now();
+    let request = GenerateRequest {
+        inputs: CANARY_INPUTS.to_string(),
+        input_ids: None,
+        messages: None,
+        parameters: GenerateParameters {
+            max_new_tokens: Some(1),
+            ..Default::default()
+        },
+    };
+
+    match tokio::time::timeout(timeout, infer.generate(request)).await {
+        Ok(Ok(_)) => {
+            metrics::counter!("tgi_canary_success").increment(1);
+            metrics::histogram!("tgi_canary_duration").record(start_time.elapsed().as_secs_f64());
+            true
+        }
+        Ok(Err(err)) => {
+            tracing::error!("Canary generation failed: {err}");
+            metrics::counter!("tgi_canary_failure", "err" => err.error_type().to_string())
+                .increment(1);
+            false
+        }
+        Err(_) => {
+            tracing::error!("Canary generation timed out after {timeout:?}");
+            metrics::counter!("tgi_canary_failure", "err" => "timeout").increment(1);
+            false
+        }
+    }
+}
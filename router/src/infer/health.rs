@@ -1,3 +1,4 @@
+use crate::events::{Events, LifecycleEvent};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use text_generation_client::Health;
@@ -6,16 +7,22 @@ use text_generation_client::Health;
 pub(crate) struct HealthCheck {
     client: Arc<dyn Health + Send + Sync>,
     generation_health: Arc<AtomicBool>,
+    /// Whether the last health check failed
+    check_failed: Arc<AtomicBool>,
+    events: Events,
 }
 
 impl HealthCheck {
     pub(crate) fn new(
         client: Arc<dyn Health + Send + Sync>,
         generation_health: Arc<AtomicBool>,
+        events: Events,
     ) -> Self {
         Self {
             client,
             generation_health,
+            check_failed: Arc::new(AtomicBool::new(false)),
+            events,
         }
     }
 
@@ -29,6 +36,9 @@ impl HealthCheck {
         .is_ok();
         // Update generation health
         self.generation_health.store(value, Ordering::SeqCst);
+        if self.check_failed.swap(!value, Ordering::SeqCst) && value {
+            self.events.send(LifecycleEvent::ShardReconnected);
+        }
         value
     }
 }
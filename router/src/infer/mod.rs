@@ -1,26 +1,42 @@
+mod canary;
 mod health;
 pub(crate) mod v2;
 pub(crate) mod v3;
 
+pub(crate) use canary::Canary;
 pub(crate) use health::HealthCheck;
 
-use crate::validation::{ValidGenerateRequest, Validation, ValidationError};
+use crate::adapter_acl::AdapterAcl;
+use crate::auth::auth_context;
+use crate::grammar::TokenFsm;
+use crate::prompt_log::PromptEncryptor;
+use crate::provenance::ProvenanceSigner;
+use crate::quota::{unix_now, QuotaError, QuotaReservation, QuotaTracker};
+use crate::rate_limit::{RateLimitError, RateLimiter};
+use crate::validation::{
+    candidate_seeds, GrammarArtifact, LoadedAdapters, ValidGenerateRequest, Validation,
+    ValidationError, ValidationLimits, ValidationRejections,
+};
 use crate::{
     ChatTemplateInputs, ChatTemplateVersions, FinishReason, GenerateRequest, HubProcessorConfig,
     HubTokenizerConfig, Message, MessageChunk, PrefillToken, TextMessage, Token,
 };
 use crate::{
-    FunctionRef, FunctionsMap, GrammarType, Properties, TokenizerConfigToken, Tool, ToolType, Tools,
+    FunctionRef, FunctionsMap, GrammarType, Properties, TokenizerConfigToken, Tool, ToolChoice,
+    ToolType, Tools,
 };
 use futures::future::try_join_all;
 use minijinja::{Environment, ErrorKind, Template};
 use minijinja_contrib::pycompat;
 
+use regex::Regex;
 use serde_json::{json, Map, Value};
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use thiserror::Error;
-use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore, TryAcquireError};
 use tokio::time::Instant;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_stream::StreamExt;
@@ -45,6 +61,34 @@ pub struct Infer {
     chat_template: Option<ChatTemplate>,
     /// Inference limit
     limit_concurrent_requests: Arc<Semaphore>,
+    /// Adapter ids used as metric labels
+    adapter_labels: AdapterLabels,
+    /// Requests slower than this threshold are logged
+    slow_request_threshold: Option<Duration>,
+    /// Aggregated validation rejections
+    validation_rejections: ValidationRejections,
+    /// Per-key request and token limits
+    rate_limiter: Option<RateLimiter>,
+    /// Adapters each key may request
+    adapter_acl: Option<AdapterAcl>,
+    /// Signs the provenance of the responses
+    provenance: Option<ProvenanceSigner>,
+    /// Per-key daily and monthly token quotas
+    quotas: Option<QuotaTracker>,
+    /// Caps of the streamed responses
+    stream_limits: StreamLimits,
+    /// Encrypts the prompts and outputs written to the logs
+    prompt_encryptor: Option<PromptEncryptor>,
+}
+
+/// Caps of a streamed response, independent of the token limits.
+/// Streams reaching a cap are stopped with the `max_duration` or `max_bytes` finish reason.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct StreamLimits {
+    /// Maximum time since the request was received
+    pub max_duration: Option<Duration>,
+    /// Maximum size of the text of the streamed tokens, in bytes
+    pub max_bytes: Option<usize>,
 }
 
 impl Infer {
@@ -53,21 +97,17 @@ impl Infer {
         scheduler: Arc<dyn Scheduler + Send + Sync>,
         validation: Validation,
         max_concurrent_requests: usize,
-        tokenizer_config: HubTokenizerConfig,
-        processor_config: HubProcessorConfig,
+        chat_template: Option<ChatTemplate>,
+        max_adapter_metric_labels: usize,
+        slow_request_threshold: Option<Duration>,
+        validation_rejection_sample_rate: f64,
+        rate_limiter: Option<RateLimiter>,
+        provenance: Option<ProvenanceSigner>,
+        adapter_acl: Option<AdapterAcl>,
+        quotas: Option<QuotaTracker>,
+        stream_limits: StreamLimits,
+        prompt_encryptor: Option<PromptEncryptor>,
     ) -> Self {
-        let chat_template = tokenizer_config
-            .chat_template
-            .or(processor_config.chat_template)
-            .and_then(|t| match t {
-                ChatTemplateVersions::Single(template) => Some(template),
-                ChatTemplateVersions::Multiple(templates) => templates
-                    .into_iter()
-                    .find(|t| t.name == "default")
-                    .map(|t| t.template),
-            })
-            .map(|t| ChatTemplate::new(t, tokenizer_config.bos_token, tokenizer_config.eos_token));
-
         // Inference limit with a semaphore
         let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
 
@@ -76,9 +116,80 @@ impl Infer {
             scheduler,
             chat_template,
             limit_concurrent_requests: semaphore,
+            adapter_labels: AdapterLabels::new(max_adapter_metric_labels),
+            slow_request_threshold,
+            validation_rejections: ValidationRejections::new(validation_rejection_sample_rate),
+            rate_limiter,
+            provenance,
+            adapter_acl,
+            quotas,
+            stream_limits,
+            prompt_encryptor,
         }
     }
 
+    /// Requests slower than this threshold are logged
+    pub(crate) fn slow_request_threshold(&self) -> Option<Duration> {
+        self.slow_request_threshold
+    }
+
+    /// Signer of the response provenance, if enabled
+    pub(crate) fn provenance(&self) -> Option<&ProvenanceSigner> {
+        self.provenance.as_ref()
+    }
+
+    /// Caps of the streamed responses
+    pub(crate) fn stream_limits(&self) -> StreamLimits {
+        self.stream_limits
+    }
+
+    /// Prompt or output as written to the logs, encrypted if prompt encryption is enabled
+    pub(crate) fn loggable_text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match &self.prompt_encryptor {
+            Some(encryptor) => Cow::Owned(encryptor.encrypt(text)),
+            None => Cow::Borrowed(text),
+        }
+    }
+
+    /// Token quotas, if enabled
+    pub(crate) fn quotas(&self) -> Option<&QuotaTracker> {
+        self.quotas.as_ref()
+    }
+
+    /// Priority class of the caller of the current request
+    pub(crate) fn priority(&self) -> String {
+        self.validation.priority()
+    }
+
+    /// Validation limits adjustable at runtime
+    pub(crate) fn validation_limits(&self) -> &ValidationLimits {
+        self.validation.validation_limits()
+    }
+
+    /// Compile `grammar` in the background, for the requests referencing it by the returned id
+    pub(crate) fn compile_grammar(
+        &self,
+        grammar: GrammarType,
+    ) -> Result<(String, GrammarArtifact), InferError> {
+        self.validation
+            .compile_grammar_artifact(grammar)
+            .map_err(|err| {
+                tracing::error!("{err}");
+                err.into()
+            })
+    }
+
+    /// Grammar compiled by `compile_grammar`, `None` if the id is unknown
+    pub(crate) fn grammar_artifact(&self, id: &str) -> Option<GrammarArtifact> {
+        self.validation.grammar_artifact(id)
+    }
+
+    /// Metric label of the given adapter id
+    pub(crate) fn adapter_label(&self, adapter_id: Option<&str>) -> Option<String> {
+        let loaded = self.validation.loaded_adapters();
+        adapter_id.map(|adapter_id| self.adapter_labels.label(adapter_id, &loaded))
+    }
+
     /// Add a new request to the queue and return a stream of InferStreamResponse
     #[instrument(skip_all)]
     pub(crate) async fn generate_stream(
@@ -91,19 +202,117 @@ impl Infer {
             .limit_concurrent_requests
             .try_acquire_owned()
             .map_err(|err| {
-                metrics::increment_counter!("tgi_request_failure", "err" => "overloaded");
+                metrics::counter!("tgi_request_failure", "err" => "overloaded").increment(1);
                 tracing::error!("{err}");
                 err
             })?;
 
+        self.check_adapter_access(request.parameters.adapter_id.as_ref())?;
+
+        // Summarize the payload of sampled requests before it is consumed by the validation
+        let example = self.validation_rejections.sample().then(|| {
+            format!(
+                "input_chars={} {}",
+                request.inputs.chars().count(),
+                request.parameters.summary()
+            )
+        });
+
+        // Quotas only depend on the past usage, reject before spending time on the validation
+        if let Some(quotas) = &self.quotas {
+            quotas
+                .check(&auth_context().key(), unix_now())
+                .map_err(|err| {
+                    metrics::counter!("tgi_request_failure", "err" => "quota_exceeded")
+                        .increment(1);
+                    tracing::warn!("{err}");
+                    err
+                })?;
+        }
+
         // Validate request
-        let valid_request = self.validation.validate(request).await.map_err(|err| {
-            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        let mut valid_request = self.validation.validate(request).await.map_err(|err| {
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            self.validation_rejections.record(&err, example);
             tracing::error!("{err}");
             err
         })?;
 
-        self.scheduler.schedule(valid_request, permit)
+        // Rate limit on the requested tokens, which are only known after the validation
+        if let Some(rate_limiter) = &self.rate_limiter {
+            let tokens = valid_request.input_length as u64
+                + valid_request.stopping_parameters.max_new_tokens as u64;
+            rate_limiter
+                .admit(&auth_context().key(), tokens)
+                .map_err(|err| {
+                    metrics::counter!("tgi_request_failure", "err" => "rate_limited").increment(1);
+                    tracing::warn!("{err}");
+                    err
+                })?;
+        }
+
+        // Reserve the tokens the request may use, the quota is charged for the tokens it used
+        let quota_reservation = match &self.quotas {
+            Some(quotas) => {
+                let input_tokens = valid_request.input_length as u64;
+                let tokens = input_tokens + valid_request.stopping_parameters.max_new_tokens as u64;
+                let reservation = quotas
+                    .reserve(&auth_context().key(), tokens, input_tokens, unix_now())
+                    .map_err(|err| {
+                        metrics::counter!("tgi_request_failure", "err" => "quota_exceeded")
+                            .increment(1);
+                        tracing::warn!("{err}");
+                        err
+                    })?;
+                Some(reservation)
+            }
+            None => None,
+        };
+
+        // The shards do not know the stop regexes, they are matched on the streamed tokens. The
+        // tokens are also followed in the FSM of the grammar, to catch the shards leaving it.
+        let stop_regex = valid_request.stopping_parameters.stop_regex.take();
+        let grammar_fsm = valid_request.stopping_parameters.grammar_fsm.clone();
+        let (permit, input_length, mut stream) = self.scheduler.schedule(valid_request, permit)?;
+        if let Some(grammar_fsm) = grammar_fsm {
+            stream = check_grammar(stream, grammar_fsm);
+        }
+        if let Some(stop_regex) = stop_regex {
+            stream = stop_on_regex(stream, stop_regex);
+        }
+        if let Some(reservation) = quota_reservation {
+            stream = charge_quota(stream, reservation);
+        }
+        Ok((permit, input_length, stream))
+    }
+
+    /// Adapter access control
+    fn check_adapter_access(&self, adapter_id: Option<&String>) -> Result<(), InferError> {
+        if let (Some(acl), Some(adapter_id)) = (&self.adapter_acl, adapter_id) {
+            if !acl.allows(&auth_context().key(), adapter_id) {
+                metrics::counter!("tgi_request_failure", "err" => "forbidden").increment(1);
+                let err = InferError::AdapterNotAllowed(adapter_id.clone());
+                tracing::warn!("{err}");
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate a request without scheduling it. Quotas and rate limits are not applied.
+    #[instrument(skip_all)]
+    pub(crate) async fn validate(
+        &self,
+        request: GenerateRequest,
+    ) -> Result<ValidGenerateRequest, InferError> {
+        self.check_adapter_access(request.parameters.adapter_id.as_ref())?;
+        if let Some(best_of) = request.parameters.best_of {
+            self.validation.validate_best_of(best_of)?;
+        }
+        self.validation.validate(request).await.map_err(|err| {
+            tracing::error!("{err}");
+            err.into()
+        })
     }
 
     /// Tokenizer the input
@@ -117,7 +326,12 @@ impl Infer {
         let truncate = request.parameters.truncate;
         let encoding = self
             .validation
-            .tokenize(inputs, truncate)
+            .tokenize(
+                inputs,
+                truncate,
+                request.parameters.truncation_direction,
+                request.parameters.add_special_tokens.unwrap_or(true),
+            )
             .await
             .map_err(|err| {
                 tracing::error!("Tokenization {err}");
@@ -139,8 +353,9 @@ impl Infer {
             .as_ref()
             .ok_or_else(|| InferError::TemplateError(ErrorKind::TemplateNotFound.into()))?
             .apply(messages, grammar_with_prompt)
+            .map_err(InferError::TemplateError)
             .map_err(|e| {
-                metrics::increment_counter!("tgi_request_failure", "err" => "template");
+                metrics::counter!("tgi_request_failure", "err" => "template").increment(1);
                 tracing::error!("{e}");
                 e
             })
@@ -164,6 +379,7 @@ impl Infer {
         let mut result_generated_text = None;
         let mut result_start = None;
         let mut result_queued = None;
+        let mut result_first_token = None;
 
         // Iterate on stream
         while let Some(response) = stream.next().await {
@@ -174,6 +390,7 @@ impl Infer {
                 }
                 // Push last token
                 InferStreamResponse::Intermediate { token, top_tokens } => {
+                    result_first_token.get_or_insert_with(Instant::now);
                     result_tokens.push(token);
                     result_top_tokens.push(top_tokens);
                 }
@@ -186,6 +403,7 @@ impl Infer {
                     queued,
                     top_tokens,
                 } => {
+                    result_first_token.get_or_insert_with(Instant::now);
                     result_tokens.push(token);
                     result_top_tokens.push(top_tokens);
                     result_generated_text = Some(generated_text);
@@ -196,9 +414,12 @@ impl Infer {
         }
 
         // Check that we received a `InferStreamResponse::End` message
-        if let (Some(generated_text), Some(queued), Some(start)) =
-            (result_generated_text, result_queued, result_start)
-        {
+        if let (Some(generated_text), Some(queued), Some(start), Some(first_token)) = (
+            result_generated_text,
+            result_queued,
+            result_start,
+            result_first_token,
+        ) {
             Ok(InferResponse {
                 prefill: result_prefill,
                 _input_length,
@@ -206,6 +427,7 @@ impl Infer {
                 generated_text,
                 queued,
                 start,
+                first_token,
                 top_tokens: if use_top_tokens {
                     result_top_tokens
                 } else {
@@ -214,7 +436,7 @@ impl Infer {
             })
         } else {
             let err = InferError::IncompleteGeneration;
-            metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
+            metrics::counter!("tgi_request_failure", "err" => "incomplete").increment(1);
             tracing::error!("{err}");
             Err(err)
         }
@@ -229,10 +451,17 @@ impl Infer {
     ) -> Result<(InferResponse, Vec<InferResponse>), InferError> {
         // validate  best_of parameter separately
         let best_of = self.validation.validate_best_of(best_of)?;
+        let seeds = candidate_seeds(&request.parameters)?;
+        debug_assert_eq!(seeds.len(), best_of);
 
-        // create multiple generate requests
-        let mut infer_responses: Vec<InferResponse> =
-            try_join_all((0..best_of).map(|_| self.generate(request.clone()))).await?;
+        // create multiple generate requests, each with its own seed
+        let mut infer_responses: Vec<InferResponse> = try_join_all(seeds.into_iter().map(|seed| {
+            let mut request = request.clone();
+            request.parameters.seed = seed;
+            request.parameters.best_of_seeds = None;
+            self.generate(request)
+        }))
+        .await?;
 
         // get the sequence with the highest log probability per token
         let mut max_index = 0;
@@ -258,13 +487,254 @@ impl Infer {
     }
 }
 
+/// End `stream` once the text of its tokens matches `stop_regex`. The generated text ends with
+/// the match and dropping the original stream cancels the generation on the shards.
+/// The scheduler timings are not known before the end of the generation: the queue time is
+/// counted from now and the inference from the first token.
+fn stop_on_regex(
+    mut stream: UnboundedReceiverStream<Result<InferStreamResponse, InferError>>,
+    stop_regex: Regex,
+) -> UnboundedReceiverStream<Result<InferStreamResponse, InferError>> {
+    let queued = Instant::now();
+    let (response_tx, response_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut text = String::new();
+        let mut generated_tokens = 0;
+        let mut start = None;
+        while let Some(response) = stream.next().await {
+            let response = match response {
+                Ok(InferStreamResponse::Intermediate { token, top_tokens }) => {
+                    let start = *start.get_or_insert_with(Instant::now);
+                    generated_tokens += 1;
+                    if !token.special {
+                        text.push_str(&token.text);
+                    }
+                    match stop_regex.find(&text) {
+                        Some(stop) => {
+                            metrics::counter!("tgi_stop_regex_match").increment(1);
+                            text.truncate(stop.end());
+                            let end = InferStreamResponse::End {
+                                token,
+                                top_tokens,
+                                generated_text: GeneratedText {
+                                    text,
+                                    generated_tokens,
+                                    finish_reason: FinishReason::StopSequence,
+                                    seed: None,
+                                },
+                                start,
+                                queued,
+                            };
+                            let _ = response_tx.send(Ok(end));
+                            break;
+                        }
+                        None => Ok(InferStreamResponse::Intermediate { token, top_tokens }),
+                    }
+                }
+                response => response,
+            };
+            // The client is gone
+            if response_tx.send(response).is_err() {
+                break;
+            }
+        }
+    });
+    UnboundedReceiverStream::new(response_rx)
+}
+
+/// Count the tokens of `stream` in the quota `reservation`, which is settled when the stream ends
+/// or its receiver is dropped
+fn charge_quota(
+    mut stream: UnboundedReceiverStream<Result<InferStreamResponse, InferError>>,
+    mut reservation: QuotaReservation,
+) -> UnboundedReceiverStream<Result<InferStreamResponse, InferError>> {
+    let (response_tx, response_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let response = tokio::select! {
+                response = stream.next() => response,
+                // The client disconnected
+                _ = response_tx.closed() => break,
+            };
+            let Some(response) = response else {
+                break;
+            };
+            reservation.charge(matches!(
+                response,
+                Ok(InferStreamResponse::Intermediate { .. } | InferStreamResponse::End { .. })
+            ));
+            if response_tx.send(response).is_err() {
+                break;
+            }
+        }
+        drop(reservation);
+    });
+    UnboundedReceiverStream::new(response_rx)
+}
+
+/// Follow the tokens of `stream` in the FSM of its grammar, which the shards should never leave.
+/// The first token the grammar does not allow, or an end of sequence before the grammar is
+/// complete, ends `stream` with the `grammar_violation` finish reason: the text of the invalid
+/// token is not emitted and dropping the original stream cancels the generation on the shards.
+fn check_grammar(
+    mut stream: UnboundedReceiverStream<Result<InferStreamResponse, InferError>>,
+    fsm: Arc<TokenFsm>,
+) -> UnboundedReceiverStream<Result<InferStreamResponse, InferError>> {
+    let queued = Instant::now();
+    let (response_tx, response_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        // The special tokens, such as the end of sequence token, can only end the text
+        let next_state = |state: u32, token: &Token| match token.special {
+            true => fsm.is_final_state(state).then_some(state),
+            false => fsm.next_state(state, token.id),
+        };
+        let mut state = 0;
+        let mut text = String::new();
+        let mut generated_tokens = 0;
+        let mut start = None;
+        while let Some(response) = stream.next().await {
+            let response = match response {
+                Ok(InferStreamResponse::Intermediate { token, top_tokens }) => {
+                    let start = *start.get_or_insert_with(Instant::now);
+                    generated_tokens += 1;
+                    match next_state(state, &token) {
+                        Some(next) => {
+                            state = next;
+                            if !token.special {
+                                text.push_str(&token.text);
+                            }
+                            Ok(InferStreamResponse::Intermediate { token, top_tokens })
+                        }
+                        None => {
+                            let generated_text = GeneratedText {
+                                text,
+                                generated_tokens,
+                                finish_reason: FinishReason::GrammarViolation,
+                                seed: None,
+                            };
+                            let end =
+                                grammar_violation(token, top_tokens, generated_text, start, queued);
+                            let _ = response_tx.send(Ok(end));
+                            break;
+                        }
+                    }
+                }
+                Ok(InferStreamResponse::End {
+                    token,
+                    top_tokens,
+                    generated_text,
+                    start,
+                    queued,
+                }) => {
+                    let eos = matches!(
+                        generated_text.finish_reason,
+                        FinishReason::EndOfSequenceToken
+                    );
+                    match next_state(state, &token) {
+                        Some(end) if !eos || fsm.is_final_state(end) => {
+                            Ok(InferStreamResponse::End {
+                                token,
+                                top_tokens,
+                                generated_text,
+                                start,
+                                queued,
+                            })
+                        }
+                        _ => {
+                            let generated_text = GeneratedText {
+                                text,
+                                finish_reason: FinishReason::GrammarViolation,
+                                ..generated_text
+                            };
+                            let end =
+                                grammar_violation(token, top_tokens, generated_text, start, queued);
+                            let _ = response_tx.send(Ok(end));
+                            break;
+                        }
+                    }
+                }
+                response => response,
+            };
+            // The client is gone
+            if response_tx.send(response).is_err() {
+                break;
+            }
+        }
+    });
+    UnboundedReceiverStream::new(response_rx)
+}
+
+/// Last response of a generation that fell out of its grammar, without the text of the invalid
+/// token
+fn grammar_violation(
+    token: Token,
+    top_tokens: Vec<Token>,
+    generated_text: GeneratedText,
+    start: Instant,
+    queued: Instant,
+) -> InferStreamResponse {
+    metrics::counter!("tgi_grammar_violation").increment(1);
+    tracing::warn!(
+        "Generation out of its grammar at token {} after {} tokens",
+        token.id,
+        generated_text.generated_tokens
+    );
+    InferStreamResponse::End {
+        token: Token {
+            text: String::new(),
+            ..token
+        },
+        top_tokens,
+        generated_text,
+        start,
+        queued,
+    }
+}
+
+/// Label used for the adapters over the cardinality cap
+const OTHER_ADAPTERS_LABEL: &str = "other";
+
+/// Bounded set of adapter ids used as metric labels.
+/// Adapters seen after `max_labels` is reached share the "other" label.
+#[derive(Clone)]
+struct AdapterLabels {
+    max_labels: usize,
+    labels: Arc<Mutex<HashSet<String>>>,
+}
+
+impl AdapterLabels {
+    fn new(max_labels: usize) -> Self {
+        Self {
+            max_labels,
+            labels: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Only the adapters loaded by the shards get their own label, the ids sent by the clients
+    /// cannot use up the labels
+    fn label(&self, adapter_id: &str, loaded: &LoadedAdapters) -> String {
+        if !loaded.reported(adapter_id) {
+            return OTHER_ADAPTERS_LABEL.to_string();
+        }
+        let mut labels = self.labels.lock().unwrap();
+        if labels.contains(adapter_id) {
+            return adapter_id.to_string();
+        }
+        if labels.len() < self.max_labels {
+            labels.insert(adapter_id.to_string());
+            return adapter_id.to_string();
+        }
+        OTHER_ADAPTERS_LABEL.to_string()
+    }
+}
+
 /// Raise a exception (custom function) used in the chat templates
 fn raise_exception(err_text: String) -> Result<String, minijinja::Error> {
     Err(minijinja::Error::new(ErrorKind::SyntaxError, err_text))
 }
 
-#[derive(Clone)]
-struct ChatTemplate {
+#[derive(Clone, Debug)]
+pub(crate) struct ChatTemplate {
     template: Template<'static, 'static>,
     bos_token: Option<String>,
     eos_token: Option<String>,
@@ -272,6 +742,24 @@ struct ChatTemplate {
 }
 
 impl ChatTemplate {
+    /// Default chat template of the tokenizer or processor config, if any
+    pub(crate) fn from_configs(
+        tokenizer_config: HubTokenizerConfig,
+        processor_config: HubProcessorConfig,
+    ) -> Option<Self> {
+        tokenizer_config
+            .chat_template
+            .or(processor_config.chat_template)
+            .and_then(|t| match t {
+                ChatTemplateVersions::Single(template) => Some(template),
+                ChatTemplateVersions::Multiple(templates) => templates
+                    .into_iter()
+                    .find(|t| t.name == "default")
+                    .map(|t| t.template),
+            })
+            .map(|t| ChatTemplate::new(t, tokenizer_config.bos_token, tokenizer_config.eos_token))
+    }
+
     fn new(
         template: String,
         bos_token: Option<TokenizerConfigToken>,
@@ -299,11 +787,11 @@ impl ChatTemplate {
         }
     }
 
-    fn apply(
+    pub(crate) fn apply(
         &self,
         mut messages: Vec<Message>,
         grammar_with_prompt: Option<(GrammarType, String)>,
-    ) -> Result<String, InferError> {
+    ) -> Result<String, minijinja::Error> {
         if self.use_default_tool_template {
             if let Some(last_message) = messages.last_mut() {
                 if let Some((GrammarType::Json(tools), tool_prompt)) = grammar_with_prompt {
@@ -316,142 +804,164 @@ impl ChatTemplate {
 
         let messages: Vec<TextMessage> = messages.into_iter().map(|c| c.into()).collect();
 
-        self.template
-            .render(ChatTemplateInputs {
-                messages,
-                bos_token: self.bos_token.as_deref(),
-                eos_token: self.eos_token.as_deref(),
-                add_generation_prompt: true,
-                tools: None,
-                tools_prompt: None,
-            })
-            .map_err(InferError::TemplateError)
+        self.template.render(ChatTemplateInputs {
+            messages,
+            bos_token: self.bos_token.as_deref(),
+            eos_token: self.eos_token.as_deref(),
+            add_generation_prompt: true,
+            tools: None,
+            tools_prompt: None,
+        })
     }
 }
 
 pub struct ToolGrammar {}
 
+/// Function the model calls to report that it cannot call any of the tools
+const NOTIFY_ERROR_FUNCTION: &str = "notify_error";
+
 impl ToolGrammar {
+    /// JSON Schema of the calls to the tools selected by `tool_choice`: `{"function": {"_name":
+    /// ..., <arguments>}}`, where the name and the arguments of each function are constrained
+    /// together.
     pub fn apply(
         tools: Option<Vec<Tool>>,
-        tool_choice: Option<ToolType>,
+        tool_choice: ToolChoice,
     ) -> Result<Option<Tools>, InferError> {
-        if let Some((req_tools, tool_choice)) = tools.zip(tool_choice) {
-            // let tool_prompt = tool_prompt.unwrap_or_default();
-            let tools_to_use = match tool_choice {
-                ToolType::FunctionName(name) => {
-                    vec![req_tools
-                        .iter()
-                        .find(|tool| tool.function.name == *name)
-                        .unwrap_or_else(|| panic!("Tool with name {} not found", name))
-                        .clone()]
-                }
-                ToolType::Function { function } => {
-                    let tool = req_tools
-                        .iter()
-                        .find(|tool| tool.function.name == function.name)
-                        .unwrap_or_else(|| panic!("Tool with name {} not found", function.name))
-                        .clone();
-                    vec![tool]
-                }
-                ToolType::OneOf => req_tools.to_owned(),
-            };
+        let (Some(req_tools), Some(tool_choice)) = (tools, tool_choice.0) else {
+            return Ok(None);
+        };
 
-            // adds the error notification function for LLM feedback if required
-            let mut text_response_properties = Map::new();
-            text_response_properties.insert(
-                "error".to_string(),
-                serde_json::json!({
-                    "type": "string",
-                    "description": "The error or issue to notify"
-                }),
-            );
-            text_response_properties.insert(
-                "_name".to_string(),
+        // The calls are told apart by their name
+        let mut names = HashSet::new();
+        for tool in &req_tools {
+            if tool.function.name == NOTIFY_ERROR_FUNCTION {
+                return Err(InferError::ToolError(format!(
+                    "Tool name `{NOTIFY_ERROR_FUNCTION}` is reserved"
+                )));
+            }
+            if !names.insert(tool.function.name.as_str()) {
+                return Err(InferError::ToolError(format!(
+                    "Tool `{}` is defined more than once",
+                    tool.function.name
+                )));
+            }
+        }
+
+        let find_tool = |name: &str| {
+            req_tools
+                .iter()
+                .find(|tool| tool.function.name == name)
+                .cloned()
+                .ok_or_else(|| InferError::ToolError(format!("Tool with name {name} not found")))
+        };
+        let tools_to_use = match tool_choice {
+            ToolType::FunctionName(name) => vec![find_tool(&name)?],
+            ToolType::Function { function } => vec![find_tool(&function.name)?],
+            ToolType::OneOf => req_tools.clone(),
+        };
+
+        // adds the error notification function for LLM feedback if required
+        let mut text_response_properties = Map::new();
+        text_response_properties.insert(
+            "error".to_string(),
+            serde_json::json!({
+                "type": "string",
+                "description": "The error or issue to notify"
+            }),
+        );
+        text_response_properties.insert(
+            "_name".to_string(),
+            serde_json::json!({
+                "type": "string",
+                "const": NOTIFY_ERROR_FUNCTION
+            }),
+        );
+
+        let functions: HashMap<String, serde_json::Value> = tools_to_use
+            .iter()
+            .map(|tool| Ok((tool.function.name.clone(), Self::function_schema(tool)?)))
+            .chain([Ok((
+                NOTIFY_ERROR_FUNCTION.to_string(),
                 serde_json::json!({
-                    "type": "string",
-                    "const": "notify_error"
+                    "properties": text_response_properties,
+                    "required": ["error", "_name"],
+                    "type": "object"
                 }),
-            );
+            ))])
+            .collect::<Result<_, InferError>>()?;
 
-            let functions: HashMap<String, serde_json::Value> = tools_to_use
-                .iter()
-                .map(|tool| {
-                    let func = tool.function.clone();
-
-                    // Clone the existing parameters, which are expected to be a JSON object
-                    let mut params = if let Value::Object(params) = &func.arguments {
-                        params.clone()
-                    } else {
-                        Map::new()
-                    };
-
-                    // Insert the function's description at the top level, outside of properties
-                    params.insert(
-                        "description".to_string(),
-                        Value::String(func.description.clone().unwrap_or_default()),
-                    );
+        let tools = Tools {
+            functions_map: FunctionsMap { functions },
+            object_type: "object".to_string(),
+            properties: Properties {
+                function: tools_to_use
+                    .iter()
+                    .map(|tool| FunctionRef {
+                        ref_path: format!("#/$functions/{}", tool.function.name.clone()),
+                    })
+                    .chain(std::iter::once(FunctionRef {
+                        ref_path: format!("#/$functions/{NOTIFY_ERROR_FUNCTION}"),
+                    }))
+                    .collect(),
+            },
+            required: vec!["function".to_string()],
+        };
 
-                    // Ensure 'properties' exists and is an object
-                    let properties = params
-                        .entry("properties".to_string())
-                        .or_insert_with(|| json!({}))
-                        .as_object_mut()
-                        .unwrap();
-
-                    // Insert the constant for the function name inside 'properties'
-                    properties.insert(
-                        "_name".to_string(),
-                        json!({
-                            "type": "string",
-                            "const": func.name.clone(),
-                            // "description": "The name of the function"
-                        }),
-                    );
+        Ok(Some(tools))
+    }
 
-                    // Check if 'required' exists, and it is an array. If not, create an empty array.
-                    let required = params
-                        .entry("required".to_string())
-                        .or_insert_with(|| json!([]))
-                        .as_array_mut()
-                        .unwrap();
+    /// Schema of the arguments of `tool`, with the constant `_name` of the function
+    fn function_schema(tool: &Tool) -> Result<Value, InferError> {
+        let func = &tool.function;
+        let invalid = |reason: &str| {
+            InferError::ToolError(format!(
+                "Invalid parameters for tool `{}`: {reason}",
+                func.name
+            ))
+        };
 
-                    // Add 'name' to the 'required' array if it is not already present
-                    if !required.iter().any(|r| r == "_name") {
-                        required.push(json!("_name"));
-                    }
+        // Clone the existing parameters, which are expected to be a JSON object
+        let mut params = match &func.arguments {
+            Value::Object(params) => params.clone(),
+            Value::Null => Map::new(),
+            _ => return Err(invalid("expected a JSON Schema object")),
+        };
 
-                    (func.name, Value::Object(params))
-                })
-                .chain([(
-                    "notify_error".to_string(),
-                    serde_json::json!({
-                        "properties": text_response_properties,
-                        "required": ["error", "_name"],
-                        "type": "object"
-                    }),
-                )])
-                .collect();
-
-            let tools = Tools {
-                functions_map: FunctionsMap { functions },
-                properties: Properties {
-                    function: tools_to_use
-                        .iter()
-                        .map(|tool| FunctionRef {
-                            ref_path: format!("#/$functions/{}", tool.function.name.clone()),
-                        })
-                        .chain(std::iter::once(FunctionRef {
-                            ref_path: "#/$functions/notify_error".to_string(),
-                        }))
-                        .collect(),
-                },
-            };
+        // Insert the function's description at the top level, outside of properties
+        params.insert(
+            "description".to_string(),
+            Value::String(func.description.clone().unwrap_or_default()),
+        );
+        params
+            .entry("type".to_string())
+            .or_insert_with(|| json!("object"));
 
-            return Ok(Some(tools));
+        // Insert the constant for the function name inside 'properties'
+        params
+            .entry("properties".to_string())
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+            .ok_or_else(|| invalid("`properties` must be an object"))?
+            .insert(
+                "_name".to_string(),
+                json!({
+                    "type": "string",
+                    "const": func.name.clone(),
+                }),
+            );
+
+        // Add '_name' to the 'required' array if it is not already present
+        let required = params
+            .entry("required".to_string())
+            .or_insert_with(|| json!([]))
+            .as_array_mut()
+            .ok_or_else(|| invalid("`required` must be an array"))?;
+        if !required.iter().any(|r| r == "_name") {
+            required.push(json!("_name"));
         }
-        // Err(InferError::ToolError("No tools provided".to_string()))
-        Ok(None)
+
+        Ok(Value::Object(params))
     }
 }
 
@@ -500,6 +1010,8 @@ pub(crate) struct InferResponse {
     pub(crate) generated_text: GeneratedText,
     pub(crate) queued: Instant,
     pub(crate) start: Instant,
+    /// Instant when the first generated token was received
+    pub(crate) first_token: Instant,
     pub(crate) top_tokens: Vec<Vec<Token>>,
 }
 
@@ -517,6 +1029,12 @@ pub enum InferError {
     TemplateError(#[from] minijinja::Error),
     #[error("Tool error: {0}")]
     ToolError(String),
+    #[error("Rate limit exceeded: {0}")]
+    RateLimited(#[from] RateLimitError),
+    #[error("Adapter `{0}` is not allowed for this caller")]
+    AdapterNotAllowed(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(#[from] QuotaError),
 }
 
 impl InferError {
@@ -528,6 +1046,297 @@ impl InferError {
             InferError::IncompleteGeneration => "incomplete_generation",
             InferError::TemplateError(_) => "template_error",
             InferError::ToolError(_) => "tool_error",
+            InferError::RateLimited(_) => "rate_limited",
+            InferError::AdapterNotAllowed(_) => "forbidden",
+            InferError::QuotaExceeded(_) => "quota_exceeded",
+        }
+    }
+
+    /// Stable error code, used to label error metrics
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            InferError::GenerationError(_) => "generation",
+            InferError::Overloaded(_) => "overloaded",
+            InferError::ValidationError(err) => err.code(),
+            InferError::IncompleteGeneration => "incomplete_generation",
+            InferError::TemplateError(_) => "template",
+            InferError::ToolError(_) => "tool",
+            InferError::RateLimited(_) => "rate_limited",
+            InferError::AdapterNotAllowed(_) => "adapter_not_allowed",
+            InferError::QuotaExceeded(_) => "quota_exceeded",
+        }
+    }
+
+    /// Whether the error was caused by the request (`user`) or by the server (`server`)
+    pub(crate) fn error_class(&self) -> &'static str {
+        match self {
+            InferError::ValidationError(ValidationError::Tokenizer(_)) => "server",
+            InferError::ValidationError(_)
+            | InferError::TemplateError(_)
+            | InferError::ToolError(_)
+            | InferError::RateLimited(_)
+            | InferError::AdapterNotAllowed(_)
+            | InferError::QuotaExceeded(_) => "user",
+            InferError::GenerationError(_)
+            | InferError::Overloaded(_)
+            | InferError::IncompleteGeneration => "server",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionDefinition;
+
+    #[test]
+    fn test_adapter_labels_cap() {
+        let loaded = LoadedAdapters::new(Some(vec!["a".into(), "b".into(), "c".into()]));
+        let labels = AdapterLabels::new(2);
+        // Unknown adapters do not take a label
+        assert_eq!(labels.label("junk", &loaded), OTHER_ADAPTERS_LABEL);
+        assert_eq!(labels.label("a", &loaded), "a");
+        assert_eq!(labels.label("b", &loaded), "b");
+        assert_eq!(labels.label("c", &loaded), OTHER_ADAPTERS_LABEL);
+        assert_eq!(labels.label("a", &loaded), "a");
+
+        // Shards not reporting their adapters
+        let labels = AdapterLabels::new(2);
+        assert_eq!(
+            labels.label("a", &LoadedAdapters::new(None)),
+            OTHER_ADAPTERS_LABEL
+        );
+    }
+
+    #[test]
+    fn test_error_class() {
+        let err = InferError::from(ValidationError::InvalidGrammar("grammar".to_string()));
+        assert_eq!(err.code(), "validation_invalid_grammar");
+        assert_eq!(err.error_class(), "user");
+
+        let err = InferError::from(ValidationError::Tokenizer("tokenizer".to_string()));
+        assert_eq!(err.error_class(), "server");
+
+        let err = InferError::GenerationError("shard down".to_string());
+        assert_eq!(err.code(), "generation");
+        assert_eq!(err.error_class(), "server");
+    }
+
+    #[test]
+    fn test_tool_grammar() {
+        let tool = |name: &str, parameters: Value| Tool {
+            r#type: "function".to_string(),
+            function: FunctionDefinition {
+                description: None,
+                name: name.to_string(),
+                arguments: parameters,
+            },
+        };
+        let tools = vec![
+            tool(
+                "get_weather",
+                json!({
+                    "type": "object",
+                    "properties": {"location": {"type": "string"}},
+                    "required": ["location"]
+                }),
+            ),
+            tool("get_time", json!({"properties": {}})),
+        ];
+        let grammar = |tools, tool_choice| {
+            let tools = ToolGrammar::apply(Some(tools), tool_choice)?.unwrap();
+            let schema = crate::grammar::resolve_refs(&json!(tools)).unwrap();
+            crate::grammar::json_schema_to_regex(&schema).unwrap();
+            Ok::<_, InferError>(jsonschema::JSONSchema::compile(&schema).unwrap())
+        };
+
+        let schema = grammar(tools.clone(), ToolChoice::default()).unwrap();
+        assert!(
+            schema.is_valid(&json!({"function": {"_name": "get_weather", "location": "Paris"}}))
+        );
+        assert!(schema.is_valid(&json!({"function": {"_name": "get_time"}})));
+        assert!(schema.is_valid(&json!({"function": {"_name": "notify_error", "error": "?"}})));
+        assert!(!schema.is_valid(&json!({"function": {"_name": "get_weather", "location": 1}})));
+        assert!(!schema.is_valid(&json!({"function": {"_name": "get_weather"}})));
+        assert!(!schema.is_valid(&json!({"function": {"_name": "get_stock"}})));
+        assert!(!schema.is_valid(&json!({})));
+
+        let schema = grammar(
+            tools.clone(),
+            ToolChoice(Some(ToolType::FunctionName("get_time".to_string()))),
+        )
+        .unwrap();
+        assert!(schema.is_valid(&json!({"function": {"_name": "get_time"}})));
+        assert!(
+            !schema.is_valid(&json!({"function": {"_name": "get_weather", "location": "Paris"}}))
+        );
+
+        assert!(ToolGrammar::apply(Some(tools.clone()), ToolChoice(None))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            ToolGrammar::apply(
+                Some(tools.clone()),
+                ToolChoice(Some(ToolType::FunctionName("get_stock".to_string())))
+            ),
+            Err(InferError::ToolError(_))
+        ));
+        for tools in [
+            vec![tools[0].clone(), tools[0].clone()],
+            vec![tool("notify_error", json!({}))],
+            vec![tool("get_time", json!("object"))],
+            vec![tool("get_time", json!({"required": "now"}))],
+        ] {
+            assert!(matches!(
+                ToolGrammar::apply(Some(tools), ToolChoice::default()),
+                Err(InferError::ToolError(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_grammar() {
+        // FSM of "ab" with the tokens 1: "a" and 2: "b"
+        let fsm = Arc::new(TokenFsm::new(
+            vec![
+                HashMap::from([(1, 1)]),
+                HashMap::from([(2, 2)]),
+                HashMap::new(),
+            ],
+            HashSet::from([2]),
+        ));
+        let token = |id: u32, text: &str| Token {
+            id,
+            text: text.to_string(),
+            logprob: 0.0,
+            special: id == 0,
+        };
+        let intermediate = |id, text| {
+            Ok(InferStreamResponse::Intermediate {
+                token: token(id, text),
+                top_tokens: Vec::new(),
+            })
+        };
+        let eos = |text: &str| {
+            Ok(InferStreamResponse::End {
+                token: token(0, "</s>"),
+                top_tokens: Vec::new(),
+                generated_text: GeneratedText {
+                    text: text.to_string(),
+                    generated_tokens: text.len() as u32 + 1,
+                    finish_reason: FinishReason::EndOfSequenceToken,
+                    seed: None,
+                },
+                start: Instant::now(),
+                queued: Instant::now(),
+            })
+        };
+        let check = |responses: Vec<Result<InferStreamResponse, InferError>>| {
+            let (response_tx, response_rx) = mpsc::unbounded_channel();
+            for response in responses {
+                response_tx.send(response).unwrap();
+            }
+            (
+                response_tx,
+                check_grammar(UnboundedReceiverStream::new(response_rx), fsm.clone()),
+            )
+        };
+
+        let (_response_tx, mut stream) =
+            check(vec![intermediate(1, "a"), intermediate(2, "b"), eos("ab")]);
+        for _ in 0..2 {
+            assert!(matches!(
+                stream.next().await,
+                Some(Ok(InferStreamResponse::Intermediate { .. }))
+            ));
+        }
+        match stream.next().await {
+            Some(Ok(InferStreamResponse::End { generated_text, .. })) => {
+                assert_eq!(generated_text.text, "ab");
+                assert!(matches!(
+                    generated_text.finish_reason,
+                    FinishReason::EndOfSequenceToken
+                ));
+            }
+            r => panic!("Unexpected response {r:?}"),
+        }
+
+        // A token out of the grammar, then an end of sequence before the grammar is complete
+        for (responses, generated_tokens) in [
+            (vec![intermediate(1, "a"), intermediate(1, "a")], 2),
+            (vec![intermediate(1, "a"), eos("a")], 2),
+        ] {
+            let (response_tx, mut stream) = check(responses);
+            assert!(matches!(
+                stream.next().await,
+                Some(Ok(InferStreamResponse::Intermediate { .. }))
+            ));
+            match stream.next().await {
+                Some(Ok(InferStreamResponse::End {
+                    token,
+                    generated_text,
+                    ..
+                })) => {
+                    assert_eq!(token.text, "");
+                    assert_eq!(generated_text.text, "a");
+                    assert_eq!(generated_text.generated_tokens, generated_tokens);
+                    assert!(matches!(
+                        generated_text.finish_reason,
+                        FinishReason::GrammarViolation
+                    ));
+                }
+                r => panic!("Unexpected response {r:?}"),
+            }
+            // The generation is dropped after the violation
+            assert!(stream.next().await.is_none());
+            assert!(response_tx.is_closed());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_on_regex() {
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        for (id, text) in ["Steps:", "\n1", ". Mix", "\n2."].into_iter().enumerate() {
+            let token = Token {
+                id: id as u32,
+                text: text.to_string(),
+                logprob: 0.0,
+                special: false,
+            };
+            response_tx
+                .send(Ok(InferStreamResponse::Intermediate {
+                    token,
+                    top_tokens: Vec::new(),
+                }))
+                .unwrap();
+        }
+        let stop_regex = Regex::new(r"\n\d+\.").unwrap();
+        let mut stream = stop_on_regex(UnboundedReceiverStream::new(response_rx), stop_regex);
+
+        for _ in 0..2 {
+            assert!(matches!(
+                stream.next().await,
+                Some(Ok(InferStreamResponse::Intermediate { .. }))
+            ));
+        }
+        match stream.next().await {
+            Some(Ok(InferStreamResponse::End {
+                token,
+                generated_text,
+                ..
+            })) => {
+                assert_eq!(token.id, 2);
+                assert_eq!(generated_text.text, "Steps:\n1.");
+                assert_eq!(generated_text.generated_tokens, 3);
+                assert!(matches!(
+                    generated_text.finish_reason,
+                    FinishReason::StopSequence
+                ));
+            }
+            r => panic!("Unexpected response {r:?}"),
         }
+        // The generation is dropped after the match
+        assert!(stream.next().await.is_none());
+        assert!(response_tx.is_closed());
     }
 }
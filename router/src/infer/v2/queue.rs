@@ -1,3 +1,4 @@
+use crate::grammar;
 use crate::infer::{InferError, InferStreamResponse};
 use crate::validation::{
     ValidGenerateRequest, ValidGrammar, ValidParameters, ValidStoppingParameters,
@@ -6,7 +7,8 @@ use nohash_hasher::{BuildNoHashHasher, IntMap};
 use std::cmp::min;
 use std::collections::VecDeque;
 use text_generation_client::v2::{
-    Batch, GrammarType, NextTokenChooserParameters, Request, StoppingCriteriaParameters,
+    Batch, GrammarType, NextTokenChooserParameters, Request, StoppingCriteriaParameters, TokenFsm,
+    TokenSequence,
 };
 use text_generation_client::ChunksToString;
 use tokio::sync::{mpsc, oneshot};
@@ -22,6 +24,8 @@ pub(crate) struct Entry {
     pub response_tx: mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
     /// Span that will live as long as entry
     pub span: Span,
+    /// Id of the HTTP request that created this entry
+    pub request_id: Option<String>,
     /// Temporary span used as a guard when logging inference, wait times...
     pub temp_span: Option<Span>,
     /// Instant when this entry was queued
@@ -111,7 +115,7 @@ async fn queue_task(
         match cmd {
             QueueCommand::Append(entry, span) => {
                 span.in_scope(|| state.append(*entry));
-                metrics::increment_gauge!("tgi_queue_size", 1.0);
+                metrics::gauge!("tgi_queue_size").increment(1.0);
             }
             QueueCommand::NextBatch {
                 min_size,
@@ -124,7 +128,7 @@ async fn queue_task(
                 let next_batch =
                     state.next_batch(min_size, max_size, prefill_token_budget, token_budget);
                 response_sender.send(next_batch).unwrap();
-                metrics::gauge!("tgi_queue_size", state.entries.len() as f64);
+                metrics::gauge!("tgi_queue_size").set(state.entries.len() as f64);
             }),
         }
     }
@@ -226,7 +230,7 @@ impl State {
             // Filter entries where the response receiver was dropped (== entries where the request
             // was dropped by the client)
             if entry.response_tx.is_closed() {
-                metrics::increment_counter!("tgi_request_failure", "err" => "dropped");
+                metrics::counter!("tgi_request_failure", "err" => "dropped").increment(1);
                 tracing::debug!("Dropping entry");
                 continue;
             }
@@ -245,6 +249,8 @@ impl State {
 
             if self.requires_padding {
                 decode_tokens += entry.request.stopping_parameters.max_new_tokens;
+                // Only the padded models apply the classifier-free guidance
+                decode_tokens += entry.request.guidance_tokens();
             } else {
                 let max_new_tokens = match self.window_size {
                     None => entry.request.stopping_parameters.max_new_tokens,
@@ -283,9 +289,16 @@ impl State {
                 prefill_logprobs: entry.request.decoder_input_details,
                 inputs: entry.request.inputs.chunks_to_string(),
                 truncate: entry.request.truncate,
-                parameters: Some(NextTokenChooserParameters::from(
-                    entry.request.parameters.clone(),
-                )),
+                parameters: Some(NextTokenChooserParameters {
+                    // The shards follow the FSM of the router instead of compiling the grammar
+                    grammar_fsm: entry
+                        .request
+                        .stopping_parameters
+                        .grammar_fsm
+                        .as_deref()
+                        .map(TokenFsm::from),
+                    ..NextTokenChooserParameters::from(entry.request.parameters.clone())
+                }),
                 stopping_parameters: Some(StoppingCriteriaParameters::from(
                     entry.request.stopping_parameters.clone(),
                 )),
@@ -336,7 +349,7 @@ impl State {
         // Increment batch id
         self.next_batch_id += 1;
 
-        metrics::histogram!("tgi_batch_next_size", batch.size as f64);
+        metrics::histogram!("tgi_batch_next_size").record(batch.size as f64);
 
         Some((batch_entries, batch, next_batch_span))
     }
@@ -357,6 +370,17 @@ enum QueueCommand {
     },
 }
 
+impl From<&grammar::TokenFsm> for TokenFsm {
+    fn from(value: &grammar::TokenFsm) -> Self {
+        Self {
+            offsets: value.offsets.clone(),
+            tokens: value.tokens.clone(),
+            next_states: value.next_states.clone(),
+            final_states: value.final_states.clone(),
+        }
+    }
+}
+
 impl From<ValidParameters> for NextTokenChooserParameters {
     fn from(value: ValidParameters) -> Self {
         let (grammar, grammar_type) = match value.grammar {
@@ -365,6 +389,7 @@ impl From<ValidParameters> for NextTokenChooserParameters {
             Some(grammar) => match grammar {
                 ValidGrammar::Json(grammar_string) => (grammar_string, GrammarType::Json),
                 ValidGrammar::Regex(grammar_string) => (grammar_string, GrammarType::Regex),
+                ValidGrammar::Cfg(grammar_string) => (grammar_string, GrammarType::Cfg),
             },
         };
 
@@ -373,10 +398,32 @@ impl From<ValidParameters> for NextTokenChooserParameters {
             top_k: value.top_k,
             top_p: value.top_p,
             typical_p: value.typical_p,
+            min_p: value.min_p,
+            epsilon_cutoff: value.epsilon_cutoff,
+            eta_cutoff: value.eta_cutoff,
+            top_a: value.top_a,
+            mirostat_mode: value.mirostat_mode,
+            mirostat_tau: value.mirostat_tau,
+            mirostat_eta: value.mirostat_eta,
+            grammar_fsm: None,
+            logit_bias: value.logit_bias,
+            bad_words: value
+                .bad_words
+                .into_iter()
+                .map(|ids| TokenSequence { ids })
+                .collect(),
+            guidance_scale: value.guidance_scale,
+            negative_input_ids: value.negative_input_ids,
+            min_new_tokens: value.min_new_tokens,
             do_sample: value.do_sample,
             seed: value.seed,
             repetition_penalty: value.repetition_penalty,
             frequency_penalty: value.frequency_penalty,
+            presence_penalty: value.presence_penalty,
+            no_repeat_ngram_size: value.no_repeat_ngram_size,
+            dry_multiplier: value.dry_multiplier,
+            dry_base: value.dry_base,
+            dry_allowed_length: value.dry_allowed_length,
             watermark: value.watermark,
             grammar,
             grammar_type: grammar_type.into(),
@@ -389,7 +436,9 @@ impl From<ValidStoppingParameters> for StoppingCriteriaParameters {
         Self {
             max_new_tokens: value.max_new_tokens,
             stop_sequences: value.stop_sequences,
+            stop_token_ids: value.stop_token_ids,
             ignore_eos_token: value.ignore_eos_token,
+            max_time: value.max_time.unwrap_or(0.0),
         }
     }
 }
@@ -397,6 +446,7 @@ impl From<ValidStoppingParameters> for StoppingCriteriaParameters {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
     use tracing::info_span;
 
     fn default_entry() -> (
@@ -408,6 +458,7 @@ mod tests {
         let entry = Entry {
             request: ValidGenerateRequest {
                 inputs: vec![],
+                input_ids: vec![],
                 input_length: 0,
                 truncate: 0,
                 decoder_input_details: false,
@@ -416,10 +467,27 @@ mod tests {
                     top_k: 0,
                     top_p: 0.0,
                     typical_p: 0.0,
+                    min_p: 0.0,
+                    epsilon_cutoff: 0.0,
+                    eta_cutoff: 0.0,
+                    top_a: 0.0,
+                    mirostat_mode: 0,
+                    mirostat_tau: 0.0,
+                    mirostat_eta: 0.0,
+                    logit_bias: HashMap::new(),
+                    bad_words: Vec::new(),
+                    guidance_scale: 1.0,
+                    negative_input_ids: Vec::new(),
+                    min_new_tokens: 0,
                     do_sample: false,
                     seed: 0,
                     repetition_penalty: 0.0,
                     frequency_penalty: 0.0,
+                    presence_penalty: 0.0,
+                    no_repeat_ngram_size: 0,
+                    dry_multiplier: 0.0,
+                    dry_base: 0.0,
+                    dry_allowed_length: 0,
                     watermark: false,
                     grammar: None,
                 },
@@ -427,12 +495,17 @@ mod tests {
                     ignore_eos_token: false,
                     max_new_tokens: 1,
                     stop_sequences: vec![],
+                    stop_token_ids: vec![],
+                    stop_regex: None,
+                    grammar_fsm: None,
+                    max_time: None,
                 },
                 top_n_tokens: 0,
                 adapter_id: None,
             },
             response_tx,
             span: info_span!("entry"),
+            request_id: None,
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
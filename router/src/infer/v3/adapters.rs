@@ -0,0 +1,47 @@
+/// Adapters loaded on the running shards
+use crate::events::{Events, LifecycleEvent};
+use crate::validation::LoadedAdapters;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use text_generation_client::v3::ShardedClient;
+
+/// Interval between two reads of the adapters of the shards
+const SHARD_ADAPTERS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Periodically read the adapters of the shards, loaded or unloaded by the launcher while they
+/// run, update the adapters accepted by the validation and send their changes as lifecycle events
+pub(crate) async fn shard_adapters_task(
+    mut client: ShardedClient,
+    adapter_ids: Vec<String>,
+    adapters: Arc<LoadedAdapters>,
+    events: Events,
+) {
+    let mut loaded: BTreeSet<String> = adapter_ids.into_iter().collect();
+    let mut interval = tokio::time::interval(SHARD_ADAPTERS_INTERVAL);
+    loop {
+        interval.tick().await;
+        let adapter_ids = match client.info().await {
+            Ok(info) => info.adapter_ids,
+            Err(err) => {
+                tracing::debug!("Could not get the adapters of the shards: {err}");
+                continue;
+            }
+        };
+        adapters.set(adapter_ids.clone());
+        let adapter_ids: BTreeSet<String> = adapter_ids.unwrap_or_default().into_iter().collect();
+        for adapter_id in adapter_ids.difference(&loaded) {
+            tracing::info!("Adapter {adapter_id} was loaded");
+            events.send(LifecycleEvent::AdapterLoaded {
+                adapter_id: adapter_id.clone(),
+            });
+        }
+        for adapter_id in loaded.difference(&adapter_ids) {
+            tracing::info!("Adapter {adapter_id} was unloaded");
+            events.send(LifecycleEvent::AdapterUnloaded {
+                adapter_id: adapter_id.clone(),
+            });
+        }
+        loaded = adapter_ids;
+    }
+}
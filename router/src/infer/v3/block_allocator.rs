@@ -76,6 +76,8 @@ async fn block_allocator_task(
 ) {
     // Block 0 is reserved for health checks
     let mut free_blocks: Vec<u32> = (1..blocks).collect();
+    metrics::gauge!("tgi_kv_blocks_total").set(free_blocks.len() as f64);
+    metrics::gauge!("tgi_kv_blocks_free").set(free_blocks.len() as f64);
     while let Some(cmd) = receiver.recv().await {
         match cmd {
             BlockAllocatorCommand::Free { blocks } => free_blocks.extend(blocks),
@@ -121,6 +123,7 @@ async fn block_allocator_task(
                 response_sender.send(allocation).unwrap();
             }
         }
+        metrics::gauge!("tgi_kv_blocks_free").set(free_blocks.len() as f64);
     }
 }
 
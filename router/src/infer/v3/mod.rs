@@ -1,5 +1,9 @@
+mod adapters;
 mod block_allocator;
 mod queue;
 mod scheduler;
+mod stats;
 
+pub(crate) use adapters::shard_adapters_task;
 pub(crate) use scheduler::SchedulerV3;
+pub(crate) use stats::shard_stats_task;
@@ -1,3 +1,4 @@
+use crate::grammar;
 use crate::infer::v3::block_allocator::{BlockAllocation, BlockAllocator};
 use crate::infer::InferError;
 use crate::infer::InferStreamResponse;
@@ -8,7 +9,8 @@ use nohash_hasher::{BuildNoHashHasher, IntMap};
 use std::cmp::{max, min};
 use std::collections::VecDeque;
 use text_generation_client::v3::{
-    Batch, GrammarType, NextTokenChooserParameters, Request, StoppingCriteriaParameters,
+    Batch, GrammarType, NextTokenChooserParameters, Request, StoppingCriteriaParameters, TokenFsm,
+    TokenSequence,
 };
 use text_generation_client::ChunksToString;
 use text_generation_client::Input;
@@ -25,6 +27,8 @@ pub(crate) struct Entry {
     pub response_tx: mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>,
     /// Span that will live as long as entry
     pub span: Span,
+    /// Id of the HTTP request that created this entry
+    pub request_id: Option<String>,
     /// Temporary span used as a guard when logging inference, wait times...
     pub temp_span: Option<Span>,
     /// Instant when this entry was queued
@@ -126,7 +130,7 @@ async fn queue_task(
         match cmd {
             QueueCommand::Append(entry, span) => {
                 span.in_scope(|| state.append(*entry));
-                metrics::increment_gauge!("tgi_queue_size", 1.0);
+                metrics::gauge!("tgi_queue_size").increment(1.0);
             }
             QueueCommand::NextBatch {
                 min_size,
@@ -141,7 +145,7 @@ async fn queue_task(
                     .instrument(span)
                     .await;
                 response_sender.send(next_batch).unwrap();
-                metrics::gauge!("tgi_queue_size", state.entries.len() as f64);
+                metrics::gauge!("tgi_queue_size").set(state.entries.len() as f64);
             }
         }
     }
@@ -248,7 +252,7 @@ impl State {
             // Filter entries where the response receiver was dropped (== entries where the request
             // was dropped by the client)
             if entry.response_tx.is_closed() {
-                metrics::increment_counter!("tgi_request_failure", "err" => "dropped");
+                metrics::counter!("tgi_request_failure", "err" => "dropped").increment(1);
                 tracing::debug!("Dropping entry");
                 continue;
             }
@@ -261,6 +265,8 @@ impl State {
                     prefill_tokens = (batch_requests.len() + 1) as u32 * max_input_length;
 
                     decode_tokens += entry.request.stopping_parameters.max_new_tokens;
+                    // Only the padded models apply the classifier-free guidance
+                    decode_tokens += entry.request.guidance_tokens();
                     let total_tokens = prefill_tokens + decode_tokens + self.speculate;
 
                     if prefill_tokens > prefill_token_budget || total_tokens > token_budget {
@@ -342,9 +348,16 @@ impl State {
                 }),
                 inputs: entry.request.inputs.chunks_to_string(),
                 truncate: entry.request.truncate,
-                parameters: Some(NextTokenChooserParameters::from(
-                    entry.request.parameters.clone(),
-                )),
+                parameters: Some(NextTokenChooserParameters {
+                    // The shards follow the FSM of the router instead of compiling the grammar
+                    grammar_fsm: entry
+                        .request
+                        .stopping_parameters
+                        .grammar_fsm
+                        .as_deref()
+                        .map(TokenFsm::from),
+                    ..NextTokenChooserParameters::from(entry.request.parameters.clone())
+                }),
                 stopping_parameters: Some(StoppingCriteriaParameters::from(
                     entry.request.stopping_parameters.clone(),
                 )),
@@ -352,6 +365,7 @@ impl State {
                 blocks,
                 slots,
                 adapter_id: entry.request.adapter_id.clone(),
+                input_ids: entry.request.input_ids.clone(),
             });
             // Set batch_time
             entry.batch_time = Some(Instant::now());
@@ -399,7 +413,7 @@ impl State {
         // Increment batch id
         self.next_batch_id += 1;
 
-        metrics::histogram!("tgi_batch_next_size", batch.size as f64);
+        metrics::histogram!("tgi_batch_next_size").record(batch.size as f64);
 
         Some((batch_entries, batch, next_batch_span))
     }
@@ -420,6 +434,17 @@ enum QueueCommand {
     },
 }
 
+impl From<&grammar::TokenFsm> for TokenFsm {
+    fn from(value: &grammar::TokenFsm) -> Self {
+        Self {
+            offsets: value.offsets.clone(),
+            tokens: value.tokens.clone(),
+            next_states: value.next_states.clone(),
+            final_states: value.final_states.clone(),
+        }
+    }
+}
+
 impl From<ValidParameters> for NextTokenChooserParameters {
     fn from(value: ValidParameters) -> Self {
         let (grammar, grammar_type) = match value.grammar {
@@ -428,6 +453,7 @@ impl From<ValidParameters> for NextTokenChooserParameters {
             Some(grammar) => match grammar {
                 ValidGrammar::Json(grammar_string) => (grammar_string, GrammarType::Json),
                 ValidGrammar::Regex(grammar_string) => (grammar_string, GrammarType::Regex),
+                ValidGrammar::Cfg(grammar_string) => (grammar_string, GrammarType::Cfg),
             },
         };
 
@@ -436,10 +462,32 @@ impl From<ValidParameters> for NextTokenChooserParameters {
             top_k: value.top_k,
             top_p: value.top_p,
             typical_p: value.typical_p,
+            min_p: value.min_p,
+            epsilon_cutoff: value.epsilon_cutoff,
+            eta_cutoff: value.eta_cutoff,
+            top_a: value.top_a,
+            mirostat_mode: value.mirostat_mode,
+            mirostat_tau: value.mirostat_tau,
+            mirostat_eta: value.mirostat_eta,
+            grammar_fsm: None,
+            logit_bias: value.logit_bias,
+            bad_words: value
+                .bad_words
+                .into_iter()
+                .map(|ids| TokenSequence { ids })
+                .collect(),
+            guidance_scale: value.guidance_scale,
+            negative_input_ids: value.negative_input_ids,
+            min_new_tokens: value.min_new_tokens,
             do_sample: value.do_sample,
             seed: value.seed,
             repetition_penalty: value.repetition_penalty,
             frequency_penalty: value.frequency_penalty,
+            presence_penalty: value.presence_penalty,
+            no_repeat_ngram_size: value.no_repeat_ngram_size,
+            dry_multiplier: value.dry_multiplier,
+            dry_base: value.dry_base,
+            dry_allowed_length: value.dry_allowed_length,
             watermark: value.watermark,
             grammar,
             grammar_type: grammar_type.into(),
@@ -452,7 +500,9 @@ impl From<ValidStoppingParameters> for StoppingCriteriaParameters {
         Self {
             max_new_tokens: value.max_new_tokens,
             stop_sequences: value.stop_sequences,
+            stop_token_ids: value.stop_token_ids,
             ignore_eos_token: value.ignore_eos_token,
+            max_time: value.max_time.unwrap_or(0.0),
         }
     }
 }
@@ -460,6 +510,7 @@ impl From<ValidStoppingParameters> for StoppingCriteriaParameters {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
     use tracing::info_span;
 
     fn default_entry() -> (
@@ -471,6 +522,7 @@ mod tests {
         let entry = Entry {
             request: ValidGenerateRequest {
                 inputs: vec![],
+                input_ids: vec![],
                 input_length: 0,
                 truncate: 0,
                 decoder_input_details: false,
@@ -479,10 +531,27 @@ mod tests {
                     top_k: 0,
                     top_p: 0.0,
                     typical_p: 0.0,
+                    min_p: 0.0,
+                    epsilon_cutoff: 0.0,
+                    eta_cutoff: 0.0,
+                    top_a: 0.0,
+                    mirostat_mode: 0,
+                    mirostat_tau: 0.0,
+                    mirostat_eta: 0.0,
+                    logit_bias: HashMap::new(),
+                    bad_words: Vec::new(),
+                    guidance_scale: 1.0,
+                    negative_input_ids: Vec::new(),
+                    min_new_tokens: 0,
                     do_sample: false,
                     seed: 0,
                     repetition_penalty: 0.0,
                     frequency_penalty: 0.0,
+                    presence_penalty: 0.0,
+                    no_repeat_ngram_size: 0,
+                    dry_multiplier: 0.0,
+                    dry_base: 0.0,
+                    dry_allowed_length: 0,
                     watermark: false,
                     grammar: None,
                 },
@@ -490,12 +559,17 @@ mod tests {
                     ignore_eos_token: false,
                     max_new_tokens: 1,
                     stop_sequences: vec![],
+                    stop_token_ids: vec![],
+                    stop_regex: None,
+                    grammar_fsm: None,
+                    max_time: None,
                 },
                 top_n_tokens: 0,
                 adapter_id: None,
             },
             response_tx,
             span: info_span!("entry"),
+            request_id: None,
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
@@ -613,6 +687,21 @@ mod tests {
         assert_eq!(state.next_batch_id, 2);
     }
 
+    #[tokio::test]
+    async fn test_next_batch_guidance_budget() {
+        let mut state = State::new(true, 1, None, 0, 16);
+        let (mut entry, _guard) = default_entry();
+        entry.request.parameters.guidance_scale = 2.0;
+        entry.request.parameters.negative_input_ids = vec![1, 2, 3];
+        state.append(entry);
+
+        // 1 new token, and the negative prompt with its new token
+        assert!(state.next_batch(None, None, 1, 4).await.is_none());
+        let (entries, batch, _) = state.next_batch(None, None, 1, 5).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(batch.size, 1);
+    }
+
     #[tokio::test]
     async fn test_queue_append() {
         let queue = Queue::new(false, 1, None, 0, 16);
@@ -0,0 +1,28 @@
+/// Shard statistics scraping
+use std::time::Duration;
+use text_generation_client::v3::ShardedClient;
+
+/// Interval between two scrapes of the shard statistics
+const SHARD_STATS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Periodically scrape the statistics of every shard and export them as metrics
+pub(crate) async fn shard_stats_task(mut client: ShardedClient) {
+    let mut interval = tokio::time::interval(SHARD_STATS_INTERVAL);
+    loop {
+        interval.tick().await;
+        match client.stats().await {
+            Ok(stats) => {
+                for (shard, stats) in stats.into_iter().enumerate() {
+                    let labels = [("shard", shard.to_string())];
+                    metrics::gauge!("tgi_shard_memory_used_bytes", &labels)
+                        .set(stats.memory_used as f64);
+                    metrics::gauge!("tgi_shard_memory_total_bytes", &labels)
+                        .set(stats.memory_total as f64);
+                    metrics::gauge!("tgi_shard_uptime_seconds", &labels).set(stats.uptime as f64);
+                }
+            }
+            // Shards may not support statistics
+            Err(err) => tracing::debug!("Could not get shard statistics: {err}"),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+/// Per-key overrides of the input validation limits
+use serde::Deserialize;
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum InputPolicyError {
+    #[error("could not read the input policies: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid input policies: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// Limits of a key, `None` keeps the global limit.
+/// Limits can only be stricter than the global ones.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct InputPolicy {
+    pub max_input_tokens: Option<usize>,
+    pub max_new_tokens: Option<u32>,
+    pub max_images: Option<usize>,
+    pub allow_grammar: Option<bool>,
+    pub allow_ignore_eos_token: Option<bool>,
+    /// Priority class of the key, labelling its streaming latency metrics
+    pub priority: Option<String>,
+}
+
+/// Input policies of the keys (caller tenant or subject)
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct InputPolicies {
+    /// Policy of the keys missing from `keys`
+    #[serde(default)]
+    pub default: InputPolicy,
+    #[serde(default)]
+    pub keys: HashMap<String, InputPolicy>,
+}
+
+impl InputPolicies {
+    pub(crate) fn from_file(path: &str) -> Result<Self, InputPolicyError> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub(crate) fn policy(&self, key: &str) -> &InputPolicy {
+        self.keys.get(key).unwrap_or(&self.default)
+    }
+
+    /// Priority class of `key`, `default` if its policy does not set one
+    pub(crate) fn priority(&self, key: &str) -> String {
+        self.policy(key)
+            .priority
+            .clone()
+            .unwrap_or_else(|| "default".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_policies() {
+        let policies: InputPolicies = serde_json::from_str(
+            r#"{"default": {"max_images": 1}, "keys": {"acme": {"max_new_tokens": 64, "allow_grammar": false, "priority": "high"}}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            policies.policy("acme"),
+            &InputPolicy {
+                max_input_tokens: None,
+                max_new_tokens: Some(64),
+                max_images: None,
+                allow_grammar: Some(false),
+                allow_ignore_eos_token: None,
+                priority: Some("high".to_string()),
+            }
+        );
+        assert_eq!(policies.policy("other").max_images, Some(1));
+        assert_eq!(policies.priority("acme"), "high");
+        assert_eq!(policies.priority("other"), "default");
+    }
+}
@@ -0,0 +1,151 @@
+/// Client IP allow and deny lists, evaluated before the authentication
+use crate::fetch::IpNetwork;
+use crate::ErrorResponse;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// Networks allowed to reach the API
+#[derive(Clone, Debug, Default)]
+pub struct IpFilterConfig {
+    /// Only these networks are allowed, if not empty
+    pub allow: Vec<IpNetwork>,
+    /// Networks always rejected, even if allowed
+    pub deny: Vec<IpNetwork>,
+    /// Proxies whose `X-Forwarded-For` header is trusted
+    pub trusted_proxies: Vec<IpNetwork>,
+}
+
+impl IpFilterConfig {
+    pub(crate) fn is_enabled(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct IpFilter {
+    config: Arc<IpFilterConfig>,
+}
+
+impl IpFilter {
+    pub(crate) fn new(config: IpFilterConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
+    fn trusted(&self, address: IpAddr) -> bool {
+        self.config
+            .trusted_proxies
+            .iter()
+            .any(|network| network.contains(address))
+    }
+
+    /// Address of the client. `X-Forwarded-For` is only read when the peer is a trusted proxy,
+    /// from the right, up to the first address that is not a trusted proxy.
+    /// `None` if a trusted proxy forwarded an invalid address.
+    fn client_address(&self, peer: IpAddr, headers: &HeaderMap) -> Option<IpAddr> {
+        if !self.trusted(peer) {
+            return Some(peer);
+        }
+        let forwarded: Vec<&str> = headers
+            .get_all(FORWARDED_FOR_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+        let mut client = peer;
+        for address in forwarded.into_iter().rev() {
+            client = address.parse().ok()?;
+            if !self.trusted(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+
+    pub(crate) fn allows(&self, address: IpAddr) -> bool {
+        let matches = |networks: &[IpNetwork]| networks.iter().any(|n| n.contains(address));
+        !matches(&self.config.deny) && (self.config.allow.is_empty() || matches(&self.config.allow))
+    }
+}
+
+/// Reject the clients outside of the allowed networks, or in the denied ones
+pub(crate) async fn ip_filter_middleware(
+    State(filter): State<IpFilter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let address = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .and_then(|ConnectInfo(peer)| filter.client_address(peer.ip(), request.headers()));
+    match address {
+        Some(address) if filter.allows(address) => next.run(request).await,
+        _ => {
+            metrics::counter!("tgi_ip_filter_rejected").increment(1);
+            tracing::warn!("Rejected client address {address:?}");
+            (
+                StatusCode::FORBIDDEN,
+                Json(ErrorResponse {
+                    error: "client address is not allowed".to_string(),
+                    error_type: "forbidden".to_string(),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(allow: &[&str], deny: &[&str], trusted_proxies: &[&str]) -> IpFilter {
+        let networks = |values: &[&str]| values.iter().map(|v| v.parse().unwrap()).collect();
+        IpFilter::new(IpFilterConfig {
+            allow: networks(allow),
+            deny: networks(deny),
+            trusted_proxies: networks(trusted_proxies),
+        })
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let filter = filter(&["10.0.0.0/8", "fd00::/8"], &["10.0.66.0/24"], &[]);
+        assert!(filter.allows("10.1.2.3".parse().unwrap()));
+        assert!(filter.allows("fd00::1".parse().unwrap()));
+        assert!(!filter.allows("10.0.66.1".parse().unwrap()));
+        assert!(!filter.allows("192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_client_address() {
+        let filter = filter(&[], &[], &["10.0.0.0/8"]);
+        let mut headers = HeaderMap::new();
+        headers.append(FORWARDED_FOR_HEADER, "1.1.1.1, 2.2.2.2".parse().unwrap());
+        headers.append(FORWARDED_FOR_HEADER, "10.0.0.2".parse().unwrap());
+        let address =
+            |peer: &str, headers: &HeaderMap| filter.client_address(peer.parse().unwrap(), headers);
+
+        // The header of untrusted peers is ignored
+        assert_eq!(address("3.3.3.3", &headers), "3.3.3.3".parse().ok());
+        // Spoofed entries left of the first untrusted hop are ignored
+        assert_eq!(address("10.0.0.1", &headers), "2.2.2.2".parse().ok());
+        assert_eq!(
+            address("10.0.0.1", &HeaderMap::new()),
+            "10.0.0.1".parse().ok()
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED_FOR_HEADER, "unknown".parse().unwrap());
+        assert_eq!(address("10.0.0.1", &headers), None);
+    }
+}
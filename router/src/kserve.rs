@@ -204,6 +204,8 @@ pub async fn kserve_model_infer(
         .map(|(str_input, output)| {
             let generate_request = GenerateRequest {
                 inputs: str_input.to_string(),
+                input_ids: None,
+                messages: None,
                 parameters: payload.parameters.clone(),
             };
             let infer = infer.clone();
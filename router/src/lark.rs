@@ -0,0 +1,1024 @@
+/// Parsing of the Lark grammars, bounded by the router before the model servers build their parser
+use crate::gbnf::{push_class_char, MAX_REGEX_LENGTH};
+use crate::grammar::{escape, GrammarError};
+use once_cell::sync::Lazy;
+use regex::RegexBuilder;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Maximum length of the `lark` grammars
+pub(crate) const MAX_LARK_GRAMMAR_LENGTH: usize = 16 * 1024;
+
+/// Maximum number of rules and terminals of the `lark` grammars, including the imported
+/// terminals: the model servers build the parser of the grammar for each request
+pub(crate) const MAX_LARK_DEFINITIONS: usize = 128;
+
+/// Maximum number of items of the `lark` grammars once the `~` repetitions are expanded into
+/// alternatives, as Lark does when building the parser
+pub(crate) const MAX_LARK_ITEMS: usize = 4096;
+
+/// Maximum nesting of the groups, which bounds the recursion of the parser
+const MAX_LARK_NESTING: usize = 32;
+
+/// Size limit of the compiled regex terminals
+const REGEX_TERMINAL_SIZE_LIMIT: usize = 1 << 20;
+
+/// Terminals of the `common` module of Lark that can be imported. `ESCAPED_STRING` is written
+/// without the lookbehind of Lark, which the regexes of the router do not support.
+const COMMON_GRAMMAR: &str = r#"
+DIGIT: "0".."9"
+HEXDIGIT: "a".."f" | "A".."F" | DIGIT
+INT: DIGIT+
+SIGNED_INT: ["+" | "-"] INT
+DECIMAL: INT "." INT? | "." INT
+_EXP: ("e" | "E") SIGNED_INT
+FLOAT: INT _EXP | DECIMAL _EXP?
+SIGNED_FLOAT: ["+" | "-"] FLOAT
+NUMBER: FLOAT | INT
+SIGNED_NUMBER: ["+" | "-"] NUMBER
+ESCAPED_STRING: "\"" /([^"\\]|\\.)*/ "\""
+LCASE_LETTER: "a".."z"
+UCASE_LETTER: "A".."Z"
+LETTER: UCASE_LETTER | LCASE_LETTER
+WORD: LETTER+
+CNAME: ("_" | LETTER) ("_" | LETTER | DIGIT)*
+WS_INLINE: (" " | /\t/)+
+WS: /[ \t\f\r\n]/+
+CR: /\r/
+LF: /\n/
+NEWLINE: (CR? LF)+
+"#;
+
+/// Importable terminals, with their dependencies inlined so that they do not clash with the
+/// definitions of the grammars
+static COMMON_TERMINALS: Lazy<HashMap<String, Alternates>> = Lazy::new(|| {
+    let grammar = Parser::new(COMMON_GRAMMAR).parse_grammar().unwrap();
+    let mut terminals: HashMap<String, Alternates> = HashMap::new();
+    for definition in grammar.definitions {
+        let alternates = inline(definition.alternates, &terminals);
+        terminals.insert(definition.name, alternates);
+    }
+    terminals
+});
+
+/// Lark grammar in the form sent to the model servers
+#[derive(Debug, PartialEq)]
+pub(crate) enum CompiledLark {
+    /// Regex of the regular grammars, whose token FSM is built by the router
+    Regex(String),
+    /// Normalized context-free grammar, without comments or imports
+    Cfg(String),
+}
+
+/// Parse a Lark `grammar` and bound its complexity. The grammars without recursive rules nor
+/// `%ignore` are compiled to a regex, the others are normalized for the model servers.
+pub(crate) fn compile_lark(grammar: &str) -> Result<CompiledLark, GrammarError> {
+    if grammar.len() > MAX_LARK_GRAMMAR_LENGTH {
+        return Err(error(format!(
+            "the grammar must be shorter than {MAX_LARK_GRAMMAR_LENGTH} bytes"
+        )));
+    }
+    let grammar = Parser::new(grammar).parse_grammar()?.resolve_imports()?;
+    let definitions = grammar.check()?;
+    if grammar.ignored.is_empty() {
+        let mut expander = Expander {
+            definitions: &definitions,
+            expanded: HashMap::new(),
+            stack: Vec::new(),
+        };
+        if let Some(regex) = expander.expand("start") {
+            return Ok(CompiledLark::Regex(regex));
+        }
+    }
+    Ok(CompiledLark::Cfg(grammar.to_string()))
+}
+
+fn error(message: String) -> GrammarError {
+    GrammarError::Lark(message)
+}
+
+/// Alternatives of sequences of items
+type Alternates = Vec<Vec<Item>>;
+
+#[derive(Clone, Debug)]
+enum Item {
+    /// String, case-insensitive with the `i` flag
+    Literal(String, bool),
+    /// Pattern and flags of a `/regex/`
+    Regex(String, String),
+    /// Range of chars `"a".."z"`
+    Range(char, char),
+    Name(String),
+    Group(Alternates),
+    /// Optional group `[...]`
+    Maybe(Alternates),
+    Repeat(Box<Item>, u32, Option<u32>),
+}
+
+#[derive(Debug)]
+struct Definition {
+    name: String,
+    /// `?` inlines the rule when it has a single child, `!` keeps all its tokens
+    modifier: Option<char>,
+    priority: Option<i32>,
+    alternates: Alternates,
+    /// Alias `-> name` of each alternative
+    aliases: Vec<Option<String>>,
+}
+
+#[derive(Debug)]
+struct Grammar {
+    definitions: Vec<Definition>,
+    /// Terminals of the `%import common.NAME` statements
+    imports: Vec<String>,
+    /// Items of the `%ignore` statements
+    ignored: Vec<Alternates>,
+}
+
+/// Terminals are uppercase, rules are lowercase, both can start with `_`
+fn is_terminal(name: &str) -> bool {
+    name.trim_start_matches('_')
+        .starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Nesting of the groups being parsed
+    depth: usize,
+}
+
+impl Parser {
+    fn new(grammar: &str) -> Self {
+        Self {
+            chars: grammar.chars().collect(),
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn starts_with(&self, prefix: &str) -> bool {
+        prefix
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn next(&mut self) -> Result<char, GrammarError> {
+        let c = self
+            .peek()
+            .ok_or_else(|| error("unexpected end of the grammar".to_string()))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), GrammarError> {
+        for c in expected.chars() {
+            if self.next()? != c {
+                return Err(error(format!(
+                    "expected `{expected}` at char {}",
+                    self.pos - 1
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Skip the spaces and comments. The newlines end the definitions outside of the groups.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\r' | '\n' if newlines => self.pos += 1,
+                '/' if self.starts_with("//") => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Check that a definition or a statement ends its line
+    fn end_line(&mut self) -> Result<(), GrammarError> {
+        self.skip_space(false);
+        match self.peek() {
+            None | Some('\r' | '\n') => Ok(()),
+            Some(c) => Err(error(format!("unexpected `{c}` at char {}", self.pos))),
+        }
+    }
+
+    fn parse_grammar(&mut self) -> Result<Grammar, GrammarError> {
+        let mut grammar = Grammar {
+            definitions: Vec::new(),
+            imports: Vec::new(),
+            ignored: Vec::new(),
+        };
+        loop {
+            self.skip_space(true);
+            match self.peek() {
+                None => return Ok(grammar),
+                Some('%') => {
+                    self.pos += 1;
+                    match self.parse_name()?.as_str() {
+                        "import" => grammar.imports.extend(self.parse_import()?),
+                        "ignore" => {
+                            self.skip_space(false);
+                            let (alternates, aliases) = self.parse_alternates(false)?;
+                            if aliases.iter().any(Option::is_some) {
+                                return Err(error("`%ignore` cannot have aliases".to_string()));
+                            }
+                            grammar.ignored.push(alternates);
+                        }
+                        statement => {
+                            return Err(error(format!("unsupported statement `%{statement}`")))
+                        }
+                    }
+                }
+                Some(_) => grammar.definitions.push(self.parse_definition()?),
+            }
+            self.end_line()?;
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String, GrammarError> {
+        let start = self.pos;
+        if self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        {
+            while self
+                .peek()
+                .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                self.pos += 1;
+            }
+        }
+        if start == self.pos {
+            return Err(error(format!("expected a name at char {start}")));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn parse_number(&mut self) -> Result<u32, GrammarError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .map_err(|_| error(format!("expected a number at char {start}")))
+    }
+
+    /// `common.NAME` or `common (NAME, ...)`, after the `%import`
+    fn parse_import(&mut self) -> Result<Vec<String>, GrammarError> {
+        self.skip_space(false);
+        let module = self.parse_name()?;
+        if module != "common" {
+            return Err(error(format!(
+                "cannot import `{module}`, only the terminals of `common` can be imported"
+            )));
+        }
+        if self.peek() == Some('.') {
+            self.pos += 1;
+            return Ok(vec![self.parse_name()?]);
+        }
+        self.skip_space(false);
+        self.expect("(")?;
+        let mut names = Vec::new();
+        loop {
+            self.skip_space(false);
+            names.push(self.parse_name()?);
+            self.skip_space(false);
+            match self.next()? {
+                ',' => (),
+                ')' => return Ok(names),
+                c => return Err(error(format!("unexpected `{c}` at char {}", self.pos - 1))),
+            }
+        }
+    }
+
+    /// `name: ...`, `?name: ...`, `!name: ...` or `NAME.priority: ...`
+    fn parse_definition(&mut self) -> Result<Definition, GrammarError> {
+        let modifier = match self.peek() {
+            Some(c @ ('?' | '!')) => {
+                self.pos += 1;
+                Some(c)
+            }
+            _ => None,
+        };
+        let name = self.parse_name()?;
+        let priority = match self.peek() {
+            Some('.') => {
+                self.pos += 1;
+                let negative = self.peek() == Some('-');
+                if negative {
+                    self.pos += 1;
+                }
+                let priority = self.parse_number()? as i32;
+                Some(if negative { -priority } else { priority })
+            }
+            _ => None,
+        };
+        self.skip_space(false);
+        self.expect(":")?;
+        self.skip_space(false);
+        let (alternates, aliases) = self.parse_alternates(false)?;
+        if is_terminal(&name) && (modifier.is_some() || aliases.iter().any(Option::is_some)) {
+            return Err(error(format!(
+                "terminal `{name}` cannot have modifiers or aliases"
+            )));
+        }
+        Ok(Definition {
+            name,
+            modifier,
+            priority,
+            alternates,
+            aliases,
+        })
+    }
+
+    /// Alternatives separated by `|`, which can start the following lines
+    fn parse_alternates(
+        &mut self,
+        nested: bool,
+    ) -> Result<(Alternates, Vec<Option<String>>), GrammarError> {
+        let mut alternates = Vec::new();
+        let mut aliases = Vec::new();
+        loop {
+            let (sequence, alias) = self.parse_sequence(nested)?;
+            if sequence.is_empty() {
+                return Err(error(format!("expected an item at char {}", self.pos)));
+            }
+            alternates.push(sequence);
+            aliases.push(alias);
+            let end = self.pos;
+            self.skip_space(true);
+            if self.peek() != Some('|') {
+                self.pos = end;
+                return Ok((alternates, aliases));
+            }
+            self.pos += 1;
+            self.skip_space(nested);
+        }
+    }
+
+    fn parse_sequence(
+        &mut self,
+        nested: bool,
+    ) -> Result<(Vec<Item>, Option<String>), GrammarError> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            let item = match c {
+                '"' => {
+                    let literal = self.parse_string()?;
+                    if self.starts_with("..") {
+                        self.pos += 2;
+                        let end = self.parse_string()?;
+                        let mut range = literal.chars().chain(end.chars());
+                        match (range.next(), range.next(), range.next()) {
+                            (Some(start), Some(end), None) if start <= end => {
+                                Item::Range(start, end)
+                            }
+                            _ => {
+                                return Err(error(format!(
+                                    "invalid range \"{literal}\"..\"{end}\""
+                                )))
+                            }
+                        }
+                    } else {
+                        let insensitive = self.peek() == Some('i');
+                        if insensitive {
+                            self.pos += 1;
+                        }
+                        Item::Literal(literal, insensitive)
+                    }
+                }
+                '/' => {
+                    self.pos += 1;
+                    let mut pattern = String::new();
+                    loop {
+                        match self.next()? {
+                            '/' => break,
+                            '\\' => match self.next()? {
+                                '/' => pattern.push('/'),
+                                c => {
+                                    pattern.push('\\');
+                                    pattern.push(c);
+                                }
+                            },
+                            c => pattern.push(c),
+                        }
+                    }
+                    if pattern.is_empty() {
+                        return Err(error(format!("empty regex at char {}", self.pos - 2)));
+                    }
+                    let mut flags = String::new();
+                    while let Some(flag @ ('i' | 'm' | 's' | 'u' | 'x')) = self.peek() {
+                        flags.push(flag);
+                        self.pos += 1;
+                    }
+                    Item::Regex(pattern, flags)
+                }
+                '(' | '[' => {
+                    self.depth += 1;
+                    if self.depth > MAX_LARK_NESTING {
+                        return Err(error(format!(
+                            "groups can be nested at most {MAX_LARK_NESTING} times"
+                        )));
+                    }
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let (alternates, _) = self.parse_alternates(true)?;
+                    self.skip_space(true);
+                    self.depth -= 1;
+                    if c == '(' {
+                        self.expect(")")?;
+                        Item::Group(alternates)
+                    } else {
+                        self.expect("]")?;
+                        Item::Maybe(alternates)
+                    }
+                }
+                '?' | '*' | '+' | '~' => {
+                    let item = match items.pop() {
+                        Some(Item::Repeat(..)) => {
+                            return Err(error(format!("double quantifier at char {}", self.pos)))
+                        }
+                        Some(item) => item,
+                        None => {
+                            return Err(error(format!(
+                                "`{c}` without an item at char {}",
+                                self.pos
+                            )))
+                        }
+                    };
+                    self.pos += 1;
+                    let (min, max) = match c {
+                        '?' => (0, Some(1)),
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        _ => self.parse_repetition()?,
+                    };
+                    Item::Repeat(Box::new(item), min, max)
+                }
+                '-' if !nested && self.starts_with("->") => {
+                    self.pos += 2;
+                    self.skip_space(false);
+                    let alias = self.parse_name()?;
+                    return Ok((items, Some(alias)));
+                }
+                c if c.is_ascii_alphabetic() || c == '_' => Item::Name(self.parse_name()?),
+                _ => break,
+            };
+            items.push(item);
+            self.skip_space(nested);
+        }
+        Ok((items, None))
+    }
+
+    /// `"..."`, with the escapes of Python
+    fn parse_string(&mut self) -> Result<String, GrammarError> {
+        self.expect("\"")?;
+        let mut literal = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(literal),
+                '\\' => {
+                    let digits = match self.next()? {
+                        'n' => {
+                            literal.push('\n');
+                            continue;
+                        }
+                        'r' => {
+                            literal.push('\r');
+                            continue;
+                        }
+                        't' => {
+                            literal.push('\t');
+                            continue;
+                        }
+                        'f' => {
+                            literal.push('\x0c');
+                            continue;
+                        }
+                        c @ ('\\' | '"') => {
+                            literal.push(c);
+                            continue;
+                        }
+                        'x' => 2,
+                        'u' => 4,
+                        'U' => 8,
+                        // Unknown escapes are kept as is
+                        c => {
+                            literal.push('\\');
+                            literal.push(c);
+                            continue;
+                        }
+                    };
+                    let mut value = 0;
+                    for _ in 0..digits {
+                        let digit = self.next()?;
+                        value = value * 16
+                            + digit.to_digit(16).ok_or_else(|| {
+                                error(format!("invalid hexadecimal digit `{digit}`"))
+                            })?;
+                    }
+                    literal.push(
+                        char::from_u32(value)
+                            .ok_or_else(|| error(format!("invalid char {value:#x}")))?,
+                    );
+                }
+                c => literal.push(c),
+            }
+        }
+    }
+
+    /// `n` or `n..m`, after the `~`
+    fn parse_repetition(&mut self) -> Result<(u32, Option<u32>), GrammarError> {
+        self.skip_space(false);
+        let min = self.parse_number()?;
+        if !self.starts_with("..") {
+            return Ok((min, Some(min)));
+        }
+        self.pos += 2;
+        let max = self.parse_number()?;
+        if max < min {
+            return Err(error(format!("invalid repetition ~ {min}..{max}")));
+        }
+        Ok((min, Some(max)))
+    }
+}
+
+/// Replace the names of the `terminals` by groups of their alternates
+fn inline(alternates: Alternates, terminals: &HashMap<String, Alternates>) -> Alternates {
+    fn inline_item(item: Item, terminals: &HashMap<String, Alternates>) -> Item {
+        match item {
+            Item::Name(name) => match terminals.get(&name) {
+                Some(alternates) => Item::Group(alternates.clone()),
+                None => Item::Name(name),
+            },
+            Item::Group(alternates) => Item::Group(inline(alternates, terminals)),
+            Item::Maybe(alternates) => Item::Maybe(inline(alternates, terminals)),
+            Item::Repeat(item, min, max) => {
+                Item::Repeat(Box::new(inline_item(*item, terminals)), min, max)
+            }
+            item => item,
+        }
+    }
+    alternates
+        .into_iter()
+        .map(|sequence| {
+            sequence
+                .into_iter()
+                .map(|item| inline_item(item, terminals))
+                .collect()
+        })
+        .collect()
+}
+
+/// Call `f` on the items of the `alternates` and on their nested items
+fn visit<'a>(
+    alternates: &'a Alternates,
+    f: &mut impl FnMut(&'a Item) -> Result<(), GrammarError>,
+) -> Result<(), GrammarError> {
+    fn visit_item<'a>(
+        item: &'a Item,
+        f: &mut impl FnMut(&'a Item) -> Result<(), GrammarError>,
+    ) -> Result<(), GrammarError> {
+        f(item)?;
+        match item {
+            Item::Group(alternates) | Item::Maybe(alternates) => visit(alternates, f),
+            Item::Repeat(item, _, _) => visit_item(item, f),
+            _ => Ok(()),
+        }
+    }
+    alternates
+        .iter()
+        .flatten()
+        .try_for_each(|item| visit_item(item, f))
+}
+
+/// Number of items of the `alternates` once the `~` repetitions are expanded: `item ~ n..m`
+/// becomes the alternatives of `n` to `m` items
+fn size(alternates: &Alternates) -> u64 {
+    fn item_size(item: &Item) -> u64 {
+        match item {
+            Item::Group(alternates) | Item::Maybe(alternates) => size(alternates),
+            Item::Repeat(item, min, Some(max)) => {
+                let (min, max) = (*min as u64, *max as u64);
+                let repetitions = ((min + max) * (max - min + 1) / 2).max(1);
+                item_size(item).saturating_mul(repetitions)
+            }
+            Item::Repeat(item, _, None) => item_size(item),
+            _ => 1,
+        }
+    }
+    alternates
+        .iter()
+        .flatten()
+        .map(item_size)
+        .fold(0, u64::saturating_add)
+}
+
+/// Regex of a `/pattern/flags` terminal
+fn regex_terminal(pattern: &str, flags: &str) -> String {
+    format!("(?{flags}:{pattern})")
+}
+
+impl Grammar {
+    /// Add the imported terminals to the definitions
+    fn resolve_imports(mut self) -> Result<Self, GrammarError> {
+        for name in std::mem::take(&mut self.imports) {
+            let alternates = COMMON_TERMINALS
+                .get(&name)
+                .ok_or_else(|| error(format!("`common.{name}` cannot be imported")))?;
+            if self
+                .definitions
+                .iter()
+                .all(|definition| definition.name != name)
+            {
+                self.definitions.push(Definition {
+                    name,
+                    modifier: None,
+                    priority: None,
+                    aliases: vec![None; alternates.len()],
+                    alternates: alternates.clone(),
+                });
+            }
+        }
+        Ok(self)
+    }
+
+    /// Bound the complexity of the grammar and check its references. Returns the alternates of
+    /// the definitions by name.
+    fn check(&self) -> Result<HashMap<&str, &Alternates>, GrammarError> {
+        if self.definitions.len() > MAX_LARK_DEFINITIONS {
+            return Err(error(format!(
+                "the grammar can define at most {MAX_LARK_DEFINITIONS} rules and terminals"
+            )));
+        }
+        let mut definitions = HashMap::new();
+        for definition in &self.definitions {
+            if definitions
+                .insert(definition.name.as_str(), &definition.alternates)
+                .is_some()
+            {
+                return Err(error(format!("`{}` is defined twice", definition.name)));
+            }
+        }
+        if !definitions.contains_key("start") {
+            return Err(error("the grammar must define a `start` rule".to_string()));
+        }
+        let items = self
+            .definitions
+            .iter()
+            .map(|definition| &definition.alternates)
+            .chain(&self.ignored)
+            .map(size)
+            .fold(0, u64::saturating_add);
+        if items > MAX_LARK_ITEMS as u64 {
+            return Err(error(format!(
+                "the grammar can contain at most {MAX_LARK_ITEMS} items once the repetitions are expanded"
+            )));
+        }
+
+        let definitions_by_terminal = self
+            .definitions
+            .iter()
+            .map(|definition| (is_terminal(&definition.name), &definition.alternates))
+            .chain(self.ignored.iter().map(|alternates| (true, alternates)));
+        for (terminal, alternates) in definitions_by_terminal {
+            visit(alternates, &mut |item| match item {
+                Item::Name(name) if !definitions.contains_key(name.as_str()) => {
+                    Err(error(format!("`{name}` is not defined")))
+                }
+                Item::Name(name) if terminal && !is_terminal(name) => Err(error(format!(
+                    "terminals cannot reference the rule `{name}`"
+                ))),
+                Item::Regex(pattern, flags) => RegexBuilder::new(&regex_terminal(pattern, flags))
+                    .size_limit(REGEX_TERMINAL_SIZE_LIMIT)
+                    .build()
+                    .map(|_| ())
+                    .map_err(|err| error(format!("invalid regex `/{pattern}/`: {err}"))),
+                _ => Ok(()),
+            })?;
+        }
+
+        // Lark rejects the recursive terminals
+        for definition in &self.definitions {
+            if is_terminal(&definition.name) {
+                let mut stack = vec![definition.name.as_str()];
+                let mut seen = Vec::new();
+                while let Some(name) = stack.pop() {
+                    visit(definitions[name], &mut |item| match item {
+                        Item::Name(reference) if *reference == definition.name => Err(error(
+                            format!("terminal `{}` is recursive", definition.name),
+                        )),
+                        Item::Name(reference) => {
+                            if !seen.contains(reference) {
+                                seen.push(reference.clone());
+                                stack.push(reference);
+                            }
+                            Ok(())
+                        }
+                        _ => Ok(()),
+                    })?;
+                }
+            }
+        }
+        Ok(definitions)
+    }
+}
+
+/// Normalized grammar, parsed by the model servers
+impl fmt::Display for Grammar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for definition in &self.definitions {
+            if let Some(modifier) = definition.modifier {
+                write!(f, "{modifier}")?;
+            }
+            write!(f, "{}", definition.name)?;
+            if let Some(priority) = definition.priority {
+                write!(f, ".{priority}")?;
+            }
+            write!(f, ": ")?;
+            for (i, (sequence, alias)) in definition
+                .alternates
+                .iter()
+                .zip(&definition.aliases)
+                .enumerate()
+            {
+                if i > 0 {
+                    write!(f, " | ")?;
+                }
+                write_sequence(f, sequence)?;
+                if let Some(alias) = alias {
+                    write!(f, " -> {alias}")?;
+                }
+            }
+            writeln!(f)?;
+        }
+        for alternates in &self.ignored {
+            write!(f, "%ignore ")?;
+            write_alternates(f, alternates)?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_sequence(f: &mut fmt::Formatter<'_>, sequence: &[Item]) -> fmt::Result {
+    for (i, item) in sequence.iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
+fn write_alternates(f: &mut fmt::Formatter<'_>, alternates: &Alternates) -> fmt::Result {
+    for (i, sequence) in alternates.iter().enumerate() {
+        if i > 0 {
+            write!(f, " | ")?;
+        }
+        write_sequence(f, sequence)?;
+    }
+    Ok(())
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in string.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Item::Literal(literal, insensitive) => {
+                write_string(f, literal)?;
+                if *insensitive {
+                    write!(f, "i")?;
+                }
+                Ok(())
+            }
+            Item::Regex(pattern, flags) => write!(f, "/{}/{flags}", pattern.replace('/', "\\/")),
+            Item::Range(start, end) => {
+                write_string(f, &start.to_string())?;
+                write!(f, "..")?;
+                write_string(f, &end.to_string())
+            }
+            Item::Name(name) => write!(f, "{name}"),
+            Item::Group(alternates) => {
+                write!(f, "(")?;
+                write_alternates(f, alternates)?;
+                write!(f, ")")
+            }
+            Item::Maybe(alternates) => {
+                write!(f, "[")?;
+                write_alternates(f, alternates)?;
+                write!(f, "]")
+            }
+            Item::Repeat(item, min, max) => match (min, max) {
+                (0, Some(1)) => write!(f, "{item}?"),
+                (0, None) => write!(f, "{item}*"),
+                (1, None) => write!(f, "{item}+"),
+                (min, Some(max)) if min == max => write!(f, "{item} ~ {min}"),
+                (min, Some(max)) => write!(f, "{item} ~ {min}..{max}"),
+                (min, None) => write!(f, "{item} ~ {min} {item}*"),
+            },
+        }
+    }
+}
+
+struct Expander<'a> {
+    definitions: &'a HashMap<&'a str, &'a Alternates>,
+    /// Regexes of the definitions already expanded
+    expanded: HashMap<&'a str, String>,
+    /// Definitions being expanded, to detect the recursions
+    stack: Vec<&'a str>,
+}
+
+impl<'a> Expander<'a> {
+    /// Regex of the definition `name`, `None` if it is recursive or its regex is too long
+    fn expand(&mut self, name: &str) -> Option<String> {
+        if let Some(regex) = self.expanded.get(name) {
+            return Some(regex.clone());
+        }
+        let (name, alternates) = self.definitions.get_key_value(name)?;
+        if self.stack.contains(name) {
+            return None;
+        }
+        self.stack.push(name);
+        let regex = self.alternates(alternates)?;
+        self.stack.pop();
+        if regex.len() > MAX_REGEX_LENGTH {
+            return None;
+        }
+        self.expanded.insert(name, regex.clone());
+        Some(regex)
+    }
+
+    fn alternates(&mut self, alternates: &'a Alternates) -> Option<String> {
+        let sequences = alternates
+            .iter()
+            .map(|sequence| {
+                sequence
+                    .iter()
+                    .map(|item| self.item(item))
+                    .collect::<Option<String>>()
+            })
+            .collect::<Option<Vec<_>>>()?;
+        match sequences.len() {
+            1 => Some(sequences.into_iter().next().unwrap()),
+            _ => Some(format!("({})", sequences.join("|"))),
+        }
+    }
+
+    fn item(&mut self, item: &'a Item) -> Option<String> {
+        let regex = match item {
+            Item::Literal(literal, false) => escape(literal),
+            Item::Literal(literal, true) => format!("(?i:{})", escape(literal)),
+            Item::Regex(pattern, flags) => regex_terminal(pattern, flags),
+            Item::Range(start, end) => {
+                let mut class = String::from("[");
+                push_class_char(&mut class, *start);
+                class.push('-');
+                push_class_char(&mut class, *end);
+                class.push(']');
+                class
+            }
+            Item::Name(name) => format!("({})", self.expand(name)?),
+            Item::Group(alternates) => format!("({})", self.alternates(alternates)?),
+            Item::Maybe(alternates) => format!("({})?", self.alternates(alternates)?),
+            Item::Repeat(item, min, max) => {
+                let quantifier = match (min, max) {
+                    (0, None) => "*".to_string(),
+                    (1, None) => "+".to_string(),
+                    (0, Some(1)) => "?".to_string(),
+                    (min, None) => format!("{{{min},}}"),
+                    (min, Some(max)) if min == max => format!("{{{min}}}"),
+                    (min, Some(max)) => format!("{{{min},{max}}}"),
+                };
+                format!("({}){quantifier}", self.item(item)?)
+            }
+        };
+        Some(regex)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn lark_regex(grammar: &str) -> Regex {
+        match compile_lark(grammar).unwrap() {
+            CompiledLark::Regex(regex) => Regex::new(&format!("^{regex}$")).unwrap(),
+            r => panic!("Unexpected grammar {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_compile_lark_regex() {
+        let regex = lark_regex(
+            r#"
+            // A signed sum
+            start: term (("+" | "-") term)* ["="i SIGNED_NUMBER]
+            ?term: NUMBER | VAR ~ 1..2
+                 | "(" /[a-z]/ ")" -> call
+            VAR.2: "a".."c"
+            %import common (NUMBER, SIGNED_NUMBER)
+            "#,
+        );
+        for text in ["1", "1.5e3+ab-(x)", "2-3=-1", "a+1E4"] {
+            assert!(regex.is_match(text), "{text}");
+        }
+        for text in ["", "1+", "abc", "(xy)", "1=a"] {
+            assert!(!regex.is_match(text), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_compile_lark_cfg() {
+        let grammar = r#"
+            ?start: value
+            value: "[" [value ("," value)*] "]" | NUMBER | ESCAPED_STRING
+            %import common.NUMBER  // numbers
+            %import common.ESCAPED_STRING
+            "#;
+        let normalized = match compile_lark(grammar).unwrap() {
+            CompiledLark::Cfg(normalized) => normalized,
+            r => panic!("Unexpected grammar {r:?}"),
+        };
+        assert!(normalized.starts_with(
+            "?start: value\nvalue: \"[\" [value (\",\" value)*] \"]\" | NUMBER | ESCAPED_STRING\n"
+        ));
+        assert!(!normalized.contains("%import"));
+        // The normalized grammar parses to itself
+        assert_eq!(
+            compile_lark(&normalized).unwrap(),
+            CompiledLark::Cfg(normalized)
+        );
+
+        // Ignored terminals are inserted by the lexer, they are not regular
+        let grammar = "start: WORD+\n%ignore \" \"\n%import common.WORD";
+        assert_eq!(
+            compile_lark(grammar).unwrap(),
+            CompiledLark::Cfg(
+                "start: WORD+\nWORD: ((\"A\"..\"Z\") | (\"a\"..\"z\"))+\n%ignore \" \"\n"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_compile_lark_errors() {
+        let nested = format!("start: {}\"a\"{}", "(".repeat(40), ")".repeat(40));
+        let too_many_items = format!("start: {}", "\"a\"".repeat(MAX_LARK_ITEMS + 1));
+        let too_many_rules: String = (0..=MAX_LARK_DEFINITIONS)
+            .map(|i| format!("rule{i}: \"{i}\"\n"))
+            .chain(["start: rule0".to_string()])
+            .collect();
+        for grammar in [
+            "list: \"[\" list? \"]\"",
+            "start: a",
+            "start: \"a\"\nstart: \"b\"",
+            "start: \"a\" |",
+            "start: \"a\"**",
+            "start: \"ab\"..\"c\"",
+            "start: /(?<=a)b/",
+            "start: A\nA: a\na: \"a\"",
+            "start: A\nA: \"a\" A?",
+            "?A: \"a\"\nstart: A",
+            "start: (\"a\" ~ 32) ~ 0..128",
+            "start: \"a\"\n%import python.NAME",
+            "start: \"a\"\n%import common.NOPE",
+            "start: \"a\"\n%declare A",
+            &nested,
+            &too_many_items,
+            &too_many_rules,
+        ] {
+            assert!(compile_lark(grammar).is_err(), "{grammar}");
+        }
+    }
+}
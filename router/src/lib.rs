@@ -1,13 +1,39 @@
 /// Text Generation Inference Webserver
+mod adapter_acl;
+mod audit;
+pub mod auth;
 pub mod config;
+mod cors;
+mod default_parameters;
+mod events;
+pub mod fetch;
+mod fsm_cache;
+mod gbnf;
+mod grammar;
+mod grammar_registry;
+mod image_pipeline;
 mod infer;
+mod input_policy;
+pub mod ip_filter;
+mod lark;
+mod probe;
+pub mod prompt_log;
+mod provenance;
+pub mod quota;
+pub mod rate_limit;
+pub mod secrets;
 pub mod server;
+pub mod telemetry;
+pub mod tls;
+pub mod token_estimate;
 mod validation;
 
 #[cfg(feature = "kserve")]
 mod kserve;
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
 use tracing::warn;
 use utoipa::ToSchema;
 use validation::Validation;
@@ -125,18 +151,84 @@ impl HubProcessorConfig {
 }
 
 #[derive(Clone, Debug, Deserialize, ToSchema, Serialize)]
-#[serde(tag = "type", content = "value")]
+#[serde(remote = "Self", tag = "type", content = "value")]
 pub(crate) enum GrammarType {
     /// A string that represents a [JSON Schema](https://json-schema.org/).
     ///
     /// JSON Schema is a declarative language that allows to annotate JSON documents
     /// with types and descriptions.
     #[serde(rename = "json")]
-    #[serde(alias = "json_object")]
     #[schema(example = json ! ({"properties": {"location":{"type": "string"}}}))]
     Json(serde_json::Value),
+    /// Any JSON object, like the OpenAI `response_format: {"type": "json_object"}`.
+    /// A given JSON Schema is used like with `json`.
+    #[serde(rename = "json_object")]
+    #[schema(value_type = Option<Object>, example = json ! (null))]
+    JsonObject(Option<serde_json::Value>),
+    /// A regular expression matched by the whole generated text: a leading `^` and a trailing
+    /// `$` are implied, other anchors and word boundaries are not supported.
     #[serde(rename = "regex")]
     Regex(String),
+    /// One of the given strings, generated without compiling a JSON Schema.
+    #[serde(rename = "choice")]
+    #[schema(example = json ! (["positive", "negative", "neutral"]))]
+    Choice(Vec<String>),
+    /// A [GBNF](https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md) grammar,
+    /// the EBNF dialect of llama.cpp, generated from its `root` rule. The rules cannot be
+    /// recursive.
+    #[serde(rename = "ebnf", alias = "gbnf")]
+    #[schema(example = "root ::= (\"yes\" | \"no\") \".\"")]
+    Ebnf(String),
+    /// A context-free [Lark](https://lark-parser.readthedocs.io/en/latest/grammar.html) grammar,
+    /// generated from its `start` rule. Unlike the other grammars, it can describe nested
+    /// structures such as balanced brackets. Only the terminals of `common` can be imported.
+    /// The grammars without recursive rules nor `%ignore` are compiled like the regex grammars.
+    #[serde(rename = "lark")]
+    #[schema(
+        example = "start: value\nvalue: \"[\" [value (\",\" value)*] \"]\" | NUMBER\n%import common.NUMBER"
+    )]
+    Lark(String),
+    /// The name of a grammar of the registry of the server, loaded from the files of
+    /// `--grammar-registry-dir`. The name can also be given as `name` instead of `value`.
+    #[serde(rename = "registry")]
+    #[schema(example = "invoice_v2")]
+    Registry(String),
+    /// The id of a grammar compiled by `/grammar/compile`.
+    #[serde(rename = "compiled")]
+    #[schema(example = "9f86d081884c7d659a2feaa0c55ad015")]
+    Compiled(String),
+    /// Any of the given grammars, which cannot be unions or context-free `lark` grammars. JSON
+    /// Schemas and regex grammars (`regex`, `choice`, `ebnf`, `json_object` without a schema and
+    /// the regular `lark` grammars) cannot be mixed.
+    #[serde(rename = "union")]
+    Union(Vec<GrammarType>),
+}
+
+impl<'de> Deserialize<'de> for GrammarType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Registry grammars are also referenced with `{"type": "registry", "name": ...}`
+        let value = serde_json::Value::deserialize(deserializer)?;
+        if value.get("type").and_then(|kind| kind.as_str()) == Some("registry")
+            && value.get("value").is_none()
+        {
+            if let Some(name) = value.get("name").and_then(|name| name.as_str()) {
+                return Ok(GrammarType::Registry(name.to_string()));
+            }
+        }
+        GrammarType::deserialize(value).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for GrammarType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        GrammarType::serialize(self, serializer)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, ToSchema)]
@@ -184,23 +276,21 @@ pub struct Info {
     pub sha: Option<&'static str>,
     #[schema(nullable = true, example = "null")]
     pub docker_label: Option<&'static str>,
+    /// Base64 encoded Ed25519 key verifying the provenance signatures
+    #[schema(nullable = true, example = "null")]
+    pub provenance_public_key: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize, ToSchema, Default)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema, Default)]
 pub(crate) struct GenerateParameters {
     /// Generate best_of sequences and return the one if the highest token logprobs.
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
     pub best_of: Option<usize>,
 
-    /// The value used to module the logits distribution.
+    /// The value used to module the logits distribution. `0` is greedy decoding.
     #[serde(default)]
-    #[schema(
-        exclusive_minimum = 0.0,
-        nullable = true,
-        default = "null",
-        example = 0.5
-    )]
+    #[schema(minimum = 0.0, nullable = true, default = "null", example = 0.5)]
     pub temperature: Option<f32>,
 
     /// The parameter for repetition penalty. 1.0 means no penalty.
@@ -226,6 +316,53 @@ pub(crate) struct GenerateParameters {
     )]
     pub frequency_penalty: Option<f32>,
 
+    /// The parameter for presence penalty. 0.0 means no penalty
+    /// Penalize new tokens based on whether they appear in the text so far,
+    /// increasing the model's likelihood to talk about new topics.
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.1
+    )]
+    pub presence_penalty: Option<f32>,
+
+    /// Size of the n-grams that can only occur once in the text: tokens that would repeat an n-gram are
+    /// never generated.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        maximum = 10,
+        nullable = true,
+        default = "null",
+        example = 3
+    )]
+    pub no_repeat_ngram_size: Option<u32>,
+
+    /// Multiplier of the DRY ("don't repeat yourself") penalty, which penalizes the tokens that would extend a
+    /// repetition of the text so far. 0.0 means no penalty.
+    #[serde(default)]
+    #[schema(minimum = 0.0, nullable = true, default = "null", example = 0.8)]
+    pub dry_multiplier: Option<f32>,
+
+    /// Base of the DRY penalty: the penalty is `dry_multiplier * dry_base ^ (length - dry_allowed_length)`, where
+    /// `length` is the length of the repetition. Defaults to 1.75.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 1.75
+    )]
+    pub dry_base: Option<f32>,
+
+    /// Repetitions up to this length are not penalized by DRY. Defaults to 2.
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 2)]
+    pub dry_allowed_length: Option<u32>,
+
     /// The number of highest probability vocabulary tokens to keep for top-k-filtering.
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
@@ -254,16 +391,114 @@ pub(crate) struct GenerateParameters {
     )]
     pub typical_p: Option<f32>,
 
+    /// Min-p value: tokens whose probability is lower than `min_p` times the probability of the
+    /// most likely token are filtered out.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.05
+    )]
+    pub min_p: Option<f32>,
+
+    /// Epsilon sampling: tokens whose probability is lower than `epsilon_cutoff` are filtered out.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        exclusive_maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.0003
+    )]
+    pub epsilon_cutoff: Option<f32>,
+
+    /// Eta sampling: like `epsilon_cutoff`, with a cutoff that depends on the entropy of the
+    /// distribution, see "Truncation Sampling as Language Model Desmoothing".
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        exclusive_maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.0003
+    )]
+    pub eta_cutoff: Option<f32>,
+
+    /// Top-a sampling: tokens whose probability is lower than `top_a` times the square of the
+    /// highest probability are filtered out.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.2
+    )]
+    pub top_a: Option<f32>,
+
+    /// Mirostat version, only mirostat v2 (`2`) is supported. Mirostat truncates the distribution
+    /// to keep the surprise of the generated tokens close to `mirostat_tau`, see
+    /// [Mirostat: A Neural Text Decoding Algorithm that Directly Controls Perplexity](https://arxiv.org/abs/2007.14966).
+    /// 0 disables it.
+    #[serde(default)]
+    #[schema(maximum = 2, nullable = true, default = "null", example = 2)]
+    pub mirostat_mode: Option<u32>,
+
+    /// Target surprise of mirostat, in bits. Defaults to 5.0.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        nullable = true,
+        default = "null",
+        example = 5.0
+    )]
+    pub mirostat_tau: Option<f32>,
+
+    /// Learning rate of mirostat. Defaults to 0.1.
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.1
+    )]
+    pub mirostat_eta: Option<f32>,
+
+    /// Bias added to the logits of the given token ids before sampling, between -100 and 100.
+    /// -100 bans a token, 100 makes it the only candidate.
+    #[serde(default)]
+    #[schema(example = json ! ({"42": -100.0}))]
+    pub logit_bias: HashMap<u32, f32>,
+
     /// Activate logits sampling.
     #[serde(default)]
     #[schema(default = "false", example = true)]
     pub do_sample: bool,
 
     /// Maximum number of tokens to generate.
-    #[serde(default = "default_max_new_tokens")]
-    #[schema(nullable = true, default = "100", example = "20")]
+    /// Defaults to the tokens left by the inputs in the `max_total_tokens` budget.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "20")]
     pub max_new_tokens: Option<u32>,
 
+    /// Minimum number of tokens to generate: the end of sequence token cannot be generated before.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "5")]
+    pub min_new_tokens: Option<u32>,
+
+    /// Maximum generation time, in seconds. Defaults to the maximum allowed by the server.
+    #[serde(default)]
+    #[schema(
+        nullable = true,
+        default = "null",
+        example = 10.0,
+        exclusive_minimum = 0.0
+    )]
+    pub max_time: Option<f32>,
+
     /// Whether to prepend the prompt to the generated text
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = false)]
@@ -274,16 +509,82 @@ pub(crate) struct GenerateParameters {
     #[schema(inline, max_items = 4, example = json ! (["photographer"]))]
     pub stop: Vec<String>,
 
+    /// Stop generating tokens if one of the token ids of `stop_token_ids` is generated.
+    #[serde(default)]
+    #[schema(inline, max_items = 4, example = json ! ([128009]))]
+    pub stop_token_ids: Vec<u32>,
+
+    /// Stop generating tokens once the generated text matches one of the regular expressions of `stop_regex`.
+    /// They are matched by the router on the decoded tokens, the generated text ends with the match.
+    #[serde(default)]
+    #[schema(inline, max_items = 4, example = json ! (["\\n\\d+\\."]))]
+    pub stop_regex: Vec<String>,
+
+    /// Phrases that cannot be generated. They are tokenized as given: prefix a word with a space to ban it in the middle
+    /// of a sentence.
+    #[serde(default)]
+    #[schema(inline, max_items = 64, example = json ! ([" photographer"]))]
+    pub bad_words: Vec<String>,
+
+    /// Negative prompt of the classifier-free guidance: the model is steered away from it. Requires
+    /// `guidance_scale`.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "Low quality, repetitive")]
+    pub negative_inputs: Option<String>,
+
+    /// Classifier-free guidance scale, `1.0` disables the guidance. The flash and seq2seq models
+    /// reject the other values.
+    #[serde(default)]
+    #[schema(
+        nullable = true,
+        default = "null",
+        minimum = 1.0,
+        maximum = 30.0,
+        example = 1.5
+    )]
+    pub guidance_scale: Option<f32>,
+
+    /// Text after the completion. With it, `inputs` is the text before the completion and the
+    /// model fills in the middle.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "\n    return result")]
+    pub suffix: Option<String>,
+
     /// Truncate inputs tokens to the given size.
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = "null")]
     pub truncate: Option<usize>,
 
+    /// Side of the inputs that `truncate` drops tokens from. `right` keeps the beginning of the
+    /// inputs, such as a system prompt.
+    #[serde(default)]
+    #[schema(default = "left", example = "right")]
+    pub truncation_direction: TruncationDirection,
+
+    /// Whether to add the special tokens of the tokenizer, such as BOS, to the inputs. Disable it
+    /// for prompts that already contain them, like the output of a chat template.
+    #[serde(default)]
+    #[schema(nullable = true, default = "true", example = false)]
+    pub add_special_tokens: Option<bool>,
+
+    /// Overrides the sanitization of the inputs configured on the server. Control characters are
+    /// the Unicode `Cc` characters other than tabs and line breaks.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "strip")]
+    pub input_sanitization: Option<InputSanitization>,
+
     /// Watermarking with [A Watermark for Large Language Models](https://arxiv.org/abs/2301.10226).
     #[serde(default)]
     #[schema(default = "false", example = true)]
     pub watermark: bool,
 
+    /// Keep generating after the EOS token, until `max_new_tokens` or a stop sequence. Meant for
+    /// benchmarks and synthetic data that need fixed-length outputs, operators can disable it
+    /// with the input policies.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub ignore_eos_token: bool,
+
     /// Whether to return generation details.
     #[serde(default)]
     #[schema(default = "true")]
@@ -304,6 +605,12 @@ pub(crate) struct GenerateParameters {
     )]
     pub seed: Option<u64>,
 
+    /// Random sampling seed of each candidate when `best_of` > 1, one per candidate.
+    /// Without it, the candidates of a request with a `seed` use `seed`, `seed + 1`, ..., `seed + best_of - 1`.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ([1, 2]))]
+    pub best_of_seeds: Option<Vec<u64>>,
+
     /// The number of highest probability vocabulary tokens to keep for top-n-filtering.
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 5)]
@@ -320,8 +627,32 @@ pub(crate) struct GenerateParameters {
     pub adapter_id: Option<String>,
 }
 
-fn default_max_new_tokens() -> Option<u32> {
-    Some(100)
+impl GenerateParameters {
+    /// Short summary of the parameters, used in logs
+    pub(crate) fn summary(&self) -> String {
+        format!(
+            "max_new_tokens={:?} best_of={:?} temperature={:?} top_k={:?} top_p={:?} typical_p={:?} \
+            min_p={:?} do_sample={} repetition_penalty={:?} frequency_penalty={:?} presence_penalty={:?} \
+            stop_sequences={} stop_token_ids={:?} stop_regex={} bad_words={} grammar={} adapter_id={:?}",
+            self.max_new_tokens,
+            self.best_of,
+            self.temperature,
+            self.top_k,
+            self.top_p,
+            self.typical_p,
+            self.min_p,
+            self.do_sample,
+            self.repetition_penalty,
+            self.frequency_penalty,
+            self.presence_penalty,
+            self.stop.len(),
+            self.stop_token_ids,
+            self.stop_regex.len(),
+            self.bad_words.len(),
+            self.grammar.is_some(),
+            self.adapter_id,
+        )
+    }
 }
 
 fn default_parameters() -> GenerateParameters {
@@ -330,18 +661,44 @@ fn default_parameters() -> GenerateParameters {
         temperature: None,
         repetition_penalty: None,
         frequency_penalty: None,
+        presence_penalty: None,
+        no_repeat_ngram_size: None,
+        dry_multiplier: None,
+        dry_base: None,
+        dry_allowed_length: None,
         top_k: None,
         top_p: None,
         typical_p: None,
+        min_p: None,
+        epsilon_cutoff: None,
+        eta_cutoff: None,
+        top_a: None,
+        mirostat_mode: None,
+        mirostat_tau: None,
+        mirostat_eta: None,
+        logit_bias: HashMap::new(),
         do_sample: true,
-        max_new_tokens: default_max_new_tokens(),
+        max_new_tokens: None,
+        min_new_tokens: None,
+        max_time: None,
         return_full_text: None,
         stop: Vec::new(),
+        stop_token_ids: Vec::new(),
+        stop_regex: Vec::new(),
+        bad_words: Vec::new(),
+        negative_inputs: None,
+        guidance_scale: None,
+        suffix: None,
         truncate: None,
+        truncation_direction: TruncationDirection::Left,
+        add_special_tokens: None,
+        input_sanitization: None,
         watermark: false,
+        ignore_eos_token: false,
         details: false,
         decoder_input_details: false,
         seed: None,
+        best_of_seeds: None,
         top_n_tokens: None,
         grammar: None,
         adapter_id: None,
@@ -407,14 +764,26 @@ pub struct CompletionRequest {
     #[schema(nullable = true, example = 0.95)]
     pub top_p: Option<f32>,
 
+    /// Tokens whose probability is lower than `min_p` times the probability of the most likely token are not
+    /// considered. Must be between 0 and 1, exclusive.
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.05)]
+    pub min_p: Option<f32>,
+
+    /// Modify the likelihood of specified tokens appearing in the completion. Accepts a JSON object that maps tokens
+    /// (specified by their token ID in the tokenizer) to an associated bias value from -100 to 100.
+    #[serde(default)]
+    #[schema(nullable = true, example = json ! ({"42": -100.0}))]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+
     #[serde(default = "bool::default")]
     pub stream: bool,
 
     #[schema(nullable = true, example = 42)]
     pub seed: Option<u64>,
 
-    /// The text to append to the prompt. This is useful for completing sentences or generating a paragraph of text.
-    /// please see the completion_template field in the model's tokenizer_config.json file for completion template.
+    /// The text after the completion: the model fills in the middle between the prompt and the suffix.
+    /// Only supported by models with fill-in-the-middle tokens.
     #[serde(default)]
     pub suffix: Option<String>,
 
@@ -427,6 +796,12 @@ pub struct CompletionRequest {
     #[schema(example = "1.0")]
     pub frequency_penalty: Option<f32>,
 
+    /// Number between -2.0 and 2.0. Positive values penalize new tokens based on whether they appear in the text so far,
+    /// increasing the model's likelihood to talk about new topics
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.1)]
+    pub presence_penalty: Option<f32>,
+
     /// Up to 4 sequences where the API will stop generating further tokens.
     #[serde(default)]
     #[schema(nullable = true, example = "null")]
@@ -734,14 +1109,14 @@ pub(crate) struct ChatRequest {
     #[schema(example = "1.0")]
     pub frequency_penalty: Option<f32>,
 
-    /// UNUSED
     /// Modify the likelihood of specified tokens appearing in the completion. Accepts a JSON object that maps tokens
     /// (specified by their token ID in the tokenizer) to an associated bias value from -100 to 100. Mathematically,
     /// the bias is added to the logits generated by the model prior to sampling. The exact effect will vary per model,
     /// but values between -1 and 1 should decrease or increase likelihood of selection; values like -100 or 100 should
     /// result in a ban or exclusive selection of the relevant token.
     #[serde(default)]
-    pub logit_bias: Option<Vec<f32>>,
+    #[schema(nullable = true, example = json ! ({"42": -100.0}))]
+    pub logit_bias: Option<HashMap<u32, f32>>,
 
     /// Whether to return log probabilities of the output tokens or not. If true, returns the log probabilities of each
     /// output token returned in the content of message.
@@ -798,6 +1173,12 @@ pub(crate) struct ChatRequest {
     #[schema(nullable = true, example = 0.95)]
     pub top_p: Option<f32>,
 
+    /// Tokens whose probability is lower than `min_p` times the probability of the most likely token are not
+    /// considered. Must be between 0 and 1, exclusive.
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.05)]
+    pub min_p: Option<f32>,
+
     /// A list of tools the model may call. Currently, only functions are supported as a tool. Use this to provide a list of
     /// functions the model may generate JSON inputs for.
     #[serde(default)]
@@ -812,10 +1193,10 @@ pub(crate) struct ChatRequest {
     )]
     pub tool_prompt: Option<String>,
 
-    /// A specific tool to use. If not provided, the model will default to use any of the tools provided in the tools parameter.
+    /// A specific tool to use, `auto` or `none`. If not provided, the model will default to use any of the tools provided in the tools parameter.
     #[serde(default)]
-    #[schema(nullable = true, example = "null")]
-    pub tool_choice: Option<ToolType>,
+    #[schema(value_type = Option<ToolType>, nullable = true, example = "null")]
+    pub tool_choice: ToolChoice,
 
     /// Response format constraints for the generation.
     ///
@@ -848,6 +1229,13 @@ pub struct FunctionName {
 #[serde(from = "ToolTypeDeserializer")]
 pub struct ToolChoice(pub Option<ToolType>);
 
+/// Like OpenAI, the tools are used when they are provided without a `tool_choice`
+impl Default for ToolChoice {
+    fn default() -> Self {
+        ToolChoice(Some(ToolType::OneOf))
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 enum ToolTypeDeserializer {
@@ -860,7 +1248,7 @@ impl From<ToolTypeDeserializer> for ToolChoice {
         match value {
             ToolTypeDeserializer::None(opt) => match opt.as_deref() {
                 Some("none") => ToolChoice(None),
-                Some("auto") => ToolChoice(Some(ToolType::OneOf)),
+                Some("auto" | "required") => ToolChoice(Some(ToolType::OneOf)),
                 Some(s) => ToolChoice(Some(ToolType::FunctionName(s.to_string()))),
                 None => ToolChoice(Some(ToolType::OneOf)),
             },
@@ -873,7 +1261,11 @@ impl From<ToolTypeDeserializer> for ToolChoice {
 pub struct Tools {
     #[serde(flatten)]
     functions_map: FunctionsMap,
+    #[serde(rename = "type")]
+    object_type: String,
     properties: Properties,
+    /// The call must be generated, the model can only refuse with `notify_error`
+    required: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -942,6 +1334,57 @@ pub(crate) struct ToolCall {
 #[derive(Clone, Deserialize, ToSchema, Serialize, Debug, PartialEq)]
 pub struct Url {
     url: String,
+    /// Resolution of images, ignored by the other chunks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    detail: Option<ImageDetail>,
+}
+
+/// Resolution an image is processed at. `low` uses the base resolution of the vision model,
+/// with fewer image tokens, `high` and `auto` the maximum resolution of the model.
+#[derive(Clone, Copy, Deserialize, ToSchema, Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageDetail {
+    Low,
+    High,
+    #[default]
+    Auto,
+}
+
+impl std::str::FromStr for ImageDetail {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "low" => Ok(ImageDetail::Low),
+            "high" => Ok(ImageDetail::High),
+            "auto" => Ok(ImageDetail::Auto),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Sanitization of the text inputs before tokenization
+#[derive(
+    Clone, Copy, Deserialize, ToSchema, Serialize, Debug, Default, PartialEq, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum InputSanitization {
+    /// The inputs are tokenized as given
+    #[default]
+    None,
+    /// Control characters are removed and the inputs are normalized to NFC
+    Strip,
+    /// Inputs with control characters are rejected, the others are normalized to NFC
+    Reject,
+}
+
+/// Side of the inputs that truncation drops tokens from
+#[derive(Clone, Copy, Deserialize, ToSchema, Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TruncationDirection {
+    #[default]
+    Left,
+    Right,
 }
 
 #[derive(Clone, Deserialize, ToSchema, Serialize, Debug, PartialEq)]
@@ -950,6 +1393,9 @@ pub struct Url {
 pub enum MessageChunk {
     Text { text: String },
     ImageUrl { image_url: Url },
+    VideoUrl { video_url: Url },
+    AudioUrl { audio_url: Url },
+    DocumentUrl { document_url: Url },
 }
 
 #[derive(Clone, Deserialize, ToSchema, Serialize, Debug, PartialEq)]
@@ -1003,7 +1449,26 @@ impl From<Message> for TextMessage {
                     .into_iter()
                     .map(|chunk| match chunk {
                         MessageChunk::Text { text } => text,
-                        MessageChunk::ImageUrl { image_url } => format!("![]({})", image_url.url),
+                        MessageChunk::ImageUrl { image_url } => match image_url.detail {
+                            Some(detail @ (ImageDetail::Low | ImageDetail::High)) => {
+                                let detail = if detail == ImageDetail::Low {
+                                    "low"
+                                } else {
+                                    "high"
+                                };
+                                format!("![]({}#detail={detail})", image_url.url)
+                            }
+                            _ => format!("![]({})", image_url.url),
+                        },
+                        MessageChunk::VideoUrl { video_url } => {
+                            format!("![video]({})", video_url.url)
+                        }
+                        MessageChunk::AudioUrl { audio_url } => {
+                            format!("![audio]({})", audio_url.url)
+                        }
+                        MessageChunk::DocumentUrl { document_url } => {
+                            format!("![document]({})", document_url.url)
+                        }
                     })
                     .collect::<Vec<_>>()
                     .join(""),
@@ -1028,16 +1493,34 @@ pub(crate) enum OutputMessage {
 
 #[derive(Clone, Debug, Deserialize, ToSchema)]
 pub(crate) struct GenerateRequest {
+    #[serde(default)]
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
+    /// Token ids of the inputs, instead of `inputs`. They are not tokenized again.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ([1, 2, 3]))]
+    pub input_ids: Option<Vec<u32>>,
+    /// Chat messages, instead of `inputs`. They are rendered with the chat template of the model.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub messages: Option<Vec<Message>>,
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
 }
 
 #[derive(Clone, Debug, Deserialize, ToSchema)]
 pub(crate) struct CompatGenerateRequest {
+    #[serde(default)]
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
+    /// Token ids of the inputs, instead of `inputs`. They are not tokenized again.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ([1, 2, 3]))]
+    pub input_ids: Option<Vec<u32>>,
+    /// Chat messages, instead of `inputs`. They are rendered with the chat template of the model.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub messages: Option<Vec<Message>>,
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
     #[serde(default)]
@@ -1049,6 +1532,8 @@ impl From<CompatGenerateRequest> for GenerateRequest {
     fn from(req: CompatGenerateRequest) -> Self {
         Self {
             inputs: req.inputs,
+            input_ids: req.input_ids,
+            messages: req.messages,
             parameters: req.parameters,
         }
     }
@@ -1099,6 +1584,18 @@ pub(crate) enum FinishReason {
     EndOfSequenceToken,
     #[schema(rename = "stop_sequence")]
     StopSequence,
+    /// The generation reached the `max_time` of the request
+    #[schema(rename = "max_time")]
+    MaxTime,
+    /// The stream reached the maximum stream duration
+    #[schema(rename = "max_duration")]
+    MaxDuration,
+    /// The stream reached the maximum stream size
+    #[schema(rename = "max_bytes")]
+    MaxBytes,
+    /// The router stopped a generation that fell out of its grammar
+    #[schema(rename = "grammar_violation")]
+    GrammarViolation,
 }
 
 impl std::fmt::Display for FinishReason {
@@ -1107,6 +1604,10 @@ impl std::fmt::Display for FinishReason {
             FinishReason::Length => write!(f, "length"),
             FinishReason::EndOfSequenceToken => write!(f, "eos_token"),
             FinishReason::StopSequence => write!(f, "stop_sequence"),
+            FinishReason::MaxTime => write!(f, "max_time"),
+            FinishReason::MaxDuration => write!(f, "max_duration"),
+            FinishReason::MaxBytes => write!(f, "max_bytes"),
+            FinishReason::GrammarViolation => write!(f, "grammar_violation"),
         }
     }
 }
@@ -1127,6 +1628,44 @@ pub(crate) struct BestOfSequence {
     pub top_tokens: Vec<Vec<Token>>,
 }
 
+#[derive(Debug, Serialize, ToSchema, Clone, Copy)]
+pub(crate) struct Timings {
+    /// Time spent waiting in the queue, in milliseconds
+    #[schema(example = 12)]
+    pub queue_time: u64,
+    /// Time spent processing the prompt until the first token, in milliseconds
+    #[schema(example = 45)]
+    pub prefill_time: u64,
+    /// Time spent generating the following tokens, in milliseconds
+    #[schema(example = 320)]
+    pub decode_time: u64,
+    /// Generated tokens per second of inference
+    #[schema(example = 31.5)]
+    pub tokens_per_second: f64,
+}
+
+impl Timings {
+    pub(crate) fn new(
+        queue_time: Duration,
+        prefill_time: Duration,
+        decode_time: Duration,
+        generated_tokens: u32,
+    ) -> Self {
+        let inference_time = (prefill_time + decode_time).as_secs_f64();
+        let tokens_per_second = if inference_time > 0.0 {
+            generated_tokens as f64 / inference_time
+        } else {
+            0.0
+        };
+        Self {
+            queue_time: queue_time.as_millis() as u64,
+            prefill_time: prefill_time.as_millis() as u64,
+            decode_time: decode_time.as_millis() as u64,
+            tokens_per_second,
+        }
+    }
+}
+
 #[derive(Serialize, ToSchema)]
 pub(crate) struct Details {
     #[schema(example = "length")]
@@ -1141,6 +1680,48 @@ pub(crate) struct Details {
     pub best_of_sequences: Option<Vec<BestOfSequence>>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub top_tokens: Vec<Vec<Token>>,
+    pub timings: Timings,
+}
+
+/// Detached Ed25519 signature of the lines `model_sha`, `parameters_hash`, `output_hash`
+/// and `timestamp`, joined with `\n`
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct Provenance {
+    #[schema(example = "e985a63cdc139290c5f700ff1929f0b5942cced2")]
+    pub model_sha: String,
+    /// Hex encoded SHA-256 of the JSON parameters
+    pub parameters_hash: String,
+    /// Hex encoded SHA-256 of the generated text
+    pub output_hash: String,
+    /// Unix timestamp, in seconds
+    #[schema(example = 1718000000)]
+    pub timestamp: u64,
+    /// Base64 encoded signature
+    pub signature: String,
+}
+
+/// Token usage of a quota window
+#[derive(Debug, Serialize, ToSchema, PartialEq)]
+pub(crate) struct QuotaWindowUsage {
+    /// Tokens allowed in the window, `null` if unlimited
+    #[schema(nullable = true, example = 100000)]
+    pub limit: Option<u64>,
+    #[schema(example = 1200)]
+    pub used: u64,
+    #[schema(nullable = true, example = 98800)]
+    pub remaining: Option<u64>,
+    /// Unix timestamp of the end of the window, in seconds
+    #[schema(example = 1718064000)]
+    pub reset: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct QuotaUsage {
+    /// Tenant or subject of the caller
+    #[schema(example = "acme")]
+    pub key: String,
+    pub daily: QuotaWindowUsage,
+    pub monthly: QuotaWindowUsage,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1149,12 +1730,63 @@ pub(crate) struct GenerateResponse {
     pub generated_text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub details: Option<Details>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
 }
 
 #[derive(Serialize, ToSchema)]
 #[serde(transparent)]
 pub(crate) struct TokenizeResponse(Vec<SimpleToken>);
 
+/// Grammar compiled by `/grammar/compile`
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct CompileGrammarResponse {
+    /// Id of the grammar in the requests: `{"type": "compiled", "value": id}`
+    #[schema(example = "9f86d081884c7d659a2feaa0c55ad015")]
+    pub id: String,
+    pub status: GrammarStatus,
+    /// Compilation error of the `failed` grammars
+    #[schema(nullable = true, example = json ! (null))]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum GrammarStatus {
+    Compiling,
+    Ready,
+    Failed,
+}
+
+/// Parameters of a validated request, as they would be sent to the model
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ValidateResponse {
+    /// Number of input tokens, after truncation
+    #[schema(example = 12)]
+    pub input_length: u32,
+    #[schema(example = 20)]
+    pub max_new_tokens: u32,
+    #[schema(example = 1.0)]
+    pub temperature: f32,
+    /// 0 disables top-k sampling
+    #[schema(example = 0)]
+    pub top_k: u32,
+    #[schema(example = 1.0)]
+    pub top_p: f32,
+    #[schema(example = 1.0)]
+    pub typical_p: f32,
+    #[schema(example = 1.0)]
+    pub repetition_penalty: f32,
+    #[schema(example = false)]
+    pub do_sample: bool,
+    /// Drawn at random when the request does not set one, it is not reused by later requests
+    #[schema(example = 299792458)]
+    pub seed: u64,
+    /// Time limit of the generation in seconds
+    #[schema(nullable = true, example = 30.0)]
+    pub max_time: Option<f32>,
+}
+
 #[derive(Serialize, ToSchema)]
 pub(crate) struct StreamDetails {
     #[schema(example = "length")]
@@ -1163,6 +1795,7 @@ pub(crate) struct StreamDetails {
     pub generated_tokens: u32,
     #[schema(nullable = true, example = 42)]
     pub seed: Option<u64>,
+    pub timings: Timings,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1175,6 +1808,8 @@ pub(crate) struct StreamResponse {
     pub generated_text: Option<String>,
     #[schema(nullable = true, default = "null")]
     pub details: Option<StreamDetails>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -1310,25 +1945,70 @@ mod tests {
                 role: "user".to_string(),
                 content: MessageContent::MultipleChunks(vec![
                     MessageChunk::Text { text: "Whats in this image?".to_string() },
-                    MessageChunk::ImageUrl { image_url: Url { url: "https://huggingface.co/datasets/huggingface/documentation-images/resolve/main/transformers/rabbit.png".to_string() }},
+                    MessageChunk::ImageUrl { image_url: Url { url: "https://huggingface.co/datasets/huggingface/documentation-images/resolve/main/transformers/rabbit.png".to_string(), detail: None }},
                 ]),
                 name: None
             }
         );
     }
 
+    #[test]
+    fn test_chat_request_tool_choice() {
+        let tool_choice = |tool_choice: Option<serde_json::Value>| {
+            let mut json = json!({"model": "", "messages": []});
+            if let Some(tool_choice) = tool_choice {
+                json["tool_choice"] = tool_choice;
+            }
+            serde_json::from_value::<ChatRequest>(json)
+                .unwrap()
+                .tool_choice
+                .0
+        };
+        assert_eq!(tool_choice(None), Some(ToolType::OneOf));
+        assert_eq!(tool_choice(Some(json!("auto"))), Some(ToolType::OneOf));
+        assert_eq!(tool_choice(Some(json!("none"))), None);
+        assert_eq!(
+            tool_choice(Some(json!("get_weather"))),
+            Some(ToolType::FunctionName("get_weather".to_string()))
+        );
+        assert_eq!(
+            tool_choice(Some(json!({"function": {"name": "get_weather"}}))),
+            Some(ToolType::Function {
+                function: FunctionName {
+                    name: "get_weather".to_string()
+                }
+            })
+        );
+    }
+
     #[test]
     fn text_message_convert() {
         let message = Message{
                 role: "user".to_string(),
                 content: MessageContent::MultipleChunks(vec![
                     MessageChunk::Text { text: "Whats in this image?".to_string() },
-                    MessageChunk::ImageUrl { image_url: Url { url: "https://huggingface.co/datasets/huggingface/documentation-images/resolve/main/transformers/rabbit.png".to_string() } }
+                    MessageChunk::ImageUrl { image_url: Url { url: "https://huggingface.co/datasets/huggingface/documentation-images/resolve/main/transformers/rabbit.png".to_string(), detail: None } }
                 ]),
                 name: None
             };
         let textmsg: TextMessage = message.into();
         assert_eq!(textmsg.content, "Whats in this image?![](https://huggingface.co/datasets/huggingface/documentation-images/resolve/main/transformers/rabbit.png)");
+
+        let message = Message {
+            role: "user".to_string(),
+            content: MessageContent::MultipleChunks(vec![MessageChunk::ImageUrl {
+                image_url: Url {
+                    url: "https://example.com/cat.png".to_string(),
+                    detail: Some(ImageDetail::Low),
+                },
+            }]),
+            name: None,
+        };
+        let textmsg: TextMessage = message.into();
+        assert_eq!(
+            textmsg.content,
+            "![](https://example.com/cat.png#detail=low)"
+        );
     }
     #[test]
     fn openai_output() {
@@ -1362,4 +2042,29 @@ mod tests {
             r#"{"role":"assistant","tool_calls":[{"id":"0","type":"function","function":{"description":null,"name":"myfn","arguments":{"format":"csv"}}}]}"#
         );
     }
+
+    #[test]
+    fn test_grammar_registry_name() {
+        for json in [
+            json!({"type": "registry", "value": "invoice_v2"}),
+            json!({"type": "registry", "name": "invoice_v2"}),
+        ] {
+            let grammar: GrammarType = serde_json::from_value(json).unwrap();
+            assert!(matches!(grammar, GrammarType::Registry(name) if name == "invoice_v2"));
+        }
+
+        let json = json!({"type": "union", "value": [
+            {"type": "registry", "name": "invoice_v2"},
+            {"type": "regex", "value": "[0-9]+"}
+        ]});
+        let grammar: GrammarType = serde_json::from_value(json).unwrap();
+        let serialized = serde_json::to_string(&grammar).unwrap();
+        assert_eq!(
+            serialized,
+            r#"{"type":"union","value":[{"type":"registry","value":"invoice_v2"},{"type":"regex","value":"[0-9]+"}]}"#
+        );
+
+        let json = json!({"type": "registry"});
+        assert!(serde_json::from_value::<GrammarType>(json).is_err());
+    }
 }
@@ -12,9 +12,21 @@ use std::fs::File;
 use std::io::BufReader;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use text_generation_router::auth::{ApiKeyConfig, HmacConfig, JwtConfig};
 use text_generation_router::config::Config;
+use text_generation_router::fetch::{FetchPolicy, FetchRule, IpNetwork};
+use text_generation_router::ip_filter::IpFilterConfig;
+use text_generation_router::prompt_log::{KeyProvider, LocalKeyProvider};
+use text_generation_router::quota::{FileUsageStore, UsageStore};
+use text_generation_router::secrets::SecretSource;
+use text_generation_router::server::ValidationArgs;
+use text_generation_router::tls::TlsConfig;
+use text_generation_router::token_estimate::{ScriptRatio, TokenEstimator};
 use text_generation_router::{
-    server, HubModelInfo, HubPreprocessorConfig, HubProcessorConfig, HubTokenizerConfig,
+    server, telemetry, HubModelInfo, HubPreprocessorConfig, HubProcessorConfig, HubTokenizerConfig,
+    InputSanitization,
 };
 use thiserror::Error;
 use tokenizers::{processors::template::TemplateProcessing, Tokenizer};
@@ -70,8 +82,14 @@ struct Args {
     #[clap(default_value = "text-generation-inference.router", long, env)]
     otlp_service_name: String,
     #[clap(long, env)]
+    otlp_metrics_endpoint: Option<String>,
+    #[clap(default_value = "60", long, env)]
+    otlp_metrics_interval: u64,
+    #[clap(long, env)]
     cors_allow_origin: Option<Vec<String>>,
     #[clap(long, env)]
+    cors_config: Option<String>,
+    #[clap(long, env)]
     ngrok: bool,
     #[clap(long, env)]
     ngrok_authtoken: Option<String>,
@@ -83,12 +101,126 @@ struct Args {
     disable_grammar_support: bool,
     #[clap(default_value = "4", long, env)]
     max_client_batch_size: usize,
+    #[clap(default_value = "32", long, env)]
+    max_adapter_metric_labels: usize,
+    #[clap(long, env)]
+    slow_request_threshold_ms: Option<u64>,
+    #[clap(long, env)]
+    canary_interval: Option<u64>,
+    #[clap(default_value = "0", long, env)]
+    validation_rejection_sample_rate: f64,
+    #[clap(long, env)]
+    jwt_jwks_url: Option<String>,
+    #[clap(long, env)]
+    jwt_issuer: Option<String>,
+    #[clap(long, env)]
+    jwt_audience: Option<String>,
+    #[clap(default_value = "60", long, env)]
+    jwt_leeway: u64,
+    #[clap(default_value = "tenant", long, env)]
+    jwt_tenant_claim: String,
+    #[clap(long, env)]
+    rate_limit_config: Option<String>,
+    #[clap(default_value = "2097152", long, env)]
+    max_json_body_size: usize,
+    #[clap(default_value = "20971520", long, env)]
+    max_multimodal_body_size: usize,
+    #[clap(default_value = "http,https", long, env, value_delimiter = ',')]
+    image_fetch_allowed_schemes: Vec<String>,
+    #[clap(long, env, value_delimiter = ',')]
+    image_fetch_allow: Vec<FetchRule>,
+    #[clap(long, env, value_delimiter = ',')]
+    image_fetch_deny: Vec<FetchRule>,
+    #[clap(long, env, default_value_t = false)]
+    image_fetch_allow_private_networks: bool,
+    #[clap(default_value = "3", long, env)]
+    image_fetch_max_redirects: usize,
+    #[clap(long, env)]
+    provenance_signing_key: Option<String>,
+    #[clap(long, env)]
+    adapter_acl: Option<String>,
+    #[clap(long, env)]
+    hmac_keys: Option<String>,
+    #[clap(default_value = "300", long, env)]
+    hmac_replay_window: u64,
+    #[clap(default_value = "3600", long, env)]
+    secret_rotation_window: u64,
+    #[clap(long, env)]
+    input_policies: Option<String>,
+    #[clap(long, env)]
+    tls_cert_path: Option<String>,
+    #[clap(long, env)]
+    tls_key_path: Option<String>,
+    #[clap(long, env)]
+    tls_client_ca_path: Option<String>,
+    #[clap(long, env)]
+    tls_client_tenants: Option<String>,
+    #[clap(long, env)]
+    quota_config: Option<String>,
+    #[clap(long, env)]
+    quota_store: Option<String>,
+    #[clap(long, env, value_delimiter = ',')]
+    ip_allow: Vec<IpNetwork>,
+    #[clap(long, env, value_delimiter = ',')]
+    ip_deny: Vec<IpNetwork>,
+    #[clap(long, env, value_delimiter = ',')]
+    trusted_proxies: Vec<IpNetwork>,
+    #[clap(long, env)]
+    api_keys: Option<String>,
+    #[clap(long, env, default_value_t = false)]
+    protect_metrics: bool,
+    #[clap(default_value = "10", long, env)]
+    grammar_failure_limit: usize,
+    #[clap(default_value = "60", long, env)]
+    grammar_failure_window: u64,
+    #[clap(default_value = "30", long, env)]
+    grammar_compile_timeout: u64,
+    #[clap(long, env)]
+    max_stream_duration: Option<u64>,
+    #[clap(long, env)]
+    max_stream_bytes: Option<usize>,
+    #[clap(long, env)]
+    prompt_log_key: Option<String>,
+    #[clap(long, env, value_delimiter = ',')]
+    admin_subjects: Vec<String>,
+    #[clap(long, env)]
+    multimodal_limits: Option<String>,
+    #[clap(long, env, default_value_t = false)]
+    probe_max_batch_total_tokens: bool,
+    #[clap(long, env, value_delimiter = ',')]
+    fim_tokens: Vec<String>,
+    #[clap(long, env)]
+    max_generation_time: Option<f32>,
+    #[clap(default_value = "none", long, env, value_enum)]
+    input_sanitization: InputSanitization,
+    #[clap(long, env)]
+    chars_per_token: Option<f32>,
+    #[clap(long, env, value_delimiter = ',')]
+    chars_per_token_overrides: Vec<ScriptRatio>,
+    #[clap(long, env)]
+    default_parameters: Option<String>,
+    #[clap(long, env)]
+    max_input_bytes: Option<usize>,
+    #[clap(long, env)]
+    max_chunk_chars: Option<usize>,
+    #[clap(long, env)]
+    grammar_cache_dir: Option<String>,
+    #[clap(default_value = "1073741824", long, env)]
+    grammar_cache_max_bytes: u64,
+    #[clap(long, env)]
+    grammar_registry_dir: Option<String>,
+    #[clap(long, env)]
+    config_file: Option<String>,
+    #[clap(long, env)]
+    config_deployment: Option<String>,
+    #[clap(long, env, default_value_t = false)]
+    validate_config: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), RouterError> {
-    // Get args
-    let args = Args::parse();
+    // Get args, completed by the `router` section of the configuration file
+    let args: Args = text_generation_config_file::parse("router")?;
     // Pattern match configuration
     let Args {
         max_concurrent_requests,
@@ -112,17 +244,88 @@ async fn main() -> Result<(), RouterError> {
         json_output,
         otlp_endpoint,
         otlp_service_name,
+        otlp_metrics_endpoint,
+        otlp_metrics_interval,
         cors_allow_origin,
+        cors_config,
         ngrok,
         ngrok_authtoken,
         ngrok_edge,
         messages_api_enabled,
         disable_grammar_support,
         max_client_batch_size,
+        max_adapter_metric_labels,
+        slow_request_threshold_ms,
+        canary_interval,
+        validation_rejection_sample_rate,
+        jwt_jwks_url,
+        jwt_issuer,
+        jwt_audience,
+        jwt_leeway,
+        jwt_tenant_claim,
+        rate_limit_config,
+        max_json_body_size,
+        max_multimodal_body_size,
+        image_fetch_allowed_schemes,
+        image_fetch_allow,
+        image_fetch_deny,
+        image_fetch_allow_private_networks,
+        image_fetch_max_redirects,
+        provenance_signing_key,
+        adapter_acl,
+        hmac_keys,
+        hmac_replay_window,
+        secret_rotation_window,
+        input_policies,
+        tls_cert_path,
+        tls_key_path,
+        tls_client_ca_path,
+        tls_client_tenants,
+        quota_config,
+        quota_store,
+        ip_allow,
+        ip_deny,
+        trusted_proxies,
+        api_keys,
+        protect_metrics,
+        grammar_failure_limit,
+        grammar_failure_window,
+        grammar_compile_timeout,
+        max_stream_duration,
+        max_stream_bytes,
+        prompt_log_key,
+        admin_subjects,
+        multimodal_limits,
+        probe_max_batch_total_tokens,
+        fim_tokens,
+        max_generation_time,
+        input_sanitization,
+        chars_per_token,
+        chars_per_token_overrides,
+        default_parameters,
+        max_input_bytes,
+        max_chunk_chars,
+        grammar_cache_dir,
+        grammar_cache_max_bytes,
+        grammar_registry_dir,
+        config_file: _,
+        config_deployment: _,
+        validate_config,
     } = args;
 
     // Launch Tokio runtime
-    init_logging(otlp_endpoint, otlp_service_name, json_output);
+    init_logging(otlp_endpoint, otlp_service_name.clone(), json_output);
+
+    // Push metrics over OTLP
+    let otlp_recorder = otlp_metrics_endpoint.and_then(|otlp_metrics_endpoint| {
+        telemetry::init_otlp_metrics(
+            otlp_metrics_endpoint,
+            otlp_service_name,
+            Duration::from_secs(otlp_metrics_interval),
+        )
+        .map_err(|err| tracing::warn!("Could not start pushing OTLP metrics: {err}"))
+        .ok()
+    });
 
     // Validate args
     if max_input_tokens >= max_total_tokens {
@@ -130,6 +333,35 @@ async fn main() -> Result<(), RouterError> {
             "`max_input_tokens` must be < `max_total_tokens`".to_string(),
         ));
     }
+    // Prefix, suffix and middle tokens, detected from the tokenizer if unset
+    let fim_tokens: Option<[String; 3]> = match fim_tokens.is_empty() {
+        true => None,
+        false => Some(fim_tokens.try_into().map_err(|tokens: Vec<String>| {
+            RouterError::ArgumentValidation(format!(
+                "`fim_tokens` must be the prefix, suffix and middle tokens. Given: {tokens:?}"
+            ))
+        })?),
+    };
+    if let Some(max_generation_time) = max_generation_time {
+        if max_generation_time <= 0.0 {
+            return Err(RouterError::ArgumentValidation(
+                "`max_generation_time` must be > 0".to_string(),
+            ));
+        }
+    }
+    // Input lengths are estimated from their characters when the model has no fast tokenizer
+    let token_estimator = match chars_per_token {
+        Some(chars_per_token) => Some(
+            TokenEstimator::new(chars_per_token, chars_per_token_overrides)
+                .map_err(RouterError::ArgumentValidation)?,
+        ),
+        None if !chars_per_token_overrides.is_empty() => {
+            return Err(RouterError::ArgumentValidation(
+                "`chars_per_token_overrides` requires `chars_per_token`".to_string(),
+            ));
+        }
+        None => None,
+    };
     if max_input_tokens as u32 > max_batch_prefill_tokens {
         return Err(RouterError::ArgumentValidation(format!("`max_batch_prefill_tokens` must be >= `max_input_tokens`. Given: {max_batch_prefill_tokens} and {max_input_tokens}")));
     }
@@ -149,6 +381,114 @@ async fn main() -> Result<(), RouterError> {
         }
     }
 
+    // The admin subjects are namespaced by credential, e.g. `jwt:ops` or `cert:ops`
+    if let Some(subject) = admin_subjects.iter().find(|subject| {
+        !["jwt:", "key:", "hmac:", "cert:"]
+            .iter()
+            .any(|prefix| subject.starts_with(prefix))
+    }) {
+        return Err(RouterError::ArgumentValidation(format!(
+            "`admin_subjects` must be prefixed by `jwt:`, `key:`, `hmac:` or `cert:`. Given: {subject}"
+        )));
+    }
+
+    if canary_interval == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`canary_interval` must be > 0".to_string(),
+        ));
+    }
+
+    if !(0.0..=1.0).contains(&validation_rejection_sample_rate) {
+        return Err(RouterError::ArgumentValidation(format!("`validation_rejection_sample_rate` must be >= 0.0 and <= 1.0. Given: {validation_rejection_sample_rate}")));
+    }
+
+    // Token quotas, the usage is kept in a local file
+    if quota_config.is_some() && quota_store.is_none() {
+        return Err(RouterError::ArgumentValidation(
+            "`quota_config` requires `quota_store`".to_string(),
+        ));
+    }
+    let quota_store =
+        quota_store.map(|path| Arc::new(FileUsageStore::new(path)) as Arc<dyn UsageStore>);
+
+    // Envelope encryption of the logged prompts, `env:NAME` reads the key from a variable
+    let prompt_log_key_provider = prompt_log_key
+        .map(|key| LocalKeyProvider::new(&SecretSource::parse(&key)))
+        .transpose()
+        .map_err(|err| RouterError::ArgumentValidation(format!("`prompt_log_key`: {err}")))?
+        .map(|provider| Arc::new(provider) as Arc<dyn KeyProvider>);
+
+    // Only check the arguments
+    if validate_config {
+        tracing::info!("Valid configuration");
+        return Ok(());
+    }
+
+    // Client networks allowed to reach the API
+    let ip_filter = IpFilterConfig {
+        allow: ip_allow,
+        deny: ip_deny,
+        trusted_proxies,
+    };
+
+    // HTTPS listener
+    if tls_client_tenants.is_some() && tls_client_ca_path.is_none() {
+        return Err(RouterError::ArgumentValidation(
+            "`tls_client_tenants` requires `tls_client_ca_path`".to_string(),
+        ));
+    }
+    if tls_client_ca_path.is_some() && tls_cert_path.is_none() {
+        return Err(RouterError::ArgumentValidation(
+            "`tls_client_ca_path` requires `tls_cert_path` and `tls_key_path`".to_string(),
+        ));
+    }
+    let tls_config = match (tls_cert_path, tls_key_path) {
+        (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+            cert_path,
+            key_path,
+            client_ca_path: tls_client_ca_path,
+            client_tenants_path: tls_client_tenants,
+        }),
+        (None, None) => None,
+        _ => {
+            return Err(RouterError::ArgumentValidation(
+                "`tls_cert_path` and `tls_key_path` must be set together".to_string(),
+            ));
+        }
+    };
+
+    // Restrictions on the image URLs fetched by the router
+    let fetch_policy = FetchPolicy {
+        allowed_schemes: image_fetch_allowed_schemes,
+        allow: image_fetch_allow,
+        deny: image_fetch_deny,
+        allow_private_networks: image_fetch_allow_private_networks,
+        max_redirects: image_fetch_max_redirects,
+    };
+
+    // JWT bearer token validation
+    let jwt_config = jwt_jwks_url.map(|jwks_url| JwtConfig {
+        jwks_url,
+        issuer: jwt_issuer,
+        audience: jwt_audience,
+        leeway: Duration::from_secs(jwt_leeway),
+        tenant_claim: jwt_tenant_claim,
+        rotation_window: Duration::from_secs(secret_rotation_window),
+    });
+
+    // HMAC signed requests of service callers, `env:NAME` reads the keys from a variable
+    let hmac_config = hmac_keys.map(|keys| HmacConfig {
+        keys: SecretSource::parse(&keys),
+        replay_window: Duration::from_secs(hmac_replay_window),
+        rotation_window: Duration::from_secs(secret_rotation_window),
+    });
+
+    // Scoped API keys, `env:NAME` reads the keys from a variable
+    let api_key_config = api_keys.map(|keys| ApiKeyConfig {
+        keys: SecretSource::parse(&keys),
+        rotation_window: Duration::from_secs(secret_rotation_window),
+    });
+
     // CORS allowed origins
     // map to go inside the option and then map to parse from String to HeaderValue
     // Finally, convert to AllowOrigin
@@ -329,7 +669,10 @@ async fn main() -> Result<(), RouterError> {
     tracing::info!("Using config {config:?}");
     if tokenizer.is_none() {
         tracing::warn!("Could not find a fast tokenizer implementation for {tokenizer_name}");
-        tracing::warn!("Rust input length validation and truncation is disabled");
+        match &token_estimator {
+            Some(_) => tracing::warn!("Rust input lengths are estimated from `chars_per_token`"),
+            None => tracing::warn!("Rust input length validation and truncation is disabled"),
+        }
     }
 
     // if pipeline-tag == text-generation we default to return_full_text = true
@@ -388,6 +731,48 @@ async fn main() -> Result<(), RouterError> {
         messages_api_enabled,
         disable_grammar_support,
         max_client_batch_size,
+        max_adapter_metric_labels,
+        slow_request_threshold_ms.map(Duration::from_millis),
+        canary_interval.map(Duration::from_secs),
+        validation_rejection_sample_rate,
+        jwt_config,
+        rate_limit_config,
+        max_json_body_size,
+        max_multimodal_body_size,
+        provenance_signing_key,
+        adapter_acl,
+        hmac_config,
+        tls_config,
+        quota_config,
+        quota_store,
+        ip_filter,
+        cors_config,
+        api_key_config,
+        protect_metrics,
+        max_stream_duration.map(Duration::from_secs),
+        max_stream_bytes,
+        prompt_log_key_provider,
+        admin_subjects,
+        probe_max_batch_total_tokens,
+        token_estimator,
+        otlp_recorder,
+        ValidationArgs {
+            fetch_policy,
+            input_policies,
+            grammar_failure_limit,
+            grammar_failure_window: Duration::from_secs(grammar_failure_window),
+            grammar_compile_timeout: Duration::from_secs(grammar_compile_timeout),
+            multimodal_limits,
+            fim_tokens,
+            max_generation_time,
+            input_sanitization,
+            default_parameters,
+            max_input_bytes,
+            max_chunk_chars,
+            grammar_cache_dir,
+            grammar_cache_max_bytes,
+            grammar_registry_dir,
+        },
     )
     .await?;
     Ok(())
@@ -606,6 +991,8 @@ enum RouterError {
     WebServer(#[from] server::WebServerError),
     #[error("Tokio runtime failed to start: {0}")]
     Tokio(#[from] std::io::Error),
+    #[error("Configuration file error: {0}")]
+    ConfigFile(#[from] text_generation_config_file::ConfigFileError),
 }
 
 #[cfg(test)]
@@ -0,0 +1,84 @@
+/// Empirical search of the max batch total tokens of the models that do not report it
+use std::future::Future;
+
+/// Maximum number of warmup batches sent by the search
+pub(crate) const PROBE_STEPS: u32 = 8;
+/// Batch size of the largest probe when `max_batch_size` is not set
+pub(crate) const PROBE_MAX_BATCH_SIZE: u32 = 128;
+/// Fraction of the largest successful probe kept as budget, decoding allocates more memory than
+/// the prefill of the same number of tokens
+const PROBE_HEADROOM: f32 = 0.9;
+
+/// Binary search of the largest token budget in `[min_tokens, max_tokens]` that `warmup` succeeds
+/// with, in at most `PROBE_STEPS` warmups after the one of `min_tokens`.
+/// Fails if `warmup` fails on `min_tokens`.
+pub(crate) async fn probe_max_batch_total_tokens<F, Fut, E>(
+    min_tokens: u32,
+    max_tokens: u32,
+    mut warmup: F,
+) -> Result<u32, E>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    tracing::info!("Probing max batch total tokens between {min_tokens} and {max_tokens}");
+    warmup(min_tokens).await?;
+
+    // `low` succeeded, `high` failed or is the first value over the range
+    let (mut low, mut high) = (min_tokens, max_tokens.max(min_tokens).saturating_add(1));
+    for _ in 0..PROBE_STEPS {
+        if high - low <= 1 {
+            break;
+        }
+        let tokens = low + (high - low) / 2;
+        match warmup(tokens).await {
+            Ok(()) => {
+                tracing::info!("Warmup with {tokens} tokens succeeded");
+                low = tokens;
+            }
+            Err(_) => {
+                tracing::info!("Warmup with {tokens} tokens failed");
+                high = tokens;
+            }
+        }
+    }
+    let max_batch_total_tokens = ((low as f32 * PROBE_HEADROOM) as u32).max(min_tokens);
+    metrics::gauge!("tgi_batch_probed_total_tokens").set(low as f64);
+    Ok(max_batch_total_tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_max_batch_total_tokens() {
+        let fits = |limit: u32| {
+            move |tokens: u32| async move { (tokens <= limit).then_some(()).ok_or(()) }
+        };
+
+        // Converges to the limit with `PROBE_STEPS` warmups: (102400 - 2048) / 2^8 < 400
+        let budget = probe_max_batch_total_tokens(2048, 102400, fits(50000))
+            .await
+            .unwrap();
+        let limit = (50000.0 * PROBE_HEADROOM) as u32;
+        assert!(budget <= limit && budget + 400 > limit, "{budget}");
+
+        // The whole range fits
+        let budget = probe_max_batch_total_tokens(2048, 4096, fits(u32::MAX))
+            .await
+            .unwrap();
+        assert!(budget + 16 > (4096.0 * PROBE_HEADROOM) as u32, "{budget}");
+
+        // Never below the minimum
+        assert_eq!(
+            probe_max_batch_total_tokens(2048, 4096, fits(2100))
+                .await
+                .unwrap(),
+            2048
+        );
+        assert!(probe_max_batch_total_tokens(2048, 4096, fits(1000))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,225 @@
+/// Envelope encryption of the prompts and outputs written to the logs
+use crate::provenance::sha256_hex;
+use crate::secrets::{SecretError, SecretSource};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Prefix and version of the encrypted log fields
+const ENVELOPE_PREFIX: &str = "enc:v1";
+
+#[derive(Debug, Error)]
+pub enum PromptLogError {
+    #[error("could not read the prompt log key: {0}")]
+    Read(#[from] SecretError),
+    #[error("invalid prompt log key, expected 32 base64 encoded bytes")]
+    InvalidKey,
+    #[error("could not wrap the prompt log data key: {0}")]
+    Wrap(String),
+    #[error("could not unwrap the prompt log data key: {0}")]
+    Unwrap(String),
+    #[error("invalid encrypted log field")]
+    InvalidEnvelope,
+}
+
+/// Holder of the key encryption key, e.g. a KMS client.
+/// The logs only contain data keys wrapped by the provider.
+pub trait KeyProvider: Send + Sync {
+    /// Identifier of the key encryption key, written next to the wrapped data keys
+    fn key_id(&self) -> &str;
+
+    fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>, PromptLogError>;
+
+    fn unwrap(&self, wrapped_key: &[u8]) -> Result<Vec<u8>, PromptLogError>;
+}
+
+/// AES-256-GCM key encryption key, read from a file or an environment variable
+pub struct LocalKeyProvider {
+    key: LessSafeKey,
+    key_id: String,
+}
+
+impl LocalKeyProvider {
+    /// `source` contains the base64 encoded 32 bytes key
+    pub fn new(source: &SecretSource) -> Result<Self, PromptLogError> {
+        let encoded = String::from_utf8(source.read()?).map_err(|_| PromptLogError::InvalidKey)?;
+        let key = STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| PromptLogError::InvalidKey)?;
+        let unbound =
+            UnboundKey::new(&AES_256_GCM, &key).map_err(|_| PromptLogError::InvalidKey)?;
+        Ok(Self {
+            key: LessSafeKey::new(unbound),
+            key_id: format!("local-{}", &sha256_hex(&key)[..16]),
+        })
+    }
+}
+
+impl KeyProvider for LocalKeyProvider {
+    fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>, PromptLogError> {
+        seal(&self.key, &SystemRandom::new(), data_key)
+            .map_err(|_| PromptLogError::Wrap("encryption failed".to_string()))
+    }
+
+    fn unwrap(&self, wrapped_key: &[u8]) -> Result<Vec<u8>, PromptLogError> {
+        open(&self.key, wrapped_key)
+            .map_err(|_| PromptLogError::Unwrap("decryption failed".to_string()))
+    }
+}
+
+/// Encrypt the texts written to the logs with a random data key, wrapped by a [`KeyProvider`]
+/// once at startup. Encrypted fields are self-contained:
+/// `enc:v1:<key id>:<wrapped data key>:<nonce and ciphertext>`, base64 encoded.
+#[derive(Clone)]
+pub struct PromptEncryptor {
+    data_key: Arc<LessSafeKey>,
+    /// `enc:v1:<key id>:<wrapped data key>`
+    header: String,
+    rng: SystemRandom,
+}
+
+impl PromptEncryptor {
+    pub fn new(provider: &dyn KeyProvider) -> Result<Self, PromptLogError> {
+        let rng = SystemRandom::new();
+        let mut data_key = [0u8; 32];
+        rng.fill(&mut data_key)
+            .map_err(|_| PromptLogError::Wrap("could not generate the data key".to_string()))?;
+        let wrapped_key = provider.wrap(&data_key)?;
+        let unbound =
+            UnboundKey::new(&AES_256_GCM, &data_key).map_err(|_| PromptLogError::InvalidKey)?;
+        Ok(Self {
+            data_key: Arc::new(LessSafeKey::new(unbound)),
+            header: format!(
+                "{ENVELOPE_PREFIX}:{}:{}",
+                provider.key_id(),
+                STANDARD.encode(wrapped_key)
+            ),
+            rng,
+        })
+    }
+
+    pub fn encrypt(&self, text: &str) -> String {
+        match seal(&self.data_key, &self.rng, text.as_bytes()) {
+            Ok(sealed) => format!("{}:{}", self.header, STANDARD.encode(sealed)),
+            // Never fall back to the plain text
+            Err(_) => format!("{ENVELOPE_PREFIX}:encryption_failed"),
+        }
+    }
+}
+
+impl std::fmt::Debug for PromptEncryptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PromptEncryptor")
+            .field("header", &self.header)
+            .finish()
+    }
+}
+
+/// Decrypt a field written by [`PromptEncryptor::encrypt`]
+pub fn decrypt(provider: &dyn KeyProvider, field: &str) -> Result<String, PromptLogError> {
+    let mut parts = field
+        .strip_prefix(ENVELOPE_PREFIX)
+        .and_then(|field| field.strip_prefix(':'))
+        .ok_or(PromptLogError::InvalidEnvelope)?
+        .split(':');
+    let (Some(key_id), Some(wrapped_key), Some(sealed), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(PromptLogError::InvalidEnvelope);
+    };
+    if key_id != provider.key_id() {
+        return Err(PromptLogError::Unwrap(format!("unknown key `{key_id}`")));
+    }
+    let decode = |value: &str| {
+        STANDARD
+            .decode(value)
+            .map_err(|_| PromptLogError::InvalidEnvelope)
+    };
+    let data_key = provider.unwrap(&decode(wrapped_key)?)?;
+    let unbound = UnboundKey::new(&AES_256_GCM, &data_key)
+        .map_err(|_| PromptLogError::Unwrap("invalid data key".to_string()))?;
+    let text = open(&LessSafeKey::new(unbound), &decode(sealed)?)
+        .map_err(|_| PromptLogError::InvalidEnvelope)?;
+    String::from_utf8(text).map_err(|_| PromptLogError::InvalidEnvelope)
+}
+
+/// Encrypt `plaintext` with a random nonce, returns the nonce followed by the ciphertext
+fn seal(
+    key: &LessSafeKey,
+    rng: &SystemRandom,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, ring::error::Unspecified> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce)?;
+    let mut sealed = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::empty(),
+        &mut sealed,
+    )?;
+    let mut output = nonce.to_vec();
+    output.append(&mut sealed);
+    Ok(output)
+}
+
+fn open(key: &LessSafeKey, sealed: &[u8]) -> Result<Vec<u8>, ring::error::Unspecified> {
+    if sealed.len() < NONCE_LEN {
+        return Err(ring::error::Unspecified);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)?;
+    let mut plaintext = ciphertext.to_vec();
+    let length = key
+        .open_in_place(nonce, Aad::empty(), &mut plaintext)?
+        .len();
+    plaintext.truncate(length);
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_encryption() {
+        std::env::set_var("TGI_TEST_PROMPT_LOG_KEY", STANDARD.encode([7u8; 32]));
+        let provider =
+            LocalKeyProvider::new(&SecretSource::parse("env:TGI_TEST_PROMPT_LOG_KEY")).unwrap();
+        let encryptor = PromptEncryptor::new(&provider).unwrap();
+
+        let field = encryptor.encrypt("What is Deep Learning?");
+        assert!(field.starts_with(&format!("enc:v1:{}:", provider.key_id())));
+        assert!(!field.contains("Deep Learning"));
+        assert_eq!(
+            decrypt(&provider, &field).unwrap(),
+            "What is Deep Learning?"
+        );
+        // Every field has its own nonce
+        assert_ne!(encryptor.encrypt("Hello"), encryptor.encrypt("Hello"));
+
+        let mut tampered = field.clone();
+        tampered.pop();
+        tampered.push(if field.ends_with('A') { 'B' } else { 'A' });
+        assert!(decrypt(&provider, &tampered).is_err());
+
+        std::env::set_var("TGI_TEST_PROMPT_LOG_KEY", STANDARD.encode([8u8; 32]));
+        let other =
+            LocalKeyProvider::new(&SecretSource::parse("env:TGI_TEST_PROMPT_LOG_KEY")).unwrap();
+        assert!(matches!(
+            decrypt(&other, &field),
+            Err(PromptLogError::Unwrap(_))
+        ));
+
+        std::env::set_var("TGI_TEST_PROMPT_LOG_KEY", "too short");
+        assert!(matches!(
+            LocalKeyProvider::new(&SecretSource::parse("env:TGI_TEST_PROMPT_LOG_KEY")),
+            Err(PromptLogError::InvalidKey)
+        ));
+    }
+}
@@ -0,0 +1,137 @@
+/// Signed provenance of the generated texts
+use crate::{GenerateParameters, Provenance};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::digest::{digest, SHA256};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ProvenanceError {
+    #[error("could not read the provenance signing key: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid provenance signing key, expected an Ed25519 PKCS#8 DER document: {0}")]
+    Key(String),
+}
+
+/// Sign the model sha, the parameters and the output of the requests with an Ed25519 key
+#[derive(Clone)]
+pub(crate) struct ProvenanceSigner {
+    key_pair: Arc<Ed25519KeyPair>,
+    model_sha: String,
+}
+
+impl ProvenanceSigner {
+    pub(crate) fn new(pkcs8: &[u8], model_sha: String) -> Result<Self, ProvenanceError> {
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+            .map_err(|err| ProvenanceError::Key(err.to_string()))?;
+        Ok(Self {
+            key_pair: Arc::new(key_pair),
+            model_sha,
+        })
+    }
+
+    pub(crate) fn from_file(path: &str, model_sha: String) -> Result<Self, ProvenanceError> {
+        Self::new(&std::fs::read(path)?, model_sha)
+    }
+
+    /// Base64 encoded public key, used to verify the signatures
+    pub(crate) fn public_key(&self) -> String {
+        STANDARD.encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Sign the provenance of `output`, generated with the parameters hashed by [`hash_parameters`]
+    pub(crate) fn sign(&self, parameters_hash: String, output: &str) -> Provenance {
+        let output_hash = sha256_hex(output.as_bytes());
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let message = signed_message(&self.model_sha, &parameters_hash, &output_hash, timestamp);
+        let signature = STANDARD.encode(self.key_pair.sign(message.as_bytes()).as_ref());
+        Provenance {
+            model_sha: self.model_sha.clone(),
+            parameters_hash,
+            output_hash,
+            timestamp,
+            signature,
+        }
+    }
+}
+
+impl std::fmt::Debug for ProvenanceSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProvenanceSigner")
+            .field("public_key", &self.public_key())
+            .field("model_sha", &self.model_sha)
+            .finish()
+    }
+}
+
+/// SHA-256 of the JSON serialization of the request parameters
+pub(crate) fn hash_parameters(parameters: &GenerateParameters) -> String {
+    // Serializing plain data to a Vec cannot fail
+    sha256_hex(&serde_json::to_vec(parameters).unwrap_or_default())
+}
+
+/// Message covered by the signature: the provenance fields, one per line
+fn signed_message(
+    model_sha: &str,
+    parameters_hash: &str,
+    output_hash: &str,
+    timestamp: u64,
+) -> String {
+    format!("{model_sha}\n{parameters_hash}\n{output_hash}\n{timestamp}")
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    hex(digest(&SHA256, data).as_ref())
+}
+
+/// Lowercase hexadecimal encoding of `bytes`
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(2 * bytes.len()), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    #[test]
+    fn test_provenance_signature() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let signer = ProvenanceSigner::new(pkcs8.as_ref(), "abc123".to_string()).unwrap();
+        let parameters_hash = hash_parameters(&GenerateParameters::default());
+        let provenance = signer.sign(parameters_hash.clone(), "Hello");
+
+        assert_eq!(provenance.model_sha, "abc123");
+        assert_eq!(provenance.parameters_hash, parameters_hash);
+        assert_eq!(
+            provenance.output_hash,
+            "185f8db32271fe25f561a6fc938b2e264306ec304eda518007d1764826381969"
+        );
+
+        let public_key = STANDARD.decode(signer.public_key()).unwrap();
+        let signature = STANDARD.decode(&provenance.signature).unwrap();
+        let message = signed_message(
+            &provenance.model_sha,
+            &provenance.parameters_hash,
+            &provenance.output_hash,
+            provenance.timestamp,
+        );
+        let key = UnparsedPublicKey::new(&ED25519, public_key);
+        assert!(key.verify(message.as_bytes(), &signature).is_ok());
+        assert!(key.verify(b"tampered", &signature).is_err());
+
+        assert!(ProvenanceSigner::new(b"not a key", String::new()).is_err());
+    }
+}
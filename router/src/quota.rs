@@ -0,0 +1,519 @@
+/// Per-key token quotas over daily and monthly windows
+use crate::auth::auth_context;
+use crate::{QuotaUsage, QuotaWindowUsage};
+use axum::extract::{Request, State};
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Interval between two writes of the usage to the store
+const USAGE_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Token quotas of a key, `None` means unlimited
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct QuotaLimits {
+    pub tokens_per_day: Option<u64>,
+    pub tokens_per_month: Option<u64>,
+}
+
+/// Quota configuration file
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct QuotaConfig {
+    /// Quotas of the keys missing from `keys`
+    #[serde(default)]
+    pub default: QuotaLimits,
+    #[serde(default)]
+    pub keys: HashMap<String, QuotaLimits>,
+}
+
+impl QuotaConfig {
+    fn limits(&self, key: &str) -> &QuotaLimits {
+        self.keys.get(key).unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum QuotaConfigError {
+    #[error("could not read the quota configuration or usage: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid quota configuration: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum QuotaError {
+    #[error("daily quota of {0} tokens exceeded, resets at {1} (unix time)")]
+    Daily(u64, u64),
+    #[error("monthly quota of {0} tokens exceeded, resets at {1} (unix time)")]
+    Monthly(u64, u64),
+}
+
+/// Tokens used by a key in its last day and month windows
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct KeyUsage {
+    /// Days since the unix epoch
+    pub day: u64,
+    pub day_tokens: u64,
+    /// Months since January 1970
+    pub month: u64,
+    pub month_tokens: u64,
+}
+
+impl KeyUsage {
+    /// Reset the counters of the windows that ended
+    fn roll(&mut self, windows: &Windows) {
+        if self.day != windows.day {
+            self.day = windows.day;
+            self.day_tokens = 0;
+        }
+        if self.month != windows.month {
+            self.month = windows.month;
+            self.month_tokens = 0;
+        }
+    }
+}
+
+/// Persists the usage across restarts. Implement it to keep the usage in an external store.
+pub trait UsageStore: Send + Sync {
+    fn load(&self) -> std::io::Result<HashMap<String, KeyUsage>>;
+    fn save(&self, usage: &HashMap<String, KeyUsage>) -> std::io::Result<()>;
+}
+
+/// Usage kept in a local JSON file
+#[derive(Debug)]
+pub struct FileUsageStore {
+    path: String,
+}
+
+impl FileUsageStore {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+}
+
+impl UsageStore for FileUsageStore {
+    fn load(&self) -> std::io::Result<HashMap<String, KeyUsage>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, usage: &HashMap<String, KeyUsage>) -> std::io::Result<()> {
+        // Write then rename, so a crash never leaves a truncated file
+        let tmp = format!("{}.tmp", self.path);
+        std::fs::write(&tmp, serde_json::to_vec(usage)?)?;
+        std::fs::rename(tmp, &self.path)
+    }
+}
+
+/// Index and end of the current day and month
+#[derive(Debug, PartialEq)]
+struct Windows {
+    day: u64,
+    day_reset: u64,
+    month: u64,
+    month_reset: u64,
+}
+
+impl Windows {
+    fn at(now: u64) -> Self {
+        let day = now / SECONDS_PER_DAY;
+        let (year, month) = civil_from_days(day as i64);
+        let (next_year, next_month) = match month {
+            12 => (year + 1, 1),
+            month => (year, month + 1),
+        };
+        Self {
+            day,
+            day_reset: (day + 1) * SECONDS_PER_DAY,
+            month: ((year - 1970) * 12 + month as i64 - 1) as u64,
+            month_reset: days_from_civil(next_year, next_month) as u64 * SECONDS_PER_DAY,
+        }
+    }
+}
+
+/// Year and month (1-12) of a number of days since the unix epoch
+fn civil_from_days(days: i64) -> (i64, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // Months starting from March
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32)
+}
+
+/// Days since the unix epoch of the first day of a month
+fn days_from_civil(year: i64, month: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Token usage and quotas, keyed by the caller tenant or subject
+#[derive(Clone)]
+pub(crate) struct QuotaTracker {
+    config: Arc<QuotaConfig>,
+    usage: Arc<Mutex<HashMap<String, KeyUsage>>>,
+    /// Tokens reserved by the requests in flight, locked after `usage`
+    reserved: Arc<Mutex<HashMap<String, u64>>>,
+    store: Arc<dyn UsageStore>,
+    /// Whether the usage changed since the last flush
+    dirty: Arc<AtomicBool>,
+}
+
+impl QuotaTracker {
+    pub(crate) fn new(
+        config: QuotaConfig,
+        store: Arc<dyn UsageStore>,
+    ) -> Result<Self, QuotaConfigError> {
+        let usage = store.load()?;
+        Ok(Self {
+            config: Arc::new(config),
+            usage: Arc::new(Mutex::new(usage)),
+            reserved: Arc::new(Mutex::new(HashMap::new())),
+            store,
+            dirty: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Load the configuration from `path` and flush the usage to `store` periodically
+    pub(crate) fn spawn(path: &str, store: Arc<dyn UsageStore>) -> Result<Self, QuotaConfigError> {
+        let config = serde_json::from_slice(&std::fs::read(path)?)?;
+        let tracker = Self::new(config, store)?;
+        tokio::spawn(tracker.clone().flush_task());
+        Ok(tracker)
+    }
+
+    async fn flush_task(self) {
+        let mut ticker = tokio::time::interval(USAGE_FLUSH_INTERVAL);
+        loop {
+            ticker.tick().await;
+            self.flush();
+        }
+    }
+
+    /// Write the usage to the store if it changed
+    pub(crate) fn flush(&self) {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let usage = self.usage.lock().unwrap().clone();
+        if let Err(err) = self.store.save(&usage) {
+            tracing::warn!("Could not save the quota usage: {err}");
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Reject the requests of `key` once one of its quotas is exhausted, counting the tokens
+    /// reserved by its requests in flight
+    pub(crate) fn check(&self, key: &str, now: u64) -> Result<(), QuotaError> {
+        self.check_tokens(key, 0, now)
+    }
+
+    /// Reserve the `tokens` a request may use, rejected if they do not fit in the quotas of `key`.
+    /// The reservation is replaced by the actual usage of the request when it is dropped.
+    pub(crate) fn reserve(
+        &self,
+        key: &str,
+        tokens: u64,
+        input_tokens: u64,
+        now: u64,
+    ) -> Result<QuotaReservation, QuotaError> {
+        self.check_tokens(key, tokens, now)?;
+        Ok(QuotaReservation {
+            tracker: self.clone(),
+            key: key.to_string(),
+            reserved: tokens,
+            input_tokens,
+            started: false,
+            generated_tokens: 0,
+        })
+    }
+
+    /// Check that `tokens` more fit in the quotas of `key`, and reserve them
+    fn check_tokens(&self, key: &str, tokens: u64, now: u64) -> Result<(), QuotaError> {
+        let limits = self.config.limits(key);
+        let windows = Windows::at(now);
+        let usage = self.usage.lock().unwrap();
+        let mut key_usage = usage.get(key).cloned().unwrap_or_default();
+        key_usage.roll(&windows);
+        let mut reserved = self.reserved.lock().unwrap();
+        let pending = reserved.get(key).copied().unwrap_or_default();
+
+        // Without new tokens, reject once the quota is exhausted
+        let exceeds = |used: u64, limit: u64| match tokens {
+            0 => used + pending >= limit,
+            tokens => used + pending + tokens > limit,
+        };
+        if let Some(limit) = limits.tokens_per_day {
+            if exceeds(key_usage.day_tokens, limit) {
+                return Err(QuotaError::Daily(limit, windows.day_reset));
+            }
+        }
+        if let Some(limit) = limits.tokens_per_month {
+            if exceeds(key_usage.month_tokens, limit) {
+                return Err(QuotaError::Monthly(limit, windows.month_reset));
+            }
+        }
+        if tokens > 0 {
+            *reserved.entry(key.to_string()).or_default() += tokens;
+        }
+        Ok(())
+    }
+
+    /// Replace the `reserved` tokens of a request of `key` by the tokens it `used`
+    fn settle(&self, key: &str, reserved: u64, used: u64, now: u64) {
+        self.record(key, used, now);
+        let mut reservations = self.reserved.lock().unwrap();
+        if let Some(pending) = reservations.get_mut(key) {
+            *pending = pending.saturating_sub(reserved);
+            if *pending == 0 {
+                reservations.remove(key);
+            }
+        }
+    }
+
+    /// Add the tokens of a request to the usage of `key`
+    fn record(&self, key: &str, tokens: u64, now: u64) {
+        let windows = Windows::at(now);
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(key.to_string()).or_default();
+        usage.roll(&windows);
+        usage.day_tokens += tokens;
+        usage.month_tokens += tokens;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Usage and remaining quota of `key`
+    pub(crate) fn usage(&self, key: &str, now: u64) -> QuotaUsage {
+        let limits = self.config.limits(key);
+        let windows = Windows::at(now);
+        let mut usage = self
+            .usage
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_default();
+        usage.roll(&windows);
+
+        let window = |limit: Option<u64>, used: u64, reset: u64| QuotaWindowUsage {
+            limit,
+            used,
+            remaining: limit.map(|limit| limit.saturating_sub(used)),
+            reset,
+        };
+        QuotaUsage {
+            key: key.to_string(),
+            daily: window(limits.tokens_per_day, usage.day_tokens, windows.day_reset),
+            monthly: window(
+                limits.tokens_per_month,
+                usage.month_tokens,
+                windows.month_reset,
+            ),
+        }
+    }
+}
+
+/// Tokens reserved by an admitted request. Dropped when the request ends, completed, failed or
+/// cancelled by the client, it charges the tokens actually used.
+pub(crate) struct QuotaReservation {
+    tracker: QuotaTracker,
+    key: String,
+    reserved: u64,
+    input_tokens: u64,
+    /// Whether the shards started the generation, the input tokens are only charged then
+    started: bool,
+    generated_tokens: u64,
+}
+
+impl QuotaReservation {
+    /// Charge a response of the shards, a generated token or not
+    pub(crate) fn charge(&mut self, generated_token: bool) {
+        self.started = true;
+        self.generated_tokens += u64::from(generated_token);
+    }
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        let input_tokens = if self.started { self.input_tokens } else { 0 };
+        let used = input_tokens + self.generated_tokens;
+        self.tracker
+            .settle(&self.key, self.reserved, used, unix_now());
+    }
+}
+
+/// Add the remaining quota of the caller to the response headers
+pub(crate) async fn quota_middleware(
+    State(tracker): State<QuotaTracker>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let usage = tracker.usage(&auth_context().key(), unix_now());
+    let headers = response.headers_mut();
+    let windows = [
+        (
+            usage.daily,
+            [
+                "x-quota-limit-tokens-day",
+                "x-quota-remaining-tokens-day",
+                "x-quota-reset-tokens-day",
+            ],
+        ),
+        (
+            usage.monthly,
+            [
+                "x-quota-limit-tokens-month",
+                "x-quota-remaining-tokens-month",
+                "x-quota-reset-tokens-month",
+            ],
+        ),
+    ];
+    for (usage, [limit_header, remaining_header, reset_header]) in windows {
+        if let (Some(limit), Some(remaining)) = (usage.limit, usage.remaining) {
+            headers.insert(limit_header, HeaderValue::from(limit));
+            headers.insert(remaining_header, HeaderValue::from(remaining));
+            headers.insert(reset_header, HeaderValue::from(usage.reset));
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<HashMap<String, KeyUsage>>);
+
+    impl UsageStore for MemoryStore {
+        fn load(&self) -> std::io::Result<HashMap<String, KeyUsage>> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn save(&self, usage: &HashMap<String, KeyUsage>) -> std::io::Result<()> {
+            self.0.lock().unwrap().clone_from(usage);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_windows() {
+        // 2024-02-29T12:00:00Z
+        let windows = Windows::at(1_709_208_000);
+        assert_eq!(windows.day, 19_782);
+        assert_eq!(windows.day_reset, 1_709_251_200);
+        assert_eq!(windows.month, 54 * 12 + 1);
+        // 2024-03-01T00:00:00Z
+        assert_eq!(windows.month_reset, 1_709_251_200);
+
+        // 2023-12-15T00:00:00Z
+        let windows = Windows::at(1_702_598_400);
+        // 2024-01-01T00:00:00Z
+        assert_eq!(windows.month_reset, 1_704_067_200);
+    }
+
+    #[test]
+    fn test_quota_tracker() {
+        let store = Arc::new(MemoryStore::default());
+        let config: QuotaConfig = serde_json::from_str(
+            r#"{"default": {"tokens_per_day": 100}, "keys": {"acme": {"tokens_per_month": 150}}}"#,
+        )
+        .unwrap();
+        let tracker = QuotaTracker::new(config.clone(), store.clone()).unwrap();
+        // 2024-02-10T12:00:00Z
+        let now = 1_707_566_400;
+
+        tracker.record("user", 100, now);
+        assert_eq!(
+            tracker.check("user", now),
+            Err(QuotaError::Daily(100, 1_707_609_600))
+        );
+        // The next day
+        assert!(tracker.check("user", now + SECONDS_PER_DAY).is_ok());
+
+        tracker.record("acme", 100, now);
+        assert!(tracker.check("acme", now).is_ok());
+        tracker.record("acme", 100, now + SECONDS_PER_DAY);
+        assert_eq!(
+            tracker.check("acme", now + SECONDS_PER_DAY),
+            Err(QuotaError::Monthly(150, 1_709_251_200))
+        );
+        let usage = tracker.usage("acme", now + SECONDS_PER_DAY);
+        assert_eq!(
+            usage.monthly,
+            QuotaWindowUsage {
+                limit: Some(150),
+                used: 200,
+                remaining: Some(0),
+                reset: 1_709_251_200,
+            }
+        );
+        assert_eq!(usage.daily.limit, None);
+
+        // The usage survives a restart
+        tracker.flush();
+        let tracker = QuotaTracker::new(config, store).unwrap();
+        assert!(tracker.check("user", now).is_err());
+    }
+
+    #[test]
+    fn test_quota_reservation() {
+        let config: QuotaConfig =
+            serde_json::from_str(r#"{"default": {"tokens_per_day": 100}}"#).unwrap();
+        let tracker = QuotaTracker::new(config, Arc::new(MemoryStore::default())).unwrap();
+        let now = unix_now();
+
+        // Concurrent requests cannot overshoot the quota
+        let mut first = tracker.reserve("user", 60, 10, now).unwrap();
+        assert!(tracker.reserve("user", 60, 10, now).is_err());
+        let mut second = tracker.reserve("user", 40, 10, now).unwrap();
+        assert!(tracker.check("user", now).is_err());
+
+        // Settled to the tokens used, also when the request is cancelled
+        first.charge(true);
+        first.charge(true);
+        drop(first);
+        assert_eq!(tracker.usage("user", now).daily.used, 12);
+        second.charge(false);
+        drop(second);
+        assert_eq!(tracker.usage("user", now).daily.used, 22);
+
+        // Requests cancelled before their generation starts are not charged
+        assert!(tracker.reserve("user", 78, 10, now).is_ok());
+        assert_eq!(tracker.usage("user", now).daily.used, 22);
+        assert!(tracker.reserve("user", 79, 10, now).is_err());
+    }
+}
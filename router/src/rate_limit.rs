@@ -0,0 +1,282 @@
+/// Per-key request and token rate limiting
+use crate::auth::auth_context;
+use axum::extract::{Request, State};
+use axum::http::HeaderValue;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+/// Interval between two checks of the configuration file modification time
+const CONFIG_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Key of the callers without identity
+pub(crate) const ANONYMOUS_KEY: &str = "anonymous";
+
+/// Limits of a key, `None` means unlimited
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct Limits {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+
+/// Rate limiting configuration file
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct RateLimitConfig {
+    /// Limits of the keys missing from `keys`
+    #[serde(default)]
+    pub default: Limits,
+    #[serde(default)]
+    pub keys: HashMap<String, Limits>,
+}
+
+impl RateLimitConfig {
+    fn limits(&self, key: &str) -> &Limits {
+        self.keys.get(key).unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RateLimitConfigError {
+    #[error("could not read the rate limit configuration: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid rate limit configuration: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RateLimitError {
+    #[error("requests per minute limit exceeded, retry after {0:?}")]
+    Requests(Duration),
+    #[error("tokens per minute limit exceeded, retry after {0:?}")]
+    Tokens(Duration),
+    #[error("request needs {0} tokens but the limit is {1} tokens per minute")]
+    TooManyTokens(u64, u32),
+}
+
+/// Token bucket refilled with `capacity` tokens per minute
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: u32, now: Instant) -> Self {
+        Self {
+            capacity: capacity as f64,
+            available: capacity as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, capacity: u32, now: Instant) {
+        self.capacity = capacity as f64;
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated_at = now;
+    }
+
+    /// Time until `amount` tokens are available
+    fn wait_time(&self, amount: f64) -> Duration {
+        let missing = (amount - self.available).max(0.0);
+        Duration::from_secs_f64(missing * 60.0 / self.capacity.max(1.0))
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyBuckets {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+/// Refill `bucket` or create it if the key became limited, drop it if it is now unlimited
+fn update_bucket(bucket: &mut Option<Bucket>, capacity: Option<u32>, now: Instant) {
+    match (bucket.as_mut(), capacity) {
+        (_, None) => *bucket = None,
+        (None, Some(capacity)) => *bucket = Some(Bucket::new(capacity, now)),
+        (Some(bucket), Some(capacity)) => bucket.refill(capacity, now),
+    }
+}
+
+/// Quota of a key, returned in the response headers
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Quota {
+    pub requests: Option<(u32, u32)>,
+    pub tokens: Option<(u32, u32)>,
+}
+
+/// Token bucket rate limiter, keyed by the caller tenant or subject
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimiter {
+    config: Arc<Mutex<RateLimitConfig>>,
+    buckets: Arc<Mutex<HashMap<String, KeyBuckets>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: Arc::new(Mutex::new(config)),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Load the configuration from `path` and reload it whenever the file changes
+    pub(crate) fn spawn(path: String) -> Result<Self, RateLimitConfigError> {
+        let (config, modified) = load_config(&path)?;
+        let limiter = Self::new(config);
+        tokio::spawn(limiter.clone().reload_task(path, modified));
+        Ok(limiter)
+    }
+
+    async fn reload_task(self, path: String, mut modified: SystemTime) {
+        let mut ticker = tokio::time::interval(CONFIG_RELOAD_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match std::fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                Ok(time) if time == modified => continue,
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::warn!("Could not stat the rate limit configuration: {err}");
+                    continue;
+                }
+            }
+            match load_config(&path) {
+                Ok((config, time)) => {
+                    modified = time;
+                    *self.config.lock().unwrap() = config;
+                    tracing::info!("Reloaded the rate limit configuration");
+                }
+                Err(err) => tracing::warn!("Keeping the previous rate limits: {err}"),
+            }
+        }
+    }
+
+    /// Admit a request of `tokens` tokens for `key`, consuming its quota
+    pub(crate) fn admit(&self, key: &str, tokens: u64) -> Result<(), RateLimitError> {
+        let limits = self.config.lock().unwrap().limits(key).clone();
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = buckets.entry(key.to_string()).or_default();
+        update_bucket(&mut buckets.requests, limits.requests_per_minute, now);
+        update_bucket(&mut buckets.tokens, limits.tokens_per_minute, now);
+
+        if let Some(bucket) = &buckets.requests {
+            if bucket.available < 1.0 {
+                return Err(RateLimitError::Requests(bucket.wait_time(1.0)));
+            }
+        }
+        if let (Some(bucket), Some(capacity)) = (&buckets.tokens, limits.tokens_per_minute) {
+            if tokens > capacity as u64 {
+                return Err(RateLimitError::TooManyTokens(tokens, capacity));
+            }
+            if bucket.available < tokens as f64 {
+                return Err(RateLimitError::Tokens(bucket.wait_time(tokens as f64)));
+            }
+        }
+
+        if let Some(bucket) = &mut buckets.requests {
+            bucket.available -= 1.0;
+        }
+        if let Some(bucket) = &mut buckets.tokens {
+            bucket.available -= tokens as f64;
+        }
+        Ok(())
+    }
+
+    /// Limits and remaining quota of `key`
+    pub(crate) fn quota(&self, key: &str) -> Quota {
+        let limits = self.config.lock().unwrap().limits(key).clone();
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = buckets.entry(key.to_string()).or_default();
+        update_bucket(&mut buckets.requests, limits.requests_per_minute, now);
+        update_bucket(&mut buckets.tokens, limits.tokens_per_minute, now);
+
+        let remaining = |bucket: &Option<Bucket>| bucket.as_ref().map(|b| b.available as u32);
+        Quota {
+            requests: limits.requests_per_minute.zip(remaining(&buckets.requests)),
+            tokens: limits.tokens_per_minute.zip(remaining(&buckets.tokens)),
+        }
+    }
+}
+
+fn load_config(path: &str) -> Result<(RateLimitConfig, SystemTime), RateLimitConfigError> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    let config = serde_json::from_slice(&std::fs::read(path)?)?;
+    Ok((config, modified))
+}
+
+/// Add the quota of the caller to the response headers
+pub(crate) async fn rate_limit_middleware(
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let quota = limiter.quota(&auth_context().key());
+    let headers = response.headers_mut();
+    if let Some((limit, remaining)) = quota.requests {
+        headers.insert("x-ratelimit-limit-requests", HeaderValue::from(limit));
+        headers.insert(
+            "x-ratelimit-remaining-requests",
+            HeaderValue::from(remaining),
+        );
+    }
+    if let Some((limit, remaining)) = quota.tokens {
+        headers.insert("x-ratelimit-limit-tokens", HeaderValue::from(limit));
+        headers.insert("x-ratelimit-remaining-tokens", HeaderValue::from(remaining));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            default: Limits {
+                requests_per_minute: Some(2),
+                tokens_per_minute: None,
+            },
+            keys: HashMap::from([(
+                "acme".to_string(),
+                Limits {
+                    requests_per_minute: None,
+                    tokens_per_minute: Some(100),
+                },
+            )]),
+        });
+
+        assert!(limiter.admit("user", 1000).is_ok());
+        assert!(limiter.admit("user", 1000).is_ok());
+        assert!(matches!(
+            limiter.admit("user", 1),
+            Err(RateLimitError::Requests(_))
+        ));
+        assert_eq!(
+            limiter.quota("user"),
+            Quota {
+                requests: Some((2, 0)),
+                tokens: None
+            }
+        );
+
+        assert!(limiter.admit("acme", 60).is_ok());
+        assert!(matches!(
+            limiter.admit("acme", 60),
+            Err(RateLimitError::Tokens(_))
+        ));
+        assert_eq!(
+            limiter.admit("acme", 200),
+            Err(RateLimitError::TooManyTokens(200, 100))
+        );
+        assert!(limiter.admit("acme", 40).is_ok());
+    }
+}
@@ -0,0 +1,182 @@
+/// Credentials loaded from files or environment variables, rotated without restart
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Interval between two reads of the credential files
+pub(crate) const SECRET_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("could not read {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("environment variable {0} is not set")]
+    MissingEnv(String),
+}
+
+/// Location of a credential: `env:NAME` reads the environment variable `NAME`,
+/// anything else is a file path. Files are re-read periodically, variables only at startup.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SecretSource {
+    File(String),
+    Env(String),
+}
+
+impl SecretSource {
+    pub fn parse(value: &str) -> Self {
+        match value.strip_prefix("env:") {
+            Some(name) => SecretSource::Env(name.to_string()),
+            None => SecretSource::File(value.to_string()),
+        }
+    }
+
+    pub(crate) fn read(&self) -> Result<Vec<u8>, SecretError> {
+        match self {
+            SecretSource::File(path) => {
+                std::fs::read(path).map_err(|err| SecretError::Io(path.clone(), err))
+            }
+            SecretSource::Env(name) => std::env::var(name)
+                .map(String::into_bytes)
+                .map_err(|_| SecretError::MissingEnv(name.clone())),
+        }
+    }
+
+    /// Whether the credential can change while the router runs
+    pub(crate) fn reloadable(&self) -> bool {
+        matches!(self, SecretSource::File(_))
+    }
+}
+
+/// Keys by id. Replaced or removed values stay valid during `rotation_window`,
+/// so clients still using the previous credentials are not rejected while they roll over.
+#[derive(Debug)]
+pub(crate) struct RotatingKeys<V> {
+    current: HashMap<String, V>,
+    /// Previous values and when they stop being valid
+    previous: Vec<(String, V, Instant)>,
+    rotation_window: Duration,
+}
+
+impl<V: PartialEq> RotatingKeys<V> {
+    pub(crate) fn new(current: HashMap<String, V>, rotation_window: Duration) -> Self {
+        Self {
+            current,
+            previous: Vec::new(),
+            rotation_window,
+        }
+    }
+
+    /// Replace the keys, the values that changed are kept until the end of the rotation window
+    pub(crate) fn update(&mut self, keys: HashMap<String, V>, now: Instant) {
+        let expiration = now + self.rotation_window;
+        let previous = std::mem::replace(&mut self.current, keys);
+        self.previous.retain(|(_, _, expires_at)| *expires_at > now);
+        for (id, value) in previous {
+            if self.current.get(&id) != Some(&value) {
+                self.previous.push((id, value, expiration));
+            }
+        }
+    }
+
+    /// Valid values of `id`, the current one first
+    pub(crate) fn get<'a>(&'a self, id: &'a str, now: Instant) -> impl Iterator<Item = &'a V> {
+        self.current.get(id).into_iter().chain(
+            self.previous
+                .iter()
+                .filter(move |(previous_id, _, expires_at)| previous_id == id && *expires_at > now)
+                .map(|(_, value, _)| value),
+        )
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.current.len()
+    }
+}
+
+/// Re-read `source` every `SECRET_RELOAD_INTERVAL` and rotate `keys` when its content changes.
+/// Invalid content is logged and the current keys are kept.
+pub(crate) async fn reload_task<V, F>(
+    name: &'static str,
+    metric_label: &'static str,
+    source: SecretSource,
+    mut raw_keys: Vec<u8>,
+    keys: Arc<RwLock<RotatingKeys<V>>>,
+    parse: F,
+) where
+    V: PartialEq,
+    F: Fn(&[u8]) -> Result<HashMap<String, V>, serde_json::Error>,
+{
+    let mut ticker = tokio::time::interval(SECRET_RELOAD_INTERVAL);
+    // The first tick completes immediately
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let new_raw_keys = match source.read() {
+            Ok(new_raw_keys) if new_raw_keys == raw_keys => continue,
+            Ok(new_raw_keys) => new_raw_keys,
+            Err(err) => {
+                tracing::warn!("Could not reload the {name}: {err}");
+                continue;
+            }
+        };
+        match parse(&new_raw_keys) {
+            Ok(parsed) => {
+                let mut keys = keys.write().unwrap();
+                keys.update(parsed, Instant::now());
+                tracing::info!("Reloaded {} {name}", keys.len());
+                metrics::counter!("tgi_auth_secret_reload", "secret" => metric_label).increment(1);
+                raw_keys = new_raw_keys;
+            }
+            Err(err) => tracing::warn!("Invalid {name}, keeping the previous ones: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_source() {
+        assert_eq!(
+            SecretSource::parse("env:TGI_KEYS"),
+            SecretSource::Env("TGI_KEYS".to_string())
+        );
+        assert_eq!(
+            SecretSource::parse("/etc/tgi/keys.json"),
+            SecretSource::File("/etc/tgi/keys.json".to_string())
+        );
+        assert!(matches!(
+            SecretSource::Env("TGI_MISSING_SECRET".to_string()).read(),
+            Err(SecretError::MissingEnv(_))
+        ));
+    }
+
+    #[test]
+    fn test_rotating_keys() {
+        let now = Instant::now();
+        let mut keys = RotatingKeys::new(
+            HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]),
+            Duration::from_secs(60),
+        );
+        let values = |keys: &RotatingKeys<i32>, id: &str, now: Instant| {
+            keys.get(id, now).copied().collect::<Vec<_>>()
+        };
+
+        // `a` is rotated and `b` is removed
+        keys.update(HashMap::from([("a".to_string(), 3)]), now);
+        assert_eq!(keys.len(), 1);
+        assert_eq!(values(&keys, "a", now), vec![3, 1]);
+        assert_eq!(values(&keys, "b", now), vec![2]);
+
+        // Unchanged keys do not extend the window
+        let later = now + Duration::from_secs(30);
+        keys.update(HashMap::from([("a".to_string(), 3)]), later);
+        assert_eq!(values(&keys, "a", later), vec![3, 1]);
+
+        let expired = now + Duration::from_secs(61);
+        assert_eq!(values(&keys, "a", expired), vec![3]);
+        assert!(values(&keys, "b", expired).is_empty());
+    }
+}
@@ -1,20 +1,47 @@
 /// HTTP Server logic
-use crate::config::Config;
+use crate::adapter_acl::{AdapterAcl, AdapterAclError};
+use crate::audit::admin_audit_middleware;
+use crate::auth::{
+    auth_context, auth_middleware, scope_auth_context, ApiKeyConfig, ApiKeyVerifier, ApiKeysError,
+    Authenticator, HmacConfig, HmacKeysError, HmacVerifier, JwtConfig, JwtValidator,
+};
+use crate::config::{Config, MultimodalLimits, MultimodalLimitsError};
+use crate::cors::{cors_middleware, CorsConfigError, CorsPolicies};
+use crate::default_parameters::{DefaultParameters, DefaultParametersError};
+use crate::events::{Events, LifecycleEvent};
+use crate::fetch::FetchPolicy;
+use crate::fsm_cache::{FsmCacheError, FsmDiskCache};
+use crate::grammar_registry::{GrammarRegistry, GrammarRegistryError};
 use crate::infer::v2::SchedulerV2;
-use crate::infer::v3::SchedulerV3;
-use crate::infer::{HealthCheck, Scheduler};
-use crate::infer::{Infer, InferError, InferResponse, InferStreamResponse, ToolGrammar};
+use crate::infer::v3::{shard_adapters_task, shard_stats_task, SchedulerV3};
+use crate::infer::{Canary, HealthCheck, Scheduler};
+use crate::infer::{
+    ChatTemplate, Infer, InferError, InferResponse, InferStreamResponse, StreamLimits, ToolGrammar,
+};
+use crate::input_policy::{InputPolicies, InputPolicyError};
+use crate::ip_filter::{ip_filter_middleware, IpFilter, IpFilterConfig};
 #[cfg(feature = "kserve")]
 use crate::kserve::{
     kerve_server_metadata, kserve_health_live, kserve_health_ready, kserve_model_infer,
     kserve_model_metadata, kserve_model_metadata_ready,
 };
-use crate::validation::ValidationError;
+use crate::probe::{self, PROBE_MAX_BATCH_SIZE};
+use crate::prompt_log::{KeyProvider, PromptEncryptor, PromptLogError};
+use crate::provenance::{hash_parameters, ProvenanceError, ProvenanceSigner};
+use crate::quota::{quota_middleware, unix_now, QuotaConfigError, QuotaTracker, UsageStore};
+use crate::rate_limit::{rate_limit_middleware, RateLimitConfigError, RateLimiter};
+use crate::telemetry::{self, OtlpRecorder};
+use crate::tls::{TlsConfig, TlsError, TlsServer};
+use crate::token_estimate::TokenEstimator;
+use crate::validation::{
+    GrammarArtifact, InputSizeLimits, LimitValues, LimitsUpdate, ValidationConfig, ValidationError,
+};
 use crate::{
-    BestOfSequence, Details, ErrorResponse, FinishReason, GenerateParameters, GenerateRequest,
-    GenerateResponse, GrammarType, HubModelInfo, HubProcessorConfig, HubTokenizerConfig, Info,
-    Message, PrefillToken, SimpleToken, StreamDetails, StreamResponse, Token, TokenizeResponse,
-    Usage, Validation,
+    BestOfSequence, CompileGrammarResponse, Details, ErrorResponse, FinishReason,
+    GenerateParameters, GenerateRequest, GenerateResponse, GrammarStatus, GrammarType,
+    HubModelInfo, HubProcessorConfig, HubTokenizerConfig, Info, Message, PrefillToken, Provenance,
+    QuotaUsage, QuotaWindowUsage, SimpleToken, StreamDetails, StreamResponse, Timings, Token,
+    TokenizeResponse, Usage, ValidateResponse, Validation,
 };
 use crate::{
     ChatCompletion, ChatCompletionChoice, ChatCompletionChunk, ChatCompletionComplete,
@@ -23,10 +50,15 @@ use crate::{
     CompletionRequest, CompletionType, DeltaToolCall, Function, Tool, VertexRequest,
     VertexResponse,
 };
-use crate::{FunctionDefinition, HubPreprocessorConfig, ToolCall, ToolType};
+use crate::{
+    FunctionDefinition, HubPreprocessorConfig, InputSanitization, ToolCall, ToolType,
+    TruncationDirection,
+};
 use async_stream::__private::AsyncStream;
-use axum::extract::Extension;
-use axum::http::{HeaderMap, Method, StatusCode};
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, Extension, MatchedPath, Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
@@ -36,20 +68,24 @@ use futures::stream::StreamExt;
 use futures::stream::{FuturesOrdered, FuturesUnordered};
 use futures::Stream;
 use futures::TryStreamExt;
+use grpc_metadata::REQUEST_ID_KEY;
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::convert::Infallible;
 use std::net::SocketAddr;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use text_generation_client::{v2, v3, ClientError, ShardInfo};
 use thiserror::Error;
 use tokenizers::Tokenizer;
 use tokio::select;
 use tokio::signal;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::oneshot;
 use tokio::time::Instant;
-use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::cors::AllowOrigin;
 use tracing::{info_span, instrument, Instrument};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
@@ -122,11 +158,16 @@ responses(
 example = json ! ({"error": "unhealthy", "error_type": "healthcheck"})),
 )
 )]
-#[instrument(skip(health))]
+#[instrument(skip(health, events))]
 /// Health check method
+/// Unhealthy in maintenance, to drain the traffic of the router
 async fn health(
     mut health: Extension<HealthCheck>,
+    events: Extension<Events>,
 ) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if events.in_maintenance() {
+        return Err(maintenance_error());
+    }
     match health.check().await {
         true => Ok(()),
         false => Err((
@@ -139,6 +180,135 @@ async fn health(
     }
 }
 
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/ready",
+responses(
+(status = 200, description = "The last canary generation succeeded"),
+(status = 503, description = "Text generation inference is not ready", body = ErrorResponse,
+example = json ! ({"error": "not ready", "error_type": "readiness"})),
+)
+)]
+#[instrument(skip(health, canary, events))]
+/// Readiness check method
+/// Uses the canary generation if enabled, the health check otherwise
+async fn ready(
+    mut health: Extension<HealthCheck>,
+    canary: Extension<Option<Canary>>,
+    events: Extension<Events>,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if events.in_maintenance() {
+        return Err(maintenance_error());
+    }
+    let ready = match &canary.0 {
+        Some(canary) => canary.is_healthy(),
+        None => health.check().await,
+    };
+    match ready {
+        true => Ok(()),
+        false => Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorResponse {
+                error: "not ready".to_string(),
+                error_type: "readiness".to_string(),
+            }),
+        )),
+    }
+}
+
+fn maintenance_error() -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(ErrorResponse {
+            error: "maintenance".to_string(),
+            error_type: "maintenance".to_string(),
+        }),
+    )
+}
+
+/// Maintenance mode of the router
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Maintenance {
+    enabled: bool,
+}
+
+/// Whether the router is in maintenance
+#[instrument(skip_all)]
+async fn admin_maintenance(events: Extension<Events>) -> Json<Maintenance> {
+    Json(Maintenance {
+        enabled: events.in_maintenance(),
+    })
+}
+
+/// Turn the maintenance mode on or off. In maintenance, the health and readiness checks fail to
+/// drain the traffic of the router, the requests that still reach it are served.
+#[instrument(skip_all)]
+async fn admin_update_maintenance(
+    events: Extension<Events>,
+    Json(maintenance): Json<Maintenance>,
+) -> Json<Maintenance> {
+    events.set_maintenance(maintenance.enabled);
+    Json(maintenance)
+}
+
+/// Current values of the runtime adjustable validation limits
+#[instrument(skip_all)]
+async fn admin_limits(Extension(infer): Extension<Infer>) -> Json<LimitValues> {
+    Json(infer.validation_limits().values())
+}
+
+/// Adjust the validation limits without restarting the router
+#[instrument(skip_all)]
+async fn admin_update_limits(
+    Extension(infer): Extension<Infer>,
+    Json(update): Json<LimitsUpdate>,
+) -> Result<Json<LimitValues>, (StatusCode, Json<ErrorResponse>)> {
+    match infer.validation_limits().update(update) {
+        Ok(values) => Ok(Json(values)),
+        Err(err) => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: err.to_string(),
+                error_type: "limits".to_string(),
+            }),
+        )),
+    }
+}
+
+/// Stream lifecycle events (warmup, batch failures and recoveries, shard reconnections,
+/// maintenance and adapters) as Server-Sent Events
+#[instrument(skip_all)]
+async fn admin_events(
+    events: Extension<Events>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let (replay, mut receiver) = events.subscribe();
+    let stream = async_stream::stream! {
+        // The latest state first, the events sent before the subscription are not received
+        for record in replay {
+            yield Ok(Event::default().json_data(record).unwrap_or_else(|err| {
+                tracing::error!("Failed to serialize event: {err}");
+                Event::default()
+            }));
+        }
+        loop {
+            match receiver.recv().await {
+                Ok(record) => {
+                    yield Ok(Event::default().json_data(record).unwrap_or_else(|err| {
+                        tracing::error!("Failed to serialize event: {err}");
+                        Event::default()
+                    }));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Events subscriber lagged, skipped {skipped} events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    };
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
 /// Generate tokens
 #[utoipa::path(
 post,
@@ -165,6 +335,8 @@ total_time,
 validation_time,
 queue_time,
 inference_time,
+prefill_time,
+decode_time,
 time_per_token,
 seed,
 )
@@ -185,10 +357,20 @@ pub(crate) async fn generate_internal(
     span: tracing::Span,
 ) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
     let start_time = Instant::now();
-    metrics::increment_counter!("tgi_request_count");
+    metrics::counter!("tgi_request_count").increment(1);
+    let adapter_label = infer.adapter_label(req.parameters.adapter_id.as_deref());
+    if let Some(adapter) = &adapter_label {
+        metrics::counter!("tgi_adapter_request_count", "adapter" => adapter.clone()).increment(1);
+    }
 
     // Do not long ultra long inputs, like image payloads.
-    tracing::debug!("Input: {}", &req.inputs[..1000.min(req.inputs.len())]);
+    tracing::debug!(
+        "Input: {}",
+        infer.loggable_text(&req.inputs[..1000.min(req.inputs.len())])
+    );
+    let slow_request = infer
+        .slow_request_threshold()
+        .map(|threshold| (threshold, req.parameters.summary()));
 
     let compute_characters = req.inputs.chars().count();
     let mut add_prompt = None;
@@ -197,15 +379,35 @@ pub(crate) async fn generate_internal(
     }
 
     let details: bool = req.parameters.details || req.parameters.decoder_input_details;
+    let parameters_hash = infer.provenance().map(|_| hash_parameters(&req.parameters));
 
     // Inference
-    let (response, best_of_responses) = match req.parameters.best_of {
-        Some(best_of) if best_of > 1 => {
-            let (response, best_of_responses) = infer.generate_best_of(req, best_of).await?;
-            (response, Some(best_of_responses))
-        }
-        _ => (infer.generate(req).await?, None),
+    let inference = match req.parameters.best_of {
+        Some(best_of) if best_of > 1 => infer
+            .generate_best_of(req, best_of)
+            .await
+            .map(|(response, best_of_responses)| (response, Some(best_of_responses))),
+        _ => infer.generate(req).await.map(|response| (response, None)),
     };
+    let (response, best_of_responses) = inference.map_err(|err| {
+        adapter_failure_metrics(adapter_label.as_deref(), &err);
+        err
+    })?;
+
+    // Timings
+    let total_time = start_time.elapsed();
+    let validation_time = response.queued - start_time;
+    let queue_time = response.start - response.queued;
+    let inference_time = Instant::now() - response.start;
+    let prefill_time = response.first_token - response.start;
+    let decode_time = inference_time.saturating_sub(prefill_time);
+    let time_per_token = inference_time / response.generated_text.generated_tokens;
+    let timings = Timings::new(
+        queue_time,
+        prefill_time,
+        decode_time,
+        response.generated_text.generated_tokens,
+    );
 
     // Token details
     let input_length = response._input_length;
@@ -243,23 +445,19 @@ pub(crate) async fn generate_internal(
                 seed: response.generated_text.seed,
                 best_of_sequences,
                 top_tokens: response.top_tokens,
+                timings,
             })
         }
         false => None,
     };
 
-    // Timings
-    let total_time = start_time.elapsed();
-    let validation_time = response.queued - start_time;
-    let queue_time = response.start - response.queued;
-    let inference_time = Instant::now() - response.start;
-    let time_per_token = inference_time / response.generated_text.generated_tokens;
-
     // Tracing metadata
     span.record("total_time", format!("{total_time:?}"));
     span.record("validation_time", format!("{validation_time:?}"));
     span.record("queue_time", format!("{queue_time:?}"));
     span.record("inference_time", format!("{inference_time:?}"));
+    span.record("prefill_time", format!("{prefill_time:?}"));
+    span.record("decode_time", format!("{decode_time:?}"));
     span.record("time_per_token", format!("{time_per_token:?}"));
     span.record("seed", format!("{:?}", response.generated_text.seed));
 
@@ -290,35 +488,71 @@ pub(crate) async fn generate_internal(
         "x-inference-time",
         inference_time.as_millis().to_string().parse().unwrap(),
     );
+    headers.insert(
+        "x-prefill-time",
+        prefill_time.as_millis().to_string().parse().unwrap(),
+    );
+    headers.insert(
+        "x-decode-time",
+        decode_time.as_millis().to_string().parse().unwrap(),
+    );
     headers.insert(
         "x-time-per-token",
         time_per_token.as_millis().to_string().parse().unwrap(),
     );
+    headers.insert(
+        "x-tokens-per-second",
+        timings.tokens_per_second.to_string().parse().unwrap(),
+    );
     headers.insert("x-prompt-tokens", input_length.into());
     headers.insert(
         "x-generated-tokens",
         response.generated_text.generated_tokens.into(),
     );
 
+    if let Some((threshold, parameters)) = &slow_request {
+        if total_time > *threshold {
+            log_slow_request(
+                total_time,
+                validation_time,
+                &timings,
+                input_length,
+                response.generated_text.generated_tokens,
+                parameters,
+            );
+        }
+    }
+
     // Metrics
-    metrics::increment_counter!("tgi_request_success");
-    metrics::histogram!("tgi_request_duration", total_time.as_secs_f64());
-    metrics::histogram!(
-        "tgi_request_validation_duration",
-        validation_time.as_secs_f64()
+    metrics::counter!("tgi_request_success").increment(1);
+    metrics::histogram!("tgi_request_duration").record(total_time.as_secs_f64());
+    metrics::histogram!("tgi_request_validation_duration").record(validation_time.as_secs_f64());
+    metrics::histogram!("tgi_request_queue_duration").record(queue_time.as_secs_f64());
+    metrics::histogram!("tgi_request_inference_duration").record(inference_time.as_secs_f64());
+    metrics::histogram!("tgi_request_mean_time_per_token_duration")
+        .record(time_per_token.as_secs_f64());
+    metrics::histogram!("tgi_request_generated_tokens")
+        .record(response.generated_text.generated_tokens as f64);
+    telemetry::record_exemplar("tgi_request_duration", &[], total_time.as_secs_f64(), &span);
+    telemetry::record_exemplar(
+        "tgi_request_queue_duration",
+        &[],
+        queue_time.as_secs_f64(),
+        &span,
     );
-    metrics::histogram!("tgi_request_queue_duration", queue_time.as_secs_f64());
-    metrics::histogram!(
+    telemetry::record_exemplar(
         "tgi_request_inference_duration",
-        inference_time.as_secs_f64()
-    );
-    metrics::histogram!(
-        "tgi_request_mean_time_per_token_duration",
-        time_per_token.as_secs_f64()
+        &[],
+        inference_time.as_secs_f64(),
+        &span,
     );
-    metrics::histogram!(
-        "tgi_request_generated_tokens",
-        response.generated_text.generated_tokens as f64
+    adapter_success_metrics(
+        adapter_label.as_deref(),
+        total_time,
+        queue_time,
+        inference_time,
+        input_length,
+        response.generated_text.generated_tokens,
     );
 
     // Send response
@@ -327,12 +561,17 @@ pub(crate) async fn generate_internal(
         output_text = prompt + &output_text;
     }
 
-    tracing::debug!("Output: {}", output_text);
+    tracing::debug!("Output: {}", infer.loggable_text(&output_text));
     tracing::info!("Success");
 
+    let provenance = infer
+        .provenance()
+        .zip(parameters_hash)
+        .map(|(signer, parameters_hash)| signer.sign(parameters_hash, &output_text));
     let response = GenerateResponse {
         generated_text: output_text,
         details,
+        provenance,
     };
     Ok((headers, Json(response)))
 }
@@ -368,6 +607,8 @@ total_time,
 validation_time,
 queue_time,
 inference_time,
+prefill_time,
+decode_time,
 time_per_token,
 seed,
 )
@@ -399,9 +640,16 @@ async fn generate_stream_internal(
     span: tracing::Span,
 ) -> (HeaderMap, impl Stream<Item = Result<Event, Infallible>>) {
     let start_time = Instant::now();
-    metrics::increment_counter!("tgi_request_count");
+    metrics::counter!("tgi_request_count").increment(1);
+    let adapter_label = infer.adapter_label(req.parameters.adapter_id.as_deref());
+    if let Some(adapter) = &adapter_label {
+        metrics::counter!("tgi_adapter_request_count", "adapter" => adapter.clone()).increment(1);
+    }
 
-    tracing::debug!("Input: {}", req.inputs);
+    tracing::debug!("Input: {}", infer.loggable_text(&req.inputs));
+    let slow_request = infer
+        .slow_request_threshold()
+        .map(|threshold| (threshold, req.parameters.summary()));
 
     let compute_characters = req.inputs.chars().count();
 
@@ -412,36 +660,65 @@ async fn generate_stream_internal(
         compute_characters.to_string().parse().unwrap(),
     );
     headers.insert("X-Accel-Buffering", "no".parse().unwrap());
+    // The stream is polled outside of the request scope
+    let request_id = grpc_metadata::request_id().unwrap_or_default();
+    let route = current_route();
+    let auth_context = auth_context();
+    let priority = infer.priority();
+    let stream_limits = infer.stream_limits();
+    let deadline = stream_limits
+        .max_duration
+        .map(|max_duration| start_time + max_duration);
 
     let stream = async_stream::stream! {
         // Inference
         let mut end_reached = false;
         let mut error = false;
+        // Reported when the stream is dropped
+        let mut outcome = StreamOutcome::new(start_time, priority);
 
         let mut add_prompt = None;
         if req.parameters.return_full_text.unwrap_or(false) {
             add_prompt = Some(req.inputs.clone());
         }
         let details = req.parameters.details;
+        let mut parameters_hash = infer.provenance().map(|_| hash_parameters(&req.parameters));
 
         let best_of = req.parameters.best_of.unwrap_or(1);
         if best_of != 1 {
             let err = InferError::from(ValidationError::BestOfStream);
-            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
             tracing::error!("{err}");
+            outcome.outcome = StreamOutcome::ERRORED;
+            error_metrics(&route, &err);
             yield Ok(Event::from(err));
         } else if req.parameters.decoder_input_details {
             let err = InferError::from(ValidationError::PrefillDetailsStream);
-            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
             tracing::error!("{err}");
+            outcome.outcome = StreamOutcome::ERRORED;
+            error_metrics(&route, &err);
             yield Ok(Event::from(err));
         } else {
-            match infer.generate_stream(req).instrument(info_span!(parent: &span, "async_stream")).await {
+            match grpc_metadata::scope_request_id(request_id, scope_auth_context(auth_context, infer.generate_stream(req))).instrument(info_span!(parent: &span, "async_stream")).await {
                 // Keep permit as long as generate_stream lives
-                Ok((_permit, _input_length, mut response_stream)) => {
+                Ok((_permit, input_length, mut response_stream)) => {
                     let mut index = 0;
+                    // Emitted tokens, returned if a stream limit is reached
+                    let mut streamed_text = String::new();
+                    let mut streamed_bytes = 0;
+                    let mut streamed_tokens = 0;
+                    let mut limit_reached = None;
                     // Server-Sent Event stream
-                    while let Some(response) = response_stream.next().await {
+                    loop {
+                        let response = match next_before(&mut response_stream, deadline).await {
+                            Ok(Some(response)) => response,
+                            Ok(None) => break,
+                            Err(_) => {
+                                limit_reached = Some(FinishReason::MaxDuration);
+                                break;
+                            }
+                        };
                         index += 1;
                         match response {
                             Ok(response) => {
@@ -453,6 +730,16 @@ async fn generate_stream_internal(
                                         token,
                                         top_tokens,
                                     } => {
+                                        if stream_limits.max_bytes.is_some_and(|max_bytes| streamed_bytes + token.text.len() > max_bytes) {
+                                            limit_reached = Some(FinishReason::MaxBytes);
+                                            break;
+                                        }
+                                        streamed_bytes += token.text.len();
+                                        streamed_tokens += 1;
+                                        if !token.special {
+                                            streamed_text.push_str(&token.text);
+                                        }
+                                        outcome.token();
                                         tracing::debug!(parent: &span, "Token: {:?}", token);
 
                                         // StreamResponse
@@ -462,6 +749,7 @@ async fn generate_stream_internal(
                                             top_tokens,
                                             generated_text: None,
                                             details: None,
+                                            provenance: None,
                                         };
                                         let event = on_message_callback(stream_token);
                                         yield Ok(event);
@@ -474,57 +762,76 @@ async fn generate_stream_internal(
                                         queued,
                                         top_tokens,
                                     } => {
+                                        // Timings
+                                        let total_time = start_time.elapsed();
+                                        let validation_time = queued - start_time;
+                                        let queue_time = start - queued;
+                                        let inference_time = Instant::now() - start;
+                                        let prefill_time = outcome.token() - start;
+                                        let decode_time = inference_time.saturating_sub(prefill_time);
+                                        let time_per_token = inference_time / generated_text.generated_tokens;
+                                        let timings = Timings::new(queue_time, prefill_time, decode_time, generated_text.generated_tokens);
+
                                         // Token details
                                         let details = match details {
                                             true => Some(StreamDetails {
                                                 finish_reason: generated_text.finish_reason,
                                                 generated_tokens: generated_text.generated_tokens,
                                                 seed: generated_text.seed,
+                                                timings,
                                             }),
                                             false => None,
                                         };
 
-                                        // Timings
-                                        let total_time = start_time.elapsed();
-                                        let validation_time = queued - start_time;
-                                        let queue_time = start - queued;
-                                        let inference_time = Instant::now() - start;
-                                        let time_per_token = inference_time / generated_text.generated_tokens;
-
                                         // Tracing metadata
                                         span.record("total_time", format!("{total_time:?}"));
                                         span.record("validation_time", format!("{validation_time:?}"));
                                         span.record("queue_time", format!("{queue_time:?}"));
                                         span.record("inference_time", format!("{inference_time:?}"));
+                                        span.record("prefill_time", format!("{prefill_time:?}"));
+                                        span.record("decode_time", format!("{decode_time:?}"));
                                         span.record("time_per_token", format!("{time_per_token:?}"));
                                         span.record("seed", format!("{:?}", generated_text.seed));
 
+                                        if let Some((threshold, parameters)) = &slow_request {
+                                            if total_time > *threshold {
+                                                log_slow_request(total_time, validation_time, &timings, input_length, generated_text.generated_tokens, parameters);
+                                            }
+                                        }
+
                                         // Metrics
-                                        metrics::increment_counter!("tgi_request_success");
-                                        metrics::histogram!("tgi_request_duration", total_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_validation_duration", validation_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_queue_duration", queue_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_inference_duration", inference_time.as_secs_f64());
-                                        metrics::histogram!("tgi_request_mean_time_per_token_duration", time_per_token.as_secs_f64());
-                                        metrics::histogram!("tgi_request_generated_tokens", generated_text.generated_tokens as f64);
+                                        metrics::counter!("tgi_request_success").increment(1);
+                                        metrics::histogram!("tgi_request_duration").record(total_time.as_secs_f64());
+                                        metrics::histogram!("tgi_request_validation_duration").record(validation_time.as_secs_f64());
+                                        metrics::histogram!("tgi_request_queue_duration").record(queue_time.as_secs_f64());
+                                        metrics::histogram!("tgi_request_inference_duration").record(inference_time.as_secs_f64());
+                                        metrics::histogram!("tgi_request_mean_time_per_token_duration").record(time_per_token.as_secs_f64());
+                                        metrics::histogram!("tgi_request_generated_tokens").record(generated_text.generated_tokens as f64);
+                                        telemetry::record_exemplar("tgi_request_duration", &[], total_time.as_secs_f64(), &span);
+                                        telemetry::record_exemplar("tgi_request_queue_duration", &[], queue_time.as_secs_f64(), &span);
+                                        telemetry::record_exemplar("tgi_request_inference_duration", &[], inference_time.as_secs_f64(), &span);
+                                        adapter_success_metrics(adapter_label.as_deref(), total_time, queue_time, inference_time, input_length, generated_text.generated_tokens);
 
                                         // StreamResponse
                                         end_reached = true;
+                                        outcome.outcome = StreamOutcome::COMPLETED;
 
                                         let mut output_text = generated_text.text;
-                                        if let Some(prompt) = add_prompt {
+                                        if let Some(prompt) = add_prompt.take() {
                                             output_text = prompt + &output_text;
                                         }
 
-                                        tracing::debug!(parent: &span, "Output: {}", output_text);
+                                        tracing::debug!(parent: &span, "Output: {}", infer.loggable_text(&output_text));
                                         tracing::info!(parent: &span, "Success");
 
+                                        let provenance = infer.provenance().zip(parameters_hash.take()).map(|(signer, parameters_hash)| signer.sign(parameters_hash, &output_text));
                                         let stream_token = StreamResponse {
                                             index,
                                             token,
                                             top_tokens,
                                             generated_text: Some(output_text),
-                                            details
+                                            details,
+                                            provenance,
                                         };
 
 
@@ -537,15 +844,63 @@ async fn generate_stream_internal(
                             // yield error
                             Err(err) => {
                                 error = true;
+                                outcome.outcome = StreamOutcome::ERRORED;
+                                adapter_failure_metrics(adapter_label.as_deref(), &err);
+                                error_metrics(&route, &err);
                                 yield Ok(Event::from(err));
                                 break;
                             }
                         }
                     }
+
+                    // End the stream with the tokens emitted so far
+                    if let Some(finish_reason) = limit_reached {
+                        // Cancel the generation
+                        drop(response_stream);
+                        end_reached = true;
+                        outcome.outcome = StreamOutcome::LIMITED;
+                        metrics::counter!("tgi_stream_limit_reached", "limit" => finish_reason.to_string()).increment(1);
+                        tracing::warn!(parent: &span, "Stream stopped after {streamed_tokens} tokens: {finish_reason}");
+
+                        // The queue time is only known at the end of the generation, it is
+                        // counted in the prefill time
+                        let now = Instant::now();
+                        let first_token = outcome.first_token.unwrap_or(now);
+                        let timings = Timings::new(Duration::ZERO, first_token - start_time, now - first_token, streamed_tokens);
+                        let details = details.then_some(StreamDetails {
+                            finish_reason,
+                            generated_tokens: streamed_tokens,
+                            seed: None,
+                            timings,
+                        });
+
+                        let mut output_text = streamed_text;
+                        if let Some(prompt) = add_prompt.take() {
+                            output_text = prompt + &output_text;
+                        }
+                        let provenance = infer.provenance().zip(parameters_hash.take()).map(|(signer, parameters_hash)| signer.sign(parameters_hash, &output_text));
+                        let stream_token = StreamResponse {
+                            index: index + 1,
+                            token: Token {
+                                id: 0,
+                                text: String::new(),
+                                logprob: f32::NAN,
+                                special: true,
+                            },
+                            top_tokens: Vec::new(),
+                            generated_text: Some(output_text),
+                            details,
+                            provenance,
+                        };
+                        yield Ok(on_message_callback(stream_token));
+                    }
                 },
                 // yield error
                 Err(err) => {
                     error = true;
+                    outcome.outcome = StreamOutcome::ERRORED;
+                    adapter_failure_metrics(adapter_label.as_deref(), &err);
+                    error_metrics(&route, &err);
                     yield Ok(Event::from(err));
                 }
             }
@@ -553,8 +908,11 @@ async fn generate_stream_internal(
             // Skip if we already sent an error
             if !end_reached && !error {
                 let err = InferError::IncompleteGeneration;
-                metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
+                metrics::counter!("tgi_request_failure", "err" => "incomplete").increment(1);
+                adapter_failure_metrics(adapter_label.as_deref(), &err);
                 tracing::error!("{err}");
+                outcome.outcome = StreamOutcome::SERVER_CANCELLED;
+                error_metrics(&route, &err);
                 yield Ok(Event::from(err));
             }
         }
@@ -604,7 +962,7 @@ async fn completions(
     Json(req): Json<CompletionRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
-    metrics::increment_counter!("tgi_request_count");
+    metrics::counter!("tgi_request_count").increment(1);
 
     let CompletionRequest {
         max_tokens,
@@ -617,27 +975,9 @@ async fn completions(
 
     let max_new_tokens = max_tokens.or(Some(100));
     let stop = stop.unwrap_or_default();
-    // enable greedy only when temperature is 0
-    let (do_sample, temperature) = match temperature {
-        Some(temperature) if temperature == 0.0 => (false, None),
-        other => (true, other),
-    };
-
-    // if suffix is present throw an error
-    if req.suffix.is_some() {
-        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
-        return Err((
-            StatusCode::UNPROCESSABLE_ENTITY,
-            Json(ErrorResponse {
-                error: "Suffix is not supported and can be achieved by preprocessing the prompt."
-                    .to_string(),
-                error_type: "suffix not supported".to_string(),
-            }),
-        ));
-    }
 
     if req.prompt.0.len() > info.max_client_batch_size {
-        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
             Json(ErrorResponse {
@@ -656,19 +996,26 @@ async fn completions(
         .iter()
         .map(|prompt| GenerateRequest {
             inputs: prompt.to_string(),
+            input_ids: None,
+            messages: None,
             parameters: GenerateParameters {
                 best_of: None,
                 temperature,
                 repetition_penalty: req.repetition_penalty,
                 frequency_penalty: req.frequency_penalty,
+                presence_penalty: req.presence_penalty,
                 top_k: None,
                 top_p: req.top_p,
+                min_p: req.min_p,
+                logit_bias: req.logit_bias.clone().unwrap_or_default(),
                 typical_p: None,
-                do_sample,
+                do_sample: true,
                 max_new_tokens,
                 return_full_text: None,
                 stop: stop.clone(),
+                suffix: req.suffix.clone(),
                 truncate: None,
+                truncation_direction: TruncationDirection::Left,
                 watermark: false,
                 details: true,
                 decoder_input_details: !stream,
@@ -725,28 +1072,38 @@ async fn completions(
                 let (header_tx, header_rx) = oneshot::channel();
                 let (sse_tx, sse_rx) = tokio::sync::mpsc::unbounded_channel();
 
-                tokio::spawn(async move {
-                    let (header_map, sse) = generate_stream_internal(
-                        infer_clone.clone(),
-                        compute_type_clone.clone(),
-                        Json(generate_request),
-                        on_message_callback,
-                        span_clone.clone(),
-                    )
-                    .await;
-
-                    // send and dont wait for response
-                    let _ = header_tx.send(header_map);
-
-                    // pin an emit messages to the sse_tx
-                    let mut sse = Box::pin(sse);
-                    while let Some(event) = sse.next().await {
-                        if sse_tx.send(event).is_err() {
-                            tracing::error!("Failed to send event. Receiver dropped.");
-                            break;
-                        }
-                    }
-                });
+                // Keep the request id, route and caller identity in the spawned task
+                let request_id = grpc_metadata::request_id().unwrap_or_default();
+                let route = current_route();
+                let auth_context = auth_context();
+                tokio::spawn(ROUTE.scope(
+                    route,
+                    scope_auth_context(
+                        auth_context,
+                        grpc_metadata::scope_request_id(request_id, async move {
+                            let (header_map, sse) = generate_stream_internal(
+                                infer_clone.clone(),
+                                compute_type_clone.clone(),
+                                Json(generate_request),
+                                on_message_callback,
+                                span_clone.clone(),
+                            )
+                            .await;
+
+                            // send and dont wait for response
+                            let _ = header_tx.send(header_map);
+
+                            // pin an emit messages to the sse_tx
+                            let mut sse = Box::pin(sse);
+                            while let Some(event) = sse.next().await {
+                                if sse_tx.send(event).is_err() {
+                                    tracing::error!("Failed to send event. Receiver dropped.");
+                                    break;
+                                }
+                            }
+                        }),
+                    ),
+                ));
 
                 (header_rx, sse_rx)
             };
@@ -856,6 +1213,8 @@ async fn completions(
         let mut x_validation_time = 0u32;
         let mut x_queue_time = 0u32;
         let mut x_inference_time = 0u32;
+        let mut x_prefill_time = 0u32;
+        let mut x_decode_time = 0u32;
         let mut x_time_per_token = 0u32;
         let mut x_prompt_tokens = 0u32;
         let mut x_generated_tokens = 0u32;
@@ -904,6 +1263,14 @@ async fn completions(
                     .get("x-inference-time")
                     .and_then(|v| v.to_str().ok()?.parse().ok())
                     .unwrap_or(0);
+                x_prefill_time += headers
+                    .get("x-prefill-time")
+                    .and_then(|v| v.to_str().ok()?.parse().ok())
+                    .unwrap_or(0);
+                x_decode_time += headers
+                    .get("x-decode-time")
+                    .and_then(|v| v.to_str().ok()?.parse().ok())
+                    .unwrap_or(0);
                 x_time_per_token += headers
                     .get("x-time-per-token")
                     .and_then(|v| v.to_str().ok()?.parse().ok())
@@ -958,6 +1325,8 @@ async fn completions(
         headers.insert("x-validation-time", x_validation_time.into());
         headers.insert("x-queue-time", x_queue_time.into());
         headers.insert("x-inference-time", x_inference_time.into());
+        headers.insert("x-prefill-time", x_prefill_time.into());
+        headers.insert("x-decode-time", x_decode_time.into());
         headers.insert("x-time-per-token", x_time_per_token.into());
         headers.insert("x-prompt-tokens", x_prompt_tokens.into());
         headers.insert("x-generated-tokens", x_generated_tokens.into());
@@ -1009,7 +1378,7 @@ async fn chat_completions(
     Json(req): Json<ChatRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
-    metrics::increment_counter!("tgi_request_count");
+    metrics::counter!("tgi_request_count").increment(1);
     let ChatRequest {
         logprobs,
         max_tokens,
@@ -1026,20 +1395,14 @@ async fn chat_completions(
         ..
     } = req;
 
-    let repetition_penalty = presence_penalty.map(|x| x + 2.0);
     let max_new_tokens = max_tokens.or(Some(100));
     let logprobs = logprobs.unwrap_or(false);
     let tool_prompt = tool_prompt.unwrap_or_default();
     let stop = stop.unwrap_or_default();
-    // enable greedy only when temperature is 0
-    let (do_sample, temperature) = match temperature {
-        Some(temperature) if temperature == 0.0 => (false, None),
-        other => (true, other),
-    };
 
     // response_format and tools are mutually exclusive
     if response_format.is_some() && tools.as_ref().is_some() {
-        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
         return Err((
             StatusCode::UNPROCESSABLE_ENTITY,
             Json(ErrorResponse {
@@ -1053,7 +1416,8 @@ async fn chat_completions(
     let tool_grammar = match ToolGrammar::apply(tools, tool_choice) {
         Ok(grammar) => grammar,
         Err(err) => {
-            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            error_metrics(&current_route(), &err);
             tracing::error!("{err}");
             return Err((
                 StatusCode::UNPROCESSABLE_ENTITY,
@@ -1082,7 +1446,8 @@ async fn chat_completions(
     let inputs = match infer.apply_chat_template(messages, tools_grammar_prompt) {
         Ok(inputs) => inputs,
         Err(err) => {
-            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+            error_metrics(&current_route(), &err);
             tracing::error!("{err}");
             return Err((
                 StatusCode::UNPROCESSABLE_ENTITY,
@@ -1097,19 +1462,25 @@ async fn chat_completions(
     // build the request passing some parameters
     let generate_request = GenerateRequest {
         inputs: inputs.to_string(),
+        input_ids: None,
+        messages: None,
         parameters: GenerateParameters {
             best_of: None,
             temperature,
-            repetition_penalty,
+            repetition_penalty: None,
             frequency_penalty: req.frequency_penalty,
+            presence_penalty,
             top_k: None,
             top_p: req.top_p,
+            min_p: req.min_p,
+            logit_bias: req.logit_bias.unwrap_or_default(),
             typical_p: None,
-            do_sample,
+            do_sample: true,
             max_new_tokens,
             return_full_text: None,
             stop,
             truncate: None,
+            truncation_direction: TruncationDirection::Left,
             watermark: false,
             details: true,
             decoder_input_details: !stream,
@@ -1280,7 +1651,7 @@ async fn vertex_compatibility(
     Json(req): Json<VertexRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
-    metrics::increment_counter!("tgi_request_count");
+    metrics::counter!("tgi_request_count").increment(1);
 
     // check that theres at least one instance
     if req.instances.is_empty() {
@@ -1300,6 +1671,8 @@ async fn vertex_compatibility(
         .map(|instance| {
             let generate_request = GenerateRequest {
                 inputs: instance.inputs.clone(),
+                input_ids: None,
+                messages: None,
                 parameters: GenerateParameters {
                     do_sample: true,
                     max_new_tokens: instance.parameters.as_ref().and_then(|p| p.max_new_tokens),
@@ -1385,20 +1758,467 @@ async fn tokenize(
     }
 }
 
+/// Validate a request without generating
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/validate",
+request_body = GenerateRequest,
+responses(
+(status = 200, description = "Resolved parameters", body = ValidateResponse),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error"})),
+)
+)]
+#[instrument(skip_all)]
+async fn validate(
+    Extension(infer): Extension<Infer>,
+    Json(req): Json<GenerateRequest>,
+) -> Result<Json<ValidateResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let valid_request = infer.validate(req).await?;
+    let parameters = valid_request.parameters;
+    Ok(Json(ValidateResponse {
+        input_length: valid_request.input_length,
+        max_new_tokens: valid_request.stopping_parameters.max_new_tokens,
+        temperature: parameters.temperature,
+        top_k: parameters.top_k,
+        top_p: parameters.top_p,
+        typical_p: parameters.typical_p,
+        repetition_penalty: parameters.repetition_penalty,
+        do_sample: parameters.do_sample,
+        seed: parameters.seed,
+        max_time: valid_request.stopping_parameters.max_time,
+    }))
+}
+
+/// Compile a grammar in the background, for the requests referencing it by id
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/grammar/compile",
+request_body = GrammarType,
+responses(
+(status = 202, description = "Compiling or compiled grammar", body = CompileGrammarResponse),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error"})),
+)
+)]
+#[instrument(skip_all)]
+async fn compile_grammar(
+    Extension(infer): Extension<Infer>,
+    Json(grammar): Json<GrammarType>,
+) -> Result<(StatusCode, Json<CompileGrammarResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let (id, artifact) = infer.compile_grammar(grammar)?;
+    Ok((StatusCode::ACCEPTED, Json(grammar_response(id, artifact))))
+}
+
+/// Status of a grammar compiled by `/grammar/compile`
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/grammar/compile/{id}",
+params(("id" = String, Path, description = "Id returned by `/grammar/compile`")),
+responses(
+(status = 200, description = "Compiling or compiled grammar", body = CompileGrammarResponse),
+(status = 404, description = "Unknown grammar", body = ErrorResponse,
+example = json ! ({"error": "Unknown grammar", "error_type": "grammar"})),
+)
+)]
+#[instrument(skip_all)]
+async fn grammar_status(
+    Extension(infer): Extension<Infer>,
+    Path(id): Path<String>,
+) -> Result<Json<CompileGrammarResponse>, (StatusCode, Json<ErrorResponse>)> {
+    match infer.grammar_artifact(&id) {
+        Some(artifact) => Ok(Json(grammar_response(id, artifact))),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Unknown grammar `{id}`"),
+                error_type: "grammar".to_string(),
+            }),
+        )),
+    }
+}
+
+fn grammar_response(id: String, artifact: GrammarArtifact) -> CompileGrammarResponse {
+    let (status, error) = match artifact {
+        GrammarArtifact::Compiling => (GrammarStatus::Compiling, None),
+        GrammarArtifact::Ready(_) => (GrammarStatus::Ready, None),
+        GrammarArtifact::Failed(err) => (GrammarStatus::Failed, Some(err)),
+    };
+    CompileGrammarResponse { id, status, error }
+}
+
+/// Token usage and remaining quota of the caller
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/usage",
+responses(
+(status = 200, description = "Token usage of the caller", body = QuotaUsage),
+(status = 404, description = "Quotas are not enabled", body = ErrorResponse,
+example = json ! ({"error": "Quotas are not enabled", "error_type": "usage"})),
+)
+)]
+#[instrument(skip_all)]
+async fn usage(
+    Extension(infer): Extension<Infer>,
+) -> Result<Json<QuotaUsage>, (StatusCode, Json<ErrorResponse>)> {
+    match infer.quotas() {
+        Some(quotas) => Ok(Json(quotas.usage(&auth_context().key(), unix_now()))),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Quotas are not enabled".to_string(),
+                error_type: "usage".to_string(),
+            }),
+        )),
+    }
+}
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text";
+
 /// Prometheus metrics scrape endpoint
+/// Latency histograms carry trace exemplars when the OpenMetrics format is requested
 #[utoipa::path(
 get,
 tag = "Text Generation Inference",
 path = "/metrics",
 responses((status = 200, description = "Prometheus Metrics", body = String))
 )]
-async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
-    prom_handle.render()
+async fn metrics(prom_handle: Extension<PrometheusHandle>, headers: HeaderMap) -> Response {
+    let openmetrics = headers
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(OPENMETRICS_CONTENT_TYPE));
+    match openmetrics {
+        true => (
+            [(
+                http::header::CONTENT_TYPE,
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            )],
+            telemetry::render_openmetrics(&prom_handle.render()),
+        )
+            .into_response(),
+        false => prom_handle.render().into_response(),
+    }
+}
+
+/// Outcome of a token stream, reported with its time to first token when the stream is dropped.
+/// The latencies are labelled with the priority class of the caller.
+struct StreamOutcome {
+    outcome: &'static str,
+    priority: String,
+    start_time: Instant,
+    first_token: Option<Instant>,
+    last_token: Option<Instant>,
+}
+
+impl StreamOutcome {
+    const COMPLETED: &'static str = "completed";
+    const CLIENT_DISCONNECTED: &'static str = "client_disconnected";
+    const SERVER_CANCELLED: &'static str = "server_cancelled";
+    const ERRORED: &'static str = "errored";
+    /// Stopped by the stream duration or size limit
+    const LIMITED: &'static str = "limited";
+
+    /// Streams dropped before reaching an outcome were dropped by the client
+    fn new(start_time: Instant, priority: String) -> Self {
+        Self {
+            outcome: Self::CLIENT_DISCONNECTED,
+            priority,
+            start_time,
+            first_token: None,
+            last_token: None,
+        }
+    }
+
+    /// Record the arrival of a token and its latency since the previous token.
+    /// Returns the arrival time of the first token
+    fn token(&mut self) -> Instant {
+        let now = Instant::now();
+        if let Some(last_token) = self.last_token.replace(now) {
+            metrics::histogram!("tgi_stream_inter_token_duration", "priority" => self.priority.clone())
+                .record((now - last_token).as_secs_f64());
+        }
+        *self.first_token.get_or_insert(now)
+    }
+}
+
+impl Drop for StreamOutcome {
+    fn drop(&mut self) {
+        metrics::counter!("tgi_stream_outcome", "outcome" => self.outcome).increment(1);
+        if let Some(first_token) = self.first_token {
+            metrics::histogram!(
+                "tgi_stream_time_to_first_token_duration",
+                "outcome" => self.outcome,
+                "priority" => self.priority.clone()
+            )
+            .record((first_token - self.start_time).as_secs_f64());
+        }
+    }
+}
+
+/// Next item of `stream`, `Err` if `deadline` is reached first
+async fn next_before<S: Stream + Unpin>(
+    stream: &mut S,
+    deadline: Option<Instant>,
+) -> Result<Option<S::Item>, tokio::time::error::Elapsed> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, stream.next()).await,
+        None => Ok(stream.next().await),
+    }
+}
+
+/// Log a request slower than the configured threshold with its latency breakdown
+fn log_slow_request(
+    total_time: Duration,
+    validation_time: Duration,
+    timings: &Timings,
+    input_length: u32,
+    generated_tokens: u32,
+    parameters: &str,
+) {
+    tracing::warn!(
+        total_time = ?total_time,
+        validation_time = ?validation_time,
+        queue_time_ms = timings.queue_time,
+        prefill_time_ms = timings.prefill_time,
+        decode_time_ms = timings.decode_time,
+        tokens_per_second = timings.tokens_per_second,
+        input_length,
+        generated_tokens,
+        parameters,
+        "Slow request"
+    );
+}
+
+/// Per-adapter request success metrics
+fn adapter_success_metrics(
+    adapter: Option<&str>,
+    total_time: Duration,
+    queue_time: Duration,
+    inference_time: Duration,
+    input_length: u32,
+    generated_tokens: u32,
+) {
+    if let Some(adapter) = adapter {
+        let labels = [("adapter", adapter.to_string())];
+        metrics::counter!("tgi_adapter_request_success", &labels).increment(1);
+        metrics::histogram!("tgi_adapter_request_duration", &labels)
+            .record(total_time.as_secs_f64());
+        metrics::histogram!("tgi_adapter_request_queue_duration", &labels)
+            .record(queue_time.as_secs_f64());
+        metrics::histogram!("tgi_adapter_request_inference_duration", &labels)
+            .record(inference_time.as_secs_f64());
+        metrics::histogram!("tgi_adapter_request_input_length", &labels)
+            .record(input_length as f64);
+        metrics::histogram!("tgi_adapter_request_generated_tokens", &labels)
+            .record(generated_tokens as f64);
+    }
+}
+
+/// Per-adapter request failure metrics
+fn adapter_failure_metrics(adapter: Option<&str>, err: &InferError) {
+    if let Some(adapter) = adapter {
+        metrics::counter!("tgi_adapter_request_failure", "adapter" => adapter.to_string(), "err" => err.error_type().to_string()).increment(1);
+    }
+}
+
+tokio::task_local! {
+    /// Matched route of the request being served
+    static ROUTE: String;
+}
+
+/// Matched route of the current request, `unknown` outside of a request scope
+fn current_route() -> String {
+    ROUTE
+        .try_with(|route| route.clone())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Count errors by stable code, class and route
+fn error_metrics(route: &str, err: &InferError) {
+    metrics::counter!("tgi_error", "code" => err.code(), "class" => err.error_class(), "route" => route.to_string()).increment(1);
+}
+
+/// Scope the matched route of the request, used to label error metrics
+async fn route_middleware(request: axum::extract::Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    ROUTE.scope(route, next.run(request)).await
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct ComputeType(String);
 
+/// Reject bodies larger than `limit` bytes with a structured error
+async fn body_limit_middleware(
+    State(limit): State<usize>,
+    request: axum::extract::Request,
+    next: Next,
+) -> Response {
+    let content_length = request
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > limit) {
+        return payload_too_large(limit);
+    }
+
+    // Bodies without a content length are rejected by the extractors when they exceed the limit,
+    // only these rejections are replaced and the errors of the handlers are kept
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let request = {
+        let exceeded = exceeded.clone();
+        let mut read = 0;
+        request.map(|body| {
+            Body::from_stream(body.into_data_stream().inspect(move |data| {
+                if let Ok(data) = data {
+                    read += data.len();
+                    if read > limit {
+                        exceeded.store(true, Ordering::SeqCst);
+                    }
+                }
+            }))
+        })
+    };
+    let response = next.run(request).await;
+    if response.status() == StatusCode::PAYLOAD_TOO_LARGE && exceeded.load(Ordering::SeqCst) {
+        return payload_too_large(limit);
+    }
+    response
+}
+
+fn payload_too_large(limit: usize) -> Response {
+    metrics::counter!("tgi_request_failure", "err" => "payload_too_large").increment(1);
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(ErrorResponse {
+            error: format!("Request body must be at most {limit} bytes"),
+            error_type: "payload_too_large".to_string(),
+        }),
+    )
+        .into_response()
+}
+
+/// Maximum length of a client provided `x-request-id`
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Whether a client provided `x-request-id` can be forwarded to the shards, which receive the ids
+/// of a batch joined by `,`
+fn valid_request_id(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LENGTH
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.:/".contains(&byte))
+}
+
+/// Accept or generate an `x-request-id` for every request.
+/// The id is attached to the request span, forwarded to the shards and echoed in the response.
+/// Invalid client ids are replaced by a generated one.
+async fn request_id_middleware(request: axum::extract::Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_KEY)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| valid_request_id(value))
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        subject = tracing::field::Empty,
+        tenant = tracing::field::Empty
+    );
+    let mut response =
+        grpc_metadata::scope_request_id(request_id.clone(), next.run(request).instrument(span))
+            .await;
+    // Safe unwrap: the id is either a valid header value or hex
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_KEY, request_id.parse().unwrap());
+    response
+}
+
+/// Options of the validation of the requests, the files are loaded by `run`
+pub struct ValidationArgs {
+    pub fetch_policy: FetchPolicy,
+    /// Path of the per-key overrides of the limits
+    pub input_policies: Option<String>,
+    pub grammar_failure_limit: usize,
+    pub grammar_failure_window: Duration,
+    pub grammar_compile_timeout: Duration,
+    /// Path of the overrides of the multimodal limits
+    pub multimodal_limits: Option<String>,
+    pub fim_tokens: Option<[String; 3]>,
+    pub max_generation_time: Option<f32>,
+    pub input_sanitization: InputSanitization,
+    /// Path of the server defaults of the generation parameters
+    pub default_parameters: Option<String>,
+    pub max_input_bytes: Option<usize>,
+    pub max_chunk_chars: Option<usize>,
+    /// Directory of the token FSMs kept across restarts
+    pub grammar_cache_dir: Option<String>,
+    pub grammar_cache_max_bytes: u64,
+    /// Directory of the grammars referenced by name
+    pub grammar_registry_dir: Option<String>,
+}
+
+impl ValidationArgs {
+    fn into_config(self) -> Result<ValidationConfig, WebServerError> {
+        let input_policies = self
+            .input_policies
+            .map(|path| InputPolicies::from_file(&path))
+            .transpose()?
+            .unwrap_or_default();
+        let multimodal_limits = self
+            .multimodal_limits
+            .map(|path| MultimodalLimits::from_file(&path))
+            .transpose()?;
+        let default_parameters = self
+            .default_parameters
+            .map(|path| DefaultParameters::from_file(&path))
+            .transpose()?
+            .unwrap_or_default();
+        let fsm_cache = self
+            .grammar_cache_dir
+            .map(|dir| FsmDiskCache::open(&dir, self.grammar_cache_max_bytes))
+            .transpose()?;
+        let grammar_registry = self
+            .grammar_registry_dir
+            .map(|dir| GrammarRegistry::from_dir(&dir))
+            .transpose()?
+            .unwrap_or_default();
+        Ok(ValidationConfig {
+            fetch_policy: self.fetch_policy,
+            input_policies,
+            grammar_failure_limit: self.grammar_failure_limit,
+            grammar_failure_window: self.grammar_failure_window,
+            grammar_compile_timeout: self.grammar_compile_timeout,
+            multimodal_limits,
+            fim_tokens: self.fim_tokens,
+            max_time: self.max_generation_time,
+            input_sanitization: self.input_sanitization,
+            default_parameters,
+            input_size_limits: InputSizeLimits {
+                max_input_bytes: self.max_input_bytes,
+                max_chunk_chars: self.max_chunk_chars,
+            },
+            fsm_cache,
+            grammar_registry,
+            supports_guidance: false,
+        })
+    }
+}
+
 /// Serving method
 #[allow(clippy::too_many_arguments)]
 pub async fn run(
@@ -1430,12 +2250,39 @@ pub async fn run(
     messages_api_enabled: bool,
     grammar_support: bool,
     max_client_batch_size: usize,
+    max_adapter_metric_labels: usize,
+    slow_request_threshold: Option<Duration>,
+    canary_interval: Option<Duration>,
+    validation_rejection_sample_rate: f64,
+    jwt_config: Option<JwtConfig>,
+    rate_limit_config: Option<String>,
+    max_json_body_size: usize,
+    max_multimodal_body_size: usize,
+    provenance_signing_key: Option<String>,
+    adapter_acl: Option<String>,
+    hmac_config: Option<HmacConfig>,
+    tls_config: Option<TlsConfig>,
+    quota_config: Option<String>,
+    quota_store: Option<Arc<dyn UsageStore>>,
+    ip_filter: IpFilterConfig,
+    cors_config: Option<String>,
+    api_key_config: Option<ApiKeyConfig>,
+    protect_metrics: bool,
+    max_stream_duration: Option<Duration>,
+    max_stream_bytes: Option<usize>,
+    prompt_log_key_provider: Option<Arc<dyn KeyProvider>>,
+    admin_subjects: Vec<String>,
+    probe_max_batch_total_tokens: bool,
+    token_estimator: Option<TokenEstimator>,
+    otlp_recorder: Option<OtlpRecorder>,
+    validation_args: ValidationArgs,
 ) -> Result<(), WebServerError> {
     // OpenAPI documentation
     #[derive(OpenApi)]
     #[openapi(
     paths(
     health,
+    ready,
     get_model_info,
     compat_generate,
     generate,
@@ -1443,6 +2290,10 @@ pub async fn run(
     chat_completions,
     completions,
     tokenize,
+    validate,
+    compile_grammar,
+    grammar_status,
+    usage,
     metrics,
     ),
     components(
@@ -1469,14 +2320,23 @@ pub async fn run(
     Token,
     GenerateResponse,
     TokenizeResponse,
+    ValidateResponse,
+    CompileGrammarResponse,
+    GrammarStatus,
     SimpleToken,
     BestOfSequence,
     Details,
     FinishReason,
     StreamResponse,
     StreamDetails,
+    Provenance,
+    QuotaUsage,
+    QuotaWindowUsage,
+    Timings,
     ErrorResponse,
     GrammarType,
+    TruncationDirection,
+    InputSanitization,
     Usage,
     DeltaToolCall,
     ToolType,
@@ -1502,11 +2362,15 @@ pub async fn run(
     // Create state
 
     // Open connection, get model info and warmup
-    let (scheduler, health_ext, shard_info, max_batch_total_tokens): (
+    // Lifecycle events streamed on /admin/events
+    let events = Events::new();
+    // The v3 shards report the adapters loaded while they run
+    let (scheduler, health_ext, shard_info, max_batch_total_tokens, adapters_client): (
         Arc<dyn Scheduler + Send + Sync>,
         HealthCheck,
         ShardInfo,
         u32,
+        Option<v3::ShardedClient>,
     ) = {
         // Helper function to check both v2 and v3
         let check_max_batch_total_tokens = |max_supported_batch_total_tokens: Option<u32>| {
@@ -1540,6 +2404,14 @@ pub async fn run(
             }
         };
 
+        // Bounds of the search of the max batch total tokens, for models that do not report it
+        let min_probe_tokens = (max_total_tokens as u32).max(max_batch_prefill_tokens);
+        let max_probe_tokens = max_batch_size
+            .map_or(PROBE_MAX_BATCH_SIZE, |max_batch_size| max_batch_size as u32)
+            .saturating_mul(max_total_tokens as u32);
+        let probe_tokens = (probe_max_batch_total_tokens && max_batch_total_tokens.is_none())
+            .then_some((min_probe_tokens, max_probe_tokens));
+
         let generation_health = Arc::new(AtomicBool::new(false));
 
         match v3::ShardedClient::connect_uds(master_shard_uds_path.clone()).await {
@@ -1555,20 +2427,28 @@ pub async fn run(
 
                 // Warmup model
                 tracing::info!("Warming up model");
-                let max_batch_total_tokens = check_max_batch_total_tokens(
-                    sharded_client
-                        .warmup(
-                            max_input_tokens as u32,
-                            max_batch_prefill_tokens,
-                            max_total_tokens as u32,
-                            max_batch_size,
-                        )
-                        .await
-                        .map_err(WebServerError::Warmup)?,
-                )?;
-
-                let health_ext =
-                    HealthCheck::new(Arc::new(sharded_client.clone()), generation_health.clone());
+                let max_supported_batch_total_tokens = warmup_shards(
+                    &mut sharded_client,
+                    max_input_tokens as u32,
+                    max_batch_prefill_tokens,
+                    max_total_tokens as u32,
+                    max_batch_size,
+                    probe_tokens,
+                )
+                .await
+                .map_err(WebServerError::Warmup)?;
+                let max_batch_total_tokens =
+                    check_max_batch_total_tokens(max_supported_batch_total_tokens)?;
+
+                // Export the shard statistics
+                tokio::spawn(shard_stats_task(sharded_client.clone()));
+
+                let health_ext = HealthCheck::new(
+                    Arc::new(sharded_client.clone()),
+                    generation_health.clone(),
+                    events.clone(),
+                );
+                let adapters_client = Some(sharded_client.clone());
                 let scheduler = Arc::new(SchedulerV3::new(
                     sharded_client,
                     waiting_served_ratio,
@@ -1580,10 +2460,17 @@ pub async fn run(
                     shard_info.window_size,
                     shard_info.speculate,
                     generation_health,
+                    events.clone(),
                 ));
                 tracing::info!("Using scheduler V3");
 
-                (scheduler, health_ext, shard_info, max_batch_total_tokens)
+                (
+                    scheduler,
+                    health_ext,
+                    shard_info,
+                    max_batch_total_tokens,
+                    adapters_client,
+                )
             }
             Err(_) => {
                 let mut sharded_client = v2::ShardedClient::connect_uds(master_shard_uds_path)
@@ -1601,20 +2488,24 @@ pub async fn run(
 
                 // Warmup model
                 tracing::info!("Warming up model");
-                let max_batch_total_tokens = check_max_batch_total_tokens(
-                    sharded_client
-                        .warmup(
-                            max_input_tokens as u32,
-                            max_batch_prefill_tokens,
-                            max_total_tokens as u32,
-                            max_batch_size,
-                        )
-                        .await
-                        .map_err(WebServerError::Warmup)?,
-                )?;
-
-                let health_ext =
-                    HealthCheck::new(Arc::new(sharded_client.clone()), generation_health.clone());
+                let max_supported_batch_total_tokens = warmup_shards(
+                    &mut sharded_client,
+                    max_input_tokens as u32,
+                    max_batch_prefill_tokens,
+                    max_total_tokens as u32,
+                    max_batch_size,
+                    probe_tokens,
+                )
+                .await
+                .map_err(WebServerError::Warmup)?;
+                let max_batch_total_tokens =
+                    check_max_batch_total_tokens(max_supported_batch_total_tokens)?;
+
+                let health_ext = HealthCheck::new(
+                    Arc::new(sharded_client.clone()),
+                    generation_health.clone(),
+                    events.clone(),
+                );
                 let scheduler = Arc::new(SchedulerV2::new(
                     sharded_client,
                     waiting_served_ratio,
@@ -1626,15 +2517,32 @@ pub async fn run(
                     shard_info.window_size,
                     shard_info.speculate,
                     generation_health,
+                    events.clone(),
                 ));
                 tracing::info!("Using scheduler V2");
 
-                (scheduler, health_ext, shard_info, max_batch_total_tokens)
+                (
+                    scheduler,
+                    health_ext,
+                    shard_info,
+                    max_batch_total_tokens,
+                    None,
+                )
             }
         }
     };
     tracing::info!("Setting max batch total tokens to {max_batch_total_tokens}");
+    events.send(LifecycleEvent::WarmupFinished {
+        max_batch_total_tokens,
+    });
+
+    // Images are inlined in the payloads of multimodal models
+    let max_body_size = match config.as_ref().is_some_and(Config::is_multimodal) {
+        true => max_multimodal_body_size,
+        false => max_json_body_size,
+    };
 
+    let chat_template = ChatTemplate::from_configs(tokenizer_config, processor_config);
     let validation = Validation::new(
         validation_workers,
         tokenizer,
@@ -1646,16 +2554,73 @@ pub async fn run(
         max_input_tokens,
         max_total_tokens,
         grammar_support,
+        chat_template.clone(),
+        token_estimator,
+        shard_info.adapter_ids.clone(),
+        ValidationConfig {
+            supports_guidance: shard_info.supports_guidance,
+            ..validation_args.into_config()?
+        },
     );
+    validation.compile_grammar_registry().await?;
+    // Follow the adapters loaded on the running shards
+    if let Some(client) = adapters_client {
+        tokio::spawn(shard_adapters_task(
+            client,
+            shard_info.adapter_ids.clone().unwrap_or_default(),
+            validation.loaded_adapters(),
+            events.clone(),
+        ));
+    }
+
+    let rate_limiter = rate_limit_config.map(RateLimiter::spawn).transpose()?;
+    let provenance = provenance_signing_key
+        .map(|path| {
+            let model_sha = model_info.sha.clone().unwrap_or("unknown".to_string());
+            ProvenanceSigner::from_file(&path, model_sha)
+        })
+        .transpose()?;
+    let provenance_public_key = provenance.as_ref().map(ProvenanceSigner::public_key);
+    let adapter_acl = adapter_acl
+        .map(|path| AdapterAcl::from_file(&path))
+        .transpose()?;
+    let quotas = quota_config
+        .zip(quota_store)
+        .map(|(path, store)| QuotaTracker::spawn(&path, store))
+        .transpose()?;
+
+    let prompt_encryptor = prompt_log_key_provider
+        .map(|provider| {
+            tracing::info!(
+                "Encrypting the logged prompts with key {}",
+                provider.key_id()
+            );
+            PromptEncryptor::new(provider.as_ref())
+        })
+        .transpose()?;
 
     let infer = Infer::new(
         scheduler,
         validation,
         max_concurrent_requests,
-        tokenizer_config,
-        processor_config,
+        chat_template,
+        max_adapter_metric_labels,
+        slow_request_threshold,
+        validation_rejection_sample_rate,
+        rate_limiter.clone(),
+        provenance,
+        adapter_acl,
+        quotas.clone(),
+        StreamLimits {
+            max_duration: max_stream_duration,
+            max_bytes: max_stream_bytes,
+        },
+        prompt_encryptor,
     );
 
+    // Synthetic generation probe
+    let canary = canary_interval.map(|interval| Canary::spawn(infer.clone(), interval));
+
     // Duration buckets
     let duration_matcher = Matcher::Suffix(String::from("duration"));
     let n_duration_buckets = 35;
@@ -1669,11 +2634,15 @@ pub async fn run(
     }
     // Input Length buckets
     let input_length_matcher = Matcher::Full(String::from("tgi_request_input_length"));
+    let adapter_input_length_matcher =
+        Matcher::Full(String::from("tgi_adapter_request_input_length"));
     let input_length_buckets: Vec<f64> = (0..100)
         .map(|x| (max_input_tokens as f64 / 100.0) * (x + 1) as f64)
         .collect();
     // Generated tokens buckets
     let generated_tokens_matcher = Matcher::Full(String::from("tgi_request_generated_tokens"));
+    let adapter_generated_tokens_matcher =
+        Matcher::Full(String::from("tgi_adapter_request_generated_tokens"));
     let generated_tokens_buckets: Vec<f64> = (0..100)
         .map(|x| (max_total_tokens as f64 / 100.0) * (x + 1) as f64)
         .collect();
@@ -1695,24 +2664,33 @@ pub async fn run(
         .unwrap()
         .set_buckets_for_metric(input_length_matcher, &input_length_buckets)
         .unwrap()
+        .set_buckets_for_metric(adapter_input_length_matcher, &input_length_buckets)
+        .unwrap()
         .set_buckets_for_metric(generated_tokens_matcher, &generated_tokens_buckets)
         .unwrap()
+        .set_buckets_for_metric(adapter_generated_tokens_matcher, &generated_tokens_buckets)
+        .unwrap()
         .set_buckets_for_metric(max_new_tokens_matcher, &max_new_tokens_buckets)
         .unwrap()
         .set_buckets_for_metric(batch_size_matcher, &batch_size_buckets)
         .unwrap()
         .set_buckets_for_metric(skipped_matcher, &skipped_buckets)
-        .unwrap();
-    let prom_handle = builder
-        .install_recorder()
+        .unwrap()
+        // Identify the deployment on every metric
+        .add_global_label("model_id", model_info.model_id.clone())
+        .add_global_label(
+            "model_sha",
+            model_info
+                .sha
+                .clone()
+                .unwrap_or_else(|| "unknown".to_string()),
+        )
+        .add_global_label("router_version", env!("CARGO_PKG_VERSION"));
+    let prom_handle = telemetry::install_recorder(builder.build_recorder(), otlp_recorder)
         .expect("failed to install metrics recorder");
 
-    // CORS layer
-    let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
-    let cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers([http::header::CONTENT_TYPE])
-        .allow_origin(allow_origin);
+    // CORS policies of the public and admin routes
+    let cors_policies = CorsPolicies::from_file(cors_config.as_deref(), allow_origin)?;
 
     // Endpoint info
     let info = Info {
@@ -1736,6 +2714,7 @@ pub async fn run(
         version: env!("CARGO_PKG_VERSION"),
         sha: option_env!("VERGEN_GIT_SHA"),
         docker_label: option_env!("DOCKER_LABEL"),
+        provenance_public_key,
     };
 
     #[allow(unused_mut)] // mut is needed for conditional compilation
@@ -1794,6 +2773,35 @@ pub async fn run(
     // Configure Swagger UI
     let swagger_ui = SwaggerUi::new("/docs").url("/api-doc/openapi.json", doc);
 
+    // Bearer token or signed request authentication
+    let jwt = match jwt_config {
+        Some(jwt_config) => {
+            tracing::info!("Validating JWT bearer tokens");
+            Some(JwtValidator::spawn(jwt_config).await)
+        }
+        None => None,
+    };
+    let api_keys = api_key_config
+        .map(|api_key_config| {
+            tracing::info!("Validating API keys");
+            ApiKeyVerifier::spawn(api_key_config)
+        })
+        .transpose()?;
+    let hmac = hmac_config
+        .map(|hmac_config| {
+            tracing::info!("Validating HMAC request signatures");
+            HmacVerifier::spawn(hmac_config, max_body_size)
+        })
+        .transpose()?;
+    // Spawned before serving, the client certificates identify the callers
+    let tls_server = tls_config.map(TlsServer::spawn).transpose()?;
+    let client_tenants = tls_server.as_ref().and_then(TlsServer::client_tenants);
+    if client_tenants.is_some() {
+        tracing::info!("Requiring client certificates");
+    }
+    let authenticated =
+        jwt.is_some() || api_keys.is_some() || hmac.is_some() || client_tenants.is_some();
+
     // Define base and health routes
     let base_routes = Router::new()
         .route("/", post(compat_generate))
@@ -1805,10 +2813,29 @@ pub async fn run(
         .route("/v1/completions", post(completions))
         .route("/vertex", post(vertex_compatibility))
         .route("/tokenize", post(tokenize))
+        .route("/validate", post(validate))
+        .route("/grammar/compile", post(compile_grammar))
+        .route("/grammar/compile/:id", get(grammar_status))
         .route("/health", get(health))
+        .route("/ready", get(ready))
         .route("/ping", get(health))
         .route("/metrics", get(metrics));
 
+    // Admin routes, only served to the callers authenticated with an admin credential
+    let admin_routes = if authenticated {
+        Router::new()
+            .route("/usage", get(usage))
+            .route("/admin/events", get(admin_events))
+            .route(
+                "/admin/maintenance",
+                get(admin_maintenance).put(admin_update_maintenance),
+            )
+            .route("/admin/limits", get(admin_limits).put(admin_update_limits))
+    } else {
+        tracing::warn!("The admin routes and `/usage` are disabled without authentication");
+        Router::new()
+    };
+
     // Conditional AWS Sagemaker route
     let aws_sagemaker_route = if messages_api_enabled {
         Router::new().route("/invocations", post(chat_completions)) // Use 'chat_completions' for OAI_ENABLED
@@ -1823,6 +2850,7 @@ pub async fn run(
     let mut app = Router::new()
         .merge(swagger_ui)
         .merge(base_routes)
+        .merge(admin_routes)
         .merge(aws_sagemaker_route);
 
     #[cfg(feature = "google")]
@@ -1860,16 +2888,66 @@ pub async fn run(
             );
     }
 
+    // Quota headers, the limits are enforced by `Infer`
+    if let Some(rate_limiter) = rate_limiter {
+        app = app.layer(middleware::from_fn_with_state(
+            rate_limiter,
+            rate_limit_middleware,
+        ));
+    }
+
+    // Quota headers, the quotas are enforced by `Infer`
+    if let Some(quotas) = quotas.clone() {
+        app = app.layer(middleware::from_fn_with_state(quotas, quota_middleware));
+    }
+
+    if authenticated {
+        app = app.layer(middleware::from_fn_with_state(
+            Authenticator {
+                jwt,
+                api_keys,
+                hmac,
+                client_tenants,
+                protect_metrics,
+                admin_subjects: Arc::new(admin_subjects.into_iter().collect()),
+            },
+            auth_middleware,
+        ));
+    }
+
+    // Admin actions, with the caller identity set by the authentication
+    app = app.layer(middleware::from_fn(admin_audit_middleware));
+
+    // Client networks, checked before the authentication
+    if ip_filter.is_enabled() {
+        app = app.layer(middleware::from_fn_with_state(
+            IpFilter::new(ip_filter),
+            ip_filter_middleware,
+        ));
+    }
+
     // add layers after routes
     app = app
         .layer(Extension(info))
         .layer(Extension(health_ext.clone()))
+        .layer(Extension(canary))
+        .layer(Extension(events))
         .layer(Extension(compat_return_full_text))
         .layer(Extension(infer))
         .layer(Extension(compute_type))
         .layer(Extension(prom_handle.clone()))
+        .layer(DefaultBodyLimit::max(max_body_size))
+        .layer(middleware::from_fn_with_state(
+            max_body_size,
+            body_limit_middleware,
+        ))
+        .layer(middleware::from_fn(route_middleware))
+        .layer(middleware::from_fn(request_id_middleware))
         .layer(OtelAxumLayer::default())
-        .layer(cors_layer);
+        .layer(middleware::from_fn_with_state(
+            cors_policies,
+            cors_middleware,
+        ));
 
     tracing::info!("Connected");
 
@@ -1893,14 +2971,127 @@ pub async fn run(
         // Run server
 
         let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-        axum::serve(listener, app)
-            .with_graceful_shutdown(shutdown_signal())
-            .await
-            .map_err(|err| WebServerError::Axum(Box::new(err)))?;
+        match tls_server {
+            Some(tls_server) => {
+                tracing::info!("Serving HTTPS");
+                tls_server.serve(listener, app, shutdown_signal()).await;
+            }
+            None => {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .map_err(|err| WebServerError::Axum(Box::new(err)))?;
+            }
+        }
+    }
+    // Keep the usage of the last requests
+    if let Some(quotas) = quotas {
+        quotas.flush();
     }
     Ok(())
 }
 
+/// Warmup of the shards, common to the v2 and v3 clients
+trait WarmupClient: Clone {
+    async fn warmup(
+        &mut self,
+        max_input_length: u32,
+        max_prefill_tokens: u32,
+        max_total_tokens: u32,
+        max_batch_size: Option<usize>,
+    ) -> Result<Option<u32>, ClientError>;
+
+    async fn clear_cache(&mut self) -> Result<(), ClientError>;
+}
+
+impl WarmupClient for v2::ShardedClient {
+    async fn warmup(
+        &mut self,
+        max_input_length: u32,
+        max_prefill_tokens: u32,
+        max_total_tokens: u32,
+        max_batch_size: Option<usize>,
+    ) -> Result<Option<u32>, ClientError> {
+        v2::ShardedClient::warmup(
+            self,
+            max_input_length,
+            max_prefill_tokens,
+            max_total_tokens,
+            max_batch_size,
+        )
+        .await
+    }
+
+    async fn clear_cache(&mut self) -> Result<(), ClientError> {
+        v2::ShardedClient::clear_cache(self, None).await
+    }
+}
+
+impl WarmupClient for v3::ShardedClient {
+    async fn warmup(
+        &mut self,
+        max_input_length: u32,
+        max_prefill_tokens: u32,
+        max_total_tokens: u32,
+        max_batch_size: Option<usize>,
+    ) -> Result<Option<u32>, ClientError> {
+        v3::ShardedClient::warmup(
+            self,
+            max_input_length,
+            max_prefill_tokens,
+            max_total_tokens,
+            max_batch_size,
+        )
+        .await
+    }
+
+    async fn clear_cache(&mut self) -> Result<(), ClientError> {
+        v3::ShardedClient::clear_cache(self, None).await
+    }
+}
+
+/// Warmup the shards and return the max batch total tokens they support. When the model does not
+/// report it, it is probed in the `probe_tokens` range if set.
+async fn warmup_shards<C: WarmupClient>(
+    client: &mut C,
+    max_input_tokens: u32,
+    max_batch_prefill_tokens: u32,
+    max_total_tokens: u32,
+    max_batch_size: Option<usize>,
+    probe_tokens: Option<(u32, u32)>,
+) -> Result<Option<u32>, ClientError> {
+    let max_supported_batch_total_tokens = client
+        .warmup(
+            max_input_tokens,
+            max_batch_prefill_tokens,
+            max_total_tokens,
+            max_batch_size,
+        )
+        .await?;
+    match (max_supported_batch_total_tokens, probe_tokens) {
+        (None, Some((min_probe_tokens, max_probe_tokens))) => {
+            let max_batch_total_tokens =
+                probe::probe_max_batch_total_tokens(min_probe_tokens, max_probe_tokens, |tokens| {
+                    let mut client = client.clone();
+                    async move {
+                        let warmup = client
+                            .warmup(max_input_tokens, tokens, max_total_tokens, max_batch_size)
+                            .await;
+                        // Free the memory of the probe, even if it failed
+                        client.clear_cache().await?;
+                        warmup.map(|_| ())
+                    }
+                })
+                .await?;
+            Ok(Some(max_batch_total_tokens))
+        }
+        (max_supported, _) => Ok(max_supported),
+    }
+}
+
 /// Shutdown signal handler
 async fn shutdown_signal() {
     let ctrl_c = async {
@@ -1932,13 +3123,23 @@ async fn shutdown_signal() {
 /// Convert to Axum supported formats
 impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
     fn from(err: InferError) -> Self {
+        error_metrics(&current_route(), &err);
         let status_code = match err {
             InferError::GenerationError(_) => StatusCode::FAILED_DEPENDENCY,
             InferError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::ValidationError(ValidationError::GrammarThrottled(_)) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            InferError::ValidationError(
+                ValidationError::InputTooLarge(_, _) | ValidationError::ChunkTooLong(_, _),
+            ) => StatusCode::PAYLOAD_TOO_LARGE,
             InferError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             InferError::IncompleteGeneration => StatusCode::INTERNAL_SERVER_ERROR,
             InferError::TemplateError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             InferError::ToolError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            InferError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::AdapterNotAllowed(_) => StatusCode::FORBIDDEN,
+            InferError::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
         };
 
         (
@@ -1976,4 +3177,32 @@ pub enum WebServerError {
     NotEnoughMemory(usize),
     #[error("Axum error: {0}")]
     Axum(#[from] axum::BoxError),
+    #[error("{0}")]
+    RateLimitConfig(#[from] RateLimitConfigError),
+    #[error("{0}")]
+    QuotaConfig(#[from] QuotaConfigError),
+    #[error("{0}")]
+    CorsConfig(#[from] CorsConfigError),
+    #[error("{0}")]
+    Provenance(#[from] ProvenanceError),
+    #[error("{0}")]
+    PromptLog(#[from] PromptLogError),
+    #[error("{0}")]
+    AdapterAcl(#[from] AdapterAclError),
+    #[error("{0}")]
+    HmacKeys(#[from] HmacKeysError),
+    #[error("{0}")]
+    ApiKeys(#[from] ApiKeysError),
+    #[error("{0}")]
+    InputPolicy(#[from] InputPolicyError),
+    #[error("{0}")]
+    DefaultParameters(#[from] DefaultParametersError),
+    #[error("{0}")]
+    FsmCache(#[from] FsmCacheError),
+    #[error("{0}")]
+    GrammarRegistry(#[from] GrammarRegistryError),
+    #[error("{0}")]
+    MultimodalLimits(#[from] MultimodalLimitsError),
+    #[error("{0}")]
+    Tls(#[from] TlsError),
 }
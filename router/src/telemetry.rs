@@ -0,0 +1,460 @@
+/// Push router metrics to an OpenTelemetry collector and annotate Prometheus histograms
+/// with trace exemplars
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SetRecorderError, SharedString, Unit,
+};
+use metrics_exporter_prometheus::{PrometheusHandle, PrometheusRecorder};
+use metrics_util::layers::FanoutBuilder;
+use once_cell::sync::Lazy;
+use opentelemetry::metrics::{self as otel, Meter, MetricsError};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Number of exemplars kept per histogram
+const MAX_EXEMPLARS: usize = 64;
+
+/// Latest exemplars of each histogram, by name and label set
+static EXEMPLARS: Lazy<Mutex<HashMap<ExemplarKey, VecDeque<Exemplar>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Name of a histogram and its labels, sorted and escaped as in the exposition format
+type ExemplarKey = (String, String);
+
+#[derive(Clone, Debug, PartialEq)]
+struct Exemplar {
+    value: f64,
+    trace_id: String,
+    timestamp: f64,
+}
+
+/// Record the trace id of `span` as an exemplar of the histogram `name` with the given `labels`
+pub(crate) fn record_exemplar(name: &str, labels: &[(&str, &str)], value: f64, span: &Span) {
+    let context = span.context();
+    let span_ref = context.span();
+    let span_context = span_ref.span_context();
+    if !span_context.is_valid() {
+        return;
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let labels: Vec<(&str, String)> = labels
+        .iter()
+        .map(|(name, value)| (*name, escape_label_value(value)))
+        .collect();
+    let key = exemplar_key(
+        name,
+        labels.iter().map(|(name, value)| (*name, value.as_str())),
+    );
+
+    let mut exemplars = EXEMPLARS.lock().unwrap();
+    let exemplars = exemplars.entry(key).or_default();
+    if exemplars.len() >= MAX_EXEMPLARS {
+        exemplars.pop_front();
+    }
+    exemplars.push_back(Exemplar {
+        value,
+        trace_id: span_context.trace_id().to_string(),
+        timestamp,
+    });
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Key of a histogram, its labels are escaped and `le` is not part of it
+fn exemplar_key<'a>(name: &str, labels: impl Iterator<Item = (&'a str, &'a str)>) -> ExemplarKey {
+    let mut labels: Vec<(&str, &str)> = labels.filter(|(name, _)| *name != "le").collect();
+    labels.sort();
+    let labels: Vec<String> = labels
+        .into_iter()
+        .map(|(name, value)| format!("{name}=\"{value}\""))
+        .collect();
+    (name.to_string(), labels.join(","))
+}
+
+/// Render the Prometheus text `metrics` in the OpenMetrics format, with the recorded exemplars
+/// attached to the histogram buckets
+pub(crate) fn render_openmetrics(metrics: &str) -> String {
+    let exemplars = EXEMPLARS.lock().unwrap();
+    let mut output = add_exemplars(&rename_counters(metrics), &exemplars);
+    output.push_str("# EOF\n");
+    output
+}
+
+/// Name the counter families without the `_total` suffix and their samples with it, as
+/// OpenMetrics requires
+fn rename_counters(metrics: &str) -> String {
+    let counters: HashSet<&str> = metrics
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .filter_map(|line| line.strip_suffix(" counter"))
+        .collect();
+    let family = |name: &str| name.strip_suffix("_total").unwrap_or(name).to_string();
+
+    let mut output = String::with_capacity(metrics.len());
+    for line in metrics.lines() {
+        let line = match line.strip_prefix("# ") {
+            Some(comment) => match comment.split_once(' ') {
+                Some((keyword @ ("HELP" | "TYPE"), rest)) => {
+                    let (name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                    match counters.contains(name) {
+                        true => format!("# {keyword} {} {rest}", family(name)),
+                        false => line.to_string(),
+                    }
+                }
+                _ => line.to_string(),
+            },
+            None => {
+                let end = line.find(['{', ' ']).unwrap_or(line.len());
+                let (name, rest) = line.split_at(end);
+                match counters.contains(name) {
+                    true => format!("{}_total{rest}", family(name)),
+                    false => line.to_string(),
+                }
+            }
+        };
+        output.push_str(&line);
+        output.push('\n');
+    }
+    output
+}
+
+/// Append to every histogram bucket the latest exemplar of its label set falling into it
+fn add_exemplars(metrics: &str, exemplars: &HashMap<ExemplarKey, VecDeque<Exemplar>>) -> String {
+    let mut output = String::with_capacity(metrics.len());
+    // Upper bound of the previous bucket of the current histogram
+    let mut lower_bound = f64::NEG_INFINITY;
+
+    for line in metrics.lines() {
+        output.push_str(line);
+
+        if let Some((key, bound)) = parse_bucket(line) {
+            if let Some(exemplar) = exemplars.get(&key).and_then(|exemplars| {
+                exemplars
+                    .iter()
+                    .rev()
+                    .find(|e| e.value > lower_bound && e.value <= bound)
+            }) {
+                output.push_str(&format!(
+                    " # {{trace_id=\"{}\"}} {} {}",
+                    exemplar.trace_id, exemplar.value, exemplar.timestamp
+                ));
+            }
+            lower_bound = match bound {
+                f64::INFINITY => f64::NEG_INFINITY,
+                bound => bound,
+            };
+        } else {
+            lower_bound = f64::NEG_INFINITY;
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// Parse the histogram key and upper bound of a `name_bucket{...,le="bound"} count` line
+fn parse_bucket(line: &str) -> Option<(ExemplarKey, f64)> {
+    let (name, labels) = line.split_once("_bucket{")?;
+    let labels = parse_labels(labels)?;
+    let bound = match labels.iter().find(|(name, _)| *name == "le")?.1 {
+        "+Inf" => f64::INFINITY,
+        bound => bound.parse().ok()?,
+    };
+    Some((exemplar_key(name, labels.into_iter()), bound))
+}
+
+/// Names and escaped values of the labels of a sample, from the text following its `{`
+fn parse_labels(mut labels: &str) -> Option<Vec<(&str, &str)>> {
+    let mut parsed = Vec::new();
+    loop {
+        labels = labels.trim_start_matches(',');
+        if labels.starts_with('}') {
+            return Some(parsed);
+        }
+        let (name, rest) = labels.split_once("=\"")?;
+        // The value ends at the first quote that is not escaped
+        let mut escaped = false;
+        let end = rest.char_indices().find_map(|(i, c)| {
+            let end = c == '"' && !escaped;
+            escaped = c == '\\' && !escaped;
+            end.then_some(i)
+        })?;
+        parsed.push((name.trim(), &rest[..end]));
+        labels = &rest[end + 1..];
+    }
+}
+
+/// Build the recorder pushing all router metrics to `otlp_endpoint` every `interval`
+pub fn init_otlp_metrics(
+    otlp_endpoint: String,
+    otlp_service_name: String,
+    interval: Duration,
+) -> Result<OtlpRecorder, MetricsError> {
+    opentelemetry_otlp::new_pipeline()
+        .metrics(opentelemetry::runtime::Tokio)
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(otlp_endpoint),
+        )
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            otlp_service_name,
+        )]))
+        .with_period(interval)
+        .build()?;
+
+    Ok(OtlpRecorder::new(global::meter("text-generation-router")))
+}
+
+/// Install the global recorder of the router metrics, rendered by the returned Prometheus
+/// handle and also pushed over OTLP with `otlp`
+pub(crate) fn install_recorder(
+    prometheus: PrometheusRecorder,
+    otlp: Option<OtlpRecorder>,
+) -> Result<PrometheusHandle, SetRecorderError<impl Recorder>> {
+    let handle = prometheus.handle();
+    let mut fanout = FanoutBuilder::default().add_recorder(prometheus);
+    if let Some(otlp) = otlp {
+        fanout = fanout.add_recorder(otlp);
+    }
+    metrics::set_global_recorder(fanout.build())?;
+    Ok(handle)
+}
+
+/// Forward `metrics` records to OpenTelemetry instruments
+pub struct OtlpRecorder {
+    meter: Meter,
+    counters: Mutex<HashMap<Key, Arc<OtlpCounter>>>,
+    gauges: Mutex<HashMap<Key, Arc<OtlpGauge>>>,
+    histograms: Mutex<HashMap<Key, Arc<OtlpHistogram>>>,
+}
+
+impl OtlpRecorder {
+    fn new(meter: Meter) -> Self {
+        Self {
+            meter,
+            counters: Mutex::new(HashMap::new()),
+            gauges: Mutex::new(HashMap::new()),
+            histograms: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+fn attributes(key: &Key) -> Vec<KeyValue> {
+    key.labels()
+        .map(|label| KeyValue::new(label.key().to_string(), label.value().to_string()))
+        .collect()
+}
+
+impl Recorder for OtlpRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let counter = self
+            .counters
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                Arc::new(OtlpCounter {
+                    counter: self.meter.u64_counter(key.name().to_string()).init(),
+                    attributes: attributes(key),
+                })
+            })
+            .clone();
+        Counter::from_arc(counter)
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let gauge = self
+            .gauges
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                Arc::new(OtlpGauge {
+                    gauge: self
+                        .meter
+                        .f64_up_down_counter(key.name().to_string())
+                        .init(),
+                    value: Mutex::new(0.0),
+                    attributes: attributes(key),
+                })
+            })
+            .clone();
+        Gauge::from_arc(gauge)
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let histogram = self
+            .histograms
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                Arc::new(OtlpHistogram {
+                    histogram: self.meter.f64_histogram(key.name().to_string()).init(),
+                    attributes: attributes(key),
+                })
+            })
+            .clone();
+        Histogram::from_arc(histogram)
+    }
+}
+
+struct OtlpCounter {
+    counter: otel::Counter<u64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl CounterFn for OtlpCounter {
+    fn increment(&self, value: u64) {
+        self.counter.add(value, &self.attributes);
+    }
+
+    fn absolute(&self, _value: u64) {
+        // OpenTelemetry counters are monotonic sums and cannot be set
+    }
+}
+
+/// Gauges are exported as up-down counters, setting the gauge adds the difference
+/// with its previous value
+struct OtlpGauge {
+    gauge: otel::UpDownCounter<f64>,
+    value: Mutex<f64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl GaugeFn for OtlpGauge {
+    fn increment(&self, value: f64) {
+        *self.value.lock().unwrap() += value;
+        self.gauge.add(value, &self.attributes);
+    }
+
+    fn decrement(&self, value: f64) {
+        *self.value.lock().unwrap() -= value;
+        self.gauge.add(-value, &self.attributes);
+    }
+
+    fn set(&self, value: f64) {
+        let mut current = self.value.lock().unwrap();
+        self.gauge.add(value - *current, &self.attributes);
+        *current = value;
+    }
+}
+
+struct OtlpHistogram {
+    histogram: otel::Histogram<f64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl HistogramFn for OtlpHistogram {
+    fn record(&self, value: f64) {
+        self.histogram.record(value, &self.attributes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_exemplars() {
+        let metrics = "# TYPE tgi_request_duration histogram
+tgi_request_duration_bucket{le=\"0.1\"} 1
+tgi_request_duration_bucket{le=\"1\"} 2
+tgi_request_duration_bucket{le=\"+Inf\"} 3
+tgi_request_duration_sum 5.55
+tgi_request_duration_count 3
+tgi_request_duration_bucket{priority=\"batch\",le=\"0.1\"} 0
+tgi_request_duration_bucket{priority=\"batch\",le=\"1\"} 1
+tgi_request_duration_bucket{priority=\"batch\",le=\"+Inf\"} 1
+";
+        let exemplar = |value: f64, trace_id: &str| Exemplar {
+            value,
+            trace_id: trace_id.to_string(),
+            timestamp: 1.0,
+        };
+        let exemplars = HashMap::from([
+            (
+                exemplar_key("tgi_request_duration", [].into_iter()),
+                VecDeque::from([exemplar(0.05, "a"), exemplar(0.5, "b"), exemplar(0.7, "c")]),
+            ),
+            (
+                exemplar_key("tgi_request_duration", [("priority", "batch")].into_iter()),
+                VecDeque::from([exemplar(0.3, "d")]),
+            ),
+        ]);
+
+        // The exemplars of each label set only go to its buckets
+        assert_eq!(
+            add_exemplars(metrics, &exemplars),
+            "# TYPE tgi_request_duration histogram
+tgi_request_duration_bucket{le=\"0.1\"} 1 # {trace_id=\"a\"} 0.05 1
+tgi_request_duration_bucket{le=\"1\"} 2 # {trace_id=\"c\"} 0.7 1
+tgi_request_duration_bucket{le=\"+Inf\"} 3
+tgi_request_duration_sum 5.55
+tgi_request_duration_count 3
+tgi_request_duration_bucket{priority=\"batch\",le=\"0.1\"} 0
+tgi_request_duration_bucket{priority=\"batch\",le=\"1\"} 1 # {trace_id=\"d\"} 0.3 1
+tgi_request_duration_bucket{priority=\"batch\",le=\"+Inf\"} 1
+"
+        );
+    }
+
+    #[test]
+    fn test_parse_labels() {
+        assert_eq!(
+            parse_labels(r#"route="/generate",err="a \"b\", c",le="1"} 2"#),
+            Some(vec![
+                ("route", "/generate"),
+                ("err", r#"a \"b\", c"#),
+                ("le", "1")
+            ])
+        );
+        assert_eq!(parse_labels("} 2"), Some(vec![]));
+        assert_eq!(parse_labels(r#"route="/generate} 2"#), None);
+    }
+
+    #[test]
+    fn test_rename_counters() {
+        let metrics = "# HELP tgi_request_success Successful requests
+# TYPE tgi_request_success counter
+tgi_request_success 3
+# TYPE tgi_stream_total counter
+tgi_stream_total{limit=\"tokens\"} 1
+# TYPE tgi_queue_size gauge
+tgi_queue_size 2
+";
+        assert_eq!(
+            rename_counters(metrics),
+            "# HELP tgi_request_success Successful requests
+# TYPE tgi_request_success counter
+tgi_request_success_total 3
+# TYPE tgi_stream counter
+tgi_stream_total{limit=\"tokens\"} 1
+# TYPE tgi_queue_size gauge
+tgi_queue_size 2
+"
+        );
+    }
+}
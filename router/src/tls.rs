@@ -0,0 +1,407 @@
+/// TLS termination of the router listener
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::{service_fn, Service};
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use std::collections::HashMap;
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::{VerifierBuilderError, WebPkiClientVerifier};
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+/// Interval between two checks of the certificate and key modification times
+const CERTIFICATE_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connections that did not complete the handshake in time are closed
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// PEM encoded certificate chain and private key of the listener
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// PEM encoded CAs of the client certificates, clients must present a certificate if set
+    pub client_ca_path: Option<String>,
+    /// JSON file mapping client certificate common names to tenants
+    pub client_tenants_path: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("could not read `{0}`: {1}")]
+    Io(String, std::io::Error),
+    #[error("no certificate found in `{0}`")]
+    NoCertificate(String),
+    #[error("no private key found in `{0}`")]
+    NoPrivateKey(String),
+    #[error("invalid TLS configuration: {0}")]
+    Rustls(#[from] tokio_rustls::rustls::Error),
+    #[error("invalid client CA: {0}")]
+    ClientCa(#[from] VerifierBuilderError),
+    #[error("invalid client tenants: {0}")]
+    ClientTenants(#[from] serde_json::Error),
+}
+
+/// Modification times of the certificate, of the key and of the client CA
+type Modified = Vec<SystemTime>;
+
+/// Identity of the client certificate, added to the request extensions
+#[derive(Clone, Debug)]
+pub(crate) struct ClientCertificate {
+    pub common_name: Option<String>,
+}
+
+/// HTTPS server whose certificate is reloaded when the files change
+#[derive(Clone)]
+pub(crate) struct TlsServer {
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+    /// Tenants of the client certificate common names, if client certificates are required
+    client_tenants: Option<Arc<HashMap<String, String>>>,
+}
+
+impl TlsServer {
+    /// Load the certificate and key, and reload them whenever the files change
+    pub(crate) fn spawn(tls_config: TlsConfig) -> Result<Self, TlsError> {
+        let (config, modified) = load_config(&tls_config)?;
+        let client_tenants = match (&tls_config.client_ca_path, &tls_config.client_tenants_path) {
+            (Some(_), Some(path)) => {
+                let file = std::fs::read(path).map_err(|err| TlsError::Io(path.clone(), err))?;
+                Some(Arc::new(serde_json::from_slice(&file)?))
+            }
+            (Some(_), None) => Some(Arc::new(HashMap::new())),
+            (None, _) => None,
+        };
+        let server = Self {
+            config: Arc::new(RwLock::new(config)),
+            client_tenants,
+        };
+        tokio::spawn(server.clone().reload_task(tls_config, modified));
+        Ok(server)
+    }
+
+    async fn reload_task(self, tls_config: TlsConfig, mut modified: Modified) {
+        let mut ticker = tokio::time::interval(CERTIFICATE_RELOAD_INTERVAL);
+        loop {
+            ticker.tick().await;
+            match modified_times(&tls_config) {
+                Ok(times) if times == modified => continue,
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::warn!("Could not stat the TLS certificate: {err}");
+                    continue;
+                }
+            }
+            match load_config(&tls_config) {
+                Ok((config, times)) => {
+                    modified = times;
+                    *self.config.write().unwrap() = config;
+                    metrics::counter!("tgi_tls_certificate_reload").increment(1);
+                    tracing::info!("Reloaded the TLS certificate");
+                }
+                // The files may be replaced one after the other, retry on the next tick
+                Err(err) => tracing::warn!("Keeping the previous TLS certificate: {err}"),
+            }
+        }
+    }
+
+    /// Tenants of the client certificate common names, if client certificates are required
+    pub(crate) fn client_tenants(&self) -> Option<Arc<HashMap<String, String>>> {
+        self.client_tenants.clone()
+    }
+
+    /// Acceptor using the latest certificate
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.read().unwrap().clone())
+    }
+
+    /// Serve `app` over TLS until `shutdown` completes, then wait for the open connections
+    pub(crate) async fn serve(
+        self,
+        listener: TcpListener,
+        app: Router,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let (shutdown_sender, shutdown_receiver) = watch::channel(());
+        // Dropped by every connection task when it ends
+        let (close_sender, close_receiver) = watch::channel(());
+        tokio::pin!(shutdown);
+
+        loop {
+            let (stream, remote_address) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        tracing::warn!("Could not accept connection: {err}");
+                        continue;
+                    }
+                },
+                _ = &mut shutdown => break,
+            };
+
+            let acceptor = self.acceptor();
+            let service = TowerToHyperService::new(app.clone());
+            let mut shutdown_receiver = shutdown_receiver.clone();
+            let close_receiver = close_receiver.clone();
+            tokio::spawn(async move {
+                let stream = match tokio::time::timeout(
+                    TLS_HANDSHAKE_TIMEOUT,
+                    acceptor.accept(stream),
+                )
+                .await
+                {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        metrics::counter!("tgi_tls_handshake_failure").increment(1);
+                        tracing::debug!("TLS handshake failed: {err}");
+                        return;
+                    }
+                    Err(_) => {
+                        metrics::counter!("tgi_tls_handshake_failure").increment(1);
+                        tracing::debug!("TLS handshake timed out");
+                        return;
+                    }
+                };
+
+                // Identity of the client, shared by all the requests of the connection
+                let client_certificate = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(|certificates| certificates.first())
+                    .map(|certificate| ClientCertificate {
+                        common_name: common_name(certificate),
+                    });
+                let service = service_fn(move |mut request: Request<Incoming>| {
+                    request.extensions_mut().insert(ConnectInfo(remote_address));
+                    if let Some(client_certificate) = &client_certificate {
+                        request.extensions_mut().insert(client_certificate.clone());
+                    }
+                    service.call(request)
+                });
+
+                let connection = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .with_upgrades();
+                tokio::pin!(connection);
+                tokio::select! {
+                    _ = connection.as_mut() => {}
+                    _ = shutdown_receiver.changed() => {
+                        connection.as_mut().graceful_shutdown();
+                        let _ = connection.await;
+                    }
+                }
+                drop(close_receiver);
+            });
+        }
+
+        drop(close_receiver);
+        drop(shutdown_receiver);
+        let _ = shutdown_sender.send(());
+        close_sender.closed().await;
+    }
+}
+
+fn modified_times(tls_config: &TlsConfig) -> Result<Modified, TlsError> {
+    [&tls_config.cert_path, &tls_config.key_path]
+        .into_iter()
+        .chain(&tls_config.client_ca_path)
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .map_err(|err| TlsError::Io(path.to_string(), err))
+        })
+        .collect()
+}
+
+fn read_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let file = File::open(path).map_err(|err| TlsError::Io(path.to_string(), err))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| TlsError::Io(path.to_string(), err))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_string()));
+    }
+    Ok(certs)
+}
+
+fn load_config(tls_config: &TlsConfig) -> Result<(Arc<ServerConfig>, Modified), TlsError> {
+    let modified = modified_times(tls_config)?;
+    let certs = read_certificates(&tls_config.cert_path)?;
+    let key_file = File::open(&tls_config.key_path)
+        .map_err(|err| TlsError::Io(tls_config.key_path.clone(), err))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+        .map_err(|err| TlsError::Io(tls_config.key_path.clone(), err))?
+        .ok_or_else(|| TlsError::NoPrivateKey(tls_config.key_path.clone()))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &tls_config.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(client_ca_path)? {
+                roots.add(certificate)?;
+            }
+            builder
+                .with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok((Arc::new(config), modified))
+}
+
+/// Common name of the subject of a DER encoded certificate
+fn common_name(certificate: &[u8]) -> Option<String> {
+    let (_, certificate, _) = read_der(certificate)?;
+    let (_, mut tbs_certificate, _) = read_der(certificate)?;
+    // Optional explicit version
+    let (tag, _, rest) = read_der(tbs_certificate)?;
+    if tag == 0xa0 {
+        tbs_certificate = rest;
+    }
+    // Serial number, signature algorithm, issuer and validity
+    for _ in 0..4 {
+        tbs_certificate = read_der(tbs_certificate)?.2;
+    }
+    // The subject is a sequence of sets of (type, value) attributes
+    let (_, mut subject, _) = read_der(tbs_certificate)?;
+    while !subject.is_empty() {
+        let (_, mut set, rest) = read_der(subject)?;
+        subject = rest;
+        while !set.is_empty() {
+            let (_, attribute, rest) = read_der(set)?;
+            set = rest;
+            let (_, oid, value) = read_der(attribute)?;
+            if oid == COMMON_NAME_OID {
+                let (_, value, _) = read_der(value)?;
+                return String::from_utf8(value.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+/// 2.5.4.3
+const COMMON_NAME_OID: [u8; 3] = [0x55, 0x04, 0x03];
+
+/// Read a DER element, returns its tag, its contents and the remaining bytes
+fn read_der(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&length, mut data) = data.split_first()?;
+    let length = if length < 0x80 {
+        length as usize
+    } else {
+        let size = (length & 0x7f) as usize;
+        if size == 0 || size > 4 || data.len() < size {
+            return None;
+        }
+        let (length, rest) = data.split_at(size);
+        data = rest;
+        length
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize)
+    };
+    if data.len() < length {
+        return None;
+    }
+    let (contents, rest) = data.split_at(length);
+    Some((tag, contents, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_config_errors() {
+        let dir = std::env::temp_dir().join(format!("tgi-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+        let empty = empty.to_str().unwrap().to_string();
+
+        let missing = TlsConfig {
+            cert_path: dir.join("missing.pem").to_str().unwrap().to_string(),
+            key_path: empty.clone(),
+            client_ca_path: None,
+            client_tenants_path: None,
+        };
+        assert!(matches!(load_config(&missing), Err(TlsError::Io(_, _))));
+
+        let no_certificate = TlsConfig {
+            cert_path: empty.clone(),
+            key_path: empty,
+            client_ca_path: None,
+            client_tenants_path: None,
+        };
+        assert!(matches!(
+            load_config(&no_certificate),
+            Err(TlsError::NoCertificate(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn der(tag: u8, contents: &[&[u8]]) -> Vec<u8> {
+        let contents = contents.concat();
+        let mut element = vec![tag];
+        if contents.len() < 0x80 {
+            element.push(contents.len() as u8);
+        } else {
+            element.push(0x82);
+            element.extend_from_slice(&(contents.len() as u16).to_be_bytes());
+        }
+        element.extend(contents);
+        element
+    }
+
+    #[test]
+    fn test_common_name() {
+        let attribute = |oid: &[u8], value: &str| {
+            der(
+                0x31,
+                &[&der(
+                    0x30,
+                    &[&der(0x06, &[oid]), &der(0x0c, &[value.as_bytes()])],
+                )],
+            )
+        };
+        let subject = der(
+            0x30,
+            &[
+                &attribute(&[0x55, 0x04, 0x0a], "Acme"),
+                &attribute(&COMMON_NAME_OID, "billing-service"),
+            ],
+        );
+        // Long enough to need a multi-byte length
+        let issuer = der(0x30, &[&attribute(&[0x55, 0x04, 0x0a], &"CA".repeat(100))]);
+        let tbs_certificate = der(
+            0x30,
+            &[
+                &der(0xa0, &[&der(0x02, &[&[2]])]),
+                &der(0x02, &[&[1]]),
+                &der(0x30, &[]),
+                &issuer,
+                &der(0x30, &[]),
+                &subject,
+            ],
+        );
+        let certificate = der(0x30, &[&tbs_certificate, &der(0x30, &[])]);
+        assert_eq!(
+            common_name(&certificate).as_deref(),
+            Some("billing-service")
+        );
+        assert_eq!(common_name(&certificate[..20]), None);
+    }
+}
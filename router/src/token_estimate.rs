@@ -0,0 +1,160 @@
+/// Estimate the number of tokens of the inputs when the model has no fast tokenizer
+use std::str::FromStr;
+
+/// Writing systems that can override the chars-per-token ratio
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Hebrew,
+    Arabic,
+    Devanagari,
+    Thai,
+    Hangul,
+    /// Hiragana and Katakana
+    Kana,
+    /// CJK ideographs
+    Han,
+}
+
+impl Script {
+    /// Script of `c`, `None` for digits, punctuation, whitespace and the other scripts
+    fn of(c: char) -> Option<Self> {
+        match c {
+            'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}' => {
+                Some(Script::Latin)
+            }
+            '\u{0370}'..='\u{03FF}' | '\u{1F00}'..='\u{1FFF}' => Some(Script::Greek),
+            '\u{0400}'..='\u{052F}' => Some(Script::Cyrillic),
+            '\u{0590}'..='\u{05FF}' => Some(Script::Hebrew),
+            '\u{0600}'..='\u{06FF}' | '\u{0750}'..='\u{077F}' => Some(Script::Arabic),
+            '\u{0900}'..='\u{097F}' => Some(Script::Devanagari),
+            '\u{0E00}'..='\u{0E7F}' => Some(Script::Thai),
+            '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' | '\u{AC00}'..='\u{D7AF}' => {
+                Some(Script::Hangul)
+            }
+            '\u{3040}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' => Some(Script::Kana),
+            '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}' => {
+                Some(Script::Han)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Script {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "latin" => Ok(Script::Latin),
+            "greek" => Ok(Script::Greek),
+            "cyrillic" => Ok(Script::Cyrillic),
+            "hebrew" => Ok(Script::Hebrew),
+            "arabic" => Ok(Script::Arabic),
+            "devanagari" => Ok(Script::Devanagari),
+            "thai" => Ok(Script::Thai),
+            "hangul" => Ok(Script::Hangul),
+            "kana" => Ok(Script::Kana),
+            "han" => Ok(Script::Han),
+            script => Err(format!("unknown script `{script}`")),
+        }
+    }
+}
+
+/// Chars-per-token ratio of a script, parsed from `<script>=<ratio>`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScriptRatio {
+    pub script: Script,
+    pub chars_per_token: f32,
+}
+
+impl FromStr for ScriptRatio {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (script, chars_per_token) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected `<script>=<chars per token>`, given `{value}`"))?;
+        let chars_per_token = chars_per_token
+            .trim()
+            .parse()
+            .map_err(|err| format!("invalid chars per token `{chars_per_token}`: {err}"))?;
+        Ok(Self {
+            script: script.parse()?,
+            chars_per_token,
+        })
+    }
+}
+
+/// Token count estimate from the number of characters of each script
+#[derive(Clone, Debug)]
+pub struct TokenEstimator {
+    /// Ratio of the characters without an override
+    chars_per_token: f32,
+    overrides: Vec<ScriptRatio>,
+}
+
+impl TokenEstimator {
+    pub fn new(chars_per_token: f32, overrides: Vec<ScriptRatio>) -> Result<Self, String> {
+        if let Some(ratio) = std::iter::once(chars_per_token)
+            .chain(overrides.iter().map(|ratio| ratio.chars_per_token))
+            .find(|ratio| !ratio.is_finite() || *ratio <= 0.0)
+        {
+            return Err(format!("chars per token must be > 0. Given: {ratio}"));
+        }
+        Ok(Self {
+            chars_per_token,
+            overrides,
+        })
+    }
+
+    /// Estimated number of tokens of `text`, rounded up
+    pub(crate) fn estimate(&self, text: &str) -> usize {
+        let tokens: f32 = text
+            .chars()
+            .map(|c| {
+                let script = Script::of(c);
+                let chars_per_token = self
+                    .overrides
+                    .iter()
+                    // The last override of a script wins
+                    .rfind(|ratio| Some(ratio.script) == script)
+                    .map_or(self.chars_per_token, |ratio| ratio.chars_per_token);
+                1.0 / chars_per_token
+            })
+            .sum();
+        tokens.ceil() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let estimator = TokenEstimator::new(
+            4.0,
+            vec!["han=1".parse().unwrap(), "Hangul = 2.0".parse().unwrap()],
+        )
+        .unwrap();
+
+        assert_eq!(estimator.estimate(""), 0);
+        assert_eq!(estimator.estimate("Hello"), 2);
+        assert_eq!(estimator.estimate("你好"), 2);
+        assert_eq!(estimator.estimate("안녕하세요"), 3);
+        // 4 chars at 4 chars per token and 2 ideographs
+        assert_eq!(estimator.estimate("ok, 你好"), 3);
+    }
+
+    #[test]
+    fn test_invalid_ratios() {
+        assert!("klingon=2".parse::<ScriptRatio>().is_err());
+        assert!("han".parse::<ScriptRatio>().is_err());
+        assert!("han=fast".parse::<ScriptRatio>().is_err());
+        assert!(TokenEstimator::new(0.0, Vec::new()).is_err());
+        assert!(TokenEstimator::new(4.0, vec!["han=-1".parse().unwrap()]).is_err());
+    }
+}
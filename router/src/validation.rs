@@ -1,36 +1,354 @@
 /// Payload validation logic
-use crate::config::Config;
+use crate::auth::auth_context;
+use crate::config::{Config, MultimodalLimits};
+use crate::default_parameters::DefaultParameters;
+use crate::fetch::{FetchError, FetchPolicy};
+use crate::fsm_cache::FsmDiskCache;
+use crate::gbnf::gbnf_to_regex;
+use crate::grammar::{
+    self, json_schema_to_regex, resolve_refs, TokenFsm, TokenVocabulary, JSON_OBJECT_REGEX,
+};
+use crate::grammar_registry::{GrammarRegistry, GrammarRegistryError};
+use crate::image_pipeline::{
+    rasterize_pdf, ImageCache, ProcessedImage, IMAGE_CACHE_SIZE, IMAGE_CACHE_TTL,
+};
+use crate::infer::ChatTemplate;
+use crate::input_policy::InputPolicies;
+use crate::lark::{compile_lark, CompiledLark};
+use crate::provenance;
+use crate::token_estimate::TokenEstimator;
 use crate::validation::ValidationError::{BestOfSampling, BestOfSeed, EmptyInput};
 use crate::{
     GenerateParameters, GenerateRequest, GrammarType, HubPreprocessorConfig, Idefics2Preprocessor,
+    ImageDetail, InputSanitization, TruncationDirection,
 };
 use base64::{engine::general_purpose::STANDARD, Engine};
-use image::{io::Reader as ImageReader, ImageFormat};
 use jsonschema::{Draft, JSONSchema};
 use rand::{thread_rng, Rng};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::io::Cursor;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::iter;
-use text_generation_client::{Chunk, Image, InputChunk};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use text_generation_client::{video, Audio, Chunk, Image, InputChunk, Video};
 use thiserror::Error;
-use tokenizers::tokenizer::Tokenizer;
+use tokenizers::tokenizer::{Encoding, Tokenizer};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tracing::{instrument, Span};
-use {once_cell::sync::Lazy, regex::Regex};
+use unicode_normalization::UnicodeNormalization;
+use {
+    once_cell::sync::Lazy,
+    regex::{Regex, RegexBuilder},
+};
 
 /// Validation
 #[derive(Debug, Clone)]
 pub struct Validation {
     /// Validation parameters
     max_best_of: usize,
-    max_stop_sequences: usize,
-    max_top_n_tokens: u32,
-    max_input_length: usize,
-    max_total_tokens: usize,
+    /// Limits that can be adjusted at runtime
+    limits: Arc<ValidationLimits>,
     disable_grammar_support: bool,
+    /// Maximum number of images per request accepted by the model
+    max_images: Option<usize>,
+    /// Per-key overrides of the limits
+    input_policies: Arc<InputPolicies>,
+    /// Grammars that already compiled
+    grammar_cache: GrammarCache,
+    /// Token FSMs of the recent regex grammars, to check the generated tokens
+    grammar_fsms: GrammarFsms,
+    /// Callers whose grammars repeatedly failed to compile
+    grammar_throttle: GrammarThrottle,
+    /// Vocabulary of the token FSMs of the grammars, `None` without a fast tokenizer
+    vocabulary: Option<Arc<TokenVocabulary>>,
+    /// Token FSMs kept across restarts
+    fsm_cache: Option<FsmDiskCache>,
+    /// Maximum duration of the compilation of a grammar
+    grammar_compile_timeout: Duration,
+    /// Grammars referenced by name by the requests
+    grammar_registry: GrammarRegistry,
+    /// Grammars compiled ahead of the requests referencing them by id
+    grammar_artifacts: GrammarArtifacts,
+    /// The shards apply the classifier-free guidance, the flash and seq2seq models do not
+    supports_guidance: bool,
     /// Channel to communicate with the background tokenization task
     sender: Option<mpsc::UnboundedSender<TokenizerRequest>>,
+    /// Vocabulary size of the fast tokenizer, added tokens included
+    vocab_size: Option<usize>,
+    /// Special tokens of the fill-in-the-middle prompts, `None` if the model does not support them
+    fim_tokens: Option<FimTokens>,
+    /// Maximum generation time in seconds, also used when the request does not set one
+    max_time: Option<f32>,
+    /// Sanitization of the inputs of the requests that do not override it
+    input_sanitization: InputSanitization,
+    /// Chat template rendering the `messages` of the requests
+    chat_template: Option<ChatTemplate>,
+    /// Estimate of the input lengths without a fast tokenizer
+    token_estimator: Option<TokenEstimator>,
+    /// Adapters loaded by the shards, refreshed while they run
+    adapters: Arc<LoadedAdapters>,
+    /// Values of the parameters omitted by the requests
+    default_parameters: DefaultParameters,
+    /// Sizes of the inputs checked before tokenization
+    input_size_limits: InputSizeLimits,
+    /// The inputs of multimodal models are split into text and media chunks
+    multimodal: bool,
+}
+
+/// Sizes of the inputs checked before tokenization, so that huge inputs are rejected cheaply.
+/// `None` disables the check.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct InputSizeLimits {
+    /// Maximum size of the inputs, in bytes
+    pub max_input_bytes: Option<usize>,
+    /// Maximum number of characters of a text chunk of the inputs
+    pub max_chunk_chars: Option<usize>,
+}
+
+/// Options of the validation, the defaults disable the optional checks
+#[derive(Clone, Debug)]
+pub(crate) struct ValidationConfig {
+    /// Restrictions on the URLs of the images fetched by the router
+    pub fetch_policy: FetchPolicy,
+    /// Per-key overrides of the limits
+    pub input_policies: InputPolicies,
+    /// Grammar compilation failures of a caller before it is throttled, 0 disables the throttling
+    pub grammar_failure_limit: usize,
+    /// Window of the grammar compilation failures
+    pub grammar_failure_window: Duration,
+    /// Maximum duration of the compilation of a grammar
+    pub grammar_compile_timeout: Duration,
+    /// Overrides of the multimodal limits of the model configuration
+    pub multimodal_limits: Option<MultimodalLimits>,
+    /// Prefix, suffix and middle tokens, overriding the ones found in the tokenizer
+    pub fim_tokens: Option<[String; 3]>,
+    /// Maximum generation time in seconds
+    pub max_time: Option<f32>,
+    /// Sanitization of the inputs of the requests that do not override it
+    pub input_sanitization: InputSanitization,
+    /// Server defaults of the generation parameters
+    pub default_parameters: DefaultParameters,
+    /// Sizes of the inputs checked before tokenization
+    pub input_size_limits: InputSizeLimits,
+    /// Token FSMs kept across restarts
+    pub fsm_cache: Option<FsmDiskCache>,
+    /// Grammars referenced by name by the requests
+    pub grammar_registry: GrammarRegistry,
+    /// The shards apply the classifier-free guidance of `guidance_scale`
+    pub supports_guidance: bool,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            fetch_policy: FetchPolicy::default(),
+            input_policies: InputPolicies::default(),
+            grammar_failure_limit: 0,
+            grammar_failure_window: Duration::ZERO,
+            grammar_compile_timeout: Duration::from_secs(30),
+            multimodal_limits: None,
+            fim_tokens: None,
+            max_time: None,
+            input_sanitization: InputSanitization::default(),
+            default_parameters: DefaultParameters::default(),
+            input_size_limits: InputSizeLimits::default(),
+            fsm_cache: None,
+            grammar_registry: GrammarRegistry::default(),
+            supports_guidance: false,
+        }
+    }
+}
+
+/// Limits of the caller of the request being validated
+#[derive(Debug)]
+struct InputLimits {
+    max_input_length: usize,
+    max_total_tokens: usize,
+    max_new_tokens: Option<u32>,
+    /// Server default of `max_new_tokens`
+    default_max_new_tokens: Option<u32>,
+    max_images: Option<usize>,
+    allow_grammar: bool,
+    allow_ignore_eos_token: bool,
+}
+
+impl InputLimits {
+    /// `max_new_tokens` of the requests that omit it, with `remaining` tokens left by the inputs
+    fn resolve_max_new_tokens(&self, remaining: usize) -> u32 {
+        [self.max_new_tokens, self.default_max_new_tokens]
+            .into_iter()
+            .flatten()
+            .fold(remaining as u32, u32::min)
+    }
+}
+
+/// Values of the runtime adjustable limits
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub(crate) struct LimitValues {
+    pub max_stop_sequences: usize,
+    pub max_top_n_tokens: u32,
+    pub max_input_tokens: usize,
+    pub max_total_tokens: usize,
+}
+
+/// New values of the limits, `None` keeps the current value
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LimitsUpdate {
+    pub max_stop_sequences: Option<usize>,
+    pub max_top_n_tokens: Option<u32>,
+    pub max_input_tokens: Option<usize>,
+    pub max_total_tokens: Option<usize>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum LimitsError {
+    #[error("`max_input_tokens` must be < `max_total_tokens`. Given: {0} and {1}")]
+    InputTokens(usize, usize),
+    #[error("`{0}` cannot be raised above its startup value {1}. Given: {2}")]
+    AboveStartup(&'static str, usize, usize),
+}
+
+/// Adapters loaded by the shards, shared by the clones of `Validation` and updated when the
+/// launcher loads or unloads adapters
+#[derive(Debug)]
+pub(crate) struct LoadedAdapters {
+    /// `None` if the shards do not report their adapters, every adapter is then accepted
+    adapter_ids: RwLock<Option<HashSet<String>>>,
+}
+
+impl LoadedAdapters {
+    pub(crate) fn new(adapter_ids: Option<Vec<String>>) -> Self {
+        Self {
+            adapter_ids: RwLock::new(adapter_ids.map(HashSet::from_iter)),
+        }
+    }
+
+    /// Replace the adapters with the ones reported by the shards
+    pub(crate) fn set(&self, adapter_ids: Option<Vec<String>>) {
+        *self.adapter_ids.write().unwrap() = adapter_ids.map(HashSet::from_iter);
+    }
+
+    fn contains(&self, adapter_id: &str) -> bool {
+        self.adapter_ids
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(true, |adapter_ids| adapter_ids.contains(adapter_id))
+    }
+
+    /// Whether the shards reported `adapter_id` as loaded
+    pub(crate) fn reported(&self, adapter_id: &str) -> bool {
+        self.adapter_ids
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|adapter_ids| adapter_ids.contains(adapter_id))
+    }
+}
+
+/// Validation limits shared by the clones of `Validation`, adjustable at runtime.
+/// A request validated during an update can see both old and new values, which only makes the
+/// checks stricter as every value is valid on its own.
+#[derive(Debug)]
+pub(crate) struct ValidationLimits {
+    max_stop_sequences: AtomicUsize,
+    max_top_n_tokens: AtomicU32,
+    max_input_tokens: AtomicUsize,
+    max_total_tokens: AtomicUsize,
+    /// The batches were sized for the startup token limits, they cannot be raised above them
+    startup: LimitValues,
+    /// Serializes the updates
+    update: Mutex<()>,
+}
+
+impl ValidationLimits {
+    fn new(startup: LimitValues) -> Self {
+        Self {
+            max_stop_sequences: AtomicUsize::new(startup.max_stop_sequences),
+            max_top_n_tokens: AtomicU32::new(startup.max_top_n_tokens),
+            max_input_tokens: AtomicUsize::new(startup.max_input_tokens),
+            max_total_tokens: AtomicUsize::new(startup.max_total_tokens),
+            startup,
+            update: Mutex::new(()),
+        }
+    }
+
+    fn max_stop_sequences(&self) -> usize {
+        self.max_stop_sequences.load(Ordering::Relaxed)
+    }
+
+    fn max_top_n_tokens(&self) -> u32 {
+        self.max_top_n_tokens.load(Ordering::Relaxed)
+    }
+
+    fn max_input_tokens(&self) -> usize {
+        self.max_input_tokens.load(Ordering::Relaxed)
+    }
+
+    fn max_total_tokens(&self) -> usize {
+        self.max_total_tokens.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn values(&self) -> LimitValues {
+        LimitValues {
+            max_stop_sequences: self.max_stop_sequences(),
+            max_top_n_tokens: self.max_top_n_tokens(),
+            max_input_tokens: self.max_input_tokens(),
+            max_total_tokens: self.max_total_tokens(),
+        }
+    }
+
+    /// Apply `update` and get the new values. Nothing changes if one of the values is invalid.
+    pub(crate) fn update(&self, update: LimitsUpdate) -> Result<LimitValues, LimitsError> {
+        let _guard = self.update.lock().unwrap_or_else(|err| err.into_inner());
+        let current = self.values();
+        let values = LimitValues {
+            max_stop_sequences: update
+                .max_stop_sequences
+                .unwrap_or(current.max_stop_sequences),
+            max_top_n_tokens: update.max_top_n_tokens.unwrap_or(current.max_top_n_tokens),
+            max_input_tokens: update.max_input_tokens.unwrap_or(current.max_input_tokens),
+            max_total_tokens: update.max_total_tokens.unwrap_or(current.max_total_tokens),
+        };
+        if values.max_input_tokens >= values.max_total_tokens {
+            return Err(LimitsError::InputTokens(
+                values.max_input_tokens,
+                values.max_total_tokens,
+            ));
+        }
+        for (name, value, startup) in [
+            (
+                "max_input_tokens",
+                values.max_input_tokens,
+                self.startup.max_input_tokens,
+            ),
+            (
+                "max_total_tokens",
+                values.max_total_tokens,
+                self.startup.max_total_tokens,
+            ),
+        ] {
+            if value > startup {
+                return Err(LimitsError::AboveStartup(name, startup, value));
+            }
+        }
+
+        self.max_stop_sequences
+            .store(values.max_stop_sequences, Ordering::Relaxed);
+        self.max_top_n_tokens
+            .store(values.max_top_n_tokens, Ordering::Relaxed);
+        self.max_input_tokens
+            .store(values.max_input_tokens, Ordering::Relaxed);
+        self.max_total_tokens
+            .store(values.max_total_tokens, Ordering::Relaxed);
+        tracing::info!("Validation limits updated: {values:?}");
+        Ok(values)
+    }
 }
 
 impl Validation {
@@ -46,18 +364,63 @@ impl Validation {
         max_input_length: usize,
         max_total_tokens: usize,
         disable_grammar_support: bool,
+        chat_template: Option<ChatTemplate>,
+        token_estimator: Option<TokenEstimator>,
+        adapter_ids: Option<Vec<String>>,
+        validation_config: ValidationConfig,
     ) -> Self {
+        let ValidationConfig {
+            fetch_policy,
+            input_policies,
+            grammar_failure_limit,
+            grammar_failure_window,
+            grammar_compile_timeout,
+            multimodal_limits,
+            fim_tokens,
+            max_time,
+            input_sanitization,
+            default_parameters,
+            input_size_limits,
+            fsm_cache,
+            grammar_registry,
+            supports_guidance,
+        } = validation_config;
+        let multimodal = config.as_ref().is_some_and(Config::is_multimodal);
+        let multimodal_limits = config
+            .as_ref()
+            .map(MultimodalLimits::from_config)
+            .unwrap_or_default()
+            .merge(multimodal_limits.unwrap_or_default());
+        let max_images = multimodal_limits.max_images;
+        let vocab_size = tokenizer
+            .as_ref()
+            .map(|tokenizer| tokenizer.get_vocab_size(true));
+        let vocabulary = match disable_grammar_support {
+            true => None,
+            false => tokenizer
+                .as_ref()
+                .map(|tokenizer| Arc::new(TokenVocabulary::from_tokenizer(tokenizer))),
+        };
+        let fim_tokens = fim_tokens
+            .map(FimTokens::from)
+            .or_else(|| tokenizer.as_ref().and_then(FimTokens::from_tokenizer));
+
         // If we have a fast tokenizer
         let sender = if let Some(tokenizer) = tokenizer {
             // Create round robin channel
             let (validation_sender, validation_round_robin_receiver) = mpsc::unbounded_channel();
             let mut senders = Vec::with_capacity(workers);
+            // Shared by the workers
+            let image_cache = ImageCache::new(IMAGE_CACHE_SIZE, IMAGE_CACHE_TTL);
 
             // Create workers
             for _ in 0..workers {
                 let tokenizer_clone = tokenizer.clone();
                 let config_clone = config.clone();
                 let preprocessor_config_clone = preprocessor_config.clone();
+                let fetch_policy_clone = fetch_policy.clone();
+                let multimodal_limits_clone = multimodal_limits.clone();
+                let image_cache_clone = image_cache.clone();
                 let (tokenizer_sender, tokenizer_receiver) = mpsc::unbounded_channel();
                 senders.push(tokenizer_sender);
 
@@ -67,6 +430,9 @@ impl Validation {
                         tokenizer_clone,
                         config_clone,
                         preprocessor_config_clone,
+                        fetch_policy_clone,
+                        multimodal_limits_clone,
+                        image_cache_clone,
                         tokenizer_receiver,
                     )
                 });
@@ -80,15 +446,96 @@ impl Validation {
             None
         };
 
+        let grammar_cache = GrammarCache::new(GRAMMAR_CACHE_SIZE, Some("tgi_grammar_cache_size"));
+        let grammar_fsms = GrammarFsms::new(GRAMMAR_FSMS_SIZE, None);
+        // The FSM of `json_object` takes seconds to build with the large vocabularies
+        if let Some(vocabulary) = vocabulary.clone() {
+            let grammar_cache = grammar_cache.clone();
+            let grammar_fsms = grammar_fsms.clone();
+            let fsm_cache = fsm_cache.clone();
+            tokio::task::spawn_blocking(move || {
+                let cached = fsm_cache
+                    .as_ref()
+                    .and_then(|fsm_cache| fsm_cache.load(vocabulary.hash(), &JSON_OBJECT_REGEX));
+                let fsm = match cached {
+                    Some(fsm) => fsm,
+                    None => {
+                        let Ok(fsm) = TokenFsm::compile(
+                            &JSON_OBJECT_REGEX,
+                            &vocabulary,
+                            &AtomicBool::new(false),
+                        ) else {
+                            return;
+                        };
+                        if let Some(fsm_cache) = &fsm_cache {
+                            fsm_cache.store(vocabulary.hash(), &JSON_OBJECT_REGEX, &fsm);
+                        }
+                        fsm
+                    }
+                };
+                grammar_fsms.insert(JSON_OBJECT_REGEX.clone(), Arc::new(fsm));
+                grammar_cache.insert(JSON_OBJECT_REGEX.clone(), ());
+            });
+        }
+
         Self {
             max_best_of,
             sender,
-            max_stop_sequences,
-            max_top_n_tokens,
-            max_input_length,
-            max_total_tokens,
+            vocab_size,
+            fim_tokens,
+            max_time,
+            input_sanitization,
+            chat_template,
+            token_estimator,
+            adapters: Arc::new(LoadedAdapters::new(adapter_ids)),
+            default_parameters,
+            input_size_limits,
+            multimodal,
+            limits: Arc::new(ValidationLimits::new(LimitValues {
+                max_stop_sequences,
+                max_top_n_tokens,
+                max_input_tokens: max_input_length,
+                max_total_tokens,
+            })),
             disable_grammar_support,
+            max_images,
+            input_policies: Arc::new(input_policies),
+            grammar_cache,
+            grammar_fsms,
+            grammar_throttle: GrammarThrottle::new(grammar_failure_limit, grammar_failure_window),
+            vocabulary,
+            fsm_cache,
+            grammar_compile_timeout,
+            grammar_registry,
+            supports_guidance,
+            grammar_artifacts: GrammarArtifacts::new(
+                GRAMMAR_ARTIFACTS_SIZE,
+                Some("tgi_grammar_artifacts"),
+            ),
+        }
+    }
+
+    /// Validate and compile the grammars of the registry before the first request
+    pub(crate) async fn compile_grammar_registry(&self) -> Result<(), GrammarRegistryError> {
+        if self.disable_grammar_support {
+            if !self.grammar_registry.is_empty() {
+                tracing::warn!("The grammar registry is ignored without grammar support");
+            }
+            return Ok(());
         }
+        for (name, grammar) in self.grammar_registry.iter() {
+            let grammar = self
+                .validate_grammar(grammar.clone(), GRAMMAR_REGISTRY_KEY)
+                .map_err(|err| GrammarRegistryError::Invalid(name.clone(), err))?;
+            self.compile_grammar(grammar, GRAMMAR_REGISTRY_KEY)
+                .await
+                .map_err(|err| GrammarRegistryError::Invalid(name.clone(), err))?;
+        }
+        tracing::info!(
+            "Compiled the {} grammars of the registry",
+            self.grammar_registry.len()
+        );
+        Ok(())
     }
 
     #[instrument(skip(self, inputs))]
@@ -96,6 +543,8 @@ impl Validation {
         &self,
         inputs: String,
         truncate: Option<usize>,
+        truncation_direction: TruncationDirection,
+        add_special_tokens: bool,
     ) -> Result<Option<(tokenizers::Encoding, Vec<InputChunk>)>, ValidationError> {
         // If we have a fast tokenizer
         if let Some(sender) = &self.sender {
@@ -104,8 +553,16 @@ impl Validation {
             // Send request to the background validation task
             // Unwrap is safe here
             sender
-                .send(((inputs, truncate), response_sender, Span::current()))
+                .send((
+                    TokenizerQuery::Inputs(
+                        (inputs, truncate, truncation_direction, add_special_tokens),
+                        response_sender,
+                    ),
+                    Span::current(),
+                    Instant::now(),
+                ))
                 .unwrap();
+            metrics::gauge!("tgi_tokenizer_queue_size").increment(1.0);
 
             // Await on response channel
             // Unwrap is safe here
@@ -116,99 +573,623 @@ impl Validation {
         }
     }
 
-    #[instrument(skip(self, inputs))]
-    async fn validate_input(
+    /// Token ids of each phrase, without special tokens
+    async fn tokenize_phrases(
         &self,
-        inputs: String,
-        truncate: Option<usize>,
-        max_new_tokens: Option<u32>,
-    ) -> Result<(Vec<InputChunk>, usize, u32), ValidationError> {
+        phrases: Vec<String>,
+    ) -> Result<Option<Vec<Vec<u32>>>, ValidationError> {
         // If we have a fast tokenizer
-        if let Some((encoding, inputs)) = self.tokenize(inputs.clone(), truncate).await? {
-            // Create response channel
-            let input_length = if let Some(truncate) = truncate {
-                std::cmp::min(encoding.len(), truncate)
-            } else {
-                encoding.len()
+        if let Some(sender) = &self.sender {
+            let (response_sender, response_receiver) = oneshot::channel();
+            // Unwrap is safe here
+            sender
+                .send((
+                    TokenizerQuery::Phrases(phrases, response_sender),
+                    Span::current(),
+                    Instant::now(),
+                ))
+                .unwrap();
+            metrics::gauge!("tgi_tokenizer_queue_size").increment(1.0);
+
+            // Unwrap is safe here
+            let ids = response_receiver.await.unwrap()?;
+            Ok(Some(ids))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Text of token ids, special tokens included
+    async fn decode(&self, ids: Vec<u32>) -> Result<Option<String>, ValidationError> {
+        // If we have a fast tokenizer
+        if let Some(sender) = &self.sender {
+            let (response_sender, response_receiver) = oneshot::channel();
+            // Unwrap is safe here
+            sender
+                .send((
+                    TokenizerQuery::Decode(ids, response_sender),
+                    Span::current(),
+                    Instant::now(),
+                ))
+                .unwrap();
+            metrics::gauge!("tgi_tokenizer_queue_size").increment(1.0);
+
+            // Unwrap is safe here
+            let text = response_receiver.await.unwrap()?;
+            Ok(Some(text))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Runtime adjustable limits
+    pub(crate) fn validation_limits(&self) -> &ValidationLimits {
+        &self.limits
+    }
+
+    /// Adapters loaded by the shards
+    pub(crate) fn loaded_adapters(&self) -> Arc<LoadedAdapters> {
+        self.adapters.clone()
+    }
+
+    /// Priority class of the caller of the current request
+    pub(crate) fn priority(&self) -> String {
+        self.input_policies.priority(&auth_context().key())
+    }
+
+    /// Limits of the caller of the current request
+    fn limits(&self) -> InputLimits {
+        let policy = self.input_policies.policy(&auth_context().key());
+        let max_input_length = self.limits.max_input_tokens();
+        InputLimits {
+            max_input_length: policy
+                .max_input_tokens
+                .map_or(max_input_length, |max| max.min(max_input_length)),
+            max_total_tokens: self.limits.max_total_tokens(),
+            max_new_tokens: policy.max_new_tokens,
+            default_max_new_tokens: self.default_parameters.max_new_tokens,
+            max_images: match (policy.max_images, self.max_images) {
+                (Some(policy), Some(model)) => Some(policy.min(model)),
+                (policy, model) => policy.or(model),
+            },
+            allow_grammar: policy.allow_grammar.unwrap_or(true),
+            allow_ignore_eos_token: policy.allow_ignore_eos_token.unwrap_or(true),
+        }
+    }
+
+    /// Key of the caller sending a grammar, if grammars are supported and allowed for the caller
+    fn grammar_key(&self, allow_grammar: bool) -> Result<String, ValidationError> {
+        // Ensure that grammar is not set if it's not supported
+        if self.disable_grammar_support {
+            return Err(ValidationError::Grammar);
+        }
+        if !allow_grammar {
+            return Err(ValidationError::GrammarNotAllowed);
+        }
+        let key = auth_context().key();
+        if let Some(retry_after) = self.grammar_throttle.retry_after(&key, Instant::now()) {
+            metrics::counter!("tgi_grammar_throttled").increment(1);
+            return Err(ValidationError::GrammarThrottled(retry_after.as_secs() + 1));
+        }
+        Ok(key)
+    }
+
+    /// Compile `grammar` in the background, for the requests referencing it by the returned id.
+    /// The id is derived from the grammar: the same grammar always gets the same id.
+    pub(crate) fn compile_grammar_artifact(
+        &self,
+        grammar: GrammarType,
+    ) -> Result<(String, GrammarArtifact), ValidationError> {
+        let key = self.grammar_key(self.limits().allow_grammar)?;
+        let serialized = serde_json::to_string(&grammar)
+            .map_err(|err| ValidationError::InvalidGrammar(err.to_string()))?;
+        let id = provenance::hex(&digest(&SHA256, serialized.as_bytes()).as_ref()[..16]);
+        // The failed grammars are compiled again, the failure may have been a timeout
+        match self.grammar_artifacts.get(&id) {
+            Some(GrammarArtifact::Failed(_)) | None => {}
+            Some(artifact) => return Ok((id, artifact)),
+        }
+        self.grammar_artifacts
+            .insert(id.clone(), GrammarArtifact::Compiling);
+        metrics::counter!("tgi_grammar_artifact_compile").increment(1);
+
+        let validation = self.clone();
+        let artifact_id = id.clone();
+        tokio::spawn(async move {
+            let artifact = match validation.validate_grammar(grammar, &key) {
+                Ok(grammar) => validation.compile_grammar(grammar, &key).await,
+                Err(err) => Err(err),
+            };
+            let artifact = match artifact {
+                Ok(grammar) => GrammarArtifact::Ready(grammar),
+                Err(err) => GrammarArtifact::Failed(err.to_string()),
             };
+            validation.grammar_artifacts.insert(artifact_id, artifact);
+        });
+        Ok((id, GrammarArtifact::Compiling))
+    }
 
-            // Get total tokens
-            let max_new_tokens: u32 = if let Some(max_new_tokens) = max_new_tokens {
-                max_new_tokens
+    /// Grammar compiled by `compile_grammar_artifact`, `None` if the id is unknown or evicted
+    pub(crate) fn grammar_artifact(&self, id: &str) -> Option<GrammarArtifact> {
+        self.grammar_artifacts.get(id)
+    }
+
+    /// Validate `grammar` and convert it for the model servers. `key` identifies the caller
+    /// whose failed grammars are throttled.
+    fn validate_grammar(
+        &self,
+        grammar: GrammarType,
+        key: &str,
+    ) -> Result<ValidGrammar, ValidationError> {
+        let valid_grammar = match grammar {
+            GrammarType::Json(json) | GrammarType::JsonObject(Some(json)) => {
+                let json = match json {
+                    // if value is a string, we need to parse it again to make sure its
+                    // a valid json
+                    Value::String(s) => serde_json::from_str(&s)
+                        .map_err(|e| ValidationError::InvalidGrammar(e.to_string())),
+                    Value::Object(_) => Ok(json),
+                    _ => Err(ValidationError::Grammar),
+                }?;
+                // The model servers do not resolve the references
+                let json = resolve_refs(&json)
+                    .map_err(|err| ValidationError::InvalidGrammar(err.to_string()))?;
+
+                // Serialize json to string
+                let schema = serde_json::to_string(&json)
+                    .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
+
+                // Check if the json is a valid JSONSchema
+                if self.grammar_cache.contains(&schema) {
+                    metrics::counter!("tgi_grammar_cache_hit").increment(1);
+                } else {
+                    metrics::counter!("tgi_grammar_cache_miss").increment(1);
+                    let start_time = Instant::now();
+                    JSONSchema::options()
+                        .with_draft(Draft::Draft202012)
+                        .compile(&json)
+                        .map_err(|e| {
+                            metrics::counter!("tgi_grammar_compile_failure", "type" => "json")
+                                .increment(1);
+                            self.grammar_throttle
+                                .record_failure(key.to_string(), Instant::now());
+                            ValidationError::InvalidGrammar(e.to_string())
+                        })?;
+                    let compile_duration = start_time.elapsed();
+                    metrics::histogram!("tgi_grammar_compile_duration", "type" => "json")
+                        .record(compile_duration.as_secs_f64());
+                    // Slow grammars are accepted, but count as failures
+                    if compile_duration > GRAMMAR_SLOW_COMPILE {
+                        metrics::counter!("tgi_grammar_compile_slow", "type" => "json")
+                            .increment(1);
+                        self.grammar_throttle
+                            .record_failure(key.to_string(), Instant::now());
+                    }
+                    self.grammar_cache.insert(schema.clone(), ());
+                }
+
+                ValidGrammar::Json(schema)
+            }
+            GrammarType::JsonObject(None) => ValidGrammar::Regex(JSON_OBJECT_REGEX.clone()),
+            GrammarType::Regex(regex) => {
+                let regex = check_regex_grammar(&regex)?;
+                ValidGrammar::Regex(regex)
+            }
+            GrammarType::Choice(choices) => {
+                if choices.is_empty() || choices.iter().any(String::is_empty) {
+                    return Err(ValidationError::InvalidGrammar(
+                        "`choice` must be a list of non-empty strings".to_string(),
+                    ));
+                }
+                let regex = choice_regex(&choices);
+                ValidGrammar::Regex(regex)
+            }
+            GrammarType::Ebnf(grammar) => {
+                let regex = gbnf_to_regex(&grammar)
+                    .map_err(|err| ValidationError::InvalidGrammar(err.to_string()))?;
+                ValidGrammar::Regex(regex)
+            }
+            GrammarType::Lark(grammar) => match compile_lark(&grammar)
+                .map_err(|err| ValidationError::InvalidGrammar(err.to_string()))?
+            {
+                CompiledLark::Regex(regex) => ValidGrammar::Regex(regex),
+                CompiledLark::Cfg(grammar) => ValidGrammar::Cfg(grammar),
+            },
+            GrammarType::Registry(name) => {
+                let grammar = self.grammar_registry.get(&name).cloned().ok_or_else(|| {
+                    ValidationError::InvalidGrammar(format!(
+                        "`{name}` is not a grammar of the registry"
+                    ))
+                })?;
+                self.validate_grammar(grammar, key)?
+            }
+            GrammarType::Compiled(id) => match self.grammar_artifacts.get(&id) {
+                Some(GrammarArtifact::Ready(grammar)) => grammar,
+                Some(GrammarArtifact::Compiling) => {
+                    return Err(ValidationError::InvalidGrammar(format!(
+                        "grammar `{id}` is still compiling"
+                    )))
+                }
+                Some(GrammarArtifact::Failed(err)) => {
+                    return Err(ValidationError::InvalidGrammar(format!(
+                        "grammar `{id}` failed to compile: {err}"
+                    )))
+                }
+                None => {
+                    return Err(ValidationError::InvalidGrammar(format!(
+                        "`{id}` is not the id of a compiled grammar"
+                    )))
+                }
+            },
+            GrammarType::Union(grammars) => {
+                if grammars.is_empty() || grammars.len() > MAX_UNION_GRAMMARS {
+                    return Err(ValidationError::InvalidGrammar(format!(
+                        "`union` must contain between 1 and {MAX_UNION_GRAMMARS} grammars"
+                    )));
+                }
+                let grammars = grammars
+                    .into_iter()
+                    .map(|grammar| match grammar {
+                        GrammarType::Union(_) => Err(ValidationError::InvalidGrammar(
+                            "`union` grammars cannot be nested".to_string(),
+                        )),
+                        grammar => self.validate_grammar(grammar, key),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                union_grammar(grammars)?
+            }
+        };
+        Ok(valid_grammar)
+    }
+
+    /// Convert the JSON Schemas to regexes and build the token FSM of the regexes. The schemas
+    /// using unsupported keywords are left to the model servers.
+    async fn compile_grammar(
+        &self,
+        grammar: ValidGrammar,
+        key: &str,
+    ) -> Result<ValidGrammar, ValidationError> {
+        let regex = match grammar {
+            ValidGrammar::Json(schema) => {
+                let json: Value = serde_json::from_str(&schema)
+                    .map_err(|err| ValidationError::InvalidGrammar(err.to_string()))?;
+                match json_schema_to_regex(&json) {
+                    Ok(regex) => regex,
+                    Err(err) => {
+                        tracing::debug!("JSON Schema compiled by the model servers: {err}");
+                        return Ok(ValidGrammar::Json(schema));
+                    }
+                }
+            }
+            ValidGrammar::Regex(regex) => regex,
+            // The parsers of the context-free grammars are built by the model servers
+            ValidGrammar::Cfg(grammar) => return Ok(ValidGrammar::Cfg(grammar)),
+        };
+
+        if let Some(vocabulary) = &self.vocabulary {
+            if self.grammar_cache.contains(&regex) {
+                metrics::counter!("tgi_grammar_cache_hit").increment(1);
+            } else if let Some(fsm) = self
+                .fsm_cache
+                .as_ref()
+                .and_then(|fsm_cache| fsm_cache.load(vocabulary.hash(), &regex))
+            {
+                metrics::counter!("tgi_grammar_disk_cache_hit").increment(1);
+                record_fsm_size(&fsm);
+                self.grammar_fsms.insert(regex.clone(), Arc::new(fsm));
+                self.grammar_cache.insert(regex.clone(), ());
             } else {
-                self.max_total_tokens.saturating_sub(input_length) as u32
-            };
-            let total_tokens = input_length + max_new_tokens as usize;
+                metrics::counter!("tgi_grammar_cache_miss").increment(1);
+                let start_time = Instant::now();
+                let fsm = self
+                    .compile_fsm(&regex, vocabulary.clone())
+                    .await
+                    .map_err(|err| {
+                        metrics::counter!("tgi_grammar_compile_failure", "type" => "fsm")
+                            .increment(1);
+                        self.grammar_throttle
+                            .record_failure(key.to_string(), Instant::now());
+                        err
+                    })?;
+                let compile_duration = start_time.elapsed();
+                metrics::histogram!("tgi_grammar_compile_duration", "type" => "fsm")
+                    .record(compile_duration.as_secs_f64());
+                record_fsm_size(&fsm);
+                if compile_duration > GRAMMAR_SLOW_COMPILE {
+                    metrics::counter!("tgi_grammar_compile_slow", "type" => "fsm").increment(1);
+                    self.grammar_throttle
+                        .record_failure(key.to_string(), Instant::now());
+                }
+                if let Some(fsm_cache) = &self.fsm_cache {
+                    fsm_cache.store(vocabulary.hash(), &regex, &fsm);
+                }
+                self.grammar_fsms.insert(regex.clone(), Arc::new(fsm));
+                self.grammar_cache.insert(regex.clone(), ());
+            }
+        }
+        Ok(ValidGrammar::Regex(regex))
+    }
 
-            // Validate MaxTotalTokens
-            if total_tokens > self.max_total_tokens {
-                return Err(ValidationError::MaxTotalTokens(
-                    self.max_total_tokens,
-                    input_length,
-                    max_new_tokens,
-                ));
+    /// Build the token FSM of `regex` on the blocking threads. The compilation is cancelled after
+    /// `grammar_compile_timeout`, or when the request is dropped because its client disconnected.
+    async fn compile_fsm(
+        &self,
+        regex: &str,
+        vocabulary: Arc<TokenVocabulary>,
+    ) -> Result<TokenFsm, ValidationError> {
+        let cancel = CancelOnDrop::default();
+        let cancelled = cancel.0.clone();
+        let regex = regex.to_string();
+        let task =
+            tokio::task::spawn_blocking(move || TokenFsm::compile(&regex, &vocabulary, &cancelled));
+        match tokio::time::timeout(self.grammar_compile_timeout, task).await {
+            Ok(Ok(result)) => {
+                result.map_err(|err| ValidationError::InvalidGrammar(err.to_string()))
+            }
+            // The compilation panicked
+            Ok(Err(err)) => Err(ValidationError::InvalidGrammar(err.to_string())),
+            Err(_) => {
+                metrics::counter!("tgi_grammar_compile_timeout", "type" => "fsm").increment(1);
+                Err(ValidationError::GrammarTimeout(
+                    self.grammar_compile_timeout,
+                ))
             }
+        }
+    }
 
-            // Validate InputLength
-            if input_length > self.max_input_length {
-                return Err(ValidationError::InputLength(
-                    self.max_input_length,
-                    input_length,
-                ));
+    /// Reject the oversized inputs before they reach the tokenizer workers
+    fn check_input_size(&self, inputs: &str) -> Result<(), ValidationError> {
+        if let Some(max_bytes) = self.input_size_limits.max_input_bytes {
+            if inputs.len() > max_bytes {
+                return Err(ValidationError::InputTooLarge(max_bytes, inputs.len()));
+            }
+        }
+        if let Some(max_chars) = self.input_size_limits.max_chunk_chars {
+            let longest = match self.multimodal {
+                true => MEDIA_RE
+                    .split(inputs)
+                    .map(|chunk| chunk.chars().count())
+                    .max(),
+                false => Some(inputs.chars().count()),
+            };
+            if let Some(longest) = longest.filter(|longest| *longest > max_chars) {
+                return Err(ValidationError::ChunkTooLong(max_chars, longest));
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self, inputs))]
+    async fn validate_input(
+        &self,
+        inputs: String,
+        truncate: Option<usize>,
+        truncation_direction: TruncationDirection,
+        add_special_tokens: bool,
+        input_sanitization: Option<InputSanitization>,
+        max_new_tokens: Option<u32>,
+    ) -> Result<(Vec<InputChunk>, Vec<u32>, usize, u32), ValidationError> {
+        self.check_input_size(&inputs)?;
+        let inputs = sanitize(
+            inputs,
+            input_sanitization.unwrap_or(self.input_sanitization),
+        )?;
+        let limits = self.limits();
+        if let (Some(requested), Some(limit)) = (max_new_tokens, limits.max_new_tokens) {
+            if requested > limit {
+                return Err(ValidationError::MaxNewTokens(limit as usize, requested));
             }
+        }
+
+        // If we have a fast tokenizer
+        if let Some((encoding, inputs)) = self
+            .tokenize(
+                inputs.clone(),
+                truncate,
+                truncation_direction,
+                add_special_tokens,
+            )
+            .await?
+        {
+            // The encoding is already truncated
+            let input_length = encoding.len();
+            let max_new_tokens = self.validate_length(&limits, input_length, max_new_tokens)?;
 
-            metrics::histogram!("tgi_request_input_length", input_length as f64);
-            Ok((inputs, input_length, max_new_tokens))
+            // The model servers add the special tokens when they tokenize the inputs: send them
+            // the token ids instead. Only possible without images, which they expand themselves.
+            let text_only = matches!(
+                inputs.as_slice(),
+                [InputChunk {
+                    chunk: Some(Chunk::Text(_))
+                }]
+            );
+            let input_ids = match add_special_tokens || !text_only {
+                true => Vec::new(),
+                false => encoding.get_ids().to_vec(),
+            };
+            Ok((inputs, input_ids, input_length, max_new_tokens))
         }
         // Return inputs without validation
         else {
             // In this case, we don't know the real length in tokens of the inputs
-            // However, the inputs will be truncated by the python servers
-            // We make sure that truncate + max_new_tokens <= self.max_total_tokens
+            // However, the inputs will be truncated by the python servers, from the left only
+            // We make sure that truncate + max_new_tokens <= max_total_tokens
+            if truncate.is_some() && truncation_direction == TruncationDirection::Right {
+                return Err(ValidationError::TruncationDirection);
+            }
+            if !add_special_tokens {
+                return Err(ValidationError::AddSpecialTokens);
+            }
+            // The estimate is only approximate, but lets us enforce the limits
+            if let Some(token_estimator) = &self.token_estimator {
+                let estimate = token_estimator.estimate(&inputs);
+                let input_length = truncate.map_or(estimate, |truncate| estimate.min(truncate));
+                let max_new_tokens = self.validate_length(&limits, input_length, max_new_tokens)?;
+                return Ok((
+                    vec![Chunk::Text(inputs).into()],
+                    Vec::new(),
+                    input_length,
+                    max_new_tokens,
+                ));
+            }
             let max_new_tokens: u32 = if let Some(max_new_tokens) = max_new_tokens {
                 max_new_tokens
             } else if let Some(truncate) = truncate {
-                self.max_total_tokens.saturating_sub(truncate) as u32
+                limits.resolve_max_new_tokens(limits.max_total_tokens.saturating_sub(truncate))
+            } else if let Some(default) = limits.default_max_new_tokens {
+                limits
+                    .max_new_tokens
+                    .map_or(default, |limit| default.min(limit))
             } else {
-                return Err(ValidationError::UnsetMaxNewTokens);
+                // The inputs can take up to `max_input_length` tokens
+                match limits
+                    .max_total_tokens
+                    .saturating_sub(limits.max_input_length) as u32
+                {
+                    0 => return Err(ValidationError::UnsetMaxNewTokens),
+                    max_new_tokens => limits
+                        .max_new_tokens
+                        .map_or(max_new_tokens, |limit| max_new_tokens.min(limit)),
+                }
             };
-            let mut input_length = truncate.unwrap_or(self.max_input_length);
+            let mut input_length = truncate.unwrap_or(limits.max_input_length);
 
             // We don't have a tokenizer, therefore we have no idea how long is the query, let
             // them through and hope for the best.
             // Validate MaxNewTokens
-            if (input_length as u32 + max_new_tokens) > self.max_total_tokens as u32 {
+            if (input_length as u32 + max_new_tokens) > limits.max_total_tokens as u32 {
                 input_length = input_length.saturating_sub(max_new_tokens as usize);
             }
 
             Ok((
                 vec![Chunk::Text(inputs).into()],
+                Vec::new(),
                 input_length,
                 max_new_tokens,
             ))
         }
     }
 
+    /// Validate pre-tokenized inputs. The token ids are truncated here, and decoded for the model
+    /// servers that need the text of the inputs.
+    #[instrument(skip(self, input_ids))]
+    async fn validate_input_ids(
+        &self,
+        mut input_ids: Vec<u32>,
+        truncate: Option<usize>,
+        truncation_direction: TruncationDirection,
+        max_new_tokens: Option<u32>,
+    ) -> Result<(Vec<InputChunk>, Vec<u32>, u32), ValidationError> {
+        let limits = self.limits();
+        if let (Some(requested), Some(limit)) = (max_new_tokens, limits.max_new_tokens) {
+            if requested > limit {
+                return Err(ValidationError::MaxNewTokens(limit as usize, requested));
+            }
+        }
+
+        let vocab_size = self.vocab_size.ok_or(ValidationError::InputIdsTokenizer)?;
+        if let Some(id) = input_ids.iter().find(|id| **id as usize >= vocab_size) {
+            return Err(ValidationError::InputId(vocab_size, *id));
+        }
+
+        if let Some(truncate) = truncate.filter(|truncate| input_ids.len() > *truncate) {
+            match truncation_direction {
+                TruncationDirection::Left => {
+                    input_ids.drain(..input_ids.len() - truncate);
+                }
+                TruncationDirection::Right => input_ids.truncate(truncate),
+            }
+        }
+        let max_new_tokens = self.validate_length(&limits, input_ids.len(), max_new_tokens)?;
+
+        let inputs = self
+            .decode(input_ids.clone())
+            .await?
+            .ok_or(ValidationError::InputIdsTokenizer)?;
+        Ok((vec![Chunk::Text(inputs).into()], input_ids, max_new_tokens))
+    }
+
+    /// Validate the length of the inputs against the token limits, and get `max_new_tokens`
+    fn validate_length(
+        &self,
+        limits: &InputLimits,
+        input_length: usize,
+        max_new_tokens: Option<u32>,
+    ) -> Result<u32, ValidationError> {
+        // Get total tokens
+        let max_new_tokens: u32 = if let Some(max_new_tokens) = max_new_tokens {
+            max_new_tokens
+        } else {
+            limits.resolve_max_new_tokens(limits.max_total_tokens.saturating_sub(input_length))
+        };
+        let total_tokens = input_length + max_new_tokens as usize;
+
+        // Validate MaxTotalTokens
+        if total_tokens > limits.max_total_tokens {
+            return Err(ValidationError::MaxTotalTokens(
+                limits.max_total_tokens,
+                input_length,
+                max_new_tokens,
+            ));
+        }
+
+        // Validate InputLength
+        if input_length > limits.max_input_length {
+            return Err(ValidationError::InputLength(
+                limits.max_input_length,
+                input_length,
+            ));
+        }
+
+        metrics::histogram!("tgi_request_input_length").record(input_length as f64);
+        Ok(max_new_tokens)
+    }
+
     /// Validate a payload and get the number of tokens in the input
     #[instrument(skip_all)]
     pub(crate) async fn validate(
         &self,
         request: GenerateRequest,
     ) -> Result<ValidGenerateRequest, ValidationError> {
+        let seeds = candidate_seeds(&request.parameters)?;
         let GenerateParameters {
             best_of,
             temperature,
             repetition_penalty,
             frequency_penalty,
+            presence_penalty,
+            no_repeat_ngram_size,
+            dry_multiplier,
+            dry_base,
+            dry_allowed_length,
             top_k,
             top_p,
             typical_p,
+            min_p,
+            epsilon_cutoff,
+            eta_cutoff,
+            top_a,
+            mirostat_mode,
+            mirostat_tau,
+            mirostat_eta,
+            logit_bias,
             do_sample,
             max_new_tokens,
+            min_new_tokens,
+            max_time,
             stop: stop_sequences,
+            stop_token_ids,
+            stop_regex,
+            bad_words,
+            negative_inputs,
+            guidance_scale,
+            suffix,
             truncate,
-            seed,
+            truncation_direction,
+            add_special_tokens,
+            input_sanitization,
             watermark,
+            ignore_eos_token,
             decoder_input_details,
             top_n_tokens,
             grammar,
@@ -216,19 +1197,47 @@ impl Validation {
             ..
         } = request.parameters;
 
+        // Server defaults of the omitted parameters, `max_new_tokens` is resolved with the inputs
+        let defaults = &self.default_parameters;
+        let temperature = temperature.or(defaults.temperature);
+        let top_k = top_k.or(defaults.top_k);
+        let top_p = top_p.or(defaults.top_p);
+        let typical_p = typical_p.or(defaults.typical_p);
+        let min_p = min_p.or(defaults.min_p);
+        let repetition_penalty = repetition_penalty.or(defaults.repetition_penalty);
+        let frequency_penalty = frequency_penalty.or(defaults.frequency_penalty);
+        let presence_penalty = presence_penalty.or(defaults.presence_penalty);
+        let do_sample = do_sample || defaults.do_sample;
+
+        // The shards use the base model for the adapters they did not load
+        if let Some(adapter_id) = &adapter_id {
+            if !self.adapters.contains(adapter_id) {
+                return Err(ValidationError::UnknownAdapter(adapter_id.clone()));
+            }
+        }
+
+        // `temperature: 0` is greedy decoding, like in the OpenAI API
+        let greedy = temperature == Some(0.0);
+
         // sampling must be true when best_of > 1
         let best_of = best_of.unwrap_or(1);
-        let sampling = do_sample
-            || temperature.is_some()
-            || top_k.is_some()
-            || top_p.is_some()
-            || typical_p.is_some();
+        let sampling = !greedy
+            && (do_sample
+                || temperature.is_some()
+                || top_k.is_some()
+                || top_p.is_some()
+                || typical_p.is_some()
+                || min_p.is_some()
+                || epsilon_cutoff.is_some()
+                || eta_cutoff.is_some()
+                || top_a.is_some()
+                || mirostat_mode.is_some_and(|mode| mode != 0));
 
         if best_of > 1 && !sampling {
             return Err(BestOfSampling);
         }
 
-        let temperature = temperature.unwrap_or(1.0);
+        let temperature = temperature.filter(|_| !greedy).unwrap_or(1.0);
         if temperature <= 0.0 {
             return Err(ValidationError::Temperature);
         }
@@ -243,11 +1252,44 @@ impl Validation {
             return Err(ValidationError::FrequencyPenalty);
         }
 
-        // Different because the proto default value is not a valid value
-        // for the user
-        let top_p = top_p
-            .map(|value| {
-                if value <= 0.0 || value >= 1.0 {
+        let presence_penalty = presence_penalty.unwrap_or(0.0);
+        if !(-2.0..=2.0).contains(&presence_penalty) {
+            return Err(ValidationError::PresencePenalty);
+        }
+
+        // 0 disables it on the shards
+        let no_repeat_ngram_size = match no_repeat_ngram_size {
+            Some(size) if size == 0 || size > MAX_NO_REPEAT_NGRAM_SIZE => {
+                return Err(ValidationError::NoRepeatNgramSize(
+                    MAX_NO_REPEAT_NGRAM_SIZE,
+                    size,
+                ))
+            }
+            size => size.unwrap_or(0),
+        };
+
+        // 0.0 disables DRY on the shards, which then ignore its other parameters
+        if dry_multiplier.is_none() && (dry_base.is_some() || dry_allowed_length.is_some()) {
+            return Err(ValidationError::DryParameters);
+        }
+        let dry_multiplier = dry_multiplier.unwrap_or(0.0);
+        if dry_multiplier < 0.0 {
+            return Err(ValidationError::DryMultiplier);
+        }
+        let dry_base = dry_base.unwrap_or(DEFAULT_DRY_BASE);
+        if dry_base <= 1.0 {
+            return Err(ValidationError::DryBase);
+        }
+        let dry_allowed_length = dry_allowed_length.unwrap_or(DEFAULT_DRY_ALLOWED_LENGTH);
+        if dry_allowed_length == 0 {
+            return Err(ValidationError::DryAllowedLength);
+        }
+
+        // Different because the proto default value is not a valid value
+        // for the user
+        let top_p = top_p
+            .map(|value| {
+                if value <= 0.0 || value >= 1.0 {
                     return Err(ValidationError::TopP);
                 }
                 Ok(value)
@@ -263,6 +1305,62 @@ impl Validation {
             })
             .unwrap_or(Ok(1.0))?;
 
+        // 0.0 disables the filtering on the shards
+        let min_p = min_p
+            .map(|value| {
+                if value <= 0.0 || value >= 1.0 {
+                    return Err(ValidationError::MinP);
+                }
+                Ok(value)
+            })
+            .unwrap_or(Ok(0.0))?;
+
+        // 0.0 disables the cutoffs on the shards
+        let epsilon_cutoff = epsilon_cutoff
+            .map(|value| {
+                if value <= 0.0 || value >= 1.0 {
+                    return Err(ValidationError::EpsilonCutoff);
+                }
+                Ok(value)
+            })
+            .unwrap_or(Ok(0.0))?;
+
+        let eta_cutoff = eta_cutoff
+            .map(|value| {
+                if value <= 0.0 || value >= 1.0 {
+                    return Err(ValidationError::EtaCutoff);
+                }
+                Ok(value)
+            })
+            .unwrap_or(Ok(0.0))?;
+
+        // 0 disables mirostat on the shards, which then ignore its other parameters
+        if mirostat_mode.unwrap_or(0) == 0 && (mirostat_tau.is_some() || mirostat_eta.is_some()) {
+            return Err(ValidationError::MirostatParameters);
+        }
+        let mirostat_mode = mirostat_mode.unwrap_or(0);
+        if mirostat_mode != 0 && mirostat_mode != 2 {
+            return Err(ValidationError::MirostatMode(mirostat_mode));
+        }
+        let mirostat_tau = mirostat_tau.unwrap_or(DEFAULT_MIROSTAT_TAU);
+        if mirostat_tau <= 0.0 {
+            return Err(ValidationError::MirostatTau);
+        }
+        let mirostat_eta = mirostat_eta.unwrap_or(DEFAULT_MIROSTAT_ETA);
+        if mirostat_eta <= 0.0 || mirostat_eta > 1.0 {
+            return Err(ValidationError::MirostatEta);
+        }
+
+        // 0.0 disables it on the shards
+        let top_a = top_a
+            .map(|value| {
+                if value <= 0.0 || value > 1.0 {
+                    return Err(ValidationError::TopA);
+                }
+                Ok(value)
+            })
+            .unwrap_or(Ok(0.0))?;
+
         let top_k: u32 = top_k
             .map(|value| {
                 if value <= 0 {
@@ -272,107 +1370,291 @@ impl Validation {
             })
             .unwrap_or(Ok(0))?;
 
+        if logit_bias.len() > MAX_LOGIT_BIAS_TOKENS {
+            return Err(ValidationError::LogitBias(
+                MAX_LOGIT_BIAS_TOKENS,
+                logit_bias.len(),
+            ));
+        }
+        let logit_bias = logit_bias
+            .into_iter()
+            .map(|(token_id, bias)| (token_id, bias.clamp(-MAX_LOGIT_BIAS, MAX_LOGIT_BIAS)))
+            .collect();
+
         if max_new_tokens == Some(0) {
             return Err(ValidationError::NegativeMaxNewTokens);
         }
 
-        if stop_sequences.len() > self.max_stop_sequences {
+        let max_stop_sequences = self.limits.max_stop_sequences();
+        if stop_sequences.len() > max_stop_sequences {
             return Err(ValidationError::StopSequence(
-                self.max_stop_sequences,
+                max_stop_sequences,
                 stop_sequences.len(),
             ));
         }
 
-        // If seed is None, assign a random one
-        let seed = match seed {
-            None => thread_rng().gen(),
-            Some(seed) => {
-                if best_of > 1 {
-                    return Err(BestOfSeed);
-                }
-                seed
+        if stop_token_ids.len() > max_stop_sequences {
+            return Err(ValidationError::StopTokenIds(
+                max_stop_sequences,
+                stop_token_ids.len(),
+            ));
+        }
+        if let Some(vocab_size) = self.vocab_size {
+            if let Some(&token_id) = stop_token_ids
+                .iter()
+                .find(|&&token_id| token_id as usize >= vocab_size)
+            {
+                return Err(ValidationError::StopTokenId(vocab_size, token_id));
+            }
+        }
+
+        if stop_regex.len() > max_stop_sequences {
+            return Err(ValidationError::StopRegex(
+                max_stop_sequences,
+                stop_regex.len(),
+            ));
+        }
+        let stop_regex = stop_regex_matcher(&stop_regex)?;
+
+        // Requests without `max_time` are bounded by the server maximum
+        let max_time = match (max_time, self.max_time) {
+            (Some(max_time), _) if max_time <= 0.0 => Err(ValidationError::NegativeMaxTime),
+            (Some(max_time), Some(limit)) if max_time > limit => {
+                Err(ValidationError::MaxTime(limit, max_time))
+            }
+            (max_time, limit) => Ok(max_time.or(limit)),
+        }?;
+
+        if bad_words.len() > MAX_BAD_WORDS {
+            return Err(ValidationError::BadWords(MAX_BAD_WORDS, bad_words.len()));
+        }
+        let bad_words = if bad_words.is_empty() {
+            Vec::new()
+        } else {
+            let bad_words = self
+                .tokenize_phrases(bad_words)
+                .await?
+                .ok_or(ValidationError::BadWordsTokenizer)?;
+            if let Some(ids) = bad_words
+                .iter()
+                .find(|ids| ids.is_empty() || ids.len() > MAX_BAD_WORD_TOKENS)
+            {
+                return Err(ValidationError::BadWordLength(
+                    MAX_BAD_WORD_TOKENS,
+                    ids.len(),
+                ));
             }
+            bad_words
         };
 
+        // If seed is None, assign a random one
+        let seed = seeds[0].unwrap_or_else(|| thread_rng().gen());
+
+        let max_top_n_tokens = self.limits.max_top_n_tokens();
         let top_n_tokens = top_n_tokens
             .map(|value| {
-                if value > self.max_top_n_tokens {
-                    return Err(ValidationError::TopNTokens(self.max_top_n_tokens, value));
+                if value > max_top_n_tokens {
+                    return Err(ValidationError::TopNTokens(max_top_n_tokens, value));
                 }
                 Ok(value)
             })
             .unwrap_or(Ok(0))?;
 
-        // Check if inputs is empty
-        if request.inputs.is_empty() {
-            return Err(EmptyInput);
-        }
-
         // Check if truncate is strictly positive and less than max_input_length
+        let limits = self.limits();
         let truncate = truncate
             .map(|value| {
-                if value == 0 || value > self.max_input_length {
-                    return Err(ValidationError::Truncate(self.max_input_length, value));
+                if value == 0 || value > limits.max_input_length {
+                    return Err(ValidationError::Truncate(limits.max_input_length, value));
                 }
                 Ok(Some(value))
             })
             .unwrap_or(Ok(None))?;
 
+        if ignore_eos_token && !limits.allow_ignore_eos_token {
+            return Err(ValidationError::IgnoreEosTokenNotAllowed);
+        }
+
+        // Chat messages are rendered with the chat template of the model, like the chat completions
+        let request_inputs = match request.messages {
+            Some(messages) => {
+                if !request.inputs.is_empty() || request.input_ids.is_some() || suffix.is_some() {
+                    return Err(ValidationError::Messages);
+                }
+                self.chat_template
+                    .as_ref()
+                    .ok_or(ValidationError::ChatTemplateNotFound)?
+                    .apply(messages, None)
+                    .map_err(|err| ValidationError::ChatTemplate(err.to_string()))?
+            }
+            None => request.inputs,
+        };
+
         // Validate inputs
-        let (inputs, input_length, max_new_tokens) = self
-            .validate_input(request.inputs, truncate, max_new_tokens)
-            .await?;
+        let (inputs, input_ids, input_length, max_new_tokens) = match request.input_ids {
+            Some(input_ids) => {
+                if !request_inputs.is_empty() || suffix.is_some() {
+                    return Err(ValidationError::InputIds);
+                }
+                if input_ids.is_empty() {
+                    return Err(EmptyInput);
+                }
+                let (inputs, input_ids, max_new_tokens) = self
+                    .validate_input_ids(input_ids, truncate, truncation_direction, max_new_tokens)
+                    .await?;
+                (inputs, input_ids.clone(), input_ids.len(), max_new_tokens)
+            }
+            None => {
+                // The inputs are the prefix of a fill-in-the-middle prompt, which may be empty
+                let inputs = match suffix {
+                    Some(suffix) => {
+                        let fim_tokens = self.fim_tokens.as_ref().ok_or(ValidationError::Suffix)?;
+                        fim_tokens.prompt(&request_inputs, &suffix)
+                    }
+                    None => request_inputs,
+                };
+
+                // Check if inputs is empty
+                if inputs.is_empty() {
+                    return Err(EmptyInput);
+                }
+
+                self.validate_input(
+                    inputs,
+                    truncate,
+                    truncation_direction,
+                    add_special_tokens.unwrap_or(true),
+                    input_sanitization,
+                    max_new_tokens,
+                )
+                .await?
+            }
+        };
+
+        if let Some(max_images) = limits.max_images {
+            let images = inputs
+                .iter()
+                .filter(|input| matches!(input.chunk, Some(Chunk::Image(_))))
+                .count();
+            if images > max_images {
+                return Err(ValidationError::TooManyImages(max_images, images));
+            }
+        }
+
+        // 1.0 disables classifier-free guidance on the shards
+        let guidance_scale = guidance_scale.unwrap_or(1.0);
+        if !(1.0..=MAX_GUIDANCE_SCALE).contains(&guidance_scale) {
+            return Err(ValidationError::GuidanceScale(
+                MAX_GUIDANCE_SCALE,
+                guidance_scale,
+            ));
+        }
+        if guidance_scale != 1.0 && !self.supports_guidance {
+            return Err(ValidationError::GuidanceUnsupported);
+        }
+        // The shards run the model on the negative prompt next to the inputs
+        let negative_input_ids = match negative_inputs {
+            Some(_) if guidance_scale == 1.0 => return Err(ValidationError::NegativeInputs),
+            Some(negative_inputs) => {
+                let (encoding, chunks) = self
+                    .tokenize(
+                        negative_inputs,
+                        None,
+                        TruncationDirection::Left,
+                        add_special_tokens.unwrap_or(true),
+                    )
+                    .await?
+                    .ok_or(ValidationError::NegativeInputsTokenizer)?;
+                if chunks
+                    .iter()
+                    .any(|chunk| !matches!(chunk.chunk, Some(Chunk::Text(_))))
+                {
+                    return Err(ValidationError::NegativeInputsTokenizer);
+                }
+                let negative_length = encoding.len();
+                if input_length + negative_length > limits.max_input_length
+                    || input_length + negative_length + max_new_tokens as usize
+                        > limits.max_total_tokens
+                {
+                    return Err(ValidationError::NegativeInputsLength(
+                        limits.max_input_length,
+                        input_length,
+                        negative_length,
+                    ));
+                }
+                encoding.get_ids().to_vec()
+            }
+            None => Vec::new(),
+        };
+
+        // `max_new_tokens` already fits in the total tokens budget
+        let min_new_tokens = min_new_tokens.unwrap_or(0);
+        if min_new_tokens > max_new_tokens {
+            return Err(ValidationError::MinNewTokens(
+                max_new_tokens,
+                min_new_tokens,
+            ));
+        }
 
-        // TODO: we should build the FSM here and pass the compiled FSM instead of the grammar
-        // NOTE: this is currently difficult because we need the tokenizer in Python to build
-        // the FSM and we'd have to load a copy of the tokenizer into our Pyo3 instance which
-        // may be slow and memory intensive. Best case is to have a Rust implementation of the FSM
-        // compiler and use that to build the FSM here.
+        // NOTE: the token FSM is built here to reject the grammars that cannot be generated, but
+        // the model servers still build their own from the regex: the token maps of the large
+        // vocabularies do not fit in the gRPC messages.
 
         // Validate grammar and unpack the grammar and type for the proto message
         let grammar = match grammar {
             Some(grammar) => {
-                // Ensure that grammar is not set if it's not supported
-                if self.disable_grammar_support {
-                    return Err(ValidationError::Grammar);
-                }
-                let valid_grammar = match grammar {
-                    GrammarType::Json(json) => {
-                        let json = match json {
-                            // if value is a string, we need to parse it again to make sure its
-                            // a valid json
-                            Value::String(s) => serde_json::from_str(&s)
-                                .map_err(|e| ValidationError::InvalidGrammar(e.to_string())),
-                            Value::Object(_) => Ok(json),
-                            _ => Err(ValidationError::Grammar),
-                        }?;
-
-                        // Check if the json is a valid JSONSchema
-                        JSONSchema::options()
-                            .with_draft(Draft::Draft202012)
-                            .compile(&json)
-                            .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
-
-                        // Serialize json to string
-                        ValidGrammar::Json(
-                            serde_json::to_string(&json)
-                                .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?,
-                        )
-                    }
-                    GrammarType::Regex(regex) => ValidGrammar::Regex(regex),
-                };
-                Some(valid_grammar)
+                let key = self.grammar_key(limits.allow_grammar)?;
+                let grammar = self.validate_grammar(grammar, &key)?;
+                Some(self.compile_grammar(grammar, &key).await?)
             }
             None => None,
         };
+        // The generated tokens are only checked while the FSM of the grammar is in memory
+        let grammar_fsm = match &grammar {
+            Some(ValidGrammar::Regex(regex)) => self.grammar_fsms.get(regex),
+            _ => None,
+        };
 
+        // Greedy decoding ignores the sampling parameters
+        let (do_sample, top_k, top_p, typical_p, min_p, epsilon_cutoff, eta_cutoff, top_a) =
+            match greedy {
+                true => (false, 0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0),
+                false => (
+                    do_sample,
+                    top_k,
+                    top_p,
+                    typical_p,
+                    min_p,
+                    epsilon_cutoff,
+                    eta_cutoff,
+                    top_a,
+                ),
+            };
+        let mirostat_mode = if greedy { 0 } else { mirostat_mode };
         let parameters = ValidParameters {
             temperature,
             repetition_penalty,
             frequency_penalty,
+            presence_penalty,
+            no_repeat_ngram_size,
+            dry_multiplier,
+            dry_base,
+            dry_allowed_length,
             top_k,
             top_p,
             typical_p,
+            min_p,
+            epsilon_cutoff,
+            eta_cutoff,
+            top_a,
+            mirostat_mode,
+            mirostat_tau,
+            mirostat_eta,
+            logit_bias,
+            bad_words,
+            guidance_scale,
+            negative_input_ids,
+            min_new_tokens,
             do_sample,
             seed,
             watermark,
@@ -381,16 +1663,24 @@ impl Validation {
         let stopping_parameters = ValidStoppingParameters {
             max_new_tokens,
             stop_sequences,
-            ignore_eos_token: false,
+            stop_token_ids,
+            stop_regex,
+            grammar_fsm,
+            ignore_eos_token,
+            max_time,
         };
 
-        metrics::histogram!("tgi_request_max_new_tokens", max_new_tokens as f64);
+        metrics::histogram!("tgi_request_max_new_tokens").record(max_new_tokens as f64);
 
         Ok(ValidGenerateRequest {
             inputs,
+            input_ids,
             decoder_input_details,
             input_length: input_length as u32,
-            truncate: truncate.unwrap_or(self.max_input_length) as u32,
+            // Truncation keeps more tokens than `truncate` rather than splitting an image
+            truncate: truncate.map_or(limits.max_input_length, |truncate| {
+                truncate.max(input_length)
+            }) as u32,
             parameters,
             stopping_parameters,
             top_n_tokens,
@@ -413,224 +1703,1090 @@ impl Validation {
     }
 }
 
-/// Round robin tokenization task
-async fn round_robin_task(
-    mut receiver: mpsc::UnboundedReceiver<TokenizerRequest>,
-    senders: Vec<mpsc::UnboundedSender<TokenizerRequest>>,
-) {
-    loop {
-        for sender in &senders {
-            match receiver.recv().await {
-                None => return,
-                Some(request) => sender.send(request).unwrap(),
-            };
+/// Seed of each candidate of a request, `None` for a random one: the `best_of_seeds`, or seeds
+/// derived from `seed`
+pub(crate) fn candidate_seeds(
+    parameters: &GenerateParameters,
+) -> Result<Vec<Option<u64>>, ValidationError> {
+    let best_of = parameters.best_of.unwrap_or(1);
+    match (parameters.seed, &parameters.best_of_seeds) {
+        (Some(_), Some(_)) => Err(BestOfSeed),
+        (None, Some(seeds)) if seeds.len() != best_of => {
+            Err(ValidationError::BestOfSeeds(best_of, seeds.len()))
         }
+        (None, Some(seeds)) => Ok(seeds.iter().copied().map(Some).collect()),
+        (Some(seed), None) => Ok((0..best_of as u64)
+            .map(|i| Some(seed.wrapping_add(i)))
+            .collect()),
+        (None, None) => Ok(vec![None; best_of.max(1)]),
     }
 }
 
-/// Start tokenization workers
-fn tokenizer_worker(
-    tokenizer: Tokenizer,
-    config: Option<Config>,
-    preprocessor_config: Option<HubPreprocessorConfig>,
-    mut receiver: mpsc::UnboundedReceiver<TokenizerRequest>,
-) {
-    // Loop over requests
-    while let Some(((inputs, truncate), response_tx, parent_span)) = receiver.blocking_recv() {
-        parent_span.in_scope(|| {
-            response_tx
-                .send(prepare_input(
-                    inputs,
-                    truncate,
-                    &tokenizer,
-                    config.as_ref(),
-                    preprocessor_config.as_ref(),
-                ))
-                .unwrap_or(())
-        })
+/// Special tokens of the fill-in-the-middle prompts of the well-known code models: StarCoder,
+/// Qwen2.5-Coder and DeepSeek-Coder
+const KNOWN_FIM_TOKENS: [[&str; 3]; 3] = [
+    ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    ["<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"],
+];
+
+/// Prefix, suffix and middle special tokens of the fill-in-the-middle prompts of a model
+#[derive(Clone, Debug, PartialEq)]
+struct FimTokens {
+    prefix: String,
+    suffix: String,
+    middle: String,
+}
+
+impl From<[String; 3]> for FimTokens {
+    fn from([prefix, suffix, middle]: [String; 3]) -> Self {
+        Self {
+            prefix,
+            suffix,
+            middle,
+        }
     }
 }
 
-fn format_from_mimetype(mimetype: &str) -> Option<ImageFormat> {
-    match mimetype {
-        "image/png" => Some(ImageFormat::Png),
-        "image/jpeg" => Some(ImageFormat::Jpeg),
-        "image/jpg" => Some(ImageFormat::Jpeg),
-        "image/gif" => Some(ImageFormat::Gif),
-        "image/webp" => Some(ImageFormat::WebP),
-        "image/tiff" => Some(ImageFormat::Tiff),
-        // "image/pnm"=>Some(ImageFormat::Pnm),
-        // "image/tga"=>Some(ImageFormat::Tga),
-        // "image/dds"=>Some(ImageFormat::Dds),
-        // "image/bmp"=>Some(ImageFormat::Bmp),
-        // "image/ico"=>Some(ImageFormat::Ico),
-        // "image/x-exr"=>Some(ImageFormat::OpenExr),
-        _ => None,
+impl FimTokens {
+    /// Well-known FIM tokens whose tokens are all in the vocabulary of `tokenizer`
+    fn from_tokenizer(tokenizer: &Tokenizer) -> Option<Self> {
+        KNOWN_FIM_TOKENS
+            .iter()
+            .find(|tokens| {
+                tokens
+                    .iter()
+                    .all(|token| tokenizer.token_to_id(token).is_some())
+            })
+            .map(|tokens| tokens.map(String::from).into())
+    }
+
+    /// Prompt in the prefix-suffix-middle order, the model generates the middle
+    fn prompt(&self, prefix: &str, suffix: &str) -> String {
+        format!(
+            "{}{prefix}{}{suffix}{}",
+            self.prefix, self.suffix, self.middle
+        )
     }
 }
 
-fn format_to_mimetype(format: ImageFormat) -> String {
-    match format {
-        ImageFormat::Png => "image/png",
-        ImageFormat::Jpeg => "image/jpeg",
-        ImageFormat::Gif => "image/gif",
-        ImageFormat::WebP => "image/webp",
-        ImageFormat::Tiff => "image/tiff",
-        _ => "application/octet-stream",
+/// Maximum value of `guidance_scale`
+const MAX_GUIDANCE_SCALE: f32 = 30.0;
+
+/// Maximum number of phrases of `bad_words`
+const MAX_BAD_WORDS: usize = 64;
+
+/// Maximum number of tokens of a phrase of `bad_words`
+const MAX_BAD_WORD_TOKENS: usize = 16;
+
+/// Maximum value of `no_repeat_ngram_size`
+const MAX_NO_REPEAT_NGRAM_SIZE: u32 = 10;
+
+/// `dry_base` of the requests that only set `dry_multiplier`
+const DEFAULT_DRY_BASE: f32 = 1.75;
+
+/// `dry_allowed_length` of the requests that only set `dry_multiplier`
+const DEFAULT_DRY_ALLOWED_LENGTH: u32 = 2;
+
+/// `mirostat_tau` of the requests that only set `mirostat_mode`
+const DEFAULT_MIROSTAT_TAU: f32 = 5.0;
+
+/// `mirostat_eta` of the requests that only set `mirostat_mode`
+const DEFAULT_MIROSTAT_ETA: f32 = 0.1;
+
+/// Maximum number of tokens of `logit_bias`
+const MAX_LOGIT_BIAS_TOKENS: usize = 300;
+
+/// `logit_bias` values are clamped to [-MAX_LOGIT_BIAS, MAX_LOGIT_BIAS]
+const MAX_LOGIT_BIAS: f32 = 100.0;
+
+/// Maximum length of a stop regex
+const MAX_STOP_REGEX_LENGTH: usize = 256;
+/// Maximum size of the compiled stop regexes, in bytes
+const STOP_REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Compile the stop regexes into a single alternation, `None` without any regex
+fn stop_regex_matcher(stop_regex: &[String]) -> Result<Option<Regex>, ValidationError> {
+    if stop_regex.is_empty() {
+        return Ok(None);
     }
-    .to_string()
+    if let Some(regex) = stop_regex
+        .iter()
+        .find(|regex| regex.is_empty() || regex.len() > MAX_STOP_REGEX_LENGTH)
+    {
+        return Err(ValidationError::InvalidStopRegex(format!(
+            "regular expressions must have 1 to {MAX_STOP_REGEX_LENGTH} bytes, given {}",
+            regex.len()
+        )));
+    }
+    let alternation = stop_regex
+        .iter()
+        .map(|regex| format!("(?:{regex})"))
+        .collect::<Vec<_>>()
+        .join("|");
+    RegexBuilder::new(&alternation)
+        .size_limit(STOP_REGEX_SIZE_LIMIT)
+        .build()
+        .map(Some)
+        .map_err(|err| ValidationError::InvalidStopRegex(err.to_string()))
 }
 
-fn fetch_image(input: &str) -> Result<(Vec<u8>, String, usize, usize), ValidationError> {
-    if input.starts_with("![](http://") || input.starts_with("![](https://") {
-        let url = &input["![](".len()..input.len() - 1];
-        let data = reqwest::blocking::get(url)?.bytes()?;
-
-        let format = image::guess_format(&data)?;
-        // TODO Remove this clone
-        let img = ImageReader::with_format(Cursor::new(data.clone()), format).decode()?;
-        let height: usize = img.height().try_into()?;
-        let width: usize = img.width().try_into()?;
-        let mimetype = format_to_mimetype(format);
-        Ok((data.to_vec(), mimetype, height, width))
-    } else if input.starts_with("![](data:") {
-        // Remove ![](....)
-        let content = &input["![](data:".len()..input.len() - 1];
-        let tokens: Vec<_> = content.split(';').collect();
-        if tokens.len() != 2 {
-            return Err(ValidationError::InvalidImageContent(content.to_string()));
-        }
-        let mimetype = tokens[0];
-        let content = tokens[1];
-
-        if !content.starts_with("base64,") {
-            return Err(ValidationError::InvalidImageContent(content.to_string()));
-        }
-
-        let data = STANDARD.decode(content["base64,".len()..].as_bytes())?;
-        let img = if let Some(format) = format_from_mimetype(mimetype) {
-            ImageReader::with_format(Cursor::new(&data), format).decode()?
-        } else {
-            ImageReader::new(Cursor::new(&data))
-                .with_guessed_format()
-                .map_err(|_io_error| ValidationError::InvalidImageContent(content.to_string()))?
-                .decode()?
-        };
+/// Alternation of the literal `choices`, in the regex syntax of the model servers
+fn choice_regex(choices: &[String]) -> String {
+    let choices: Vec<String> = choices
+        .iter()
+        .map(|choice| grammar::escape(choice))
+        .collect();
+    format!("({})", choices.join("|"))
+}
+
+/// Maximum length of the `regex` grammars
+const MAX_REGEX_GRAMMAR_LENGTH: usize = 4 * 1024;
+/// Maximum nesting of the groups and repetitions of the `regex` grammars
+const REGEX_GRAMMAR_NEST_LIMIT: u32 = 32;
+/// Maximum size of the compiled `regex` grammars, in bytes
+const REGEX_GRAMMAR_SIZE_LIMIT: usize = 1 << 20;
+
+/// Check the syntax and complexity of a user `regex` grammar. The generated text always matches
+/// the whole regex: the leading `^` and trailing `$` are removed, the other anchors and word
+/// boundaries are rejected as the model servers do not support them.
+fn check_regex_grammar(regex: &str) -> Result<String, ValidationError> {
+    if regex.is_empty() || regex.len() > MAX_REGEX_GRAMMAR_LENGTH {
+        return Err(ValidationError::InvalidGrammar(format!(
+            "`regex` grammars must have 1 to {MAX_REGEX_GRAMMAR_LENGTH} bytes, given {}",
+            regex.len()
+        )));
+    }
+    let regex = regex.strip_prefix('^').unwrap_or(regex);
+    let regex = match regex.strip_suffix('$') {
+        // `\$` is a literal dollar sign
+        Some(stripped) if stripped.chars().rev().take_while(|&c| c == '\\').count() % 2 == 0 => {
+            stripped
+        }
+        _ => regex,
+    };
+    if regex.is_empty() {
+        return Err(ValidationError::InvalidGrammar(
+            "`regex` grammars cannot be empty".to_string(),
+        ));
+    }
+
+    let hir = regex_automata::util::syntax::parse_with(
+        regex,
+        &regex_automata::util::syntax::Config::new().nest_limit(REGEX_GRAMMAR_NEST_LIMIT),
+    )
+    .map_err(|err| ValidationError::InvalidGrammar(format!("invalid `regex`: {err}")))?;
+    if !hir.properties().look_set().is_empty() {
+        return Err(ValidationError::InvalidGrammar(
+            "`regex` grammars cannot contain anchors or word boundaries, the generated text \
+             always matches the whole regex"
+                .to_string(),
+        ));
+    }
+    RegexBuilder::new(regex)
+        .size_limit(REGEX_GRAMMAR_SIZE_LIMIT)
+        .build()
+        .map_err(|err| ValidationError::InvalidGrammar(format!("invalid `regex`: {err}")))?;
+    Ok(regex.to_string())
+}
+
+/// Maximum number of grammars of a `union`
+const MAX_UNION_GRAMMARS: usize = 8;
 
-        let height: usize = img.height().try_into()?;
-        let width: usize = img.width().try_into()?;
-        Ok((data, mimetype.to_string(), height, width))
+/// Alternation of the valid `grammars`: JSON Schemas are combined with `anyOf`, regexes with `|`.
+/// The two kinds cannot be mixed.
+fn union_grammar(grammars: Vec<ValidGrammar>) -> Result<ValidGrammar, ValidationError> {
+    let mut schemas = Vec::new();
+    let mut regexes = Vec::new();
+    for grammar in grammars {
+        match grammar {
+            ValidGrammar::Json(schema) => schemas.push(schema),
+            ValidGrammar::Regex(regex) => regexes.push(format!("({regex})")),
+            ValidGrammar::Cfg(_) => {
+                return Err(ValidationError::InvalidGrammar(
+                    "`union` cannot contain context-free `lark` grammars".to_string(),
+                ))
+            }
+        }
+    }
+    if regexes.is_empty() {
+        // The `$ref`s of the schemas are already inlined
+        let any_of = schemas
+            .iter()
+            .map(|schema| serde_json::from_str(schema))
+            .collect::<Result<Vec<Value>, _>>()
+            .map_err(|err| ValidationError::InvalidGrammar(err.to_string()))?;
+        Ok(ValidGrammar::Json(
+            serde_json::json!({ "anyOf": any_of }).to_string(),
+        ))
+    } else if schemas.is_empty() {
+        Ok(ValidGrammar::Regex(format!("({})", regexes.join("|"))))
     } else {
-        Err(ValidationError::InvalidImageContent(input.to_string()))
+        Err(ValidationError::InvalidGrammar(
+            "`union` cannot mix JSON Schemas with regex grammars".to_string(),
+        ))
     }
 }
 
-fn image_tokens(
-    config: &Config,
-    preprocessor_config: Option<&HubPreprocessorConfig>,
-    height: usize,
-    width: usize,
-) -> String {
-    use Config::*;
-    use HubPreprocessorConfig::*;
-    match config {
-        Idefics => "<image>".to_string(),
-        Idefics2(config) => {
-            const FAKE: &str = "<fake_token_around_image>";
-            const IMAGE: &str = "<image>";
+/// Record the size of the compiled token FSM of a grammar
+fn record_fsm_size(fsm: &TokenFsm) {
+    metrics::histogram!("tgi_grammar_fsm_states").record(fsm.states() as f64);
+    metrics::histogram!("tgi_grammar_fsm_transitions").record(fsm.transitions() as f64);
+}
 
-            let slots = config.get_number_of_features(height, width);
+/// Maximum number of compiled grammars kept in the cache
+const GRAMMAR_CACHE_SIZE: usize = 1024;
 
-            let mut image_string = String::with_capacity(2 * FAKE.len() + slots * IMAGE.len());
-            image_string.push_str(FAKE);
-            image_string.extend(iter::repeat(IMAGE).take(slots));
-            image_string.push_str(FAKE);
+/// Bounded map by string keys, shared by its clones.
+/// The oldest entry is evicted when the map is full, updating an entry keeps its place.
+#[derive(Debug)]
+struct BoundedMap<V> {
+    capacity: usize,
+    /// Gauge of the number of entries
+    metric: Option<&'static str>,
+    inner: Arc<Mutex<BoundedMapInner<V>>>,
+}
 
-            if matches!(
-                preprocessor_config,
-                Some(Idefics2Processor(Idefics2Preprocessor {
-                    do_image_splitting: true,
-                    ..
-                }))
-            ) {
-                image_string = image_string.repeat(5);
-            };
+#[derive(Debug)]
+struct BoundedMapInner<V> {
+    entries: HashMap<String, V>,
+    /// Keys from the oldest to the newest
+    order: VecDeque<String>,
+}
 
-            image_string
+impl<V> Clone for BoundedMap<V> {
+    fn clone(&self) -> Self {
+        Self {
+            capacity: self.capacity,
+            metric: self.metric,
+            inner: self.inner.clone(),
         }
-        Paligemma(config) => "<image>".repeat(config.get_number_of_features(height, width)),
-        LlavaNext(config) => "<image>".repeat(config.get_number_of_features(height, width)),
-        _ => unimplemented!("Images tokens are not supported for this model configuration"),
     }
 }
 
-fn image_tokens_fixup(config: &Config, text: String) -> String {
-    match config {
-        Config::Idefics2(_) => {
-            const FAKE: &str = "<fake_token_around_image>";
-            text.replace(&format!("{FAKE}{FAKE}"), FAKE)
+impl<V: Clone> BoundedMap<V> {
+    fn new(capacity: usize, metric: Option<&'static str>) -> Self {
+        Self {
+            capacity,
+            metric,
+            inner: Arc::new(Mutex::new(BoundedMapInner {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            })),
         }
-        _ => text,
     }
-}
 
-/// Get input length and optionally truncate it
-fn prepare_input(
-    inputs: String,
-    _truncate: Option<usize>,
-    tokenizer: &Tokenizer,
-    config: Option<&Config>,
-    preprocessor_config: Option<&HubPreprocessorConfig>,
-) -> Result<(tokenizers::Encoding, Vec<InputChunk>), ValidationError> {
-    use Config::*;
-    static RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"!\[\]\([^\)]*\)").unwrap());
-    let (tokenizer_query, input_chunks) = match config {
-        Some(config @ (Idefics | Idefics2(_) | Paligemma(_) | LlavaNext(_))) => {
-            let mut input_chunks = Vec::new();
-            let mut tokenizer_query = String::with_capacity(inputs.len());
-            let mut start = 0;
-            for chunk in RE.find_iter(&inputs) {
-                let chunk_start = chunk.start();
-                let chunk_end = chunk.end();
-                if chunk_start != start {
-                    input_chunks.push(Chunk::Text(inputs[start..chunk_start].to_string()).into());
-                    tokenizer_query.push_str(&inputs[start..chunk_start]);
-                }
-                let (data, mimetype, height, width) = fetch_image(&inputs[chunk_start..chunk_end])?;
-                input_chunks.push(Chunk::Image(Image { data, mimetype }).into());
-                tokenizer_query.push_str(&image_tokens(config, preprocessor_config, height, width));
-                start = chunk_end;
-            }
-            if start != inputs.len() {
-                input_chunks.push(Chunk::Text(inputs[start..].to_string()).into());
-                tokenizer_query.push_str(&inputs[start..]);
-            }
+    fn get(&self, key: &str) -> Option<V> {
+        self.inner.lock().unwrap().entries.get(key).cloned()
+    }
 
-            tokenizer_query = image_tokens_fixup(config, tokenizer_query);
+    fn contains(&self, key: &str) -> bool {
+        self.inner.lock().unwrap().entries.contains_key(key)
+    }
 
-            (tokenizer_query, input_chunks)
+    fn insert(&self, key: String, value: V) {
+        let mut inner = self.inner.lock().unwrap();
+        let BoundedMapInner { entries, order } = &mut *inner;
+        if let Some(existing) = entries.get_mut(&key) {
+            *existing = value;
+            return;
         }
-        _ => (inputs.clone(), vec![Chunk::Text(inputs).into()]),
-    };
+        if self.capacity == 0 {
+            return;
+        }
+        if entries.len() >= self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key.clone(), value);
+        order.push_back(key);
+        if let Some(metric) = self.metric {
+            metrics::gauge!(metric).set(entries.len() as f64);
+        }
+    }
+}
 
-    // Get the number of tokens in the input
-    let encoding = tokenizer
-        .encode(tokenizer_query, true)
-        .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+/// Grammars that already compiled
+type GrammarCache = BoundedMap<()>;
 
-    Ok((encoding, input_chunks))
+/// Maximum number of token FSMs kept in memory, they take megabytes with the large vocabularies
+const GRAMMAR_FSMS_SIZE: usize = 64;
+
+/// Token FSMs of the regex grammars, by regex
+type GrammarFsms = BoundedMap<Arc<TokenFsm>>;
+
+/// Maximum number of grammars compiled by `/grammar/compile`
+const GRAMMAR_ARTIFACTS_SIZE: usize = 1024;
+
+/// Grammar compiled by `/grammar/compile`
+#[derive(Clone, Debug)]
+pub(crate) enum GrammarArtifact {
+    Compiling,
+    Ready(ValidGrammar),
+    /// The compilation error
+    Failed(String),
 }
 
-type TokenizerRequest = (
-    (String, Option<usize>),
-    oneshot::Sender<Result<(tokenizers::Encoding, Vec<InputChunk>), ValidationError>>,
-    Span,
-);
+/// Grammars compiled by `/grammar/compile`, by id
+type GrammarArtifacts = BoundedMap<GrammarArtifact>;
 
-#[derive(Debug, Clone)]
+/// Grammars taking longer to compile count as compilation failures
+const GRAMMAR_SLOW_COMPILE: Duration = Duration::from_secs(1);
+
+/// Throttle key of the compilation of the grammar registry
+const GRAMMAR_REGISTRY_KEY: &str = "grammar_registry";
+
+/// Set when dropped, to cancel the compilation of a grammar whose request timed out or was
+/// dropped
+#[derive(Debug, Default)]
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Recent grammar compilation failures by caller key. Callers with `max_failures` failures
+/// during `window` cannot send grammars until their oldest failure leaves the window.
+/// Disabled if `max_failures` is 0.
+#[derive(Debug, Clone)]
+struct GrammarThrottle {
+    max_failures: usize,
+    window: Duration,
+    failures: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
+}
+
+impl GrammarThrottle {
+    fn new(max_failures: usize, window: Duration) -> Self {
+        Self {
+            max_failures,
+            window,
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Time until `key` can send grammars again, `None` if it is not throttled
+    fn retry_after(&self, key: &str, now: Instant) -> Option<Duration> {
+        if self.max_failures == 0 {
+            return None;
+        }
+        let failures = self.failures.lock().unwrap();
+        let failures = failures.get(key)?;
+        let recent: Vec<&Instant> = failures
+            .iter()
+            .filter(|failure| now.duration_since(**failure) < self.window)
+            .collect();
+        if recent.len() < self.max_failures {
+            return None;
+        }
+        Some(self.window - now.duration_since(*recent[0]))
+    }
+
+    fn record_failure(&self, key: String, now: Instant) {
+        if self.max_failures == 0 {
+            return;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        // Forget the callers without recent failures
+        failures.retain(|_, key_failures| {
+            key_failures
+                .back()
+                .is_some_and(|last| now.duration_since(*last) < self.window)
+        });
+        let key_failures = failures.entry(key).or_default();
+        key_failures.push_back(now);
+        if key_failures.len() > self.max_failures {
+            key_failures.pop_front();
+        }
+    }
+}
+
+/// Interval between two logs of the aggregated validation rejections
+const REJECTIONS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Validation rejections counted by error code and offending parameter.
+/// The counts are logged and reset every `REJECTIONS_LOG_INTERVAL`.
+#[derive(Debug, Clone)]
+pub(crate) struct ValidationRejections {
+    /// Ratio of the rejected requests logged with a summary of their payload
+    sample_rate: f64,
+    counts: Arc<Mutex<HashMap<(&'static str, &'static str), u64>>>,
+}
+
+impl ValidationRejections {
+    pub(crate) fn new(sample_rate: f64) -> Self {
+        let rejections = Self {
+            sample_rate,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        };
+        tokio::spawn(rejections.clone().log_task());
+        rejections
+    }
+
+    /// Whether the payload of the next request should be summarized in case of rejection
+    pub(crate) fn sample(&self) -> bool {
+        self.sample_rate > 0.0 && thread_rng().gen::<f64>() < self.sample_rate
+    }
+
+    /// Count a rejection and log the payload summary of sampled requests
+    pub(crate) fn record(&self, err: &ValidationError, example: Option<String>) {
+        let (code, parameter) = (err.code(), err.parameter());
+        metrics::counter!("tgi_validation_rejection", "code" => code, "parameter" => parameter)
+            .increment(1);
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry((code, parameter))
+            .or_default() += 1;
+        if let Some(example) = example {
+            tracing::info!("Rejected request ({code}): {example}");
+        }
+    }
+
+    /// Reset the counts and return them sorted from the most frequent
+    fn take(&self) -> Vec<((&'static str, &'static str), u64)> {
+        let mut counts: Vec<_> = self.counts.lock().unwrap().drain().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+
+    async fn log_task(self) {
+        let mut ticker = tokio::time::interval(REJECTIONS_LOG_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let counts = self.take();
+            if counts.is_empty() {
+                continue;
+            }
+            let summary = counts
+                .iter()
+                .map(|((code, parameter), count)| format!("{code} ({parameter}): {count}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            tracing::warn!(
+                "Validation rejections in the last {REJECTIONS_LOG_INTERVAL:?}: {summary}"
+            );
+        }
+    }
+}
+
+/// Round robin tokenization task
+async fn round_robin_task(
+    mut receiver: mpsc::UnboundedReceiver<TokenizerRequest>,
+    senders: Vec<mpsc::UnboundedSender<TokenizerRequest>>,
+) {
+    loop {
+        for sender in &senders {
+            match receiver.recv().await {
+                None => return,
+                Some(request) => sender.send(request).unwrap(),
+            };
+        }
+    }
+}
+
+/// Start tokenization workers
+fn tokenizer_worker(
+    tokenizer: Tokenizer,
+    config: Option<Config>,
+    preprocessor_config: Option<HubPreprocessorConfig>,
+    fetch_policy: FetchPolicy,
+    multimodal_limits: MultimodalLimits,
+    image_cache: ImageCache,
+    mut receiver: mpsc::UnboundedReceiver<TokenizerRequest>,
+) {
+    // Loop over requests
+    while let Some((query, parent_span, queued)) = receiver.blocking_recv() {
+        metrics::gauge!("tgi_tokenizer_queue_size").decrement(1.0);
+        metrics::histogram!("tgi_tokenizer_queue_duration").record(queued.elapsed().as_secs_f64());
+        parent_span.in_scope(|| {
+            let start_time = Instant::now();
+            match query {
+                TokenizerQuery::Inputs(
+                    (inputs, truncate, truncation_direction, add_special_tokens),
+                    response_tx,
+                ) => {
+                    let result = prepare_input(
+                        inputs,
+                        truncate,
+                        truncation_direction,
+                        add_special_tokens,
+                        &tokenizer,
+                        config.as_ref(),
+                        preprocessor_config.as_ref(),
+                        &fetch_policy,
+                        &multimodal_limits,
+                        &image_cache,
+                    );
+                    metrics::histogram!("tgi_tokenizer_duration")
+                        .record(start_time.elapsed().as_secs_f64());
+                    response_tx.send(result).unwrap_or(())
+                }
+                TokenizerQuery::Decode(ids, response_tx) => {
+                    let result = tokenizer
+                        .decode(&ids, false)
+                        .map_err(|err| ValidationError::Tokenizer(err.to_string()));
+                    metrics::histogram!("tgi_tokenizer_duration")
+                        .record(start_time.elapsed().as_secs_f64());
+                    response_tx.send(result).unwrap_or(())
+                }
+                TokenizerQuery::Phrases(phrases, response_tx) => {
+                    let result = phrases
+                        .iter()
+                        .map(|phrase| {
+                            tokenizer
+                                .encode(phrase.as_str(), false)
+                                .map(|encoding| encoding.get_ids().to_vec())
+                                .map_err(|err| ValidationError::Tokenizer(err.to_string()))
+                        })
+                        .collect();
+                    metrics::histogram!("tgi_tokenizer_duration")
+                        .record(start_time.elapsed().as_secs_f64());
+                    response_tx.send(result).unwrap_or(())
+                }
+            }
+        })
+    }
+}
+
+/// Maximum size of an image, in bytes, unless set by the multimodal limits
+const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// Split a `data:<mimetype>[;<parameter>...];base64,<data>` URI into its mimetype and its
+/// base64 encoded data. Only base64 encoded data URIs are supported.
+fn parse_data_uri(uri: &str) -> Option<(&str, &str)> {
+    let (header, data) = uri.strip_prefix("data:")?.split_once(',')?;
+    let mut parameters = header.split(';');
+    let mimetype = parameters.next()?;
+    match parameters.last() {
+        Some("base64") => Some((mimetype, data)),
+        _ => None,
+    }
+}
+
+/// Prefix of `content`, so that errors do not echo whole data URIs
+fn truncated(content: &str) -> String {
+    const MAX_LENGTH: usize = 64;
+    match content.char_indices().nth(MAX_LENGTH) {
+        Some((index, _)) => format!("{}...", &content[..index]),
+        None => content.to_string(),
+    }
+}
+
+/// Fetch or decode a `![](<URL or data URI>)` chunk and prepare it for the model servers.
+/// A `#detail=low|high|auto` suffix selects the resolution of the image.
+fn fetch_image(
+    input: &str,
+    fetch_policy: &FetchPolicy,
+    limits: &MultimodalLimits,
+    image_cache: &ImageCache,
+) -> Result<ProcessedImage, ValidationError> {
+    let target = &input["![](".len()..input.len() - 1];
+    let (target, detail) = match target.rsplit_once("#detail=") {
+        Some((target, detail)) => (
+            target,
+            detail.parse().map_err(|_| {
+                ValidationError::InvalidImageContent(format!("invalid detail `{detail}`"))
+            })?,
+        ),
+        None => (target, ImageDetail::Auto),
+    };
+    let max_image_bytes = limits.max_image_bytes.unwrap_or(MAX_IMAGE_BYTES);
+    let url = target.starts_with("http://") || target.starts_with("https://");
+    let (data, declared) = if url {
+        if let Some(image) = image_cache.get_url(target, limits, detail) {
+            return Ok(image);
+        }
+        (fetch_policy.fetch(target, max_image_bytes)?, None)
+    } else if target.starts_with("data:") {
+        let (mimetype, content) = parse_data_uri(target)
+            .filter(|(mimetype, _)| mimetype.starts_with("image/"))
+            .ok_or_else(|| ValidationError::InvalidImageContent(truncated(target)))?;
+        // Reject oversized images before decoding them
+        if content.len() / 4 * 3 > max_image_bytes {
+            return Err(ValidationError::ImageSize(
+                max_image_bytes,
+                content.len() / 4 * 3,
+            ));
+        }
+        (STANDARD.decode(content.as_bytes())?, Some(mimetype))
+    } else {
+        return Err(ValidationError::InvalidImageContent(truncated(input)));
+    };
+    if data.len() > max_image_bytes {
+        return Err(ValidationError::ImageSize(max_image_bytes, data.len()));
+    }
+    image_cache.process(data, declared, limits, detail, url.then_some(target))
+}
+
+/// Maximum size of a document, in bytes
+const MAX_DOCUMENT_BYTES: usize = 50 * 1024 * 1024;
+
+/// Fetch or decode a `![document](<URL or data URI>)` chunk, only PDF documents are supported
+fn fetch_document(input: &str, fetch_policy: &FetchPolicy) -> Result<Vec<u8>, ValidationError> {
+    let target = &input["![document](".len()..input.len() - 1];
+    let data = if target.starts_with("data:") {
+        let (_, data) = parse_data_uri(target)
+            .filter(|(mimetype, _)| *mimetype == "application/pdf")
+            .ok_or_else(|| ValidationError::InvalidDocumentContent(truncated(target)))?;
+        STANDARD.decode(data.as_bytes())?
+    } else if target.starts_with("http://") || target.starts_with("https://") {
+        fetch_policy.fetch(target, MAX_DOCUMENT_BYTES)?
+    } else {
+        return Err(ValidationError::InvalidDocumentContent(truncated(target)));
+    };
+    if data.len() > MAX_DOCUMENT_BYTES {
+        return Err(ValidationError::DocumentSize(
+            MAX_DOCUMENT_BYTES,
+            data.len(),
+        ));
+    }
+    if !data.starts_with(b"%PDF-") {
+        return Err(ValidationError::InvalidDocumentContent(
+            "expected a PDF document".to_string(),
+        ));
+    }
+    Ok(data)
+}
+
+fn image_tokens(
+    config: &Config,
+    preprocessor_config: Option<&HubPreprocessorConfig>,
+    height: usize,
+    width: usize,
+) -> String {
+    use Config::*;
+    use HubPreprocessorConfig::*;
+    match config {
+        // The processor wraps every image in fake tokens, like Idefics2 without the slots
+        Idefics => "<fake_token_around_image><image><fake_token_around_image>".to_string(),
+        Idefics2(config) => {
+            const FAKE: &str = "<fake_token_around_image>";
+            const IMAGE: &str = "<image>";
+
+            let slots = config.get_number_of_features(height, width);
+
+            let mut image_string = String::with_capacity(2 * FAKE.len() + slots * IMAGE.len());
+            image_string.push_str(FAKE);
+            image_string.extend(iter::repeat(IMAGE).take(slots));
+            image_string.push_str(FAKE);
+
+            if matches!(
+                preprocessor_config,
+                Some(Idefics2Processor(Idefics2Preprocessor {
+                    do_image_splitting: true,
+                    ..
+                }))
+            ) {
+                image_string = image_string.repeat(5);
+            };
+
+            image_string
+        }
+        Paligemma(config) => "<image>".repeat(config.get_number_of_features(height, width)),
+        LlavaNext(config) => "<image>".repeat(config.get_number_of_features(height, width)),
+        _ => unimplemented!("Images tokens are not supported for this model configuration"),
+    }
+}
+
+/// Frames sampled from a video without `frames` hint
+const DEFAULT_VIDEO_FRAMES: u32 = 8;
+/// Maximum number of frames sampled from a video
+const MAX_VIDEO_FRAMES: u32 = 64;
+
+/// Parse a `![video](<URI or data URI>#frames=<count>&fps=<rate>)` chunk, the frame-sampling
+/// hints are optional. URIs are checked against the fetch policy and fetched by the model server.
+fn parse_video(input: &str, fetch_policy: &FetchPolicy) -> Result<Video, ValidationError> {
+    let target = &input["![video](".len()..input.len() - 1];
+    let (target, (num_frames, fps)) = match target
+        .rsplit_once('#')
+        .and_then(|(target, fragment)| Some((target, frame_hints(fragment)?)))
+    {
+        Some((target, hints)) => (target, hints),
+        None => (target, (None, None)),
+    };
+    let num_frames = num_frames.unwrap_or(DEFAULT_VIDEO_FRAMES);
+    if num_frames == 0 || num_frames > MAX_VIDEO_FRAMES {
+        return Err(ValidationError::VideoFrames(MAX_VIDEO_FRAMES, num_frames));
+    }
+
+    let (source, mimetype) = if target.starts_with("data:") {
+        let (mimetype, data) = parse_data_uri(target)
+            .filter(|(mimetype, _)| mimetype.starts_with("video/"))
+            .ok_or_else(|| ValidationError::InvalidVideoContent(truncated(target)))?;
+        let data = STANDARD.decode(data.as_bytes())?;
+        (video::Source::Data(data), mimetype.to_string())
+    } else {
+        fetch_policy
+            .check_url(target)
+            .map_err(|err| ValidationError::InvalidVideoContent(err.to_string()))?;
+        let mimetype = match target.rsplit('.').next() {
+            Some("mp4") => "video/mp4",
+            Some("webm") => "video/webm",
+            Some("mov") => "video/quicktime",
+            Some("mkv") => "video/x-matroska",
+            _ => "",
+        };
+        (video::Source::Uri(target.to_string()), mimetype.to_string())
+    };
+    Ok(Video {
+        source: Some(source),
+        mimetype,
+        num_frames,
+        fps,
+    })
+}
+
+/// `frames` and `fps` hints of a video fragment, `None` if the fragment is not made of hints
+fn frame_hints(fragment: &str) -> Option<(Option<u32>, Option<f32>)> {
+    let mut hints = (None, None);
+    for hint in fragment.split('&') {
+        match hint.split_once('=')? {
+            ("frames", frames) => hints.0 = Some(frames.parse().ok()?),
+            ("fps", fps) => {
+                hints.1 = Some(
+                    fps.parse()
+                        .ok()
+                        .filter(|fps: &f32| fps.is_finite() && *fps > 0.0)?,
+                )
+            }
+            _ => return None,
+        }
+    }
+    Some(hints)
+}
+
+/// Video frames are counted as images at the base resolution of the vision model
+fn video_tokens(
+    config: &Config,
+    preprocessor_config: Option<&HubPreprocessorConfig>,
+    num_frames: u32,
+) -> String {
+    let size = match config {
+        Config::LlavaNext(config) => config.vision_config.image_size,
+        _ => 1,
+    };
+    image_tokens(config, preprocessor_config, size, size).repeat(num_frames as usize)
+}
+
+/// Supported audio sample rates, in Hz
+const AUDIO_SAMPLE_RATES: std::ops::RangeInclusive<u32> = 8_000..=48_000;
+/// Maximum duration of an audio input, in seconds
+const MAX_AUDIO_DURATION: f32 = 300.0;
+/// Maximum size of a fetched audio input, in bytes: above `MAX_AUDIO_DURATION` of 32-bit stereo
+/// audio at the highest sample rate
+const MAX_AUDIO_BYTES: usize = 128 * 1024 * 1024;
+/// Tokens of the audio encoders per second of audio
+const AUDIO_TOKENS_PER_SECOND: f32 = 25.0;
+
+/// Fetch or decode a `![audio](<URL or data URI>)` chunk and check its WAV header
+fn fetch_audio(input: &str, fetch_policy: &FetchPolicy) -> Result<Audio, ValidationError> {
+    let target = &input["![audio](".len()..input.len() - 1];
+    let (data, mimetype) = if target.starts_with("data:") {
+        let (mimetype, data) = parse_data_uri(target)
+            .ok_or_else(|| ValidationError::InvalidAudioContent(truncated(target)))?;
+        (STANDARD.decode(data.as_bytes())?, mimetype.to_string())
+    } else if target.starts_with("http://") || target.starts_with("https://") {
+        (
+            fetch_policy.fetch(target, MAX_AUDIO_BYTES)?,
+            "audio/wav".to_string(),
+        )
+    } else {
+        return Err(ValidationError::InvalidAudioContent(target.to_string()));
+    };
+    if !matches!(
+        mimetype.as_str(),
+        "audio/wav" | "audio/x-wav" | "audio/wave"
+    ) {
+        return Err(ValidationError::InvalidAudioContent(format!(
+            "unsupported format {mimetype}, expected audio/wav"
+        )));
+    }
+
+    let (sample_rate, duration) = wav_properties(&data)
+        .ok_or_else(|| ValidationError::InvalidAudioContent("invalid WAV header".to_string()))?;
+    if !AUDIO_SAMPLE_RATES.contains(&sample_rate) {
+        return Err(ValidationError::AudioSampleRate(
+            *AUDIO_SAMPLE_RATES.start(),
+            *AUDIO_SAMPLE_RATES.end(),
+            sample_rate,
+        ));
+    }
+    if duration > MAX_AUDIO_DURATION {
+        return Err(ValidationError::AudioDuration(MAX_AUDIO_DURATION, duration));
+    }
+    Ok(Audio {
+        data,
+        mimetype,
+        sample_rate,
+        duration,
+    })
+}
+
+/// Sample rate and duration, in seconds, of a RIFF WAVE file
+fn wav_properties(data: &[u8]) -> Option<(u32, f32)> {
+    let u32_at = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut format = None;
+    let mut offset = 12;
+    while let (Some(id), Some(size)) = (data.get(offset..offset + 4), u32_at(offset + 4)) {
+        let body = offset + 8;
+        match id {
+            // Sample rate and byte rate
+            b"fmt " => format = Some((u32_at(body + 4)?, u32_at(body + 8)?)),
+            b"data" => {
+                let (sample_rate, byte_rate) = format?;
+                if byte_rate == 0 {
+                    return None;
+                }
+                return Some((sample_rate, size as f32 / byte_rate as f32));
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size
+        offset = body + size as usize + (size as usize % 2);
+    }
+    None
+}
+
+fn audio_tokens(duration: f32) -> String {
+    "<audio>".repeat((duration * AUDIO_TOKENS_PER_SECOND).ceil() as usize)
+}
+
+fn image_tokens_fixup(config: &Config, text: String) -> String {
+    match config {
+        Config::Idefics | Config::Idefics2(_) => {
+            const FAKE: &str = "<fake_token_around_image>";
+            text.replace(&format!("{FAKE}{FAKE}"), FAKE)
+        }
+        _ => text,
+    }
+}
+
+/// Markdown media chunks of the inputs of multimodal models: images, videos, audios and documents
+static MEDIA_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"!\[(video|audio|document)?\]\([^\)]*\)").unwrap());
+
+/// Get input length and optionally truncate it
+#[allow(clippy::too_many_arguments)]
+fn prepare_input(
+    inputs: String,
+    truncate: Option<usize>,
+    truncation_direction: TruncationDirection,
+    add_special_tokens: bool,
+    tokenizer: &Tokenizer,
+    config: Option<&Config>,
+    preprocessor_config: Option<&HubPreprocessorConfig>,
+    fetch_policy: &FetchPolicy,
+    multimodal_limits: &MultimodalLimits,
+    image_cache: &ImageCache,
+) -> Result<(tokenizers::Encoding, Vec<InputChunk>), ValidationError> {
+    use Config::*;
+    let (tokenizer_query, mut input_chunks) = match config {
+        Some(config @ (Idefics | Idefics2(_) | Paligemma(_) | LlavaNext(_))) => {
+            // Count the images before fetching any of them
+            if let Some(max_images) = multimodal_limits.max_images {
+                let images = MEDIA_RE
+                    .captures_iter(&inputs)
+                    .filter(|chunk| chunk.get(1).is_none())
+                    .count();
+                if images > max_images {
+                    return Err(ValidationError::TooManyImages(max_images, images));
+                }
+            }
+            let mut input_chunks = Vec::new();
+            let mut tokenizer_query = String::with_capacity(inputs.len());
+            let mut start = 0;
+            for chunk in MEDIA_RE.find_iter(&inputs) {
+                let chunk_start = chunk.start();
+                let chunk_end = chunk.end();
+                if chunk_start != start {
+                    input_chunks.push(Chunk::Text(inputs[start..chunk_start].to_string()).into());
+                    tokenizer_query.push_str(&inputs[start..chunk_start]);
+                }
+                let chunk = &inputs[chunk_start..chunk_end];
+                if chunk.starts_with("![video](") {
+                    let video = parse_video(chunk, fetch_policy)?;
+                    tokenizer_query.push_str(&video_tokens(
+                        config,
+                        preprocessor_config,
+                        video.num_frames,
+                    ));
+                    input_chunks.push(Chunk::Video(video).into());
+                } else if chunk.starts_with("![audio](") {
+                    let audio = fetch_audio(chunk, fetch_policy)?;
+                    tokenizer_query.push_str(&audio_tokens(audio.duration));
+                    input_chunks.push(Chunk::Audio(audio).into());
+                } else if chunk.starts_with("![document](") {
+                    let document = fetch_document(chunk, fetch_policy)?;
+                    for page in rasterize_pdf(&document, multimodal_limits)? {
+                        let ProcessedImage {
+                            data,
+                            mimetype,
+                            height,
+                            width,
+                        } = image_cache.process(
+                            page,
+                            None,
+                            multimodal_limits,
+                            ImageDetail::Auto,
+                            None,
+                        )?;
+                        input_chunks.push(Chunk::Image(Image { data, mimetype }).into());
+                        tokenizer_query.push_str(&image_tokens(
+                            config,
+                            preprocessor_config,
+                            height,
+                            width,
+                        ));
+                    }
+                } else {
+                    let ProcessedImage {
+                        data,
+                        mimetype,
+                        height,
+                        width,
+                    } = fetch_image(chunk, fetch_policy, multimodal_limits, image_cache)?;
+                    input_chunks.push(Chunk::Image(Image { data, mimetype }).into());
+                    tokenizer_query.push_str(&image_tokens(
+                        config,
+                        preprocessor_config,
+                        height,
+                        width,
+                    ));
+                }
+                start = chunk_end;
+            }
+            if start != inputs.len() {
+                input_chunks.push(Chunk::Text(inputs[start..].to_string()).into());
+                tokenizer_query.push_str(&inputs[start..]);
+            }
+
+            tokenizer_query = image_tokens_fixup(config, tokenizer_query);
+
+            (tokenizer_query, input_chunks)
+        }
+        _ => (inputs.clone(), vec![Chunk::Text(inputs).into()]),
+    };
+
+    // Get the number of tokens in the input
+    let mut encoding = tokenizer
+        .encode(tokenizer_query.as_str(), add_special_tokens)
+        .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+    if let Some(truncate) = truncate {
+        let multimodal = config.is_some_and(Config::is_multimodal);
+        match truncation_direction {
+            TruncationDirection::Left => {
+                truncate_encoding(tokenizer, &mut encoding, truncate, multimodal)
+            }
+            TruncationDirection::Right if multimodal => {
+                return Err(ValidationError::TruncationDirection)
+            }
+            TruncationDirection::Right if encoding.len() > truncate => {
+                // The model servers truncate from the left: only send them the text of the kept
+                // tokens
+                let prefix = text_prefix(&tokenizer_query, &encoding, truncate);
+                input_chunks = vec![Chunk::Text(prefix.to_string()).into()];
+                encoding.truncate(truncate, 0, tokenizers::TruncationDirection::Right);
+            }
+            TruncationDirection::Right => {}
+        }
+    }
+
+    Ok((encoding, input_chunks))
+}
+
+/// Removes or rejects the control characters of `inputs`, other than tabs and line breaks, and
+/// normalizes it to NFC
+fn sanitize(inputs: String, sanitization: InputSanitization) -> Result<String, ValidationError> {
+    let is_control = |c: &char| c.is_control() && !matches!(c, '\t' | '\n' | '\r');
+    match sanitization {
+        InputSanitization::None => Ok(inputs),
+        InputSanitization::Strip => Ok(inputs.chars().filter(|c| !is_control(c)).nfc().collect()),
+        InputSanitization::Reject => match inputs.chars().find(is_control) {
+            Some(c) => Err(ValidationError::ControlCharacter(c as u32)),
+            None => Ok(inputs.nfc().collect()),
+        },
+    }
+}
+
+/// Text of the first `length` tokens of `encoding`, the encoding of `text`
+fn text_prefix<'a>(text: &'a str, encoding: &Encoding, length: usize) -> &'a str {
+    let mut end = encoding.get_offsets()[..length]
+        .iter()
+        .map(|(_, end)| *end)
+        .max()
+        .unwrap_or(0)
+        .min(text.len());
+    // Byte-level tokens can end inside of a character
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Placeholders inserted in place of the images, video frames and audio clips
+const PLACEHOLDER_TOKENS: [&str; 3] = ["<image>", "<fake_token_around_image>", "<audio>"];
+
+/// Keep the last `truncate` tokens, like the model servers. For multimodal models, the kept
+/// tokens are extended up to the first placeholder: the model servers process every image of
+/// the request, so truncation must never split or drop their placeholders.
+fn truncate_encoding(
+    tokenizer: &Tokenizer,
+    encoding: &mut Encoding,
+    truncate: usize,
+    multimodal: bool,
+) {
+    let mut truncate = truncate;
+    if multimodal {
+        let placeholders: Vec<u32> = PLACEHOLDER_TOKENS
+            .iter()
+            .filter_map(|token| tokenizer.token_to_id(token))
+            .collect();
+        if let Some(first) = encoding
+            .get_ids()
+            .iter()
+            .position(|id| placeholders.contains(id))
+        {
+            truncate = truncate.max(encoding.len() - first);
+        }
+    }
+    encoding.truncate(truncate, 0, tokenizers::TruncationDirection::Left);
+}
+
+/// Work of the tokenization workers
+enum TokenizerQuery {
+    /// Inputs of a request, prepared and tokenized like the model servers do, their truncation
+    /// and whether to add the special tokens
+    Inputs(
+        (String, Option<usize>, TruncationDirection, bool),
+        oneshot::Sender<Result<(tokenizers::Encoding, Vec<InputChunk>), ValidationError>>,
+    ),
+    /// Phrases tokenized on their own, without special tokens
+    Phrases(
+        Vec<String>,
+        oneshot::Sender<Result<Vec<Vec<u32>>, ValidationError>>,
+    ),
+    /// Token ids decoded back to text, special tokens included
+    Decode(Vec<u32>, oneshot::Sender<Result<String, ValidationError>>),
+}
+
+type TokenizerRequest = (TokenizerQuery, Span, Instant);
+
+#[derive(Debug, Clone)]
 pub(crate) enum ValidGrammar {
     Json(String),
     Regex(String),
+    /// Lark context-free grammar
+    Cfg(String),
 }
 
 #[derive(Debug, Clone)]
@@ -643,6 +2799,30 @@ pub(crate) struct ValidParameters {
     pub top_p: f32,
     /// / restricting to top tokens summing to prob_cut_off <= prob_cut_off
     pub typical_p: f32,
+    /// / minimum token probability, relative to the probability of the most likely token
+    pub min_p: f32,
+    /// / tokens whose probability is lower than epsilon_cutoff are filtered out
+    pub epsilon_cutoff: f32,
+    /// / entropy-dependent cutoff of eta sampling
+    pub eta_cutoff: f32,
+    /// / tokens whose probability is lower than top_a * (highest probability)^2 are filtered out
+    pub top_a: f32,
+    /// / mirostat version, 2 for mirostat v2 (0 disables it)
+    pub mirostat_mode: u32,
+    /// / target surprise of mirostat, in bits
+    pub mirostat_tau: f32,
+    /// / learning rate of mirostat
+    pub mirostat_eta: f32,
+    /// / bias added to the logits of the tokens, by token id
+    pub logit_bias: HashMap<u32, f32>,
+    /// / token sequences that cannot be generated
+    pub bad_words: Vec<Vec<u32>>,
+    /// / classifier-free guidance scale (1.0 disables it)
+    pub guidance_scale: f32,
+    /// / token ids of the negative prompt of the classifier-free guidance
+    pub negative_input_ids: Vec<u32>,
+    /// / minimum number of generated tokens before the end of sequence token is allowed
+    pub min_new_tokens: u32,
     /// / apply sampling on the logits
     pub do_sample: bool,
     /// / random seed for sampling
@@ -651,6 +2831,16 @@ pub(crate) struct ValidParameters {
     pub repetition_penalty: f32,
     /// / frequency penalty
     pub frequency_penalty: f32,
+    /// / presence penalty
+    pub presence_penalty: f32,
+    /// / size of the n-grams that can only occur once (0 disables it)
+    pub no_repeat_ngram_size: u32,
+    /// / DRY penalty multiplier (0.0 disables it)
+    pub dry_multiplier: f32,
+    /// / DRY penalty base
+    pub dry_base: f32,
+    /// / length of the repetitions that the DRY penalty ignores
+    pub dry_allowed_length: u32,
     /// / token watermarking using "A Watermark for Large Language Models"
     pub watermark: bool,
     /// / grammar (applied if not empty)
@@ -663,14 +2853,24 @@ pub(crate) struct ValidStoppingParameters {
     pub max_new_tokens: u32,
     /// / Optional stopping sequences
     pub stop_sequences: Vec<String>,
+    /// / Optional stopping token ids
+    pub stop_token_ids: Vec<u32>,
+    /// / Alternation of the stop regexes, matched by the router on the generated text
+    pub stop_regex: Option<Regex>,
+    /// / Token FSM of the grammar, checked by the router on the generated tokens
+    pub grammar_fsm: Option<Arc<TokenFsm>>,
     /// / Ignore end of sequence token
     /// / used for benchmarking
     pub ignore_eos_token: bool,
+    /// / Maximum generation time in seconds
+    pub max_time: Option<f32>,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct ValidGenerateRequest {
     pub inputs: Vec<InputChunk>,
+    /// Token ids of the inputs if the request was pre-tokenized, empty otherwise
+    pub input_ids: Vec<u32>,
     pub input_length: u32,
     pub truncate: u32,
     pub decoder_input_details: bool,
@@ -680,6 +2880,18 @@ pub(crate) struct ValidGenerateRequest {
     pub adapter_id: Option<String>,
 }
 
+impl ValidGenerateRequest {
+    /// Tokens of the unconditional sequence of the classifier-free guidance: the shards run the
+    /// model on the negative prompt, or on the last input token without one, with its own cache
+    pub(crate) fn guidance_tokens(&self) -> u32 {
+        if self.parameters.guidance_scale == 1.0 {
+            return 0;
+        }
+        self.parameters.negative_input_ids.len().max(1) as u32
+            + self.stopping_parameters.max_new_tokens
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ValidationError {
     #[error("`best_of` must be > 0 and <= {0}. Given: {1}")]
@@ -688,8 +2900,10 @@ pub enum ValidationError {
     BestOfDisabled,
     #[error("you must use sampling when `best_of` is > 1")]
     BestOfSampling,
-    #[error("`seed` must not be set when `best_of` > 1")]
+    #[error("`seed` and `best_of_seeds` cannot be both set")]
     BestOfSeed,
+    #[error("`best_of_seeds` must have `best_of` ({0}) seeds. Given: {1}")]
+    BestOfSeeds(usize, usize),
     #[error("`best_of` != 1 is not supported when streaming tokens")]
     BestOfStream,
     #[error("`top_n_tokens` must be >= 0 and <= {0}. Given: {1}")]
@@ -698,40 +2912,134 @@ pub enum ValidationError {
     TopNTokensDisabled,
     #[error("`decoder_input_details` == true is not supported when streaming tokens")]
     PrefillDetailsStream,
-    #[error("`temperature` must be strictly positive")]
+    #[error("`temperature` must be positive")]
     Temperature,
     #[error("`repetition_penalty` must be strictly positive")]
     RepetitionPenalty,
     #[error("`frequency_penalty` must be >= -2.0 and <= 2.0")]
     FrequencyPenalty,
+    #[error("`presence_penalty` must be >= -2.0 and <= 2.0")]
+    PresencePenalty,
+    #[error("`no_repeat_ngram_size` must be strictly positive and <= {0}. Given: {1}")]
+    NoRepeatNgramSize(u32, u32),
+    #[error("`dry_multiplier` must be >= 0.0")]
+    DryMultiplier,
+    #[error("`dry_base` must be > 1.0")]
+    DryBase,
+    #[error("`dry_allowed_length` must be strictly positive")]
+    DryAllowedLength,
+    #[error("`dry_base` and `dry_allowed_length` require `dry_multiplier`")]
+    DryParameters,
+    #[error("`guidance_scale` must be between 1 and {0}. Given: {1}")]
+    GuidanceScale(f32, f32),
+    #[error("`guidance_scale` is not supported by this model")]
+    GuidanceUnsupported,
+    #[error("`negative_inputs` requires `guidance_scale` > 1")]
+    NegativeInputs,
+    #[error("`negative_inputs` must be text and require a fast tokenizer")]
+    NegativeInputsTokenizer,
+    #[error("`inputs` tokens + `negative_inputs` tokens must be <= {0}, within the total tokens. Given: {1} `inputs` tokens and {2} `negative_inputs` tokens")]
+    NegativeInputsLength(usize, usize, usize),
+    #[error("`bad_words` can contain at most {0} phrases. Given: {1}")]
+    BadWords(usize, usize),
+    #[error("`bad_words` phrases must be between 1 and {0} tokens long. Given: {1}")]
+    BadWordLength(usize, usize),
+    #[error("`bad_words` cannot be used if a fast tokenizer is not in use")]
+    BadWordsTokenizer,
     #[error("`top_p` must be > 0.0 and < 1.0")]
     TopP,
     #[error("`top_k` must be strictly positive")]
     TopK,
+    #[error("`add_special_tokens` false requires a fast tokenizer")]
+    AddSpecialTokens,
+    #[error("`inputs` and `suffix` must not be set with `input_ids`")]
+    InputIds,
+    #[error("`inputs`, `input_ids` and `suffix` must not be set with `messages`")]
+    Messages,
+    #[error("the model has no chat template to render `messages`")]
+    ChatTemplateNotFound,
+    #[error("could not render the chat template: {0}")]
+    ChatTemplate(String),
+    #[error("`input_ids` require a fast tokenizer")]
+    InputIdsTokenizer,
+    #[error("`input_ids` must be smaller than the vocabulary size {0}. Given: {1}")]
+    InputId(usize, u32),
+    #[error("`suffix` is not supported: the model has no fill-in-the-middle tokens")]
+    Suffix,
+    #[error("`truncation_direction` right requires a fast tokenizer and a text-only model")]
+    TruncationDirection,
     #[error("`truncate` must be strictly positive and less than {0}. Given: {1}")]
     Truncate(usize, usize),
     #[error("`typical_p` must be > 0.0 and < 1.0")]
     TypicalP,
+    #[error("`min_p` must be > 0.0 and < 1.0")]
+    MinP,
+    #[error("`epsilon_cutoff` must be > 0.0 and < 1.0")]
+    EpsilonCutoff,
+    #[error("`eta_cutoff` must be > 0.0 and < 1.0")]
+    EtaCutoff,
+    #[error("`top_a` must be > 0.0 and <= 1.0")]
+    TopA,
+    #[error("`mirostat_mode` must be 0 or 2. Given: {0}")]
+    MirostatMode(u32),
+    #[error("`mirostat_tau` must be strictly positive")]
+    MirostatTau,
+    #[error("`mirostat_eta` must be > 0.0 and <= 1.0")]
+    MirostatEta,
+    #[error("`mirostat_tau` and `mirostat_eta` require `mirostat_mode`")]
+    MirostatParameters,
+    #[error("`logit_bias` can contain at most {0} tokens. Given: {1}")]
+    LogitBias(usize, usize),
     #[error("one of `max_new_tokens` or `truncate` must be set if a fast tokenizer is not in use")]
     UnsetMaxNewTokens,
     #[error("`max_new_tokens` must be strictly positive")]
     NegativeMaxNewTokens,
     #[error("`max_new_tokens` must be <= {0}. Given: {1}")]
     MaxNewTokens(usize, u32),
+    #[error("`min_new_tokens` must be <= `max_new_tokens` ({0}). Given: {1}")]
+    MinNewTokens(u32, u32),
     #[error("`inputs` tokens + `max_new_tokens` must be <= {0}. Given: {1} `inputs` tokens and {2} `max_new_tokens`")]
     MaxTotalTokens(usize, usize, u32),
     #[error("`inputs` must have less than {0} tokens. Given: {1}")]
     InputLength(usize, usize),
     #[error("`inputs` cannot be empty")]
     EmptyInput,
+    #[error("`inputs` cannot contain control characters. Given: U+{0:04X}")]
+    ControlCharacter(u32),
     #[error("`stop` supports up to {0} stop sequences. Given: {1}")]
     StopSequence(usize, usize),
+    #[error("`stop_token_ids` supports up to {0} token ids. Given: {1}")]
+    StopTokenIds(usize, usize),
+    #[error("`stop_token_ids` must be less than the vocabulary size {0}. Given: {1}")]
+    StopTokenId(usize, u32),
+    #[error("`stop_regex` supports up to {0} regular expressions. Given: {1}")]
+    StopRegex(usize, usize),
+    #[error("`stop_regex` is not valid: {0}")]
+    InvalidStopRegex(String),
+    #[error("adapter `{0}` is not loaded")]
+    UnknownAdapter(String),
+    #[error("`max_time` must be strictly positive")]
+    NegativeMaxTime,
+    #[error("`max_time` must be <= {0}. Given: {1}")]
+    MaxTime(f32, f32),
     #[error("tokenizer error {0}")]
     Tokenizer(String),
     #[error("grammar is not supported")]
     Grammar,
+    #[error("grammar is not allowed for this caller")]
+    GrammarNotAllowed,
+    #[error("`ignore_eos_token` is not allowed for this caller")]
+    IgnoreEosTokenNotAllowed,
+    #[error("`inputs` must be at most {0} bytes. Given: {1}")]
+    InputTooLarge(usize, usize),
+    #[error("text chunks of `inputs` must be at most {0} characters. Given: {1}")]
+    ChunkTooLong(usize, usize),
     #[error("grammar is not valid: {0}")]
     InvalidGrammar(String),
+    #[error("too many grammars failed to compile, retry in {0} seconds")]
+    GrammarThrottled(u64),
+    #[error("grammar compilation took more than {0:?}")]
+    GrammarTimeout(Duration),
     #[error("base64 encoding is invalid: {0}")]
     InvalidBase64(#[from] base64::DecodeError),
     #[error("invalid image: {0}")]
@@ -740,16 +3048,390 @@ pub enum ValidationError {
     InvalidInt(#[from] core::num::TryFromIntError),
     #[error("invalid image content: {0}")]
     InvalidImageContent(String),
+    #[error("images must be at most {0} bytes. Given: {1}")]
+    ImageSize(usize, usize),
+    #[error("image width and height must be <= {0} pixels")]
+    ImageDimensions(u32),
+    #[error("image format {0} is not supported by the model, expected one of: {1}")]
+    UnsupportedImageFormat(String, String),
     #[error("Could not fetch image: {0}")]
     FailedFetchImage(#[from] reqwest::Error),
+    #[error("image URL is not allowed: {0}")]
+    ImageUrlNotAllowed(String),
+    #[error("fetched inputs must be at most {0} bytes")]
+    FetchSize(usize),
+    #[error("`inputs` must contain at most {0} images. Given: {1}")]
+    TooManyImages(usize, usize),
+    #[error("invalid video content: {0}")]
+    InvalidVideoContent(String),
+    #[error("video `frames` must be > 0 and <= {0}. Given: {1}")]
+    VideoFrames(u32, u32),
+    #[error("invalid audio content: {0}")]
+    InvalidAudioContent(String),
+    #[error("audio sample rate must be >= {0} Hz and <= {1} Hz. Given: {2} Hz")]
+    AudioSampleRate(u32, u32, u32),
+    #[error("audio inputs must be shorter than {0} seconds. Given: {1} seconds")]
+    AudioDuration(f32, f32),
+    #[error("invalid document content: {0}")]
+    InvalidDocumentContent(String),
+    #[error("documents must be at most {0} bytes. Given: {1}")]
+    DocumentSize(usize, usize),
+    #[error("documents must have at most {0} pages")]
+    DocumentPages(usize),
+    #[error("could not rasterize the document: {0}")]
+    DocumentRasterizer(String),
 }
 
-#[cfg(test)]
+impl From<FetchError> for ValidationError {
+    fn from(err: FetchError) -> Self {
+        match err {
+            FetchError::Request(err) => ValidationError::FailedFetchImage(err),
+            FetchError::TooLarge(max_bytes) => ValidationError::FetchSize(max_bytes),
+            FetchError::Read(err) => ValidationError::InvalidImageContent(err.to_string()),
+            err => ValidationError::ImageUrlNotAllowed(err.to_string()),
+        }
+    }
+}
+
+impl ValidationError {
+    /// Stable error code, used to label error metrics
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            ValidationError::BestOf(_, _) => "validation_best_of",
+            ValidationError::BestOfDisabled => "validation_best_of_disabled",
+            ValidationError::BestOfSampling => "validation_best_of_sampling",
+            ValidationError::BestOfSeed => "validation_best_of_seed",
+            ValidationError::BestOfSeeds(_, _) => "validation_best_of_seeds",
+            ValidationError::BestOfStream => "validation_best_of_stream",
+            ValidationError::TopNTokens(_, _) => "validation_top_n_tokens",
+            ValidationError::TopNTokensDisabled => "validation_top_n_tokens_disabled",
+            ValidationError::PrefillDetailsStream => "validation_prefill_details_stream",
+            ValidationError::Temperature => "validation_temperature",
+            ValidationError::RepetitionPenalty => "validation_repetition_penalty",
+            ValidationError::FrequencyPenalty => "validation_frequency_penalty",
+            ValidationError::PresencePenalty => "validation_presence_penalty",
+            ValidationError::NoRepeatNgramSize(_, _) => "validation_no_repeat_ngram_size",
+            ValidationError::DryMultiplier => "validation_dry_multiplier",
+            ValidationError::DryBase => "validation_dry_base",
+            ValidationError::DryAllowedLength => "validation_dry_allowed_length",
+            ValidationError::DryParameters => "validation_dry_parameters",
+            ValidationError::GuidanceScale(_, _) => "validation_guidance_scale",
+            ValidationError::GuidanceUnsupported => "validation_guidance_unsupported",
+            ValidationError::NegativeInputs => "validation_negative_inputs",
+            ValidationError::NegativeInputsTokenizer => "validation_negative_inputs_tokenizer",
+            ValidationError::NegativeInputsLength(_, _, _) => "validation_negative_inputs_length",
+            ValidationError::BadWords(_, _) => "validation_bad_words",
+            ValidationError::BadWordLength(_, _) => "validation_bad_word_length",
+            ValidationError::BadWordsTokenizer => "validation_bad_words_tokenizer",
+            ValidationError::TopP => "validation_top_p",
+            ValidationError::TopK => "validation_top_k",
+            ValidationError::Truncate(_, _) => "validation_truncate",
+            ValidationError::TruncationDirection => "validation_truncation_direction",
+            ValidationError::Suffix => "validation_suffix",
+            ValidationError::AddSpecialTokens => "validation_add_special_tokens",
+            ValidationError::InputIds => "validation_input_ids",
+            ValidationError::Messages => "validation_messages",
+            ValidationError::ChatTemplateNotFound => "validation_chat_template_not_found",
+            ValidationError::ChatTemplate(_) => "validation_chat_template",
+            ValidationError::InputIdsTokenizer => "validation_input_ids_tokenizer",
+            ValidationError::InputId(_, _) => "validation_input_id",
+            ValidationError::TypicalP => "validation_typical_p",
+            ValidationError::MinP => "validation_min_p",
+            ValidationError::EpsilonCutoff => "validation_epsilon_cutoff",
+            ValidationError::EtaCutoff => "validation_eta_cutoff",
+            ValidationError::TopA => "validation_top_a",
+            ValidationError::MirostatMode(_) => "validation_mirostat_mode",
+            ValidationError::MirostatTau => "validation_mirostat_tau",
+            ValidationError::MirostatEta => "validation_mirostat_eta",
+            ValidationError::MirostatParameters => "validation_mirostat_parameters",
+            ValidationError::LogitBias(_, _) => "validation_logit_bias",
+            ValidationError::UnsetMaxNewTokens => "validation_unset_max_new_tokens",
+            ValidationError::NegativeMaxNewTokens => "validation_negative_max_new_tokens",
+            ValidationError::MaxNewTokens(_, _) => "validation_max_new_tokens",
+            ValidationError::MinNewTokens(_, _) => "validation_min_new_tokens",
+            ValidationError::MaxTotalTokens(_, _, _) => "validation_max_total_tokens",
+            ValidationError::InputLength(_, _) => "validation_input_length",
+            ValidationError::EmptyInput => "validation_empty_input",
+            ValidationError::ControlCharacter(_) => "validation_control_character",
+            ValidationError::StopSequence(_, _) => "validation_stop_sequence",
+            ValidationError::StopTokenIds(_, _) => "validation_stop_token_ids",
+            ValidationError::StopRegex(_, _) => "validation_stop_regex",
+            ValidationError::InvalidStopRegex(_) => "validation_invalid_stop_regex",
+            ValidationError::UnknownAdapter(_) => "validation_unknown_adapter",
+            ValidationError::StopTokenId(_, _) => "validation_stop_token_id",
+            ValidationError::NegativeMaxTime => "validation_negative_max_time",
+            ValidationError::MaxTime(_, _) => "validation_max_time",
+            ValidationError::Tokenizer(_) => "validation_tokenizer",
+            ValidationError::Grammar => "validation_grammar_unsupported",
+            ValidationError::GrammarNotAllowed => "validation_grammar_not_allowed",
+            ValidationError::IgnoreEosTokenNotAllowed => "validation_ignore_eos_token_not_allowed",
+            ValidationError::InputTooLarge(_, _) => "validation_input_too_large",
+            ValidationError::ChunkTooLong(_, _) => "validation_chunk_too_long",
+            ValidationError::InvalidGrammar(_) => "validation_invalid_grammar",
+            ValidationError::GrammarThrottled(_) => "validation_grammar_throttled",
+            ValidationError::GrammarTimeout(_) => "validation_grammar_timeout",
+            ValidationError::InvalidBase64(_) => "validation_invalid_base64",
+            ValidationError::InvalidImage(_) => "validation_invalid_image",
+            ValidationError::InvalidInt(_) => "validation_invalid_int",
+            ValidationError::InvalidImageContent(_) => "validation_invalid_image_content",
+            ValidationError::ImageSize(_, _) => "validation_image_size",
+            ValidationError::ImageDimensions(_) => "validation_image_dimensions",
+            ValidationError::UnsupportedImageFormat(_, _) => "validation_unsupported_image_format",
+            ValidationError::FailedFetchImage(_) => "validation_failed_fetch_image",
+            ValidationError::ImageUrlNotAllowed(_) => "validation_image_url_not_allowed",
+            ValidationError::FetchSize(_) => "validation_fetch_size",
+            ValidationError::TooManyImages(_, _) => "validation_too_many_images",
+            ValidationError::InvalidVideoContent(_) => "validation_invalid_video_content",
+            ValidationError::VideoFrames(_, _) => "validation_video_frames",
+            ValidationError::InvalidAudioContent(_) => "validation_invalid_audio_content",
+            ValidationError::AudioSampleRate(_, _, _) => "validation_audio_sample_rate",
+            ValidationError::AudioDuration(_, _) => "validation_audio_duration",
+            ValidationError::InvalidDocumentContent(_) => "validation_invalid_document_content",
+            ValidationError::DocumentSize(_, _) => "validation_document_size",
+            ValidationError::DocumentPages(_) => "validation_document_pages",
+            ValidationError::DocumentRasterizer(_) => "validation_document_rasterizer",
+        }
+    }
+
+    /// Request parameter at the origin of the error
+    pub(crate) fn parameter(&self) -> &'static str {
+        match self {
+            ValidationError::BestOf(_, _) => "best_of",
+            ValidationError::BestOfDisabled => "best_of",
+            ValidationError::BestOfSampling => "best_of",
+            ValidationError::BestOfSeed => "seed",
+            ValidationError::BestOfSeeds(_, _) => "best_of_seeds",
+            ValidationError::BestOfStream => "best_of",
+            ValidationError::TopNTokens(_, _) => "top_n_tokens",
+            ValidationError::TopNTokensDisabled => "top_n_tokens",
+            ValidationError::PrefillDetailsStream => "decoder_input_details",
+            ValidationError::Temperature => "temperature",
+            ValidationError::RepetitionPenalty => "repetition_penalty",
+            ValidationError::FrequencyPenalty => "frequency_penalty",
+            ValidationError::PresencePenalty => "presence_penalty",
+            ValidationError::NoRepeatNgramSize(_, _) => "no_repeat_ngram_size",
+            ValidationError::DryMultiplier | ValidationError::DryParameters => "dry_multiplier",
+            ValidationError::DryBase => "dry_base",
+            ValidationError::DryAllowedLength => "dry_allowed_length",
+            ValidationError::GuidanceScale(_, _) | ValidationError::GuidanceUnsupported => {
+                "guidance_scale"
+            }
+            ValidationError::NegativeInputs
+            | ValidationError::NegativeInputsTokenizer
+            | ValidationError::NegativeInputsLength(_, _, _) => "negative_inputs",
+            ValidationError::BadWords(_, _) => "bad_words",
+            ValidationError::BadWordLength(_, _) => "bad_words",
+            ValidationError::BadWordsTokenizer => "bad_words",
+            ValidationError::TopP => "top_p",
+            ValidationError::TopK => "top_k",
+            ValidationError::Truncate(_, _) => "truncate",
+            ValidationError::TruncationDirection => "truncation_direction",
+            ValidationError::Suffix => "suffix",
+            ValidationError::AddSpecialTokens => "add_special_tokens",
+            ValidationError::InputIds
+            | ValidationError::InputIdsTokenizer
+            | ValidationError::InputId(_, _) => "input_ids",
+            ValidationError::Messages
+            | ValidationError::ChatTemplateNotFound
+            | ValidationError::ChatTemplate(_) => "messages",
+            ValidationError::TypicalP => "typical_p",
+            ValidationError::MinP => "min_p",
+            ValidationError::EpsilonCutoff => "epsilon_cutoff",
+            ValidationError::EtaCutoff => "eta_cutoff",
+            ValidationError::TopA => "top_a",
+            ValidationError::MirostatMode(_) | ValidationError::MirostatParameters => {
+                "mirostat_mode"
+            }
+            ValidationError::MirostatTau => "mirostat_tau",
+            ValidationError::MirostatEta => "mirostat_eta",
+            ValidationError::LogitBias(_, _) => "logit_bias",
+            ValidationError::UnsetMaxNewTokens => "max_new_tokens",
+            ValidationError::NegativeMaxNewTokens => "max_new_tokens",
+            ValidationError::MaxNewTokens(_, _) => "max_new_tokens",
+            ValidationError::MinNewTokens(_, _) => "min_new_tokens",
+            ValidationError::MaxTotalTokens(_, _, _) => "inputs",
+            ValidationError::InputLength(_, _) => "inputs",
+            ValidationError::EmptyInput => "inputs",
+            ValidationError::ControlCharacter(_) => "inputs",
+            ValidationError::StopSequence(_, _) => "stop",
+            ValidationError::StopTokenIds(_, _) => "stop_token_ids",
+            ValidationError::StopRegex(_, _) | ValidationError::InvalidStopRegex(_) => "stop_regex",
+            ValidationError::UnknownAdapter(_) => "adapter_id",
+            ValidationError::StopTokenId(_, _) => "stop_token_ids",
+            ValidationError::NegativeMaxTime | ValidationError::MaxTime(_, _) => "max_time",
+            ValidationError::Tokenizer(_) => "inputs",
+            ValidationError::Grammar => "grammar",
+            ValidationError::GrammarNotAllowed => "grammar",
+            ValidationError::IgnoreEosTokenNotAllowed => "ignore_eos_token",
+            ValidationError::InputTooLarge(_, _) | ValidationError::ChunkTooLong(_, _) => "inputs",
+            ValidationError::InvalidGrammar(_) => "grammar",
+            ValidationError::GrammarThrottled(_) => "grammar",
+            ValidationError::GrammarTimeout(_) => "grammar",
+            ValidationError::InvalidBase64(_) => "inputs",
+            ValidationError::InvalidImage(_) => "inputs",
+            ValidationError::InvalidInt(_) => "inputs",
+            ValidationError::InvalidImageContent(_) => "inputs",
+            ValidationError::ImageSize(_, _) => "inputs",
+            ValidationError::ImageDimensions(_) => "inputs",
+            ValidationError::UnsupportedImageFormat(_, _) => "inputs",
+            ValidationError::FailedFetchImage(_) => "inputs",
+            ValidationError::ImageUrlNotAllowed(_) => "inputs",
+            ValidationError::FetchSize(_) => "inputs",
+            ValidationError::TooManyImages(_, _) => "inputs",
+            ValidationError::InvalidVideoContent(_) => "inputs",
+            ValidationError::VideoFrames(_, _) => "inputs",
+            ValidationError::InvalidAudioContent(_) => "inputs",
+            ValidationError::AudioSampleRate(_, _, _) => "inputs",
+            ValidationError::AudioDuration(_, _) => "inputs",
+            ValidationError::InvalidDocumentContent(_) => "inputs",
+            ValidationError::DocumentSize(_, _) => "inputs",
+            ValidationError::DocumentPages(_) => "inputs",
+            ValidationError::DocumentRasterizer(_) => "inputs",
+        }
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::{scope_auth_context, AuthContext};
     use crate::config::{Idefics2, PaliTextConfig, Paligemma};
-    use crate::default_parameters;
+    use crate::grammar::json_object_regex;
+    use crate::input_policy::InputPolicy;
+    use crate::lark::MAX_LARK_DEFINITIONS;
     use crate::tests::get_tokenizer;
+    use crate::{default_parameters, ChatTemplateVersions, HubProcessorConfig, HubTokenizerConfig};
+    use serde_json::json;
+    use tokenizers::AddedToken;
+
+    #[tokio::test]
+    async fn test_validation_input_policy() {
+        let input_policies = InputPolicies {
+            default: InputPolicy::default(),
+            keys: HashMap::from([(
+                "acme".to_string(),
+                InputPolicy {
+                    max_input_tokens: Some(3),
+                    max_new_tokens: Some(4),
+                    max_images: None,
+                    allow_grammar: Some(false),
+                    allow_ignore_eos_token: Some(false),
+                    priority: None,
+                },
+            )]),
+        };
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            10,
+            false,
+            None,
+            None,
+            None,
+            ValidationConfig {
+                input_policies,
+                ..Default::default()
+            },
+        );
+
+        // Global limits
+        match validation
+            .validate_input(
+                "Hello".to_string(),
+                Some(5),
+                TruncationDirection::Left,
+                true,
+                None,
+                Some(5),
+            )
+            .await
+        {
+            Ok((_, _, 5, 5)) => (),
+            r => panic!("Unexpected not max new tokens: {r:?}"),
+        }
+
+        let ignore_eos_token = || GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(2),
+                ignore_eos_token: true,
+                ..default_parameters()
+            },
+        };
+        let acme = AuthContext {
+            credential: None,
+            subject: None,
+            tenant: Some("acme".to_string()),
+            scopes: None,
+        };
+        scope_auth_context(acme, async {
+            match validation
+                .validate_input(
+                    "Hello".to_string(),
+                    Some(3),
+                    TruncationDirection::Left,
+                    true,
+                    None,
+                    Some(5),
+                )
+                .await
+            {
+                Err(ValidationError::MaxNewTokens(4, 5)) => (),
+                r => panic!("Unexpected not max new tokens: {r:?}"),
+            }
+            match validation
+                .validate_input(
+                    "Hello".to_string(),
+                    Some(3),
+                    TruncationDirection::Left,
+                    true,
+                    None,
+                    None,
+                )
+                .await
+            {
+                Ok((_, _, 3, 4)) => (),
+                r => panic!("Unexpected max new tokens: {r:?}"),
+            }
+
+            let request = |truncate, grammar| GenerateRequest {
+                inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(2),
+                    truncate: Some(truncate),
+                    grammar,
+                    ..default_parameters()
+                },
+            };
+            match validation.validate(request(5, None)).await {
+                Err(ValidationError::Truncate(3, 5)) => (),
+                r => panic!("Unexpected truncate: {r:?}"),
+            }
+            let grammar = Some(GrammarType::Regex("[a-z]+".to_string()));
+            match validation.validate(request(2, grammar)).await {
+                Err(ValidationError::GrammarNotAllowed) => (),
+                r => panic!("Unexpected grammar: {r:?}"),
+            }
+            match validation.validate(ignore_eos_token()).await {
+                Err(ValidationError::IgnoreEosTokenNotAllowed) => (),
+                r => panic!("Unexpected ignore_eos_token: {r:?}"),
+            }
+        })
+        .await;
+
+        let valid_request = validation.validate(ignore_eos_token()).await.unwrap();
+        assert!(valid_request.stopping_parameters.ignore_eos_token);
+    }
 
     #[tokio::test]
     async fn test_validation_max_new_tokens() {
@@ -773,15 +3455,26 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
         );
 
         let max_new_tokens = 10;
         match validation
-            .validate_input("Hello".to_string(), None, Some(max_new_tokens))
+            .validate_input(
+                "Hello".to_string(),
+                None,
+                TruncationDirection::Left,
+                true,
+                None,
+                Some(max_new_tokens),
+            )
             .await
         {
             // Err(ValidationError::MaxNewTokens(1, 10)) => (),
-            Ok((_s, 0, 10)) => (),
+            Ok((_s, _, 0, 10)) => (),
             r => panic!("Unexpected not max new tokens: {r:?}"),
         }
     }
@@ -808,11 +3501,22 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
         );
 
         let max_new_tokens = 10;
         match validation
-            .validate_input("Hello".to_string(), None, Some(max_new_tokens))
+            .validate_input(
+                "Hello".to_string(),
+                None,
+                TruncationDirection::Left,
+                true,
+                None,
+                Some(max_new_tokens),
+            )
             .await
         {
             Err(ValidationError::MaxTotalTokens(6, 1, 10)) => (),
@@ -842,10 +3546,16 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
         );
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
                 parameters: GenerateParameters {
                     best_of: Some(2),
                     do_sample: false,
@@ -881,10 +3591,16 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
         );
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
                 parameters: GenerateParameters {
                     top_p: Some(1.0),
                     max_new_tokens: Some(5),
@@ -900,6 +3616,8 @@ mod tests {
         match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
                 parameters: GenerateParameters {
                     top_p: Some(0.99),
                     max_new_tokens: Some(5),
@@ -915,6 +3633,8 @@ mod tests {
         let valid_request = validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
                 parameters: GenerateParameters {
                     top_p: None,
                     max_new_tokens: Some(5),
@@ -928,10 +3648,10 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_validation_top_n_tokens() {
-        let tokenizer = Some(get_tokenizer().await);
+    async fn test_validation_min_p() {
+        let tokenizer = None;
         let max_best_of = 2;
-        let max_stop_sequences = 3;
+        let max_stop_sequence = 3;
         let max_top_n_tokens = 4;
         let max_input_length = 5;
         let max_total_tokens = 106;
@@ -944,90 +3664,366 @@ mod tests {
             config,
             None,
             max_best_of,
-            max_stop_sequences,
+            max_stop_sequence,
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
         );
-        match validation
+        for min_p in [0.0, 1.0] {
+            match validation
+                .validate(GenerateRequest {
+                    inputs: "Hello".to_string(),
+                    input_ids: None,
+                    messages: None,
+                    parameters: GenerateParameters {
+                        min_p: Some(min_p),
+                        max_new_tokens: Some(5),
+                        ..default_parameters()
+                    },
+                })
+                .await
+            {
+                Err(ValidationError::MinP) => (),
+                _ => panic!("Unexpected min_p {min_p}"),
+            }
+        }
+
+        let valid_request = validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
                 parameters: GenerateParameters {
-                    top_n_tokens: Some(5),
+                    min_p: Some(0.05),
                     max_new_tokens: Some(5),
                     ..default_parameters()
                 },
             })
             .await
-        {
-            Err(ValidationError::TopNTokens(4, 5)) => (),
-            _ => panic!("Unexpected top_n_tokens"),
-        }
+            .unwrap();
+        assert_eq!(valid_request.parameters.min_p, 0.05);
 
-        validation
+        let valid_request = validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
                 parameters: GenerateParameters {
-                    top_n_tokens: Some(4),
+                    min_p: None,
                     max_new_tokens: Some(5),
                     ..default_parameters()
                 },
             })
             .await
             .unwrap();
+        // 0.0 disables min_p on the shards
+        assert_eq!(valid_request.parameters.min_p, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_validation_cutoffs() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            106,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let cutoffs = |epsilon_cutoff, eta_cutoff| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                epsilon_cutoff,
+                eta_cutoff,
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        for cutoff in [0.0, 1.0] {
+            match validation.validate(cutoffs(Some(cutoff), None)).await {
+                Err(ValidationError::EpsilonCutoff) => (),
+                r => panic!("Unexpected epsilon_cutoff {r:?}"),
+            }
+            match validation.validate(cutoffs(None, Some(cutoff))).await {
+                Err(ValidationError::EtaCutoff) => (),
+                r => panic!("Unexpected eta_cutoff {r:?}"),
+            }
+        }
+
+        let valid_request = validation
+            .validate(cutoffs(Some(0.0003), Some(0.002)))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.epsilon_cutoff, 0.0003);
+        assert_eq!(valid_request.parameters.eta_cutoff, 0.002);
 
-        validation
+        // 0.0 disables the cutoffs on the shards
+        let valid_request = validation.validate(cutoffs(None, None)).await.unwrap();
+        assert_eq!(valid_request.parameters.epsilon_cutoff, 0.0);
+        assert_eq!(valid_request.parameters.eta_cutoff, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_validation_input_size() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            106,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig {
+                input_size_limits: InputSizeLimits {
+                    max_input_bytes: Some(12),
+                    max_chunk_chars: Some(8),
+                },
+                ..Default::default()
+            },
+        );
+        let request = |inputs: &str| GenerateRequest {
+            inputs: inputs.to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        assert!(validation.validate(request("Hello")).await.is_ok());
+        // 4 characters, 12 bytes
+        assert!(validation.validate(request("你好你好")).await.is_ok());
+        match validation.validate(request("你好你好你")).await {
+            Err(ValidationError::InputTooLarge(12, 15)) => (),
+            r => panic!("Unexpected inputs {r:?}"),
+        }
+        match validation.validate(request("Hello you")).await {
+            Err(ValidationError::ChunkTooLong(8, 9)) => (),
+            r => panic!("Unexpected inputs {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_top_a() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            106,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let top_a = |top_a, temperature| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                top_a,
+                temperature,
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        for value in [0.0, 1.1] {
+            match validation.validate(top_a(Some(value), None)).await {
+                Err(ValidationError::TopA) => (),
+                r => panic!("Unexpected top_a {r:?}"),
+            }
+        }
+
+        let valid_request = validation.validate(top_a(Some(1.0), None)).await.unwrap();
+        assert_eq!(valid_request.parameters.top_a, 1.0);
+
+        // Greedy decoding ignores it
+        let valid_request = validation
+            .validate(top_a(Some(0.2), Some(0.0)))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.top_a, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_validation_mirostat() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            106,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let mirostat = |mirostat_mode, mirostat_tau, mirostat_eta| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                mirostat_mode,
+                mirostat_tau,
+                mirostat_eta,
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation
+            .validate(mirostat(Some(2), None, None))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.mirostat_mode, 2);
+        assert_eq!(valid_request.parameters.mirostat_tau, DEFAULT_MIROSTAT_TAU);
+        assert_eq!(valid_request.parameters.mirostat_eta, DEFAULT_MIROSTAT_ETA);
+        let valid_request = validation
+            .validate(mirostat(Some(2), Some(3.0), Some(0.2)))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.mirostat_tau, 3.0);
+        assert_eq!(valid_request.parameters.mirostat_eta, 0.2);
+        // 0 disables mirostat on the shards
+        let valid_request = validation
+            .validate(mirostat(None, None, None))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.mirostat_mode, 0);
+
+        match validation.validate(mirostat(Some(1), None, None)).await {
+            Err(ValidationError::MirostatMode(1)) => (),
+            r => panic!("Unexpected mirostat_mode {r:?}"),
+        }
+        match validation
+            .validate(mirostat(Some(2), Some(0.0), None))
+            .await
+        {
+            Err(ValidationError::MirostatTau) => (),
+            r => panic!("Unexpected mirostat_tau {r:?}"),
+        }
+        match validation
+            .validate(mirostat(Some(2), None, Some(1.5)))
+            .await
+        {
+            Err(ValidationError::MirostatEta) => (),
+            r => panic!("Unexpected mirostat_eta {r:?}"),
+        }
+        match validation.validate(mirostat(None, Some(3.0), None)).await {
+            Err(ValidationError::MirostatParameters) => (),
+            r => panic!("Unexpected mirostat_tau {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_presence_penalty() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            config,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        match validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
                 parameters: GenerateParameters {
-                    top_n_tokens: Some(0),
+                    presence_penalty: Some(2.5),
                     max_new_tokens: Some(5),
                     ..default_parameters()
                 },
             })
             .await
-            .unwrap();
+        {
+            Err(ValidationError::PresencePenalty) => (),
+            _ => panic!("Unexpected presence_penalty"),
+        }
 
         let valid_request = validation
             .validate(GenerateRequest {
                 inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
                 parameters: GenerateParameters {
-                    top_n_tokens: None,
+                    presence_penalty: Some(-2.0),
                     max_new_tokens: Some(5),
                     ..default_parameters()
                 },
             })
             .await
             .unwrap();
-
-        assert_eq!(valid_request.top_n_tokens, 0);
+        assert_eq!(valid_request.parameters.presence_penalty, -2.0);
     }
 
-    static PIXEL_GIF: &str = "R0lGODdhAQABAIEAAP///wAAAAAAAAAAACwAAAAAAQABAAAIBAABBAQAOw==";
-
     #[tokio::test]
-    async fn test_prepare_input_chunks() {
-        let pixel_data = STANDARD.decode(PIXEL_GIF).unwrap();
-
-        let tokenizer = Some(get_tokenizer().await);
-
+    async fn test_validation_no_repeat_ngram_size() {
+        let tokenizer = None;
         let max_best_of = 2;
         let max_stop_sequence = 3;
         let max_top_n_tokens = 4;
         let max_input_length = 5;
-        let max_total_tokens = 6;
-        let disable_grammar_support = true;
+        let max_total_tokens = 106;
         let workers = 1;
-        let config = Config::Paligemma(Paligemma {
-            text_config: PaliTextConfig {
-                num_image_tokens: 1,
-            },
-        });
+        let disable_grammar_support = true;
+        let config = None;
         let validation = Validation::new(
             workers,
             tokenizer,
-            Some(config),
+            config,
             None,
             max_best_of,
             max_stop_sequence,
@@ -1035,14 +4031,1453 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
         );
+        for no_repeat_ngram_size in [0, MAX_NO_REPEAT_NGRAM_SIZE + 1] {
+            match validation
+                .validate(GenerateRequest {
+                    inputs: "Hello".to_string(),
+                    input_ids: None,
+                    messages: None,
+                    parameters: GenerateParameters {
+                        no_repeat_ngram_size: Some(no_repeat_ngram_size),
+                        max_new_tokens: Some(5),
+                        ..default_parameters()
+                    },
+                })
+                .await
+            {
+                Err(ValidationError::NoRepeatNgramSize(MAX_NO_REPEAT_NGRAM_SIZE, _)) => (),
+                _ => panic!("Unexpected no_repeat_ngram_size {no_repeat_ngram_size}"),
+            }
+        }
 
-        let chunks = match validation
-            .tokenize(
-                format!("test![](data:image/gif;base64,{})", PIXEL_GIF),
-                None,
-            )
-            .await
+        let valid_request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
+                parameters: GenerateParameters {
+                    no_repeat_ngram_size: Some(3),
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.no_repeat_ngram_size, 3);
+    }
+
+    #[tokio::test]
+    async fn test_validation_dry() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            106,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let dry = |dry_multiplier, dry_base, dry_allowed_length| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                dry_multiplier,
+                dry_base,
+                dry_allowed_length,
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation
+            .validate(dry(Some(0.8), None, None))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.dry_multiplier, 0.8);
+        assert_eq!(valid_request.parameters.dry_base, DEFAULT_DRY_BASE);
+        assert_eq!(
+            valid_request.parameters.dry_allowed_length,
+            DEFAULT_DRY_ALLOWED_LENGTH
+        );
+        let valid_request = validation
+            .validate(dry(Some(0.8), Some(2.0), Some(3)))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.dry_base, 2.0);
+        assert_eq!(valid_request.parameters.dry_allowed_length, 3);
+        // 0.0 disables DRY on the shards
+        let valid_request = validation.validate(dry(None, None, None)).await.unwrap();
+        assert_eq!(valid_request.parameters.dry_multiplier, 0.0);
+
+        match validation.validate(dry(Some(-1.0), None, None)).await {
+            Err(ValidationError::DryMultiplier) => (),
+            r => panic!("Unexpected dry_multiplier {r:?}"),
+        }
+        match validation.validate(dry(Some(0.8), Some(1.0), None)).await {
+            Err(ValidationError::DryBase) => (),
+            r => panic!("Unexpected dry_base {r:?}"),
+        }
+        match validation.validate(dry(Some(0.8), None, Some(0))).await {
+            Err(ValidationError::DryAllowedLength) => (),
+            r => panic!("Unexpected dry_allowed_length {r:?}"),
+        }
+        match validation.validate(dry(None, Some(2.0), None)).await {
+            Err(ValidationError::DryParameters) => (),
+            r => panic!("Unexpected dry_base {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_bad_words() {
+        let tokenizer = Some(word_level_tokenizer(&["a", "b", "[UNK]"]));
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            config,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let bad_words = |bad_words: Vec<&str>| GenerateRequest {
+            inputs: "a b".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                bad_words: bad_words.into_iter().map(str::to_string).collect(),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation
+            .validate(bad_words(vec!["b", "a b c"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.parameters.bad_words,
+            vec![vec![1], vec![0, 1, 2]]
+        );
+
+        match validation.validate(bad_words(vec!["a", ""])).await {
+            Err(ValidationError::BadWordLength(MAX_BAD_WORD_TOKENS, 0)) => (),
+            r => panic!("Unexpected bad_words {r:?}"),
+        }
+        let long_phrase = vec!["a"; MAX_BAD_WORD_TOKENS + 1].join(" ");
+        match validation.validate(bad_words(vec![&long_phrase])).await {
+            Err(ValidationError::BadWordLength(MAX_BAD_WORD_TOKENS, _)) => (),
+            r => panic!("Unexpected bad_words {r:?}"),
+        }
+        match validation
+            .validate(bad_words(vec!["a"; MAX_BAD_WORDS + 1]))
+            .await
+        {
+            Err(ValidationError::BadWords(MAX_BAD_WORDS, _)) => (),
+            r => panic!("Unexpected bad_words {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_stop_token_ids() {
+        let tokenizer = Some(word_level_tokenizer(&["a", "b", "[UNK]"]));
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            config,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let stop_token_ids = |stop_token_ids: Vec<u32>| GenerateRequest {
+            inputs: "a b".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                stop_token_ids,
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation
+            .validate(stop_token_ids(vec![0, 2]))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.stopping_parameters.stop_token_ids, vec![0, 2]);
+
+        match validation.validate(stop_token_ids(vec![1, 3])).await {
+            Err(ValidationError::StopTokenId(3, 3)) => (),
+            r => panic!("Unexpected stop_token_ids {r:?}"),
+        }
+        match validation.validate(stop_token_ids(vec![0, 1, 2, 0])).await {
+            Err(ValidationError::StopTokenIds(3, 4)) => (),
+            r => panic!("Unexpected stop_token_ids {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_stop_regex() {
+        let tokenizer = Some(word_level_tokenizer(&["a", "b", "[UNK]"]));
+        let validation = Validation::new(
+            1,
+            tokenizer,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            106,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let stop_regex = |stop_regex: Vec<&str>| GenerateRequest {
+            inputs: "a b".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                stop_regex: stop_regex.into_iter().map(String::from).collect(),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation.validate(stop_regex(vec![])).await.unwrap();
+        assert!(valid_request.stopping_parameters.stop_regex.is_none());
+        let valid_request = validation
+            .validate(stop_regex(vec![r"\n\d+\.", "END"]))
+            .await
+            .unwrap();
+        let regex = valid_request.stopping_parameters.stop_regex.unwrap();
+        assert_eq!(regex.find("Steps:\n12. Mix").unwrap().as_str(), "\n12.");
+        assert!(regex.is_match("THE END"));
+
+        match validation
+            .validate(stop_regex(vec!["a", "b", "c", "d"]))
+            .await
+        {
+            Err(ValidationError::StopRegex(3, 4)) => (),
+            r => panic!("Unexpected stop_regex {r:?}"),
+        }
+        match validation.validate(stop_regex(vec!["(a"])).await {
+            Err(ValidationError::InvalidStopRegex(_)) => (),
+            r => panic!("Unexpected stop_regex {r:?}"),
+        }
+        match validation.validate(stop_regex(vec![""])).await {
+            Err(ValidationError::InvalidStopRegex(_)) => (),
+            r => panic!("Unexpected stop_regex {r:?}"),
+        }
+        // Too complex once compiled
+        match validation.validate(stop_regex(vec![r"\w{1000}"])).await {
+            Err(ValidationError::InvalidStopRegex(_)) => (),
+            r => panic!("Unexpected stop_regex {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_max_time() {
+        let tokenizer = Some(word_level_tokenizer(&["a", "b", "[UNK]"]));
+        let validation = Validation::new(
+            1,
+            tokenizer,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            106,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig {
+                max_time: Some(30.0),
+                ..Default::default()
+            },
+        );
+        let max_time = |max_time| GenerateRequest {
+            inputs: "a b".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                max_time,
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation.validate(max_time(Some(2.5))).await.unwrap();
+        assert_eq!(valid_request.stopping_parameters.max_time, Some(2.5));
+        // Unset uses the server maximum
+        let valid_request = validation.validate(max_time(None)).await.unwrap();
+        assert_eq!(valid_request.stopping_parameters.max_time, Some(30.0));
+
+        match validation.validate(max_time(Some(0.0))).await {
+            Err(ValidationError::NegativeMaxTime) => (),
+            r => panic!("Unexpected max_time {r:?}"),
+        }
+        match validation.validate(max_time(Some(60.0))).await {
+            Err(ValidationError::MaxTime(limit, given)) if limit == 30.0 && given == 60.0 => (),
+            r => panic!("Unexpected max_time {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_limits_update() {
+        let tokenizer = Some(word_level_tokenizer(&["a", "b", "[UNK]"]));
+        let validation = Validation::new(
+            1,
+            tokenizer,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            106,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let request = |max_new_tokens, stop: Vec<String>| GenerateRequest {
+            inputs: "a b a".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                max_new_tokens,
+                stop,
+                ..default_parameters()
+            },
+        };
+        let limits = validation.validation_limits();
+
+        let values = limits
+            .update(LimitsUpdate {
+                max_stop_sequences: Some(1),
+                max_input_tokens: Some(2),
+                max_total_tokens: Some(10),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            values,
+            LimitValues {
+                max_stop_sequences: 1,
+                max_top_n_tokens: 4,
+                max_input_tokens: 2,
+                max_total_tokens: 10,
+            }
+        );
+        // The clones share the limits
+        let validation = validation.clone();
+        match validation.validate(request(Some(1), Vec::new())).await {
+            Err(ValidationError::InputLength(2, 3)) => (),
+            r => panic!("Unexpected input length {r:?}"),
+        }
+
+        limits
+            .update(LimitsUpdate {
+                max_input_tokens: Some(5),
+                ..Default::default()
+            })
+            .unwrap();
+        let valid_request = validation
+            .validate(request(None, vec!["a".to_string()]))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.stopping_parameters.max_new_tokens, 7);
+        match validation
+            .validate(request(Some(1), vec!["a".to_string(), "b".to_string()]))
+            .await
+        {
+            Err(ValidationError::StopSequence(1, 2)) => (),
+            r => panic!("Unexpected stop sequences {r:?}"),
+        }
+
+        // Invalid updates change nothing
+        assert_eq!(
+            limits.update(LimitsUpdate {
+                max_total_tokens: Some(5),
+                ..Default::default()
+            }),
+            Err(LimitsError::InputTokens(5, 5))
+        );
+        assert_eq!(
+            limits.update(LimitsUpdate {
+                max_stop_sequences: Some(4),
+                max_total_tokens: Some(200),
+                ..Default::default()
+            }),
+            Err(LimitsError::AboveStartup("max_total_tokens", 106, 200))
+        );
+        assert_eq!(limits.values().max_stop_sequences, 1);
+        assert_eq!(limits.values().max_total_tokens, 10);
+    }
+
+    #[tokio::test]
+    async fn test_validation_adapter_id() {
+        let validation = |adapter_ids| {
+            Validation::new(
+                1,
+                Some(word_level_tokenizer(&["a", "b", "[UNK]"])),
+                None,
+                None,
+                2,
+                3,
+                4,
+                5,
+                106,
+                true,
+                None,
+                None,
+                adapter_ids,
+                ValidationConfig::default(),
+            )
+        };
+        let request = |adapter_id: Option<&str>| GenerateRequest {
+            inputs: "a b".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                adapter_id: adapter_id.map(String::from),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let validation_with_adapters = validation(Some(vec!["acme/lora".to_string()]));
+        let valid_request = validation_with_adapters
+            .validate(request(Some("acme/lora")))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.adapter_id.as_deref(), Some("acme/lora"));
+        // The base model
+        assert!(validation_with_adapters
+            .validate(request(None))
+            .await
+            .is_ok());
+        match validation_with_adapters
+            .validate(request(Some("other")))
+            .await
+        {
+            Err(ValidationError::UnknownAdapter(adapter_id)) if adapter_id == "other" => (),
+            r => panic!("Unexpected adapter_id {r:?}"),
+        }
+
+        // Adapters loaded and unloaded while the shards run
+        validation_with_adapters
+            .loaded_adapters()
+            .set(Some(vec!["other".to_string()]));
+        assert!(validation_with_adapters
+            .validate(request(Some("other")))
+            .await
+            .is_ok());
+        assert!(matches!(
+            validation_with_adapters
+                .validate(request(Some("acme/lora")))
+                .await,
+            Err(ValidationError::UnknownAdapter(_))
+        ));
+
+        // Not checked when the shards do not report their adapters
+        assert!(validation(None)
+            .validate(request(Some("other")))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_validation_default_parameters() {
+        let tokenizer = Some(word_level_tokenizer(&["a", "b", "[UNK]"]));
+        let validation = Validation::new(
+            1,
+            tokenizer,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            10,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig {
+                default_parameters: DefaultParameters {
+                    temperature: Some(0.7),
+                    top_p: Some(0.9),
+                    max_new_tokens: Some(6),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let request = |inputs: &str, parameters| GenerateRequest {
+            inputs: inputs.to_string(),
+            input_ids: None,
+            messages: None,
+            parameters,
+        };
+
+        let valid_request = validation
+            .validate(request("a b", default_parameters()))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.temperature, 0.7);
+        assert_eq!(valid_request.parameters.top_p, 0.9);
+        assert_eq!(valid_request.stopping_parameters.max_new_tokens, 6);
+
+        // The request values win
+        let valid_request = validation
+            .validate(request(
+                "a b",
+                GenerateParameters {
+                    temperature: Some(0.2),
+                    max_new_tokens: Some(3),
+                    ..default_parameters()
+                },
+            ))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.temperature, 0.2);
+        assert_eq!(valid_request.stopping_parameters.max_new_tokens, 3);
+
+        // The default `max_new_tokens` is lowered for the long inputs
+        let valid_request = validation
+            .validate(request("a b a b a", default_parameters()))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.stopping_parameters.max_new_tokens, 5);
+    }
+
+    #[tokio::test]
+    async fn test_validation_token_estimate() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            106,
+            true,
+            None,
+            Some(TokenEstimator::new(2.0, Vec::new()).unwrap()),
+            None,
+            ValidationConfig::default(),
+        );
+        let request = |inputs: &str, truncate| GenerateRequest {
+            inputs: inputs.to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                truncate,
+                ..default_parameters()
+            },
+        };
+
+        // `max_new_tokens` defaults to the remaining tokens
+        let valid_request = validation.validate(request("a b c d", None)).await.unwrap();
+        assert_eq!(valid_request.input_length, 4);
+        assert_eq!(valid_request.stopping_parameters.max_new_tokens, 102);
+
+        match validation.validate(request("abcdefghijkl", None)).await {
+            Err(ValidationError::InputLength(5, 6)) => (),
+            r => panic!("Unexpected input length {r:?}"),
+        }
+        let valid_request = validation
+            .validate(request("abcdefghijkl", Some(3)))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.input_length, 3);
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let inputs = "Hello\u{7}\tworld\u{0}\r\ne\u{301}".to_string();
+        assert_eq!(
+            sanitize(inputs.clone(), InputSanitization::None).unwrap(),
+            inputs
+        );
+        assert_eq!(
+            sanitize(inputs.clone(), InputSanitization::Strip).unwrap(),
+            "Hello\tworld\r\n\u{e9}"
+        );
+        match sanitize(inputs, InputSanitization::Reject) {
+            Err(ValidationError::ControlCharacter(7)) => (),
+            r => panic!("Unexpected sanitization {r:?}"),
+        }
+        assert_eq!(
+            sanitize("e\u{301}".to_string(), InputSanitization::Reject).unwrap(),
+            "\u{e9}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validation_input_sanitization() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            50,
+            106,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig {
+                input_sanitization: InputSanitization::Reject,
+                ..Default::default()
+            },
+        );
+        let sanitized = |input_sanitization| GenerateRequest {
+            inputs: "Hello\u{1b}[31m".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                input_sanitization,
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        match validation.validate(sanitized(None)).await {
+            Err(ValidationError::ControlCharacter(0x1b)) => (),
+            r => panic!("Unexpected sanitization {r:?}"),
+        }
+        // Requests can override the server sanitization
+        let valid_request = validation
+            .validate(sanitized(Some(InputSanitization::Strip)))
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.inputs,
+            vec![Chunk::Text("Hello[31m".to_string()).into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validation_messages() {
+        let chat_template = ChatTemplate::from_configs(
+            HubTokenizerConfig {
+                chat_template: Some(ChatTemplateVersions::Single(
+                    "{% for message in messages %}{{ message.role }}: {{ message.content }}\n{% endfor %}"
+                        .to_string(),
+                )),
+                ..Default::default()
+            },
+            HubProcessorConfig::default(),
+        );
+        let validation = |chat_template| {
+            Validation::new(
+                1,
+                None,
+                None,
+                None,
+                2,
+                3,
+                4,
+                50,
+                106,
+                true,
+                chat_template,
+                None,
+                None,
+                ValidationConfig::default(),
+            )
+        };
+        let request = |inputs: &str| {
+            GenerateRequest {
+            inputs: inputs.to_string(),
+            input_ids: None,
+            messages: Some(
+                serde_json::from_str(
+                    r#"[{"role": "system", "content": "Be brief"}, {"role": "user", "content": "Hello"}]"#,
+                )
+                .unwrap(),
+            ),
+            parameters: GenerateParameters {
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        }
+        };
+
+        let valid_request = validation(chat_template.clone())
+            .validate(request(""))
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.inputs,
+            vec![Chunk::Text("system: Be brief\nuser: Hello\n".to_string()).into()]
+        );
+
+        match validation(chat_template).validate(request("Hello")).await {
+            Err(ValidationError::Messages) => (),
+            r => panic!("Unexpected messages {r:?}"),
+        }
+        match validation(None).validate(request("")).await {
+            Err(ValidationError::ChatTemplateNotFound) => (),
+            r => panic!("Unexpected messages {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_candidate_seeds() {
+        let parameters = |best_of, seed, best_of_seeds| GenerateParameters {
+            best_of: Some(best_of),
+            seed,
+            best_of_seeds,
+            ..default_parameters()
+        };
+
+        assert_eq!(
+            candidate_seeds(&parameters(3, Some(u64::MAX), None)).unwrap(),
+            vec![Some(u64::MAX), Some(0), Some(1)]
+        );
+        assert_eq!(
+            candidate_seeds(&parameters(2, None, Some(vec![7, 3]))).unwrap(),
+            vec![Some(7), Some(3)]
+        );
+        assert_eq!(
+            candidate_seeds(&parameters(2, None, None)).unwrap(),
+            vec![None, None]
+        );
+        match candidate_seeds(&parameters(2, None, Some(vec![7]))) {
+            Err(ValidationError::BestOfSeeds(2, 1)) => (),
+            r => panic!("Unexpected best_of_seeds {r:?}"),
+        }
+        match candidate_seeds(&parameters(2, Some(1), Some(vec![7, 3]))) {
+            Err(ValidationError::BestOfSeed) => (),
+            r => panic!("Unexpected best_of_seeds {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_greedy_temperature() {
+        let tokenizer = Some(word_level_tokenizer(&["a", "b", "[UNK]"]));
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            config,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let temperature = |temperature, best_of| GenerateRequest {
+            inputs: "a b".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                temperature: Some(temperature),
+                best_of: Some(best_of),
+                do_sample: true,
+                top_k: Some(5),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation.validate(temperature(0.0, 1)).await.unwrap();
+        assert!(!valid_request.parameters.do_sample);
+        assert_eq!(valid_request.parameters.temperature, 1.0);
+        assert_eq!(valid_request.parameters.top_k, 0);
+
+        match validation.validate(temperature(0.0, 2)).await {
+            Err(ValidationError::BestOfSampling) => (),
+            r => panic!("Unexpected greedy best_of {r:?}"),
+        }
+        match validation.validate(temperature(-1.0, 1)).await {
+            Err(ValidationError::Temperature) => (),
+            r => panic!("Unexpected temperature {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_truncation_direction() {
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = |tokenizer| {
+            Validation::new(
+                workers,
+                tokenizer,
+                config.clone(),
+                None,
+                max_best_of,
+                max_stop_sequence,
+                max_top_n_tokens,
+                max_input_length,
+                max_total_tokens,
+                disable_grammar_support,
+                None,
+                None,
+                None,
+                ValidationConfig::default(),
+            )
+        };
+        let truncated = |truncation_direction| GenerateRequest {
+            inputs: "a b  [UNK] a".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                truncate: Some(2),
+                truncation_direction,
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let validation_with_tokenizer =
+            validation(Some(word_level_tokenizer(&["a", "b", "[UNK]"])));
+        let valid_request = validation_with_tokenizer
+            .validate(truncated(TruncationDirection::Right))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.input_length, 2);
+        assert_eq!(
+            valid_request.inputs,
+            vec![Chunk::Text("a b".to_string()).into()]
+        );
+        let valid_request = validation_with_tokenizer
+            .validate(truncated(TruncationDirection::Left))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.input_length, 2);
+        assert_eq!(
+            valid_request.inputs,
+            vec![Chunk::Text("a b  [UNK] a".to_string()).into()]
+        );
+
+        match validation(None)
+            .validate(truncated(TruncationDirection::Right))
+            .await
+        {
+            Err(ValidationError::TruncationDirection) => (),
+            r => panic!("Unexpected truncation_direction {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_suffix() {
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = |tokenizer| {
+            Validation::new(
+                workers,
+                Some(tokenizer),
+                config.clone(),
+                None,
+                max_best_of,
+                max_stop_sequence,
+                max_top_n_tokens,
+                max_input_length,
+                max_total_tokens,
+                disable_grammar_support,
+                None,
+                None,
+                None,
+                ValidationConfig::default(),
+            )
+        };
+        let request = GenerateRequest {
+            inputs: "a".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                suffix: Some("b".to_string()),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let fim_tokens = ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"];
+        let mut tokenizer = word_level_tokenizer(&["a", "b", "[UNK]"]);
+        tokenizer.add_special_tokens(&fim_tokens.map(|token| AddedToken::from(token, true)));
+        let valid_request = validation(tokenizer)
+            .validate(request.clone())
+            .await
+            .unwrap();
+        assert_eq!(valid_request.input_length, 5);
+        assert_eq!(
+            valid_request.inputs,
+            vec![Chunk::Text("<fim_prefix>a<fim_suffix>b<fim_middle>".to_string()).into()]
+        );
+
+        match validation(word_level_tokenizer(&["a", "b", "[UNK]"]))
+            .validate(request)
+            .await
+        {
+            Err(ValidationError::Suffix) => (),
+            r => panic!("Unexpected suffix {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_input_ids() {
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = |tokenizer| {
+            Validation::new(
+                workers,
+                tokenizer,
+                config.clone(),
+                None,
+                max_best_of,
+                max_stop_sequence,
+                max_top_n_tokens,
+                max_input_length,
+                max_total_tokens,
+                disable_grammar_support,
+                None,
+                None,
+                None,
+                ValidationConfig::default(),
+            )
+        };
+        let request = |inputs: &str, input_ids| GenerateRequest {
+            inputs: inputs.to_string(),
+            input_ids: Some(input_ids),
+            messages: None,
+            parameters: GenerateParameters {
+                truncate: Some(3),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let validation_with_tokenizer =
+            validation(Some(word_level_tokenizer(&["a", "b", "[UNK]"])));
+        let valid_request = validation_with_tokenizer
+            .validate(request("", vec![0, 1, 0, 2]))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.input_ids, vec![1, 0, 2]);
+        assert_eq!(valid_request.input_length, 3);
+        assert_eq!(
+            valid_request.inputs,
+            vec![Chunk::Text("b a [UNK]".to_string()).into()]
+        );
+
+        match validation_with_tokenizer
+            .validate(request("", vec![0, 3]))
+            .await
+        {
+            Err(ValidationError::InputId(3, 3)) => (),
+            r => panic!("Unexpected input_ids {r:?}"),
+        }
+        match validation_with_tokenizer
+            .validate(request("a", vec![0]))
+            .await
+        {
+            Err(ValidationError::InputIds) => (),
+            r => panic!("Unexpected input_ids {r:?}"),
+        }
+        match validation(None).validate(request("", vec![0])).await {
+            Err(ValidationError::InputIdsTokenizer) => (),
+            r => panic!("Unexpected input_ids {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_add_special_tokens() {
+        use tokenizers::processors::template::TemplateProcessing;
+
+        let mut tokenizer = word_level_tokenizer(&["<s>", "a", "b", "[UNK]"]);
+        tokenizer.with_post_processor(
+            TemplateProcessing::builder()
+                .try_single("<s> $A")
+                .unwrap()
+                .special_tokens(vec![("<s>", 0)])
+                .build()
+                .unwrap(),
+        );
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = Validation::new(
+            workers,
+            Some(tokenizer),
+            config,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let request = |add_special_tokens| GenerateRequest {
+            inputs: "<s> a b".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                add_special_tokens,
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation.validate(request(None)).await.unwrap();
+        assert_eq!(valid_request.input_length, 4);
+        assert!(valid_request.input_ids.is_empty());
+
+        let valid_request = validation.validate(request(Some(false))).await.unwrap();
+        assert_eq!(valid_request.input_length, 3);
+        assert_eq!(valid_request.input_ids, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_validation_guidance() {
+        let tokenizer = Some(word_level_tokenizer(&["a", "b", "[UNK]"]));
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            config,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig {
+                supports_guidance: true,
+                ..Default::default()
+            },
+        );
+        let guided = |negative_inputs: Option<&str>, guidance_scale| GenerateRequest {
+            inputs: "a b".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                negative_inputs: negative_inputs.map(str::to_string),
+                guidance_scale,
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation
+            .validate(guided(Some("a b a"), Some(2.0)))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.guidance_scale, 2.0);
+        assert_eq!(valid_request.parameters.negative_input_ids, vec![0, 1, 0]);
+
+        match validation
+            .validate(guided(Some("a b a b"), Some(2.0)))
+            .await
+        {
+            Err(ValidationError::NegativeInputsLength(5, 2, 4)) => (),
+            r => panic!("Unexpected negative_inputs {r:?}"),
+        }
+        match validation.validate(guided(Some("a"), None)).await {
+            Err(ValidationError::NegativeInputs) => (),
+            r => panic!("Unexpected negative_inputs {r:?}"),
+        }
+        match validation.validate(guided(None, Some(0.5))).await {
+            Err(ValidationError::GuidanceScale(_, _)) => (),
+            r => panic!("Unexpected guidance_scale {r:?}"),
+        }
+        let valid_request = validation.validate(guided(None, Some(2.0))).await.unwrap();
+        assert_eq!(valid_request.guidance_tokens(), 6);
+        assert_eq!(
+            validation
+                .validate(guided(None, None))
+                .await
+                .unwrap()
+                .guidance_tokens(),
+            0
+        );
+
+        // The flash and seq2seq models ignore the guidance
+        let validation = Validation::new(
+            workers,
+            Some(word_level_tokenizer(&["a", "b", "[UNK]"])),
+            None,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        match validation.validate(guided(None, Some(2.0))).await {
+            Err(ValidationError::GuidanceUnsupported) => (),
+            r => panic!("Unexpected guidance_scale {r:?}"),
+        }
+        assert!(validation.validate(guided(None, None)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_validation_min_new_tokens() {
+        let tokenizer = Some(word_level_tokenizer(&["a", "b", "[UNK]"]));
+        let max_total_tokens = 10;
+        let validation = Validation::new(
+            1,
+            tokenizer,
+            None,
+            None,
+            2,
+            3,
+            4,
+            5,
+            max_total_tokens,
+            true,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let min_new_tokens = |min_new_tokens, max_new_tokens| GenerateRequest {
+            inputs: "a b".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                min_new_tokens,
+                max_new_tokens,
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation
+            .validate(min_new_tokens(Some(3), Some(5)))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.min_new_tokens, 3);
+        let valid_request = validation
+            .validate(min_new_tokens(None, Some(5)))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.min_new_tokens, 0);
+
+        match validation.validate(min_new_tokens(Some(6), Some(5))).await {
+            Err(ValidationError::MinNewTokens(5, 6)) => (),
+            r => panic!("Unexpected min_new_tokens {r:?}"),
+        }
+        // Without `max_new_tokens`, the total tokens budget is the limit
+        match validation.validate(min_new_tokens(Some(9), None)).await {
+            Err(ValidationError::MinNewTokens(8, 9)) => (),
+            r => panic!("Unexpected min_new_tokens {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_logit_bias() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            config,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let logit_bias = (0..=MAX_LOGIT_BIAS_TOKENS as u32)
+            .map(|token_id| (token_id, 1.0))
+            .collect();
+        match validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
+                parameters: GenerateParameters {
+                    logit_bias,
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+        {
+            Err(ValidationError::LogitBias(MAX_LOGIT_BIAS_TOKENS, _)) => (),
+            _ => panic!("Unexpected logit_bias"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
+                parameters: GenerateParameters {
+                    logit_bias: HashMap::from([(1, -500.0), (2, 0.5), (3, 200.0)]),
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.parameters.logit_bias,
+            HashMap::from([(1, -100.0), (2, 0.5), (3, 100.0)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validation_top_n_tokens() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequences = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 106;
+        let workers = 1;
+        let disable_grammar_support = true;
+        let config = None;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            config,
+            None,
+            max_best_of,
+            max_stop_sequences,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        match validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
+                parameters: GenerateParameters {
+                    top_n_tokens: Some(5),
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+        {
+            Err(ValidationError::TopNTokens(4, 5)) => (),
+            _ => panic!("Unexpected top_n_tokens"),
+        }
+
+        validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
+                parameters: GenerateParameters {
+                    top_n_tokens: Some(4),
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+
+        validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
+                parameters: GenerateParameters {
+                    top_n_tokens: Some(0),
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                inputs: "Hello".to_string(),
+                input_ids: None,
+                messages: None,
+                parameters: GenerateParameters {
+                    top_n_tokens: None,
+                    max_new_tokens: Some(5),
+                    ..default_parameters()
+                },
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(valid_request.top_n_tokens, 0);
+    }
+
+    static PIXEL_GIF: &str = "R0lGODdhAQABAIEAAP///wAAAAAAAAAAACwAAAAAAQABAAAIBAABBAQAOw==";
+
+    #[tokio::test]
+    async fn test_prepare_input_chunks() {
+        let pixel_data = STANDARD.decode(PIXEL_GIF).unwrap();
+
+        let tokenizer = Some(get_tokenizer().await);
+
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 6;
+        let disable_grammar_support = true;
+        let workers = 1;
+        let config = Config::Paligemma(Paligemma {
+            text_config: PaliTextConfig {
+                num_image_tokens: 1,
+            },
+        });
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            Some(config),
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+            disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+
+        let chunks = match validation
+            .tokenize(
+                format!("test![](data:image/gif;base64,{})", PIXEL_GIF),
+                None,
+                TruncationDirection::Left,
+                true,
+            )
+            .await
         {
             Ok(Some((_encoding, chunks))) => chunks,
             _ => panic!("Unexpected tokenization failure"),
@@ -1075,7 +5510,7 @@ mod tests {
         let max_total_tokens = 6;
         let disable_grammar_support = true;
         let workers = 1;
-        let config = Config::Idefics2(Idefics2 {});
+        let config = Config::Idefics2(Idefics2::default());
         let validation = Validation::new(
             workers,
             tokenizer,
@@ -1091,6 +5526,10 @@ mod tests {
             max_input_length,
             max_total_tokens,
             disable_grammar_support,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
         );
 
         let (encoding, chunks) = match validation
@@ -1100,6 +5539,8 @@ mod tests {
                     PIXEL_GIF, PIXEL_GIF
                 ),
                 None,
+                TruncationDirection::Left,
+                true,
             )
             .await
         {
@@ -1140,4 +5581,926 @@ mod tests {
             11
         );
     }
+
+    #[test]
+    fn test_grammar_cache_eviction() {
+        let cache = GrammarCache::new(2, None);
+        cache.insert("a".to_string(), ());
+        cache.insert("b".to_string(), ());
+        cache.insert("a".to_string(), ());
+        assert!(cache.contains("a"));
+        assert!(cache.contains("b"));
+
+        cache.insert("c".to_string(), ());
+        assert!(!cache.contains("a"));
+        assert!(cache.contains("b"));
+        assert!(cache.contains("c"));
+    }
+
+    #[test]
+    fn test_bounded_map_update() {
+        let map = BoundedMap::new(2, None);
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        map.insert("a".to_string(), 3);
+        assert_eq!(map.get("a"), Some(3));
+
+        // The update keeps the place of `a` in the eviction order
+        map.insert("c".to_string(), 4);
+        assert_eq!(map.get("a"), None);
+        assert_eq!(map.get("b"), Some(2));
+        assert_eq!(map.get("c"), Some(4));
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar_choice() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            50,
+            106,
+            false,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let choice = |choices: Vec<&str>| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                grammar: Some(GrammarType::Choice(
+                    choices.into_iter().map(String::from).collect(),
+                )),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation
+            .validate(choice(vec!["yes", "no (maybe)", "1.5+"]))
+            .await
+            .unwrap();
+        match valid_request.parameters.grammar {
+            Some(ValidGrammar::Regex(regex)) => assert_eq!(regex, r"(yes|no \(maybe\)|1\.5\+)"),
+            r => panic!("Unexpected grammar {r:?}"),
+        }
+
+        for choices in [vec![], vec!["yes", ""]] {
+            match validation.validate(choice(choices)).await {
+                Err(ValidationError::InvalidGrammar(_)) => (),
+                r => panic!("Unexpected grammar {r:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar_union() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            50,
+            106,
+            false,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let union = |grammars: Vec<GrammarType>| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                grammar: Some(GrammarType::Union(grammars)),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation
+            .validate(union(vec![
+                GrammarType::Regex("[0-9]+".to_string()),
+                GrammarType::Choice(vec!["yes".to_string(), "no".to_string()]),
+            ]))
+            .await
+            .unwrap();
+        match valid_request.parameters.grammar {
+            Some(ValidGrammar::Regex(regex)) => assert_eq!(regex, "(([0-9]+)|((yes|no)))"),
+            r => panic!("Unexpected grammar {r:?}"),
+        }
+
+        let point = json!({
+            "$defs": {"coordinate": {"type": "number"}},
+            "properties": {"x": {"$ref": "#/$defs/coordinate"}}
+        });
+        let valid_request = validation
+            .validate(union(vec![
+                GrammarType::Json(point),
+                GrammarType::Json(json!({"type": "string"})),
+            ]))
+            .await
+            .unwrap();
+        // The union is compiled to a regex in the router
+        let regex = match valid_request.parameters.grammar {
+            Some(ValidGrammar::Regex(regex)) => Regex::new(&format!("^{regex}$")).unwrap(),
+            r => panic!("Unexpected grammar {r:?}"),
+        };
+        for json in [r#"{"x": 1.5}"#, "{}", r#""point""#] {
+            assert!(regex.is_match(json), "{json}");
+        }
+        assert!(!regex.is_match(r#"{"x": "1.5"}"#));
+
+        for grammars in [
+            vec![],
+            vec![
+                GrammarType::Json(json!({"type": "string"})),
+                GrammarType::Regex("[0-9]+".to_string()),
+            ],
+            vec![GrammarType::Union(vec![GrammarType::Regex(
+                "[0-9]+".to_string(),
+            )])],
+            vec![GrammarType::Lark("start: \"a\" start?".to_string())],
+        ] {
+            match validation.validate(union(grammars)).await {
+                Err(ValidationError::InvalidGrammar(_)) => (),
+                r => panic!("Unexpected grammar {r:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar_lark() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            50,
+            106,
+            false,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let lark = |grammar: &str| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                grammar: Some(GrammarType::Lark(grammar.to_string())),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let grammar = "?start: list\nlist: \"[\" [list (\",\" list)*] \"]\"";
+        let valid_request = validation.validate(lark(grammar)).await.unwrap();
+        match valid_request.parameters.grammar {
+            Some(ValidGrammar::Cfg(cfg)) => assert_eq!(cfg, format!("{grammar}\n")),
+            r => panic!("Unexpected grammar {r:?}"),
+        }
+
+        // Grammars without recursive rules are compiled to a regex in the router
+        let grammar = "start: \"a\"+ NUMBER\n%import common.NUMBER";
+        let valid_request = validation.validate(lark(grammar)).await.unwrap();
+        match valid_request.parameters.grammar {
+            Some(ValidGrammar::Regex(regex)) => {
+                assert!(Regex::new(&format!("^{regex}$")).unwrap().is_match("aa1.5"))
+            }
+            r => panic!("Unexpected grammar {r:?}"),
+        }
+
+        let too_many_rules: String = (0..=MAX_LARK_DEFINITIONS)
+            .map(|i| format!("rule{i}: \"{i}\"\n"))
+            .chain(["start: rule0".to_string()])
+            .collect();
+        for grammar in ["list: \"[\" list? \"]\"", &too_many_rules] {
+            match validation.validate(lark(grammar)).await {
+                Err(ValidationError::InvalidGrammar(_)) => (),
+                r => panic!("Unexpected grammar {r:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar_registry() {
+        let dir = std::env::temp_dir().join(format!("tgi-registry-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("digits.regex"), "^[0-9]+$\n").unwrap();
+        let grammar_registry = GrammarRegistry::from_dir(dir.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            50,
+            106,
+            false,
+            None,
+            None,
+            None,
+            ValidationConfig {
+                grammar_registry,
+                ..Default::default()
+            },
+        );
+        validation.compile_grammar_registry().await.unwrap();
+        let registry = |name: &str| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                grammar: Some(GrammarType::Registry(name.to_string())),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        // Validated like the grammars of the requests
+        let valid_request = validation.validate(registry("digits")).await.unwrap();
+        match valid_request.parameters.grammar {
+            Some(ValidGrammar::Regex(regex)) => assert_eq!(regex, "[0-9]+"),
+            r => panic!("Unexpected grammar {r:?}"),
+        }
+        match validation.validate(registry("invoice_v2")).await {
+            Err(ValidationError::InvalidGrammar(_)) => (),
+            r => panic!("Unexpected grammar {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar_refs() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            50,
+            106,
+            false,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let schema = |schema: Value| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                grammar: Some(GrammarType::Json(schema)),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        // `not` is left to the model servers, with the references inlined
+        let valid_request = validation
+            .validate(schema(json!({
+                "$defs": {"name": {"not": {"const": ""}}},
+                "properties": {"name": {"$ref": "#/$defs/name"}}
+            })))
+            .await
+            .unwrap();
+        match valid_request.parameters.grammar {
+            Some(ValidGrammar::Json(schema)) => assert_eq!(
+                serde_json::from_str::<Value>(&schema).unwrap(),
+                json!({"properties": {"name": {"not": {"const": ""}}}})
+            ),
+            r => panic!("Unexpected grammar {r:?}"),
+        }
+
+        for invalid in [
+            json!({"properties": {"name": {"$ref": "https://example.com/name.json"}}}),
+            json!({"$defs": {"list": {"properties": {"next": {"$ref": "#/$defs/list"}}}},
+                   "properties": {"head": {"$ref": "#/$defs/list"}}}),
+        ] {
+            match validation.validate(schema(invalid)).await {
+                Err(ValidationError::InvalidGrammar(_)) => (),
+                r => panic!("Unexpected grammar {r:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar_artifact() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            50,
+            106,
+            false,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let compiled = |id: &str| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                grammar: Some(GrammarType::Compiled(id.to_string())),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+        let wait = |id: String| {
+            let validation = validation.clone();
+            async move {
+                loop {
+                    match validation.grammar_artifact(&id) {
+                        Some(GrammarArtifact::Compiling) => tokio::task::yield_now().await,
+                        artifact => return artifact,
+                    }
+                }
+            }
+        };
+
+        let (id, _) = validation
+            .compile_grammar_artifact(GrammarType::Regex("[0-9]+".to_string()))
+            .unwrap();
+        assert!(matches!(
+            wait(id.clone()).await,
+            Some(GrammarArtifact::Ready(_))
+        ));
+        // The same grammar has the same id
+        let (same_id, artifact) = validation
+            .compile_grammar_artifact(GrammarType::Regex("[0-9]+".to_string()))
+            .unwrap();
+        assert_eq!(same_id, id);
+        assert!(matches!(artifact, GrammarArtifact::Ready(_)));
+        let valid_request = validation.validate(compiled(&id)).await.unwrap();
+        match valid_request.parameters.grammar {
+            Some(ValidGrammar::Regex(regex)) => assert_eq!(regex, "[0-9]+"),
+            r => panic!("Unexpected grammar {r:?}"),
+        }
+
+        let (failed_id, _) = validation
+            .compile_grammar_artifact(GrammarType::Regex("(yes".to_string()))
+            .unwrap();
+        assert!(matches!(
+            wait(failed_id.clone()).await,
+            Some(GrammarArtifact::Failed(_))
+        ));
+        for id in [failed_id.as_str(), "unknown"] {
+            match validation.validate(compiled(id)).await {
+                Err(ValidationError::InvalidGrammar(_)) => (),
+                r => panic!("Unexpected grammar {r:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar_regex() {
+        let validation = Validation::new(
+            1,
+            None,
+            None,
+            None,
+            2,
+            3,
+            4,
+            50,
+            106,
+            false,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let regex = |regex: &str| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                grammar: Some(GrammarType::Regex(regex.to_string())),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        // The leading `^` and trailing `$` are implied
+        for (given, expected) in [
+            ("[a-z]+", "[a-z]+"),
+            ("^[a-z]+$", "[a-z]+"),
+            ("[0-9]+\\$", "[0-9]+\\$"),
+            ("[0-9]+\\\\$", "[0-9]+\\\\"),
+        ] {
+            let valid_request = validation.validate(regex(given)).await.unwrap();
+            match valid_request.parameters.grammar {
+                Some(ValidGrammar::Regex(valid)) => assert_eq!(valid, expected),
+                r => panic!("Unexpected grammar {r:?}"),
+            }
+        }
+
+        let too_long = "a".repeat(MAX_REGEX_GRAMMAR_LENGTH + 1);
+        let too_nested = format!("{}a{}", "(".repeat(64), ")".repeat(64));
+        for invalid in [
+            "",
+            "^$",
+            "(yes",
+            "a{1000}{1000}",
+            "\\bword\\b",
+            "a^b",
+            "(?=a)a",
+            &too_long,
+            &too_nested,
+        ] {
+            match validation.validate(regex(invalid)).await {
+                Err(ValidationError::InvalidGrammar(_)) => (),
+                r => panic!("Unexpected grammar {r:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar_fsm() {
+        let tokenizer = word_level_tokenizer(&["Hello", "yes", "no", "[UNK]"]);
+        let validation = Validation::new(
+            1,
+            Some(tokenizer),
+            None,
+            None,
+            2,
+            3,
+            4,
+            50,
+            106,
+            false,
+            None,
+            None,
+            None,
+            ValidationConfig::default(),
+        );
+        let grammar = |grammar: GrammarType| GenerateRequest {
+            inputs: "Hello".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                grammar: Some(grammar),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+
+        let valid_request = validation
+            .validate(grammar(GrammarType::Choice(vec![
+                "yes".to_string(),
+                "no".to_string(),
+            ])))
+            .await
+            .unwrap();
+        assert!(matches!(
+            valid_request.parameters.grammar,
+            Some(ValidGrammar::Regex(_))
+        ));
+        assert!(valid_request.stopping_parameters.grammar_fsm.is_some());
+        // The FSM is kept for the next requests with the same grammar
+        let valid_request = validation
+            .validate(grammar(GrammarType::Choice(vec![
+                "yes".to_string(),
+                "no".to_string(),
+            ])))
+            .await
+            .unwrap();
+        assert!(valid_request.stopping_parameters.grammar_fsm.is_some());
+
+        // An invalid regex, and grammars that no token of the vocabulary can start
+        for grammar_type in [
+            GrammarType::Regex("(yes".to_string()),
+            GrammarType::Regex("maybe".to_string()),
+            GrammarType::Json(json!({"type": "integer"})),
+        ] {
+            match validation.validate(grammar(grammar_type)).await {
+                Err(ValidationError::InvalidGrammar(_)) => (),
+                r => panic!("Unexpected grammar {r:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar_timeout() {
+        // Large enough for the FSM to take longer than the timeout
+        let words: Vec<String> = (0..10_000).map(|i| format!("w{i}")).collect();
+        let vocab: Vec<&str> = words.iter().map(String::as_str).chain(["[UNK]"]).collect();
+        let validation = Validation::new(
+            1,
+            Some(word_level_tokenizer(&vocab)),
+            None,
+            None,
+            2,
+            3,
+            4,
+            50,
+            106,
+            false,
+            None,
+            None,
+            None,
+            ValidationConfig {
+                grammar_compile_timeout: Duration::ZERO,
+                ..Default::default()
+            },
+        );
+        let request = GenerateRequest {
+            inputs: "w1".to_string(),
+            input_ids: None,
+            messages: None,
+            parameters: GenerateParameters {
+                grammar: Some(GrammarType::Regex("(w[0-9]+){1,64}".to_string())),
+                max_new_tokens: Some(5),
+                ..default_parameters()
+            },
+        };
+        match validation.validate(request).await {
+            Err(ValidationError::GrammarTimeout(_)) => (),
+            r => panic!("Unexpected grammar {r:?}"),
+        }
+    }
+
+    #[test]
+    fn test_json_object_regex() {
+        let regex = Regex::new(&format!("^{}$", json_object_regex(2))).unwrap();
+        for json in [
+            "{}",
+            r#"{"a": 1}"#,
+            r#"{"a":-1.5e+3, "b": [true, null, "c\"d"]}"#,
+            r#"{"a": {"b": [1, 2]}}"#,
+        ] {
+            assert!(regex.is_match(json), "{json}");
+        }
+        for json in [
+            "[]",
+            r#"{"a": 1,}"#,
+            r#"{"a": 01}"#,
+            r#"{a: 1}"#,
+            // Too deep
+            r#"{"a": {"b": [{}]}}"#,
+        ] {
+            assert!(!regex.is_match(json), "{json}");
+        }
+
+        let grammar: GrammarType = serde_json::from_str(r#"{"type": "json_object"}"#).unwrap();
+        assert!(matches!(grammar, GrammarType::JsonObject(None)));
+        let grammar: GrammarType =
+            serde_json::from_str(r#"{"type": "json_object", "value": {"type": "object"}}"#)
+                .unwrap();
+        assert!(matches!(grammar, GrammarType::JsonObject(Some(_))));
+    }
+
+    /// Whitespace-separated words of `vocab`, whose ids are their positions. The last word is the
+    /// unknown token.
+    fn word_level_tokenizer(vocab: &[&str]) -> Tokenizer {
+        use tokenizers::models::wordlevel::WordLevel;
+        use tokenizers::pre_tokenizers::whitespace::WhitespaceSplit;
+
+        let unk_token = vocab.last().unwrap().to_string();
+        let vocab = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token(unk_token)
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(WhitespaceSplit);
+        tokenizer
+    }
+
+    #[test]
+    fn test_truncate_encoding() {
+        let tokenizer = word_level_tokenizer(&["a", "b", "<image>", "[UNK]"]);
+        let truncated = |truncate, multimodal| {
+            let mut encoding = tokenizer.encode("a b <image> <image> b a", false).unwrap();
+            truncate_encoding(&tokenizer, &mut encoding, truncate, multimodal);
+            encoding.get_ids().to_vec()
+        };
+
+        // The end of the input is kept
+        assert_eq!(truncated(3, false), vec![2, 1, 0]);
+        assert_eq!(truncated(5, true), vec![1, 2, 2, 1, 0]);
+        // The placeholders are never split or dropped
+        assert_eq!(truncated(3, true), vec![2, 2, 1, 0]);
+        assert_eq!(truncated(1, true), vec![2, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_fetch_image_data_uri() {
+        let mut png = Vec::new();
+        image::RgbImage::new(3, 2)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let encoded = STANDARD.encode(&png);
+        let policy = FetchPolicy::default();
+        let cache = ImageCache::new(0, Duration::ZERO);
+        let limits = MultimodalLimits::default();
+
+        let image = fetch_image(
+            &format!("![](data:image/png;base64,{encoded})"),
+            &policy,
+            &limits,
+            &cache,
+        )
+        .unwrap();
+        assert_eq!(
+            (
+                image.data,
+                image.mimetype.as_str(),
+                image.height,
+                image.width
+            ),
+            (png.clone(), "image/png", 2, 3)
+        );
+        // Parameters before the encoding are ignored
+        assert!(fetch_image(
+            &format!("![](data:image/png;name=cat.png;base64,{encoded})"),
+            &policy,
+            &limits,
+            &cache
+        )
+        .is_ok());
+
+        // The declared mimetype must match the content
+        assert!(matches!(
+            fetch_image(
+                &format!("![](data:image/jpeg;base64,{encoded})"),
+                &policy,
+                &limits,
+                &cache
+            ),
+            Err(ValidationError::InvalidImageContent(_))
+        ));
+        assert!(matches!(
+            fetch_image(
+                &format!("![](data:text/plain;base64,{encoded})"),
+                &policy,
+                &limits,
+                &cache
+            ),
+            Err(ValidationError::InvalidImageContent(_))
+        ));
+        assert!(matches!(
+            fetch_image(
+                &format!("![](data:image/png,{encoded})"),
+                &policy,
+                &limits,
+                &cache
+            ),
+            Err(ValidationError::InvalidImageContent(_))
+        ));
+        let oversized = "A".repeat(MAX_IMAGE_BYTES / 3 * 4 + 4);
+        assert!(matches!(
+            fetch_image(
+                &format!("![](data:image/png;base64,{oversized})"),
+                &policy,
+                &limits,
+                &cache
+            ),
+            Err(ValidationError::ImageSize(_, _))
+        ));
+        // The size limit can be lowered by the multimodal limits
+        let small = MultimodalLimits {
+            max_image_bytes: Some(png.len() - 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            fetch_image(
+                &format!("![](data:image/png;base64,{encoded})"),
+                &policy,
+                &small,
+                &cache
+            ),
+            Err(ValidationError::ImageSize(max, _)) if max == png.len() - 1
+        ));
+
+        let limits = MultimodalLimits {
+            max_resolution: Some(3),
+            low_detail_resolution: Some(2),
+            ..Default::default()
+        };
+        let width = |detail: &str| {
+            fetch_image(
+                &format!("![](data:image/png;base64,{encoded}{detail})"),
+                &policy,
+                &limits,
+                &cache,
+            )
+            .map(|image| image.width)
+        };
+        assert_eq!(width("").unwrap(), 3);
+        assert_eq!(width("#detail=high").unwrap(), 3);
+        assert_eq!(width("#detail=low").unwrap(), 2);
+        assert!(matches!(
+            width("#detail=medium"),
+            Err(ValidationError::InvalidImageContent(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_video() {
+        let video = parse_video(
+            "![video](data:video/mp4;base64,AAAA#frames=4&fps=0.5)",
+            &FetchPolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(video.source, Some(video::Source::Data(vec![0, 0, 0])));
+        assert_eq!(video.mimetype, "video/mp4");
+        assert_eq!(video.num_frames, 4);
+        assert_eq!(video.fps, Some(0.5));
+
+        let video = parse_video(
+            "![video](data:video/webm;base64,AAAA)",
+            &FetchPolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(video.num_frames, DEFAULT_VIDEO_FRAMES);
+        assert_eq!(video.fps, None);
+
+        assert!(matches!(
+            parse_video(
+                "![video](data:video/mp4;base64,AAAA#frames=1000)",
+                &FetchPolicy::default()
+            ),
+            Err(ValidationError::VideoFrames(MAX_VIDEO_FRAMES, 1000))
+        ));
+        assert!(matches!(
+            parse_video(
+                "![video](data:image/png;base64,AAAA)",
+                &FetchPolicy::default()
+            ),
+            Err(ValidationError::InvalidVideoContent(_))
+        ));
+        assert!(matches!(
+            parse_video(
+                "![video](ftp://example.com/clip.mp4)",
+                &FetchPolicy::default()
+            ),
+            Err(ValidationError::InvalidVideoContent(_))
+        ));
+
+        let config = Config::Paligemma(Paligemma {
+            text_config: PaliTextConfig {
+                num_image_tokens: 2,
+            },
+        });
+        assert_eq!(
+            video_tokens(&config, None, 3),
+            "<image>".repeat(6),
+            "Each frame counts as an image"
+        );
+    }
+
+    /// PCM WAV file of `samples` 16 bits mono samples
+    fn wav(sample_rate: u32, samples: u32) -> Vec<u8> {
+        let data_size = samples * 2;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&[1, 0, 1, 0]);
+        wav.extend_from_slice(&sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+        wav.extend_from_slice(&[2, 0, 16, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_size.to_le_bytes());
+        wav.resize(wav.len() + data_size as usize, 0);
+        wav
+    }
+
+    #[test]
+    fn test_fetch_audio() {
+        let chunk =
+            |data: &[u8]| format!("![audio](data:audio/wav;base64,{})", STANDARD.encode(data));
+        let audio = fetch_audio(&chunk(&wav(16_000, 8_000)), &FetchPolicy::default()).unwrap();
+        assert_eq!(audio.sample_rate, 16_000);
+        assert_eq!(audio.duration, 0.5);
+        assert_eq!(audio_tokens(audio.duration), "<audio>".repeat(13));
+
+        assert!(matches!(
+            fetch_audio(&chunk(&wav(4_000, 8_000)), &FetchPolicy::default()),
+            Err(ValidationError::AudioSampleRate(_, _, 4_000))
+        ));
+        assert!(matches!(
+            fetch_audio(&chunk(&wav(8_000, 8_000 * 301)), &FetchPolicy::default()),
+            Err(ValidationError::AudioDuration(_, _))
+        ));
+        assert!(matches!(
+            fetch_audio(&chunk(b"not a wav file"), &FetchPolicy::default()),
+            Err(ValidationError::InvalidAudioContent(_))
+        ));
+        assert!(matches!(
+            fetch_audio(
+                "![audio](data:audio/mpeg;base64,AAAA)",
+                &FetchPolicy::default()
+            ),
+            Err(ValidationError::InvalidAudioContent(_))
+        ));
+    }
+
+    #[test]
+    fn test_fetch_document() {
+        let policy = FetchPolicy::default();
+        let pdf = STANDARD.encode(b"%PDF-1.7\n%%EOF\n");
+        assert!(fetch_document(
+            &format!("![document](data:application/pdf;base64,{pdf})"),
+            &policy
+        )
+        .is_ok());
+        assert!(matches!(
+            fetch_document(
+                &format!("![document](data:image/png;base64,{pdf})"),
+                &policy
+            ),
+            Err(ValidationError::InvalidDocumentContent(_))
+        ));
+        let text = STANDARD.encode(b"not a document");
+        assert!(matches!(
+            fetch_document(
+                &format!("![document](data:application/pdf;base64,{text})"),
+                &policy
+            ),
+            Err(ValidationError::InvalidDocumentContent(_))
+        ));
+    }
+
+    #[test]
+    fn test_grammar_throttle() {
+        let throttle = GrammarThrottle::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        let at = |seconds: u64| now + Duration::from_secs(seconds);
+
+        throttle.record_failure("acme".to_string(), at(0));
+        assert_eq!(throttle.retry_after("acme", at(1)), None);
+        throttle.record_failure("acme".to_string(), at(10));
+        assert_eq!(
+            throttle.retry_after("acme", at(10)),
+            Some(Duration::from_secs(50))
+        );
+        assert_eq!(throttle.retry_after("other", at(10)), None);
+
+        // The oldest failure left the window
+        assert_eq!(throttle.retry_after("acme", at(60)), None);
+        throttle.record_failure("acme".to_string(), at(65));
+        assert_eq!(
+            throttle.retry_after("acme", at(65)),
+            Some(Duration::from_secs(5))
+        );
+
+        let disabled = GrammarThrottle::new(0, Duration::from_secs(60));
+        disabled.record_failure("acme".to_string(), at(0));
+        assert_eq!(disabled.retry_after("acme", at(0)), None);
+    }
+
+    #[tokio::test]
+    async fn test_validation_rejections() {
+        let rejections = ValidationRejections::new(0.0);
+        assert!(!rejections.sample());
+
+        rejections.record(&ValidationError::TopP, None);
+        rejections.record(&ValidationError::InputLength(1, 2), None);
+        rejections.record(&ValidationError::InputLength(1, 3), None);
+
+        assert_eq!(
+            rejections.take(),
+            vec![
+                (("validation_input_length", "inputs"), 2),
+                (("validation_top_p", "top_p"), 1)
+            ]
+        );
+        assert!(rejections.take().is_empty());
+    }
 }
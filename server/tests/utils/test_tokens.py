@@ -1,4 +1,6 @@
 import torch
+from text_generation_server.pb.generate_pb2 import TokenFsm
+from text_generation_server.utils.logits_process import TokenFSM
 from text_generation_server.utils.tokens import (
     StopSequenceCriteria,
     StoppingCriteria,
@@ -37,6 +39,22 @@ def test_stopping_criteria_eos():
     assert criteria(0, "") == (True, FinishReason.FINISH_REASON_EOS_TOKEN)
 
 
+
+def test_stopping_criteria_stop_token_ids():
+    criteria = StoppingCriteria(
+        0, [], max_new_tokens=5, ignore_eos_token=True, stop_token_ids={7}
+    )
+    assert criteria(0, "") == (False, None)
+    assert criteria(7, "") == (True, FinishReason.FINISH_REASON_STOP_SEQUENCE)
+
+
+def test_stopping_criteria_max_time():
+    criteria = StoppingCriteria(0, [], max_new_tokens=5, max_time=60.0)
+    assert criteria(1, "") == (False, None)
+    criteria.start_time -= 61.0
+    assert criteria(1, "") == (True, FinishReason.FINISH_REASON_MAX_TIME)
+
+
 def test_stopping_criteria_max():
     criteria = StoppingCriteria(0, [StopSequenceCriteria("/test;")], max_new_tokens=5)
     assert criteria(1, "") == (False, None)
@@ -46,6 +64,27 @@ def test_stopping_criteria_max():
     assert criteria(1, "") == (True, FinishReason.FINISH_REASON_LENGTH)
 
 
+def test_token_fsm():
+    # 0 -(3)-> 1, 0 -(7)-> 2, 2 -(1)-> 0, 2 -(3)-> 2, 2 -(9)-> 1, 1 is final
+    fsm = TokenFSM(
+        TokenFsm(
+            offsets=[0, 2, 2, 5],
+            tokens=[3, 7, 1, 3, 9],
+            next_states=[1, 2, 0, 2, 1],
+            final_states=[1],
+        ),
+        eos_token_id=0,
+    )
+
+    assert fsm.allowed_token_ids(0) == [3, 7]
+    assert fsm.allowed_token_ids(1) == [0]
+    assert fsm.allowed_token_ids(2) == [1, 3, 9]
+    assert fsm.next_state(0, 7) == 2
+    assert fsm.next_state(2, 9) == 1
+    assert fsm.next_state(0, 5) == -1
+    assert fsm.next_state(1, 0) == -1
+
+
 def test_batch_top_tokens():
     top_n_tokens = [0, 2, 3, 4, 5]
     top_n_tokens_tensor = torch.tensor(top_n_tokens)
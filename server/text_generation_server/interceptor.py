@@ -7,6 +7,9 @@ from grpc_interceptor.server import AsyncServerInterceptor
 from loguru import logger
 from typing import Callable, Any
 
+# Metadata key of the ids of the router requests served by a call, comma separated
+REQUEST_ID_KEY = "x-request-id"
+
 
 class ExceptionInterceptor(AsyncServerInterceptor):
     async def intercept(
@@ -16,22 +19,28 @@ class ExceptionInterceptor(AsyncServerInterceptor):
         context: grpc.ServicerContext,
         method_name: str,
     ) -> Any:
-        try:
-            response = method(request_or_iterator, context)
-            return await response
-        except Exception as err:
-            method_name = method_name.split("/")[-1]
-            logger.exception(f"Method {method_name} encountered an error.")
+        # Correlate the shard logs with the router requests
+        metadata = dict(context.invocation_metadata() or ())
+        request_ids = metadata.get(REQUEST_ID_KEY, "")
+        with logger.contextualize(request_ids=request_ids):
+            try:
+                response = method(request_or_iterator, context)
+                return await response
+            except Exception as err:
+                method_name = method_name.split("/")[-1]
+                logger.exception(
+                    f"Method {method_name} encountered an error (requests: {request_ids or 'none'})."
+                )
 
-            # Runtime Error cannot be recovered from
-            if isinstance(err, RuntimeError):
-                exit(1)
+                # Runtime Error cannot be recovered from
+                if isinstance(err, RuntimeError):
+                    exit(1)
 
-            if torch.cuda.is_available():
-                torch.cuda.empty_cache()
+                if torch.cuda.is_available():
+                    torch.cuda.empty_cache()
 
-            await context.abort_with_status(
-                rpc_status.to_status(
-                    status_pb2.Status(code=code_pb2.INTERNAL, message=str(err))
+                await context.abort_with_status(
+                    rpc_status.to_status(
+                        status_pb2.Status(code=code_pb2.INTERNAL, message=str(err))
+                    )
                 )
-            )
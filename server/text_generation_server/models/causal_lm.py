@@ -550,6 +550,10 @@ class CausalLM(Model):
     def batch_type(self) -> Type[CausalLMBatch]:
         return CausalLMBatch
 
+    @property
+    def supports_guidance(self) -> bool:
+        return True
+
     def decode(self, generated_ids: List[int]) -> str:
         return self.tokenizer.decode(
             generated_ids, skip_special_tokens=True, clean_up_tokenization_spaces=False
@@ -638,7 +642,7 @@ class CausalLM(Model):
             top_token_logprobs,
         ) in enumerate(iterator):
             # Select next token
-            next_token_id, logprobs = next_token_chooser(
+            next_token_id, logprobs = next_token_chooser.enable_guidance(self.model)(
                 all_input_ids.view(1, -1), logits[-1:, :]
             )
 
@@ -143,13 +143,21 @@ class FlashCausalLMBatch(Batch):
         batch_inputs = []
         max_truncation = 0
         for r in requests:
-            batch_inputs.append(concat_text_chunks(r.input_chunks.chunks))
-            max_truncation = max(max_truncation, r.truncate)
-
-        batch_tokenized_inputs = tokenizer(
-            batch_inputs, truncation=True, max_length=max_truncation
-        )["input_ids"]
-        return batch_tokenized_inputs
+            # Pre-tokenized requests are not tokenized again
+            if not r.input_ids:
+                batch_inputs.append(concat_text_chunks(r.input_chunks.chunks))
+                max_truncation = max(max_truncation, r.truncate)
+
+        batch_tokenized_inputs = []
+        if batch_inputs:
+            batch_tokenized_inputs = tokenizer(
+                batch_inputs, truncation=True, max_length=max_truncation
+            )["input_ids"]
+        batch_tokenized_inputs = iter(batch_tokenized_inputs)
+        return [
+            list(r.input_ids) if r.input_ids else next(batch_tokenized_inputs)
+            for r in requests
+        ]
 
     @classmethod
     def from_tokenized(
@@ -664,6 +672,8 @@ class FlashCausalLMBatch(Batch):
 
         next_token_chooser_parameters = []
         fsm_grammar_states = []
+        mirostat_mus = []
+        grammar_fsms = []
         stopping_criterias = []
         top_n_tokens = []
 
@@ -729,6 +739,8 @@ class FlashCausalLMBatch(Batch):
 
             next_token_chooser_parameters.extend([r.parameters for r in batch.requests])
             fsm_grammar_states.extend(batch.next_token_chooser.fsm_grammar_states)
+            mirostat_mus.extend(batch.next_token_chooser.mirostat_mus)
+            grammar_fsms.extend(batch.next_token_chooser.grammar_fsms)
             stopping_criterias.extend(batch.stopping_criterias)
 
             top_n_tokens.extend(batch.top_n_tokens)
@@ -745,6 +757,8 @@ class FlashCausalLMBatch(Batch):
             device=batches[0].next_token_chooser.device,
             tokenizer=batches[0].next_token_chooser.tokenizer,
             fsm_grammar_states=fsm_grammar_states,
+            mirostat_mus=mirostat_mus,
+            grammar_fsms=grammar_fsms,
         )
 
         speculative_ids = (
@@ -1261,6 +1275,11 @@ class FlashCausalLM(Model):
             speculate,
             batch.speculative_ids,
             speculative_logits,
+            input_lengths=batch.input_lengths,
+            generated_tokens=[
+                stopping_criteria.current_tokens
+                for stopping_criteria in batch.stopping_criterias
+            ],
         )
 
         batch_top_token_ids, batch_top_token_logprobs = batch_top_tokens(
@@ -8,6 +8,7 @@ from transformers import PreTrainedTokenizerBase, PretrainedConfig
 
 from text_generation_server.models.types import Batch, Generation
 from text_generation_server.utils.speculate import get_speculate
+from text_generation_server.models.globals import get_adapter_to_index
 from text_generation_server.pb.generate_pb2 import InfoResponse
 from text_generation_server.adapters.weights import LayerAdapterWeights
 from text_generation_server.utils.adapter import (
@@ -86,6 +87,8 @@ class Model(ABC):
             device_type=self.device.type,
             window_size=self.sliding_window,
             speculate=self.speculate,
+            adapter_ids=list(get_adapter_to_index() or {}),
+            supports_guidance=self.supports_guidance,
         )
 
     @property
@@ -146,6 +149,11 @@ class Model(ABC):
     def supports_adapter_loading(self) -> bool:
         return False
 
+    @property
+    def supports_guidance(self) -> bool:
+        # The router rejects `guidance_scale` when the model does not apply it
+        return False
+
     def adapter_target_to_layer(self) -> Dict[str, Tuple[str, torch.Tensor]]:
         return {}
 
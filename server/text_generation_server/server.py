@@ -29,7 +29,11 @@ except (ImportError, NotImplementedError):
 
 from text_generation_server.pb import generate_pb2_grpc, generate_pb2
 from text_generation_server.tracing import UDSOpenTelemetryAioServerInterceptor
-from text_generation_server.models.globals import set_model_id, set_adapter_to_index
+from text_generation_server.models.globals import (
+    set_model_id,
+    set_adapter_to_index,
+    get_adapter_to_index,
+)
 from text_generation_server.utils.adapter import (
     AdapterParameters,
 )
@@ -59,6 +63,8 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
         self.model = model
         self.quantize = quantize
         self.server_urls = server_urls
+        self.start_time = time.time()
+        self.next_adapter_index = 1
         # For some reason, inference_mode does not work well with GLOO which we use on CPU
         if model.device.type == "cuda":
             # Force inference mode for the lifetime of TextGenerationService
@@ -72,6 +78,65 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
             torch.zeros((2, 2)).cuda()
         return generate_pb2.HealthResponse()
 
+    async def Stats(self, request, context):
+        memory_used = 0
+        memory_total = 0
+        if self.model.device.type == "cuda":
+            free, memory_total = torch.cuda.mem_get_info(self.model.device)
+            memory_used = memory_total - free
+        return generate_pb2.StatsResponse(
+            memory_used=memory_used,
+            memory_total=memory_total,
+            uptime=int(time.time() - self.start_time),
+        )
+
+    async def LoadAdapter(self, request, context):
+        adapter_to_index = get_adapter_to_index()
+        adapter_index = adapter_to_index.get(request.adapter_id)
+        if adapter_index is None:
+            # Indices are never reused, batches may still refer to unloaded adapters
+            adapter_index = max(adapter_to_index.values(), default=0) + 1
+            adapter_index = max(adapter_index, self.next_adapter_index)
+            self.model.load_adapter(
+                AdapterParameters(
+                    adapter_ids=[request.adapter_id],
+                    weights=None,  #  will be set to 1
+                    merge_strategy=0,
+                    density=1.0,
+                    majority_sign_method=0,
+                ),
+                None,  # adapter_source
+                adapter_index,
+                None,  # api_token
+                True,  # dynamic
+            )
+            self.next_adapter_index = adapter_index + 1
+            set_adapter_to_index(
+                {**adapter_to_index, request.adapter_id: adapter_index}
+            )
+            logger.info(f"Loaded adapter {request.adapter_id} at index {adapter_index}")
+        return generate_pb2.LoadAdapterResponse(adapter_index=adapter_index)
+
+    async def UnloadAdapter(self, request, context):
+        adapter_to_index = dict(get_adapter_to_index())
+        adapter_index = adapter_to_index.pop(request.adapter_id, None)
+        if adapter_index is not None:
+            self.model.offload_adapter(
+                AdapterParameters(
+                    adapter_ids=[request.adapter_id],
+                    weights=None,
+                    merge_strategy=0,
+                    density=1.0,
+                    majority_sign_method=0,
+                ),
+                None,  # adapter_source
+                adapter_index,
+            )
+            # New requests with this adapter use the base model
+            set_adapter_to_index(adapter_to_index)
+            logger.info(f"Unloaded adapter {request.adapter_id}")
+        return generate_pb2.UnloadAdapterResponse()
+
     async def ServiceDiscovery(self, request, context):
         return generate_pb2.ServiceDiscoveryResponse(urls=self.server_urls)
 
@@ -1,19 +1,27 @@
 import math
 import torch
 
+from bisect import bisect_left
 from loguru import logger
 from typing import Dict, Union
-from text_generation_server.pb.generate_pb2 import GrammarType
+from text_generation_server.pb.generate_pb2 import (
+    GrammarType,
+    NextTokenChooserParameters,
+    TokenFsm,
+)
 
-from outlines.fsm.fsm import RegexFSM
+from outlines.fsm.fsm import CFGFSM, RegexFSM
 from outlines.fsm.json_schema import build_regex_from_schema
 from functools import lru_cache
 from typing import List, Optional, DefaultDict
 import time
 
 from transformers import (
+    EpsilonLogitsWarper,
+    EtaLogitsWarper,
     LogitsWarper,
     LogitsProcessor,
+    MinPLogitsWarper,
     TemperatureLogitsWarper,
     TopKLogitsWarper,
     TopPLogitsWarper,
@@ -30,6 +38,10 @@ class StaticWarper:
         top_k=None,
         top_p=None,
         typical_p=None,
+        min_p=None,
+        epsilon_cutoff=None,
+        eta_cutoff=None,
+        top_a=None,
     ):
         self.warpers = []
 
@@ -42,6 +54,14 @@ class StaticWarper:
             self.warpers.append(TopPLogitsWarper(top_p=top_p))
         if typical_p is not None and typical_p < 1.0:
             self.warpers.append(TypicalLogitsWarper(mass=typical_p))
+        if min_p is not None and min_p > 0.0:
+            self.warpers.append(MinPLogitsWarper(min_p=min_p))
+        if epsilon_cutoff is not None and epsilon_cutoff > 0.0:
+            self.warpers.append(EpsilonLogitsWarper(epsilon=epsilon_cutoff))
+        if eta_cutoff is not None and eta_cutoff > 0.0:
+            self.warpers.append(EtaLogitsWarper(epsilon=eta_cutoff))
+        if top_a is not None and top_a > 0.0:
+            self.warpers.append(TopALogitsWarper(top_a=top_a))
 
         self.cuda_graph = None
         self.static_scores = None
@@ -82,9 +102,20 @@ def static_warper(
     top_k: Optional[int],
     top_p: Optional[float],
     typical_p: Optional[float],
+    min_p: Optional[float] = None,
+    epsilon_cutoff: Optional[float] = None,
+    eta_cutoff: Optional[float] = None,
+    top_a: Optional[float] = None,
 ) -> StaticWarper:
     return StaticWarper(
-        temperature=temperature, top_k=top_k, top_p=top_p, typical_p=typical_p
+        temperature=temperature,
+        top_k=top_k,
+        top_p=top_p,
+        typical_p=typical_p,
+        min_p=min_p,
+        epsilon_cutoff=epsilon_cutoff,
+        eta_cutoff=eta_cutoff,
+        top_a=top_a,
     )
 
 
@@ -188,6 +219,301 @@ class HeterogeneousFrequencyPenaltyLogitsProcessor(LogitsProcessor):
         return None
 
 
+class PresencePenaltyLogitsProcessor(LogitsProcessor):
+    r"""
+    Presence penalty as defined by OpenAI
+
+    Args:
+        penalty (`float`):
+            The parameter for presence penalty. 0.0 means no penalty.
+    """
+
+    def __init__(self, penalty: float):
+        self.penalty = penalty
+
+    def __call__(
+        self, input_ids: torch.LongTensor, scores: torch.FloatTensor
+    ) -> torch.FloatTensor:
+        # penalize every token present in input_ids once, whatever its count
+        present = torch.zeros_like(scores).scatter_(1, input_ids, 1.0)
+        return scores.sub_(present * self.penalty)
+
+
+class HeterogeneousPresencePenaltyLogitsProcessor(LogitsProcessor):
+    r"""
+    Presence penalty as defined by OpenAI in
+    https://platform.openai.com/docs/guides/text-generation/parameter-details
+
+    Args:
+        presence_penalty (`List[float]`):
+            The parameter for presence penalty. 0.0 means no penalty.
+    """
+
+    def __init__(self, penalty: List[float], dtype: torch.dtype, device: torch.device):
+        self.penalty = penalty
+        self.penalty_tensor = torch.tensor(
+            penalty, dtype=dtype, device=device
+        ).unsqueeze(1)
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        # penalize every token present in input_ids once, whatever its count
+        present = torch.zeros_like(scores).scatter_(1, input_ids, 1.0)
+        scores -= present * self.penalty_tensor
+        return scores
+
+    def filter(self, indices):
+        self.penalty = [self.penalty[i] for i in indices]
+        if any([x != 0.0 for x in self.penalty]):
+            self.penalty_tensor = self.penalty_tensor[indices]
+            return self
+        return None
+
+
+def banned_ngram_tokens(tokens: List[int], ngram_size: int) -> List[int]:
+    """Tokens that would repeat an n-gram of `tokens` if generated after them"""
+    if len(tokens) + 1 < ngram_size:
+        return []
+    prefix = tokens[len(tokens) - ngram_size + 1 :]
+    return [
+        tokens[start + ngram_size - 1]
+        for start in range(len(tokens) - ngram_size + 1)
+        if tokens[start : start + ngram_size - 1] == prefix
+    ]
+
+
+class HeterogeneousNoRepeatNGramLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] that bans the tokens that would repeat an n-gram of the sequence.
+    This version allows for a separate value for each sample and runs inplace when possible.
+    It doesn't validate inputs.
+
+    Args:
+        ngram_size (`List[int]`):
+            Size of the n-grams that can only occur once. 0 disables it for a sample.
+    """
+
+    def __init__(self, ngram_size: List[int]):
+        self.ngram_size = ngram_size
+
+    def __call__(
+        self,
+        input_ids: torch.Tensor,
+        scores: torch.Tensor,
+        input_lengths: Optional[List[int]] = None,
+    ) -> torch.Tensor:
+        for i, ngram_size in enumerate(self.ngram_size):
+            if ngram_size == 0:
+                continue
+            # input_ids can be padded: only keep the tokens of the sequence
+            length = input_ids.size(1) if input_lengths is None else input_lengths[i]
+            banned = banned_ngram_tokens(input_ids[i, :length].tolist(), ngram_size)
+            if banned:
+                scores[i, banned] = -math.inf
+        return scores
+
+    def filter(self, indices):
+        self.ngram_size = [self.ngram_size[i] for i in indices]
+        if any(x != 0 for x in self.ngram_size):
+            return self
+        return None
+
+
+def banned_word_tokens(tokens: List[int], bad_words: List[List[int]]) -> List[int]:
+    """Last tokens of the bad words whose other tokens end `tokens`"""
+    return [
+        bad_word[-1]
+        for bad_word in bad_words
+        if len(bad_word) - 1 <= len(tokens)
+        and tokens[len(tokens) - len(bad_word) + 1 :] == bad_word[:-1]
+    ]
+
+
+class BadWordsLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] that bans the last token of the bad words when the sequence ends with their other
+    tokens, so that they are never generated.
+
+    Args:
+        bad_words (`List[List[int]]`):
+            Token ids of the bad words.
+    """
+
+    def __init__(self, bad_words: List[List[int]]):
+        self.bad_words = bad_words
+
+    def __call__(
+        self, input_ids: torch.LongTensor, scores: torch.FloatTensor
+    ) -> torch.FloatTensor:
+        banned = banned_word_tokens(input_ids[0].tolist(), self.bad_words)
+        if banned:
+            scores[:, banned] = -math.inf
+        return scores
+
+
+class HeterogeneousBadWordsLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] that bans the last token of the bad words when the sequence ends with their other
+    tokens, so that they are never generated.
+    This version allows for a separate value for each sample and runs inplace when possible.
+
+    Args:
+        bad_words (`List[List[List[int]]]`):
+            Token ids of the bad words of each sample.
+    """
+
+    def __init__(self, bad_words: List[List[List[int]]]):
+        self.bad_words = bad_words
+
+    def __call__(
+        self,
+        input_ids: torch.Tensor,
+        scores: torch.Tensor,
+        input_lengths: Optional[List[int]] = None,
+    ) -> torch.Tensor:
+        for i, bad_words in enumerate(self.bad_words):
+            if not bad_words:
+                continue
+            # Only the single token bad words are banned whatever the sequence
+            if all(len(bad_word) == 1 for bad_word in bad_words):
+                tokens = []
+            else:
+                # input_ids can be padded: only keep the tokens of the sequence
+                length = (
+                    input_ids.size(1) if input_lengths is None else input_lengths[i]
+                )
+                tokens = input_ids[i, :length].tolist()
+            banned = banned_word_tokens(tokens, bad_words)
+            if banned:
+                scores[i, banned] = -math.inf
+        return scores
+
+    def filter(self, indices):
+        self.bad_words = [self.bad_words[i] for i in indices]
+        if any(self.bad_words):
+            return self
+        return None
+
+
+class MinNewTokensLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] that bans the end of sequence tokens until `min_new_tokens` tokens are generated.
+    It must be called once per generated token.
+
+    Args:
+        min_new_tokens (`int`):
+            Minimum number of generated tokens.
+        eos_token_ids (`List[int]`):
+            Token ids ending the generation.
+    """
+
+    def __init__(self, min_new_tokens: int, eos_token_ids: List[int]):
+        self.min_new_tokens = min_new_tokens
+        self.eos_token_ids = eos_token_ids
+        self.generated_tokens = 0
+
+    def __call__(
+        self, input_ids: torch.LongTensor, scores: torch.FloatTensor
+    ) -> torch.FloatTensor:
+        if self.generated_tokens < self.min_new_tokens:
+            scores[:, self.eos_token_ids] = -math.inf
+        self.generated_tokens += 1
+        return scores
+
+
+class HeterogeneousMinNewTokensLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] that bans the end of sequence tokens until `min_new_tokens` tokens are generated.
+    This version allows for a separate value for each sample and runs inplace.
+
+    Args:
+        min_new_tokens (`List[int]`):
+            Minimum number of generated tokens of each sample.
+        eos_token_ids (`List[int]`):
+            Token ids ending the generation.
+    """
+
+    def __init__(self, min_new_tokens: List[int], eos_token_ids: List[int]):
+        self.min_new_tokens = min_new_tokens
+        self.eos_token_ids = eos_token_ids
+
+    def __call__(
+        self,
+        input_ids: torch.Tensor,
+        scores: torch.Tensor,
+        generated_tokens: List[int],
+    ) -> torch.Tensor:
+        for i, min_new_tokens in enumerate(self.min_new_tokens):
+            if generated_tokens[i] < min_new_tokens:
+                scores[i, self.eos_token_ids] = -math.inf
+        return scores
+
+    def filter(self, indices):
+        self.min_new_tokens = [self.min_new_tokens[i] for i in indices]
+        if any(self.min_new_tokens):
+            return self
+        return None
+
+
+class LogitBiasLogitsProcessor(LogitsProcessor):
+    r"""
+    Bias added to the logits of some tokens, as defined by OpenAI
+
+    Args:
+        logit_bias (`Dict[int, float]`):
+            Bias added to the logits, by token id. Token ids outside of the vocabulary are ignored.
+    """
+
+    def __init__(self, logit_bias: Dict[int, float], device: torch.device):
+        self.token_ids = torch.tensor(
+            list(logit_bias.keys()), dtype=torch.int64, device=device
+        )
+        self.bias = torch.tensor(list(logit_bias.values()), device=device)
+
+    def __call__(
+        self, input_ids: torch.LongTensor, scores: torch.FloatTensor
+    ) -> torch.FloatTensor:
+        in_vocab = self.token_ids < scores.size(-1)
+        token_ids = self.token_ids[in_vocab]
+        scores[:, token_ids] += self.bias[in_vocab].to(scores.dtype)
+        return scores
+
+
+class HeterogeneousLogitBiasLogitsProcessor(LogitsProcessor):
+    r"""
+    Bias added to the logits of some tokens, as defined by OpenAI.
+    This version allows for a separate value for each sample.
+
+    Args:
+        logit_bias (`List[Dict[int, float]]`):
+            Bias added to the logits, by token id, for each sample. Token ids outside of the vocabulary are ignored.
+    """
+
+    def __init__(self, logit_bias: List[Dict[int, float]], device: torch.device):
+        self.logit_bias = logit_bias
+        self.device = device
+        rows, token_ids, bias = [], [], []
+        for i, sample_bias in enumerate(logit_bias):
+            rows.extend([i] * len(sample_bias))
+            token_ids.extend(sample_bias.keys())
+            bias.extend(sample_bias.values())
+        self.rows = torch.tensor(rows, dtype=torch.int64, device=device)
+        self.token_ids = torch.tensor(token_ids, dtype=torch.int64, device=device)
+        self.bias = torch.tensor(bias, device=device)
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        in_vocab = self.token_ids < scores.size(-1)
+        scores[self.rows[in_vocab], self.token_ids[in_vocab]] += self.bias[
+            in_vocab
+        ].to(scores.dtype)
+        return scores
+
+    def filter(self, indices):
+        logit_bias = [self.logit_bias[i] for i in indices]
+        if any(logit_bias):
+            return HeterogeneousLogitBiasLogitsProcessor(logit_bias, self.device)
+        return None
+
+
 class HeterogeneousTemperatureLogitsWarper:
     r"""
     [`LogitsWarper`] for temperature (exponential scaling output probability distribution).
@@ -449,6 +775,334 @@ class HeterogeneousTypicalLogitsWarper(LogitsWarper):
         return None
 
 
+class HeterogeneousMinPLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs min-p filtering: tokens whose probability is lower than `min_p` times the
+    probability of the most likely token are removed.
+    This version allows for a separate value for each sample and runs inplace when possible.
+    It doesn't validate inputs.
+
+    Args:
+        min_p (`List[float]`):
+            Minimum probability of a token, relative to the most likely token. 0 disables the filtering.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(
+        self,
+        min_p: List[float],
+        dtype: torch.dtype,
+        device: torch.device,
+        filter_value: float = -math.inf,
+    ):
+        self.min_p = min_p
+        self.min_p_tensor = torch.tensor(min_p, dtype=dtype, device=device).unsqueeze(1)
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        probs = torch.softmax(scores, dim=-1)
+        top_probs = probs.amax(dim=-1, keepdim=True)
+        # 0 is a special value: no token is below the threshold
+        indices_to_remove = probs < self.min_p_tensor * top_probs
+        warped_scores = scores.masked_fill_(indices_to_remove, self.filter_value)
+
+        return warped_scores
+
+    def filter(self, indices):
+        self.min_p = [self.min_p[i] for i in indices]
+
+        if any(x > 0.0 for x in self.min_p):
+            self.min_p_tensor = self.min_p_tensor[indices]
+            return self
+        return None
+
+
+class HeterogeneousEpsilonLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs epsilon sampling: tokens whose probability is lower than `epsilon` are
+    removed. The most likely token is always kept.
+    This version allows for a separate value for each sample and runs inplace when possible.
+    It doesn't validate inputs.
+
+    Args:
+        epsilon (`List[float]`):
+            Minimum probability of a token. 0 disables the filtering.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(
+        self,
+        epsilon: List[float],
+        dtype: torch.dtype,
+        device: torch.device,
+        filter_value: float = -math.inf,
+    ):
+        self.epsilon = epsilon
+        self.epsilon_tensor = torch.tensor(
+            epsilon, dtype=dtype, device=device
+        ).unsqueeze(1)
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        probs = torch.softmax(scores, dim=-1)
+        top_probs = probs.amax(dim=-1, keepdim=True)
+        indices_to_remove = (probs < self.epsilon_tensor) & (probs < top_probs)
+        warped_scores = scores.masked_fill_(indices_to_remove, self.filter_value)
+
+        return warped_scores
+
+    def filter(self, indices):
+        self.epsilon = [self.epsilon[i] for i in indices]
+
+        if any(x > 0.0 for x in self.epsilon):
+            self.epsilon_tensor = self.epsilon_tensor[indices]
+            return self
+        return None
+
+
+class HeterogeneousEtaLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs eta sampling: tokens whose probability is lower than
+    `min(epsilon, sqrt(epsilon) * exp(-entropy))` are removed, see "Truncation Sampling as Language Model
+    Desmoothing". The most likely token is always kept.
+    This version allows for a separate value for each sample and runs inplace when possible.
+    It doesn't validate inputs.
+
+    Args:
+        epsilon (`List[float]`):
+            Base cutoff of eta sampling. 0 disables the filtering.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(
+        self,
+        epsilon: List[float],
+        dtype: torch.dtype,
+        device: torch.device,
+        filter_value: float = -math.inf,
+    ):
+        self.epsilon = epsilon
+        self.epsilon_tensor = torch.tensor(
+            epsilon, dtype=dtype, device=device
+        ).unsqueeze(1)
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        probs = torch.softmax(scores, dim=-1)
+        entropy = torch.distributions.Categorical(logits=scores).entropy()
+        eta = torch.min(
+            self.epsilon_tensor,
+            torch.sqrt(self.epsilon_tensor) * torch.exp(-entropy).unsqueeze(1),
+        )
+        top_probs = probs.amax(dim=-1, keepdim=True)
+        indices_to_remove = (probs < eta) & (probs < top_probs)
+        warped_scores = scores.masked_fill_(indices_to_remove, self.filter_value)
+
+        return warped_scores
+
+    def filter(self, indices):
+        self.epsilon = [self.epsilon[i] for i in indices]
+
+        if any(x > 0.0 for x in self.epsilon):
+            self.epsilon_tensor = self.epsilon_tensor[indices]
+            return self
+        return None
+
+
+class TopALogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs top-a sampling: tokens whose probability is lower than
+    `top_a * max_prob ** 2` are removed. The most likely token is always kept.
+
+    Args:
+        top_a (`float`):
+            Scale of the squared probability of the most likely token. 0 disables the filtering.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(self, top_a: float, filter_value: float = -math.inf):
+        self.top_a = top_a
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        probs = torch.softmax(scores, dim=-1)
+        top_probs = probs.amax(dim=-1, keepdim=True)
+        indices_to_remove = probs < self.top_a * top_probs**2
+        return scores.masked_fill(indices_to_remove, self.filter_value)
+
+
+class HeterogeneousTopALogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs top-a sampling: tokens whose probability is lower than
+    `top_a * max_prob ** 2` are removed. The most likely token is always kept.
+    This version allows for a separate value for each sample and runs inplace when possible.
+    It doesn't validate inputs.
+
+    Args:
+        top_a (`List[float]`):
+            Scale of the squared probability of the most likely token. 0 disables the filtering.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(
+        self,
+        top_a: List[float],
+        dtype: torch.dtype,
+        device: torch.device,
+        filter_value: float = -math.inf,
+    ):
+        self.top_a = top_a
+        self.top_a_tensor = torch.tensor(top_a, dtype=dtype, device=device).unsqueeze(1)
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        probs = torch.softmax(scores, dim=-1)
+        top_probs = probs.amax(dim=-1, keepdim=True)
+        # top_a <= 1 so the most likely token is never removed
+        indices_to_remove = probs < self.top_a_tensor * top_probs**2
+        warped_scores = scores.masked_fill_(indices_to_remove, self.filter_value)
+
+        return warped_scores
+
+    def filter(self, indices):
+        self.top_a = [self.top_a[i] for i in indices]
+
+        if any(x > 0.0 for x in self.top_a):
+            self.top_a_tensor = self.top_a_tensor[indices]
+            return self
+        return None
+
+
+class MirostatLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs mirostat v2 sampling: tokens whose surprise is higher than `mu` are removed,
+    and `mu` is updated after each token to target a surprise of `tau`, see "Mirostat: A Neural Text Decoding
+    Algorithm that Directly Controls Perplexity". The most likely token is always kept.
+    Unlike the other warpers it has a state, `update` must be called with the log probability of each chosen token.
+
+    Args:
+        tau (`float`):
+            Target surprise, in bits.
+        eta (`float`):
+            Learning rate of `mu`.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(self, tau: float, eta: float, filter_value: float = -math.inf):
+        self.tau = tau
+        self.eta = eta
+        self.mu = 2 * tau
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        logprobs = torch.log_softmax(scores, dim=-1)
+        surprises = -logprobs / math.log(2)
+        top_logprobs = logprobs.amax(dim=-1, keepdim=True)
+        indices_to_remove = (surprises > self.mu) & (logprobs < top_logprobs)
+        return scores.masked_fill(indices_to_remove, self.filter_value)
+
+    def update(self, logprob: float):
+        surprise = -logprob / math.log(2)
+        self.mu -= self.eta * (surprise - self.tau)
+
+
+class HeterogeneousMirostatLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs mirostat v2 sampling: tokens whose surprise is higher than `mu` are removed,
+    and `mu` is updated after each token to target a surprise of `tau`. The most likely token is always kept.
+    This version allows for a separate value for each sample and runs inplace when possible.
+    It doesn't validate inputs.
+
+    Args:
+        mode (`List[int]`):
+            Mirostat version, 2 for mirostat v2. 0 disables the filtering.
+        tau (`List[float]`):
+            Target surprise, in bits.
+        eta (`List[float]`):
+            Learning rate of `mu`.
+        mu (`List[Optional[float]]`):
+            Current `mu` of the samples already started, `None` starts from `2 * tau`.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(
+        self,
+        mode: List[int],
+        tau: List[float],
+        eta: List[float],
+        mu: List[Optional[float]],
+        device: torch.device,
+        filter_value: float = -math.inf,
+    ):
+        self.mode = mode
+        # Disabled samples never filter and never update
+        self.tau_tensor = torch.tensor(
+            [t if m != 0 else 0.0 for m, t in zip(mode, tau)],
+            dtype=torch.float32,
+            device=device,
+        )
+        self.eta_tensor = torch.tensor(
+            [e if m != 0 else 0.0 for m, e in zip(mode, eta)],
+            dtype=torch.float32,
+            device=device,
+        )
+        self.mu_tensor = torch.tensor(
+            [
+                math.inf if m == 0 else (2 * t if u is None else u)
+                for m, t, u in zip(mode, tau, mu)
+            ],
+            dtype=torch.float32,
+            device=device,
+        )
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        logprobs = torch.log_softmax(scores.float(), dim=-1)
+        surprises = -logprobs / math.log(2)
+        top_logprobs = logprobs.amax(dim=-1, keepdim=True)
+        indices_to_remove = (surprises > self.mu_tensor.unsqueeze(1)) & (
+            logprobs < top_logprobs
+        )
+        warped_scores = scores.masked_fill_(indices_to_remove, self.filter_value)
+
+        return warped_scores
+
+    def update(self, logprobs: torch.Tensor, accepted_ids: torch.Tensor):
+        """
+        Update `mu` with the log probabilities of the accepted tokens, the speculated ones update it in turn
+        """
+        index = torch.repeat_interleave(
+            torch.arange(accepted_ids.shape[0], device=accepted_ids.device),
+            accepted_ids.long(),
+        )
+        surprises = -logprobs.float() / math.log(2)
+        errors = surprises - self.tau_tensor[index]
+        self.mu_tensor.index_add_(0, index, -self.eta_tensor[index] * errors)
+
+    @property
+    def mu(self) -> List[Optional[float]]:
+        return [
+            None if m == 0 else u for m, u in zip(self.mode, self.mu_tensor.tolist())
+        ]
+
+    def filter(self, indices):
+        self.mode = [self.mode[i] for i in indices]
+
+        if any(x != 0 for x in self.mode):
+            self.tau_tensor = self.tau_tensor[indices]
+            self.eta_tensor = self.eta_tensor[indices]
+            self.mu_tensor = self.mu_tensor[indices]
+            return self
+        return None
+
+
 class HeterogeneousProcessorWrapper(LogitsProcessor):
     r"""
     A wrapper for logit warpers or processors without heterogeneous parameter support.
@@ -480,15 +1134,54 @@ class HeterogeneousProcessorWrapper(LogitsProcessor):
         return None
 
 
+class TokenFSM:
+    """Token FSM of a regex grammar built by the router, with the interface of the
+    FSMs of Outlines: the end of sequence token is allowed in the final states."""
+
+    def __init__(self, fsm: TokenFsm, eos_token_id: int):
+        # The transitions of a state are a range of `tokens` and `next_states`
+        self.offsets = fsm.offsets
+        self.tokens = fsm.tokens
+        self.next_states = fsm.next_states
+        self.final_states = set(fsm.final_states)
+        self.eos_token_id = eos_token_id
+
+    @staticmethod
+    def from_pb(pb: NextTokenChooserParameters, tokenizer) -> Optional["TokenFSM"]:
+        if not pb.HasField("grammar_fsm"):
+            return None
+        return TokenFSM(pb.grammar_fsm, tokenizer.eos_token_id)
+
+    def allowed_token_ids(self, state: int) -> List[int]:
+        allowed = list(self.tokens[self.offsets[state] : self.offsets[state + 1]])
+        if state in self.final_states:
+            allowed.append(self.eos_token_id)
+        return allowed
+
+    def next_state(self, state: int, token_id: int) -> int:
+        if token_id == self.eos_token_id:
+            return -1
+        start, end = self.offsets[state], self.offsets[state + 1]
+        i = bisect_left(self.tokens, token_id, start, end)
+        if i == end or self.tokens[i] != token_id:
+            return -1
+        return self.next_states[i]
+
+
 class GrammarLogitProcessor(LogitsProcessor):
     fsm_state: DefaultDict[int, int]
-    fsm: RegexFSM
+    fsm: Union[RegexFSM, TokenFSM]
 
-    def __init__(self, tokenizer, device, grammar, grammar_type):
+    def __init__(self, tokenizer, device, grammar, grammar_type, fsm=None):
         self.device = device
         self.tokenizer = GrammarLogitProcessor._cached_adapt_tokenizer(tokenizer)
-        self.fsm = GrammarLogitProcessor._cached_compile_fsm(
-            grammar_type, grammar, self.tokenizer
+        # The router sends the FSMs of the regex grammars it compiled
+        self.fsm = (
+            fsm
+            if fsm is not None
+            else GrammarLogitProcessor._compile_fsm(
+                grammar_type, grammar, self.tokenizer
+            )
         )
 
     def __call__(
@@ -515,7 +1208,16 @@ class GrammarLogitProcessor(LogitsProcessor):
             return fsm_grammar_state
         return fsm.next_state(fsm_grammar_state, next_token_id)
 
-    # TODO: move grammar compilation into the router
+    @staticmethod
+    def _compile_fsm(grammar_type, grammar, tokenizer):
+        # The CFG FSMs hold the parser state of their generation, they cannot be shared
+        if grammar_type == GrammarType.GRAMMAR_TYPE_CFG:
+            return CFGFSM(grammar, _CFGTokenizer(tokenizer))
+        return GrammarLogitProcessor._cached_compile_fsm(
+            grammar_type, grammar, tokenizer
+        )
+
+    # Only the grammars that the router did not compile, or without a fast tokenizer
     @staticmethod
     @lru_cache(maxsize=32, typed=True)
     def _cached_compile_fsm(grammar_type, schema, tokenizer):
@@ -558,16 +1260,34 @@ class GrammarLogitProcessor(LogitsProcessor):
         return tokenizer
 
 
+class _CFGTokenizer:
+    """Adapted tokenizer whose `decode` returns a list, like the tokenizers of Outlines
+    expected by `CFGFSM`."""
+
+    def __init__(self, tokenizer):
+        self._tokenizer = tokenizer
+
+    def __getattr__(self, name):
+        return getattr(self._tokenizer, name)
+
+    def decode(self, token_ids):
+        return [self._tokenizer.decode(token_ids)]
+
+
 class HeterogeneousGrammarLogitProcessor(LogitsProcessor):
-    def __init__(self, tokenizer, device, grammars, grammar_types):
+    def __init__(self, tokenizer, device, grammars, grammar_types, fsms=None):
         self.device = device
         self.tokenizer = GrammarLogitProcessor._cached_adapt_tokenizer(tokenizer)
         self.fsms = []
-        for grammar, grammar_type in zip(grammars, grammar_types):
+        for i, (grammar, grammar_type) in enumerate(zip(grammars, grammar_types)):
             if len(grammar) == 0:
                 self.fsms.append(None)
                 continue
-            fsm = GrammarLogitProcessor._cached_compile_fsm(
+            # The FSMs of concatenated batches are kept with their state
+            if fsms is not None and fsms[i] is not None:
+                self.fsms.append(fsms[i])
+                continue
+            fsm = GrammarLogitProcessor._compile_fsm(
                 grammar_type, grammar, self.tokenizer
             )
             self.fsms.append(fsm)
@@ -1,25 +1,59 @@
 import re
-from typing import List, Optional, Tuple, Set, Union
+import time
+from typing import Dict, List, Optional, Tuple, Set, Union
 
 import math
 import torch
 from text_generation_server.pb import generate_pb2
 from text_generation_server.pb.generate_pb2 import FinishReason, GrammarType
 from text_generation_server.utils.logits_process import (
+    BadWordsLogitsProcessor,
     FrequencyPenaltyLogitsProcessor,
     GrammarLogitProcessor,
+    LogitBiasLogitsProcessor,
+    MinNewTokensLogitsProcessor,
+    MirostatLogitsWarper,
+    PresencePenaltyLogitsProcessor,
     HeterogeneousProcessorWrapper,
     HeterogeneousRepetitionPenaltyLogitsProcessor,
     HeterogeneousFrequencyPenaltyLogitsProcessor,
+    HeterogeneousLogitBiasLogitsProcessor,
+    HeterogeneousMinNewTokensLogitsProcessor,
+    HeterogeneousBadWordsLogitsProcessor,
+    HeterogeneousMinPLogitsWarper,
+    HeterogeneousEpsilonLogitsWarper,
+    HeterogeneousEtaLogitsWarper,
+    HeterogeneousTopALogitsWarper,
+    HeterogeneousMirostatLogitsWarper,
+    HeterogeneousNoRepeatNGramLogitsProcessor,
+    HeterogeneousPresencePenaltyLogitsProcessor,
     HeterogeneousTemperatureLogitsWarper,
     HeterogeneousTopKLogitsWarper,
     HeterogeneousTopPLogitsWarper,
     HeterogeneousTypicalLogitsWarper,
     HeterogeneousGrammarLogitProcessor,
+    TokenFSM,
     static_warper,
 )
 from text_generation_server.utils.watermark import WatermarkLogitsProcessor
-from transformers import PreTrainedTokenizerBase, RepetitionPenaltyLogitsProcessor
+from transformers import (
+    NoRepeatNGramLogitsProcessor,
+    PreTrainedTokenizerBase,
+    RepetitionPenaltyLogitsProcessor,
+    UnbatchedClassifierFreeGuidanceLogitsProcessor,
+)
+
+
+def eos_token_ids(tokenizer: Optional[PreTrainedTokenizerBase]) -> List[int]:
+    if tokenizer is None:
+        return []
+    # Same hack as `StoppingCriteria.from_pb`
+    eos_token_id = getattr(tokenizer, "_eos_token_ids", tokenizer.eos_token_id)
+    if eos_token_id is None:
+        return []
+    if isinstance(eos_token_id, int):
+        return [eos_token_id]
+    return list(eos_token_id)
 
 
 class NextTokenChooser:
@@ -29,9 +63,23 @@ class NextTokenChooser:
         temperature: float = 1.0,
         repetition_penalty: float = 1.0,
         frequency_penalty: float = 0.0,
+        presence_penalty: float = 0.0,
+        no_repeat_ngram_size: int = 0,
+        bad_words: Optional[List[List[int]]] = None,
         top_k: Optional[int] = None,
         top_p: Optional[float] = None,
         typical_p: Optional[float] = None,
+        min_p: Optional[float] = None,
+        epsilon_cutoff: Optional[float] = None,
+        eta_cutoff: Optional[float] = None,
+        top_a: Optional[float] = None,
+        mirostat_mode: int = 0,
+        mirostat_tau: float = 0.0,
+        mirostat_eta: float = 0.0,
+        logit_bias: Optional[Dict[int, float]] = None,
+        guidance_scale: float = 1.0,
+        negative_input_ids: Optional[List[int]] = None,
+        min_new_tokens: int = 0,
         do_sample: bool = False,
         seed: int = 0,
         device: str = "cpu",
@@ -39,7 +87,13 @@ class NextTokenChooser:
         grammar: str = "",
         grammar_type: GrammarType = GrammarType.GRAMMAR_TYPE_NONE,
         fsm_grammar_state: int = 0,
+        grammar_fsm: Optional[TokenFSM] = None,
     ):
+        # The guidance runs the model, see `enable_guidance`
+        self.guidance_scale = guidance_scale
+        self.negative_input_ids = negative_input_ids
+        self.guidance_processor = None
+        self.device = device
         self.watermark_processor = (
             WatermarkLogitsProcessor(device=device) if watermark else None
         )
@@ -53,8 +107,29 @@ class NextTokenChooser:
             if frequency_penalty and frequency_penalty != 0.0
             else None
         )
+        self.presence_processor = (
+            PresencePenaltyLogitsProcessor(penalty=presence_penalty)
+            if presence_penalty and presence_penalty != 0.0
+            else None
+        )
+        self.no_repeat_ngram_processor = (
+            NoRepeatNGramLogitsProcessor(no_repeat_ngram_size)
+            if no_repeat_ngram_size
+            else None
+        )
+        self.bad_words_processor = (
+            BadWordsLogitsProcessor(bad_words) if bad_words else None
+        )
+        self.logit_bias_processor = (
+            LogitBiasLogitsProcessor(logit_bias, device) if logit_bias else None
+        )
+        self.min_new_tokens_processor = (
+            MinNewTokensLogitsProcessor(min_new_tokens, eos_token_ids(tokenizer))
+            if min_new_tokens
+            else None
+        )
         self.grammar_processor = (
-            GrammarLogitProcessor(tokenizer, device, grammar, grammar_type)
+            GrammarLogitProcessor(tokenizer, device, grammar, grammar_type, grammar_fsm)
             if grammar != ""
             else None
         )
@@ -65,27 +140,57 @@ class NextTokenChooser:
             or (top_k is not None and top_k != 0)
             or (top_p is not None and top_p < 1.0)
             or (typical_p is not None and typical_p < 1.0)
+            or (min_p is not None and min_p > 0.0)
+            or (epsilon_cutoff is not None and epsilon_cutoff > 0.0)
+            or (eta_cutoff is not None and eta_cutoff > 0.0)
+            or (top_a is not None and top_a > 0.0)
         )
         if has_warpers:
             self.static_warper = static_warper(
-                temperature=temperature, top_k=top_k, top_p=top_p, typical_p=typical_p
+                temperature=temperature,
+                top_k=top_k,
+                top_p=top_p,
+                typical_p=typical_p,
+                min_p=min_p,
+                epsilon_cutoff=epsilon_cutoff,
+                eta_cutoff=eta_cutoff,
+                top_a=top_a,
             )
         else:
             self.static_warper = None
 
-        sampling = do_sample or has_warpers
+        # Stateful, so not part of the cached static warper
+        self.mirostat_warper = (
+            MirostatLogitsWarper(tau=mirostat_tau, eta=mirostat_eta)
+            if mirostat_mode != 0
+            else None
+        )
+
+        sampling = do_sample or has_warpers or self.mirostat_warper is not None
 
         self.choice = Sampling(seed, device) if sampling else Greedy()
         self.fsm_grammar_state = fsm_grammar_state
         self.grammar = grammar
 
     def __call__(self, input_ids, scores):
+        if self.guidance_processor is not None:
+            scores = self.guidance_processor(input_ids, scores)
         if self.watermark_processor is not None:
             scores = self.watermark_processor(input_ids, scores)
         if self.repetition_processor is not None:
             scores = self.repetition_processor(input_ids, scores)
         if self.frequency_processor is not None:
             scores = self.frequency_processor(input_ids, scores)
+        if self.presence_processor is not None:
+            scores = self.presence_processor(input_ids, scores)
+        if self.no_repeat_ngram_processor is not None:
+            scores = self.no_repeat_ngram_processor(input_ids, scores)
+        if self.bad_words_processor is not None:
+            scores = self.bad_words_processor(input_ids, scores)
+        if self.logit_bias_processor is not None:
+            scores = self.logit_bias_processor(input_ids, scores)
+        if self.min_new_tokens_processor is not None:
+            scores = self.min_new_tokens_processor(input_ids, scores)
         if self.grammar_processor is not None:
             scores = self.grammar_processor(scores, self.fsm_grammar_state)
 
@@ -94,8 +199,15 @@ class NextTokenChooser:
         else:
             scores, next_logprob = self.static_warper(scores)
 
+        if self.mirostat_warper is not None:
+            scores = self.mirostat_warper(input_ids, scores)
+            next_logprob = torch.log_softmax(scores, -1)
+
         next_id = self.choice(scores[-1]).view(1, 1)
 
+        if self.mirostat_warper is not None:
+            self.mirostat_warper.update(next_logprob[-1, next_id.item()].item())
+
         return next_id, next_logprob
 
     def advance_grammar(self, next_id: int):
@@ -105,6 +217,21 @@ class NextTokenChooser:
             )
         return self
 
+    def enable_guidance(self, model):
+        """
+        Classifier-free guidance runs `model` on the negative prompt, with its own cache
+        """
+        if self.guidance_processor is None and self.guidance_scale != 1.0:
+            unconditional_ids = None
+            if self.negative_input_ids:
+                unconditional_ids = torch.tensor(
+                    [self.negative_input_ids], device=self.device
+                )
+            self.guidance_processor = UnbatchedClassifierFreeGuidanceLogitsProcessor(
+                self.guidance_scale, model, unconditional_ids
+            )
+        return self
+
     @classmethod
     def from_pb(
         cls,
@@ -117,15 +244,31 @@ class NextTokenChooser:
             temperature=pb.temperature,
             repetition_penalty=pb.repetition_penalty,
             frequency_penalty=pb.frequency_penalty,
+            presence_penalty=pb.presence_penalty,
+            no_repeat_ngram_size=pb.no_repeat_ngram_size,
+            bad_words=[list(bad_word.ids) for bad_word in pb.bad_words],
             top_k=pb.top_k,
             top_p=pb.top_p,
             typical_p=pb.typical_p,
+            min_p=pb.min_p,
+            epsilon_cutoff=pb.epsilon_cutoff,
+            eta_cutoff=pb.eta_cutoff,
+            top_a=pb.top_a,
+            mirostat_mode=pb.mirostat_mode,
+            mirostat_tau=pb.mirostat_tau,
+            mirostat_eta=pb.mirostat_eta,
+            logit_bias=dict(pb.logit_bias),
+            # 0.0 is the default value of older routers
+            guidance_scale=pb.guidance_scale or 1.0,
+            negative_input_ids=list(pb.negative_input_ids),
+            min_new_tokens=pb.min_new_tokens,
             do_sample=pb.do_sample,
             seed=pb.seed,
             device=device,
             tokenizer=tokenizer,
             grammar=pb.grammar,
             grammar_type=pb.grammar_type,
+            grammar_fsm=TokenFSM.from_pb(pb, tokenizer),
         )
 
 
@@ -147,6 +290,8 @@ class StoppingCriteria:
         stop_sequence_criterias: List[StopSequenceCriteria],
         max_new_tokens: int = 20,
         ignore_eos_token: bool = False,
+        stop_token_ids: Optional[Set[int]] = None,
+        max_time: Optional[float] = None,
     ):
         if eos_token_ids is None:
             eos_token_ids = set()
@@ -164,6 +309,9 @@ class StoppingCriteria:
         self.current_tokens = 0
         self.current_output = ""
         self.ignore_eos_token = ignore_eos_token
+        self.stop_token_ids = stop_token_ids if stop_token_ids else set()
+        self.max_time = max_time
+        self.start_time = time.monotonic()
 
     def __call__(self, last_token: int, last_output: str) -> Tuple[bool, Optional[str]]:
         self.current_tokens += 1
@@ -176,6 +324,9 @@ class StoppingCriteria:
         if not self.ignore_eos_token and last_token in self.eos_token_ids:
             return True, FinishReason.FINISH_REASON_EOS_TOKEN
 
+        if last_token in self.stop_token_ids:
+            return True, FinishReason.FINISH_REASON_STOP_SEQUENCE
+
         if self.stop_sequence_criterias:
             self.current_output += last_output
             # There is no need to keep an output that is too long
@@ -186,6 +337,12 @@ class StoppingCriteria:
                 if stop_sequence_criteria(self.current_output):
                     return True, FinishReason.FINISH_REASON_STOP_SEQUENCE
 
+        if (
+            self.max_time is not None
+            and time.monotonic() - self.start_time > self.max_time
+        ):
+            return True, FinishReason.FINISH_REASON_MAX_TIME
+
         return False, None
 
     @classmethod
@@ -204,6 +361,8 @@ class StoppingCriteria:
             stop_sequence_criterias,
             pb.max_new_tokens,
             pb.ignore_eos_token,
+            set(pb.stop_token_ids),
+            pb.max_time if pb.max_time > 0 else None,
         )
 
 
@@ -240,15 +399,29 @@ class HeterogeneousNextTokenChooser:
         temperature: List[float],
         repetition_penalty: List[float],
         frequency_penalty: List[float],
+        presence_penalty: List[float],
+        no_repeat_ngram_size: List[int],
+        bad_words: List[List[List[int]]],
         top_k: List[int],
         top_p: List[float],
         typical_p: List[float],
+        min_p: List[float],
+        epsilon_cutoff: List[float],
+        eta_cutoff: List[float],
+        top_a: List[float],
+        mirostat_mode: List[int],
+        mirostat_tau: List[float],
+        mirostat_eta: List[float],
+        logit_bias: List[Dict[int, float]],
+        min_new_tokens: List[int],
         do_sample: List[bool],
         seeds: List[int],
         tokenizer: PreTrainedTokenizerBase,
         grammars: List[str],
         grammar_types: List[int],
         fsm_grammar_states=List[int],
+        mirostat_mus: Optional[List[Optional[float]]] = None,
+        grammar_fsms: Optional[List] = None,
     ):
         warpers = []
 
@@ -280,9 +453,41 @@ class HeterogeneousNextTokenChooser:
             else None
         )
 
+        self.presence_processor = (
+            HeterogeneousPresencePenaltyLogitsProcessor(
+                presence_penalty, dtype, device
+            )
+            if any([x != 0.0 for x in presence_penalty])
+            else None
+        )
+
+        self.no_repeat_ngram_processor = (
+            HeterogeneousNoRepeatNGramLogitsProcessor(no_repeat_ngram_size)
+            if any([x != 0 for x in no_repeat_ngram_size])
+            else None
+        )
+
+        self.bad_words_processor = (
+            HeterogeneousBadWordsLogitsProcessor(bad_words) if any(bad_words) else None
+        )
+
+        self.logit_bias_processor = (
+            HeterogeneousLogitBiasLogitsProcessor(logit_bias, device)
+            if any(logit_bias)
+            else None
+        )
+
+        self.min_new_tokens_processor = (
+            HeterogeneousMinNewTokensLogitsProcessor(
+                min_new_tokens, eos_token_ids(tokenizer)
+            )
+            if any(min_new_tokens)
+            else None
+        )
+
         self.grammar_processor = (
             HeterogeneousGrammarLogitProcessor(
-                tokenizer, device, grammars, grammar_types
+                tokenizer, device, grammars, grammar_types, grammar_fsms
             )
             if any([grammar != "" for grammar in grammars])
             else None
@@ -308,8 +513,43 @@ class HeterogeneousNextTokenChooser:
             do_sample = [sample or x < 1.0 for x, sample in zip(typical_p, do_sample)]
             warpers.append(HeterogeneousTypicalLogitsWarper(typical_p, dtype, device))
 
+        if any(x > 0.0 for x in min_p):
+            do_sample = [sample or x > 0.0 for x, sample in zip(min_p, do_sample)]
+            warpers.append(HeterogeneousMinPLogitsWarper(min_p, dtype, device))
+
+        if any(x > 0.0 for x in epsilon_cutoff):
+            do_sample = [
+                sample or x > 0.0 for x, sample in zip(epsilon_cutoff, do_sample)
+            ]
+            warpers.append(
+                HeterogeneousEpsilonLogitsWarper(epsilon_cutoff, dtype, device)
+            )
+
+        if any(x > 0.0 for x in eta_cutoff):
+            do_sample = [sample or x > 0.0 for x, sample in zip(eta_cutoff, do_sample)]
+            warpers.append(HeterogeneousEtaLogitsWarper(eta_cutoff, dtype, device))
+
+        if any(x > 0.0 for x in top_a):
+            do_sample = [sample or x > 0.0 for x, sample in zip(top_a, do_sample)]
+            warpers.append(HeterogeneousTopALogitsWarper(top_a, dtype, device))
+
         self.warpers = warpers
 
+        # Stateful, applied after the other warpers and updated with the chosen tokens
+        if any(x != 0 for x in mirostat_mode):
+            do_sample = [
+                sample or x != 0 for x, sample in zip(mirostat_mode, do_sample)
+            ]
+            self.mirostat_warper = HeterogeneousMirostatLogitsWarper(
+                mirostat_mode,
+                mirostat_tau,
+                mirostat_eta,
+                mirostat_mus if mirostat_mus else [None] * len(mirostat_mode),
+                device,
+            )
+        else:
+            self.mirostat_warper = None
+
         if any(do_sample):
             self.choice = HeterogeneousSampling(do_sample, seeds, device)
         else:
@@ -332,6 +572,8 @@ class HeterogeneousNextTokenChooser:
         speculated_ids: Optional[torch.Tensor] = None,
         speculative_scores: Optional[torch.Tensor] = None,
         verbose=False,
+        input_lengths: Optional[List[int]] = None,
+        generated_tokens: Optional[List[int]] = None,
     ):
         if speculated_ids is not None:
             B = scores.shape[0] // (speculated_ids.shape[1] + 1)
@@ -352,10 +594,27 @@ class HeterogeneousNextTokenChooser:
                 _scores = self.repetition_processor(input_ids, _scores)
             if self.frequency_processor is not None:
                 _scores = self.frequency_processor(input_ids, _scores)
+            if self.presence_processor is not None:
+                _scores = self.presence_processor(input_ids, _scores)
+            if self.no_repeat_ngram_processor is not None:
+                _scores = self.no_repeat_ngram_processor(
+                    input_ids, _scores, input_lengths
+                )
+            if self.bad_words_processor is not None:
+                _scores = self.bad_words_processor(input_ids, _scores, input_lengths)
+            if self.logit_bias_processor is not None:
+                _scores = self.logit_bias_processor(input_ids, _scores)
+            if self.min_new_tokens_processor is not None:
+                # The j-th speculated token comes after j other new tokens
+                _scores = self.min_new_tokens_processor(
+                    input_ids, _scores, [tokens + j for tokens in generated_tokens]
+                )
             if self.grammar_processor is not None:
                 _scores = self.grammar_processor(_scores, self.fsm_grammar_states)
             for warper in self.warpers:
                 _scores = warper(input_ids, _scores)
+            if self.mirostat_warper is not None:
+                _scores = self.mirostat_warper(input_ids, _scores)
             _next_ids = self.choice(_scores)
             scores[:, j] = _scores
             next_ids[:, j] = _next_ids
@@ -399,6 +658,9 @@ class HeterogeneousNextTokenChooser:
 
         next_logprobs = torch.gather(logprobs, 1, next_ids.view(-1, 1)).view(-1)
 
+        if self.mirostat_warper is not None:
+            self.mirostat_warper.update(next_logprobs, accepted_ids)
+
         if speculate > 0:
             if speculative_scores is not None:
                 # Medusa provided some scores
@@ -413,6 +675,25 @@ class HeterogeneousNextTokenChooser:
 
         return next_ids, next_logprobs, alllogprobs, accepted_ids, speculative_ids
 
+    @property
+    def mirostat_mus(self) -> List[Optional[float]]:
+        """
+        `mu` of each sample, to keep the mirostat state when batches are concatenated
+        """
+        if self.mirostat_warper is None:
+            return [None] * len(self.seeds)
+        return self.mirostat_warper.mu
+
+    @property
+    def grammar_fsms(self) -> List:
+        """
+        FSM of each sample, to keep the parser state of the CFG grammars when batches are
+        concatenated
+        """
+        if self.grammar_processor is None:
+            return [None] * len(self.seeds)
+        return self.grammar_processor.fsms
+
     def advance_grammar(self, next_ids: List[int]):
         if self.grammar_processor is not None:
             other_new_states = self.grammar_processor.advance_batch(
@@ -442,6 +723,25 @@ class HeterogeneousNextTokenChooser:
         if self.frequency_processor is not None:
             self.frequency_processor = self.frequency_processor.filter(indices)
 
+        if self.presence_processor is not None:
+            self.presence_processor = self.presence_processor.filter(indices)
+
+        if self.no_repeat_ngram_processor is not None:
+            self.no_repeat_ngram_processor = self.no_repeat_ngram_processor.filter(
+                indices
+            )
+
+        if self.bad_words_processor is not None:
+            self.bad_words_processor = self.bad_words_processor.filter(indices)
+
+        if self.logit_bias_processor is not None:
+            self.logit_bias_processor = self.logit_bias_processor.filter(indices)
+
+        if self.min_new_tokens_processor is not None:
+            self.min_new_tokens_processor = self.min_new_tokens_processor.filter(
+                indices
+            )
+
         if self.grammar_processor is not None:
             self.grammar_processor = self.grammar_processor.filter(indices)
 
@@ -452,6 +752,9 @@ class HeterogeneousNextTokenChooser:
                 filtered_warpers.append(filtered_warper)
         self.warpers = filtered_warpers
 
+        if self.mirostat_warper is not None:
+            self.mirostat_warper = self.mirostat_warper.filter(indices)
+
         self.seeds = [self.seeds[i] for i in indices]
         self.do_sample = [self.do_sample[i] for i in indices]
 
@@ -482,15 +785,31 @@ class HeterogeneousNextTokenChooser:
         device: torch.device,
         tokenizer: PreTrainedTokenizerBase,
         fsm_grammar_states: Optional[List[int]] = None,
+        mirostat_mus: Optional[List[Optional[float]]] = None,
+        grammar_fsms: Optional[List] = None,
     ) -> "HeterogeneousNextTokenChooser":
         return HeterogeneousNextTokenChooser(
             watermark=[pb_.watermark for pb_ in pb],
             temperature=[pb_.temperature for pb_ in pb],
             repetition_penalty=[pb_.repetition_penalty for pb_ in pb],
             frequency_penalty=[pb_.frequency_penalty for pb_ in pb],
+            presence_penalty=[pb_.presence_penalty for pb_ in pb],
+            no_repeat_ngram_size=[pb_.no_repeat_ngram_size for pb_ in pb],
+            bad_words=[
+                [list(bad_word.ids) for bad_word in pb_.bad_words] for pb_ in pb
+            ],
             top_k=[pb_.top_k for pb_ in pb],
             top_p=[pb_.top_p for pb_ in pb],
             typical_p=[pb_.typical_p for pb_ in pb],
+            min_p=[pb_.min_p for pb_ in pb],
+            epsilon_cutoff=[pb_.epsilon_cutoff for pb_ in pb],
+            eta_cutoff=[pb_.eta_cutoff for pb_ in pb],
+            top_a=[pb_.top_a for pb_ in pb],
+            mirostat_mode=[pb_.mirostat_mode for pb_ in pb],
+            mirostat_tau=[pb_.mirostat_tau for pb_ in pb],
+            mirostat_eta=[pb_.mirostat_eta for pb_ in pb],
+            logit_bias=[dict(pb_.logit_bias) for pb_ in pb],
+            min_new_tokens=[pb_.min_new_tokens for pb_ in pb],
             do_sample=[pb_.do_sample for pb_ in pb],
             seeds=[pb_.seed for pb_ in pb],
             device=device,
@@ -501,6 +820,13 @@ class HeterogeneousNextTokenChooser:
             fsm_grammar_states=(
                 fsm_grammar_states if fsm_grammar_states else [0] * len(pb)
             ),
+            mirostat_mus=mirostat_mus,
+            # The FSMs of concatenated batches, or the ones built by the router
+            grammar_fsms=(
+                grammar_fsms
+                if grammar_fsms is not None
+                else [TokenFSM.from_pb(pb_, tokenizer) for pb_ in pb]
+            ),
         )
 
 